@@ -3,6 +3,7 @@ use futures::stream::StreamExt;
 use rand::Rng;
 use rmesg::{
     entry::{Entry, LogFacility, LogLevel},
+    interval::PollInterval,
     klogctl::{klog, KLogEntries},
     kmsgfile::{kmsg, KMsgEntriesIter, KMsgEntriesStream},
 };
@@ -28,6 +29,7 @@ fn random_entry() -> Entry {
         },
         message: "Some very long string with no purpose. Lorem. Ipsum. Something Something."
             .to_owned(),
+        ..Default::default()
     }
 }
 
@@ -95,7 +97,7 @@ fn klog_read() {
 }
 
 fn klog_iter_read() {
-    let entries = KLogEntries::with_options(false, Duration::from_secs(1)).unwrap();
+    let entries = KLogEntries::with_options(false, PollInterval::from_secs(1).unwrap()).unwrap();
     let mut count = 0;
     for entry in entries {
         black_box(entry).unwrap();
@@ -107,7 +109,8 @@ fn klog_iter_read() {
 }
 
 async fn klog_stream_read() {
-    let mut entries = KLogEntries::with_options(false, Duration::from_secs(1)).unwrap();
+    let mut entries =
+        KLogEntries::with_options(false, PollInterval::from_secs(1).unwrap()).unwrap();
     let mut count = 0;
     while let Some(entry) = StreamExt::next(&mut entries).await {
         black_box(entry).unwrap();
@@ -0,0 +1,139 @@
+// Throughput of the hot paths, on synthetic buffers (so they don't depend on what's in
+// this machine's kernel log): parsing, diffing a klogctl poll against the previous
+// one, formatting, and collecting snapshots (as `Entry`s, or compacted). Run with
+// `cargo bench --features bench --bench hotpaths`.
+//
+// Baseline (release profile, 10,000 entries per iteration, on a shared x86_64 VM):
+//
+//   parse/kmsg           ~15 ms    (~660K lines/s)
+//   parse/klog           ~15 ms    (~660K lines/s)
+//   poll_diff/first      ~100 ns   (nothing to compare against, so nothing to scan)
+//   poll_diff/half_new   ~117 µs   (~86M entries/s)
+//   format/display       ~3.9 ms   (~2.6M entries/s)
+//   format/kmsg          ~1.3 ms   (~8.0M entries/s)
+//   format/klog          ~1.7 ms   (~5.9M entries/s)
+//   snapshot/entries     ~2.1 ms   (~4.7M entries/s)
+//   snapshot/compact     ~1.5 ms   (~6.9M entries/s, the same parser without an
+//                                   allocation per message)
+//
+// A change that moves one of these by much more than run-to-run noise (a few percent)
+// deserves a look; the counters in `rmesg::counters` catch changes in the amount of
+// work done per entry in the tests.
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use rmesg::{
+    compact::compact_from_kmsg_buffer,
+    counters::{synthetic_entries, synthetic_klog_buffer, synthetic_kmsg_buffer},
+    klogctl::PollCursor,
+    parse::{console_entry_from_line, kmsg_entry_from_line},
+    staticbuf::{parse_record_into, SUGGESTED_RECORD_CAPACITY},
+};
+
+const ENTRIES: usize = 10_000;
+
+fn parse(c: &mut Criterion) {
+    let kmsg_buffer = synthetic_kmsg_buffer(ENTRIES);
+    let klog_buffer = synthetic_klog_buffer(ENTRIES);
+
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Elements(ENTRIES as u64));
+    group.bench_function("kmsg", |b| {
+        b.iter(|| {
+            for line in kmsg_buffer.lines() {
+                black_box(kmsg_entry_from_line(line).unwrap());
+            }
+        })
+    });
+    group.bench_function("klog", |b| {
+        b.iter(|| {
+            for line in klog_buffer.lines() {
+                black_box(console_entry_from_line(line).unwrap());
+            }
+        })
+    });
+    group.finish();
+}
+
+fn poll_diff(c: &mut Criterion) {
+    let entries = synthetic_entries(ENTRIES);
+    let mut halfway = PollCursor::default();
+    halfway.advance(&entries[..=ENTRIES / 2]);
+
+    let mut group = c.benchmark_group("poll_diff");
+    group.throughput(Throughput::Elements(ENTRIES as u64));
+    group.bench_function("first", |b| {
+        b.iter_batched(
+            || entries.clone(),
+            |entries| black_box(PollCursor::default().newer_entries(entries)),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("half_new", |b| {
+        b.iter_batched(
+            || entries.clone(),
+            |entries| {
+                let mut cursor = halfway;
+                black_box(cursor.newer_entries(entries))
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn format(c: &mut Criterion) {
+    let entries = synthetic_entries(ENTRIES);
+
+    let mut group = c.benchmark_group("format");
+    group.throughput(Throughput::Elements(ENTRIES as u64));
+    group.bench_function("display", |b| {
+        b.iter(|| {
+            for entry in &entries {
+                black_box(entry.to_string());
+            }
+        })
+    });
+    group.bench_function("kmsg", |b| {
+        b.iter(|| {
+            for entry in &entries {
+                black_box(entry.to_kmsg_str().unwrap());
+            }
+        })
+    });
+    group.bench_function("klog", |b| {
+        b.iter(|| {
+            for entry in &entries {
+                black_box(entry.to_klog_str().unwrap());
+            }
+        })
+    });
+    group.finish();
+}
+
+fn snapshot(c: &mut Criterion) {
+    let kmsg_buffer = synthetic_kmsg_buffer(ENTRIES);
+
+    let mut group = c.benchmark_group("snapshot");
+    group.throughput(Throughput::Elements(ENTRIES as u64));
+    // the same parser as compact_from_kmsg_buffer's, so that only the storage differs
+    group.bench_function("entries", |b| {
+        b.iter(|| {
+            let mut scratch = synthetic_entries(1).remove(0);
+            scratch.message.reserve(SUGGESTED_RECORD_CAPACITY);
+            let entries: Vec<_> = kmsg_buffer
+                .lines()
+                .map(|line| {
+                    parse_record_into(line.as_bytes(), &mut scratch).unwrap();
+                    scratch.clone()
+                })
+                .collect();
+            black_box(entries)
+        })
+    });
+    group.bench_function("compact", |b| {
+        b.iter(|| black_box(compact_from_kmsg_buffer(&kmsg_buffer).unwrap()))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse, poll_diff, format, snapshot);
+criterion_main!(benches);
@@ -0,0 +1,19 @@
+// Generates the gRPC service from proto/rmesg.proto when the "grpc" feature is on.
+// The proto is compiled with protox so no system protoc is needed.
+fn main() {
+    println!("cargo:rerun-if-changed=proto/rmesg.proto");
+
+    #[cfg(feature = "grpc")]
+    {
+        let descriptors = protox::compile(["rmesg.proto"], ["proto"])
+            .expect("Unable to compile proto/rmesg.proto");
+        tonic_build::configure()
+            .build_client(true)
+            // the generated `connect` needs the 2021 prelude; clients pass in a channel instead
+            .build_transport(false)
+            // as `Entry::tags` is
+            .btree_map(["."])
+            .compile_fds(descriptors)
+            .expect("Unable to generate gRPC service from proto/rmesg.proto");
+    }
+}
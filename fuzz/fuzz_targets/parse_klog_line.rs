@@ -0,0 +1,11 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use rmesg::fuzz::{parse_klog_line, MAX_RECORD_LEN};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(entry) = parse_klog_line(data) {
+        // nothing parsed out of a record is bigger than the record
+        assert!(data.len() <= MAX_RECORD_LEN);
+        assert!(entry.message.len() <= data.len());
+    }
+});
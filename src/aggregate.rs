@@ -0,0 +1,365 @@
+use crate::entry::{Entry, LogFacility, LogLevel};
+/// Aggregation of several hosts' kernel logs into one stream, so a small fleet can
+/// centralize its kernel logs with nothing but rmesg (`rmesg aggregate AGENT...`).
+///
+/// Each host runs an agent serving the `grpc::KernelLogService`; the aggregator follows
+/// every agent and merges what they send into one stream of entries. Each entry comes
+/// tagged with its host and boot (as a `provenance::Origin::Remote` provenance with the
+/// agent's boot ID), so entries from different hosts, or from before and after a host
+/// rebooted, stay distinguishable.
+///
+/// Kernel timestamps count from each host's own boot, so the merged stream is ordered by
+/// the wall-clock times the agents send instead. Entries are held for a short delay
+/// before being passed on, so that ones arriving slightly late from one agent can still
+/// go out ahead of later ones from another; raising the delay tolerates slower links at
+/// the cost of latency.
+///
+use crate::error::RMesgError;
+use crate::grpc::proto::{self, kernel_log_client::KernelLogClient};
+use crate::provenance::{Origin, Provenance, SourceBackend, View};
+
+use futures::stream::{self, Stream, StreamExt};
+use num::FromPrimitive;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Suggested time to hold entries for, when in doubt.
+pub const SUGGESTED_DELAY: Duration = Duration::from_millis(500);
+
+/// A stream of entries from one or more agents.
+pub type AgentStream = Pin<Box<dyn Stream<Item = Result<Entry, RMesgError>> + Send>>;
+
+/// Converts entries as agents send them, sharing a provenance between consecutive
+/// entries from the same host and boot.
+#[derive(Debug)]
+pub struct RemoteTagger {
+    agent: String,
+    provenance: Option<Arc<Provenance>>,
+}
+
+impl RemoteTagger {
+    /// Converts entries from the agent at `agent`, which names the host when an entry
+    /// doesn't.
+    pub fn with_options(agent: &str) -> RemoteTagger {
+        RemoteTagger {
+            agent: agent.to_owned(),
+            provenance: None,
+        }
+    }
+
+    pub fn entry(&mut self, entry: proto::Entry) -> Entry {
+        let host = entry.host.unwrap_or_else(|| self.agent.clone());
+        let provenance = match &self.provenance {
+            Some(p) if p.origin == Origin::Remote(host.clone()) && p.boot_id == entry.boot_id => {
+                p.clone()
+            }
+            _ => {
+                let p = Arc::new(Provenance {
+                    backend: SourceBackend::Remote,
+                    origin: Origin::Remote(host),
+                    // the agent doesn't say; to the aggregator its log is its host's
+                    view: View::Host,
+                    boot_id: entry.boot_id,
+                });
+                self.provenance = Some(p.clone());
+                p
+            }
+        };
+
+        Entry {
+            facility: entry.facility.and_then(LogFacility::from_u32),
+            level: entry.level.and_then(LogLevel::from_i32),
+            sequence_num: entry.sequence_num.map(|s| s as usize),
+            timestamp_from_system_start: entry.timestamp_us.map(Duration::from_micros),
+            message: entry.message,
+            timestamp_realtime: entry
+                .realtime_us
+                .filter(|us| *us >= 0)
+                .map(|us| UNIX_EPOCH + Duration::from_micros(us as u64)),
+            provenance: Some(provenance),
+            malformed: false,
+            priority: None,
+            tags: entry.tags,
+        }
+    }
+}
+
+/// Follows the agent at `endpoint` (e.g. `http://db1:50051`), from the entries
+/// currently in its buffer on. Only entries matching `filter` are sent.
+pub async fn follow_agent(
+    endpoint: &str,
+    filter: Option<proto::Filter>,
+) -> Result<AgentStream, RMesgError> {
+    let unavailable = |e: &dyn std::fmt::Display| {
+        RMesgError::BackendUnavailable(format!("Unable to follow agent {}: {}", endpoint, e))
+    };
+    let channel = tonic::transport::Endpoint::from_shared(endpoint.to_owned())
+        .map_err(|e| unavailable(&e))?
+        .connect()
+        .await
+        .map_err(|e| unavailable(&e))?;
+    let entries = KernelLogClient::new(channel)
+        .follow(proto::FollowRequest { filter })
+        .await
+        .map_err(|e| unavailable(&e.message()))?
+        .into_inner();
+
+    let agent = endpoint.to_owned();
+    let mut tagger = RemoteTagger::with_options(&agent);
+    Ok(Box::pin(entries.map(move |entry| match entry {
+        Ok(entry) => Ok(tagger.entry(entry)),
+        Err(status) => Err(RMesgError::BackendUnavailable(format!(
+            "Agent {} stopped sending: {}",
+            agent,
+            status.message()
+        ))),
+    })))
+}
+
+/// Follows every agent in `endpoints`, merged (see `merge`). Fails if any of them can't
+/// be followed.
+pub async fn follow_agents(
+    endpoints: &[String],
+    filter: Option<proto::Filter>,
+    delay: Duration,
+) -> Result<AgentStream, RMesgError> {
+    let mut streams = Vec::with_capacity(endpoints.len());
+    for endpoint in endpoints {
+        streams.push(follow_agent(endpoint, filter.clone()).await?);
+    }
+    Ok(Box::pin(merge(streams, delay)))
+}
+
+/// Holds entries for `delay` after they arrive, to pass them on in wall-clock order.
+/// Entries without a wall-clock time are ordered as of when they arrived.
+#[derive(Debug)]
+pub struct MergeBuffer {
+    delay: Duration,
+    // (ordered by, arrived at, entry), in the order they arrived
+    held: VecDeque<(SystemTime, Instant, Entry)>,
+}
+
+impl MergeBuffer {
+    pub fn with_options(delay: Duration) -> MergeBuffer {
+        MergeBuffer {
+            delay,
+            held: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, entry: Entry, now: Instant) {
+        let order = entry.timestamp_realtime.unwrap_or_else(SystemTime::now);
+        self.held.push_back((order, now, entry));
+    }
+
+    pub fn len(&self) -> usize {
+        self.held.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.held.is_empty()
+    }
+
+    /// How long until an entry is due to be passed on, if any are held.
+    pub fn next_due(&self, now: Instant) -> Option<Duration> {
+        self.held
+            .front()
+            .map(|(_, arrived, _)| (*arrived + self.delay).saturating_duration_since(now))
+    }
+
+    /// The entries due to be passed on, in wall-clock order: those held for `delay`,
+    /// along with any held for less that go before them.
+    pub fn pop_due(&mut self, now: Instant) -> Vec<Entry> {
+        let cutoff = self
+            .held
+            .iter()
+            .filter(|(_, arrived, _)| *arrived + self.delay <= now)
+            .map(|(order, _, _)| *order)
+            .max();
+        match cutoff {
+            Some(cutoff) => self.pop_until(|order| order <= cutoff),
+            None => Vec::new(),
+        }
+    }
+
+    /// Everything held, in wall-clock order.
+    pub fn drain(&mut self) -> Vec<Entry> {
+        self.pop_until(|_| true)
+    }
+
+    fn pop_until<F: Fn(SystemTime) -> bool>(&mut self, due: F) -> Vec<Entry> {
+        let (mut ready, held): (Vec<_>, Vec<_>) =
+            self.held.drain(..).partition(|(order, _, _)| due(*order));
+        self.held = held.into();
+        // stable, so entries at the same time stay in the order they arrived
+        ready.sort_by_key(|(order, _, _)| *order);
+        ready.into_iter().map(|(_, _, entry)| entry).collect()
+    }
+}
+
+/// Merges `streams` (e.g. from `follow_agent`) into one, in wall-clock order as far as a
+/// `MergeBuffer` holding entries for `delay` can make it. Errors are passed on as soon
+/// as they arrive; the merged stream ends when every stream has, with whatever is held.
+pub fn merge<S>(streams: Vec<S>, delay: Duration) -> impl Stream<Item = Result<Entry, RMesgError>>
+where
+    S: Stream<Item = Result<Entry, RMesgError>> + Send + Unpin + 'static,
+{
+    struct State<S> {
+        streams: stream::SelectAll<S>,
+        buffer: MergeBuffer,
+        ready: VecDeque<Result<Entry, RMesgError>>,
+        ended: bool,
+    }
+
+    let state = State {
+        streams: stream::select_all(streams),
+        buffer: MergeBuffer::with_options(delay),
+        ready: VecDeque::new(),
+        ended: false,
+    };
+    stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(item) = state.ready.pop_front() {
+                return Some((item, state));
+            }
+            if state.ended {
+                if state.buffer.is_empty() {
+                    return None;
+                }
+                state.ready.extend(state.buffer.drain().into_iter().map(Ok));
+                continue;
+            }
+
+            let next = match state.buffer.next_due(Instant::now()) {
+                Some(wait) => tokio::time::timeout(wait, state.streams.next()).await.ok(),
+                None => Some(state.streams.next().await),
+            };
+            match next {
+                // waited long enough for something to be due
+                None => {}
+                Some(None) => state.ended = true,
+                Some(Some(Ok(entry))) => state.buffer.push(entry, Instant::now()),
+                Some(Some(Err(e))) => state.ready.push_back(Err(e)),
+            }
+            let due = state.buffer.pop_due(Instant::now());
+            state.ready.extend(due.into_iter().map(Ok));
+        }
+    })
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(realtime_secs: u64, message: &str) -> Entry {
+        Entry {
+            message: message.to_owned(),
+            timestamp_realtime: Some(UNIX_EPOCH + Duration::from_secs(realtime_secs)),
+            ..Default::default()
+        }
+    }
+
+    fn messages(entries: Vec<Entry>) -> Vec<String> {
+        entries.into_iter().map(|e| e.message).collect()
+    }
+
+    #[test]
+    fn test_remote_tagger() {
+        let mut tagger = RemoteTagger::with_options("http://db1:50051");
+        let sent = proto::Entry {
+            timestamp_us: Some(1_500_000),
+            level: Some(proto::Level::Err as i32),
+            facility: Some(0),
+            sequence_num: Some(42),
+            subsystem: Some("nvme".to_owned()),
+            message: "nvme nvme0: I/O 12 QID 3 timeout".to_owned(),
+            host: Some("db1".to_owned()),
+            boot_id: Some("1234".to_owned()),
+            realtime_us: Some(1_700_000_000_000_000),
+            tags: vec![("team".to_owned(), "storage".to_owned())]
+                .into_iter()
+                .collect(),
+        };
+
+        let first = tagger.entry(sent.clone());
+        assert_eq!(first.level, Some(LogLevel::Error));
+        assert_eq!(first.sequence_num, Some(42));
+        assert_eq!(
+            first.timestamp_realtime,
+            Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+        );
+        let provenance = first.provenance.clone().unwrap();
+        assert_eq!(provenance.backend, SourceBackend::Remote);
+        assert_eq!(provenance.origin, Origin::Remote("db1".to_owned()));
+        assert_eq!(provenance.boot_id.as_deref(), Some("1234"));
+        assert_eq!(first.tag("team"), Some("storage"));
+        // back out the way it came in
+        assert_eq!(proto::Entry::from(&first), sent);
+
+        // the same host and boot share a provenance
+        let second = tagger.entry(sent.clone());
+        assert!(Arc::ptr_eq(
+            &provenance,
+            second.provenance.as_ref().unwrap()
+        ));
+        let rebooted = tagger.entry(proto::Entry {
+            boot_id: Some("5678".to_owned()),
+            host: None,
+            ..sent
+        });
+        let provenance = rebooted.provenance.unwrap();
+        assert_eq!(
+            provenance.origin,
+            Origin::Remote("http://db1:50051".to_owned())
+        );
+        assert_eq!(provenance.boot_id.as_deref(), Some("5678"));
+    }
+
+    #[test]
+    fn test_merge_buffer() {
+        let mut buffer = MergeBuffer::with_options(Duration::from_secs(1));
+        let start = Instant::now();
+        buffer.push(entry(20, "b"), start);
+        buffer.push(entry(30, "d"), start + Duration::from_millis(500));
+        buffer.push(entry(10, "a"), start + Duration::from_millis(600));
+        assert_eq!(buffer.next_due(start), Some(Duration::from_secs(1)));
+        assert!(buffer
+            .pop_due(start + Duration::from_millis(900))
+            .is_empty());
+
+        // "b" is due, and takes the earlier "a" with it
+        assert_eq!(
+            messages(buffer.pop_due(start + Duration::from_secs(1))),
+            vec!["a", "b"]
+        );
+        buffer.push(entry(25, "c"), start + Duration::from_millis(1100));
+        assert_eq!(messages(buffer.drain()), vec!["c", "d"]);
+        assert_eq!(buffer.next_due(start), None);
+    }
+
+    #[tokio::test]
+    async fn test_merge() {
+        let db1 = stream::iter(vec![Ok(entry(10, "db1 a")), Ok(entry(30, "db1 b"))]);
+        let db2 = stream::iter(vec![
+            Ok(entry(20, "db2 a")),
+            Err(RMesgError::BackendUnavailable("db2".to_owned())),
+            Ok(entry(40, "db2 b")),
+        ]);
+        let merged: Vec<_> = merge(vec![db1, db2], Duration::from_secs(1))
+            .collect()
+            .await;
+
+        assert!(matches!(merged[0], Err(RMesgError::BackendUnavailable(_))));
+        let merged: Vec<String> = merged
+            .into_iter()
+            .skip(1)
+            .map(|e| e.unwrap().message)
+            .collect();
+        assert_eq!(merged, vec!["db1 a", "db2 a", "db1 b", "db2 b"]);
+    }
+}
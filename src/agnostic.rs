@@ -0,0 +1,491 @@
+#[cfg(any(feature = "klogctl", feature = "kmsg"))]
+use crate::entry::Entry;
+/// Runtime-agnostic streams over the polling backends.
+///
+/// The streams in `klogctl` and `kmsgfile` sleep with tokio's timers, so they need a
+/// tokio runtime. The ones here only need a `Timer`: something that hands out futures
+/// completing after a duration, and wakes the task when they do. `ThreadTimer` does
+/// that with a plain thread, so these work on async-std, smol or a hand-rolled
+/// executor; anyone with a better timer (an embedded HAL's, or their runtime's own)
+/// can plug it in instead. Only the `futures` feature is needed, not `async`.
+///
+/// /dev/kmsg needs no timer at all: `KMsgStream` waits for records in poll(2), and
+/// yields each one as soon as it's logged. `logs_stream` uses it wherever it can.
+///
+#[cfg(any(feature = "klogctl", feature = "kmsg"))]
+use crate::error::RMesgError;
+#[cfg(feature = "klogctl")]
+use crate::klogctl::KLogEntries;
+#[cfg(feature = "kmsg")]
+use crate::kmsgfile::{self, StartPosition};
+#[cfg(feature = "kmsg")]
+use crate::parse::{self, ParseMode};
+#[cfg(any(feature = "klogctl", feature = "kmsg"))]
+use crate::provenance::{self, SourceBackend};
+
+use core::future::Future;
+use core::pin::Pin;
+use futures::task::{Context, Poll, Waker};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(any(feature = "klogctl", feature = "kmsg"))]
+use futures::stream::Stream;
+#[cfg(feature = "kmsg")]
+use std::fs::File;
+#[cfg(feature = "kmsg")]
+use std::os::unix::io::AsRawFd;
+
+/// A future completing after a while, from a `Timer`.
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Source of sleeps for the streams in this module.
+pub trait Timer {
+    /// A future that completes once `duration` has passed, waking its task then.
+    fn sleep(&self, duration: Duration) -> Sleep;
+}
+
+/// A `Timer` that needs no runtime: each sleep is a thread that wakes the task when
+/// it's up. Polls are seconds apart at most a few times a second, so a thread per
+/// sleep costs next to nothing.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ThreadTimer;
+
+impl Timer for ThreadTimer {
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(ThreadSleep {
+            deadline: Instant::now() + duration,
+            waker: None,
+        })
+    }
+}
+
+struct ThreadSleep {
+    deadline: Instant,
+    // the waker the sleeping thread wakes, shared so a re-poll can update it
+    waker: Option<Arc<Mutex<Waker>>>,
+}
+
+impl Future for ThreadSleep {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let now = Instant::now();
+        if now >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        match &self.waker {
+            Some(waker) => {
+                let mut waker = waker.lock().unwrap_or_else(|e| e.into_inner());
+                if !waker.will_wake(cx.waker()) {
+                    *waker = cx.waker().clone();
+                }
+            }
+            None => {
+                let waker = Arc::new(Mutex::new(cx.waker().clone()));
+                let remaining = self.deadline - now;
+                let shared = waker.clone();
+                thread::spawn(move || {
+                    thread::sleep(remaining);
+                    shared
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .wake_by_ref();
+                });
+                self.waker = Some(waker);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// Follows the buffer through klogctl on any executor: `KLogEntries`' polling, with
+/// the waits in between on a `Timer` rather than tokio.
+#[cfg(feature = "klogctl")]
+pub struct KLogStream<T: Timer = ThreadTimer> {
+    entries: KLogEntries,
+    timer: T,
+    sleep: Option<Sleep>,
+}
+
+#[cfg(feature = "klogctl")]
+impl<T: Timer> KLogStream<T> {
+    pub fn with_options(entries: KLogEntries, timer: T) -> KLogStream<T> {
+        KLogStream {
+            entries,
+            timer,
+            sleep: None,
+        }
+    }
+}
+
+#[cfg(feature = "klogctl")]
+impl<T: Timer + Unpin> Stream for KLogStream<T> {
+    type Item = Result<Entry, RMesgError>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(sleep) = self.sleep.as_mut() {
+                match sleep.as_mut().poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(()) => self.sleep = None,
+                }
+            }
+
+            match self.entries.next_ready() {
+                Ok(Some(entry)) => {
+                    return Poll::Ready(Some(Ok(provenance::tag(entry, SourceBackend::KLogCtl))))
+                }
+                Ok(None) => {
+                    let interval = self.entries.sleep_interval();
+                    self.sleep = Some(self.timer.sleep(interval));
+                }
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            }
+        }
+    }
+}
+
+/// Follows the buffer through klogctl (with adaptive polling, as `rmesg::logs_stream`
+/// does), on any executor, sleeping on a `ThreadTimer`.
+#[cfg(feature = "klogctl")]
+pub fn klog_stream(clear: bool) -> Result<KLogStream, RMesgError> {
+    Ok(KLogStream::with_options(
+        crate::klog_entries_only_if_timestamp_enabled(clear)?,
+        ThreadTimer,
+    ))
+}
+
+// The kernel caps a single /dev/kmsg record (including its dictionary) at a little under
+// 8KiB; a smaller read fails with EINVAL
+#[cfg(feature = "kmsg")]
+const RECORD_CAPACITY: usize = 8192;
+
+/// Follows /dev/kmsg on any executor, without polling: records are read as they're
+/// logged, and while there's none the wait is in poll(2), on a thread that wakes the
+/// task as soon as there is. Dictionary lines are skipped.
+#[cfg(feature = "kmsg")]
+pub struct KMsgStream {
+    file: File,
+    raw: bool,
+    mode: ParseMode,
+    // read but not yet split into lines (a regular file, unlike /dev/kmsg, hands over
+    // more than one record per read)
+    pending: Vec<u8>,
+    // the waker the waiting thread is to wake, taken by it when it does
+    waiting: Option<Arc<Mutex<Option<Waker>>>>,
+    ended: bool,
+}
+
+#[cfg(feature = "kmsg")]
+impl KMsgStream {
+    /// Create a new KMsgStream
+    /// `file_override`: When `Some`, overrides the path from where to read the kernel logs
+    /// `raw: bool` When set, does not parse the message and instead sets the entire log entry in the "message" field
+    pub fn with_options(
+        file_override: Option<String>,
+        raw: bool,
+    ) -> Result<KMsgStream, RMesgError> {
+        let path = file_override.as_deref().unwrap_or(kmsgfile::DEV_KMSG_PATH);
+        let file = kmsgfile::open_at(path, StartPosition::Oldest)?;
+        let fd = file.as_raw_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        Ok(KMsgStream {
+            file,
+            raw,
+            mode: ParseMode::Strict,
+            pending: Vec::new(),
+            waiting: None,
+            ended: false,
+        })
+    }
+
+    /// Handles malformed records as `mode` says (by default, `ParseMode::Strict`).
+    pub fn with_parse_mode(mut self, mode: ParseMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    // The next line, reading more when there's no whole one pending. `Ok(None)` means
+    // there's nothing to read yet, or (with `ended` set) ever.
+    fn next_line(&mut self) -> Result<Option<String>, RMesgError> {
+        loop {
+            if let Some(newline) = self.pending.iter().position(|b| *b == b'\n') {
+                let mut line = String::from_utf8(self.pending.drain(..=newline).collect())?;
+                line.pop();
+                if line.starts_with(' ') {
+                    continue;
+                }
+                return Ok(Some(line));
+            }
+
+            let start = self.pending.len();
+            self.pending.resize(start + RECORD_CAPACITY, 0);
+            let n = unsafe {
+                libc::read(
+                    self.file.as_raw_fd(),
+                    self.pending[start..].as_mut_ptr() as *mut libc::c_void,
+                    RECORD_CAPACITY,
+                )
+            };
+            self.pending.truncate(start + n.max(0) as usize);
+            if n > 0 {
+                continue;
+            }
+            if n == 0 {
+                self.ended = true;
+                return Ok(None);
+            }
+            let e = std::io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(libc::EAGAIN) => return Ok(None),
+                // EPIPE included: records were overwritten before they could be read, and
+                // the next read carries on from the oldest one left
+                _ => {
+                    return Err(RMesgError::IOError(format!(
+                        "Error reading next record from kernel log device file: {}",
+                        e
+                    )))
+                }
+            }
+        }
+    }
+
+    // Has the task woken once there's something to read: by the thread already waiting
+    // for it, if there's one still, or by a new one
+    fn wait(&mut self, waker: &Waker) -> Result<(), RMesgError> {
+        if let Some(waiting) = &self.waiting {
+            let mut slot = waiting.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(current) = slot.as_mut() {
+                if !current.will_wake(waker) {
+                    *current = waker.clone();
+                }
+                return Ok(());
+            }
+        }
+
+        // a descriptor of its own, so that this one being closed (and its number reused)
+        // when the stream is dropped can't leave the thread waiting on something else
+        let file = self.file.try_clone()?;
+        let waiting = Arc::new(Mutex::new(Some(waker.clone())));
+        let shared = waiting.clone();
+        thread::spawn(move || {
+            wait_readable(&file);
+            let waker = shared.lock().unwrap_or_else(|e| e.into_inner()).take();
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        });
+        self.waiting = Some(waiting);
+        Ok(())
+    }
+}
+
+// Blocks until `file` can be read. Any outcome but an interruption is worth trying a
+// read for: POLLERR, for one, is records having been overwritten, which the read reports.
+#[cfg(feature = "kmsg")]
+fn wait_readable(file: &File) {
+    let mut pollfd = libc::pollfd {
+        fd: file.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    while unsafe { libc::poll(&mut pollfd, 1, -1) } < 0
+        && std::io::Error::last_os_error().raw_os_error() == Some(libc::EINTR)
+    {}
+}
+
+#[cfg(feature = "kmsg")]
+impl Stream for KMsgStream {
+    type Item = Result<Entry, RMesgError>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let line = match self.next_line() {
+            Ok(Some(line)) => line,
+            Ok(None) if self.ended => return Poll::Ready(None),
+            Ok(None) => {
+                return match self.wait(cx.waker()) {
+                    Ok(()) => Poll::Pending,
+                    Err(e) => Poll::Ready(Some(Err(e))),
+                }
+            }
+            Err(e) => return Poll::Ready(Some(Err(e))),
+        };
+
+        let entry = match self.raw {
+            true => Entry {
+                facility: None,
+                level: None,
+                sequence_num: None,
+                timestamp_from_system_start: None,
+                message: line,
+                timestamp_realtime: None,
+                provenance: None,
+                malformed: false,
+                priority: None,
+                tags: Default::default(),
+            },
+            false => match parse::kmsg_entry(&line, self.mode) {
+                Ok(entry) => entry,
+                Err(e) => return Poll::Ready(Some(Err(e.into()))),
+            },
+        };
+        Poll::Ready(Some(Ok(provenance::tag(entry, SourceBackend::DevKMsg))))
+    }
+}
+
+/// Either of the streams here, as `logs_stream` picks.
+#[cfg(any(feature = "klogctl", feature = "kmsg"))]
+pub enum EntriesStream {
+    #[cfg(feature = "kmsg")]
+    DevKMsg(KMsgStream),
+    #[cfg(feature = "klogctl")]
+    KLogCtl(KLogStream),
+}
+
+#[cfg(any(feature = "klogctl", feature = "kmsg"))]
+impl Stream for EntriesStream {
+    type Item = Result<Entry, RMesgError>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.get_mut() {
+            #[cfg(feature = "kmsg")]
+            EntriesStream::DevKMsg(stream) => Pin::new(stream).poll_next(cx),
+            #[cfg(feature = "klogctl")]
+            EntriesStream::KLogCtl(stream) => Pin::new(stream).poll_next(cx),
+        }
+    }
+}
+
+/// Follows the kernel log on any executor: through /dev/kmsg, with no polling, where
+/// it can be opened (and the buffer isn't to be cleared, which only klogctl can do),
+/// and otherwise by polling klogctl (see `klog_stream`).
+#[cfg(any(feature = "klogctl", feature = "kmsg"))]
+pub fn logs_stream(
+    clear: bool,
+    #[allow(unused_variables)] raw: bool,
+) -> Result<EntriesStream, RMesgError> {
+    #[cfg(feature = "kmsg")]
+    if !clear {
+        match KMsgStream::with_options(None, raw) {
+            Ok(stream) => return Ok(EntriesStream::DevKMsg(stream)),
+            #[cfg(feature = "klogctl")]
+            Err(RMesgError::DevKMsgFileOpenError(s)) => {
+                eprintln!(
+                    "Falling back from device file to klogctl due to error: {}",
+                    s
+                );
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    #[cfg(feature = "klogctl")]
+    let stream = Ok(EntriesStream::KLogCtl(klog_stream(clear)?));
+    #[cfg(not(feature = "klogctl"))]
+    let stream = Err(RMesgError::BackendUnavailable(
+        "Clearing the buffer needs klogctl (enable the klogctl feature)".to_owned(),
+    ));
+    stream
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::executor::block_on;
+    #[cfg(feature = "kmsg")]
+    use futures::stream::StreamExt;
+
+    #[test]
+    fn test_thread_timer() {
+        let start = Instant::now();
+        block_on(ThreadTimer.sleep(Duration::from_millis(50)));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // a sleep that's already up doesn't need waking
+        block_on(ThreadTimer.sleep(Duration::from_secs(0)));
+    }
+
+    #[cfg(all(feature = "klogctl", target_os = "linux"))]
+    #[test]
+    fn test_klog_stream() {
+        use crate::interval::PollInterval;
+        use futures::stream::StreamExt;
+
+        // klogctl needs privileges the test may not have
+        let entries = match KLogEntries::with_options(false, PollInterval::from_millis(10).unwrap())
+        {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        let mut stream = KLogStream::with_options(entries, ThreadTimer);
+        match block_on(stream.next()) {
+            Some(Ok(entry)) => assert_eq!(
+                entry.provenance.map(|p| p.backend),
+                Some(SourceBackend::KLogCtl)
+            ),
+            Some(Err(e)) => println!("klogctl unavailable: {}", e),
+            None => panic!("the stream ended"),
+        }
+    }
+
+    #[cfg(feature = "kmsg")]
+    #[test]
+    fn test_kmsg_stream_file() {
+        let path = std::env::temp_dir().join(format!("rmesg-agnostic-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "6,1,0,-;Linux version 5.10.0\n SUBSYSTEM=acpi\n4,2,1500000,-;ACPI: tables loaded\n",
+        )
+        .unwrap();
+        let stream = KMsgStream::with_options(Some(path.to_string_lossy().into_owned()), false);
+        std::fs::remove_file(&path).unwrap();
+
+        let entries: Vec<Entry> = block_on(stream.unwrap().map(|e| e.unwrap()).collect());
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].sequence_num, Some(2));
+        assert_eq!(entries[1].message, "ACPI: tables loaded");
+        assert_eq!(
+            entries[0].provenance.as_ref().map(|p| p.backend),
+            Some(SourceBackend::DevKMsg)
+        );
+    }
+
+    #[cfg(all(feature = "kmsg", target_os = "linux"))]
+    #[test]
+    fn test_kmsg_stream_wakes() {
+        use std::io::Write;
+
+        // writing to /dev/kmsg needs privileges the test may not have
+        let mut kmsg = match std::fs::OpenOptions::new().write(true).open("/dev/kmsg") {
+            Ok(kmsg) => kmsg,
+            Err(_) => return,
+        };
+        let mut stream = KMsgStream::with_options(None, false).unwrap();
+        let marker = format!("rmesg-agnostic-wake-{}", std::process::id());
+
+        // logged once the stream has caught up and is waiting
+        let message = marker.clone();
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            kmsg.write_all(format!("<6>{}\n", message).as_bytes())
+                .unwrap();
+        });
+        let found = block_on(async {
+            while let Some(entry) = stream.next().await {
+                if entry.is_ok_and(|e| e.message == marker) {
+                    return true;
+                }
+            }
+            false
+        });
+        writer.join().unwrap();
+        assert!(found);
+    }
+}
@@ -0,0 +1,253 @@
+use crate::entry::Entry;
+use crate::error::RMesgError;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+lazy_static! {
+    // PCI addresses (0000:00:1f.3), numbers (12, -71), hex values (0x1f) and long hex
+    // values such as addresses (ffff8880), but not names like nvme0, EXT4 or e1000e
+    static ref RE_VARIABLE: Regex = Regex::new(
+        r"\b(?:[[:xdigit:]]{4}:)?[[:xdigit:]]{2}:[[:xdigit:]]{2}\.[[:xdigit:]]\b|\b(?:[[:digit:]]+|0x[[:xdigit:]]+|[[:xdigit:]]{8,})\b"
+    )
+    .unwrap();
+}
+
+/// The template of a message: the message with its numbers, hex values and addresses
+/// replaced by `*`, so that messages differing only in those compare equal. Templates
+/// use the same syntax as `Suppression::template`.
+///
+/// For example "usb 1-1: new high-speed USB device number 2 using xhci_hcd" becomes
+/// "usb *-*: new high-speed USB device number * using xhci_hcd".
+pub fn template(message: &str) -> String {
+    RE_VARIABLE
+        .replace_all(message, "*")
+        .replace(|c: char| c.is_control(), " ")
+}
+
+/// A template and how many messages had it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TemplateCount {
+    pub template: String,
+    pub count: usize,
+}
+
+/// A template seen in the compared snapshot but not in the baseline.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NewTemplate {
+    pub template: String,
+    pub count: usize,
+    /// The first entry with this template
+    pub first: Entry,
+}
+
+/// The difference between a snapshot and a baseline, by message template.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BaselineComparison {
+    /// Templates now seen that the baseline didn't have, in the order first seen
+    pub new: Vec<NewTemplate>,
+    /// Templates the baseline had that are no longer seen, in template order
+    pub missing: Vec<TemplateCount>,
+}
+
+impl BaselineComparison {
+    pub fn is_empty(&self) -> bool {
+        self.new.is_empty() && self.missing.is_empty()
+    }
+}
+
+/// The message templates of a known-good boot, to compare later boots against (e.g.
+/// after a kernel or firmware update) for messages that are new or have gone missing.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Baseline {
+    pub(crate) templates: BTreeMap<String, usize>,
+}
+
+impl Baseline {
+    /// A baseline of the templates in `entries` (usually a snapshot covering boot, as
+    /// returned by `log_entries`).
+    pub fn from_entries(entries: &[Entry]) -> Baseline {
+        let mut templates = BTreeMap::new();
+        for entry in entries {
+            *templates.entry(template(&entry.message)).or_insert(0) += 1;
+        }
+        Baseline { templates }
+    }
+
+    /// Every template in the baseline, in template order, with how many messages had it.
+    pub fn templates(&self) -> impl Iterator<Item = TemplateCount> + '_ {
+        self.templates
+            .iter()
+            .map(|(template, count)| TemplateCount {
+                template: template.clone(),
+                count: *count,
+            })
+    }
+
+    pub fn contains(&self, template: &str) -> bool {
+        self.templates.contains_key(template)
+    }
+
+    pub fn len(&self) -> usize {
+        self.templates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.templates.is_empty()
+    }
+
+    /// Compares the templates in `entries` against this baseline.
+    pub fn compare(&self, entries: &[Entry]) -> BaselineComparison {
+        let mut present: HashSet<String> = HashSet::new();
+        // index into `new` of each new template
+        let mut new_index: HashMap<String, usize> = HashMap::new();
+        let mut new: Vec<NewTemplate> = Vec::new();
+
+        for entry in entries {
+            let template = template(&entry.message);
+            if self.templates.contains_key(&template) {
+                present.insert(template);
+                continue;
+            }
+            match new_index.get(&template) {
+                Some(i) => new[*i].count += 1,
+                None => {
+                    new_index.insert(template.clone(), new.len());
+                    new.push(NewTemplate {
+                        template,
+                        count: 1,
+                        first: entry.clone(),
+                    });
+                }
+            }
+        }
+
+        let missing = self
+            .templates()
+            .filter(|t| !present.contains(&t.template))
+            .collect();
+
+        BaselineComparison { new, missing }
+    }
+
+    /// Serializes the baseline, one `count<TAB>template` line per template, e.g. to
+    /// save to a file.
+    pub fn to_text(&self) -> String {
+        self.templates
+            .iter()
+            .map(|(template, count)| format!("{}\t{}\n", count, template))
+            .collect()
+    }
+
+    /// Parses a baseline written by `to_text`. Blank lines and lines starting with
+    /// `#` are ignored.
+    pub fn from_text(text: &str) -> Result<Baseline, RMesgError> {
+        let mut templates = BTreeMap::new();
+        for (number, line) in text.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                RMesgError::ConfigError(format!(
+                    "Invalid baseline line {}: {} (expected a count, a tab and a template)",
+                    number + 1,
+                    line
+                ))
+            };
+            let (count, template) = line.split_once('\t').ok_or_else(invalid)?;
+            let count: usize = count.trim().parse().map_err(|_| invalid())?;
+            *templates.entry(template.to_owned()).or_insert(0) += count;
+        }
+        Ok(Baseline { templates })
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entry::entry;
+
+    #[test]
+    fn test_template() {
+        assert_eq!(
+            template("usb 1-1: new high-speed USB device number 2 using xhci_hcd"),
+            "usb *-*: new high-speed USB device number * using xhci_hcd"
+        );
+        assert_eq!(
+            template("nvme nvme0: I/O 12 QID 3 timeout, completion polled"),
+            "nvme nvme0: I/O * QID * timeout, completion polled"
+        );
+        assert_eq!(
+            template("EXT4-fs (sda1): mounted filesystem at 0xffff8880 error -71"),
+            "EXT4-fs (sda1): mounted filesystem at * error -*"
+        );
+        assert_eq!(
+            template("pci 0000:00:1f.3: [8086:a348] type 00"),
+            "pci *: [*:a348] type *"
+        );
+    }
+
+    #[test]
+    fn test_compare() {
+        let good = vec![
+            entry("Linux version 5.10.0"),
+            entry("usb 1-1: new high-speed USB device number 2 using xhci_hcd"),
+            entry("usb 1-2: new high-speed USB device number 3 using xhci_hcd"),
+            entry("e1000e 0000:00:1f.6 eth0: NIC Link is Up 1000 Mbps Full Duplex"),
+        ];
+        let baseline = Baseline::from_entries(&good);
+        assert_eq!(baseline.len(), 3);
+        assert!(baseline.compare(&good).is_empty());
+
+        let updated = vec![
+            entry("Linux version 5.15.0"),
+            entry("usb 1-1: new high-speed USB device number 4 using xhci_hcd"),
+            entry("ACPI Error: AE_NOT_FOUND, While resolving a named reference package element"),
+            entry("ACPI Error: AE_NOT_FOUND, While resolving a named reference package element"),
+        ];
+        let comparison = baseline.compare(&updated);
+        assert_eq!(
+            comparison
+                .new
+                .iter()
+                .map(|t| (t.template.as_str(), t.count))
+                .collect::<Vec<_>>(),
+            vec![(
+                "ACPI Error: AE_NOT_FOUND, While resolving a named reference package element",
+                2
+            )]
+        );
+        assert_eq!(comparison.new[0].first, updated[2]);
+        assert_eq!(
+            comparison.missing,
+            vec![TemplateCount {
+                template: "e1000e * eth0: NIC Link is Up * Mbps Full Duplex".to_owned(),
+                count: 1,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_text_round_trip() {
+        let baseline = Baseline::from_entries(&[
+            entry("usb 1-1: new high-speed USB device number 2 using xhci_hcd"),
+            entry("usb 1-2: new high-speed USB device number 3 using xhci_hcd"),
+            entry("tab\tin message"),
+        ]);
+        let text = baseline.to_text();
+        assert_eq!(
+            text,
+            "1\ttab in message\n2\tusb *-*: new high-speed USB device number * using xhci_hcd\n"
+        );
+        assert_eq!(
+            Baseline::from_text(&format!("# saved baseline\n\n{}", text)).unwrap(),
+            baseline
+        );
+        assert!(Baseline::from_text("no count here").is_err());
+        assert!(Baseline::from_text("x\ttemplate").is_err());
+    }
+}
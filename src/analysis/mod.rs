@@ -0,0 +1,12 @@
+//! Analyses computed over a snapshot of log entries.
+//!
+//! Unlike `events`, which turns single entries into typed records, each analysis here
+//! looks at a whole snapshot (usually one covering boot, as returned by `log_entries`)
+//! and relates entries to each other through their timestamps.
+
+/// Comparison of a boot's message templates against a known-good baseline
+pub mod baseline;
+/// Device probe and initcall durations
+pub mod probes;
+/// Timeline of the major phases of boot
+pub mod timeline;
@@ -0,0 +1,244 @@
+use crate::analysis::timeline::{BootPhase, BootTimeline};
+use crate::entry::Entry;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::HashMap;
+use std::time::Duration;
+
+lazy_static! {
+    // initcall_debug, older kernels: probe of 0000:00:1f.3 returned 0 after 12345 usecs
+    static ref RE_PROBE_OF: Regex = Regex::new(
+        r"probe of (?P<device>[^[:space:]]+) returned (?P<ret>-?[[:digit:]]+) after (?P<usecs>[[:digit:]]+) usecs"
+    )
+    .unwrap();
+
+    // initcall_debug, newer kernels: pci 0000:00:1f.3: probe with driver snd_hda_intel returned 0 after 12345 usecs
+    static ref RE_PROBE_WITH: Regex = Regex::new(
+        r"^(?P<device>.+?): probe with driver (?P<driver>[^[:space:]]+) returned (?P<ret>-?[[:digit:]]+) after (?P<usecs>[[:digit:]]+) usecs"
+    )
+    .unwrap();
+
+    // initcall_debug: initcall usb_init+0x0/0x1a4 returned 0 after 1234 usecs
+    static ref RE_INITCALL: Regex = Regex::new(
+        r"^initcall (?P<function>[^[:space:]+]+)(?:\+[^[:space:]]+)? returned (?P<ret>-?[[:digit:]]+) after (?P<usecs>[[:digit:]]+) usecs"
+    )
+    .unwrap();
+
+    // driver core debug: bus: 'pci': really_probe: probing driver e1000e with device 0000:00:1f.6
+    static ref RE_PROBING: Regex = Regex::new(
+        r"^bus: '[^']+': [[:word:]]+: probing driver (?P<driver>[^[:space:]]+) with device (?P<device>[^[:space:]]+)"
+    )
+    .unwrap();
+
+    // driver core debug: bus: 'pci': driver_bound: bound device 0000:00:1f.6 to driver e1000e
+    //                or: driver: 'e1000e': driver_bound: bound to device '0000:00:1f.6'
+    static ref RE_BOUND: Regex = Regex::new(
+        r"^(?:bus: '[^']+': [[:word:]]+: bound device (?P<device>[^[:space:]]+) to driver (?P<driver>[^[:space:]]+)|driver: '(?P<driver2>[^']+)': [[:word:]]+: bound to device '(?P<device2>[^']+)')"
+    )
+    .unwrap();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProbeKind {
+    /// A driver probing a device
+    Device,
+    /// A kernel or module initcall (only reported with initcall_debug)
+    Initcall,
+}
+
+/// How long one device probe or initcall took.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProbeLatency {
+    pub kind: ProbeKind,
+    /// The device probed, or the initcall function
+    pub target: String,
+    /// The driver probing the device, when reported
+    pub driver: Option<String>,
+    /// The return value of the probe/initcall, when reported
+    pub result: Option<i32>,
+    /// When the probe started (time since system start), when known
+    pub started: Option<Duration>,
+    pub duration: Duration,
+    /// The boot phase the probe started in
+    pub phase: Option<BootPhase>,
+}
+
+/// Every device probe and initcall duration found in `entries`, in the order they completed.
+///
+/// Durations are taken from initcall_debug output ("... returned 0 after 1234 usecs")
+/// when the kernel was booted with it. Otherwise probes are timed by pairing the
+/// driver core's "probing driver X with device Y" and "bound device Y to driver X"
+/// debug messages, which requires dynamic debug to be enabled for drivers/base/dd.c.
+/// Probes that never bound (because they failed) can't be timed this way.
+pub fn probe_latencies(entries: &[Entry]) -> Vec<ProbeLatency> {
+    let timeline = BootTimeline::from_entries(entries);
+    let mut latencies: Vec<ProbeLatency> = Vec::new();
+    let mut probing: HashMap<String, (String, Option<Duration>)> = HashMap::new();
+
+    for entry in entries {
+        let message = entry.message.trim_start();
+        let reported = if let Some(parts) = RE_INITCALL.captures(message) {
+            Some((
+                ProbeKind::Initcall,
+                parts["function"].to_owned(),
+                None,
+                parts,
+            ))
+        } else if let Some(parts) = RE_PROBE_WITH.captures(message) {
+            let driver = Some(parts["driver"].to_owned());
+            Some((ProbeKind::Device, parts["device"].to_owned(), driver, parts))
+        } else {
+            RE_PROBE_OF
+                .captures(message)
+                .map(|parts| (ProbeKind::Device, parts["device"].to_owned(), None, parts))
+        };
+
+        if let Some((kind, target, driver, parts)) = reported {
+            let duration = match parts["usecs"].parse() {
+                Ok(usecs) => Duration::from_micros(usecs),
+                Err(_) => continue,
+            };
+            let started = entry
+                .timestamp_from_system_start
+                .map(|t| t.saturating_sub(duration));
+            latencies.push(ProbeLatency {
+                kind,
+                target,
+                driver,
+                result: parts["ret"].parse().ok(),
+                started,
+                duration,
+                phase: started.and_then(|s| timeline.phase_at(s)),
+            });
+        } else if let Some(parts) = RE_PROBING.captures(message) {
+            probing.insert(
+                parts["device"].to_owned(),
+                (
+                    parts["driver"].to_owned(),
+                    entry.timestamp_from_system_start,
+                ),
+            );
+        } else if let Some(parts) = RE_BOUND.captures(message) {
+            let device = parts.name("device").or_else(|| parts.name("device2"));
+            let (driver, started) = match device.and_then(|d| probing.remove(d.as_str())) {
+                Some(probe) => probe,
+                None => continue,
+            };
+            let (started, ended) = match (started, entry.timestamp_from_system_start) {
+                (Some(started), Some(ended)) => (started, ended),
+                _ => continue,
+            };
+            latencies.push(ProbeLatency {
+                kind: ProbeKind::Device,
+                target: device.map(|d| d.as_str().to_owned()).unwrap_or_default(),
+                driver: Some(driver),
+                result: Some(0),
+                started: Some(started),
+                duration: ended.saturating_sub(started),
+                phase: timeline.phase_at(started),
+            });
+        }
+    }
+
+    latencies
+}
+
+/// The `count` slowest device probes in `entries`, slowest first.
+pub fn slowest_probes(entries: &[Entry], count: usize) -> Vec<ProbeLatency> {
+    let mut probes: Vec<ProbeLatency> = probe_latencies(entries)
+        .into_iter()
+        .filter(|p| p.kind == ProbeKind::Device)
+        .collect();
+    probes.sort_by_key(|p| std::cmp::Reverse(p.duration));
+    probes.truncate(count);
+    probes
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entries(messages: &[(u64, &str)]) -> Vec<Entry> {
+        messages
+            .iter()
+            .map(|(millis, m)| Entry {
+                timestamp_from_system_start: Some(Duration::from_millis(*millis)),
+                message: (*m).to_owned(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_initcall_debug() {
+        let latencies = probe_latencies(&entries(&[
+            (0, "Linux version 5.10.0"),
+            (100, "PCI: Using configuration type 1 for base access"),
+            (110, "calling  usb_init+0x0/0x1a4 @ 1"),
+            (
+                112,
+                "initcall usb_init+0x0/0x1a4 returned 0 after 2000 usecs",
+            ),
+            (300, "probe of 0000:00:1f.3 returned 0 after 150000 usecs"),
+            (
+                400,
+                "pci 0000:00:1f.6: probe with driver e1000e returned -19 after 5000 usecs",
+            ),
+        ]));
+        assert_eq!(latencies.len(), 3);
+
+        assert_eq!(latencies[0].kind, ProbeKind::Initcall);
+        assert_eq!(latencies[0].target, "usb_init");
+        assert_eq!(latencies[0].duration, Duration::from_millis(2));
+        assert_eq!(latencies[0].started, Some(Duration::from_millis(110)));
+        assert_eq!(latencies[0].phase, Some(BootPhase::DriverProbes));
+
+        assert_eq!(latencies[1].target, "0000:00:1f.3");
+        assert_eq!(latencies[1].started, Some(Duration::from_millis(150)));
+
+        assert_eq!(latencies[2].target, "pci 0000:00:1f.6");
+        assert_eq!(latencies[2].driver.as_deref(), Some("e1000e"));
+        assert_eq!(latencies[2].result, Some(-19));
+    }
+
+    #[test]
+    fn test_paired_probes() {
+        let snapshot = entries(&[
+            (
+                100,
+                "bus: 'pci': really_probe: probing driver e1000e with device 0000:00:1f.6",
+            ),
+            (
+                105,
+                "bus: 'usb': really_probe: probing driver hub with device 1-0:1.0",
+            ),
+            (
+                110,
+                "driver: 'hub': driver_bound: bound to device '1-0:1.0'",
+            ),
+            (
+                350,
+                "bus: 'pci': driver_bound: bound device 0000:00:1f.6 to driver e1000e",
+            ),
+            (
+                400,
+                "bus: 'pci': really_probe: probing driver nvme with device 0000:01:00.0",
+            ),
+        ]);
+
+        let latencies = probe_latencies(&snapshot);
+        assert_eq!(latencies.len(), 2);
+        assert_eq!(latencies[0].target, "1-0:1.0");
+        assert_eq!(latencies[0].duration, Duration::from_millis(5));
+
+        let slowest = slowest_probes(&snapshot, 1);
+        assert_eq!(slowest.len(), 1);
+        assert_eq!(slowest[0].target, "0000:00:1f.6");
+        assert_eq!(slowest[0].driver.as_deref(), Some("e1000e"));
+        assert_eq!(slowest[0].duration, Duration::from_millis(250));
+    }
+}
@@ -0,0 +1,245 @@
+use crate::entry::Entry;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::fmt::Display;
+use std::time::Duration;
+
+lazy_static! {
+    static ref RE_EARLYCON: Regex = Regex::new(r"^(?:earlycon:|printk: bootconsole \[|bootconsole \[)").unwrap();
+    static ref RE_ACPI: Regex = Regex::new(r"^ACPI:").unwrap();
+    static ref RE_DRIVER_PROBES: Regex =
+        Regex::new(r"^(?:PCI: |pci [[:xdigit:]]{4}:[[:xdigit:]]{2}:|usbcore: registered new)").unwrap();
+    static ref RE_ROOTFS_MOUNT: Regex = Regex::new(
+        r"^(?:VFS: Mounted root|EXT4-fs \([^)]+\): mounted filesystem|XFS \([^)]+\): Ending clean mount|BTRFS info \([^)]+\): (?:enabling|using))"
+    )
+    .unwrap();
+    static ref RE_INIT: Regex = Regex::new(r"^(?:Run [^[:space:]]+ as init process|systemd\[1\]:)").unwrap();
+}
+
+/// The major phases of boot, in the order they happen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum BootPhase {
+    /// From the first message (usually "Linux version ...")
+    Kernel,
+    /// The early console was set up
+    Earlycon,
+    /// ACPI tables are being parsed and the ACPI subsystem initialized
+    Acpi,
+    /// Buses are enumerated and device drivers probe
+    DriverProbes,
+    /// The root filesystem was mounted
+    RootfsMount,
+    /// Userspace init was started
+    Init,
+}
+
+impl Display for BootPhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Kernel => "kernel",
+                Self::Earlycon => "earlycon",
+                Self::Acpi => "acpi",
+                Self::DriverProbes => "driver probes",
+                Self::RootfsMount => "rootfs mount",
+                Self::Init => "init",
+            }
+        )
+    }
+}
+
+/// When a boot phase started, and when the next one started.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PhaseSpan {
+    pub phase: BootPhase,
+    /// The timestamp of the entry that marked the start of this phase
+    pub start: Duration,
+    /// The start of the next phase found; None for the last one
+    pub end: Option<Duration>,
+}
+
+impl PhaseSpan {
+    pub fn duration(&self) -> Option<Duration> {
+        self.end.map(|end| end.saturating_sub(self.start))
+    }
+}
+
+/// The phases found in a boot snapshot.
+///
+/// Each phase starts at the first entry recognized as belonging to it; phases that
+/// can't be found (because that hardware or early console isn't there, or the
+/// messages were already overwritten) are left out, and the previous phase runs until
+/// the next one found. Entries without a timestamp are ignored.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BootTimeline {
+    /// Phases found, in boot order
+    pub phases: Vec<PhaseSpan>,
+}
+
+impl BootTimeline {
+    pub fn from_entries(entries: &[Entry]) -> BootTimeline {
+        let mut starts: Vec<(BootPhase, Duration)> = Vec::new();
+
+        for entry in entries {
+            let timestamp = match entry.timestamp_from_system_start {
+                Some(timestamp) => timestamp,
+                None => continue,
+            };
+            let phase = match phase_started_by(entry.message.trim_start()) {
+                Some(phase) => phase,
+                None if starts.is_empty() => BootPhase::Kernel,
+                None => continue,
+            };
+
+            // phases are recorded once, in order: anything matching an earlier phase
+            // after a later one started (e.g. ACPI messages during driver probes) is
+            // part of the later one
+            if starts.last().is_none_or(|(last, _)| phase > *last) {
+                if starts.is_empty() && phase != BootPhase::Kernel {
+                    starts.push((BootPhase::Kernel, timestamp));
+                }
+                starts.push((phase, timestamp));
+            }
+        }
+
+        let phases = starts
+            .iter()
+            .enumerate()
+            .map(|(i, (phase, start))| PhaseSpan {
+                phase: *phase,
+                start: *start,
+                end: starts.get(i + 1).map(|(_, next)| *next),
+            })
+            .collect();
+
+        BootTimeline { phases }
+    }
+
+    pub fn phase(&self, phase: BootPhase) -> Option<&PhaseSpan> {
+        self.phases.iter().find(|span| span.phase == phase)
+    }
+
+    /// The phase that was running at `timestamp` (time since system start).
+    pub fn phase_at(&self, timestamp: Duration) -> Option<BootPhase> {
+        self.phases
+            .iter()
+            .rev()
+            .find(|span| span.start <= timestamp)
+            .map(|span| span.phase)
+    }
+
+    /// Time from the first entry until userspace init was started.
+    pub fn time_to_init(&self) -> Option<Duration> {
+        let first = self.phases.first()?;
+        let init = self.phase(BootPhase::Init)?;
+        Some(init.start.saturating_sub(first.start))
+    }
+}
+
+fn phase_started_by(message: &str) -> Option<BootPhase> {
+    if RE_EARLYCON.is_match(message) {
+        Some(BootPhase::Earlycon)
+    } else if RE_ACPI.is_match(message) {
+        Some(BootPhase::Acpi)
+    } else if RE_DRIVER_PROBES.is_match(message) {
+        Some(BootPhase::DriverProbes)
+    } else if RE_ROOTFS_MOUNT.is_match(message) {
+        Some(BootPhase::RootfsMount)
+    } else if RE_INIT.is_match(message) {
+        Some(BootPhase::Init)
+    } else {
+        None
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entries(messages: &[(u64, &str)]) -> Vec<Entry> {
+        messages
+            .iter()
+            .map(|(millis, m)| Entry {
+                timestamp_from_system_start: Some(Duration::from_millis(*millis)),
+                message: (*m).to_owned(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_full_boot() {
+        let timeline = BootTimeline::from_entries(&entries(&[
+            (0, "Linux version 5.10.0 (gcc version 10.2.1)"),
+            (0, "Command line: BOOT_IMAGE=/vmlinuz root=/dev/sda1"),
+            (5, "earlycon: uart8250 at I/O port 0x3f8 (options '')"),
+            (10, "ACPI: Early table checksum verification disabled"),
+            (12, "ACPI: RSDP 0x00000000000F0490 000024 (v02 BOCHS )"),
+            (200, "PCI: Using configuration type 1 for base access"),
+            (
+                210,
+                "ACPI: PCI Root Bridge [PCI0] (domain 0000 [bus 00-ff])",
+            ),
+            (
+                900,
+                "EXT4-fs (sda1): mounted filesystem with ordered data mode. Opts: (null)",
+            ),
+            (
+                950,
+                "VFS: Mounted root (ext4 filesystem) readonly on device 8:1.",
+            ),
+            (1100, "Run /sbin/init as init process"),
+            (1500, "systemd[1]: Started Journal Service."),
+        ]));
+
+        let phases: Vec<BootPhase> = timeline.phases.iter().map(|s| s.phase).collect();
+        assert_eq!(
+            phases,
+            vec![
+                BootPhase::Kernel,
+                BootPhase::Earlycon,
+                BootPhase::Acpi,
+                BootPhase::DriverProbes,
+                BootPhase::RootfsMount,
+                BootPhase::Init
+            ]
+        );
+
+        let acpi = timeline.phase(BootPhase::Acpi).unwrap();
+        assert_eq!(acpi.start, Duration::from_millis(10));
+        assert_eq!(acpi.duration(), Some(Duration::from_millis(190)));
+
+        let probes = timeline.phase(BootPhase::DriverProbes).unwrap();
+        assert_eq!(probes.duration(), Some(Duration::from_millis(700)));
+
+        assert_eq!(timeline.phase(BootPhase::Init).unwrap().end, None);
+        assert_eq!(timeline.time_to_init(), Some(Duration::from_millis(1100)));
+    }
+
+    #[test]
+    fn test_missing_phases() {
+        let timeline = BootTimeline::from_entries(&entries(&[
+            (300, "pci 0000:00:00.0: [8086:1237] type 00 class 0x060000"),
+            (800, "Run /init as init process"),
+        ]));
+        let phases: Vec<BootPhase> = timeline.phases.iter().map(|s| s.phase).collect();
+        assert_eq!(
+            phases,
+            vec![BootPhase::Kernel, BootPhase::DriverProbes, BootPhase::Init]
+        );
+        assert_eq!(
+            timeline.phase(BootPhase::Kernel).unwrap().duration(),
+            Some(Duration::from_millis(0))
+        );
+        assert_eq!(timeline.phase(BootPhase::Earlycon), None);
+        assert_eq!(timeline.time_to_init(), Some(Duration::from_millis(500)));
+
+        assert_eq!(BootTimeline::from_entries(&[]), BootTimeline::default());
+    }
+}
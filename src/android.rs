@@ -0,0 +1,220 @@
+use crate::error::RMesgError;
+/// Android: picking whichever of /dev/kmsg and klogctl the process may use.
+///
+/// On Android the two are guarded separately: SELinux policy decides on each (a domain
+/// may be allowed `syslog_read` without access to the kmsg device, or the other way
+/// round), /dev/kmsg's own mode only lets root and the system group in, and init sets
+/// `kernel.dmesg_restrict`, so both also need CAP_SYSLOG. Rather than try one and
+/// report whatever errno the other then fails with, `Backend::Default` probes both up
+/// front, uses one that works, and when neither does says which permission is missing.
+///
+use crate::Backend;
+
+use lazy_static::lazy_static;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::path::Path;
+
+lazy_static! {
+    static ref ANDROID: bool =
+        cfg!(target_os = "android") || Path::new("/system/build.prop").exists();
+}
+
+/// Whether this is Android: built for it, or a Linux build running on it (e.g. in a
+/// chroot on the device).
+pub fn is_android() -> bool {
+    *ANDROID
+}
+
+/// Why a mechanism is off limits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Denial {
+    /// EPERM: a missing capability (CAP_SYSLOG, while `kernel.dmesg_restrict` is set)
+    Capability,
+    /// EACCES: SELinux policy (or, for /dev/kmsg, the device's mode)
+    Policy,
+    /// ENOENT: the mechanism isn't there
+    Missing,
+    /// rmesg was built without its feature
+    NotBuilt,
+    /// Any other errno
+    Other(i32),
+}
+
+impl Denial {
+    pub fn from_errno(errno: Option<i32>) -> Denial {
+        match errno {
+            Some(libc::EPERM) => Denial::Capability,
+            Some(libc::EACCES) => Denial::Policy,
+            Some(libc::ENOENT) => Denial::Missing,
+            Some(errno) => Denial::Other(errno),
+            None => Denial::Other(0),
+        }
+    }
+}
+
+/// Which of the mechanisms the process may use.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Access {
+    pub devkmsg: Result<(), Denial>,
+    pub klogctl: Result<(), Denial>,
+    /// `kernel.dmesg_restrict`, if it could be read
+    pub dmesg_restrict: Option<u32>,
+}
+
+impl Access {
+    /// Probes both mechanisms, without reading anything from them.
+    pub fn probe() -> Access {
+        Access {
+            devkmsg: probe_devkmsg(),
+            klogctl: probe_klogctl(),
+            dmesg_restrict: std::fs::read_to_string("/proc/sys/kernel/dmesg_restrict")
+                .ok()
+                .and_then(|s| s.trim().parse().ok()),
+        }
+    }
+
+    /// The backends that work, /dev/kmsg first.
+    pub fn backends(&self) -> Vec<Backend> {
+        let mut backends = Vec::new();
+        #[cfg(feature = "kmsg")]
+        if self.devkmsg.is_ok() {
+            backends.push(Backend::DevKMsg);
+        }
+        #[cfg(feature = "klogctl")]
+        if self.klogctl.is_ok() {
+            backends.push(Backend::KLogCtl);
+        }
+        backends
+    }
+
+    fn explain(&self, f: &mut Formatter, mechanism: &str, denial: Denial) -> FmtResult {
+        write!(f, "\n  {}: ", mechanism)?;
+        match (denial, mechanism) {
+            (Denial::Capability, _) => write!(
+                f,
+                "needs CAP_SYSLOG while kernel.dmesg_restrict is {} (run as root, e.g. through su)",
+                self.dmesg_restrict
+                    .map_or_else(|| "set".to_owned(), |v| v.to_string())
+            ),
+            (Denial::Policy, DEVKMSG) => write!(
+                f,
+                "denied by the device's mode (root and the system group only) or by SELinux \
+                 (the domain needs read on kmsg_device:chr_file, and syslog_read on kernel:system)"
+            ),
+            (Denial::Policy, _) => write!(
+                f,
+                "denied by SELinux (the domain needs syslog_read on kernel:system)"
+            ),
+            (Denial::Missing, _) => write!(f, "not available on this kernel"),
+            (Denial::NotBuilt, _) => write!(f, "rmesg was built without it"),
+            (Denial::Other(errno), _) => {
+                write!(f, "failed: {}", std::io::Error::from_raw_os_error(errno))
+            }
+        }
+    }
+
+    /// The backends that work (see `backends`), or an error naming what's missing for
+    /// each of them.
+    pub fn default_backends(&self) -> Result<Vec<Backend>, RMesgError> {
+        match self.backends() {
+            backends if backends.is_empty() => Err(RMesgError::BackendUnavailable(format!(
+                "Neither /dev/kmsg nor klogctl can be read. {}",
+                self
+            ))),
+            backends => Ok(backends),
+        }
+    }
+}
+
+const DEVKMSG: &str = "/dev/kmsg";
+const KLOGCTL: &str = "klogctl";
+
+impl Display for Access {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "Access to the kernel log on this Android device:")?;
+        match self.devkmsg {
+            Ok(()) => write!(f, "\n  {}: readable", DEVKMSG)?,
+            Err(denial) => self.explain(f, DEVKMSG, denial)?,
+        }
+        match self.klogctl {
+            Ok(()) => write!(f, "\n  {}: readable", KLOGCTL),
+            Err(denial) => self.explain(f, KLOGCTL, denial),
+        }
+    }
+}
+
+#[cfg(feature = "kmsg")]
+fn probe_devkmsg() -> Result<(), Denial> {
+    std::fs::File::open(crate::kmsgfile::DEV_KMSG_PATH)
+        .map(|_| ())
+        .map_err(|e| Denial::from_errno(e.raw_os_error()))
+}
+
+#[cfg(not(feature = "kmsg"))]
+fn probe_devkmsg() -> Result<(), Denial> {
+    Err(Denial::NotBuilt)
+}
+
+#[cfg(feature = "klogctl")]
+fn probe_klogctl() -> Result<(), Denial> {
+    // reading the size is checked exactly as reading the buffer is
+    match unsafe {
+        libc::klogctl(
+            crate::klogctl::KLogType::SyslogActionSizeBuffer as libc::c_int,
+            std::ptr::null_mut(),
+            0,
+        )
+    } {
+        r if r < 0 => Err(Denial::from_errno(
+            std::io::Error::last_os_error().raw_os_error(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(feature = "klogctl"))]
+fn probe_klogctl() -> Result<(), Denial> {
+    Err(Denial::NotBuilt)
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(feature = "klogctl")]
+    #[test]
+    fn test_backends() {
+        let access = Access {
+            devkmsg: Err(Denial::Policy),
+            klogctl: Ok(()),
+            dmesg_restrict: Some(1),
+        };
+        assert!(matches!(
+            access.default_backends().unwrap()[..],
+            [Backend::KLogCtl]
+        ));
+    }
+
+    #[test]
+    fn test_denied() {
+        let access = Access {
+            devkmsg: Err(Denial::from_errno(Some(libc::EACCES))),
+            klogctl: Err(Denial::from_errno(Some(libc::EPERM))),
+            dmesg_restrict: Some(1),
+        };
+        match access.default_backends() {
+            Err(RMesgError::BackendUnavailable(s)) => {
+                assert!(s.contains("kmsg_device:chr_file"), "{}", s);
+                assert!(
+                    s.contains("klogctl: needs CAP_SYSLOG while kernel.dmesg_restrict is 1"),
+                    "{}",
+                    s
+                );
+            }
+            other => panic!("Expected the missing permissions, got: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,305 @@
+use crate::entry::{Entry, LogLevel};
+/// Annotations: key-value tags (e.g. team=storage, rule=disk-errors) attached to the
+/// entries matching user-defined rules.
+///
+/// An `Annotator` is a stage that passes every entry on, tagged by each rule it matches.
+/// The tags go out with the entry to every output format (as labels to Loki, fields to
+/// JSON, Fluentd and webhooks, a map column in Parquet...), so that routing downstream
+/// can be driven by the classification done here. With the `config` feature, rules can
+/// be loaded from TOML.
+///
+use crate::error::RMesgError;
+use crate::stage::Stage;
+
+use regex::Regex;
+
+#[cfg(feature = "config")]
+use serde::Deserialize;
+
+/// Which entries a rule applies to.
+#[derive(Clone, Debug)]
+pub enum Match {
+    /// Messages matching a regex (anywhere in the message, unless anchored)
+    Message(Regex),
+    /// Messages from a subsystem (see `Entry::subsystem`)
+    Subsystem(String),
+    /// Entries at this level or more severe
+    AtLeast(LogLevel),
+    /// Every entry
+    Any,
+}
+
+impl Match {
+    pub fn message(regex: &str) -> Result<Match, RMesgError> {
+        match Regex::new(regex) {
+            Ok(regex) => Ok(Match::Message(regex)),
+            Err(e) => Err(RMesgError::FilterError(format!(
+                "Unable to compile annotation rule {}: {}",
+                regex, e
+            ))),
+        }
+    }
+
+    pub fn matches(&self, entry: &Entry) -> bool {
+        match self {
+            Match::Message(regex) => regex.is_match(entry.message.trim_start()),
+            Match::Subsystem(subsystem) => entry.subsystem() == Some(subsystem.as_str()),
+            Match::AtLeast(level) => entry.level.is_some_and(|l| l <= *level),
+            Match::Any => true,
+        }
+    }
+}
+
+/// Tags to attach to the entries a match applies to.
+#[derive(Clone, Debug)]
+pub struct TagRule {
+    pub when: Match,
+    pub tags: Vec<(String, String)>,
+}
+
+/// A list of rules, applied in order (so where two rules set the same key, the later
+/// one's value wins). Usable as a stage.
+#[derive(Clone, Debug, Default)]
+pub struct Annotator {
+    rules: Vec<TagRule>,
+}
+
+impl Annotator {
+    pub fn new() -> Annotator {
+        Annotator::default()
+    }
+
+    /// Adds a rule tagging the entries `when` matches with `tags`. Keys are identifiers
+    /// (letters, digits and underscores, not starting with a digit), so that they can
+    /// be used as they are as Loki labels or column names.
+    pub fn rule(mut self, when: Match, tags: &[(&str, &str)]) -> Result<Annotator, RMesgError> {
+        for (key, _) in tags {
+            validate_key(key)?;
+        }
+        self.rules.push(TagRule {
+            when,
+            tags: tags
+                .iter()
+                .map(|(k, v)| ((*k).to_owned(), (*v).to_owned()))
+                .collect(),
+        });
+        Ok(self)
+    }
+
+    /// Loads rules from TOML, as a list of `[[annotate]]` tables each with one of
+    /// `regex`, `subsystem` or `level` (or none of them, to tag every entry) and the
+    /// tags to attach. For example:
+    ///
+    /// ```toml
+    /// [[annotate]]
+    /// subsystem = "nvme"
+    /// tags = { team = "storage" }
+    ///
+    /// [[annotate]]
+    /// regex = "I/O error, dev sd[a-z]+"
+    /// tags = { team = "storage", rule = "disk-errors" }
+    ///
+    /// [[annotate]]
+    /// level = "crit"   # crit, alert and emerg
+    /// tags = { page = "yes" }
+    /// ```
+    #[cfg(feature = "config")]
+    pub fn from_toml(toml: &str) -> Result<Annotator, RMesgError> {
+        use std::collections::BTreeMap;
+        use std::str::FromStr;
+
+        #[derive(Deserialize)]
+        struct Config {
+            #[serde(default)]
+            annotate: Vec<RuleConfig>,
+        }
+        #[derive(Deserialize)]
+        struct RuleConfig {
+            regex: Option<String>,
+            subsystem: Option<String>,
+            level: Option<String>,
+            tags: BTreeMap<String, String>,
+        }
+
+        let config: Config = match toml::from_str(toml) {
+            Ok(config) => config,
+            Err(e) => {
+                return Err(RMesgError::ConfigError(format!(
+                    "Unable to parse annotation rules: {}",
+                    e
+                )))
+            }
+        };
+
+        let mut annotator = Annotator::new();
+        for r in config.annotate {
+            let when = match (r.regex, r.subsystem, r.level) {
+                (Some(regex), None, None) => Match::message(&regex)?,
+                (None, Some(subsystem), None) => Match::Subsystem(subsystem),
+                (None, None, Some(level)) => match LogLevel::from_str(&level) {
+                    Ok(level) => Match::AtLeast(level),
+                    Err(_) => {
+                        return Err(RMesgError::ConfigError(format!(
+                            "Unknown level {} in annotation rule",
+                            level
+                        )))
+                    }
+                },
+                (None, None, None) => Match::Any,
+                _ => {
+                    return Err(RMesgError::ConfigError(
+                        "Each annotation rule takes at most one of regex, subsystem or level"
+                            .to_owned(),
+                    ))
+                }
+            };
+            let tags: Vec<(&str, &str)> = r
+                .tags
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect();
+            annotator = annotator.rule(when, &tags)?;
+        }
+        Ok(annotator)
+    }
+
+    pub fn rules(&self) -> &[TagRule] {
+        &self.rules
+    }
+
+    /// Attaches the tags of every rule `entry` matches.
+    pub fn annotate(&self, entry: &mut Entry) {
+        for rule in self.rules.iter() {
+            if rule.when.matches(entry) {
+                for (key, value) in rule.tags.iter() {
+                    entry.set_tag(key, value);
+                }
+            }
+        }
+    }
+}
+
+impl Stage for Annotator {
+    fn process(&mut self, mut entry: Entry) -> Option<Entry> {
+        self.annotate(&mut entry);
+        Some(entry)
+    }
+}
+
+fn validate_key(key: &str) -> Result<(), RMesgError> {
+    let mut chars = key.chars();
+    match chars.next() {
+        Some(c)
+            if (c.is_ascii_alphabetic() || c == '_')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+        {
+            Ok(())
+        }
+        _ => Err(RMesgError::ConfigError(format!(
+            "Invalid tag key {:?} (expected letters, digits and underscores, not starting with a digit)",
+            key
+        ))),
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entry::LogFacility;
+
+    fn entry(level: LogLevel, message: &str) -> Entry {
+        Entry {
+            facility: Some(LogFacility::Kern),
+            level: Some(level),
+            message: message.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_annotate() {
+        let mut annotator = Annotator::new()
+            .rule(Match::Subsystem("nvme".to_owned()), &[("team", "storage")])
+            .unwrap()
+            .rule(
+                Match::message("I/O error").unwrap(),
+                &[("rule", "disk-errors"), ("team", "storage-oncall")],
+            )
+            .unwrap()
+            .rule(Match::AtLeast(LogLevel::Critical), &[("page", "yes")])
+            .unwrap();
+
+        let e = annotator
+            .process(entry(LogLevel::Error, "nvme nvme0: I/O error on queue 2"))
+            .unwrap();
+        assert_eq!(e.tag("team"), Some("storage-oncall"));
+        assert_eq!(e.tag("rule"), Some("disk-errors"));
+        assert_eq!(e.tag("page"), None);
+
+        let e = annotator
+            .process(entry(LogLevel::Alert, "Kernel panic - not syncing"))
+            .unwrap();
+        assert_eq!(e.tags.len(), 1);
+        assert_eq!(e.tag("page"), Some("yes"));
+
+        let e = annotator
+            .process(entry(LogLevel::Info, "e1000e: eth0 NIC Link is Up"))
+            .unwrap();
+        assert!(e.tags.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_key() {
+        assert!(Annotator::new().rule(Match::Any, &[("team", "x")]).is_ok());
+        assert!(Annotator::new()
+            .rule(Match::Any, &[("_team2", "x")])
+            .is_ok());
+        for key in &["", "2fa", "team-name", "team name"] {
+            assert!(matches!(
+                Annotator::new().rule(Match::Any, &[(key, "x")]),
+                Err(RMesgError::ConfigError(_))
+            ));
+        }
+        assert!(matches!(
+            Match::message("(unclosed"),
+            Err(RMesgError::FilterError(_))
+        ));
+    }
+
+    #[cfg(feature = "config")]
+    #[test]
+    fn test_from_toml() {
+        let annotator = Annotator::from_toml(
+            r#"
+            [[annotate]]
+            subsystem = "nvme"
+            tags = { team = "storage" }
+
+            [[annotate]]
+            level = "crit"
+            tags = { page = "yes" }
+
+            [[annotate]]
+            tags = { host_class = "db" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(annotator.rules().len(), 3);
+
+        let mut e = entry(LogLevel::Emergency, "nvme nvme0: controller is down");
+        annotator.annotate(&mut e);
+        assert_eq!(e.tag("team"), Some("storage"));
+        assert_eq!(e.tag("page"), Some("yes"));
+        assert_eq!(e.tag("host_class"), Some("db"));
+
+        assert!(Annotator::from_toml("[[annotate]]\nlevel = \"loud\"\ntags = {}\n").is_err());
+        assert!(Annotator::from_toml(
+            "[[annotate]]\nregex = \"x\"\nsubsystem = \"y\"\ntags = {}\n"
+        )
+        .is_err());
+        assert!(Annotator::from_toml("[[annotate]]\ntags = { \"a-b\" = \"c\" }\n").is_err());
+    }
+}
@@ -0,0 +1,196 @@
+use crate::entry::{Entry, LogLevel};
+/// Assertion helpers for CI jobs (typically device-driver tests) that should fail
+/// when the kernel complains.
+///
+/// Pair `assert_no_entries_above` with `capture::capture_during` to check the window
+/// of messages logged while a test ran, and use `expect_message` to wait for a
+/// message that a test expects the kernel to log.
+///
+#[cfg(feature = "kmsg")]
+use crate::error::RMesgError;
+#[cfg(feature = "kmsg")]
+use crate::kmsgfile;
+
+#[cfg(feature = "kmsg")]
+use regex::Regex;
+#[cfg(feature = "kmsg")]
+use std::fs as stdfs;
+#[cfg(feature = "kmsg")]
+use std::io::Read;
+#[cfg(feature = "kmsg")]
+use std::os::unix::fs::OpenOptionsExt;
+#[cfg(feature = "kmsg")]
+use std::os::unix::io::AsRawFd;
+#[cfg(feature = "kmsg")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "kmsg")]
+const DEV_KMSG_PATH: &str = "/dev/kmsg";
+
+/// Panics (listing the offenders) if any entry in `window` is more severe than `level`.
+/// Entries without a level are ignored.
+///
+/// e.g. `assert_no_entries_above(LogLevel::Warning, &entries)` fails on any
+/// error, critical, alert or emergency message, but allows warnings.
+pub fn assert_no_entries_above(level: LogLevel, window: &[Entry]) {
+    let offenders: Vec<String> = window
+        .iter()
+        .filter(|e| matches!(e.level, Some(l) if l < level))
+        .map(|e| {
+            format!(
+                "  <{}> {}",
+                e.level.map(|l| l.to_string()).unwrap_or_default(),
+                e
+            )
+        })
+        .collect();
+
+    if !offenders.is_empty() {
+        panic!(
+            "Expected no kernel log entries above level {}, but found {}:\n{}",
+            level,
+            offenders.len(),
+            offenders.join("\n")
+        );
+    }
+}
+
+/// Waits up to `timeout` for the kernel to log a message matching `pattern`, returning
+/// the first matching entry, or `RMesgError::Timeout` if none shows up in time.
+///
+/// Only entries logged after this function is called are considered, so trigger
+/// whatever is expected to log the message after calling it (e.g. from another thread),
+/// or use `capture::capture_during` to look at what was logged in hindsight.
+#[cfg(feature = "kmsg")]
+pub fn expect_message(pattern: &Regex, timeout: Duration) -> Result<Entry, RMesgError> {
+    let deadline = Instant::now() + timeout;
+
+    let mut file = match stdfs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(DEV_KMSG_PATH)
+    {
+        Ok(fc) => fc,
+        Err(e) => {
+            return Err(RMesgError::DevKMsgFileOpenError(format!(
+                "Unable to open file {}: {}",
+                DEV_KMSG_PATH, e
+            )))
+        }
+    };
+
+    // skip everything already logged
+    if unsafe { libc::lseek(file.as_raw_fd(), 0, libc::SEEK_END) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let mut record = vec![0u8; 8192];
+    loop {
+        match file.read(&mut record) {
+            Ok(n) => {
+                let text = String::from_utf8_lossy(&record[..n]);
+                let line = text.lines().next().unwrap_or_default();
+                let entry = kmsgfile::entry_from_line(line)?;
+                if pattern.is_match(&entry.message) {
+                    return Ok(entry);
+                }
+                continue;
+            }
+            Err(e) => match e.raw_os_error() {
+                Some(libc::EAGAIN) => {}
+                Some(libc::EPIPE) => continue,
+                _ => return Err(e.into()),
+            },
+        }
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return Err(RMesgError::Timeout(format!(
+                "No kernel log entry matching /{}/ within {:?}",
+                pattern, timeout
+            )));
+        }
+
+        let mut pollfd = libc::pollfd {
+            fd: file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = remaining.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        if unsafe { libc::poll(&mut pollfd, 1, timeout_ms.max(1)) } < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EINTR) {
+                return Err(err.into());
+            }
+        }
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(level: Option<LogLevel>) -> Entry {
+        Entry {
+            level,
+            message: "message".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_no_entries_above_passes() {
+        assert_no_entries_above(
+            LogLevel::Warning,
+            &[
+                entry(Some(LogLevel::Warning)),
+                entry(Some(LogLevel::Info)),
+                entry(None),
+            ],
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "found 1")]
+    fn test_no_entries_above_fails() {
+        assert_no_entries_above(
+            LogLevel::Warning,
+            &[entry(Some(LogLevel::Warning)), entry(Some(LogLevel::Error))],
+        );
+    }
+
+    #[cfg(all(target_os = "linux", feature = "kmsg"))]
+    #[test]
+    fn test_expect_message() {
+        use std::io::Write;
+        use std::thread;
+
+        let writer = thread::spawn(|| {
+            thread::sleep(Duration::from_millis(100));
+            let mut kmsg = stdfs::OpenOptions::new()
+                .write(true)
+                .open(DEV_KMSG_PATH)
+                .unwrap();
+            kmsg.write_all(b"<14>rmesg: test_expect_message 1234\n")
+                .unwrap();
+        });
+
+        let re = Regex::new(r"test_expect_message \d+").unwrap();
+        let entry = expect_message(&re, Duration::from_secs(10)).unwrap();
+        assert_eq!(entry.message, "rmesg: test_expect_message 1234");
+        writer.join().unwrap();
+    }
+
+    #[cfg(all(target_os = "linux", feature = "kmsg"))]
+    #[test]
+    fn test_expect_message_timeout() {
+        let re = Regex::new("this message will never be logged").unwrap();
+        match expect_message(&re, Duration::from_millis(50)) {
+            Err(RMesgError::Timeout(_)) => {}
+            other => panic!("Expected Timeout, got: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,365 @@
+use crate::clock::WallClock;
+use crate::common;
+/// Backfill from /var/log/dmesg and its rotated archives.
+///
+/// Distributions without journald save the kernel log at each boot (Debian's bootlogs,
+/// or `dmesg > /var/log/dmesg` in an init script), and rotate the previous boots' copies
+/// to `dmesg.0`, `dmesg.1.gz` and so on. Those files are all that's left of the
+/// messages the ring buffer has since overwritten, so reading them ahead of the live
+/// buffer gives a reader continuity across boots.
+///
+/// The files only have kernel timestamps, which restart at each boot; each one is
+/// anchored to the wall clock by taking its newest entry to have been logged when the
+/// file was last modified. Gzipped archives are read through `gzip -dc`.
+///
+use crate::entry::Entry;
+use crate::error::RMesgError;
+use crate::parse;
+use crate::provenance::{Provenance, Tagger};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::fs as stdfs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::SystemTime;
+
+#[cfg(feature = "futures")]
+use futures::stream::{self, Stream, StreamExt};
+
+pub const DMESG_LOG_DIR: &str = "/var/log";
+pub(crate) const GZIP: &str = "gzip";
+
+lazy_static! {
+    // dmesg    dmesg.0    dmesg.1.gz    dmesg.4.gz
+    static ref RE_ARCHIVE_NAME: Regex =
+        Regex::new(r"^dmesg(\.(?P<generation>[[:digit:]]+))?(?P<gz>\.gz)?$").unwrap();
+}
+
+/// One of the saved copies of the kernel log.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DmesgArchive {
+    pub path: PathBuf,
+    /// How many rotations old it is (`None` for /var/log/dmesg itself)
+    pub generation: Option<usize>,
+    pub compressed: bool,
+    pub modified: SystemTime,
+}
+
+impl DmesgArchive {
+    /// The archive's contents, decompressed.
+    pub fn read_raw(&self) -> Result<String, RMesgError> {
+        let bytes = match self.compressed {
+            false => stdfs::read(&self.path)?,
+            true => {
+                let output = Command::new(GZIP)
+                    .arg("-dc")
+                    .arg(&self.path)
+                    .output()
+                    .map_err(|e| {
+                        RMesgError::BackendUnavailable(format!(
+                            "Unable to run {} to read {}: {}",
+                            GZIP,
+                            self.path.display(),
+                            e
+                        ))
+                    })?;
+                if !output.status.success() {
+                    return Err(RMesgError::IOError(format!(
+                        "{} -dc {} failed: {}",
+                        GZIP,
+                        self.path.display(),
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    )));
+                }
+                output.stdout
+            }
+        };
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    /// The archive's entries, anchored to its modification time and tagged with its
+    /// path as their provenance.
+    pub fn entries(&self) -> Result<Vec<Entry>, RMesgError> {
+        let mut entries = entries_from_archive(&self.read_raw()?)?;
+        anchor(&mut entries, self.modified);
+
+        let tagger = Tagger::new(Provenance::file(&self.path.to_string_lossy()));
+        Ok(entries.into_iter().map(|e| tagger.tag(e)).collect())
+    }
+}
+
+/// The saved copies of the kernel log, oldest first (so /var/log/dmesg, this boot's or
+/// the last one's, comes last).
+///
+/// `dir_override` looks somewhere other than `DMESG_LOG_DIR`.
+pub fn dmesg_archives(dir_override: Option<String>) -> Result<Vec<DmesgArchive>, RMesgError> {
+    let dir = dir_override.unwrap_or_else(|| DMESG_LOG_DIR.to_owned());
+
+    let mut archives = Vec::new();
+    for dirent in stdfs::read_dir(&dir)? {
+        let dirent = dirent?;
+        let path = dirent.path();
+        let name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name,
+            None => continue,
+        };
+        let parts = match RE_ARCHIVE_NAME.captures(name) {
+            Some(parts) => parts,
+            None => continue,
+        };
+        let generation = match parts.name("generation") {
+            Some(generation) => Some(common::parse_fragment::<usize>(generation.as_str(), name)?),
+            None => None,
+        };
+        archives.push(DmesgArchive {
+            generation,
+            compressed: parts.name("gz").is_some(),
+            modified: dirent.metadata()?.modified()?,
+            path,
+        });
+    }
+
+    archives.sort_by_key(|a| std::cmp::Reverse(a.generation.map(|g| g + 1).unwrap_or(0)));
+    Ok(archives)
+}
+
+/// The entries in a saved copy of the kernel log. `dmesg` saves them without their
+/// priorities, so most have none (`dmesg -r`'s output keeps them).
+pub fn entries_from_archive(raw: &str) -> Result<Vec<Entry>, RMesgError> {
+    let entries: Result<Vec<Entry>, _> = raw
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(parse::parse_line)
+        .collect();
+    Ok(entries?)
+}
+
+// Fills in the entries' wall-clock timestamps, as if the newest was logged at `modified`.
+fn anchor(entries: &mut [Entry], modified: SystemTime) {
+    let newest = match entries
+        .iter()
+        .filter_map(|e| e.timestamp_from_system_start)
+        .max()
+    {
+        Some(newest) => newest,
+        None => return,
+    };
+    let mut clock = WallClock::with_boot_time(modified.checked_sub(newest).unwrap_or(modified));
+    for entry in entries.iter_mut() {
+        clock.stamp(entry);
+    }
+}
+
+/// The saved kernel logs, split into those of previous boots and the one (if any)
+/// saved during this boot, which the live buffer may still partly hold.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Backfill {
+    /// Oldest first
+    pub previous_boots: Vec<Entry>,
+    pub current_boot: Vec<Entry>,
+}
+
+impl Backfill {
+    /// Reads every archive in `dir_override` (or `DMESG_LOG_DIR`). An archive is this
+    /// boot's when it was modified since the system started; `boot_time` says when that
+    /// was (e.g. `WallClock::now()?.boot_time()`).
+    pub fn with_options(
+        dir_override: Option<String>,
+        boot_time: SystemTime,
+    ) -> Result<Backfill, RMesgError> {
+        let mut backfill = Backfill::default();
+        for archive in dmesg_archives(dir_override)? {
+            let entries = archive.entries()?;
+            match archive.modified >= boot_time {
+                true => backfill.current_boot.extend(entries),
+                false => backfill.previous_boots.extend(entries),
+            }
+        }
+        Ok(backfill)
+    }
+
+    /// Everything backfilled, oldest first.
+    pub fn entries(&self) -> Vec<Entry> {
+        self.previous_boots
+            .iter()
+            .chain(self.current_boot.iter())
+            .cloned()
+            .collect()
+    }
+
+    // Takes the entries to emit ahead of the first entry read from the live buffer:
+    // those of this boot's archive logged before it (the rest the live buffer repeats).
+    // If that entry has no timestamp, there's no telling, so none are.
+    fn take_ahead_of(&mut self, first_live: &Entry) -> Vec<Entry> {
+        let current_boot = std::mem::take(&mut self.current_boot);
+        match first_live.timestamp_from_system_start {
+            Some(first) => current_boot
+                .into_iter()
+                .filter(|e| e.timestamp_from_system_start.is_some_and(|ts| ts < first))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+// The live items preceded, at the first successful one, by the entries of this boot's
+// archive it doesn't repeat
+fn splice(
+    mut backfill: Backfill,
+) -> impl FnMut(Result<Entry, RMesgError>) -> Vec<Result<Entry, RMesgError>> {
+    let mut spliced = false;
+    move |item| {
+        let mut items = Vec::new();
+        if let (false, Ok(entry)) = (spliced, &item) {
+            spliced = true;
+            items.extend(backfill.take_ahead_of(entry).into_iter().map(Ok));
+        }
+        items.push(item);
+        items
+    }
+}
+
+/// The backfilled entries followed by `live`'s (e.g. `logs_iter`'s), without the
+/// entries of this boot's archive that the live buffer still holds.
+pub fn ahead_of<I>(backfill: Backfill, live: I) -> impl Iterator<Item = Result<Entry, RMesgError>>
+where
+    I: IntoIterator<Item = Result<Entry, RMesgError>>,
+{
+    let previous_boots = backfill.previous_boots.clone();
+    previous_boots
+        .into_iter()
+        .map(Ok)
+        .chain(live.into_iter().flat_map(splice(backfill)))
+}
+
+/// Like `ahead_of`, for streams (e.g. `logs_stream`'s).
+#[cfg(feature = "futures")]
+pub fn ahead_of_stream<S>(
+    backfill: Backfill,
+    live: S,
+) -> impl Stream<Item = Result<Entry, RMesgError>>
+where
+    S: Stream<Item = Result<Entry, RMesgError>>,
+{
+    let previous_boots = backfill.previous_boots.clone();
+    let mut splice = splice(backfill);
+    stream::iter(previous_boots.into_iter().map(Ok))
+        .chain(live.flat_map(move |item| stream::iter(splice(item))))
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::provenance::Origin;
+    use std::time::Duration;
+
+    fn fixture(name: &str, files: &[(&str, &str, u64)]) -> String {
+        let dir =
+            std::env::temp_dir().join(format!("rmesg-backfill-{}-{}", name, std::process::id()));
+        stdfs::create_dir_all(&dir).unwrap();
+        for (file, contents, modified) in files {
+            let path = dir.join(file);
+            match file.strip_suffix(".gz") {
+                Some(_) => {
+                    let plain = dir.join(format!("{}.plain", file));
+                    stdfs::write(&plain, contents).unwrap();
+                    let gzipped = Command::new(GZIP).arg("-c").arg(&plain).output().unwrap();
+                    stdfs::remove_file(&plain).unwrap();
+                    stdfs::write(&path, gzipped.stdout).unwrap();
+                }
+                None => stdfs::write(&path, contents).unwrap(),
+            }
+            stdfs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(*modified))
+                .unwrap();
+        }
+        dir.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn test_archives() {
+        let dir = fixture(
+            "archives",
+            &[
+                (
+                    "dmesg",
+                    "[    0.000000] Linux version 6.1.0\n[    2.500000] eth0: link up\n",
+                    3000,
+                ),
+                (
+                    "dmesg.0",
+                    "[    0.000000] Linux version 6.0.0\n[    1.000000] old boot\n",
+                    2000,
+                ),
+                ("dmesg.1.gz", "<6>[    4.000000] oldest boot\n", 1000),
+                ("syslog", "not the kernel's\n", 3000),
+            ],
+        );
+        let archives = dmesg_archives(Some(dir.clone())).unwrap();
+        let generations: Vec<_> = archives.iter().map(|a| a.generation).collect();
+        assert_eq!(generations, vec![Some(1), Some(0), None]);
+        assert!(archives[0].compressed);
+
+        let backfill = Backfill::with_options(
+            Some(dir),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(2500),
+        )
+        .unwrap();
+        assert_eq!(backfill.previous_boots.len(), 3);
+        assert_eq!(backfill.current_boot.len(), 2);
+
+        let oldest = &backfill.previous_boots[0];
+        assert_eq!(oldest.message, " oldest boot");
+        assert_eq!(
+            oldest.timestamp_realtime,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1000))
+        );
+        assert!(matches!(
+            oldest.provenance.as_ref().map(|p| &p.origin),
+            Some(Origin::File(path)) if path.ends_with("dmesg.1.gz")
+        ));
+        // anchored so that the newest entry was logged when the file was written
+        assert_eq!(
+            backfill.current_boot[0].timestamp_realtime,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_millis(2_997_500))
+        );
+    }
+
+    #[test]
+    fn test_ahead_of() {
+        let entry = |secs: u64, message: &str| {
+            let mut entry = parse::console_entry_from_line(message).unwrap();
+            entry.timestamp_from_system_start = Some(Duration::from_secs(secs));
+            entry
+        };
+        let backfill = Backfill {
+            previous_boots: vec![entry(100, "last boot")],
+            current_boot: vec![entry(1, "lost from the buffer"), entry(5, "still in it")],
+        };
+        let live = vec![
+            Err(RMesgError::Timeout("test".to_owned())),
+            Ok(entry(5, "still in it")),
+            Ok(entry(6, "new")),
+        ];
+        let messages: Vec<_> = ahead_of(backfill, live)
+            .map(|item| item.map_or_else(|e| e.kind().to_owned(), |e| e.message))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "last boot",
+                "Timeout",
+                "lost from the buffer",
+                "still in it",
+                "new"
+            ]
+        );
+    }
+}
@@ -0,0 +1,380 @@
+use crate::entry::Entry;
+/// Bookmarks: opaque cursors into the kernel log, for "everything since last time" queries.
+///
+/// Attach a `BookmarkTracker` to a live iterator/stream (it's a stage, so use
+/// `stage::Staged`) and call `bookmark()` on it whenever you want to remember how far
+/// you've read. Later (possibly from another process, using the bookmark's token),
+/// `replay_from` returns every entry still in the kernel buffer that came after it
+/// (or `RetentionRing::replay_from`, every such entry still retained in-process).
+///
+/// Without sequence numbers (klogctl's records), a bookmark is a timestamp, and how many
+/// entries at that timestamp it's past: drivers often log several lines within the same
+/// microsecond, and the bookmark may have been taken between them. Replaying (through
+/// `Bookmark::replaying`) counts those off, rather than taking them all as seen.
+///
+use crate::error::RMesgError;
+use crate::softclear::ClearMark;
+use crate::stage::Stage;
+use crate::{log_entries, Backend};
+
+use std::fs as stdfs;
+use std::time::Duration;
+
+const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+
+/// A position in the kernel log. Bookmarks are only meaningful within the boot they
+/// were taken in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bookmark {
+    pub(crate) boot_id: Option<String>,
+    pub(crate) mark: ClearMark,
+    // how many entries at the mark's timestamp it's past, when known
+    pub(crate) at_timestamp: Option<usize>,
+}
+
+impl Bookmark {
+    /// A bookmark at the newest entry currently in the buffer.
+    pub fn now(backend: Backend) -> Result<Bookmark, RMesgError> {
+        let entries = log_entries(backend, false)?;
+        let mark = ClearMark::after(&entries);
+        let at_timestamp = mark.timestamp_from_system_start.map(|ts| {
+            entries
+                .iter()
+                .filter(|e| e.timestamp_from_system_start == Some(ts))
+                .count()
+        });
+        Ok(Bookmark {
+            boot_id: boot_id(),
+            mark,
+            at_timestamp,
+        })
+    }
+
+    /// Whether the bookmark was taken in this boot (or where that can't be told).
+    pub fn in_this_boot(&self) -> bool {
+        match (&self.boot_id, boot_id()) {
+            (Some(then), Some(now)) => *then == now,
+            _ => true,
+        }
+    }
+
+    /// Whether `entry` was logged after this bookmark was taken, by its position alone.
+    /// Entries without a sequence number at the bookmark's timestamp never are, though
+    /// some of them may have been logged after it: `replaying` tells those apart.
+    pub fn is_after(&self, entry: &Entry) -> bool {
+        self.mark.is_after(entry)
+    }
+
+    /// For telling the entries logged after this bookmark from those before it, going
+    /// through them oldest first.
+    pub fn replaying(&self) -> Replaying {
+        Replaying {
+            bookmark: self.clone(),
+            at_timestamp: 0,
+        }
+    }
+
+    /// Serializes this bookmark, e.g. to persist it between runs.
+    pub fn to_token(&self) -> String {
+        let token = format!(
+            "{}/{}/{}",
+            self.boot_id.as_deref().unwrap_or("-"),
+            self.mark
+                .sequence_num
+                .map(|s| s.to_string())
+                .unwrap_or_else(|| "-".to_owned()),
+            self.mark
+                .timestamp_from_system_start
+                .map(|t| t.as_micros().to_string())
+                .unwrap_or_else(|| "-".to_owned()),
+        );
+        match self.at_timestamp {
+            Some(count) => format!("{}/{}", token, count),
+            None => token,
+        }
+    }
+
+    pub fn from_token(token: &str) -> Result<Bookmark, RMesgError> {
+        let invalid = || RMesgError::BookmarkError(format!("Invalid bookmark token {}", token));
+
+        // the count at the timestamp is left out by older versions
+        let parts: Vec<&str> = token.split('/').collect();
+        if parts.len() != 3 && parts.len() != 4 {
+            return Err(invalid());
+        }
+        let field = |s: &str| if s == "-" { None } else { Some(s.to_owned()) };
+
+        let sequence_num = match field(parts[1]) {
+            Some(s) => Some(s.parse().map_err(|_| invalid())?),
+            None => None,
+        };
+        let timestamp_from_system_start = match field(parts[2]) {
+            Some(t) => Some(Duration::from_micros(t.parse().map_err(|_| invalid())?)),
+            None => None,
+        };
+        let at_timestamp = match parts.get(3) {
+            Some(count) => Some(count.parse().map_err(|_| invalid())?),
+            None => None,
+        };
+
+        Ok(Bookmark {
+            boot_id: field(parts[0]),
+            mark: ClearMark {
+                sequence_num,
+                timestamp_from_system_start,
+            },
+            at_timestamp,
+        })
+    }
+}
+
+/// A stage remembering the position of the last entry that passed through it.
+pub struct BookmarkTracker {
+    boot_id: Option<String>,
+    mark: ClearMark,
+    at_timestamp: Option<usize>,
+}
+
+impl BookmarkTracker {
+    pub fn new() -> BookmarkTracker {
+        BookmarkTracker {
+            boot_id: boot_id(),
+            mark: ClearMark::default(),
+            at_timestamp: None,
+        }
+    }
+
+    // Carrying on from `bookmark`, as a reader resuming from it does
+    pub(crate) fn resuming(bookmark: &Bookmark) -> BookmarkTracker {
+        BookmarkTracker {
+            boot_id: bookmark.boot_id.clone(),
+            mark: bookmark.mark,
+            at_timestamp: bookmark.at_timestamp,
+        }
+    }
+
+    /// A bookmark just past the last entry seen.
+    pub fn bookmark(&self) -> Bookmark {
+        Bookmark {
+            boot_id: self.boot_id.clone(),
+            mark: self.mark,
+            at_timestamp: self.at_timestamp,
+        }
+    }
+
+    // As process, for entries that aren't passed on by value
+    pub(crate) fn track(&mut self, entry: &Entry) {
+        if entry.sequence_num.is_some() {
+            self.mark.sequence_num = entry.sequence_num;
+        }
+        if let Some(ts) = entry.timestamp_from_system_start {
+            self.at_timestamp = match self.mark.timestamp_from_system_start == Some(ts) {
+                true => self.at_timestamp.map(|count| count + 1),
+                false => Some(1),
+            };
+            self.mark.timestamp_from_system_start = Some(ts);
+        }
+    }
+}
+
+impl Default for BookmarkTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stage for BookmarkTracker {
+    fn process(&mut self, entry: Entry) -> Option<Entry> {
+        self.track(&entry);
+        Some(entry)
+    }
+}
+
+/// Tells the entries logged after a bookmark from those before it (see
+/// `Bookmark::replaying`). Of the entries without a sequence number at the bookmark's
+/// timestamp, as many as it's past are taken as before it, and the rest as after; from a
+/// bookmark that doesn't say how many (an older token's), all of them are before it.
+#[derive(Clone, Debug)]
+pub struct Replaying {
+    bookmark: Bookmark,
+    at_timestamp: usize,
+}
+
+impl Replaying {
+    /// Whether `entry`, the next oldest, was logged after the bookmark.
+    pub fn is_after(&mut self, entry: &Entry) -> bool {
+        let mark = self.bookmark.mark.timestamp_from_system_start;
+        if entry.sequence_num.is_some()
+            || entry.timestamp_from_system_start.is_none()
+            || entry.timestamp_from_system_start != mark
+        {
+            return self.bookmark.is_after(entry);
+        }
+        self.at_timestamp += 1;
+        self.bookmark
+            .at_timestamp
+            .is_some_and(|passed| self.at_timestamp > passed)
+    }
+}
+
+/// The entries logged after a bookmark.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Replay {
+    pub entries: Vec<Entry>,
+    /// Whether entries logged after the bookmark were already overwritten in the kernel
+    /// buffer (only detectable with sequence numbers, i.e. from /dev/kmsg)
+    pub gap: bool,
+}
+
+/// Every entry still retained in the kernel buffer that was logged after `bookmark`.
+/// Fails when the bookmark was taken in a different boot.
+pub fn replay_from(backend: Backend, bookmark: &Bookmark) -> Result<Replay, RMesgError> {
+    if let (Some(then), Some(now)) = (&bookmark.boot_id, boot_id()) {
+        if *then != now {
+            return Err(RMesgError::BookmarkError(format!(
+                "Bookmark was taken in boot {}, but this is boot {}",
+                then, now
+            )));
+        }
+    }
+
+    Ok(replay_entries(log_entries(backend, false)?, bookmark))
+}
+
+fn replay_entries(entries: Vec<Entry>, bookmark: &Bookmark) -> Replay {
+    let mut replaying = bookmark.replaying();
+    let entries: Vec<Entry> = entries
+        .into_iter()
+        .filter(|e| replaying.is_after(e))
+        .collect();
+
+    let gap = match (
+        bookmark.mark.sequence_num,
+        entries.iter().find_map(|e| e.sequence_num),
+    ) {
+        (Some(mark), Some(first)) => first > mark + 1,
+        _ => false,
+    };
+
+    Replay { entries, gap }
+}
+
+pub(crate) fn boot_id() -> Option<String> {
+    stdfs::read_to_string(BOOT_ID_PATH)
+        .ok()
+        .map(|id| id.trim().to_owned())
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(sequence_num: usize) -> Entry {
+        Entry {
+            sequence_num: Some(sequence_num),
+            timestamp_from_system_start: Some(Duration::from_millis(sequence_num as u64)),
+            message: format!("message {}", sequence_num),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_tracker_and_replay() {
+        let mut tracker = BookmarkTracker::new();
+        for n in 1..=3 {
+            tracker.process(entry(n));
+        }
+        let bookmark = tracker.bookmark();
+
+        let replay = replay_entries((1..=5).map(entry).collect(), &bookmark);
+        assert_eq!(replay.entries, vec![entry(4), entry(5)]);
+        assert!(!replay.gap);
+
+        // entries 4 and 5 were overwritten
+        let replay = replay_entries((6..=7).map(entry).collect(), &bookmark);
+        assert_eq!(replay.entries.len(), 2);
+        assert!(replay.gap);
+    }
+
+    #[test]
+    fn test_token_round_trip() {
+        let mut tracker = BookmarkTracker::new();
+        tracker.process(entry(42));
+        let bookmark = tracker.bookmark();
+        assert_eq!(
+            Bookmark::from_token(&bookmark.to_token()).unwrap(),
+            bookmark
+        );
+
+        let empty = Bookmark {
+            boot_id: None,
+            mark: ClearMark::default(),
+            at_timestamp: None,
+        };
+        assert_eq!(empty.to_token(), "-/-/-");
+        assert_eq!(Bookmark::from_token("-/-/-").unwrap(), empty);
+
+        assert!(Bookmark::from_token("garbage").is_err());
+        assert!(Bookmark::from_token("-/x/-").is_err());
+        assert!(Bookmark::from_token("-/-/1000/x").is_err());
+    }
+
+    #[test]
+    fn test_replay_at_timestamp() {
+        // as klogctl reads them: no sequence numbers, two logged in the same millisecond
+        let entry = |ms: u64, message: &str| Entry {
+            timestamp_from_system_start: Some(Duration::from_millis(ms)),
+            message: message.to_owned(),
+            ..Default::default()
+        };
+        let mut tracker = BookmarkTracker::new();
+        tracker.process(entry(1, "a"));
+        tracker.process(entry(2, "b1"));
+        tracker.process(entry(2, "b2"));
+        let bookmark = Bookmark::from_token(&tracker.bookmark().to_token()).unwrap();
+        assert_eq!(bookmark.at_timestamp, Some(2));
+
+        // a third in that millisecond was logged after the bookmark was taken
+        let buffer = vec![
+            entry(1, "a"),
+            entry(2, "b1"),
+            entry(2, "b2"),
+            entry(2, "b3"),
+            entry(3, "c"),
+        ];
+        let replay = replay_entries(buffer.clone(), &bookmark);
+        let messages: Vec<&str> = replay.entries.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(messages, vec!["b3", "c"]);
+
+        // an older token doesn't say how many it's past
+        let older = Bookmark::from_token("-/-/2000").unwrap();
+        assert_eq!(replay_entries(buffer, &older).entries, vec![entry(3, "c")]);
+    }
+
+    #[test]
+    fn test_different_boot() {
+        let bookmark = Bookmark {
+            boot_id: Some("not-this-boot".to_owned()),
+            mark: ClearMark::default(),
+            at_timestamp: None,
+        };
+        if boot_id().is_some() {
+            assert!(matches!(
+                replay_from(Backend::Default, &bookmark),
+                Err(RMesgError::BookmarkError(_))
+            ));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_bookmark_now() {
+        let bookmark = Bookmark::now(Backend::Default).unwrap();
+        let replay = replay_from(Backend::Default, &bookmark).unwrap();
+        // other tests may log concurrently, but nothing before the bookmark comes back
+        assert!(replay.entries.iter().all(|e| bookmark.mark.is_after(e)));
+    }
+}
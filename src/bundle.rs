@@ -0,0 +1,306 @@
+use crate::degradation::{self, Capability};
+/// Support bundles: everything about a host's kernel log that a support ticket needs,
+/// in one archive (`rmesg export --bundle out.tar.gz`).
+///
+/// A bundle holds the current snapshot, the logs pstore saved from previous boots (with
+/// the "pstore" feature), the printk settings, some system context (kernel version,
+/// command line, uptime, boot ID) and which of rmesg's backends work on the host. Whatever
+/// can't be read is noted in the bundle rather than failing the export, since a bundle
+/// from a half-broken host is exactly the one that's needed.
+///
+/// The archive is a plain ustar tarball in a gzip wrapper, written without compressing
+/// (stored deflate blocks), so that no compression library is needed; any `tar xzf`
+/// unpacks it.
+///
+use crate::error::RMesgError;
+use crate::Backend;
+
+use std::fs;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The directory the files are under in the archive.
+const BUNDLE_DIR: &str = "rmesg-bundle";
+
+const PRINTK_SETTINGS: [&str; 8] = [
+    "/proc/sys/kernel/printk",
+    "/proc/sys/kernel/printk_devkmsg",
+    "/proc/sys/kernel/printk_ratelimit",
+    "/proc/sys/kernel/printk_ratelimit_burst",
+    "/proc/sys/kernel/dmesg_restrict",
+    "/sys/module/printk/parameters/time",
+    "/sys/module/printk/parameters/ignore_loglevel",
+    "/sys/module/printk/parameters/console_suspend",
+];
+
+const SYSTEM_CONTEXT: [&str; 6] = [
+    "/proc/version",
+    "/proc/cmdline",
+    "/proc/uptime",
+    "/proc/sys/kernel/hostname",
+    "/proc/sys/kernel/random/boot_id",
+    "/proc/sys/kernel/tainted",
+];
+
+/// The files of a support bundle, by name.
+#[derive(Clone, Debug, Default)]
+pub struct Bundle {
+    files: Vec<(String, Vec<u8>)>,
+}
+
+impl Bundle {
+    /// Collects a bundle from this host.
+    pub fn collect() -> Bundle {
+        let mut bundle = Bundle::default();
+
+        bundle.add(
+            "dmesg.txt",
+            match crate::log_entries(Backend::Default, false) {
+                Ok(entries) => entries.iter().map(|e| format!("{}\n", e)).collect(),
+                Err(e) => format!("Unable to read the kernel log: {}\n", e),
+            },
+        );
+        #[cfg(feature = "pstore")]
+        bundle.add(
+            "pstore.txt",
+            match crate::pstore::pstore_raw(None, false) {
+                Ok(raw) => raw,
+                Err(e) => format!("Unable to read pstore: {}\n", e),
+            },
+        );
+        bundle.add("printk.txt", read_settings(&PRINTK_SETTINGS));
+        bundle.add(
+            "system.txt",
+            format!(
+                "rmesg {}\n{}",
+                env!("CARGO_PKG_VERSION"),
+                read_settings(&SYSTEM_CONTEXT)
+            ),
+        );
+        bundle.add("capabilities.txt", capabilities());
+
+        bundle
+    }
+
+    /// Adds (or replaces) a file.
+    pub fn add<C: Into<Vec<u8>>>(&mut self, name: &str, contents: C) {
+        self.files.retain(|(n, _)| n != name);
+        self.files.push((name.to_owned(), contents.into()));
+    }
+
+    /// The names of the files, in the order they're archived.
+    pub fn file_names(&self) -> Vec<&str> {
+        self.files.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    pub fn file(&self, name: &str) -> Option<&[u8]> {
+        self.files
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, contents)| contents.as_slice())
+    }
+
+    /// Writes the bundle as a .tar.gz archive.
+    pub fn write_tar_gz<W: Write>(&self, out: W) -> Result<(), RMesgError> {
+        let mtime = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let mut tar = Vec::new();
+        for (name, contents) in &self.files {
+            let path = format!("{}/{}", BUNDLE_DIR, name);
+            tar.extend_from_slice(&tar_header(&path, contents.len(), mtime)?);
+            tar.extend_from_slice(contents);
+            tar.resize(tar.len().div_ceil(512) * 512, 0);
+        }
+        // the end of the archive is marked by two empty blocks
+        tar.resize(tar.len() + 1024, 0);
+
+        gzip_stored(&tar, mtime, out)
+    }
+
+    /// Writes the bundle as a .tar.gz archive at `path`.
+    pub fn write_to(&self, path: &str) -> Result<(), RMesgError> {
+        let file = fs::File::create(path)?;
+        self.write_tar_gz(std::io::BufWriter::new(file))
+    }
+}
+
+fn read_settings(paths: &[&str]) -> String {
+    paths
+        .iter()
+        .map(|path| match fs::read_to_string(path) {
+            Ok(value) => format!("{}: {}\n", path, value.trim_end()),
+            Err(e) => format!("{}: unavailable ({})\n", path, e),
+        })
+        .collect()
+}
+
+fn capabilities() -> String {
+    [
+        Capability::DevKMsg,
+        Capability::KLogCtl,
+        Capability::Clear,
+        Capability::PStore,
+        Capability::Journald,
+    ]
+    .iter()
+    .map(|capability| match degradation::probe(*capability) {
+        Ok(()) => format!("{}: available\n", capability),
+        Err(e) => format!("{}: unavailable ({})\n", capability, e),
+    })
+    .collect()
+}
+
+fn tar_header(path: &str, size: usize, mtime: u64) -> Result<[u8; 512], RMesgError> {
+    if path.len() > 100 {
+        return Err(RMesgError::InternalError(format!(
+            "Path too long for a tar header: {}",
+            path
+        )));
+    }
+
+    let mut header = [0u8; 512];
+    let mut put = |offset: usize, field: &[u8]| {
+        header[offset..offset + field.len()].copy_from_slice(field);
+    };
+    put(0, path.as_bytes());
+    put(100, b"0000644\0");
+    put(108, b"0000000\0");
+    put(116, b"0000000\0");
+    put(124, format!("{:011o}\0", size).as_bytes());
+    put(136, format!("{:011o}\0", mtime).as_bytes());
+    // the checksum is calculated with its own field as spaces
+    put(148, b"        ");
+    put(156, b"0");
+    put(257, b"ustar\0");
+    put(263, b"00");
+    put(265, b"root");
+    put(297, b"root");
+
+    let checksum: u32 = header.iter().map(|b| *b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    Ok(header)
+}
+
+/// Wraps `data` in gzip, as stored (uncompressed) deflate blocks.
+fn gzip_stored<W: Write>(data: &[u8], mtime: u64, mut out: W) -> Result<(), RMesgError> {
+    let mut header = vec![0x1f, 0x8b, 8, 0];
+    header.extend_from_slice(&(mtime as u32).to_le_bytes());
+    // no extra flags, Unix
+    header.extend_from_slice(&[0, 3]);
+    out.write_all(&header)?;
+
+    let mut blocks = data.chunks(0xffff).peekable();
+    if blocks.peek().is_none() {
+        out.write_all(&[1, 0, 0, 0xff, 0xff])?;
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        out.write_all(&[last as u8])?;
+        out.write_all(&len.to_le_bytes())?;
+        out.write_all(&(!len).to_le_bytes())?;
+        out.write_all(block)?;
+    }
+
+    out.write_all(&crc32(data).to_le_bytes())?;
+    out.write_all(&(data.len() as u32).to_le_bytes())?;
+    out.flush()?;
+    Ok(())
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xedb8_8320,
+                _ => crc >> 1,
+            };
+        }
+    }
+    !crc
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // undoes `gzip_stored`, checking the length and checksum on the way
+    fn gunzip_stored(gz: &[u8]) -> Vec<u8> {
+        assert_eq!(&gz[..4], &[0x1f, 0x8b, 8, 0]);
+        let mut data = Vec::new();
+        let mut at = 10;
+        loop {
+            let last = gz[at] == 1;
+            let len = u16::from_le_bytes([gz[at + 1], gz[at + 2]]) as usize;
+            assert_eq!(!u16::from_le_bytes([gz[at + 3], gz[at + 4]]) as usize, len);
+            data.extend_from_slice(&gz[at + 5..at + 5 + len]);
+            at += 5 + len;
+            if last {
+                break;
+            }
+        }
+        assert_eq!(gz[at..at + 4], crc32(&data).to_le_bytes());
+        assert_eq!(gz[at + 4..], (data.len() as u32).to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn test_tar_gz() {
+        let mut bundle = Bundle::default();
+        bundle.add("dmesg.txt", "[    1.000000] usb 1-1: new device\n");
+        bundle.add("big.txt", vec![b'x'; 70_000]);
+        bundle.add("dmesg.txt", "replaced\n");
+        assert_eq!(bundle.file_names(), vec!["big.txt", "dmesg.txt"]);
+
+        let mut gz = Vec::new();
+        bundle.write_tar_gz(&mut gz).unwrap();
+        let tar = gunzip_stored(&gz);
+
+        // header, 70,000 bytes padded to 137 blocks, header, one block, end marker
+        assert_eq!(tar.len(), 512 * (1 + 137 + 1 + 1 + 2));
+        assert!(tar.starts_with(b"rmesg-bundle/big.txt\0"));
+        assert_eq!(&tar[124..136], b"00000210560\0");
+        let checksum: u32 = tar[..512]
+            .iter()
+            .enumerate()
+            .map(|(i, b)| {
+                if (148..156).contains(&i) {
+                    32
+                } else {
+                    *b as u32
+                }
+            })
+            .sum();
+        assert_eq!(&tar[148..155], format!("{:06o}\0", checksum).as_bytes());
+
+        let second = 512 * 138;
+        assert!(tar[second..].starts_with(b"rmesg-bundle/dmesg.txt\0"));
+        assert_eq!(&tar[second + 512..second + 521], b"replaced\n");
+        assert!(tar[tar.len() - 1024..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_collect() {
+        let bundle = Bundle::collect();
+        for name in &["dmesg.txt", "printk.txt", "system.txt", "capabilities.txt"] {
+            assert!(bundle.file(name).is_some(), "{} missing", name);
+        }
+        let system = String::from_utf8_lossy(bundle.file("system.txt").unwrap()).into_owned();
+        assert!(system.starts_with("rmesg "));
+        assert!(system.contains("/proc/version: "));
+    }
+}
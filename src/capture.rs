@@ -0,0 +1,122 @@
+use crate::entry::Entry;
+/// Capture of the kernel messages emitted while a piece of code runs.
+///
+/// Designed for hardware and driver integration tests: wrap the code exercising the
+/// device in `capture_during`, and inspect what the kernel had to say about it.
+///
+use crate::error::RMesgError;
+use crate::kmsgfile;
+
+use std::fs as stdfs;
+use std::io::Write;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DEV_KMSG_PATH: &str = "/dev/kmsg";
+
+/// Markers are logged at facility user, level info
+const MARKER_FACLEV: u8 = (1 << 3) + 6;
+
+static MARKER_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Runs `f`, and returns its result along with the kernel messages logged while it ran.
+///
+/// Before and after running `f`, a uniquely identifiable marker is written to /dev/kmsg
+/// and only entries between the two markers are returned. Writing markers requires
+/// permission to write to /dev/kmsg (usually root). When a marker can't be written, the
+/// capture falls back to bracketing by sequence number: everything logged after the
+/// newest entry present before `f` ran, up to the time of reading after it returned.
+///
+/// The markers themselves are never included.
+pub fn capture_during<T, F: FnOnce() -> T>(f: F) -> Result<(T, Vec<Entry>), RMesgError> {
+    let start_sequence_num = kmsgfile::kmsg(None)?
+        .iter()
+        .rev()
+        .find_map(|e| e.sequence_num);
+
+    let id = marker_id();
+    let begin_marker = format!("rmesg: capture {} begin", id);
+    let end_marker = format!("rmesg: capture {} end", id);
+
+    let begin_written = write_marker(&begin_marker).is_ok();
+    let result = f();
+    let end_written = write_marker(&end_marker).is_ok();
+
+    let entries = kmsgfile::kmsg(None)?;
+
+    let mut captured = Vec::new();
+    let mut capturing = false;
+    for entry in entries {
+        if !capturing {
+            capturing = match (begin_written, entry.sequence_num, start_sequence_num) {
+                (true, _, _) => entry.message == begin_marker,
+                (false, Some(seq), Some(start)) => seq > start,
+                (false, Some(_), None) => true,
+                (false, None, _) => false,
+            };
+            // the begin marker itself isn't captured, but a fallback start is
+            if begin_written || !capturing {
+                continue;
+            }
+        }
+
+        if end_written && entry.message == end_marker {
+            break;
+        }
+        captured.push(entry);
+    }
+
+    Ok((result, captured))
+}
+
+pub(crate) fn marker_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!(
+        "{}-{}-{}",
+        process::id(),
+        nanos,
+        MARKER_COUNTER.fetch_add(1, Ordering::SeqCst)
+    )
+}
+
+pub(crate) fn write_marker(marker: &str) -> Result<(), RMesgError> {
+    let mut kmsg = stdfs::OpenOptions::new().write(true).open(DEV_KMSG_PATH)?;
+    kmsg.write_all(format!("<{}>{}\n", MARKER_FACLEV, marker).as_bytes())?;
+    Ok(())
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_capture_during() {
+        let ((), entries) = capture_during(|| {
+            write_marker("rmesg: test_capture_during was here").unwrap();
+        })
+        .unwrap();
+
+        // other tests may be logging concurrently, so just look for ours
+        assert!(entries
+            .iter()
+            .any(|e| e.message == "rmesg: test_capture_during was here"));
+    }
+
+    #[test]
+    fn test_capture_returns_result() {
+        let (result, _) = capture_during(|| 42).unwrap();
+        assert_eq!(result, 42);
+    }
+
+    #[test]
+    fn test_marker_ids_unique() {
+        assert_ne!(marker_id(), marker_id());
+    }
+}
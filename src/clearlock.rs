@@ -0,0 +1,97 @@
+use crate::error::RMesgError;
+/// Advisory locking around clearing the kernel log buffer.
+///
+/// READ_CLEAR is destructive: when two tools on the same host both read-and-clear,
+/// each drops whatever the other hasn't read yet. Tools that agree to take a
+/// `ClearLock` (an flock on a well-known path) before clearing can't race each other;
+/// the second one to try gets `RMesgError::LockContention` instead of losing data.
+///
+/// The lock is advisory, so it only protects against other tools that also take it.
+///
+use crate::klogctl;
+
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Where rmesg-based tools coordinate clearing by default.
+pub const DEFAULT_LOCK_PATH: &str = "/run/rmesg-clear.lock";
+
+/// A held clear lock. It is released when dropped.
+#[derive(Debug)]
+pub struct ClearLock {
+    path: PathBuf,
+    _file: File,
+}
+
+impl ClearLock {
+    /// Takes the lock at `DEFAULT_LOCK_PATH`.
+    pub fn acquire() -> Result<ClearLock, RMesgError> {
+        ClearLock::acquire_at(DEFAULT_LOCK_PATH)
+    }
+
+    /// Takes the lock at `path` (created if necessary), without waiting: if someone
+    /// else holds it, fails with `RMesgError::LockContention`.
+    pub fn acquire_at<P: AsRef<Path>>(path: P) -> Result<ClearLock, RMesgError> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = std::io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::EWOULDBLOCK) => Err(RMesgError::LockContention(format!(
+                    "{} is held by another process clearing the kernel log",
+                    path.display()
+                ))),
+                _ => Err(err.into()),
+            };
+        }
+
+        Ok(ClearLock {
+            path: path.to_owned(),
+            _file: file,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Like `clear`, but only while holding the clear lock at `DEFAULT_LOCK_PATH`.
+pub fn clear_locked() -> Result<klogctl::ClearedVolume, RMesgError> {
+    let _lock = ClearLock::acquire()?;
+    klogctl::klog_clear()
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_contention() {
+        let path = std::env::temp_dir().join(format!("rmesg-clearlock-{}", std::process::id()));
+
+        let lock = ClearLock::acquire_at(&path).unwrap();
+        assert_eq!(lock.path(), path.as_path());
+
+        // flock locks belong to the open file, so a second open contends even in-process
+        assert!(matches!(
+            ClearLock::acquire_at(&path),
+            Err(RMesgError::LockContention(_))
+        ));
+
+        drop(lock);
+        assert!(ClearLock::acquire_at(&path).is_ok());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
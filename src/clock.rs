@@ -0,0 +1,234 @@
+use crate::entry::Entry;
+/// Conversion of kernel timestamps (time since system start) to wall-clock time.
+///
+/// Kernel timestamps don't advance while the system is suspended, and the wall clock
+/// may be stepped after boot, so a conversion is only as good as its anchor: the
+/// wall-clock time the kernel timestamps are counted from. `WallClock::observe` lets
+/// a reader re-anchor as it comes across entries reporting the clock being set.
+/// As a stage, a `WallClock` fills in each entry's `timestamp_realtime`, so sinks can
+/// pick whichever timestamp suits them.
+///
+#[cfg(unix)]
+use crate::error::RMesgError;
+use crate::events::clock::ClockEvent;
+use crate::stage::Stage;
+
+use std::time::{Duration, SystemTime};
+
+/// Converts kernel timestamps to wall-clock time, from an anchor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WallClock {
+    boot_time: SystemTime,
+}
+
+impl WallClock {
+    /// Anchors to the current wall clock, less the time since system start
+    /// (the same thing `dmesg -T` does).
+    #[cfg(unix)]
+    pub fn now() -> Result<WallClock, RMesgError> {
+        let since_start = since_system_start()?;
+        match SystemTime::now().checked_sub(since_start) {
+            Some(boot_time) => Ok(WallClock { boot_time }),
+            None => Err(RMesgError::UnableToObtainSystemTime),
+        }
+    }
+
+    /// Anchors to the wall-clock time the kernel says the system started at (the btime
+    /// line of /proc/stat). That's to the second only, but it's the same for every
+    /// reader throughout the boot, where `now` varies by a little each time it's called.
+    #[cfg(target_os = "linux")]
+    pub fn from_proc_stat() -> Result<WallClock, RMesgError> {
+        let stat = std::fs::read_to_string("/proc/stat")?;
+        match boot_time_from_stat(&stat) {
+            Some(boot_time) => Ok(WallClock { boot_time }),
+            None => Err(RMesgError::UnableToObtainSystemTime),
+        }
+    }
+
+    /// Anchors to a known wall-clock time the system started at.
+    pub fn with_boot_time(boot_time: SystemTime) -> WallClock {
+        WallClock { boot_time }
+    }
+
+    /// The wall-clock time kernel timestamps are currently counted from.
+    pub fn boot_time(&self) -> SystemTime {
+        self.boot_time
+    }
+
+    /// The wall-clock time of a kernel timestamp.
+    pub fn to_system_time(&self, timestamp_from_system_start: Duration) -> SystemTime {
+        self.boot_time + timestamp_from_system_start
+    }
+
+    /// Re-anchors so that `timestamp_from_system_start` converts to `wall_time`.
+    pub fn reanchor(&mut self, timestamp_from_system_start: Duration, wall_time: SystemTime) {
+        if let Some(boot_time) = wall_time.checked_sub(timestamp_from_system_start) {
+            self.boot_time = boot_time;
+        }
+    }
+
+    /// Looks for a clock event in `entry`, re-anchoring when it tells what the wall
+    /// clock was at the time (as the RTC setting the system clock does), and returns it.
+    ///
+    /// Call this on entries in the order they were logged for conversions to follow
+    /// the clock being set. Events that change the clock without saying to what
+    /// (NTP steps, resume from suspend) can't be re-anchored from the log alone; if
+    /// reading live, re-anchor with `WallClock::now()` when they show up.
+    pub fn observe(&mut self, entry: &Entry) -> Option<ClockEvent> {
+        let event = ClockEvent::from_entry(entry)?;
+        if let (ClockEvent::RtcSet { unix_time }, Some(timestamp)) =
+            (&event, entry.timestamp_from_system_start)
+        {
+            self.reanchor(
+                timestamp,
+                SystemTime::UNIX_EPOCH + Duration::from_secs(*unix_time),
+            );
+        }
+        Some(event)
+    }
+
+    /// Sets `entry.timestamp_realtime` from its kernel timestamp, after observing it (so
+    /// an entry setting the clock is converted with the new anchor).
+    pub fn stamp(&mut self, entry: &mut Entry) {
+        self.observe(entry);
+        if let Some(timestamp) = entry.timestamp_from_system_start {
+            entry.timestamp_realtime = Some(self.to_system_time(timestamp));
+        }
+    }
+}
+
+// The btime line of /proc/stat: btime 1760436000 (seconds since the epoch)
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn boot_time_from_stat(stat: &str) -> Option<SystemTime> {
+    let secs = stat
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// The current time since system start, on the clock kernel timestamps are taken from.
+#[cfg(unix)]
+pub(crate) fn since_system_start() -> Result<Duration, RMesgError> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+impl Stage for WallClock {
+    fn process(&mut self, mut entry: Entry) -> Option<Entry> {
+        self.stamp(&mut entry);
+        Some(entry)
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_now() {
+        let clock = WallClock::now().unwrap();
+        assert!(clock.boot_time() <= SystemTime::now());
+    }
+
+    #[test]
+    fn test_boot_time_from_stat() {
+        let stat = "cpu  1 2 3 4\nintr 5\nctxt 6\nbtime 1760436000\nprocesses 7\n";
+        assert_eq!(
+            boot_time_from_stat(stat),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_760_436_000))
+        );
+        assert_eq!(boot_time_from_stat("cpu  1 2 3 4\n"), None);
+        assert_eq!(boot_time_from_stat("btime soon\n"), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_from_proc_stat() {
+        let clock = WallClock::from_proc_stat().unwrap();
+        // the two anchors agree, give or take btime's rounding and any time suspended
+        assert!(
+            clock.boot_time() <= WallClock::now().unwrap().boot_time() + Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn test_observe_reanchors() {
+        let epoch = SystemTime::UNIX_EPOCH;
+        let mut clock = WallClock::with_boot_time(epoch);
+        assert_eq!(
+            clock.to_system_time(Duration::from_secs(5)),
+            epoch + Duration::from_secs(5)
+        );
+
+        let rtc = Entry {
+            timestamp_from_system_start: Some(Duration::from_secs(2)),
+            message: "rtc_cmos 00:00: setting system clock to 2021-01-01T00:00:00 UTC (1609459200)"
+                .to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(
+            clock.observe(&rtc),
+            Some(ClockEvent::RtcSet {
+                unix_time: 1609459200
+            })
+        );
+        assert_eq!(clock.boot_time(), epoch + Duration::from_secs(1609459198));
+
+        let other = Entry {
+            message: "usb 1-1: new high-speed USB device".to_owned(),
+            ..rtc
+        };
+        assert_eq!(clock.observe(&other), None);
+        assert_eq!(clock.boot_time(), epoch + Duration::from_secs(1609459198));
+    }
+
+    #[test]
+    fn test_stamp() {
+        let mut clock = WallClock::with_boot_time(SystemTime::UNIX_EPOCH);
+        let entry = |secs: u64, message: &str| Entry {
+            timestamp_from_system_start: Some(Duration::from_secs(secs)),
+            message: message.to_owned(),
+            ..Default::default()
+        };
+
+        let stamped = clock.process(entry(5, "usb 1-1: new device")).unwrap();
+        assert_eq!(
+            stamped.timestamp_realtime,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(5))
+        );
+
+        // the entry setting the clock is already on the new one
+        let stamped = clock
+            .process(entry(
+                10,
+                "rtc_cmos 00:00: setting system clock to 2021-01-01T00:00:00 UTC (1609459200)",
+            ))
+            .unwrap();
+        assert_eq!(
+            stamped.timestamp_realtime,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1609459200))
+        );
+        // and so are the ones after it; the kernel timestamp is untouched
+        let stamped = clock.process(entry(11, "usb 1-1: new device")).unwrap();
+        assert_eq!(
+            stamped.timestamp_from_system_start,
+            Some(Duration::from_secs(11))
+        );
+        assert_eq!(
+            stamped.timestamp_realtime,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1609459201))
+        );
+    }
+}
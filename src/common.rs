@@ -1,3 +1,6 @@
+// Helpers shared by the backends, not all of which may be enabled
+#![allow(dead_code)]
+
 use crate::entry::{EntryParsingError, LogFacility, LogLevel};
 use num::FromPrimitive;
 use std::any::type_name;
@@ -11,7 +14,13 @@ pub fn parse_favlecstr(
     faclevstr: &str,
     line: &str,
 ) -> Result<(Option<LogFacility>, Option<LogLevel>), EntryParsingError> {
-    let faclev = parse_fragment::<u32>(faclevstr, line)?;
+    decode_faclev(parse_fragment::<u32>(faclevstr, line)?, line)
+}
+
+pub fn decode_faclev(
+    faclev: u32,
+    line: &str,
+) -> Result<(Option<LogFacility>, Option<LogLevel>), EntryParsingError> {
     // facility is top 28 bits, log level is bottom 3 bits
     match (
         LogFacility::from_u32(faclev >> 3),
@@ -29,10 +38,13 @@ pub fn parse_timestamp_secs(
     timestampstr: &str,
     line: &str,
 ) -> Result<Option<Duration>, EntryParsingError> {
-    Ok(Some(Duration::from_secs_f64(parse_fragment::<f64>(
-        timestampstr,
-        line,
-    )?)))
+    let secs = parse_fragment::<f64>(timestampstr, line)?;
+    Duration::try_from_secs_f64(secs).map(Some).map_err(|e| {
+        EntryParsingError::Generic(format!(
+            "Timestamp {} isn't a duration ({}). Line: {}",
+            timestampstr, e, line
+        ))
+    })
 }
 
 pub fn parse_timestamp_microsecs(
@@ -60,3 +72,155 @@ where
         ))),
     }
 }
+
+/// Counts the lines in `buf` whose first byte satisfies `starts_record`. This gives
+/// the number of records in a raw buffer without parsing (or allocating) any of them.
+pub fn count_records(buf: &[u8], starts_record: impl Fn(u8) -> bool) -> usize {
+    buf.split(|b| *b == b'\n')
+        .filter(|line| matches!(line.first(), Some(b) if starts_record(*b)))
+        .count()
+}
+
+// The kernel escapes these in /dev/kmsg records (see msg_add_ext_text in
+// kernel/printk/printk.c), other than bytes >= 0x80: a String is already valid UTF-8
+fn needs_escaping(c: char) -> bool {
+    c < ' ' || c == '\x7f' || c == '\\'
+}
+
+/// Appends `message` to `out`, escaping it as /dev/kmsg does.
+pub fn escape_kmsg_message(message: &str, out: &mut String) {
+    if !message.chars().any(needs_escaping) {
+        out.push_str(message);
+        return;
+    }
+    for c in message.chars() {
+        match needs_escaping(c) {
+            true => out.push_str(&format!("\\x{:02x}", c as u32)),
+            false => out.push(c),
+        }
+    }
+}
+
+/// Undoes the \xNN escaping in a /dev/kmsg message. Escaped bytes that don't make up
+/// valid UTF-8 become U+FFFD, and anything that isn't a well-formed escape is left as is.
+pub fn unescape_kmsg_message(message: &str) -> String {
+    let mut unescaped = String::new();
+    unescape_kmsg_message_into(message, &mut unescaped);
+    unescaped
+}
+
+/// `unescape_kmsg_message` into `unescaped`, replacing what it held. Unescaping never
+/// lengthens a message, so this only allocates when `unescaped` hasn't the capacity for
+/// the escaped one.
+pub fn unescape_kmsg_message_into(message: &str, unescaped: &mut String) {
+    unescaped.clear();
+    if !message.contains("\\x") {
+        unescaped.push_str(message);
+        return;
+    }
+
+    let mut bytes = std::mem::take(unescaped).into_bytes();
+    bytes.reserve(message.len());
+    let message = message.as_bytes();
+    // where the run of escaped bytes since the last one that wasn't escaped starts
+    let mut escaped_from = 0;
+    let mut i = 0;
+    while i < message.len() {
+        let escaped = match message.get(i..i + 4) {
+            Some([b'\\', b'x', hi, lo]) if hi.is_ascii_hexdigit() && lo.is_ascii_hexdigit() => {
+                u8::from_str_radix(std::str::from_utf8(&[*hi, *lo]).unwrap(), 16).ok()
+            }
+            _ => None,
+        };
+        match escaped {
+            Some(byte) => {
+                bytes.push(byte);
+                i += 4;
+            }
+            None => {
+                replace_invalid_utf8(&mut bytes, escaped_from);
+                bytes.push(message[i]);
+                escaped_from = bytes.len();
+                i += 1;
+            }
+        }
+    }
+    replace_invalid_utf8(&mut bytes, escaped_from);
+
+    // valid UTF-8 by now: everything but the escaped bytes came from a str
+    *unescaped = String::from_utf8(bytes)
+        .unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned());
+}
+
+// Replaces what isn't UTF-8 in bytes[start..] with U+FFFD, as String::from_utf8_lossy
+// does. The replacement is built after the end and moved down, which fits in the
+// capacity of the escaped message: each of these bytes took 4 there, and takes at most
+// 3 here.
+fn replace_invalid_utf8(bytes: &mut Vec<u8>, start: usize) {
+    if std::str::from_utf8(&bytes[start..]).is_ok() {
+        return;
+    }
+    let end = bytes.len();
+    let mut i = start;
+    while i < end {
+        match std::str::from_utf8(&bytes[i..end]) {
+            Ok(_) => {
+                bytes.extend_from_within(i..end);
+                break;
+            }
+            Err(e) => {
+                let valid = e.valid_up_to();
+                bytes.extend_from_within(i..i + valid);
+                bytes.extend_from_slice("\u{fffd}".as_bytes());
+                i += valid + e.error_len().unwrap_or(end - i - valid);
+            }
+        }
+    }
+    bytes.drain(start..end);
+}
+
+// Days since 1970-01-01 of a date in the proleptic Gregorian calendar (Howard Hinnant's
+// days_from_civil)
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (i64::from(month) + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// The date `days` after 1970-01-01 (Howard Hinnant's civil_from_days)
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_days_from_civil() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        assert_eq!(days_from_civil(2024, 1, 2), 19_724);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        for days in [-1, 0, 59, 11_017, 19_724, 2_932_896] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+}
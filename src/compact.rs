@@ -0,0 +1,304 @@
+use crate::entry::{Entry, LogFacility, LogLevel};
+/// Compact snapshots: many entries, with every message in one arena.
+///
+/// Each `Entry` owns its message, so a snapshot of a million entries is a million
+/// allocations to make and as many to free, each a few dozen bytes (most kernel messages
+/// are short) with the allocator's overhead on top. A `CompactSnapshot` appends every
+/// message to one string instead, and keeps the other fields in a fixed-size record per
+/// entry: two growing allocations in all, and no pointer chasing to scan it. Entries are
+/// read back as `EntryRef`s borrowing their message, or turned back into `Entry`s.
+///
+/// `read_compact` fills one straight from /dev/kmsg, without an allocation per record;
+/// `benches/hotpaths.rs` compares it against collecting a `Vec<Entry>`. Provenance and
+/// tags aren't kept: a snapshot comes from one source, before any stages.
+///
+#[cfg(feature = "kmsg")]
+use crate::error::RMesgError;
+#[cfg(feature = "kmsg")]
+use crate::staticbuf;
+
+use std::iter::FromIterator;
+use std::mem;
+use std::time::{Duration, SystemTime};
+
+// An entry's fields but its message, which is text[start..end]
+#[derive(Clone, Debug, PartialEq)]
+struct Record {
+    facility: Option<LogFacility>,
+    level: Option<LogLevel>,
+    malformed: bool,
+    priority: Option<u32>,
+    sequence_num: Option<usize>,
+    timestamp_from_system_start: Option<Duration>,
+    timestamp_realtime: Option<SystemTime>,
+    start: usize,
+    end: usize,
+}
+
+/// An entry of a `CompactSnapshot`, borrowing its message from it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EntryRef<'a> {
+    pub facility: Option<LogFacility>,
+    pub level: Option<LogLevel>,
+    pub sequence_num: Option<usize>,
+    pub timestamp_from_system_start: Option<Duration>,
+    pub timestamp_realtime: Option<SystemTime>,
+    pub message: &'a str,
+    pub malformed: bool,
+    pub priority: Option<u32>,
+}
+
+impl EntryRef<'_> {
+    pub fn to_entry(&self) -> Entry {
+        Entry {
+            facility: self.facility,
+            level: self.level,
+            sequence_num: self.sequence_num,
+            timestamp_from_system_start: self.timestamp_from_system_start,
+            message: self.message.to_owned(),
+            timestamp_realtime: self.timestamp_realtime,
+            provenance: None,
+            malformed: self.malformed,
+            priority: self.priority,
+            tags: Default::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CompactSnapshot {
+    text: String,
+    records: Vec<Record>,
+}
+
+impl CompactSnapshot {
+    pub fn new() -> CompactSnapshot {
+        CompactSnapshot::default()
+    }
+
+    /// An empty snapshot with room for `entries` entries, and `text` bytes of messages.
+    pub fn with_capacity(entries: usize, text: usize) -> CompactSnapshot {
+        CompactSnapshot {
+            text: String::with_capacity(text),
+            records: Vec::with_capacity(entries),
+        }
+    }
+
+    /// Appends a copy of `entry` (less its provenance and tags).
+    pub fn push(&mut self, entry: &Entry) {
+        let start = self.text.len();
+        self.text.push_str(&entry.message);
+        self.records.push(Record {
+            facility: entry.facility,
+            level: entry.level,
+            malformed: entry.malformed,
+            priority: entry.priority,
+            sequence_num: entry.sequence_num,
+            timestamp_from_system_start: entry.timestamp_from_system_start,
+            timestamp_realtime: entry.timestamp_realtime,
+            start,
+            end: self.text.len(),
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<EntryRef<'_>> {
+        self.records.get(index).map(|record| self.entry_ref(record))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = EntryRef<'_>> + '_ {
+        self.records
+            .iter()
+            .map(move |record| self.entry_ref(record))
+    }
+
+    /// The entries, as `Entry`s (which allocates one message each).
+    pub fn to_entries(&self) -> Vec<Entry> {
+        self.iter().map(|entry| entry.to_entry()).collect()
+    }
+
+    /// Roughly how much memory the snapshot takes up, as `Entry::approx_size` counts it.
+    pub fn approx_size(&self) -> usize {
+        self.text.capacity() + self.records.capacity() * mem::size_of::<Record>()
+    }
+
+    fn entry_ref<'a>(&'a self, record: &Record) -> EntryRef<'a> {
+        EntryRef {
+            facility: record.facility,
+            level: record.level,
+            sequence_num: record.sequence_num,
+            timestamp_from_system_start: record.timestamp_from_system_start,
+            timestamp_realtime: record.timestamp_realtime,
+            message: &self.text[record.start..record.end],
+            malformed: record.malformed,
+            priority: record.priority,
+        }
+    }
+}
+
+impl<'a> Extend<&'a Entry> for CompactSnapshot {
+    fn extend<I: IntoIterator<Item = &'a Entry>>(&mut self, entries: I) {
+        for entry in entries {
+            self.push(entry);
+        }
+    }
+}
+
+impl<'a> FromIterator<&'a Entry> for CompactSnapshot {
+    fn from_iter<I: IntoIterator<Item = &'a Entry>>(entries: I) -> CompactSnapshot {
+        let mut snapshot = CompactSnapshot::new();
+        snapshot.extend(entries);
+        snapshot
+    }
+}
+
+/// Parses a buffer of /dev/kmsg records (one per line, as `kmsgfile::kmsg_raw` returns
+/// them) into a compact snapshot, reusing one message buffer throughout. Dictionary
+/// lines (starting with a space) are skipped.
+#[cfg(feature = "kmsg")]
+pub fn compact_from_kmsg_buffer(raw: &str) -> Result<CompactSnapshot, RMesgError> {
+    let mut scratch = scratch_entry();
+    let mut snapshot = CompactSnapshot::new();
+    for line in raw.lines().filter(|l| !l.is_empty() && !l.starts_with(' ')) {
+        staticbuf::parse_record_into(line.as_bytes(), &mut scratch)?;
+        snapshot.push(&scratch);
+    }
+    Ok(snapshot)
+}
+
+/// Reads /dev/kmsg as it is now into a compact snapshot, without an allocation per
+/// record (see `staticbuf::StaticBufferReader`).
+/// `file_override`: When `Some`, overrides the path from where to read the kernel logs
+#[cfg(feature = "kmsg")]
+pub fn read_compact(file_override: Option<String>) -> Result<CompactSnapshot, RMesgError> {
+    let mut reader = staticbuf::StaticBufferReader::with_options(
+        file_override,
+        staticbuf::SUGGESTED_RECORD_CAPACITY,
+        false,
+    )?;
+    let mut snapshot = CompactSnapshot::new();
+    while let Some(entry) = reader.next_entry()? {
+        snapshot.push(entry);
+    }
+    Ok(snapshot)
+}
+
+#[cfg(feature = "kmsg")]
+fn scratch_entry() -> Entry {
+    Entry {
+        facility: None,
+        level: None,
+        sequence_num: None,
+        timestamp_from_system_start: None,
+        message: String::with_capacity(staticbuf::SUGGESTED_RECORD_CAPACITY),
+        timestamp_realtime: None,
+        provenance: None,
+        malformed: false,
+        priority: None,
+        tags: Default::default(),
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entries(n: usize) -> Vec<Entry> {
+        (0..n)
+            .map(|i| Entry {
+                facility: Some(LogFacility::Kern),
+                level: Some(LogLevel::Info),
+                sequence_num: Some(i),
+                timestamp_from_system_start: Some(Duration::from_micros(37 * i as u64)),
+                message: format!("usb 1-{}: new high-speed USB device number {}", i % 4, i),
+                priority: Some(6),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut entries = entries(100);
+        entries[3].malformed = true;
+        entries[4].timestamp_realtime = Some(SystemTime::UNIX_EPOCH);
+        entries[5].message.clear();
+
+        let snapshot: CompactSnapshot = entries.iter().collect();
+        assert_eq!(snapshot.len(), 100);
+        assert_eq!(snapshot.get(5).unwrap().message, "");
+        assert_eq!(snapshot.get(6).unwrap().message, entries[6].message);
+        assert_eq!(snapshot.get(100), None);
+        assert_eq!(snapshot.to_entries(), entries);
+
+        // smaller than the entries themselves
+        let entries_size: usize = entries
+            .iter()
+            .map(|e| mem::size_of::<Entry>() + e.approx_size())
+            .sum();
+        assert!(snapshot.approx_size() < entries_size);
+    }
+
+    #[cfg(feature = "kmsg")]
+    #[test]
+    fn test_compact_from_kmsg_buffer() {
+        let raw = "6,1,0,-;Linux version 5.10.0\n \
+                   SUBSYSTEM=acpi\n\
+                   4,2,1500000,-;ACPI: 2 ACPI AML tables successfully acquired and loaded\n";
+        let snapshot = compact_from_kmsg_buffer(raw).unwrap();
+        assert_eq!(snapshot.len(), 2);
+
+        let second = snapshot.get(1).unwrap();
+        assert_eq!(second.level, Some(LogLevel::Warning));
+        assert_eq!(second.sequence_num, Some(2));
+        assert_eq!(
+            second.timestamp_from_system_start,
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(
+            second.message,
+            "ACPI: 2 ACPI AML tables successfully acquired and loaded"
+        );
+
+        assert!(compact_from_kmsg_buffer("no separator\n").is_err());
+    }
+
+    #[cfg(feature = "kmsg")]
+    #[test]
+    fn test_compact_escaped_round_trip() {
+        let mut escaped = entries(2);
+        escaped[0].message = "usb 1-1: Product: Caf\u{e9}\tPro".to_owned();
+        escaped[1].message = "a\\x41 backslash".to_owned();
+        let raw: String = escaped
+            .iter()
+            .map(|e| e.to_kmsg_str().unwrap() + "\n")
+            .collect();
+        assert!(raw.contains("\\x"));
+
+        // the same as /dev/kmsg's records parse to
+        let snapshot = compact_from_kmsg_buffer(&raw).unwrap();
+        assert_eq!(snapshot.to_entries(), escaped);
+        assert_eq!(snapshot.to_entries(), crate::parse::parse_buffer(&raw));
+    }
+
+    #[cfg(all(feature = "kmsg", target_os = "linux"))]
+    #[test]
+    fn test_read_compact() {
+        let snapshot = read_compact(None).unwrap();
+        assert!(!snapshot.is_empty(), "Should have non-empty logs");
+        assert!(snapshot
+            .iter()
+            .zip(snapshot.iter().skip(1))
+            .all(|(a, b)| a.sequence_num < b.sequence_num));
+    }
+}
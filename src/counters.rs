@@ -0,0 +1,175 @@
+use crate::entry::Entry;
+/// Counters of the work done on the hot paths (parsing, poll diffing, formatting).
+///
+/// Timings are noisy, but the amount of work isn't: tests assert on these counters
+/// so that a change making a hot path do more work per entry (say, scanning a poll's
+/// entries more than once) fails a test rather than slipping into a release. The
+/// criterion benchmarks (`cargo bench --features bench`) cover the timings.
+///
+/// Counters are per thread, so that tests running in parallel don't see each other's
+/// work. Without the `bench` feature they aren't compiled in at all.
+///
+use std::cell::Cell;
+
+/// A hot path that is counted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Counter {
+    /// Lines parsed (in either the kmsg or console format)
+    LinesParsed,
+    /// Polls of the buffer through klogctl
+    KLogPolls,
+    /// Entries looked at while diffing a poll against the previous one
+    PollEntriesScanned,
+    /// Entries formatted (for display, or as kmsg or klog records)
+    EntriesFormatted,
+}
+
+const COUNTERS: usize = 4;
+
+thread_local! {
+    static COUNTS: [Cell<u64>; COUNTERS] = const { [const { Cell::new(0) }; COUNTERS] };
+}
+
+/// The counts on this thread, as of when they were taken.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Counts {
+    pub lines_parsed: u64,
+    pub klog_polls: u64,
+    pub poll_entries_scanned: u64,
+    pub entries_formatted: u64,
+}
+
+impl Counts {
+    /// The counts on this thread now.
+    pub fn now() -> Counts {
+        COUNTS.with(|counts| Counts {
+            lines_parsed: counts[Counter::LinesParsed as usize].get(),
+            klog_polls: counts[Counter::KLogPolls as usize].get(),
+            poll_entries_scanned: counts[Counter::PollEntriesScanned as usize].get(),
+            entries_formatted: counts[Counter::EntriesFormatted as usize].get(),
+        })
+    }
+
+    /// The work done on this thread since these counts were taken.
+    pub fn elapsed(&self) -> Counts {
+        let now = Counts::now();
+        Counts {
+            lines_parsed: now.lines_parsed - self.lines_parsed,
+            klog_polls: now.klog_polls - self.klog_polls,
+            poll_entries_scanned: now.poll_entries_scanned - self.poll_entries_scanned,
+            entries_formatted: now.entries_formatted - self.entries_formatted,
+        }
+    }
+}
+
+pub(crate) fn bump(counter: Counter) {
+    add(counter, 1);
+}
+
+pub(crate) fn add(counter: Counter, n: u64) {
+    COUNTS.with(|counts| {
+        let count = &counts[counter as usize];
+        count.set(count.get() + n);
+    });
+}
+
+/// A synthetic buffer of `n` kmsg records, shaped like a real boot's (a mix of levels,
+/// subsystems and message lengths), for benchmarks.
+pub fn synthetic_kmsg_buffer(n: usize) -> String {
+    synthetic_entries(n)
+        .iter()
+        .map(|entry| entry.to_kmsg_str().unwrap() + "\n")
+        .collect()
+}
+
+/// A synthetic buffer of `n` console-format (klogctl) records, for benchmarks.
+pub fn synthetic_klog_buffer(n: usize) -> String {
+    synthetic_entries(n)
+        .iter()
+        .map(|entry| entry.to_klog_str().unwrap() + "\n")
+        .collect()
+}
+
+/// `n` synthetic entries, 37µs apart, for benchmarks.
+pub fn synthetic_entries(n: usize) -> Vec<Entry> {
+    const MESSAGES: [&str; 6] = [
+        "usb 1-1: new high-speed USB device number 2 using xhci_hcd",
+        "nvme nvme0: I/O 12 QID 3 timeout, aborting",
+        "EXT4-fs (sda1): mounted filesystem with ordered data mode. Opts: (null)",
+        "e1000e 0000:00:19.0 eth0: NIC Link is Up 1000 Mbps Full Duplex, Flow Control: None",
+        "audit: type=1400 audit(1614200000.123:42): apparmor=\"STATUS\" operation=\"profile_load\"",
+        "x86/fpu: Supporting XSAVE feature 0x001: 'x87 floating point registers'",
+    ];
+
+    (0..n)
+        .map(|i| Entry {
+            facility: Some(crate::entry::LogFacility::Kern),
+            level: num::FromPrimitive::from_usize(i % 8),
+            sequence_num: Some(i),
+            timestamp_from_system_start: Some(std::time::Duration::from_micros(
+                1_000 + 37 * i as u64,
+            )),
+            message: MESSAGES[i % MESSAGES.len()].to_owned(),
+            timestamp_realtime: None,
+            provenance: None,
+            malformed: false,
+            priority: None,
+            tags: Default::default(),
+        })
+        .collect()
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_counts() {
+        let kmsg_buffer = synthetic_kmsg_buffer(100);
+        let klog_buffer = synthetic_klog_buffer(50);
+        let start = Counts::now();
+        for line in kmsg_buffer.lines() {
+            crate::parse::kmsg_entry_from_line(line).unwrap();
+        }
+        for line in klog_buffer.lines() {
+            crate::parse::console_entry_from_line(line).unwrap();
+        }
+        // each line is parsed once, and nothing else is done along the way
+        assert_eq!(
+            start.elapsed(),
+            Counts {
+                lines_parsed: 150,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_format_counts() {
+        let entries = synthetic_entries(10);
+        let start = Counts::now();
+        for entry in &entries {
+            entry.to_string();
+            entry.to_kmsg_str().unwrap();
+            entry.to_klog_str().unwrap();
+        }
+        assert_eq!(start.elapsed().entries_formatted, 30);
+    }
+
+    #[cfg(feature = "klogctl")]
+    #[test]
+    fn test_poll_diff_counts() {
+        let mut entries = synthetic_entries(1000);
+        let mut cursor = crate::klogctl::PollCursor::default();
+        cursor.advance(&entries[..500]);
+
+        let start = Counts::now();
+        entries = cursor.newer_entries(entries);
+        assert_eq!(entries.len(), 500);
+        // diffing is linear: each entry of the poll is looked at exactly once
+        assert_eq!(start.elapsed().poll_entries_scanned, 1000);
+    }
+}
@@ -0,0 +1,307 @@
+use crate::error::RMesgError;
+/// Degradation reports: what a pipeline can and can't do on this host.
+///
+/// Rather than failing outright when one of the things it was asked for can't work here
+/// (no /dev/kmsg in a container, klogctl restricted by `kernel.dmesg_restrict`, pstore
+/// not mounted, a backend not compiled in), a pipeline can probe everything it wants
+/// up front, carry on with what's left, and surface the report to its operator.
+///
+use crate::wsl;
+use crate::Backend;
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use strum_macros::Display as EnumDisplay;
+
+/// Something a pipeline may need from the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, EnumDisplay)]
+pub enum Capability {
+    /// Reading (and following) /dev/kmsg
+    DevKMsg,
+    /// Reading the buffer through the klogctl system call
+    KLogCtl,
+    /// Clearing the buffer (through klogctl, which needs CAP_SYSLOG)
+    Clear,
+    /// Reading logs pstore saved from previous boots
+    PStore,
+    /// Reading kernel messages from the systemd journal
+    Journald,
+}
+
+impl Capability {
+    /// The backend providing this capability, if it is one.
+    pub fn backend(self) -> Option<Backend> {
+        match self {
+            #[cfg(feature = "kmsg")]
+            Capability::DevKMsg => Some(Backend::DevKMsg),
+            #[cfg(feature = "klogctl")]
+            Capability::KLogCtl => Some(Backend::KLogCtl),
+            #[cfg(feature = "pstore")]
+            Capability::PStore => Some(Backend::PStore),
+            #[cfg(feature = "journald")]
+            Capability::Journald => Some(Backend::Journald),
+            _ => None,
+        }
+    }
+}
+
+/// A capability that was disabled, and why.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Disabled {
+    pub capability: Capability,
+    pub reason: String,
+}
+
+/// Which requested capabilities are available, and why the others aren't.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DegradationReport {
+    /// Available capabilities, in the order requested
+    pub enabled: Vec<Capability>,
+    pub disabled: Vec<Disabled>,
+}
+
+impl DegradationReport {
+    /// Probes each of `requested` on this host.
+    pub fn probe(requested: &[Capability]) -> DegradationReport {
+        let mut report = DegradationReport::default();
+        for capability in requested {
+            report.record(*capability, probe(*capability));
+        }
+        report
+    }
+
+    /// Records the outcome of trying a capability (e.g. one the pipeline only found
+    /// out about by using it).
+    pub fn record(&mut self, capability: Capability, outcome: Result<(), RMesgError>) {
+        self.enabled.retain(|c| *c != capability);
+        self.disabled.retain(|d| d.capability != capability);
+        match outcome {
+            Ok(()) => self.enabled.push(capability),
+            Err(e) => self.disabled.push(Disabled {
+                capability,
+                reason: e.to_string(),
+            }),
+        }
+    }
+
+    pub fn is_enabled(&self, capability: Capability) -> bool {
+        self.enabled.contains(&capability)
+    }
+
+    /// Whether anything requested was disabled.
+    pub fn is_degraded(&self) -> bool {
+        !self.disabled.is_empty()
+    }
+
+    /// The first available backend, in the order requested.
+    pub fn backend(&self) -> Option<Backend> {
+        self.enabled.iter().find_map(|c| c.backend())
+    }
+}
+
+impl Display for DegradationReport {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        if !self.is_degraded() {
+            return write!(f, "Everything requested is available");
+        }
+        write!(f, "Disabled:")?;
+        for disabled in &self.disabled {
+            write!(f, "\n  {}: {}", disabled.capability, disabled.reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks whether `capability` works on this host, without side effects (in
+/// particular, `Clear` checks for the privilege rather than clearing).
+pub fn probe(capability: Capability) -> Result<(), RMesgError> {
+    if let Some(limitation) = wsl::detect().and_then(|wsl| wsl.limitation(capability)) {
+        return Err(RMesgError::BackendUnavailable(limitation.to_owned()));
+    }
+    match capability {
+        Capability::DevKMsg => probe_devkmsg(),
+        Capability::KLogCtl => probe_klogctl(),
+        Capability::Clear => probe_clear(),
+        Capability::PStore => probe_pstore(),
+        Capability::Journald => probe_journald(),
+    }
+}
+
+#[cfg(not(all(
+    feature = "kmsg",
+    feature = "klogctl",
+    feature = "pstore",
+    feature = "journald"
+)))]
+fn not_built(feature: &str) -> RMesgError {
+    RMesgError::BackendUnavailable(format!("rmesg was built without the {} feature", feature))
+}
+
+#[cfg(feature = "kmsg")]
+fn probe_devkmsg() -> Result<(), RMesgError> {
+    match std::fs::File::open(crate::kmsgfile::DEV_KMSG_PATH) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(RMesgError::DevKMsgFileOpenError(format!(
+            "Unable to open {}: {}",
+            crate::kmsgfile::DEV_KMSG_PATH,
+            e
+        ))),
+    }
+}
+
+#[cfg(not(feature = "kmsg"))]
+fn probe_devkmsg() -> Result<(), RMesgError> {
+    Err(not_built("kmsg"))
+}
+
+#[cfg(feature = "klogctl")]
+fn probe_klogctl() -> Result<(), RMesgError> {
+    // reading the size is restricted exactly as reading the buffer is
+    crate::klogctl::kernel_buffer_size()
+        .map(|_| ())
+        .map_err(|e| {
+            RMesgError::BackendUnavailable(format!(
+                "klogctl is restricted (see kernel.dmesg_restrict): {}",
+                e
+            ))
+        })
+}
+
+#[cfg(not(feature = "klogctl"))]
+fn probe_klogctl() -> Result<(), RMesgError> {
+    Err(not_built("klogctl"))
+}
+
+// CAP_SYSLOG, from linux/capability.h
+#[cfg(all(feature = "klogctl", target_os = "linux"))]
+const CAP_SYSLOG: u32 = 34;
+
+#[cfg(all(feature = "klogctl", target_os = "linux"))]
+fn probe_clear() -> Result<(), RMesgError> {
+    let status = std::fs::read_to_string("/proc/self/status")?;
+    let effective = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .ok_or_else(|| {
+            RMesgError::InternalError(
+                "Unable to read effective capabilities from /proc/self/status".to_owned(),
+            )
+        })?;
+    match effective & (1 << CAP_SYSLOG) {
+        0 => Err(RMesgError::BackendUnavailable(
+            "Clearing the buffer needs CAP_SYSLOG".to_owned(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(all(feature = "klogctl", not(target_os = "linux")))]
+fn probe_clear() -> Result<(), RMesgError> {
+    Err(RMesgError::NotImplementedForThisPlatform)
+}
+
+#[cfg(not(feature = "klogctl"))]
+fn probe_clear() -> Result<(), RMesgError> {
+    Err(not_built("klogctl"))
+}
+
+#[cfg(feature = "pstore")]
+fn probe_pstore() -> Result<(), RMesgError> {
+    match std::fs::read_dir(crate::pstore::PSTORE_PATH) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(RMesgError::BackendUnavailable(format!(
+            "Unable to read {} (is pstore mounted?): {}",
+            crate::pstore::PSTORE_PATH,
+            e
+        ))),
+    }
+}
+
+#[cfg(not(feature = "pstore"))]
+fn probe_pstore() -> Result<(), RMesgError> {
+    Err(not_built("pstore"))
+}
+
+#[cfg(feature = "journald")]
+fn probe_journald() -> Result<(), RMesgError> {
+    match std::process::Command::new(crate::journald::JOURNALCTL)
+        .arg("--version")
+        .output()
+    {
+        Ok(output) if output.status.success() => Ok(()),
+        Ok(output) => Err(RMesgError::BackendUnavailable(format!(
+            "{} --version failed: {}",
+            crate::journald::JOURNALCTL,
+            output.status
+        ))),
+        Err(e) => Err(RMesgError::BackendUnavailable(format!(
+            "Unable to run {}: {}",
+            crate::journald::JOURNALCTL,
+            e
+        ))),
+    }
+}
+
+#[cfg(not(feature = "journald"))]
+fn probe_journald() -> Result<(), RMesgError> {
+    Err(not_built("journald"))
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let mut report = DegradationReport::default();
+        report.record(Capability::KLogCtl, Ok(()));
+        report.record(
+            Capability::PStore,
+            Err(RMesgError::BackendUnavailable("not mounted".to_owned())),
+        );
+        assert!(report.is_degraded());
+        assert!(report.is_enabled(Capability::KLogCtl));
+        assert!(!report.is_enabled(Capability::PStore));
+        assert_eq!(
+            report.to_string(),
+            "Disabled:\n  PStore: RMesgError:: BackendUnavailable: not mounted"
+        );
+
+        // a later outcome replaces the earlier one
+        report.record(
+            Capability::KLogCtl,
+            Err(RMesgError::BackendUnavailable("restricted".to_owned())),
+        );
+        assert!(report.enabled.is_empty());
+        assert_eq!(report.disabled.len(), 2);
+        assert!(report.backend().is_none());
+    }
+
+    #[test]
+    fn test_probe() {
+        let report = DegradationReport::probe(&[
+            Capability::DevKMsg,
+            Capability::KLogCtl,
+            Capability::Clear,
+            Capability::PStore,
+            Capability::Journald,
+        ]);
+        // everything requested is accounted for, one way or the other
+        assert_eq!(report.enabled.len() + report.disabled.len(), 5);
+
+        #[cfg(not(feature = "pstore"))]
+        assert!(report
+            .disabled
+            .iter()
+            .any(|d| d.capability == Capability::PStore && d.reason.contains("pstore feature")));
+
+        // an enabled backend can actually be read
+        #[cfg(target_os = "linux")]
+        if let Some(backend) = report.backend() {
+            assert!(crate::count_entries(backend).is_ok());
+        }
+    }
+}
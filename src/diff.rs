@@ -0,0 +1,117 @@
+use crate::entry::{Entry, LogFacility, LogLevel};
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+// What identifies an entry without a sequence number (e.g. from klogctl)
+type ContentKey<'a> = (
+    Option<LogFacility>,
+    Option<LogLevel>,
+    Option<Duration>,
+    &'a str,
+);
+
+/// Returns the entries in `newer` that aren't in `older`, in the order they appear in `newer`.
+///
+/// Entries with sequence numbers (from /dev/kmsg) are compared by sequence number.
+/// Entries without (from klogctl) are compared by content: facility, level, timestamp
+/// and message. Identical entries are counted, so a message repeated three times in
+/// `newer` but only once in `older` yields two entries.
+///
+/// This answers "what kernel messages appeared between these two snapshots" without
+/// having to run a follow session in the meantime.
+pub fn diff(older: &[Entry], newer: &[Entry]) -> Vec<Entry> {
+    let older_seqs: HashSet<usize> = older.iter().filter_map(|e| e.sequence_num).collect();
+
+    let mut older_contents: HashMap<ContentKey<'_>, usize> = HashMap::new();
+    for entry in older.iter().filter(|e| e.sequence_num.is_none()) {
+        *older_contents.entry(content_key(entry)).or_insert(0) += 1;
+    }
+
+    newer
+        .iter()
+        .filter(|entry| match entry.sequence_num {
+            Some(seq) => !older_seqs.contains(&seq),
+            None => match older_contents.get_mut(&content_key(entry)) {
+                Some(count) if *count > 0 => {
+                    *count -= 1;
+                    false
+                }
+                _ => true,
+            },
+        })
+        .cloned()
+        .collect()
+}
+
+fn content_key(entry: &Entry) -> ContentKey<'_> {
+    (
+        entry.facility,
+        entry.level,
+        entry.timestamp_from_system_start,
+        &entry.message,
+    )
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(sequence_num: Option<usize>, ts_secs: u64, message: &str) -> Entry {
+        Entry {
+            sequence_num,
+            timestamp_from_system_start: Some(Duration::from_secs(ts_secs)),
+            message: message.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_diff_by_sequence() {
+        let older = vec![entry(Some(1), 1, "a"), entry(Some(2), 2, "b")];
+        // a repeat of an identical message is still a new record
+        let newer = vec![
+            entry(Some(2), 2, "b"),
+            entry(Some(3), 2, "b"),
+            entry(Some(4), 3, "c"),
+        ];
+        assert_eq!(
+            diff(&older, &newer),
+            vec![entry(Some(3), 2, "b"), entry(Some(4), 3, "c")]
+        );
+    }
+
+    #[test]
+    fn test_diff_by_content() {
+        let older = vec![entry(None, 1, "a"), entry(None, 2, "b")];
+        let newer = vec![
+            entry(None, 1, "a"),
+            entry(None, 2, "b"),
+            entry(None, 2, "b"),
+            entry(None, 3, "c"),
+        ];
+        assert_eq!(
+            diff(&older, &newer),
+            vec![entry(None, 2, "b"), entry(None, 3, "c")]
+        );
+    }
+
+    #[test]
+    fn test_diff_identical() {
+        let snapshot = vec![entry(Some(1), 1, "a"), entry(None, 2, "b")];
+        assert!(diff(&snapshot, &snapshot).is_empty());
+        assert_eq!(diff(&[], &snapshot), snapshot);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_diff_live() {
+        let older = crate::log_entries(crate::Backend::Default, false).unwrap();
+        let newer = crate::log_entries(crate::Backend::Default, false).unwrap();
+        // everything in the older snapshot is still in the newer one
+        assert!(diff(&newer, &older).is_empty());
+    }
+}
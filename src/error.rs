@@ -4,6 +4,9 @@ use std::error::Error;
 use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::time::SystemTimeError;
 
+#[cfg(feature = "extra-traits")]
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
 #[derive(Debug)]
 pub enum RMesgError {
     NotImplementedForThisPlatform,
@@ -17,8 +20,56 @@ pub enum RMesgError {
     EntryParsingError(String),
     UnableToObtainElapsedTime(SystemTimeError),
     DevKMsgFileOpenError(String),
+    SinkError(String),
+    BufferFull(usize),
+    FilterError(String),
+    Timeout(String),
+    ConfigError(String),
+    BookmarkError(String),
+    LockContention(String),
+    BackendUnavailable(String),
 }
 impl Error for RMesgError {}
+
+impl RMesgError {
+    /// The name of the variant, e.g. for matching on errors that went through JSON.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::NotImplementedForThisPlatform => "NotImplementedForThisPlatform",
+            Self::UnableToObtainSystemTime => "UnableToObtainSystemTime",
+            Self::UnableToAddDurationToSystemTime => "UnableToAddDurationToSystemTime",
+            Self::KLogTimestampsDisabled => "KLogTimestampsDisabled",
+            Self::IntegerOutOfBound(_) => "IntegerOutOfBound",
+            Self::Utf8StringConversionError(_) => "Utf8StringConversionError",
+            Self::IOError(_) => "IOError",
+            Self::InternalError(_) => "InternalError",
+            Self::EntryParsingError(_) => "EntryParsingError",
+            Self::UnableToObtainElapsedTime(_) => "UnableToObtainElapsedTime",
+            Self::DevKMsgFileOpenError(_) => "DevKMsgFileOpenError",
+            Self::SinkError(_) => "SinkError",
+            Self::BufferFull(_) => "BufferFull",
+            Self::FilterError(_) => "FilterError",
+            Self::Timeout(_) => "Timeout",
+            Self::ConfigError(_) => "ConfigError",
+            Self::BookmarkError(_) => "BookmarkError",
+            Self::LockContention(_) => "LockContention",
+            Self::BackendUnavailable(_) => "BackendUnavailable",
+        }
+    }
+}
+
+/// Serialized as `{"kind": "Timeout", "message": "RMesgError:: Timeout: ..."}`, with the
+/// message as displayed. (Some variants carry errors that can't be rebuilt, so there's
+/// no Deserialize.)
+#[cfg(feature = "extra-traits")]
+impl Serialize for RMesgError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("RMesgError", 2)?;
+        state.serialize_field("kind", self.kind())?;
+        state.serialize_field("message", &self.to_string())?;
+        state.end()
+    }
+}
 impl Display for RMesgError {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
         write!(
@@ -38,6 +89,14 @@ impl Display for RMesgError {
                     "Failed to add a Duration to SystemTime".to_owned(),
                 Self::KLogTimestampsDisabled => "Kernel Log timestamps are disabled".to_owned(),
                 Self::DevKMsgFileOpenError(s) => s.to_owned(),
+                Self::SinkError(s) => format!("SinkError: {}", s),
+                Self::Timeout(s) => format!("Timeout: {}", s),
+                Self::FilterError(s) => format!("FilterError: {}", s),
+                Self::BookmarkError(s) => format!("BookmarkError: {}", s),
+                Self::LockContention(s) => format!("LockContention: {}", s),
+                Self::BackendUnavailable(s) => format!("BackendUnavailable: {}", s),
+                Self::ConfigError(s) => format!("ConfigError: {}", s),
+                Self::BufferFull(c) => format!("BufferFull: record does not fit in {} bytes", c),
             }
         )
     }
@@ -59,3 +118,32 @@ impl From<entry::EntryParsingError> for RMesgError {
         RMesgError::EntryParsingError(format!("{:?}", err))
     }
 }
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_kind() {
+        assert_eq!(RMesgError::BufferFull(4).kind(), "BufferFull");
+        assert_eq!(
+            RMesgError::KLogTimestampsDisabled.kind(),
+            "KLogTimestampsDisabled"
+        );
+    }
+
+    #[cfg(feature = "extra-traits")]
+    #[test]
+    fn test_serialize() {
+        assert_eq!(
+            serde_json::to_value(RMesgError::Timeout("no marker".to_owned())).unwrap(),
+            serde_json::json!({
+                "kind": "Timeout",
+                "message": "RMesgError:: Timeout: no marker",
+            })
+        );
+    }
+}
@@ -0,0 +1,192 @@
+use crate::entry::Entry;
+use crate::events::key_values;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::BTreeMap;
+
+lazy_static! {
+    // audit: type=1400 audit(1609459200.123:456): <body>
+    static ref RE_AUDIT: Regex = Regex::new(
+        r"(?x)^
+        audit:[[:space:]]+type=(?P<type>[[:digit:]]+)[[:space:]]+
+        audit\((?P<timestamp>[[:digit:]]+\.[[:digit:]]+):(?P<serial>[[:digit:]]+)\):[[:space:]]*
+        (?P<body>.*)
+        $"
+    )
+    .unwrap();
+
+    // avc:  denied  { read write } for ...
+    static ref RE_AVC: Regex = Regex::new(
+        r"^avc:[[:space:]]+(?P<result>denied|granted)[[:space:]]+\{(?P<permissions>[^}]*)\}"
+    )
+    .unwrap();
+}
+
+/// Which security module produced the audit record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SecurityModule {
+    SELinux,
+    AppArmor,
+    /// Any other audit record (syscall, netfilter, integrity, ...)
+    Other,
+}
+
+/// An audit record logged to the kernel log (as happens on hosts not running auditd).
+#[derive(Clone, Debug, PartialEq)]
+pub struct SecurityEvent {
+    pub module: SecurityModule,
+
+    /// The audit record type (1400 is AVC, 1300 SYSCALL, etc.)
+    pub audit_type: u32,
+    /// Wall-clock time the record was generated, as the kernel printed it (seconds.millis)
+    pub audit_timestamp: String,
+    /// Serial number of the audit event
+    pub audit_serial: u64,
+
+    /// Whether this record reports an access denial
+    pub denied: bool,
+    /// The operation attempted: the SELinux permissions (e.g. "read write") or
+    /// the AppArmor operation (e.g. "open")
+    pub operation: Option<String>,
+    /// The AppArmor profile, or the SELinux source context
+    pub profile: Option<String>,
+    /// The SELinux target context (not set for AppArmor)
+    pub target: Option<String>,
+    pub pid: Option<u32>,
+    pub comm: Option<String>,
+    /// The object operated on (file name, etc.)
+    pub name: Option<String>,
+
+    /// Every key/value pair in the record, for fields not structured above
+    pub fields: BTreeMap<String, String>,
+}
+
+impl SecurityEvent {
+    pub fn from_entry(entry: &Entry) -> Option<SecurityEvent> {
+        let message = entry.message.trim_start();
+        let parts = RE_AUDIT.captures(message)?;
+
+        let body = &parts["body"];
+        let fields = key_values(body);
+        let avc = RE_AVC.captures(body);
+
+        let (module, denied, operation, profile, target) = if let Some(avc) = avc {
+            (
+                SecurityModule::SELinux,
+                &avc["result"] == "denied",
+                Some(avc["permissions"].trim().to_owned()),
+                fields.get("scontext").cloned(),
+                fields.get("tcontext").cloned(),
+            )
+        } else if let Some(apparmor) = fields.get("apparmor") {
+            (
+                SecurityModule::AppArmor,
+                apparmor == "DENIED",
+                fields.get("operation").cloned(),
+                fields.get("profile").cloned(),
+                None,
+            )
+        } else {
+            (
+                SecurityModule::Other,
+                false,
+                fields.get("op").cloned(),
+                None,
+                None,
+            )
+        };
+
+        Some(SecurityEvent {
+            module,
+            audit_type: parts["type"].parse().ok()?,
+            audit_timestamp: parts["timestamp"].to_owned(),
+            audit_serial: parts["serial"].parse().ok()?,
+            denied,
+            operation,
+            profile,
+            target,
+            pid: fields.get("pid").and_then(|p| p.parse().ok()),
+            comm: fields.get("comm").cloned(),
+            name: fields.get("name").cloned(),
+            fields,
+        })
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entry::entry;
+
+    #[test]
+    fn test_selinux_avc() {
+        let e = entry(
+            r#" audit: type=1400 audit(1609459200.123:456): avc:  denied  { read write } for  pid=1234 comm="cat" name="shadow" dev="sda1" ino=12345 scontext=system_u:system_r:httpd_t:s0 tcontext=system_u:object_r:shadow_t:s0 tclass=file permissive=0"#,
+        );
+        let event = SecurityEvent::from_entry(&e).unwrap();
+        assert_eq!(event.module, SecurityModule::SELinux);
+        assert_eq!(event.audit_type, 1400);
+        assert_eq!(event.audit_timestamp, "1609459200.123");
+        assert_eq!(event.audit_serial, 456);
+        assert!(event.denied);
+        assert_eq!(event.operation.as_deref(), Some("read write"));
+        assert_eq!(
+            event.profile.as_deref(),
+            Some("system_u:system_r:httpd_t:s0")
+        );
+        assert_eq!(
+            event.target.as_deref(),
+            Some("system_u:object_r:shadow_t:s0")
+        );
+        assert_eq!(event.pid, Some(1234));
+        assert_eq!(event.comm.as_deref(), Some("cat"));
+        assert_eq!(event.name.as_deref(), Some("shadow"));
+        assert_eq!(event.fields["tclass"], "file");
+    }
+
+    #[test]
+    fn test_apparmor_denied() {
+        let e = entry(
+            r#"audit: type=1400 audit(1609459200.123:457): apparmor="DENIED" operation="open" profile="/usr/sbin/cupsd" name="/etc/shadow" pid=981 comm="cupsd" requested_mask="r" denied_mask="r" fsuid=0 ouid=0"#,
+        );
+        let event = SecurityEvent::from_entry(&e).unwrap();
+        assert_eq!(event.module, SecurityModule::AppArmor);
+        assert!(event.denied);
+        assert_eq!(event.operation.as_deref(), Some("open"));
+        assert_eq!(event.profile.as_deref(), Some("/usr/sbin/cupsd"));
+        assert_eq!(event.target, None);
+        assert_eq!(event.pid, Some(981));
+        assert_eq!(event.comm.as_deref(), Some("cupsd"));
+        assert_eq!(event.name.as_deref(), Some("/etc/shadow"));
+        assert_eq!(event.fields["denied_mask"], "r");
+    }
+
+    #[test]
+    fn test_apparmor_status() {
+        let e = entry(
+            r#"audit: type=1400 audit(1609459200.001:2): apparmor="STATUS" operation="profile_load" profile="unconfined" name="/usr/bin/man" pid=600 comm="apparmor_parser""#,
+        );
+        let event = SecurityEvent::from_entry(&e).unwrap();
+        assert_eq!(event.module, SecurityModule::AppArmor);
+        assert!(!event.denied);
+        assert_eq!(event.operation.as_deref(), Some("profile_load"));
+    }
+
+    #[test]
+    fn test_other_audit_and_non_audit() {
+        let e = entry(
+            "audit: type=2000 audit(1609459200.001:1): state=initialized audit_enabled=0 res=1",
+        );
+        let event = SecurityEvent::from_entry(&e).unwrap();
+        assert_eq!(event.module, SecurityModule::Other);
+        assert_eq!(event.audit_type, 2000);
+        assert_eq!(event.fields["state"], "initialized");
+
+        assert!(SecurityEvent::from_entry(&entry("audit: initializing netlink subsys")).is_none());
+        assert!(SecurityEvent::from_entry(&entry("usb 1-1: new device")).is_none());
+    }
+}
@@ -0,0 +1,201 @@
+use crate::entry::Entry;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::time::Duration;
+
+lazy_static! {
+    static ref RE_CLOCK_SWITCHED: Regex =
+        Regex::new(r"^clocksource: Switched to clocksource (?P<clocksource>[^[:space:]]+)").unwrap();
+
+    // clocksource: timekeeping watchdog on CPU1: Marking clocksource 'tsc' as unstable because the skew is too large:
+    static ref RE_CLOCK_UNSTABLE: Regex =
+        Regex::new(r"Marking clocksource '(?P<clocksource>[^']+)' as unstable").unwrap();
+
+    // rtc_cmos 00:00: setting system clock to 2021-01-01T00:00:00 UTC (1609459200)
+    static ref RE_CLOCK_RTC_SET: Regex =
+        Regex::new(r"setting system clock to [^[:space:]]+ UTC \((?P<unix_time>[[:digit:]]+)\)").unwrap();
+
+    static ref RE_CLOCK_LEAP_SECOND: Regex =
+        Regex::new(r"^Clock: (?P<operation>inserting|deleting) leap second").unwrap();
+
+    // logged to /dev/kmsg by systemd when the system clock is set
+    static ref RE_CLOCK_STEPPED: Regex =
+        Regex::new(r"^systemd\[1\]: (?:Time has been changed|System clock time unset or jumped backwards)").unwrap();
+
+    // PM: suspend entry (deep)    PM: hibernation: hibernation exit
+    static ref RE_CLOCK_SLEEP: Regex =
+        Regex::new(r"^PM: (?:hibernation: )?(?:suspend|hibernation) (?P<direction>entry|exit)").unwrap();
+}
+
+/// Something that affects how kernel timestamps relate to the wall clock.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClockEvent {
+    /// The kernel switched clocksource
+    SourceSwitched {
+        clocksource: String,
+    },
+    /// The clocksource watchdog found a clocksource unreliable
+    SourceUnstable {
+        clocksource: String,
+    },
+    /// The system clock was set from the RTC, to `unix_time` (seconds since the epoch)
+    RtcSet {
+        unix_time: u64,
+    },
+    /// A leap second was inserted (or deleted)
+    LeapSecond {
+        inserted: bool,
+    },
+    /// The system clock was stepped (e.g. by NTP)
+    Stepped,
+    /// The system is suspending or hibernating; kernel timestamps stop advancing
+    /// until the matching `Resume`
+    Suspend,
+    Resume,
+    /// Consecutive entries whose timestamps are further apart than expected
+    TimestampJump {
+        gap: Duration,
+    },
+}
+
+impl ClockEvent {
+    /// Recognizes a single entry. Timestamp jumps can't be detected from one entry;
+    /// use `detect` for those.
+    pub fn from_entry(entry: &Entry) -> Option<ClockEvent> {
+        let message = entry.message.trim_start();
+
+        if let Some(parts) = RE_CLOCK_SWITCHED.captures(message) {
+            Some(ClockEvent::SourceSwitched {
+                clocksource: parts["clocksource"].to_owned(),
+            })
+        } else if let Some(parts) = RE_CLOCK_UNSTABLE.captures(message) {
+            Some(ClockEvent::SourceUnstable {
+                clocksource: parts["clocksource"].to_owned(),
+            })
+        } else if let Some(parts) = RE_CLOCK_RTC_SET.captures(message) {
+            Some(ClockEvent::RtcSet {
+                unix_time: parts["unix_time"].parse().ok()?,
+            })
+        } else if let Some(parts) = RE_CLOCK_LEAP_SECOND.captures(message) {
+            Some(ClockEvent::LeapSecond {
+                inserted: &parts["operation"] == "inserting",
+            })
+        } else if RE_CLOCK_STEPPED.is_match(message) {
+            Some(ClockEvent::Stepped)
+        } else if let Some(parts) = RE_CLOCK_SLEEP.captures(message) {
+            match &parts["direction"] {
+                "entry" => Some(ClockEvent::Suspend),
+                _ => Some(ClockEvent::Resume),
+            }
+        } else {
+            None
+        }
+    }
+
+    /// All clock events in `entries`, with the index of the entry each was found at.
+    /// A `TimestampJump` is reported (at the later entry) whenever consecutive
+    /// timestamped entries are more than `jump_threshold` apart.
+    pub fn detect(entries: &[Entry], jump_threshold: Duration) -> Vec<(usize, ClockEvent)> {
+        let mut events = Vec::new();
+        let mut previous: Option<Duration> = None;
+
+        for (i, entry) in entries.iter().enumerate() {
+            if let Some(timestamp) = entry.timestamp_from_system_start {
+                if let Some(gap) = previous.and_then(|p| timestamp.checked_sub(p)) {
+                    if gap > jump_threshold {
+                        events.push((i, ClockEvent::TimestampJump { gap }));
+                    }
+                }
+                previous = Some(timestamp);
+            }
+
+            if let Some(event) = ClockEvent::from_entry(entry) {
+                events.push((i, event));
+            }
+        }
+
+        events
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(secs: u64, message: &str) -> Entry {
+        Entry {
+            timestamp_from_system_start: Some(Duration::from_secs(secs)),
+            message: message.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn parse(message: &str) -> Option<ClockEvent> {
+        ClockEvent::from_entry(&entry(0, message))
+    }
+
+    #[test]
+    fn test_from_entry() {
+        assert_eq!(
+            parse("clocksource: Switched to clocksource tsc"),
+            Some(ClockEvent::SourceSwitched {
+                clocksource: "tsc".to_owned()
+            })
+        );
+        assert_eq!(
+            parse("clocksource: timekeeping watchdog on CPU1: Marking clocksource 'tsc' as unstable because the skew is too large:"),
+            Some(ClockEvent::SourceUnstable {
+                clocksource: "tsc".to_owned()
+            })
+        );
+        assert_eq!(
+            parse("rtc_cmos 00:00: setting system clock to 2021-01-01T00:00:00 UTC (1609459200)"),
+            Some(ClockEvent::RtcSet {
+                unix_time: 1609459200
+            })
+        );
+        assert_eq!(
+            parse("Clock: inserting leap second 23:59:60 UTC"),
+            Some(ClockEvent::LeapSecond { inserted: true })
+        );
+        assert_eq!(
+            parse("systemd[1]: Time has been changed"),
+            Some(ClockEvent::Stepped)
+        );
+        assert_eq!(parse("PM: suspend entry (deep)"), Some(ClockEvent::Suspend));
+        assert_eq!(parse("PM: suspend exit"), Some(ClockEvent::Resume));
+        assert_eq!(parse("usb 1-1: new high-speed USB device"), None);
+    }
+
+    #[test]
+    fn test_detect() {
+        let entries = vec![
+            entry(1, "clocksource: Switched to clocksource tsc"),
+            entry(2, "usb 1-1: new high-speed USB device"),
+            entry(100, "PM: suspend exit"),
+            entry(101, "usb 1-1: reset high-speed USB device"),
+        ];
+        assert_eq!(
+            ClockEvent::detect(&entries, Duration::from_secs(10)),
+            vec![
+                (
+                    0,
+                    ClockEvent::SourceSwitched {
+                        clocksource: "tsc".to_owned()
+                    }
+                ),
+                (
+                    2,
+                    ClockEvent::TimestampJump {
+                        gap: Duration::from_secs(98)
+                    }
+                ),
+                (2, ClockEvent::Resume),
+            ]
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use crate::entry::Entry;
+use crate::error::RMesgError;
+
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+/// An event recognized by a parser from outside rmesg.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CustomEvent {
+    /// The name of the parser that recognized it
+    pub parser: String,
+    /// What happened, in the parser's own terms (e.g. "link-flap")
+    pub kind: String,
+    /// Whatever else the parser extracted
+    pub fields: BTreeMap<String, String>,
+}
+
+/// A structured parser for messages rmesg doesn't know about (vendor drivers, custom
+/// patches, ...). Implement it in your own crate and `register` it (or add it to a
+/// `ParserRegistry` of your own).
+pub trait KernelEventParser: Send + Sync {
+    /// Identifies the parser in `CustomEvent::parser`, and must be unique among the
+    /// parsers in a registry.
+    fn name(&self) -> &str;
+
+    /// The event `entry` describes, or `None` if the parser doesn't recognize it.
+    fn matches(&self, entry: &Entry) -> Option<CustomEvent>;
+}
+
+/// A set of parsers to try on each entry, in the order they were registered.
+#[derive(Default)]
+pub struct ParserRegistry {
+    parsers: Vec<Box<dyn KernelEventParser>>,
+}
+
+impl ParserRegistry {
+    /// Adds `parser`, unless one of the same name is already registered.
+    pub fn register<P: KernelEventParser + 'static>(
+        &mut self,
+        parser: P,
+    ) -> Result<(), RMesgError> {
+        if self.parsers.iter().any(|p| p.name() == parser.name()) {
+            return Err(RMesgError::ConfigError(format!(
+                "A parser named {} is already registered",
+                parser.name()
+            )));
+        }
+        self.parsers.push(Box::new(parser));
+        Ok(())
+    }
+
+    /// Removes the parser named `name`, returning whether there was one.
+    pub fn unregister(&mut self, name: &str) -> bool {
+        let before = self.parsers.len();
+        self.parsers.retain(|p| p.name() != name);
+        self.parsers.len() != before
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.parsers.iter().map(|p| p.name()).collect()
+    }
+
+    /// The events the registered parsers recognize in `entry`.
+    pub fn parse(&self, entry: &Entry) -> Vec<CustomEvent> {
+        self.parsers
+            .iter()
+            .filter_map(|p| p.matches(entry))
+            .collect()
+    }
+}
+
+lazy_static! {
+    static ref REGISTRY: RwLock<ParserRegistry> = RwLock::new(ParserRegistry::default());
+}
+
+/// Adds `parser` to the process-wide registry that rmesg's event APIs consult, unless
+/// one of the same name is already registered.
+pub fn register<P: KernelEventParser + 'static>(parser: P) -> Result<(), RMesgError> {
+    REGISTRY
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .register(parser)
+}
+
+/// Removes the parser named `name` from the process-wide registry.
+pub fn unregister(name: &str) -> bool {
+    REGISTRY
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .unregister(name)
+}
+
+/// The events the parsers in the process-wide registry recognize in `entry`.
+pub fn parse(entry: &Entry) -> Vec<CustomEvent> {
+    REGISTRY
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .parse(entry)
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entry::entry;
+
+    struct LinkFlap;
+
+    impl KernelEventParser for LinkFlap {
+        fn name(&self) -> &str {
+            "acme-nic"
+        }
+
+        fn matches(&self, entry: &Entry) -> Option<CustomEvent> {
+            let port = entry.message.strip_prefix("acme_nic: link flap on port ")?;
+            Some(CustomEvent {
+                parser: self.name().to_owned(),
+                kind: "link-flap".to_owned(),
+                fields: vec![("port".to_owned(), port.to_owned())]
+                    .into_iter()
+                    .collect(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut registry = ParserRegistry::default();
+        registry.register(LinkFlap).unwrap();
+        assert!(registry.register(LinkFlap).is_err());
+        assert_eq!(registry.names(), vec!["acme-nic"]);
+
+        let events = registry.parse(&entry("acme_nic: link flap on port 3"));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, "link-flap");
+        assert_eq!(events[0].fields["port"], "3");
+        assert!(registry.parse(&entry("usb 1-1: new device")).is_empty());
+
+        assert!(registry.unregister("acme-nic"));
+        assert!(!registry.unregister("acme-nic"));
+    }
+
+    #[test]
+    fn test_global_registry() {
+        register(LinkFlap).unwrap();
+        assert_eq!(parse(&entry("acme_nic: link flap on port 1")).len(), 1);
+        assert!(unregister("acme-nic"));
+        assert!(parse(&entry("acme_nic: link flap on port 1")).is_empty());
+    }
+}
@@ -0,0 +1,151 @@
+use crate::entry::Entry;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+// Linux's, whatever the host we're parsing the log on
+const ENOENT: i32 = 2;
+
+lazy_static! {
+    // iwlwifi 0000:02:00.0: firmware: failed to load iwlwifi-8265-36.ucode (-2)
+    // iwlwifi 0000:02:00.0: Direct firmware load for iwlwifi-8265-36.ucode failed with error -2
+    // i915 0000:00:02.0: firmware: direct-loading firmware i915/kbl_dmc_ver1_04.bin
+    // i915 0000:00:02.0: [drm] Finished loading DMC firmware i915/kbl_dmc_ver1_04.bin (v1.4)
+    static ref RE_FIRMWARE: Regex = Regex::new(
+        r"(?x)^
+        (?:(?P<device>[^:]+(?::[[:xdigit:]]+)*(?:\.[[:xdigit:]]+)?):[[:space:]]+)?
+        (?:\[[^]]+\][[:space:]]+)?
+        (?:
+            firmware:[[:space:]]+failed[[:space:]]to[[:space:]]load[[:space:]]+(?P<failed>[^[:space:]]+)
+                (?:[[:space:]]+\((?P<failederr>-?[[:digit:]]+)\))?
+            |Direct[[:space:]]firmware[[:space:]]load[[:space:]]for[[:space:]]+(?P<direct>[^[:space:]]+)
+                [[:space:]]+failed[[:space:]]with[[:space:]]error[[:space:]]+(?P<directerr>-?[[:digit:]]+)
+            |firmware:[[:space:]]+direct-loading[[:space:]]firmware[[:space:]]+(?P<loaded>[^[:space:]]+)
+            |Finished[[:space:]]loading[[:space:]](?:[^[:space:]]+[[:space:]])?firmware[[:space:]]+(?P<finished>[^[:space:]]+)
+        )"
+    )
+    .unwrap();
+}
+
+/// A firmware blob being requested by a driver, and whether loading it worked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FirmwareEvent {
+    /// The requesting device as the driver named it (e.g. "iwlwifi 0000:02:00.0"),
+    /// when the message carried one
+    pub device: Option<String>,
+    /// The firmware path, relative to the firmware search path
+    pub firmware: String,
+    pub loaded: bool,
+    /// The errno the load failed with, when reported (usually -2, i.e. the file is missing)
+    pub error: Option<i32>,
+}
+
+impl FirmwareEvent {
+    pub fn from_entry(entry: &Entry) -> Option<FirmwareEvent> {
+        let parts = RE_FIRMWARE.captures(entry.message.trim_start())?;
+
+        let (firmware, loaded, error) = if let Some(f) = parts.name("failed") {
+            (f, false, parts.name("failederr"))
+        } else if let Some(f) = parts.name("direct") {
+            (f, false, parts.name("directerr"))
+        } else if let Some(f) = parts.name("loaded").or_else(|| parts.name("finished")) {
+            (f, true, None)
+        } else {
+            return None;
+        };
+
+        Some(FirmwareEvent {
+            device: parts.name("device").map(|d| d.as_str().to_owned()),
+            firmware: firmware.as_str().to_owned(),
+            loaded,
+            error: error.and_then(|e| e.as_str().parse().ok()),
+        })
+    }
+
+    /// Whether loading failed because the firmware file doesn't exist (ENOENT).
+    pub fn is_missing(&self) -> bool {
+        !self.loaded && self.error == Some(-ENOENT)
+    }
+}
+
+/// The firmware files that failed to load in `entries`, deduplicated, in the order
+/// they were first reported. The kernel usually reports a missing blob more than once.
+pub fn missing_firmware(entries: &[Entry]) -> Vec<String> {
+    let mut missing: Vec<String> = Vec::new();
+    for event in entries.iter().filter_map(FirmwareEvent::from_entry) {
+        if !event.loaded && !missing.contains(&event.firmware) {
+            missing.push(event.firmware);
+        }
+    }
+    missing
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entry::entry;
+
+    fn parse(message: &str) -> Option<FirmwareEvent> {
+        FirmwareEvent::from_entry(&entry(message))
+    }
+
+    #[test]
+    fn test_failed_loads() {
+        let e = parse("iwlwifi 0000:02:00.0: firmware: failed to load iwlwifi-8265-36.ucode (-2)")
+            .unwrap();
+        assert_eq!(e.device.as_deref(), Some("iwlwifi 0000:02:00.0"));
+        assert_eq!(e.firmware, "iwlwifi-8265-36.ucode");
+        assert!(!e.loaded);
+        assert_eq!(e.error, Some(-2));
+        assert!(e.is_missing());
+
+        let e = parse(
+            "bluetooth hci0: Direct firmware load for qca/rampatch_usb_00000302.bin failed with error -2",
+        )
+        .unwrap();
+        assert_eq!(e.device.as_deref(), Some("bluetooth hci0"));
+        assert_eq!(e.firmware, "qca/rampatch_usb_00000302.bin");
+        assert!(e.is_missing());
+
+        let e = parse("firmware: failed to load regulatory.db").unwrap();
+        assert_eq!(e.device, None);
+        assert_eq!(e.error, None);
+        assert!(!e.is_missing());
+    }
+
+    #[test]
+    fn test_successful_loads() {
+        let e =
+            parse("i915 0000:00:02.0: firmware: direct-loading firmware i915/kbl_dmc_ver1_04.bin")
+                .unwrap();
+        assert_eq!(e.device.as_deref(), Some("i915 0000:00:02.0"));
+        assert_eq!(e.firmware, "i915/kbl_dmc_ver1_04.bin");
+        assert!(e.loaded);
+
+        let e = parse(
+            "i915 0000:00:02.0: [drm] Finished loading DMC firmware i915/kbl_dmc_ver1_04.bin (v1.4)",
+        )
+        .unwrap();
+        assert_eq!(e.firmware, "i915/kbl_dmc_ver1_04.bin");
+        assert!(e.loaded);
+
+        assert!(parse("usb 1-1: new high-speed USB device").is_none());
+    }
+
+    #[test]
+    fn test_missing_firmware() {
+        let entries = vec![
+            entry("iwlwifi 0000:02:00.0: firmware: failed to load iwlwifi-8265-36.ucode (-2)"),
+            entry("iwlwifi 0000:02:00.0: Direct firmware load for iwlwifi-8265-36.ucode failed with error -2"),
+            entry("i915 0000:00:02.0: firmware: direct-loading firmware i915/kbl_dmc_ver1_04.bin"),
+            entry("r8169 0000:03:00.0: Direct firmware load for rtl_nic/rtl8168h-2.fw failed with error -2"),
+        ];
+        assert_eq!(
+            missing_firmware(&entries),
+            vec!["iwlwifi-8265-36.ucode", "rtl_nic/rtl8168h-2.fw"]
+        );
+    }
+}
@@ -0,0 +1,330 @@
+use crate::entry::Entry;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    // amdgpu 0000:03:00.0: <rest>
+    static ref RE_GPU_DEVICE: Regex = Regex::new(
+        r"(?x)^
+        (?P<driver>amdgpu|i915|nouveau)[[:space:]]+
+        (?P<bdf>[[:xdigit:]]{4}:[[:xdigit:]]{2}:[[:xdigit:]]{2}\.[[:xdigit:]]):[[:space:]]+
+        (?P<rest>.*)
+        $"
+    )
+    .unwrap();
+
+    // [drm] , [drm:amdgpu_job_timedout [amdgpu]] *ERROR* , amdgpu:
+    static ref RE_GPU_TAGS: Regex = Regex::new(
+        r"(?x)^
+        (?:\[drm(?::(?P<function>[[:word:]]+)(?:[[:space:]]\[[[:word:]]+\])?)?\][[:space:]]*)?
+        (?:\*ERROR\*[[:space:]]*)?
+        (?:amdgpu:[[:space:]]*)?
+        (?P<body>.*)
+        $"
+    )
+    .unwrap();
+
+    // ring gfx timeout, signaled seq=1234, emitted seq=1236
+    static ref RE_GPU_RING_TIMEOUT: Regex = Regex::new(
+        r"^ring (?P<ring>[^[:space:]]+) timeout(?:, signaled seq=(?P<signaled>[[:digit:]]+), emitted seq=(?P<emitted>[[:digit:]]+))?"
+    )
+    .unwrap();
+
+    // GPU HANG: ecode 9:1:85dffffb, in Xorg [1234], reason: hang on rcs0, action: reset
+    static ref RE_GPU_HANG: Regex = Regex::new(
+        r"^GPU HANG: ecode [^,]+(?:, in (?P<process>[^\[]+?) \[(?P<pid>[[:digit:]]+)\])?(?:, [Rr]eason: [Hh]ang on (?P<ring>[^,]+))?"
+    )
+    .unwrap();
+
+    // fifo: SCHED_ERROR 0a [CTXSW_TIMEOUT]
+    static ref RE_GPU_SCHED_ERROR: Regex = Regex::new(
+        r"^fifo: SCHED_ERROR [[:xdigit:]]+ \[(?P<reason>[^\]]+)\]"
+    )
+    .unwrap();
+
+    // fifo: channel 5: killed
+    static ref RE_GPU_CHANNEL_KILLED: Regex = Regex::new(
+        r"^fifo: (?:channel|chid) (?P<channel>[[:digit:]]+):? killed"
+    )
+    .unwrap();
+
+    // Resetting rcs0 for stopped heartbeat on rcs0    (i915)
+    // fifo: engine 0: scheduled for recovery          (nouveau)
+    static ref RE_GPU_RESET_BEGIN: Regex = Regex::new(
+        r"^(?:GPU reset begin|Resetting (?P<target>[^[:space:]]+) for |fifo: (?P<engine>(?:engine|runlist) [[:digit:]]+): scheduled for recovery)"
+    )
+    .unwrap();
+
+    static ref RE_GPU_RESET_SUCCEEDED: Regex = Regex::new(
+        r"^GPU reset(?:\([[:digit:]]+\))? succeeded"
+    )
+    .unwrap();
+
+    static ref RE_GPU_RESET_FAILED: Regex = Regex::new(
+        r"^(?:GPU reset(?:\([[:digit:]]+\))? failed|GPU Recovery Failed|Failed to reset chip)"
+    )
+    .unwrap();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GpuDriver {
+    Amdgpu,
+    I915,
+    Nouveau,
+}
+
+impl GpuDriver {
+    fn from_name(name: &str) -> Option<GpuDriver> {
+        if name.starts_with("amdgpu") {
+            Some(Self::Amdgpu)
+        } else if name.starts_with("i915") || name.starts_with("intel_") {
+            Some(Self::I915)
+        } else if name.starts_with("nouveau") {
+            Some(Self::Nouveau)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GpuEventKind {
+    /// The GPU stopped making progress
+    Hang {
+        /// The engine/ring that hung (e.g. "rcs0"), when reported
+        ring: Option<String>,
+        /// The process whose work was running, when reported
+        process: Option<String>,
+        pid: Option<u32>,
+        /// The driver's reason code (e.g. "CTXSW_TIMEOUT"), when reported
+        reason: Option<String>,
+    },
+    /// A job on a ring didn't complete in time
+    RingTimeout {
+        ring: String,
+        signaled: Option<u64>,
+        emitted: Option<u64>,
+    },
+    /// A channel (a GPU context) was killed
+    ChannelKilled {
+        channel: u32,
+    },
+    /// A reset of the whole GPU (`engine` None) or a single engine started
+    ResetBegin {
+        engine: Option<String>,
+    },
+    ResetSucceeded,
+    ResetFailed,
+}
+
+/// A GPU hang, timeout or reset reported by the amdgpu, i915 or nouveau drivers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GpuEvent {
+    pub driver: GpuDriver,
+    /// The PCI address of the GPU, when the message carried one
+    pub bdf: Option<String>,
+    pub kind: GpuEventKind,
+}
+
+impl GpuEvent {
+    pub fn from_entry(entry: &Entry) -> Option<GpuEvent> {
+        let message = entry.message.trim_start();
+        let (mut driver, bdf, rest) = match RE_GPU_DEVICE.captures(message) {
+            Some(device) => (
+                GpuDriver::from_name(&device["driver"]),
+                Some(device["bdf"].to_owned()),
+                device.name("rest")?.as_str(),
+            ),
+            None => (None, None, message),
+        };
+
+        let tags = RE_GPU_TAGS.captures(rest)?;
+        if driver.is_none() {
+            driver = tags
+                .name("function")
+                .and_then(|f| GpuDriver::from_name(f.as_str()));
+        }
+        let body = tags.name("body")?.as_str();
+
+        let kind = if let Some(timeout) = RE_GPU_RING_TIMEOUT.captures(body) {
+            driver = driver.or(Some(GpuDriver::Amdgpu));
+            GpuEventKind::RingTimeout {
+                ring: timeout["ring"].to_owned(),
+                signaled: timeout
+                    .name("signaled")
+                    .and_then(|s| s.as_str().parse().ok()),
+                emitted: timeout
+                    .name("emitted")
+                    .and_then(|e| e.as_str().parse().ok()),
+            }
+        } else if let Some(hang) = RE_GPU_HANG.captures(body) {
+            driver = driver.or(Some(GpuDriver::I915));
+            GpuEventKind::Hang {
+                ring: hang.name("ring").map(|r| r.as_str().to_owned()),
+                process: hang.name("process").map(|p| p.as_str().to_owned()),
+                pid: hang.name("pid").and_then(|p| p.as_str().parse().ok()),
+                reason: None,
+            }
+        } else if let Some(sched) = RE_GPU_SCHED_ERROR.captures(body) {
+            GpuEventKind::Hang {
+                ring: None,
+                process: None,
+                pid: None,
+                reason: Some(sched["reason"].to_owned()),
+            }
+        } else if let Some(killed) = RE_GPU_CHANNEL_KILLED.captures(body) {
+            GpuEventKind::ChannelKilled {
+                channel: killed["channel"].parse().ok()?,
+            }
+        } else if let Some(begin) = RE_GPU_RESET_BEGIN.captures(body) {
+            let engine = begin
+                .name("target")
+                .or_else(|| begin.name("engine"))
+                .map(|e| e.as_str())
+                .filter(|e| *e != "chip");
+            GpuEventKind::ResetBegin {
+                engine: engine.map(|e| e.to_owned()),
+            }
+        } else if RE_GPU_RESET_SUCCEEDED.is_match(body) {
+            GpuEventKind::ResetSucceeded
+        } else if RE_GPU_RESET_FAILED.is_match(body) {
+            GpuEventKind::ResetFailed
+        } else {
+            return None;
+        };
+
+        Some(GpuEvent {
+            driver: driver?,
+            bdf,
+            kind,
+        })
+    }
+
+    /// Whether this reports the GPU (or some of its work) getting stuck.
+    pub fn is_hang(&self) -> bool {
+        matches!(
+            self.kind,
+            GpuEventKind::Hang { .. }
+                | GpuEventKind::RingTimeout { .. }
+                | GpuEventKind::ChannelKilled { .. }
+        )
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(message: &str) -> Option<GpuEvent> {
+        GpuEvent::from_entry(&Entry {
+            message: message.to_owned(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_amdgpu() {
+        let e = parse("amdgpu 0000:03:00.0: [drm:amdgpu_job_timedout [amdgpu]] *ERROR* ring gfx timeout, signaled seq=1234, emitted seq=1236").unwrap();
+        assert_eq!(e.driver, GpuDriver::Amdgpu);
+        assert_eq!(e.bdf.as_deref(), Some("0000:03:00.0"));
+        assert_eq!(
+            e.kind,
+            GpuEventKind::RingTimeout {
+                ring: "gfx".to_owned(),
+                signaled: Some(1234),
+                emitted: Some(1236)
+            }
+        );
+        assert!(e.is_hang());
+
+        let e = parse("[drm:amdgpu_job_timedout [amdgpu]] *ERROR* ring sdma0 timeout").unwrap();
+        assert_eq!(e.driver, GpuDriver::Amdgpu);
+        assert_eq!(e.bdf, None);
+
+        let e = parse("amdgpu 0000:03:00.0: amdgpu: GPU reset begin!").unwrap();
+        assert_eq!(e.kind, GpuEventKind::ResetBegin { engine: None });
+        assert!(!e.is_hang());
+
+        let e = parse("amdgpu 0000:03:00.0: amdgpu: GPU reset(2) succeeded!").unwrap();
+        assert_eq!(e.kind, GpuEventKind::ResetSucceeded);
+
+        let e = parse("amdgpu 0000:03:00.0: amdgpu: GPU Recovery Failed: -110").unwrap();
+        assert_eq!(e.kind, GpuEventKind::ResetFailed);
+    }
+
+    #[test]
+    fn test_i915() {
+        let e =
+            parse("i915 0000:00:02.0: [drm] GPU HANG: ecode 9:1:85dffffb, in Xorg [1234]").unwrap();
+        assert_eq!(e.driver, GpuDriver::I915);
+        assert_eq!(
+            e.kind,
+            GpuEventKind::Hang {
+                ring: None,
+                process: Some("Xorg".to_owned()),
+                pid: Some(1234),
+                reason: None
+            }
+        );
+
+        let e = parse("[drm] GPU HANG: ecode 9:1:0x85dffffb, in chrome [2345], reason: hang on rcs0, action: reset").unwrap();
+        assert_eq!(e.driver, GpuDriver::I915);
+        match e.kind {
+            GpuEventKind::Hang { ring, .. } => assert_eq!(ring.as_deref(), Some("rcs0")),
+            other => panic!("Expected a hang, got {:?}", other),
+        }
+
+        let e =
+            parse("i915 0000:00:02.0: [drm] Resetting rcs0 for stopped heartbeat on rcs0").unwrap();
+        assert_eq!(
+            e.kind,
+            GpuEventKind::ResetBegin {
+                engine: Some("rcs0".to_owned())
+            }
+        );
+
+        let e =
+            parse("i915 0000:00:02.0: [drm] Resetting chip for stopped heartbeat on rcs0").unwrap();
+        assert_eq!(e.kind, GpuEventKind::ResetBegin { engine: None });
+
+        let e = parse("[drm:i915_reset [i915]] *ERROR* Failed to reset chip").unwrap();
+        assert_eq!(e.driver, GpuDriver::I915);
+        assert_eq!(e.kind, GpuEventKind::ResetFailed);
+    }
+
+    #[test]
+    fn test_nouveau() {
+        let e = parse("nouveau 0000:01:00.0: fifo: SCHED_ERROR 0a [CTXSW_TIMEOUT]").unwrap();
+        assert_eq!(e.driver, GpuDriver::Nouveau);
+        match e.kind {
+            GpuEventKind::Hang { ref reason, .. } => {
+                assert_eq!(reason.as_deref(), Some("CTXSW_TIMEOUT"))
+            }
+            ref other => panic!("Expected a hang, got {:?}", other),
+        }
+        assert!(e.is_hang());
+
+        let e = parse("nouveau 0000:01:00.0: fifo: channel 5: killed").unwrap();
+        assert_eq!(e.kind, GpuEventKind::ChannelKilled { channel: 5 });
+
+        let e = parse("nouveau 0000:01:00.0: fifo: engine 0: scheduled for recovery").unwrap();
+        assert_eq!(
+            e.kind,
+            GpuEventKind::ResetBegin {
+                engine: Some("engine 0".to_owned())
+            }
+        );
+    }
+
+    #[test]
+    fn test_unrelated() {
+        assert!(parse("i915 0000:00:02.0: [drm] Initialized i915 1.6.0").is_none());
+        // a reset without any indication of which driver logged it
+        assert!(parse("GPU reset begin!").is_none());
+        assert!(parse("usb 1-1: new high-speed USB device").is_none());
+    }
+}
@@ -0,0 +1,251 @@
+use crate::entry::Entry;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    // Spectre V2 : Mitigation: Enhanced IBRS
+    // Spectre V2 : User space: Mitigation: STIBP via prctl
+    // MDS: Vulnerable: Clear CPU buffers attempted, no microcode
+    static ref RE_MITIGATION: Regex = Regex::new(
+        r"(?x)^
+        (?P<vulnerability>
+            Spectre[[:space:]]V1|Spectre[[:space:]]V2|Spectre[[:space:]]BHI|Speculative[[:space:]]Store[[:space:]]Bypass
+            |L1TF|MDS|TAA|MMIO[[:space:]]Stale[[:space:]]Data|SRBDS|Retbleed|GDS|RFDS
+            |Speculative[[:space:]]Return[[:space:]]Stack[[:space:]]Overflow|ITS|Meltdown
+        )[[:space:]]*:[[:space:]]+
+        (?:(?P<scope>[[:alpha:]][[:alpha:][:space:]]*?):[[:space:]]+)?
+        (?P<state>Mitigation|Vulnerable|Not[[:space:]]affected)
+        (?::[[:space:]]*(?P<detail>.*?))?
+        [[:space:]]*$"
+    )
+    .unwrap();
+
+    // Kernel/User page tables isolation: enabled
+    static ref RE_PTI: Regex = Regex::new(r"^Kernel/User page tables isolation: enabled").unwrap();
+
+    // microcode: microcode updated early to revision 0xf0, date = 2021-11-12
+    // microcode: Updated early from: 0x000000ea
+    // microcode: Current revision: 0x000000f0
+    // microcode: sig=0x906ea, pf=0x2, revision=0xf0
+    // microcode: CPU0: patch_level=0x08701021
+    static ref RE_MICROCODE: Regex = Regex::new(
+        r"(?x)^
+        microcode:[[:space:]]+
+        (?:
+            microcode[[:space:]]updated[[:space:]]early[[:space:]]to[[:space:]]revision[[:space:]]0x(?P<early>[[:xdigit:]]+)
+                (?:,[[:space:]]date[[:space:]]=[[:space:]](?P<date>[[:digit:]-]+))?
+            |Updated[[:space:]]early[[:space:]]from:[[:space:]]0x(?P<previous>[[:xdigit:]]+)
+            |Current[[:space:]]revision:[[:space:]]0x(?P<current>[[:xdigit:]]+)
+            |sig=0x[[:xdigit:]]+,[[:space:]]pf=0x[[:xdigit:]]+,[[:space:]]revision=0x(?P<sig>[[:xdigit:]]+)
+            |(?:CPU[[:digit:]]+:[[:space:]])?patch_level=0x(?P<patch>[[:xdigit:]]+)
+        )"
+    )
+    .unwrap();
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MitigationStatus {
+    /// Mitigated, and how (e.g. "Enhanced IBRS")
+    Mitigated(String),
+    /// Vulnerable, with the kernel's explanation (may be empty)
+    Vulnerable(String),
+    NotAffected,
+}
+
+/// The mitigation status the kernel reported for one CPU vulnerability at boot.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mitigation {
+    /// The vulnerability as the kernel names it (e.g. "Spectre V2", "MDS")
+    pub vulnerability: String,
+    /// What the status applies to when the kernel reports several for one vulnerability
+    /// (e.g. "User space")
+    pub scope: Option<String>,
+    pub status: MitigationStatus,
+}
+
+impl Mitigation {
+    pub fn from_entry(entry: &Entry) -> Option<Mitigation> {
+        let message = entry.message.trim_start();
+
+        if RE_PTI.is_match(message) {
+            return Some(Mitigation {
+                vulnerability: "Meltdown".to_owned(),
+                scope: None,
+                status: MitigationStatus::Mitigated("PTI".to_owned()),
+            });
+        }
+
+        let parts = RE_MITIGATION.captures(message)?;
+        let detail = parts
+            .name("detail")
+            .map(|d| d.as_str().to_owned())
+            .unwrap_or_default();
+        let status = match &parts["state"] {
+            "Mitigation" => MitigationStatus::Mitigated(detail),
+            "Vulnerable" => MitigationStatus::Vulnerable(detail),
+            _ => MitigationStatus::NotAffected,
+        };
+
+        Some(Mitigation {
+            vulnerability: parts["vulnerability"].to_owned(),
+            scope: parts.name("scope").map(|s| s.as_str().to_owned()),
+            status,
+        })
+    }
+}
+
+/// The CPU microcode revision and vulnerability mitigations reported during boot.
+///
+/// Build one from a snapshot of the log (e.g. `log_entries`) taken at any time after
+/// boot, provided the early boot messages haven't been overwritten in the buffer.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MitigationReport {
+    /// The microcode revision running, when reported
+    pub microcode_revision: Option<u64>,
+    /// The release date of the microcode, when reported
+    pub microcode_date: Option<String>,
+    /// The revision that was running before an early update, when reported
+    pub previous_microcode_revision: Option<u64>,
+    /// Whether microcode was updated early in boot
+    pub microcode_updated_early: bool,
+
+    /// Mitigation status in the order the kernel reported them
+    pub mitigations: Vec<Mitigation>,
+}
+
+impl MitigationReport {
+    pub fn from_entries(entries: &[Entry]) -> MitigationReport {
+        let mut report = MitigationReport::default();
+
+        for entry in entries {
+            if let Some(mitigation) = Mitigation::from_entry(entry) {
+                report.mitigations.push(mitigation);
+                continue;
+            }
+
+            let parts = match RE_MICROCODE.captures(entry.message.trim_start()) {
+                Some(parts) => parts,
+                None => continue,
+            };
+            let hex = |name| {
+                parts
+                    .name(name)
+                    .and_then(|m| u64::from_str_radix(m.as_str(), 16).ok())
+            };
+
+            if let Some(revision) = hex("early") {
+                report.microcode_revision = Some(revision);
+                report.microcode_updated_early = true;
+                report.microcode_date = parts.name("date").map(|d| d.as_str().to_owned());
+            } else if let Some(previous) = hex("previous") {
+                report.previous_microcode_revision = Some(previous);
+                report.microcode_updated_early = true;
+            } else if let Some(revision) = hex("current")
+                .or_else(|| hex("sig"))
+                .or_else(|| hex("patch"))
+            {
+                report.microcode_revision = Some(revision);
+            }
+        }
+
+        report
+    }
+
+    /// The reported mitigation status of `vulnerability` (e.g. "MDS"), ignoring scoped statuses.
+    pub fn get(&self, vulnerability: &str) -> Option<&MitigationStatus> {
+        self.mitigations
+            .iter()
+            .find(|m| m.vulnerability == vulnerability && m.scope.is_none())
+            .map(|m| &m.status)
+    }
+
+    /// Every vulnerability reported as not mitigated.
+    pub fn vulnerable(&self) -> impl Iterator<Item = &Mitigation> {
+        self.mitigations
+            .iter()
+            .filter(|m| matches!(m.status, MitigationStatus::Vulnerable(_)))
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entries(messages: &[&str]) -> Vec<Entry> {
+        messages
+            .iter()
+            .map(|m| Entry {
+                message: (*m).to_owned(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_report() {
+        let report = MitigationReport::from_entries(&entries(&[
+            "microcode: microcode updated early to revision 0xf0, date = 2021-11-12",
+            "Linux version 5.10.0 (gcc version 10.2.1)",
+            "Spectre V1 : Mitigation: usercopy/swapgs barriers and __user pointer sanitization",
+            "Spectre V2 : Mitigation: Enhanced IBRS",
+            "Spectre V2 : Spectre v2 / SpectreRSB mitigation: Filling RSB on context switch",
+            "Spectre V2 : User space: Mitigation: STIBP via prctl",
+            "MDS: Vulnerable: Clear CPU buffers attempted, no microcode",
+            "TAA: Not affected",
+            "Kernel/User page tables isolation: enabled",
+        ]));
+
+        assert_eq!(report.microcode_revision, Some(0xf0));
+        assert_eq!(report.microcode_date.as_deref(), Some("2021-11-12"));
+        assert!(report.microcode_updated_early);
+        assert_eq!(report.mitigations.len(), 6);
+
+        assert_eq!(
+            report.get("Spectre V2"),
+            Some(&MitigationStatus::Mitigated("Enhanced IBRS".to_owned()))
+        );
+        assert_eq!(report.mitigations[2].scope.as_deref(), Some("User space"));
+        assert_eq!(report.get("TAA"), Some(&MitigationStatus::NotAffected));
+        assert_eq!(
+            report.get("Meltdown"),
+            Some(&MitigationStatus::Mitigated("PTI".to_owned()))
+        );
+        assert_eq!(report.get("L1TF"), None);
+
+        let vulnerable: Vec<&str> = report
+            .vulnerable()
+            .map(|m| m.vulnerability.as_str())
+            .collect();
+        assert_eq!(vulnerable, vec!["MDS"]);
+    }
+
+    #[test]
+    fn test_microcode_formats() {
+        let report = MitigationReport::from_entries(&entries(&[
+            "microcode: Current revision: 0x000000f0",
+            "microcode: Updated early from: 0x000000ea",
+        ]));
+        assert_eq!(report.microcode_revision, Some(0xf0));
+        assert_eq!(report.previous_microcode_revision, Some(0xea));
+        assert!(report.microcode_updated_early);
+
+        let report =
+            MitigationReport::from_entries(&entries(&["microcode: CPU0: patch_level=0x08701021"]));
+        assert_eq!(report.microcode_revision, Some(0x08701021));
+        assert!(!report.microcode_updated_early);
+
+        let report = MitigationReport::from_entries(&entries(&[
+            "microcode: sig=0x906ea, pf=0x2, revision=0xde",
+        ]));
+        assert_eq!(report.microcode_revision, Some(0xde));
+
+        assert_eq!(
+            MitigationReport::from_entries(&entries(&["usb 1-1: Vulnerable: not a CPU"])),
+            MitigationReport::default()
+        );
+    }
+}
@@ -0,0 +1,265 @@
+/// Structured events parsed out of kernel log entries.
+///
+/// Each submodule recognizes one family of kernel messages and turns matching
+/// entries into a typed record through `from_entry`, returning `None` for
+/// entries it doesn't recognize. Parsers for messages rmesg doesn't know about can
+/// be plugged in through `custom`. `KernelEvent` brings them all together, and
+/// `events_iter` (or `events_stream`) turns a follower's entries into typed events with
+/// every parser applied, which `rules` can escalate into alerts.
+use crate::entry::Entry;
+use crate::error::RMesgError;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::{BTreeMap, VecDeque};
+
+#[cfg(feature = "futures")]
+use futures::stream::{self, Stream, StreamExt};
+
+/// SELinux/AppArmor audit messages
+pub mod audit;
+/// Clocksource changes, clock steps and suspend/resume
+pub mod clock;
+/// Parsers from other crates (vendor drivers, custom patches), registered at runtime
+pub mod custom;
+/// Firmware load successes and failures
+pub mod firmware;
+/// GPU hangs, timeouts and resets
+pub mod gpu;
+/// CPU microcode revision and vulnerability mitigation status
+pub mod mitigation;
+/// Kernel module load failures and taint notices
+pub mod module;
+/// NVMe timeouts, controller resets and command errors
+pub mod nvme;
+/// PCIe Advanced Error Reporting events
+pub mod pcie;
+/// Escalation of repeated events into alerts ("5 NVMe errors in a minute")
+pub mod rules;
+/// Sizes and counts embedded in messages ("4096K", "2 MiB", "8 sectors"), for the parsers
+pub mod units;
+
+lazy_static! {
+    // key=value or key="quoted value"
+    static ref RE_KEY_VALUE: Regex =
+        Regex::new(r#"(?P<key>[[:word:]-]+)=(?:"(?P<quoted>[^"]*)"|(?P<value>[^[:space:]]+))"#)
+            .unwrap();
+}
+
+/// Any of the structured events rmesg recognizes.
+#[derive(Clone, Debug, PartialEq)]
+pub enum KernelEvent {
+    Security(audit::SecurityEvent),
+    Clock(clock::ClockEvent),
+    Firmware(firmware::FirmwareEvent),
+    Gpu(gpu::GpuEvent),
+    Mitigation(mitigation::Mitigation),
+    Module(module::ModuleEvent),
+    Nvme(nvme::NvmeEvent),
+    PcieAer(pcie::PcieAerEvent),
+    /// From a parser in the process-wide registry (see `custom::register`)
+    Custom(custom::CustomEvent),
+}
+
+impl KernelEvent {
+    /// Every event recognized in `entry`: by the built-in parsers, then by the registered
+    /// ones.
+    pub fn from_entry(entry: &Entry) -> Vec<KernelEvent> {
+        let mut events: Vec<KernelEvent> = vec![
+            audit::SecurityEvent::from_entry(entry).map(KernelEvent::Security),
+            clock::ClockEvent::from_entry(entry).map(KernelEvent::Clock),
+            firmware::FirmwareEvent::from_entry(entry).map(KernelEvent::Firmware),
+            gpu::GpuEvent::from_entry(entry).map(KernelEvent::Gpu),
+            mitigation::Mitigation::from_entry(entry).map(KernelEvent::Mitigation),
+            module::ModuleEvent::from_entry(entry).map(KernelEvent::Module),
+            nvme::NvmeEvent::from_entry(entry).map(KernelEvent::Nvme),
+            pcie::PcieAerEvent::from_entry(entry).map(KernelEvent::PcieAer),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        events.extend(custom::parse(entry).into_iter().map(KernelEvent::Custom));
+        events
+    }
+
+    /// The type of event this is: "security", "clock", "firmware", "gpu", "mitigation",
+    /// "module", "nvme" or "pcie-aer", or a custom event's own `kind`.
+    pub fn kind(&self) -> &str {
+        match self {
+            KernelEvent::Security(_) => "security",
+            KernelEvent::Clock(_) => "clock",
+            KernelEvent::Firmware(_) => "firmware",
+            KernelEvent::Gpu(_) => "gpu",
+            KernelEvent::Mitigation(_) => "mitigation",
+            KernelEvent::Module(_) => "module",
+            KernelEvent::Nvme(_) => "nvme",
+            KernelEvent::PcieAer(_) => "pcie-aer",
+            KernelEvent::Custom(event) => &event.kind,
+        }
+    }
+}
+
+/// An event, along with the entry it was recognized in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntryEvent {
+    pub event: KernelEvent,
+    pub entry: Entry,
+}
+
+fn entry_events(entry: Entry) -> Vec<EntryEvent> {
+    KernelEvent::from_entry(&entry)
+        .into_iter()
+        .map(|event| EntryEvent {
+            event,
+            entry: entry.clone(),
+        })
+        .collect()
+}
+
+/// The events in a stream of entries (see `events_iter`).
+pub struct EventsIter<I> {
+    entries: I,
+    pending: VecDeque<EntryEvent>,
+}
+
+impl<I: Iterator<Item = Result<Entry, RMesgError>>> Iterator for EventsIter<I> {
+    type Item = Result<EntryEvent, RMesgError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(Ok(event));
+            }
+            match self.entries.next()? {
+                Ok(entry) => self.pending.extend(entry_events(entry)),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// The typed events in `entries` (e.g. `rmesg::logs_iter`'s), skipping entries that
+/// no parser recognizes. Errors are passed through.
+pub fn events_iter<I>(entries: I) -> EventsIter<I::IntoIter>
+where
+    I: IntoIterator<Item = Result<Entry, RMesgError>>,
+{
+    EventsIter {
+        entries: entries.into_iter(),
+        pending: VecDeque::new(),
+    }
+}
+
+/// Like `events_iter`, for streams of entries (e.g. `rmesg::logs_stream`'s).
+#[cfg(feature = "futures")]
+pub fn events_stream<S>(entries: S) -> impl Stream<Item = Result<EntryEvent, RMesgError>>
+where
+    S: Stream<Item = Result<Entry, RMesgError>>,
+{
+    entries.flat_map(|entry| {
+        stream::iter(match entry {
+            Ok(entry) => entry_events(entry).into_iter().map(Ok).collect(),
+            Err(e) => vec![Err(e)],
+        })
+    })
+}
+
+/// All key=value (or key="value") pairs in a message. Later keys overwrite earlier ones.
+pub(crate) fn key_values(message: &str) -> BTreeMap<String, String> {
+    RE_KEY_VALUE
+        .captures_iter(message)
+        .map(|caps| {
+            let value = caps
+                .name("quoted")
+                .or_else(|| caps.name("value"))
+                .map(|v| v.as_str())
+                .unwrap_or_default();
+            (caps["key"].to_owned(), value.to_owned())
+        })
+        .collect()
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entry::entry;
+
+    #[test]
+    fn test_key_values() {
+        let kv = key_values(r#"apparmor="DENIED" operation="open" pid=12 name="/a b""#);
+        assert_eq!(kv["apparmor"], "DENIED");
+        assert_eq!(kv["operation"], "open");
+        assert_eq!(kv["pid"], "12");
+        assert_eq!(kv["name"], "/a b");
+    }
+
+    struct Widget;
+
+    impl custom::KernelEventParser for Widget {
+        fn name(&self) -> &str {
+            "test-events-widget"
+        }
+
+        fn matches(&self, entry: &Entry) -> Option<custom::CustomEvent> {
+            entry
+                .message
+                .starts_with("widget: ")
+                .then(|| custom::CustomEvent {
+                    parser: self.name().to_owned(),
+                    kind: "widget".to_owned(),
+                    fields: BTreeMap::new(),
+                })
+        }
+    }
+
+    #[test]
+    fn test_events_iter() {
+        custom::register(Widget).unwrap();
+        let entries = vec![
+            Ok(entry("usb 1-1: new device")),
+            Ok(entry("nvme nvme0: I/O 12 QID 3 timeout, aborting")),
+            Err(RMesgError::Timeout("test".to_owned())),
+            Ok(entry("widget: spun")),
+        ];
+        let events: Vec<_> = events_iter(entries).collect();
+        custom::unregister("test-events-widget");
+
+        assert_eq!(events.len(), 3);
+        match &events[0] {
+            Ok(EntryEvent {
+                event: KernelEvent::Nvme(_),
+                entry,
+            }) => assert!(entry.message.starts_with("nvme nvme0")),
+            other => panic!("Expected an NVMe event, got: {:?}", other),
+        }
+        assert!(matches!(events[1], Err(RMesgError::Timeout(_))));
+        match &events[2] {
+            Ok(EntryEvent {
+                event: KernelEvent::Custom(event),
+                ..
+            }) => assert_eq!(event.parser, "test-events-widget"),
+            other => panic!("Expected a custom event, got: {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "futures")]
+    #[test]
+    fn test_events_stream() {
+        let entries = stream::iter(vec![
+            Ok(entry("firmware_class: loaded")),
+            Ok(entry("nvme nvme0: I/O 12 QID 3 timeout, aborting")),
+        ]);
+        let events: Vec<_> = futures::executor::block_on(events_stream(entries).collect());
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            events[0],
+            Ok(EntryEvent {
+                event: KernelEvent::Nvme(_),
+                ..
+            })
+        ));
+    }
+}
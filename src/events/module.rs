@@ -0,0 +1,199 @@
+use crate::entry::Entry;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    static ref RE_MODULE: Regex = Regex::new(
+        r"(?x)^
+        (?P<module>[[:word:]-]+):[[:space:]]+
+        (?:
+            (?P<outoftree>loading[[:space:]]out-of-tree[[:space:]]module[[:space:]]taints[[:space:]]kernel)
+            |(?P<verification>module[[:space:]]verification[[:space:]]failed)
+            |module[[:space:]]license[[:space:]]'(?P<license>[^']*)'[[:space:]]taints[[:space:]]kernel
+            |(?P<staging>module[[:space:]]is[[:space:]]from[[:space:]]the[[:space:]]staging[[:space:]]directory)
+            |Unknown[[:space:]]symbol[[:space:]](?P<unknownsymbol>[^[:space:]]+)
+            |disagrees[[:space:]]about[[:space:]]version[[:space:]]of[[:space:]]symbol[[:space:]](?P<versionsymbol>[^[:space:]]+)
+        )"
+    )
+    .unwrap();
+}
+
+/// The kernel taint flags a module event implies (as shown in /proc/sys/kernel/tainted
+/// and oops reports).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaintFlag {
+    /// 'P': a module with a non-GPL-compatible license was loaded
+    ProprietaryModule,
+    /// 'C': a staging driver was loaded
+    StagingDriver,
+    /// 'O': an externally-built ("out-of-tree") module was loaded
+    OutOfTreeModule,
+    /// 'E': an unsigned module was loaded on a kernel supporting module signatures
+    UnsignedModule,
+}
+
+impl TaintFlag {
+    /// The letter the kernel uses for this flag in taint strings
+    pub fn letter(&self) -> char {
+        match self {
+            Self::ProprietaryModule => 'P',
+            Self::StagingDriver => 'C',
+            Self::OutOfTreeModule => 'O',
+            Self::UnsignedModule => 'E',
+        }
+    }
+
+    /// The bit this flag occupies in /proc/sys/kernel/tainted
+    pub fn bit(&self) -> u32 {
+        match self {
+            Self::ProprietaryModule => 0,
+            Self::StagingDriver => 10,
+            Self::OutOfTreeModule => 12,
+            Self::UnsignedModule => 13,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ModuleEventKind {
+    /// An out-of-tree module was loaded
+    OutOfTree,
+    /// A module's signature could not be verified (it was loaded anyway)
+    VerificationFailed,
+    /// A module with a license tainting the kernel was loaded
+    License { license: String },
+    /// A staging driver was loaded
+    Staging,
+    /// Loading failed because a module needed a symbol the kernel doesn't export
+    UnknownSymbol { symbol: String },
+    /// Loading failed because a module was built against a different symbol version
+    SymbolVersionMismatch { symbol: String },
+}
+
+/// A kernel module being loaded, failing to load, or tainting the kernel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ModuleEvent {
+    pub module: String,
+    pub kind: ModuleEventKind,
+}
+
+impl ModuleEvent {
+    pub fn from_entry(entry: &Entry) -> Option<ModuleEvent> {
+        let parts = RE_MODULE.captures(entry.message.trim_start())?;
+
+        let kind = if parts.name("outoftree").is_some() {
+            ModuleEventKind::OutOfTree
+        } else if parts.name("verification").is_some() {
+            ModuleEventKind::VerificationFailed
+        } else if let Some(license) = parts.name("license") {
+            ModuleEventKind::License {
+                license: license.as_str().to_owned(),
+            }
+        } else if parts.name("staging").is_some() {
+            ModuleEventKind::Staging
+        } else if let Some(symbol) = parts.name("unknownsymbol") {
+            ModuleEventKind::UnknownSymbol {
+                symbol: symbol.as_str().to_owned(),
+            }
+        } else if let Some(symbol) = parts.name("versionsymbol") {
+            ModuleEventKind::SymbolVersionMismatch {
+                symbol: symbol.as_str().to_owned(),
+            }
+        } else {
+            return None;
+        };
+
+        Some(ModuleEvent {
+            module: parts["module"].to_owned(),
+            kind,
+        })
+    }
+
+    /// The taint flag this event sets on the kernel, if any.
+    pub fn taint(&self) -> Option<TaintFlag> {
+        match self.kind {
+            ModuleEventKind::OutOfTree => Some(TaintFlag::OutOfTreeModule),
+            ModuleEventKind::VerificationFailed => Some(TaintFlag::UnsignedModule),
+            ModuleEventKind::License { .. } => Some(TaintFlag::ProprietaryModule),
+            ModuleEventKind::Staging => Some(TaintFlag::StagingDriver),
+            ModuleEventKind::UnknownSymbol { .. }
+            | ModuleEventKind::SymbolVersionMismatch { .. } => None,
+        }
+    }
+
+    /// Whether the module failed to load.
+    pub fn load_failed(&self) -> bool {
+        matches!(
+            self.kind,
+            ModuleEventKind::UnknownSymbol { .. } | ModuleEventKind::SymbolVersionMismatch { .. }
+        )
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(message: &str) -> Option<ModuleEvent> {
+        ModuleEvent::from_entry(&Entry {
+            message: message.to_owned(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_taints() {
+        let e = parse(" vboxdrv: loading out-of-tree module taints kernel.").unwrap();
+        assert_eq!(e.module, "vboxdrv");
+        assert_eq!(e.kind, ModuleEventKind::OutOfTree);
+        assert_eq!(e.taint(), Some(TaintFlag::OutOfTreeModule));
+        assert_eq!(e.taint().unwrap().letter(), 'O');
+
+        let e = parse(
+            "vboxdrv: module verification failed: signature and/or required key missing - tainting kernel",
+        )
+        .unwrap();
+        assert_eq!(e.kind, ModuleEventKind::VerificationFailed);
+        assert_eq!(e.taint(), Some(TaintFlag::UnsignedModule));
+
+        let e = parse("nvidia: module license 'NVIDIA' taints kernel.").unwrap();
+        assert_eq!(
+            e.kind,
+            ModuleEventKind::License {
+                license: "NVIDIA".to_owned()
+            }
+        );
+        assert_eq!(e.taint().unwrap().bit(), 0);
+
+        let e = parse("r8188eu: module is from the staging directory, the quality is unknown, you have been warned.").unwrap();
+        assert_eq!(e.module, "r8188eu");
+        assert_eq!(e.taint(), Some(TaintFlag::StagingDriver));
+    }
+
+    #[test]
+    fn test_load_failures() {
+        let e = parse("zfs: Unknown symbol spl_panic (err -2)").unwrap();
+        assert_eq!(
+            e.kind,
+            ModuleEventKind::UnknownSymbol {
+                symbol: "spl_panic".to_owned()
+            }
+        );
+        assert!(e.load_failed());
+        assert_eq!(e.taint(), None);
+
+        let e = parse("wl: disagrees about version of symbol module_layout").unwrap();
+        assert_eq!(e.module, "wl");
+        assert!(e.load_failed());
+    }
+
+    #[test]
+    fn test_unrelated() {
+        assert!(parse("usb 1-1: new high-speed USB device").is_none());
+        assert!(parse("Disabling lock debugging due to kernel taint").is_none());
+    }
+}
@@ -0,0 +1,262 @@
+use crate::entry::Entry;
+use crate::events::units;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::convert::TryFrom;
+
+lazy_static! {
+    // nvme nvme0: <rest>    or    nvme0n1: <rest>
+    static ref RE_NVME_DEVICE: Regex = Regex::new(
+        r"(?x)^
+        (?:nvme[[:space:]]+)?
+        (?P<controller>nvme[[:digit:]]+)(?:c[[:digit:]]+)?(?:n(?P<namespace>[[:digit:]]+))?:[[:space:]]+
+        (?P<rest>.*)
+        $"
+    )
+    .unwrap();
+
+    // I/O 123 QID 4 timeout, aborting
+    // I/O tag 64 (0040) opcode 0x2 (Read) QID 2 timeout, reset controller
+    static ref RE_NVME_TIMEOUT: Regex = Regex::new(
+        r"(?x)^
+        I/O[[:space:]](?:tag[[:space:]])?(?P<tag>[[:digit:]]+)[[:space:]]
+        (?:\([[:xdigit:]]+\)[[:space:]])?
+        (?:opcode[[:space:]](?P<opcode>0x[[:xdigit:]]+)(?:[[:space:]]\([^)]*\))?[[:space:]])?
+        QID[[:space:]](?P<qid>[[:digit:]]+)[[:space:]]timeout,[[:space:]]
+        (?P<action>aborting|reset[[:space:]]controller|disable[[:space:]]controller|completion[[:space:]]polled)"
+    )
+    .unwrap();
+
+    // controller is down; will reset: CSTS=0xffffffff, PCI_STATUS=0xffff
+    static ref RE_NVME_DOWN: Regex = Regex::new(
+        r"^controller is down; will reset: CSTS=(?P<csts>0x[[:xdigit:]]+)"
+    )
+    .unwrap();
+
+    // Device not ready; aborting reset, CSTS=0x1
+    static ref RE_NVME_NOT_READY: Regex = Regex::new(
+        r"^Device not ready; aborting (?:reset|initialisation)"
+    )
+    .unwrap();
+
+    // Removing after probe failure status: -19
+    static ref RE_NVME_REMOVED: Regex = Regex::new(
+        r"^Removing after probe failure status: (?P<status>-?[[:digit:]]+)"
+    )
+    .unwrap();
+
+    // Read(0x2) @ LBA 12345, 8 blocks, Unrecovered Read Error (sct 0x2 / sc 0x81) DNR
+    static ref RE_NVME_COMMAND_ERROR: Regex = Regex::new(
+        r"(?x)^
+        (?P<command>[^@]+?)[[:space:]]@[[:space:]]LBA[[:space:]](?P<lba>[[:digit:]]+),[[:space:]]
+        (?P<blocks>[[:digit:]]+)[[:space:]]blocks,[[:space:]]
+        (?P<status>.+?)[[:space:]]
+        \(sct[[:space:]](?P<sct>0x[[:xdigit:]]+)[[:space:]]/[[:space:]]sc[[:space:]](?P<sc>0x[[:xdigit:]]+)\)
+        (?P<flags>.*)
+        $"
+    )
+    .unwrap();
+}
+
+/// NVMe status code type for media and data integrity errors
+const SCT_MEDIA_ERROR: u8 = 0x2;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NvmeEventKind {
+    /// A command didn't complete in time; `action` is what the driver did about it
+    /// ("aborting", "reset controller", ...)
+    Timeout {
+        tag: u32,
+        qid: u16,
+        opcode: Option<u8>,
+        action: String,
+    },
+    /// The controller stopped responding and is being reset
+    ControllerDown { csts: u32 },
+    /// A controller reset or initialisation was abandoned
+    ResetFailed,
+    /// The driver gave up on the controller
+    Removed { status: i32 },
+    /// A command completed with an error status
+    CommandError {
+        /// The command as the kernel named it (e.g. "Read(0x2)")
+        command: String,
+        lba: u64,
+        blocks: u32,
+        /// The kernel's description of the status (e.g. "Unrecovered Read Error")
+        status: String,
+        /// Status code type
+        sct: u8,
+        /// Status code
+        sc: u8,
+        /// "Do not retry" was set
+        dnr: bool,
+    },
+}
+
+/// An NVMe driver error: a timeout, controller reset or failed command.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NvmeEvent {
+    /// The controller this concerns (e.g. "nvme0")
+    pub controller: String,
+    /// The namespace id, for errors reported against a namespace (nvme0n1 -> 1)
+    pub namespace: Option<u32>,
+    pub kind: NvmeEventKind,
+}
+
+impl NvmeEvent {
+    pub fn from_entry(entry: &Entry) -> Option<NvmeEvent> {
+        let device = RE_NVME_DEVICE.captures(entry.message.trim_start())?;
+        let rest = device.name("rest")?.as_str();
+
+        let kind = if let Some(timeout) = RE_NVME_TIMEOUT.captures(rest) {
+            NvmeEventKind::Timeout {
+                tag: timeout["tag"].parse().ok()?,
+                qid: timeout["qid"].parse().ok()?,
+                opcode: timeout.name("opcode").and_then(|o| count(o.as_str())),
+                action: timeout["action"].to_owned(),
+            }
+        } else if let Some(down) = RE_NVME_DOWN.captures(rest) {
+            NvmeEventKind::ControllerDown {
+                csts: count(&down["csts"])?,
+            }
+        } else if RE_NVME_NOT_READY.is_match(rest) {
+            NvmeEventKind::ResetFailed
+        } else if let Some(removed) = RE_NVME_REMOVED.captures(rest) {
+            NvmeEventKind::Removed {
+                status: removed["status"].parse().ok()?,
+            }
+        } else if let Some(error) = RE_NVME_COMMAND_ERROR.captures(rest) {
+            NvmeEventKind::CommandError {
+                command: error["command"].to_owned(),
+                lba: error["lba"].parse().ok()?,
+                blocks: error["blocks"].parse().ok()?,
+                status: error["status"].to_owned(),
+                sct: count(&error["sct"])?,
+                sc: count(&error["sc"])?,
+                dnr: error["flags"].split_whitespace().any(|f| f == "DNR"),
+            }
+        } else {
+            return None;
+        };
+
+        Some(NvmeEvent {
+            controller: device["controller"].to_owned(),
+            namespace: device
+                .name("namespace")
+                .and_then(|n| n.as_str().parse().ok()),
+            kind,
+        })
+    }
+
+    /// Whether this reports a media or data integrity error (i.e. the flash itself is failing).
+    pub fn is_media_error(&self) -> bool {
+        matches!(self.kind, NvmeEventKind::CommandError { sct, .. } if sct == SCT_MEDIA_ERROR)
+    }
+
+    /// Whether this reports the controller being (or failing to be) reset or removed.
+    pub fn is_controller_reset(&self) -> bool {
+        match &self.kind {
+            NvmeEventKind::Timeout { action, .. } => action == "reset controller",
+            NvmeEventKind::ControllerDown { .. }
+            | NvmeEventKind::ResetFailed
+            | NvmeEventKind::Removed { .. } => true,
+            NvmeEventKind::CommandError { .. } => false,
+        }
+    }
+}
+
+// A 0x count that fits in a `T` (an opcode, a status code...)
+fn count<T: TryFrom<u64>>(count: &str) -> Option<T> {
+    units::parse_count(count).and_then(|n| T::try_from(n).ok())
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(message: &str) -> Option<NvmeEvent> {
+        NvmeEvent::from_entry(&Entry {
+            message: message.to_owned(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let e = parse("nvme nvme0: I/O 123 QID 4 timeout, aborting").unwrap();
+        assert_eq!(e.controller, "nvme0");
+        assert_eq!(e.namespace, None);
+        assert_eq!(
+            e.kind,
+            NvmeEventKind::Timeout {
+                tag: 123,
+                qid: 4,
+                opcode: None,
+                action: "aborting".to_owned()
+            }
+        );
+        assert!(!e.is_controller_reset());
+
+        let e = parse(
+            "nvme nvme1: I/O tag 64 (0040) opcode 0x2 (Read) QID 2 timeout, reset controller",
+        )
+        .unwrap();
+        assert_eq!(e.controller, "nvme1");
+        match e.kind {
+            NvmeEventKind::Timeout {
+                tag, qid, opcode, ..
+            } => {
+                assert_eq!((tag, qid, opcode), (64, 2, Some(2)));
+            }
+            ref other => panic!("Expected a timeout, got {:?}", other),
+        }
+        assert!(e.is_controller_reset());
+    }
+
+    #[test]
+    fn test_controller_failures() {
+        let e =
+            parse("nvme nvme0: controller is down; will reset: CSTS=0xffffffff, PCI_STATUS=0xffff")
+                .unwrap();
+        assert_eq!(e.kind, NvmeEventKind::ControllerDown { csts: 0xffffffff });
+        assert!(e.is_controller_reset());
+
+        let e = parse("nvme nvme0: Device not ready; aborting reset, CSTS=0x1").unwrap();
+        assert_eq!(e.kind, NvmeEventKind::ResetFailed);
+
+        let e = parse("nvme nvme0: Removing after probe failure status: -19").unwrap();
+        assert_eq!(e.kind, NvmeEventKind::Removed { status: -19 });
+    }
+
+    #[test]
+    fn test_media_error() {
+        let e = parse(
+            "nvme0n1: Read(0x2) @ LBA 12345, 8 blocks, Unrecovered Read Error (sct 0x2 / sc 0x81) DNR ",
+        )
+        .unwrap();
+        assert_eq!(e.controller, "nvme0");
+        assert_eq!(e.namespace, Some(1));
+        assert_eq!(
+            e.kind,
+            NvmeEventKind::CommandError {
+                command: "Read(0x2)".to_owned(),
+                lba: 12345,
+                blocks: 8,
+                status: "Unrecovered Read Error".to_owned(),
+                sct: 2,
+                sc: 0x81,
+                dnr: true,
+            }
+        );
+        assert!(e.is_media_error());
+        assert!(!e.is_controller_reset());
+
+        assert!(parse("nvme nvme0: 8/0/0 default/read/poll queues").is_none());
+        assert!(parse("usb 1-1: I/O 1 QID 1 timeout, aborting").is_none());
+    }
+}
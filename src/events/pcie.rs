@@ -0,0 +1,251 @@
+use crate::entry::Entry;
+use crate::events::units;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::convert::TryFrom;
+
+lazy_static! {
+    // pcieport 0000:00:1c.5: AER: <rest>
+    static ref RE_PCI_DEVICE: Regex = Regex::new(
+        r"(?x)^
+        (?:(?P<driver>[^[:space:]]+)[[:space:]]+)?
+        (?P<bdf>[[:xdigit:]]{4}:[[:xdigit:]]{2}:[[:xdigit:]]{2}\.[[:xdigit:]]):[[:space:]]+
+        (?:AER:[[:space:]]+)?
+        (?P<rest>.*)
+        $"
+    )
+    .unwrap();
+
+    // PCIe Bus Error: severity=Corrected, type=Physical Layer, (Receiver ID)
+    static ref RE_AER_HEADER: Regex = Regex::new(
+        r"^PCIe Bus Error: severity=(?P<severity>[^,]+), type=(?P<type>[^,]+)(?:, \((?P<agent>[^)]+)\))?"
+    )
+    .unwrap();
+
+    // device [8086:9d15] error status/mask=00000001/00002000
+    static ref RE_AER_STATUS: Regex = Regex::new(
+        r"^device \[(?P<id>[[:xdigit:]]{4}:[[:xdigit:]]{4})\] error status/mask=(?P<status>[[:xdigit:]]+)/(?P<mask>[[:xdigit:]]+)"
+    )
+    .unwrap();
+
+    // [14] CmpltTO                (First)
+    static ref RE_AER_BIT: Regex = Regex::new(
+        r"^\[[[:space:]]*(?P<bit>[[:digit:]]+)\][[:space:]]+(?P<name>[^[:space:]]+)(?P<first>[[:space:]]+\(First\))?"
+    )
+    .unwrap();
+
+    // TLP Header: 40000001 0000000f 90028090 00000000
+    static ref RE_AER_TLP: Regex = Regex::new(
+        r"^TLP Header:(?P<dwords>(?:[[:space:]]+[[:xdigit:]]{8})+)"
+    )
+    .unwrap();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AerSeverity {
+    Corrected,
+    /// Uncorrected (Non-Fatal)
+    NonFatal,
+    /// Uncorrected (Fatal)
+    Fatal,
+}
+
+/// One error bit set in the AER status register.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AerError {
+    pub bit: u8,
+    /// The kernel's name for the error (e.g. "RxErr", "CmpltTO")
+    pub name: String,
+    /// Whether the kernel flagged this as the first error logged
+    pub first: bool,
+}
+
+/// A PCIe Advanced Error Reporting event.
+///
+/// The kernel reports an AER event over several lines: `from_entry` recognizes the
+/// "PCIe Bus Error" line that starts one, while `from_entries` also collects the details
+/// (status, individual error bits and TLP header) from the lines that follow.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PcieAerEvent {
+    /// The driver bound to the reporting device (e.g. "pcieport", "nvme")
+    pub driver: Option<String>,
+    /// Bus/device/function address, as domain:bus:device.function (e.g. "0000:00:1c.5")
+    pub bdf: String,
+    pub severity: AerSeverity,
+    /// The layer the error was detected in (e.g. "Physical Layer", "Transaction Layer")
+    pub error_type: String,
+    /// The agent that reported the error (e.g. "Receiver ID", "Requester ID")
+    pub agent: Option<String>,
+
+    /// vendor:device IDs (e.g. "8086:9d15")
+    pub device_id: Option<String>,
+    pub status: Option<u32>,
+    pub mask: Option<u32>,
+    pub errors: Vec<AerError>,
+    /// The header of the TLP that caused the error, when one was logged
+    pub tlp_header: Option<Vec<u32>>,
+}
+
+impl PcieAerEvent {
+    pub fn from_entry(entry: &Entry) -> Option<PcieAerEvent> {
+        let device = RE_PCI_DEVICE.captures(entry.message.trim_start())?;
+        let header = RE_AER_HEADER.captures(device.name("rest")?.as_str())?;
+
+        let severity = header["severity"].trim();
+        let severity = if severity == "Corrected" {
+            AerSeverity::Corrected
+        } else if severity.contains("Non-Fatal") {
+            AerSeverity::NonFatal
+        } else if severity.contains("Fatal") {
+            AerSeverity::Fatal
+        } else {
+            return None;
+        };
+
+        Some(PcieAerEvent {
+            driver: device.name("driver").map(|d| d.as_str().to_owned()),
+            bdf: device["bdf"].to_owned(),
+            severity,
+            error_type: header["type"].trim().to_owned(),
+            agent: header.name("agent").map(|a| a.as_str().to_owned()),
+            device_id: None,
+            status: None,
+            mask: None,
+            errors: vec![],
+            tlp_header: None,
+        })
+    }
+
+    /// All AER events in `entries`, each with the details logged after it for the same device.
+    pub fn from_entries(entries: &[Entry]) -> Vec<PcieAerEvent> {
+        let mut events: Vec<PcieAerEvent> = Vec::new();
+
+        for entry in entries {
+            if let Some(event) = PcieAerEvent::from_entry(entry) {
+                events.push(event);
+                continue;
+            }
+
+            let current = match events.last_mut() {
+                Some(current) => current,
+                None => continue,
+            };
+            let device = match RE_PCI_DEVICE.captures(entry.message.trim_start()) {
+                Some(device) if device["bdf"] == current.bdf => device,
+                _ => continue,
+            };
+            current.add_detail(device.name("rest").map(|r| r.as_str()).unwrap_or_default());
+        }
+
+        events
+    }
+
+    fn add_detail(&mut self, detail: &str) {
+        let detail = detail.trim_start();
+        if let Some(status) = RE_AER_STATUS.captures(detail) {
+            self.device_id = Some(status["id"].to_owned());
+            self.status = register(&status["status"]);
+            self.mask = register(&status["mask"]);
+        } else if let Some(bit) = RE_AER_BIT.captures(detail) {
+            if let Ok(b) = bit["bit"].parse() {
+                self.errors.push(AerError {
+                    bit: b,
+                    name: bit["name"].to_owned(),
+                    first: bit.name("first").is_some(),
+                });
+            }
+        } else if let Some(tlp) = RE_AER_TLP.captures(detail) {
+            self.tlp_header = tlp["dwords"].split_whitespace().map(register).collect();
+        }
+    }
+}
+
+// A 32-bit register, printed as hex digits without the 0x
+fn register(digits: &str) -> Option<u32> {
+    units::parse_hex(digits).and_then(|n| u32::try_from(n).ok())
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entries(messages: &[&str]) -> Vec<Entry> {
+        messages
+            .iter()
+            .map(|m| Entry {
+                message: (*m).to_owned(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_corrected_error() {
+        let events = PcieAerEvent::from_entries(&entries(&[
+            "pcieport 0000:00:1c.5: AER: Corrected error received: 0000:00:1c.5",
+            "pcieport 0000:00:1c.5: PCIe Bus Error: severity=Corrected, type=Physical Layer, (Receiver ID)",
+            "pcieport 0000:00:1c.5:   device [8086:9d15] error status/mask=00000001/00002000",
+            "usb 1-1: unrelated message in between",
+            "pcieport 0000:00:1c.5:    [ 0] RxErr",
+        ]));
+        assert_eq!(events.len(), 1);
+        let e = &events[0];
+        assert_eq!(e.driver.as_deref(), Some("pcieport"));
+        assert_eq!(e.bdf, "0000:00:1c.5");
+        assert_eq!(e.severity, AerSeverity::Corrected);
+        assert_eq!(e.error_type, "Physical Layer");
+        assert_eq!(e.agent.as_deref(), Some("Receiver ID"));
+        assert_eq!(e.device_id.as_deref(), Some("8086:9d15"));
+        assert_eq!(e.status, Some(1));
+        assert_eq!(e.mask, Some(0x2000));
+        assert_eq!(
+            e.errors,
+            vec![AerError {
+                bit: 0,
+                name: "RxErr".to_owned(),
+                first: false
+            }]
+        );
+        assert_eq!(e.tlp_header, None);
+    }
+
+    #[test]
+    fn test_uncorrected_error_with_tlp() {
+        let events = PcieAerEvent::from_entries(&entries(&[
+            "nvme 0000:03:00.0: AER: PCIe Bus Error: severity=Uncorrected (Non-Fatal), type=Transaction Layer, (Requester ID)",
+            "nvme 0000:03:00.0: AER:   device [144d:a808] error status/mask=00004000/00400000",
+            "nvme 0000:03:00.0: AER:    [14] CmpltTO                (First)",
+            "nvme 0000:03:00.0: AER:   TLP Header: 40000001 0000000f 90028090 00000000",
+            "pcieport 0000:00:01.0: AER: PCIe Bus Error: severity=Uncorrected (Fatal), type=Inaccessible, (Unregistered Agent ID)",
+        ]));
+        assert_eq!(events.len(), 2);
+
+        let e = &events[0];
+        assert_eq!(e.severity, AerSeverity::NonFatal);
+        assert_eq!(e.error_type, "Transaction Layer");
+        assert_eq!(e.status, Some(0x4000));
+        assert_eq!(e.errors[0].name, "CmpltTO");
+        assert!(e.errors[0].first);
+        assert_eq!(
+            e.tlp_header,
+            Some(vec![0x40000001, 0x0000000f, 0x90028090, 0])
+        );
+
+        assert_eq!(events[1].severity, AerSeverity::Fatal);
+        assert_eq!(events[1].bdf, "0000:00:01.0");
+        assert!(events[1].errors.is_empty());
+    }
+
+    #[test]
+    fn test_not_aer() {
+        assert!(PcieAerEvent::from_entries(&entries(&[
+            "pci 0000:00:1c.5: [8086:9d15] type 01 class 0x060400",
+            "pcieport 0000:00:1c.5:    [ 0] RxErr",
+        ]))
+        .is_empty());
+    }
+}
@@ -0,0 +1,297 @@
+use crate::entry::LogLevel;
+use crate::error::RMesgError;
+use crate::events::{EntryEvent, KernelEvent};
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+#[cfg(feature = "futures")]
+use futures::stream::{self, Stream, StreamExt};
+
+/// Escalates when `count` events of type `kind` (see `KernelEvent::kind`) are seen
+/// within `window` of kernel time.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThresholdRule {
+    /// Identifies the rule in the alerts it raises
+    pub name: String,
+    pub kind: String,
+    pub count: usize,
+    pub window: Duration,
+    /// The severity of the alerts it raises
+    pub level: LogLevel,
+}
+
+impl ThresholdRule {
+    pub fn with_options(
+        name: &str,
+        kind: &str,
+        count: usize,
+        window: Duration,
+        level: LogLevel,
+    ) -> ThresholdRule {
+        ThresholdRule {
+            name: name.to_owned(),
+            kind: kind.to_owned(),
+            count,
+            window,
+            level,
+        }
+    }
+
+    pub fn matches(&self, event: &KernelEvent) -> bool {
+        event.kind() == self.kind
+    }
+}
+
+/// A rule's threshold was reached.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Alert {
+    /// The name of the rule that raised it
+    pub rule: String,
+    pub level: LogLevel,
+    /// The events that reached the threshold, oldest first
+    pub events: Vec<EntryEvent>,
+    /// Kernel time from the first of them to the last
+    pub span: Duration,
+}
+
+#[derive(Debug)]
+struct RuleState {
+    rule: ThresholdRule,
+    // timestamped events within the window, oldest first
+    seen: VecDeque<(Duration, EntryEvent)>,
+}
+
+/// A set of threshold rules, applied to each event in turn.
+///
+/// Time is the kernel's (entries' timestamps since boot), so that replaying a log gives
+/// the same alerts as following it did; events from entries without a timestamp are
+/// counted as of the newest timestamp seen. Once a rule raises an alert, it starts
+/// counting afresh, so a steady stream of events raises one alert per `count` of them
+/// rather than one per event.
+#[derive(Debug, Default)]
+pub struct Escalation {
+    rules: Vec<RuleState>,
+    newest: Duration,
+}
+
+impl Escalation {
+    pub fn new() -> Escalation {
+        Escalation::default()
+    }
+
+    /// Adds `rule`, unless it can never fire or one of the same name is already there.
+    pub fn rule(mut self, rule: ThresholdRule) -> Result<Escalation, RMesgError> {
+        if rule.count == 0 {
+            return Err(RMesgError::ConfigError(format!(
+                "Escalation rule {} needs a count of at least one",
+                rule.name
+            )));
+        }
+        if self.rules.iter().any(|r| r.rule.name == rule.name) {
+            return Err(RMesgError::ConfigError(format!(
+                "An escalation rule named {} is already defined",
+                rule.name
+            )));
+        }
+        self.rules.push(RuleState {
+            rule,
+            seen: VecDeque::new(),
+        });
+        Ok(self)
+    }
+
+    /// Adds a rule escalating `count` events of type `kind` within `window`.
+    pub fn threshold(
+        self,
+        name: &str,
+        kind: &str,
+        count: usize,
+        window: Duration,
+        level: LogLevel,
+    ) -> Result<Escalation, RMesgError> {
+        self.rule(ThresholdRule::with_options(
+            name, kind, count, window, level,
+        ))
+    }
+
+    pub fn rules(&self) -> Vec<&ThresholdRule> {
+        self.rules.iter().map(|r| &r.rule).collect()
+    }
+
+    /// Counts `event` against the rules, returning the alerts it raises.
+    pub fn observe(&mut self, event: &EntryEvent) -> Vec<Alert> {
+        let now = match event.entry.timestamp_from_system_start {
+            Some(ts) => ts.max(self.newest),
+            None => self.newest,
+        };
+        self.newest = now;
+
+        let mut alerts = Vec::new();
+        for state in self.rules.iter_mut() {
+            let window = state.rule.window;
+            while state
+                .seen
+                .front()
+                .is_some_and(|(seen_at, _)| now - *seen_at > window)
+            {
+                state.seen.pop_front();
+            }
+            if !state.rule.matches(&event.event) {
+                continue;
+            }
+
+            state.seen.push_back((now, event.clone()));
+            if state.seen.len() >= state.rule.count {
+                let first = state.seen.front().map_or(now, |(seen_at, _)| *seen_at);
+                alerts.push(Alert {
+                    rule: state.rule.name.clone(),
+                    level: state.rule.level,
+                    events: state.seen.drain(..).map(|(_, event)| event).collect(),
+                    span: now - first,
+                });
+            }
+        }
+        alerts
+    }
+
+    /// Forgets the events counted so far.
+    pub fn reset(&mut self) {
+        for state in self.rules.iter_mut() {
+            state.seen.clear();
+        }
+        self.newest = Duration::from_secs(0);
+    }
+}
+
+/// The alerts raised by a stream of events (see `alerts_iter`).
+pub struct AlertsIter<I> {
+    events: I,
+    escalation: Escalation,
+    pending: VecDeque<Alert>,
+}
+
+impl<I> AlertsIter<I> {
+    pub fn escalation(&self) -> &Escalation {
+        &self.escalation
+    }
+}
+
+impl<I: Iterator<Item = Result<EntryEvent, RMesgError>>> Iterator for AlertsIter<I> {
+    type Item = Result<Alert, RMesgError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(alert) = self.pending.pop_front() {
+                return Some(Ok(alert));
+            }
+            match self.events.next()? {
+                Ok(event) => self.pending.extend(self.escalation.observe(&event)),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// The alerts `escalation` raises over `events` (e.g. `events::events_iter`'s). Errors
+/// are passed through.
+pub fn alerts_iter<I>(events: I, escalation: Escalation) -> AlertsIter<I::IntoIter>
+where
+    I: IntoIterator<Item = Result<EntryEvent, RMesgError>>,
+{
+    AlertsIter {
+        events: events.into_iter(),
+        escalation,
+        pending: VecDeque::new(),
+    }
+}
+
+/// Like `alerts_iter`, for streams of events (e.g. `events::events_stream`'s).
+#[cfg(feature = "futures")]
+pub fn alerts_stream<S>(
+    events: S,
+    mut escalation: Escalation,
+) -> impl Stream<Item = Result<Alert, RMesgError>>
+where
+    S: Stream<Item = Result<EntryEvent, RMesgError>>,
+{
+    events.flat_map(move |event| {
+        stream::iter(match event {
+            Ok(event) => escalation.observe(&event).into_iter().map(Ok).collect(),
+            Err(e) => vec![Err(e)],
+        })
+    })
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entry::Entry;
+    use crate::events::events_iter;
+
+    fn entry(secs: u64, message: &str) -> Entry {
+        Entry {
+            timestamp_from_system_start: Some(Duration::from_secs(secs)),
+            message: message.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    const NVME_TIMEOUT: &str = "nvme nvme0: I/O 12 QID 3 timeout, aborting";
+
+    #[test]
+    fn test_threshold() {
+        let escalation = Escalation::new()
+            .threshold(
+                "nvme-storm",
+                "nvme",
+                3,
+                Duration::from_secs(60),
+                LogLevel::Critical,
+            )
+            .unwrap();
+
+        let entries = vec![
+            // too spread out to count together
+            Ok(entry(0, NVME_TIMEOUT)),
+            Ok(entry(30, NVME_TIMEOUT)),
+            Ok(entry(100, "usb 1-1: new device")),
+            Ok(entry(100, NVME_TIMEOUT)),
+            Err(RMesgError::Timeout("test".to_owned())),
+            Ok(entry(120, NVME_TIMEOUT)),
+            Ok(entry(130, NVME_TIMEOUT)),
+            // counting starts afresh after an alert
+            Ok(entry(131, NVME_TIMEOUT)),
+        ];
+        let alerts: Vec<_> = alerts_iter(events_iter(entries), escalation).collect();
+
+        assert_eq!(alerts.len(), 2);
+        assert!(matches!(alerts[0], Err(RMesgError::Timeout(_))));
+        match &alerts[1] {
+            Ok(alert) => {
+                assert_eq!(alert.rule, "nvme-storm");
+                assert_eq!(alert.level, LogLevel::Critical);
+                assert_eq!(alert.events.len(), 3);
+                assert_eq!(alert.span, Duration::from_secs(30));
+            }
+            other => panic!("Expected an alert, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let rule =
+            ThresholdRule::with_options("a", "gpu", 1, Duration::from_secs(1), LogLevel::Alert);
+        let escalation = Escalation::new().rule(rule.clone()).unwrap();
+        assert!(matches!(
+            escalation.rule(rule),
+            Err(RMesgError::ConfigError(_))
+        ));
+        assert!(Escalation::new()
+            .threshold("b", "gpu", 0, Duration::from_secs(1), LogLevel::Alert)
+            .is_err());
+    }
+}
@@ -0,0 +1,226 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::convert::TryFrom;
+
+lazy_static! {
+    // 4096K    2 MiB    1.00 TB    1234kB    8 sectors    0x1000 bytes
+    static ref RE_SIZE: Regex = Regex::new(
+        r"(?x)
+        \b(?P<number>0x[[:xdigit:]]+|[[:digit:]]+(?:\.[[:digit:]]+)?)
+        [[:space:]]?
+        (?P<unit>(?i:[kmgtpe]i?b?|bytes?|b|sectors?))
+        \b"
+    )
+    .unwrap();
+}
+
+/// How the prefixes of sizes without an explicit base (K, kB, M, MB...) are read. KiB,
+/// MiB and the other IEC prefixes are always binary.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PrefixBase {
+    /// K is 1024: what the memory management code means by it ("Memory: 16270884K",
+    /// "total-vm:1234kB")
+    #[default]
+    Binary,
+    /// K is 1000: what the block layer means by it ("(1.00 TB/932 GiB)")
+    Decimal,
+}
+
+/// How sizes are read. Numbers are always read the way the kernel prints them, whatever
+/// the locale: ASCII digits, a '.' before any decimals, no grouping, or 0x and hex digits.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnitOptions {
+    pub prefixes: PrefixBase,
+    /// Bytes in a sector, for sizes counted in sectors
+    pub sector_size: u64,
+}
+
+impl Default for UnitOptions {
+    fn default() -> Self {
+        Self {
+            prefixes: PrefixBase::default(),
+            sector_size: 512,
+        }
+    }
+}
+
+impl UnitOptions {
+    /// A size in bytes, e.g. "4096K", "2 MiB", "1.00 TB", "8 sectors" or "512" (bytes).
+    /// Sizes with decimals are rounded down to a whole byte. Returns `None` for anything
+    /// else, or for sizes that don't fit in a u64.
+    pub fn parse_size(&self, size: &str) -> Option<u64> {
+        let size = size.trim();
+        let split = match size.strip_prefix("0x") {
+            Some(hex) => {
+                2 + hex
+                    .find(|c: char| !c.is_ascii_hexdigit())
+                    .unwrap_or(hex.len())
+            }
+            None => size
+                .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .unwrap_or(size.len()),
+        };
+        let (number, unit) = size.split_at(split);
+        let multiplier = self.multiplier(unit.trim_start())?;
+        scale(number, multiplier)
+    }
+
+    /// Every size in `message` with a unit, in bytes, in the order they appear.
+    pub fn sizes(&self, message: &str) -> Vec<u64> {
+        RE_SIZE
+            .captures_iter(message)
+            .filter_map(|caps| scale(&caps["number"], self.multiplier(&caps["unit"])?))
+            .collect()
+    }
+
+    // Bytes per unit: "" and "B"/"bytes" are bytes, then a prefix (K, M, G, T, P or E, in
+    // either case) optionally marked binary with an i and optionally followed by B
+    fn multiplier(&self, unit: &str) -> Option<u64> {
+        let lower = unit.to_ascii_lowercase();
+        match lower.as_str() {
+            "" | "b" | "byte" | "bytes" => return Some(1),
+            "sector" | "sectors" => return Some(self.sector_size),
+            _ => {}
+        }
+
+        let mut chars = lower.chars();
+        let power = match chars.next()? {
+            'k' => 1,
+            'm' => 2,
+            'g' => 3,
+            't' => 4,
+            'p' => 5,
+            'e' => 6,
+            _ => return None,
+        };
+        let rest = chars.as_str();
+        let base: u64 = match (rest, self.prefixes) {
+            ("i" | "ib", _) => 1024,
+            ("" | "b", PrefixBase::Binary) => 1024,
+            ("" | "b", PrefixBase::Decimal) => 1000,
+            _ => return None,
+        };
+        base.checked_pow(power)
+    }
+}
+
+/// A count, as the kernel prints them: decimal digits, or 0x and hex digits.
+pub fn parse_count(count: &str) -> Option<u64> {
+    let count = count.trim();
+    match count.strip_prefix("0x") {
+        Some(hex) => parse_hex(hex),
+        None if !count.is_empty() && count.chars().all(|c| c.is_ascii_digit()) => {
+            count.parse().ok()
+        }
+        None => None,
+    }
+}
+
+/// Hex digits without the 0x, as some drivers print counts and registers (AER's
+/// "status/mask=00000001/00002000").
+pub fn parse_hex(digits: &str) -> Option<u64> {
+    match !digits.is_empty() && digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        true => u64::from_str_radix(digits, 16).ok(),
+        false => None,
+    }
+}
+
+// `number` (a count, or digits with decimals) times `multiplier`, rounded down
+fn scale(number: &str, multiplier: u64) -> Option<u64> {
+    if let Some(count) = parse_count(number) {
+        return count.checked_mul(multiplier);
+    }
+
+    let (whole, decimals) = number.split_once('.')?;
+    if whole.is_empty()
+        || decimals.is_empty()
+        || !whole
+            .chars()
+            .chain(decimals.chars())
+            .all(|c| c.is_ascii_digit())
+    {
+        return None;
+    }
+    let whole: u128 = whole.parse().ok()?;
+    // digits beyond the 20th can't make a difference of a byte in a u64
+    let decimals = &decimals[..decimals.len().min(20)];
+    let scale = 10u128.pow(decimals.len() as u32);
+    let fraction: u128 = decimals.parse().ok()?;
+    let multiplier = u128::from(multiplier);
+    let bytes = whole.checked_mul(multiplier)? + fraction * multiplier / scale;
+    u64::try_from(bytes).ok()
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        let binary = UnitOptions::default();
+        assert_eq!(binary.parse_size("4096K"), Some(4_194_304));
+        assert_eq!(binary.parse_size("1234kB"), Some(1_263_616));
+        assert_eq!(binary.parse_size("2 MiB"), Some(2_097_152));
+        assert_eq!(binary.parse_size("512"), Some(512));
+        assert_eq!(binary.parse_size("0x1000 bytes"), Some(4096));
+        assert_eq!(binary.parse_size("8 sectors"), Some(4096));
+        assert_eq!(binary.parse_size("1.5G"), Some(1_610_612_736));
+
+        let decimal = UnitOptions {
+            prefixes: PrefixBase::Decimal,
+            sector_size: 4096,
+        };
+        assert_eq!(decimal.parse_size("1.00 TB"), Some(1_000_000_000_000));
+        assert_eq!(decimal.parse_size("932 GiB"), Some(1_000_727_379_968));
+        assert_eq!(decimal.parse_size("2 sectors"), Some(8192));
+
+        // not sizes, localized numbers included
+        for size in &[
+            "",
+            "K",
+            "1,5G",
+            "1.234.567",
+            "1 000K",
+            "12 apples",
+            "1.K",
+            "0x",
+        ] {
+            assert_eq!(binary.parse_size(size), None, "{:?}", size);
+        }
+        assert_eq!(binary.parse_size("16E"), None);
+    }
+
+    #[test]
+    fn test_sizes() {
+        let options = UnitOptions::default();
+        assert_eq!(
+            options.sizes(
+                "Out of memory: Killed process 1234 (java) total-vm:8388608kB, anon-rss:4096kB, file-rss:0kB"
+            ),
+            vec![8_589_934_592, 4_194_304, 0]
+        );
+        assert_eq!(
+            options.sizes("Memory: 16270884K/16664116K available (14339K kernel code)"),
+            vec![16_661_385_216, 17_064_054_784, 14_683_136]
+        );
+        assert!(options
+            .sizes("usb 1-1: new high-speed USB device number 2")
+            .is_empty());
+    }
+
+    #[test]
+    fn test_parse_count() {
+        assert_eq!(parse_count("1953525168"), Some(1_953_525_168));
+        assert_eq!(parse_count(" 0x1f "), Some(31));
+        assert_eq!(parse_count("1,024"), None);
+        assert_eq!(parse_count("-1"), None);
+        assert_eq!(parse_count("0xg"), None);
+        assert_eq!(parse_count("0x"), None);
+        assert_eq!(parse_count("0x 1f"), None);
+        assert_eq!(parse_hex("00002000"), Some(0x2000));
+        assert_eq!(parse_hex("+1"), None);
+    }
+}
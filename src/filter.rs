@@ -0,0 +1,607 @@
+use crate::entry::{Entry, LogFacility, LogLevel};
+/// Filters are stages that pass entries through unmodified, or drop them.
+///
+use crate::error::RMesgError;
+use crate::group::EntryGroup;
+use crate::stage::{Stage, Staged};
+
+use aho_corasick::{AhoCorasick, Anchored, Input, MatchKind, StartKind};
+use regex::Regex;
+use std::time::Duration;
+
+/// A set of message prefixes (e.g. "nvme", "e1000e:", "EXT4-fs") to subscribe to.
+///
+/// All prefixes are matched simultaneously (using Aho-Corasick) so the cost of
+/// matching an entry doesn't grow with the number of prefixes registered.
+/// Leading whitespace in the message (such as that left behind after a klogctl
+/// timestamp) is ignored.
+pub struct PrefixSet {
+    prefixes: Vec<String>,
+    matcher: AhoCorasick,
+}
+
+impl PrefixSet {
+    pub fn new<I, P>(prefixes: I) -> Result<PrefixSet, RMesgError>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<str>,
+    {
+        let prefixes: Vec<String> = prefixes
+            .into_iter()
+            .map(|p| p.as_ref().to_owned())
+            .collect();
+
+        let matcher = match AhoCorasick::builder()
+            .start_kind(StartKind::Anchored)
+            .build(&prefixes)
+        {
+            Ok(m) => m,
+            Err(e) => {
+                return Err(RMesgError::FilterError(format!(
+                    "Unable to build matcher for prefixes {:?}: {}",
+                    prefixes, e
+                )))
+            }
+        };
+
+        Ok(PrefixSet { prefixes, matcher })
+    }
+
+    /// The prefix this entry's message starts with, if any.
+    pub fn matching_prefix(&self, entry: &Entry) -> Option<&str> {
+        let message = entry.message.trim_start();
+        self.matcher
+            .find(Input::new(message).anchored(Anchored::Yes))
+            .map(|m| self.prefixes[m.pattern().as_usize()].as_str())
+    }
+
+    pub fn matches(&self, entry: &Entry) -> bool {
+        self.matching_prefix(entry).is_some()
+    }
+}
+
+impl Stage for PrefixSet {
+    fn process(&mut self, entry: Entry) -> Option<Entry> {
+        match self.matches(&entry) {
+            true => Some(entry),
+            false => None,
+        }
+    }
+}
+
+/// What a filter does with the entries it matches.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterAction {
+    /// Pass matching entries on and drop everything else
+    Keep,
+    /// Drop matching entries and pass everything else on
+    Drop,
+}
+
+/// Matches many literal patterns anywhere in the message simultaneously.
+///
+/// For alert-rule sets with hundreds of patterns, this is far cheaper than
+/// a chain of regex filters, as the message is scanned once regardless of
+/// how many patterns there are.
+pub struct MultiPatternFilter {
+    patterns: Vec<String>,
+    matcher: AhoCorasick,
+    action: FilterAction,
+}
+
+impl MultiPatternFilter {
+    /// Create a new MultiPatternFilter
+    /// `patterns`: The literal patterns to look for in messages
+    /// `action`: Whether entries matching any pattern are kept or dropped
+    /// `case_insensitive`: When set, patterns match regardless of (ASCII) case
+    pub fn with_options<I, P>(
+        patterns: I,
+        action: FilterAction,
+        case_insensitive: bool,
+    ) -> Result<MultiPatternFilter, RMesgError>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<str>,
+    {
+        let patterns: Vec<String> = patterns
+            .into_iter()
+            .map(|p| p.as_ref().to_owned())
+            .collect();
+
+        let matcher = match AhoCorasick::builder()
+            .match_kind(MatchKind::Standard)
+            .ascii_case_insensitive(case_insensitive)
+            .build(&patterns)
+        {
+            Ok(m) => m,
+            Err(e) => {
+                return Err(RMesgError::FilterError(format!(
+                    "Unable to build matcher for {} patterns: {}",
+                    patterns.len(),
+                    e
+                )))
+            }
+        };
+
+        Ok(MultiPatternFilter {
+            patterns,
+            matcher,
+            action,
+        })
+    }
+
+    pub fn is_match(&self, entry: &Entry) -> bool {
+        self.matcher.is_match(&entry.message)
+    }
+
+    /// Every pattern found in this entry's message (each listed once, in pattern order),
+    /// e.g. to report which alert rules fired.
+    pub fn matching_patterns(&self, entry: &Entry) -> Vec<&str> {
+        let mut matched = vec![false; self.patterns.len()];
+        for m in self.matcher.find_overlapping_iter(&entry.message) {
+            matched[m.pattern().as_usize()] = true;
+        }
+
+        self.patterns
+            .iter()
+            .zip(matched)
+            .filter(|(_, m)| *m)
+            .map(|(p, _)| p.as_str())
+            .collect()
+    }
+}
+
+impl Stage for MultiPatternFilter {
+    fn process(&mut self, entry: Entry) -> Option<Entry> {
+        match (self.action, self.is_match(&entry)) {
+            (FilterAction::Keep, true) | (FilterAction::Drop, false) => Some(entry),
+            _ => None,
+        }
+    }
+}
+
+/// Keeps only 1-in-N entries of the chatty levels, Info and Debug (others via
+/// `with_rate`), so that forwarding costs stay bounded on noisy systems. Warnings and
+/// anything more severe (and entries without a level) are always kept.
+///
+/// Sampling is deterministic: the first of every N entries at a level is kept.
+pub struct SamplingFilter {
+    // indexed by LogLevel; 1 keeps everything, 0 drops everything
+    rates: [u32; 8],
+    seen: [u64; 8],
+    dropped: u64,
+}
+
+impl SamplingFilter {
+    /// Keep 1-in-`n` Info and Debug entries.
+    pub fn new(n: u32) -> SamplingFilter {
+        let mut rates = [1; 8];
+        rates[LogLevel::Info as usize] = n;
+        rates[LogLevel::Debug as usize] = n;
+        SamplingFilter {
+            rates,
+            seen: [0; 8],
+            dropped: 0,
+        }
+    }
+
+    /// Keep 1-in-`n` entries at `level` (0 drops all of them). Only levels less
+    /// severe than Warning can be sampled.
+    pub fn with_rate(mut self, level: LogLevel, n: u32) -> Result<SamplingFilter, RMesgError> {
+        if level <= LogLevel::Warning {
+            return Err(RMesgError::FilterError(format!(
+                "Entries at level {} or more severe are never sampled",
+                level
+            )));
+        }
+        self.rates[level as usize] = n;
+        Ok(self)
+    }
+
+    /// Number of entries dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+impl Stage for SamplingFilter {
+    fn process(&mut self, entry: Entry) -> Option<Entry> {
+        let level = match entry.level {
+            Some(level) => level as usize,
+            None => return Some(entry),
+        };
+
+        let seen = self.seen[level];
+        self.seen[level] += 1;
+
+        let keep = match self.rates[level] {
+            0 => false,
+            n => seen.is_multiple_of(u64::from(n)),
+        };
+
+        if keep {
+            Some(entry)
+        } else {
+            self.dropped += 1;
+            None
+        }
+    }
+}
+
+/// The common criteria for selecting entries, built up one at a time. Every criterion
+/// set narrows what's kept; entries missing what a criterion looks at (a level, a
+/// facility, a timestamp, a subsystem) don't match it.
+///
+/// A filter can be applied to a snapshot:
+///
+/// ```ignore
+/// let filter = Filter::new().min_level(LogLevel::Warning).subsystem_prefix("usb");
+/// let entries = filter.apply(rmesg::log_entries(Backend::Default, false)?);
+/// ```
+///
+/// or attached to any of the iterators or streams (see `attach`), where it drops
+/// entries as they're parsed, before anything downstream buffers them.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    min_level: Option<LogLevel>,
+    facilities: Option<Vec<LogFacility>>,
+    text: Option<String>,
+    regex: Option<Regex>,
+    subsystem_prefix: Option<String>,
+    since: Option<Duration>,
+    until: Option<Duration>,
+}
+
+impl Filter {
+    /// A filter that keeps everything, until criteria are added.
+    pub fn new() -> Filter {
+        Filter::default()
+    }
+
+    /// Only entries at this level or more severe.
+    pub fn min_level(mut self, level: LogLevel) -> Filter {
+        self.min_level = Some(level);
+        self
+    }
+
+    /// Only entries from one of these facilities.
+    pub fn facilities<I: IntoIterator<Item = LogFacility>>(mut self, facilities: I) -> Filter {
+        self.facilities = Some(facilities.into_iter().collect());
+        self
+    }
+
+    /// Only entries whose message contains `text` (case-sensitively).
+    pub fn contains(mut self, text: &str) -> Filter {
+        self.text = Some(text.to_owned());
+        self
+    }
+
+    /// Only entries whose message matches `regex` (anywhere, unless anchored).
+    pub fn regex(mut self, regex: &str) -> Result<Filter, RMesgError> {
+        match Regex::new(regex) {
+            Ok(re) => {
+                self.regex = Some(re);
+                Ok(self)
+            }
+            Err(e) => Err(RMesgError::FilterError(format!(
+                "Unable to compile filter {}: {}",
+                regex, e
+            ))),
+        }
+    }
+
+    /// Only entries from a subsystem whose name starts with `prefix` (see
+    /// `Entry::subsystem`), e.g. "usb", or "EXT4" for EXT4-fs.
+    pub fn subsystem_prefix(mut self, prefix: &str) -> Filter {
+        self.subsystem_prefix = Some(prefix.to_owned());
+        self
+    }
+
+    /// Only entries logged at or after this time since system start.
+    pub fn since(mut self, since: Duration) -> Filter {
+        self.since = Some(since);
+        self
+    }
+
+    /// Only entries logged at or before this time since system start.
+    pub fn until(mut self, until: Duration) -> Filter {
+        self.until = Some(until);
+        self
+    }
+
+    pub fn matches(&self, entry: &Entry) -> bool {
+        self.matches_level(entry.level) && self.matches_except_level(entry)
+    }
+
+    /// Whether a group of entries (an oops report, or a record and its continuation
+    /// lines) matches: the group's level (the most severe of its members') must pass
+    /// `min_level`, and some member must match the other criteria. So filtering on level
+    /// keeps or drops a report whole, however its lines are leveled.
+    pub fn matches_group(&self, group: &EntryGroup) -> bool {
+        self.matches_level(group.level())
+            && group.entries.iter().any(|e| self.matches_except_level(e))
+    }
+
+    fn matches_level(&self, level: Option<LogLevel>) -> bool {
+        self.min_level
+            .is_none_or(|min| level.is_some_and(|l| l <= min))
+    }
+
+    fn matches_except_level(&self, entry: &Entry) -> bool {
+        let ts = entry.timestamp_from_system_start;
+        self.facilities
+            .as_ref()
+            .is_none_or(|facilities| entry.facility.is_some_and(|f| facilities.contains(&f)))
+            && self
+                .since
+                .is_none_or(|since| ts.is_some_and(|ts| ts >= since))
+            && self
+                .until
+                .is_none_or(|until| ts.is_some_and(|ts| ts <= until))
+            && self
+                .text
+                .as_ref()
+                .is_none_or(|text| entry.message.contains(text.as_str()))
+            && self.subsystem_prefix.as_ref().is_none_or(|prefix| {
+                entry
+                    .subsystem()
+                    .is_some_and(|s| s.starts_with(prefix.as_str()))
+            })
+            && self
+                .regex
+                .as_ref()
+                .is_none_or(|re| re.is_match(&entry.message))
+    }
+
+    /// The entries of a snapshot that match, in place.
+    pub fn apply(&self, mut entries: Vec<Entry>) -> Vec<Entry> {
+        entries.retain(|entry| self.matches(entry));
+        entries
+    }
+
+    /// Wraps an iterator or stream of entries (such as `logs_iter`'s), passing on only
+    /// those that match.
+    pub fn attach<I>(self, entries: I) -> Staged<I, Filter> {
+        Staged::new(entries, self)
+    }
+}
+
+impl Stage for Filter {
+    fn process(&mut self, entry: Entry) -> Option<Entry> {
+        match self.matches(&entry) {
+            true => Some(entry),
+            false => None,
+        }
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entry::entry;
+    use crate::stage;
+
+    #[test]
+    fn test_prefix_set() {
+        let set = PrefixSet::new(["nvme", "e1000e:", "EXT4-fs"]).unwrap();
+
+        assert_eq!(
+            set.matching_prefix(&entry("nvme nvme0: I/O 12 QID 3 timeout")),
+            Some("nvme")
+        );
+        assert_eq!(
+            set.matching_prefix(&entry(" EXT4-fs (sda1): mounted filesystem")),
+            Some("EXT4-fs")
+        );
+        assert!(set.matches(&entry("e1000e: eth0 NIC Link is Up")));
+        // anywhere but the start doesn't count
+        assert!(!set.matches(&entry("usb 1-1: attached nvme adapter")));
+        assert!(!set.matches(&entry(
+            "e1000e 0000:00:19.0 eth0: no colon right after name"
+        )));
+    }
+
+    #[test]
+    fn test_prefix_set_stage() {
+        let mut set = PrefixSet::new(vec!["usb"]).unwrap();
+        let entries = vec![entry("usb 1-1: new device"), entry("ACPI: Added _OSI")];
+        let filtered = stage::apply(entries, &mut set);
+        assert_eq!(filtered, vec![entry("usb 1-1: new device")]);
+    }
+
+    #[test]
+    fn test_many_prefixes() {
+        let prefixes: Vec<String> = (0..1000).map(|n| format!("drv{}:", n)).collect();
+        let set = PrefixSet::new(&prefixes).unwrap();
+        assert_eq!(set.matching_prefix(&entry("drv999: hi")), Some("drv999:"));
+        assert!(!set.matches(&entry("drv1000: hi")));
+    }
+
+    #[test]
+    fn test_multi_pattern_filter() {
+        let filter = MultiPatternFilter::with_options(
+            ["I/O error", "timeout", "reset"],
+            FilterAction::Keep,
+            false,
+        )
+        .unwrap();
+
+        let e = entry("nvme nvme0: I/O 12 QID 3 timeout, reset controller");
+        assert!(filter.is_match(&e));
+        assert_eq!(filter.matching_patterns(&e), vec!["timeout", "reset"]);
+        assert!(!filter.is_match(&entry("nvme nvme0: I/O completed")));
+        assert!(filter.is_match(&entry("blk_update_request: I/O error, dev sda")));
+    }
+
+    #[test]
+    fn test_multi_pattern_filter_actions() {
+        let messages = vec![
+            entry("usb 1-1: device descriptor read/64, error -71"),
+            entry("ACPI: Added _OSI(Module Device)"),
+            entry("EXT4-fs error (device sda1): bad block"),
+        ];
+
+        let mut keep =
+            MultiPatternFilter::with_options(["ERROR"], FilterAction::Keep, true).unwrap();
+        assert_eq!(stage::apply(messages.clone(), &mut keep).len(), 2);
+
+        let mut drop =
+            MultiPatternFilter::with_options(["ERROR"], FilterAction::Drop, true).unwrap();
+        assert_eq!(
+            stage::apply(messages.clone(), &mut drop),
+            vec![entry("ACPI: Added _OSI(Module Device)")]
+        );
+
+        let mut case_sensitive =
+            MultiPatternFilter::with_options(["ERROR"], FilterAction::Keep, false).unwrap();
+        assert!(stage::apply(messages, &mut case_sensitive).is_empty());
+    }
+
+    #[test]
+    fn test_hundreds_of_patterns() {
+        let patterns: Vec<String> = (0..500).map(|n| format!("rule-{}-hit", n)).collect();
+        let filter =
+            MultiPatternFilter::with_options(&patterns, FilterAction::Keep, false).unwrap();
+        let e = entry("driver: rule-7-hit and rule-499-hit");
+        assert_eq!(
+            filter.matching_patterns(&e),
+            vec!["rule-7-hit", "rule-499-hit"]
+        );
+    }
+
+    fn leveled(level: LogLevel) -> Entry {
+        Entry {
+            level: Some(level),
+            ..entry("message")
+        }
+    }
+
+    #[test]
+    fn test_sampling_filter() {
+        let mut sampler = SamplingFilter::new(10);
+        let entries: Vec<Entry> = (0..100)
+            .flat_map(|_| {
+                vec![
+                    leveled(LogLevel::Debug),
+                    leveled(LogLevel::Info),
+                    leveled(LogLevel::Notice),
+                    leveled(LogLevel::Warning),
+                    entry("no level"),
+                ]
+            })
+            .collect();
+
+        let kept = stage::apply(entries, &mut sampler);
+        let count = |level| kept.iter().filter(|e| e.level == level).count();
+        assert_eq!(count(Some(LogLevel::Debug)), 10);
+        assert_eq!(count(Some(LogLevel::Info)), 10);
+        assert_eq!(count(Some(LogLevel::Notice)), 100);
+        assert_eq!(count(Some(LogLevel::Warning)), 100);
+        assert_eq!(count(None), 100);
+        assert_eq!(sampler.dropped(), 180);
+    }
+
+    #[test]
+    fn test_sampling_filter_rates() {
+        let mut sampler = SamplingFilter::new(1)
+            .with_rate(LogLevel::Notice, 2)
+            .unwrap()
+            .with_rate(LogLevel::Debug, 0)
+            .unwrap();
+        let entries = vec![
+            leveled(LogLevel::Notice),
+            leveled(LogLevel::Notice),
+            leveled(LogLevel::Notice),
+            leveled(LogLevel::Debug),
+            leveled(LogLevel::Info),
+        ];
+        let kept = stage::apply(entries, &mut sampler);
+        assert_eq!(
+            kept,
+            vec![
+                leveled(LogLevel::Notice),
+                leveled(LogLevel::Notice),
+                leveled(LogLevel::Info)
+            ]
+        );
+
+        assert!(SamplingFilter::new(5)
+            .with_rate(LogLevel::Warning, 5)
+            .is_err());
+        assert!(SamplingFilter::new(5)
+            .with_rate(LogLevel::Error, 5)
+            .is_err());
+    }
+
+    #[test]
+    fn test_filter() {
+        let usb = Entry {
+            facility: Some(LogFacility::Kern),
+            level: Some(LogLevel::Warning),
+            timestamp_from_system_start: Some(Duration::from_secs(5)),
+            ..entry("usb 1-1: device descriptor read/64, error -71")
+        };
+        let nvme = Entry {
+            facility: Some(LogFacility::Kern),
+            level: Some(LogLevel::Error),
+            timestamp_from_system_start: Some(Duration::from_secs(10)),
+            ..entry("nvme nvme0: I/O 12 QID 3 timeout, aborting")
+        };
+        let user = Entry {
+            facility: Some(LogFacility::User),
+            level: Some(LogLevel::Info),
+            timestamp_from_system_start: Some(Duration::from_secs(15)),
+            ..entry("myapp: started")
+        };
+        let entries = vec![usb.clone(), nvme.clone(), user.clone()];
+
+        assert_eq!(Filter::new().apply(entries.clone()).len(), 3);
+        assert_eq!(
+            Filter::new()
+                .min_level(LogLevel::Warning)
+                .apply(entries.clone()),
+            vec![usb.clone(), nvme.clone()]
+        );
+        assert_eq!(
+            Filter::new()
+                .facilities(vec![LogFacility::User])
+                .apply(entries.clone()),
+            vec![user.clone()]
+        );
+        assert_eq!(
+            Filter::new()
+                .since(Duration::from_secs(6))
+                .until(Duration::from_secs(15))
+                .contains("I/O")
+                .apply(entries.clone()),
+            vec![nvme.clone()]
+        );
+        assert_eq!(
+            Filter::new().subsystem_prefix("us").apply(entries.clone()),
+            vec![usb.clone()]
+        );
+        assert_eq!(
+            Filter::new()
+                .regex(r"error -[[:digit:]]+$")
+                .unwrap()
+                .apply(entries.clone()),
+            vec![usb]
+        );
+        // entries without what's filtered on don't match
+        assert!(Filter::new()
+            .min_level(LogLevel::Debug)
+            .apply(vec![entry("no level")])
+            .is_empty());
+        assert!(Filter::new().regex("(unclosed").is_err());
+
+        let attached: Vec<Entry> = Filter::new()
+            .min_level(LogLevel::Error)
+            .attach(entries.into_iter().map(Ok))
+            .map(|e| e.unwrap())
+            .collect();
+        assert_eq!(attached, vec![nvme]);
+    }
+}
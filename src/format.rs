@@ -0,0 +1,269 @@
+/// Options for the formatters on `Entry` (`to_klog_str_with`, `to_kmsg_str_with` and
+/// `display_with`): how precisely timestamps are printed, whether as wall-clock times,
+/// and how long messages may get.
+///
+/// Some downstream systems reject fields that are too long or too precise; these let
+/// output be fitted to them without post-processing. The defaults print what
+/// `to_klog_str`, `to_kmsg_str` and `Display` always have.
+///
+use crate::clock::WallClock;
+use crate::common;
+use crate::entry::Entry;
+
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::time::{Duration, SystemTime};
+
+/// How many decimals of a second timestamps are printed with. Timestamps are rounded to
+/// the nearest unit (as the default of six decimals always was).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimestampPrecision {
+    Seconds,
+    Millis,
+    #[default]
+    Micros,
+}
+
+impl TimestampPrecision {
+    pub fn decimals(&self) -> u32 {
+        match self {
+            TimestampPrecision::Seconds => 0,
+            TimestampPrecision::Millis => 3,
+            TimestampPrecision::Micros => 6,
+        }
+    }
+
+    /// A timestamp in seconds, with this many decimals (e.g. "24241.325").
+    pub fn format(&self, ts: Duration) -> String {
+        let decimals = self.decimals();
+        let unit = 10u128.pow(9 - decimals);
+        let units = (ts.as_nanos() + unit / 2) / unit;
+        match decimals {
+            0 => units.to_string(),
+            _ => {
+                let scale = 10u128.pow(decimals);
+                format!(
+                    "{}.{:0width$}",
+                    units / scale,
+                    units % scale,
+                    width = decimals as usize
+                )
+            }
+        }
+    }
+
+    /// A wall-clock time in RFC 3339 format, in UTC, with this many decimals (e.g.
+    /// "2021-01-01T00:00:00.000000Z"). Times before the epoch are printed as the epoch.
+    pub fn format_system_time(&self, time: SystemTime) -> String {
+        let since_epoch = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let seconds = self.format(since_epoch);
+        let (whole, decimals) = seconds.split_at(seconds.find('.').unwrap_or(seconds.len()));
+        let whole: u64 = whole.parse().unwrap_or_default();
+        let (year, month, day) = common::civil_from_days((whole / 86400) as i64);
+        let secs = whole % 86400;
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}Z",
+            year,
+            month,
+            day,
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60,
+            decimals
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FormatOptions {
+    /// The precision of timestamps in seconds (those of `to_klog_str_with` and
+    /// `display_with`; /dev/kmsg records always count whole microseconds).
+    pub precision: TimestampPrecision,
+
+    /// When set, `display_with` prints wall-clock times (see `Entry::realtime`) instead
+    /// of the time since system start, like `dmesg -T` though in RFC 3339 and UTC.
+    pub wall_clock: Option<WallClock>,
+
+    /// Messages longer than this many bytes are cut short, ending in `ellipsis` (which
+    /// counts towards the limit), at a character boundary. This applies to the message
+    /// alone, not to the rest of the line. When `None`, messages are printed whole.
+    pub max_message_len: Option<usize>,
+
+    /// What a truncated message ends in. When it doesn't fit in `max_message_len`,
+    /// messages are cut without it.
+    pub ellipsis: String,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            precision: TimestampPrecision::default(),
+            wall_clock: None,
+            max_message_len: None,
+            ellipsis: "...".to_owned(),
+        }
+    }
+}
+
+impl FormatOptions {
+    /// `message`, truncated to `max_message_len` as need be.
+    pub fn truncate<'a>(&self, message: &'a str) -> Cow<'a, str> {
+        let max = match self.max_message_len {
+            Some(max) if message.len() > max => max,
+            _ => return Cow::Borrowed(message),
+        };
+
+        let (cut, ellipsis) = match max.checked_sub(self.ellipsis.len()) {
+            Some(cut) => (cut, self.ellipsis.as_str()),
+            None => (max, ""),
+        };
+        let cut = (0..=cut)
+            .rev()
+            .find(|i| message.is_char_boundary(*i))
+            .unwrap_or(0);
+        Cow::Owned(format!("{}{}", &message[..cut], ellipsis))
+    }
+}
+
+/// An entry displayed with `FormatOptions` (see `Entry::display_with`).
+pub struct Formatted<'a> {
+    pub(crate) entry: &'a Entry,
+    pub(crate) options: &'a FormatOptions,
+}
+
+impl Display for Formatted<'_> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        #[cfg(feature = "bench")]
+        crate::counters::bump(crate::counters::Counter::EntriesFormatted);
+
+        let entry = self.entry;
+        let precision = self.options.precision;
+        match self.options.wall_clock {
+            Some(clock) => {
+                if let Some(time) = entry.realtime(&clock) {
+                    write!(f, "[{}] ", precision.format_system_time(time))?
+                }
+            }
+            None => {
+                if let Some(ts) = entry.timestamp_from_system_start {
+                    write!(f, "[{: >16}] ", precision.format(ts))?
+                }
+            }
+        }
+
+        write!(f, "{}", self.options.truncate(&entry.message))?;
+
+        if !entry.tags.is_empty() {
+            write!(f, " [")?;
+            for (i, (key, value)) in entry.tags.iter().enumerate() {
+                if i > 0 {
+                    write!(f, " ")?;
+                }
+                write!(f, "{}={}", key, value)?;
+            }
+            write!(f, "]")?;
+        }
+        Ok(())
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_precision() {
+        let ts = Duration::from_nanos(24_241_325_252_600);
+        assert_eq!(TimestampPrecision::Micros.format(ts), "24241.325253");
+        assert_eq!(TimestampPrecision::Millis.format(ts), "24241.325");
+        assert_eq!(TimestampPrecision::Seconds.format(ts), "24241");
+        assert_eq!(
+            TimestampPrecision::Millis.format(Duration::from_micros(1_999_600)),
+            "2.000"
+        );
+        assert_eq!(
+            TimestampPrecision::Micros.format(Duration::from_micros(3)),
+            "0.000003"
+        );
+    }
+
+    #[test]
+    fn test_format_system_time() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_micros(1_609_459_200_123_456);
+        assert_eq!(
+            TimestampPrecision::Micros.format_system_time(time),
+            "2021-01-01T00:00:00.123456Z"
+        );
+        assert_eq!(
+            TimestampPrecision::Seconds.format_system_time(time),
+            "2021-01-01T00:00:00Z"
+        );
+        let leap_day = SystemTime::UNIX_EPOCH + Duration::from_secs(1_709_251_199);
+        assert_eq!(
+            TimestampPrecision::Millis.format_system_time(leap_day),
+            "2024-02-29T23:59:59.000Z"
+        );
+        assert_eq!(
+            TimestampPrecision::Seconds.format_system_time(SystemTime::UNIX_EPOCH),
+            "1970-01-01T00:00:00Z"
+        );
+    }
+
+    #[test]
+    fn test_wall_clock() {
+        let mut entry = Entry {
+            timestamp_from_system_start: Some(Duration::from_secs(2)),
+            message: "usb 1-1: new high-speed USB device".to_owned(),
+            ..Default::default()
+        };
+        let options = FormatOptions {
+            precision: TimestampPrecision::Seconds,
+            wall_clock: Some(WallClock::with_boot_time(
+                SystemTime::UNIX_EPOCH + Duration::from_secs(1_609_459_198),
+            )),
+            ..Default::default()
+        };
+        assert_eq!(
+            entry.display_with(&options).to_string(),
+            "[2021-01-01T00:00:00Z] usb 1-1: new high-speed USB device"
+        );
+
+        // a time the source recorded wins over the conversion
+        entry.timestamp_realtime = Some(SystemTime::UNIX_EPOCH);
+        assert_eq!(
+            entry.display_with(&options).to_string(),
+            "[1970-01-01T00:00:00Z] usb 1-1: new high-speed USB device"
+        );
+    }
+
+    #[test]
+    fn test_truncate() {
+        let options = FormatOptions {
+            max_message_len: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(options.truncate("short"), "short");
+        assert_eq!(options.truncate("exactly 10"), "exactly 10");
+        assert_eq!(options.truncate("nvme nvme0: I/O timeout"), "nvme nv...");
+        // not in the middle of a character
+        assert_eq!(options.truncate("ééééééé"), "ééé...");
+
+        let tiny = FormatOptions {
+            max_message_len: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(tiny.truncate("nvme"), "nv");
+
+        let unicode = FormatOptions {
+            max_message_len: Some(8),
+            ellipsis: "…".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(unicode.truncate("usb 1-1: reset"), "usb 1…");
+    }
+}
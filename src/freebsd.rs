@@ -0,0 +1,246 @@
+use crate::entry::{Entry, EntryParsingError, LogFacility};
+/// FreeBSD implementation: reads the kernel message buffer through
+/// `sysctlbyname("kern.msgbuf")`, as FreeBSD's dmesg(8) does.
+///
+/// The buffer holds lines in the console format (`<pri>message`, with a `[seconds]`
+/// timestamp after the priority when `kern.msgbuf_show_timestamp` is set), so they're
+/// parsed like klogctl's. It also holds messages from userland logged through the
+/// console; like `dmesg` without `-a`, only kernel ones (facility kern) are kept.
+///
+/// On other platforms this module still builds, but reading fails with
+/// `RMesgError::NotImplementedForThisPlatform`, so downstream code needs no
+/// conditionals of its own.
+///
+use crate::error::RMesgError;
+use crate::parse;
+use crate::provenance::{self, SourceBackend};
+use crate::source::{self, KLogSource};
+use std::convert::TryFrom;
+
+pub const MSGBUF_SYSCTL: &str = "kern.msgbuf";
+pub const MSGBUF_CLEAR_SYSCTL: &str = "kern.msgbuf_clear";
+pub const MSGBUF_SIZE_SYSCTL: &str = "kern.msgbufsize";
+
+#[cfg(target_os = "freebsd")]
+fn sysctl_bytes(name: &str) -> Result<Vec<u8>, RMesgError> {
+    use std::ffi::CString;
+    use std::ptr;
+
+    let cname = CString::new(name)
+        .map_err(|e| RMesgError::InternalError(format!("Invalid sysctl name {}: {}", name, e)))?;
+
+    let mut len: libc::size_t = 0;
+    if unsafe { libc::sysctlbyname(cname.as_ptr(), ptr::null_mut(), &mut len, ptr::null(), 0) } != 0
+    {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    // leave room for messages logged between the two calls
+    let mut buf: Vec<u8> = vec![0; len + len / 8 + 1];
+    let mut len = buf.len();
+    if unsafe {
+        libc::sysctlbyname(
+            cname.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            &mut len,
+            ptr::null(),
+            0,
+        )
+    } != 0
+    {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    buf.truncate(len);
+    Ok(buf)
+}
+
+#[cfg(not(target_os = "freebsd"))]
+fn sysctl_bytes(_name: &str) -> Result<Vec<u8>, RMesgError> {
+    Err(RMesgError::NotImplementedForThisPlatform)
+}
+
+#[cfg(target_os = "freebsd")]
+fn sysctl_int(name: &str) -> Result<libc::c_int, RMesgError> {
+    use std::ffi::CString;
+    use std::ptr;
+
+    let cname = CString::new(name)
+        .map_err(|e| RMesgError::InternalError(format!("Invalid sysctl name {}: {}", name, e)))?;
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>();
+    if unsafe {
+        libc::sysctlbyname(
+            cname.as_ptr(),
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+            ptr::null(),
+            0,
+        )
+    } != 0
+    {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(value)
+}
+
+#[cfg(not(target_os = "freebsd"))]
+fn sysctl_int(_name: &str) -> Result<i32, RMesgError> {
+    Err(RMesgError::NotImplementedForThisPlatform)
+}
+
+#[cfg(target_os = "freebsd")]
+fn sysctl_set_int(name: &str, value: libc::c_int) -> Result<(), RMesgError> {
+    use std::ffi::CString;
+    use std::ptr;
+
+    let cname = CString::new(name)
+        .map_err(|e| RMesgError::InternalError(format!("Invalid sysctl name {}: {}", name, e)))?;
+    if unsafe {
+        libc::sysctlbyname(
+            cname.as_ptr(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>(),
+        )
+    } != 0
+    {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "freebsd"))]
+fn sysctl_set_int(_name: &str, _value: i32) -> Result<(), RMesgError> {
+    Err(RMesgError::NotImplementedForThisPlatform)
+}
+
+/// The message buffer as the kernel holds it, clearing it afterwards if `clear` is set
+/// (which needs root). Messages logged between reading and clearing are lost.
+pub fn msgbuf_raw(clear: bool) -> Result<String, RMesgError> {
+    let mut buf = sysctl_bytes(MSGBUF_SYSCTL)?;
+    // the sysctl's string is NUL-terminated
+    while buf.last() == Some(&0) {
+        buf.pop();
+    }
+    let raw = String::from_utf8(buf)?;
+    if clear {
+        msgbuf_clear()?;
+    }
+    Ok(raw)
+}
+
+/// The kernel's entries in the message buffer (see `msgbuf_raw`).
+pub fn msgbuf(clear: bool) -> Result<Vec<Entry>, RMesgError> {
+    Ok(entries_from_msgbuf(&msgbuf_raw(clear)?)?)
+}
+
+/// Clears the message buffer (through `kern.msgbuf_clear`, which needs root).
+pub fn msgbuf_clear() -> Result<(), RMesgError> {
+    sysctl_set_int(MSGBUF_CLEAR_SYSCTL, 1)
+}
+
+/// Counts the kernel's entries in the message buffer.
+pub fn msgbuf_count() -> Result<usize, RMesgError> {
+    Ok(msgbuf(false)?.len())
+}
+
+/// The kernel's entries in the contents of the message buffer. Lines without a
+/// priority are taken to be the kernel's (FreeBSD only prefixes some of them).
+pub fn entries_from_msgbuf(raw: &str) -> Result<Vec<Entry>, EntryParsingError> {
+    let mut entries = Vec::new();
+    for line in raw.lines().filter(|line| !line.is_empty()) {
+        let entry = parse::console_entry_from_line(line)?;
+        if entry.facility.is_none_or(|f| f == LogFacility::Kern) {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// The message buffer as a `KLogSource`, for following it with `source::SourceEntries`.
+/// The buffer has no sequence numbers (and only has timestamps when
+/// `kern.msgbuf_show_timestamp` is set), so new entries are told apart by how a snapshot
+/// overlaps the one before (see `source::snapshot_overlap`).
+#[derive(Debug, Default)]
+pub struct MsgBufSource {
+    previous: Vec<Entry>,
+}
+
+impl MsgBufSource {
+    fn read(&mut self, only_new: bool) -> Result<Vec<Entry>, RMesgError> {
+        let entries = msgbuf(false)?;
+        let start = match only_new {
+            true => source::snapshot_overlap(&self.previous, &entries),
+            false => 0,
+        };
+        self.previous = entries.clone();
+        Ok(entries
+            .into_iter()
+            .skip(start)
+            .map(|e| provenance::tag(e, SourceBackend::MsgBuf))
+            .collect())
+    }
+}
+
+impl KLogSource for MsgBufSource {
+    fn read_all(&mut self) -> Result<Vec<Entry>, RMesgError> {
+        self.read(false)
+    }
+
+    fn poll_new(&mut self) -> Result<Vec<Entry>, RMesgError> {
+        self.read(true)
+    }
+
+    fn clear(&mut self) -> Result<(), RMesgError> {
+        msgbuf_clear()
+    }
+
+    fn capacity(&self) -> Result<usize, RMesgError> {
+        let size = sysctl_int(MSGBUF_SIZE_SYSCTL)?;
+        usize::try_from(size).map_err(|_| {
+            RMesgError::InternalError(format!("Invalid {}: {}", MSGBUF_SIZE_SYSCTL, size))
+        })
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entry::LogLevel;
+    use std::time::Duration;
+
+    #[test]
+    fn test_entries_from_msgbuf() {
+        let raw = "<6>em0: link state changed to UP\n\
+                   <13>user: not the kernel's\n\
+                   <4>[12.345678] WARNING: attempt to domain_add\n\
+                   \n\
+                   Copyright (c) 1992-2023 The FreeBSD Project.\n";
+        let entries = entries_from_msgbuf(raw).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].level, Some(LogLevel::Info));
+        assert_eq!(entries[0].message, "em0: link state changed to UP");
+        assert_eq!(
+            entries[1].timestamp_from_system_start,
+            Some(Duration::from_micros(12_345_678))
+        );
+        assert_eq!(entries[2].facility, None);
+    }
+
+    #[cfg(not(target_os = "freebsd"))]
+    #[test]
+    fn test_unsupported_platform() {
+        assert!(matches!(
+            msgbuf(false),
+            Err(RMesgError::NotImplementedForThisPlatform)
+        ));
+        assert!(matches!(
+            MsgBufSource::default().capacity(),
+            Err(RMesgError::NotImplementedForThisPlatform)
+        ));
+    }
+}
@@ -0,0 +1,90 @@
+use crate::entry::{Entry, EntryParsingError};
+/// Entry points for fuzzing the parsers with arbitrary bytes (see `fuzz/` for the
+/// cargo-fuzz targets).
+///
+/// The parsers see whatever the kernel, a pstore file or a capture hands them, so they
+/// are the part of the crate most exposed to hostile input. These take raw bytes, as a
+/// backend reads them, and are deterministic and allocation-bounded: input longer than
+/// `MAX_RECORD_LEN` is rejected before any parsing, and nothing built from an accepted
+/// input is larger than the input. Run them with `cargo +nightly fuzz run parse_kmsg_record`
+/// (or `parse_klog_line`) from the repository root.
+///
+use crate::parse;
+
+/// The longest record accepted, in bytes. /dev/kmsg records are at most 8KiB
+/// (`CONSOLE_EXT_LOG_MAX` in the kernel), and console lines are shorter still.
+pub const MAX_RECORD_LEN: usize = 8192;
+
+/// Parses one record as read from /dev/kmsg: a header line, optionally followed by
+/// the record's dictionary (continuation lines starting with a space), which is
+/// ignored.
+pub fn parse_kmsg_record(data: &[u8]) -> Result<Entry, EntryParsingError> {
+    let record = checked_str(data)?;
+    let header = record.split('\n').next().unwrap_or_default();
+    parse::kmsg_entry_from_line(header)
+}
+
+/// Parses one line in the console format (as klogctl and pstore produce), with or
+/// without its trailing newline.
+pub fn parse_klog_line(data: &[u8]) -> Result<Entry, EntryParsingError> {
+    let line = checked_str(data)?;
+    let line = line.strip_suffix('\n').unwrap_or(line);
+    if line.contains('\n') {
+        return Err(EntryParsingError::Generic(
+            "Console line contains more than one line".to_owned(),
+        ));
+    }
+    parse::console_entry_from_line(line)
+}
+
+fn checked_str(data: &[u8]) -> Result<&str, EntryParsingError> {
+    if data.len() > MAX_RECORD_LEN {
+        return Err(EntryParsingError::Generic(format!(
+            "Record of {} bytes is longer than the {} allowed",
+            data.len(),
+            MAX_RECORD_LEN
+        )));
+    }
+    std::str::from_utf8(data)
+        .map_err(|e| EntryParsingError::Generic(format!("Record is not valid UTF-8: {}", e)))
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_kmsg_record() {
+        let entry =
+            parse_kmsg_record(b"6,1,1500,-;usb 1-1: new device\n SUBSYSTEM=usb\n DEVICE=c189:1\n")
+                .unwrap();
+        assert_eq!(entry.sequence_num, Some(1));
+        assert_eq!(entry.message, "usb 1-1: new device");
+
+        assert!(parse_kmsg_record(&[b'6'; MAX_RECORD_LEN + 1]).is_err());
+        assert!(parse_kmsg_record(b"6,1,1500,-;\xff").is_err());
+        assert!(parse_kmsg_record(b"").is_ok());
+    }
+
+    #[test]
+    fn test_parse_klog_line() {
+        let entry = parse_klog_line(b"<6>[    1.500000] usb 1-1: new device\n").unwrap();
+        assert_eq!(entry.message, " usb 1-1: new device");
+        assert!(parse_klog_line(b"<6>one\n<6>two").is_err());
+        assert!(parse_klog_line(b"<99999999999>[").is_err());
+        // timestamps no Duration can hold
+        assert!(parse_klog_line(b"<6>[99999999999999999999.0] x").is_err());
+    }
+
+    #[test]
+    fn test_bounded() {
+        // escapes never unescape to more than they took up
+        let data = b"6,1,0,-;\\xff\\xfe\\xc3".to_vec();
+        let entry = parse_kmsg_record(&data).unwrap();
+        assert!(entry.message.len() <= data.len());
+        assert_eq!(parse_kmsg_record(&data).unwrap(), entry);
+    }
+}
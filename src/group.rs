@@ -0,0 +1,344 @@
+use crate::entry::{Entry, LogLevel};
+/// Grouping of multi-line events into one.
+///
+/// The kernel logs some events over many records: an oops or a WARN() is a header line
+/// ("BUG: unable to handle page fault", "WARNING: CPU: 3 PID: 1 at ..."), the registers,
+/// a call trace and an end marker, each line at its own level (the trace is often logged
+/// at a lower level than the header). Messages with embedded newlines also come through
+/// klogctl as continuation lines, without a level or timestamp of their own.
+///
+/// An `EntryGroup` holds the records of one such event. Its `level` is the most severe of
+/// its members', so that filtering a group on level behaves like filtering the event
+/// (a call trace logged at warning still belongs to an alert), while each line keeps its
+/// own. See `Filter::matches_group`.
+///
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::time::Duration;
+
+lazy_static! {
+    // The first line of an oops, a WARN(), a lockup or a hung task report
+    static ref RE_REPORT_START: Regex = Regex::new(
+        r"^(?:-+\[ cut here \]-+|BUG: |Oops: |Oops\[|WARNING: CPU: |WARNING: at |general protection fault|Kernel panic - |INFO: task [^[:space:]]+ blocked|INFO: rcu_(?:sched|preempt) (?:self-)?detected stall|watchdog: BUG: |NMI watchdog: |Unable to handle kernel )"
+    )
+    .unwrap();
+    // ------------[ cut here ]------------ comes just before a WARN()'s or a BUG()'s header
+    static ref RE_CUT_HERE: Regex = Regex::new(r"^-+\[ cut here \]-+").unwrap();
+    // ---[ end trace 0000000000000000 ]---    ---[ end Kernel panic - not syncing: ... ]---
+    static ref RE_REPORT_END: Regex = Regex::new(r"^-+\[ end ").unwrap();
+}
+
+/// The records of one event, in the order they were logged.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntryGroup {
+    pub entries: Vec<Entry>,
+}
+
+impl EntryGroup {
+    /// The group's effective level: the most severe of its members' (or `None` when none
+    /// of them has one).
+    pub fn level(&self) -> Option<LogLevel> {
+        self.entries.iter().filter_map(|e| e.level).min()
+    }
+
+    /// The first record (the report's header, or the record continuation lines belong to).
+    pub fn first(&self) -> &Entry {
+        &self.entries[0]
+    }
+
+    /// Whether this is a multi-line report (rather than a record and its continuations).
+    pub fn is_report(&self) -> bool {
+        is_report_start(self.first())
+    }
+
+    /// The members' messages, one per line.
+    pub fn message(&self) -> String {
+        self.entries
+            .iter()
+            .map(|e| e.message.trim_start())
+            .collect::<Vec<&str>>()
+            .join("\n")
+    }
+
+    /// The group as a single entry: the first record's, with the group's level and the
+    /// members' messages, one per line. Each member's own level is lost.
+    pub fn to_entry(&self) -> Entry {
+        Entry {
+            level: self.level(),
+            message: self.message(),
+            ..self.first().clone()
+        }
+    }
+}
+
+fn is_report_start(entry: &Entry) -> bool {
+    RE_REPORT_START.is_match(entry.message.trim_start())
+}
+
+// A line without a level or a timestamp of its own continues the record before it
+fn is_continuation(entry: &Entry) -> bool {
+    entry.level.is_none() && entry.timestamp_from_system_start.is_none()
+}
+
+#[derive(Clone, Debug)]
+pub struct GroupOptions {
+    /// A report ends after this many lines, if its end marker hasn't come by then
+    pub max_lines: usize,
+    /// A report also ends at a gap this long between two of its records (it's logged
+    /// all at once, so a gap means the end marker was lost)
+    pub max_gap: Duration,
+}
+
+impl Default for GroupOptions {
+    fn default() -> Self {
+        Self {
+            max_lines: 256,
+            max_gap: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Groups entries as they come, for following a log: `push` each entry, and `finish`
+/// at the end for the last group.
+#[derive(Clone, Debug, Default)]
+pub struct Grouper {
+    options: GroupOptions,
+    current: Option<EntryGroup>,
+}
+
+impl Grouper {
+    pub fn with_options(options: GroupOptions) -> Grouper {
+        Grouper {
+            options,
+            current: None,
+        }
+    }
+
+    /// Adds an entry, returning the group it completes, if any.
+    pub fn push(&mut self, entry: Entry) -> Option<EntryGroup> {
+        if let Some(current) = self.current.as_mut() {
+            if belongs(&self.options, current, &entry) {
+                let ends =
+                    current.is_report() && RE_REPORT_END.is_match(entry.message.trim_start());
+                current.entries.push(entry);
+                return match ends {
+                    true => self.current.take(),
+                    false => None,
+                };
+            }
+        }
+        self.current.replace(EntryGroup {
+            entries: vec![entry],
+        })
+    }
+
+    /// The group still open, if any.
+    pub fn finish(&mut self) -> Option<EntryGroup> {
+        self.current.take()
+    }
+}
+
+// The report so far is just its cut here line, so the header that follows is its own
+fn awaits_header(current: &EntryGroup) -> bool {
+    current.entries.len() == 1 && RE_CUT_HERE.is_match(current.first().message.trim_start())
+}
+
+// Whether `entry` continues `current`: it's a continuation line, or the next line of a
+// report (until the report's limits)
+fn belongs(options: &GroupOptions, current: &EntryGroup, entry: &Entry) -> bool {
+    if is_continuation(entry) {
+        return true;
+    }
+    if !current.is_report() {
+        return false;
+    }
+    if is_report_start(entry) {
+        return awaits_header(current) && !RE_CUT_HERE.is_match(entry.message.trim_start());
+    }
+    let last = current
+        .entries
+        .iter()
+        .rev()
+        .find_map(|e| e.timestamp_from_system_start);
+    current.entries.len() < options.max_lines
+        && match (last, entry.timestamp_from_system_start) {
+            (Some(last), Some(ts)) => ts.saturating_sub(last) <= options.max_gap,
+            _ => true,
+        }
+}
+
+/// Groups a snapshot's entries (such as the ones `log_entries` returns).
+pub fn group_entries(entries: Vec<Entry>, options: GroupOptions) -> Vec<EntryGroup> {
+    let mut grouper = Grouper::with_options(options);
+    let mut groups: Vec<EntryGroup> = entries
+        .into_iter()
+        .filter_map(|entry| grouper.push(entry))
+        .collect();
+    groups.extend(grouper.finish());
+    groups
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entry::LogFacility;
+    use crate::filter::Filter;
+
+    fn entry(ms: u64, level: Option<LogLevel>, message: &str) -> Entry {
+        Entry {
+            facility: level.map(|_| LogFacility::Kern),
+            level,
+            timestamp_from_system_start: level.map(|_| Duration::from_millis(ms)),
+            message: message.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn oops() -> Vec<Entry> {
+        vec![
+            entry(
+                1000,
+                Some(LogLevel::Info),
+                "usb 1-1: new high-speed USB device",
+            ),
+            entry(
+                2000,
+                Some(LogLevel::Alert),
+                "BUG: unable to handle page fault for address: ffffffffc0a01000",
+            ),
+            entry(
+                2000,
+                Some(LogLevel::Alert),
+                "#PF: supervisor read access in kernel mode",
+            ),
+            entry(2001, Some(LogLevel::Warning), "Call Trace:"),
+            entry(2001, Some(LogLevel::Warning), " <TASK>"),
+            entry(2001, Some(LogLevel::Warning), " do_syscall_64+0x5b/0x80"),
+            entry(
+                2002,
+                Some(LogLevel::Warning),
+                "---[ end trace 0000000000000000 ]---",
+            ),
+            entry(3000, Some(LogLevel::Info), "e1000e: eth0 NIC Link is Up"),
+        ]
+    }
+
+    #[test]
+    fn test_report_group() {
+        let groups = group_entries(oops(), GroupOptions::default());
+        assert_eq!(groups.len(), 3);
+        assert!(!groups[0].is_report());
+
+        let report = &groups[1];
+        assert!(report.is_report());
+        assert_eq!(report.entries.len(), 6);
+        assert_eq!(report.level(), Some(LogLevel::Alert));
+        assert_eq!(report.entries[2].level, Some(LogLevel::Warning));
+        assert!(report
+            .message()
+            .ends_with("do_syscall_64+0x5b/0x80\n---[ end trace 0000000000000000 ]---"));
+
+        let merged = report.to_entry();
+        assert_eq!(merged.level, Some(LogLevel::Alert));
+        assert_eq!(
+            merged.timestamp_from_system_start,
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(groups[2].entries.len(), 1);
+
+        // a WARN()'s header comes after its cut here line
+        let groups = group_entries(
+            vec![
+                entry(
+                    1000,
+                    Some(LogLevel::Warning),
+                    "------------[ cut here ]------------",
+                ),
+                entry(
+                    1000,
+                    Some(LogLevel::Warning),
+                    "WARNING: CPU: 3 PID: 1 at fs/x.c:10 x+0x10/0x20",
+                ),
+                entry(1000, Some(LogLevel::Warning), "Modules linked in: nvme"),
+                entry(
+                    1001,
+                    Some(LogLevel::Warning),
+                    "---[ end trace 0000000000000000 ]---",
+                ),
+                entry(
+                    2000,
+                    Some(LogLevel::Warning),
+                    "------------[ cut here ]------------",
+                ),
+                entry(
+                    2000,
+                    Some(LogLevel::Warning),
+                    "------------[ cut here ]------------",
+                ),
+            ],
+            GroupOptions::default(),
+        );
+        let sizes: Vec<usize> = groups.iter().map(|g| g.entries.len()).collect();
+        assert_eq!(sizes, vec![4, 1, 1]);
+        assert!(groups[0].is_report());
+    }
+
+    #[test]
+    fn test_continuations_and_limits() {
+        let groups = group_entries(
+            vec![
+                entry(
+                    0,
+                    Some(LogLevel::Info),
+                    "Command line: BOOT_IMAGE=/boot/kernel",
+                ),
+                entry(0, None, " LINE2=foobar"),
+                entry(0, None, " LINE 3 = foobar ; with semicolon"),
+                // a report whose end marker never came
+                entry(
+                    1000,
+                    Some(LogLevel::Warning),
+                    "WARNING: CPU: 3 PID: 1 at fs/x.c:10",
+                ),
+                entry(1000, Some(LogLevel::Warning), "Modules linked in: nvme"),
+                entry(5000, Some(LogLevel::Info), "eth0: link up"),
+            ],
+            GroupOptions::default(),
+        );
+        let sizes: Vec<usize> = groups.iter().map(|g| g.entries.len()).collect();
+        assert_eq!(sizes, vec![3, 2, 1]);
+        assert_eq!(groups[0].level(), Some(LogLevel::Info));
+
+        let groups = group_entries(
+            oops(),
+            GroupOptions {
+                max_lines: 2,
+                ..Default::default()
+            },
+        );
+        assert_eq!(groups[1].entries.len(), 2);
+    }
+
+    #[test]
+    fn test_filter_groups() {
+        let groups = group_entries(oops(), GroupOptions::default());
+        let errors = Filter::new().min_level(LogLevel::Error);
+        // the whole report, trace and all, though only its first lines are errors
+        let kept: Vec<&EntryGroup> = groups.iter().filter(|g| errors.matches_group(g)).collect();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].entries.len(), 6);
+
+        // other criteria match if any line does
+        assert!(Filter::new()
+            .min_level(LogLevel::Error)
+            .contains("do_syscall_64")
+            .matches_group(&groups[1]));
+        assert!(!Filter::new()
+            .min_level(LogLevel::Error)
+            .contains("NIC Link")
+            .matches_group(&groups[2]));
+    }
+}
@@ -0,0 +1,328 @@
+/// gRPC service (generated with tonic from proto/rmesg.proto), so remote management
+/// planes can pull kernel logs from agents with strong typing.
+///
+/// Agents serve it with tonic's transport, e.g.:
+///
+/// ```ignore
+/// tonic::transport::Server::builder()
+///     .add_service(KernelLogService::with_options(Backend::Default, false).into_server())
+///     .serve("0.0.0.0:50051".parse()?)
+///     .await?;
+/// ```
+///
+/// Management planes use the generated `proto::kernel_log_client::KernelLogClient`
+/// (`KernelLogClient::new(channel)`, with a channel from tonic's transport), or
+/// `aggregate` to follow several agents at once.
+///
+use crate::clock::WallClock;
+use crate::entry::{Entry, LogLevel};
+use crate::error::RMesgError;
+use crate::provenance::Origin;
+use crate::Backend;
+
+use futures::stream::{Stream, StreamExt};
+use lazy_static::lazy_static;
+use num::FromPrimitive;
+use regex::Regex;
+use std::fs;
+use std::pin::Pin;
+use std::time::UNIX_EPOCH;
+use tonic::{Request, Response, Status};
+
+/// Where the hostname (sent with every entry) is read from
+const PROC_SYS_KERNEL_HOSTNAME: &str = "/proc/sys/kernel/hostname";
+
+lazy_static! {
+    static ref HOSTNAME: Option<String> = fs::read_to_string(PROC_SYS_KERNEL_HOSTNAME)
+        .ok()
+        .map(|hostname| hostname.trim().to_owned());
+    // for entries that don't have a wall-clock time of their own
+    static ref WALL_CLOCK: Option<WallClock> = wall_clock();
+}
+
+/// Messages and client/server stubs generated from proto/rmesg.proto
+pub mod proto {
+    tonic::include_proto!("rmesg.v1");
+}
+
+use proto::kernel_log_server::{KernelLog, KernelLogServer};
+
+/// Serves the `KernelLog` service from a backend.
+#[derive(Clone, Debug)]
+pub struct KernelLogService {
+    backend: Backend,
+    allow_clear: bool,
+}
+
+impl KernelLogService {
+    /// Serves entries from `backend`. Unless `allow_clear` is set, `Clear` is refused.
+    pub fn with_options(backend: Backend, allow_clear: bool) -> KernelLogService {
+        KernelLogService {
+            backend,
+            allow_clear,
+        }
+    }
+
+    pub fn into_server(self) -> KernelLogServer<KernelLogService> {
+        KernelLogServer::new(self)
+    }
+}
+
+#[tonic::async_trait]
+impl KernelLog for KernelLogService {
+    async fn snapshot(
+        &self,
+        request: Request<proto::SnapshotRequest>,
+    ) -> Result<Response<proto::SnapshotResponse>, Status> {
+        let filter =
+            RequestFilter::from_proto(request.into_inner().filter).map_err(status_from_error)?;
+        let backend = self.backend;
+        let entries = tokio::task::spawn_blocking(move || crate::log_entries(backend, false))
+            .await
+            .map_err(|e| Status::internal(format!("Snapshot task failed: {}", e)))?
+            .map_err(status_from_error)?;
+
+        Ok(Response::new(proto::SnapshotResponse {
+            entries: entries
+                .iter()
+                .filter(|e| filter.matches(e))
+                .map(proto::Entry::from)
+                .collect(),
+        }))
+    }
+
+    type FollowStream = Pin<Box<dyn Stream<Item = Result<proto::Entry, Status>> + Send>>;
+
+    async fn follow(
+        &self,
+        request: Request<proto::FollowRequest>,
+    ) -> Result<Response<Self::FollowStream>, Status> {
+        let filter =
+            RequestFilter::from_proto(request.into_inner().filter).map_err(status_from_error)?;
+        let entries = crate::logs_stream(self.backend, false, false)
+            .await
+            .map_err(status_from_error)?;
+
+        let stream = entries.filter_map(move |entry| {
+            futures::future::ready(match entry {
+                Ok(entry) if filter.matches(&entry) => Some(Ok(proto::Entry::from(&entry))),
+                Ok(_) => None,
+                Err(e) => Some(Err(status_from_error(e))),
+            })
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn clear(
+        &self,
+        _request: Request<proto::ClearRequest>,
+    ) -> Result<Response<proto::ClearResponse>, Status> {
+        if !self.allow_clear {
+            return Err(Status::permission_denied(
+                "This agent does not allow clearing the kernel log",
+            ));
+        }
+        clear_buffer().await.map(Response::new)
+    }
+}
+
+#[cfg(feature = "klogctl")]
+async fn clear_buffer() -> Result<proto::ClearResponse, Status> {
+    let cleared = tokio::task::spawn_blocking(crate::clearlock::clear_locked)
+        .await
+        .map_err(|e| Status::internal(format!("Clear task failed: {}", e)))?
+        .map_err(status_from_error)?;
+    Ok(proto::ClearResponse {
+        bytes: cleared.bytes as u64,
+        entries: cleared.entries as u64,
+        unread_bytes: cleared.unread_bytes as u64,
+    })
+}
+
+#[cfg(not(feature = "klogctl"))]
+async fn clear_buffer() -> Result<proto::ClearResponse, Status> {
+    Err(Status::unimplemented(
+        "Clearing requires the klogctl backend",
+    ))
+}
+
+#[cfg(unix)]
+fn wall_clock() -> Option<WallClock> {
+    WallClock::now().ok()
+}
+
+#[cfg(not(unix))]
+fn wall_clock() -> Option<WallClock> {
+    None
+}
+
+impl From<&Entry> for proto::Entry {
+    /// Entries passed on from another agent keep that agent's host.
+    fn from(entry: &Entry) -> proto::Entry {
+        let provenance = entry.provenance.as_deref();
+        let realtime = match *WALL_CLOCK {
+            Some(clock) => entry.realtime(&clock),
+            None => entry.timestamp_realtime,
+        };
+        proto::Entry {
+            timestamp_us: entry
+                .timestamp_from_system_start
+                .map(|ts| ts.as_micros() as u64),
+            level: entry.level.map(|l| l as i32),
+            facility: entry.facility.map(|f| f as u32),
+            sequence_num: entry.sequence_num.map(|s| s as u64),
+            subsystem: entry.subsystem().map(|s| s.to_owned()),
+            message: entry.message.clone(),
+            host: match provenance.map(|p| &p.origin) {
+                Some(Origin::Remote(host)) => Some(host.clone()),
+                _ => HOSTNAME.clone(),
+            },
+            boot_id: provenance.and_then(|p| p.boot_id.clone()),
+            realtime_us: realtime
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|t| t.as_micros() as i64),
+            tags: entry.tags.clone(),
+        }
+    }
+}
+
+// The entries a request's `Filter` selects
+struct RequestFilter {
+    min_level: Option<LogLevel>,
+    pattern: Option<Regex>,
+}
+
+impl RequestFilter {
+    fn from_proto(filter: Option<proto::Filter>) -> Result<RequestFilter, RMesgError> {
+        let filter = filter.unwrap_or_default();
+        let min_level = match filter.min_level {
+            Some(level) => match LogLevel::from_i32(level) {
+                Some(level) => Some(level),
+                None => {
+                    return Err(RMesgError::FilterError(format!(
+                        "Unknown log level {}",
+                        level
+                    )))
+                }
+            },
+            None => None,
+        };
+        let pattern = match filter.pattern {
+            Some(pattern) => match Regex::new(&pattern) {
+                Ok(pattern) => Some(pattern),
+                Err(e) => {
+                    return Err(RMesgError::FilterError(format!(
+                        "Unable to compile pattern {}: {}",
+                        pattern, e
+                    )))
+                }
+            },
+            None => None,
+        };
+        Ok(RequestFilter { min_level, pattern })
+    }
+
+    fn matches(&self, entry: &Entry) -> bool {
+        let level_matches = self
+            .min_level
+            .is_none_or(|min| entry.level.is_some_and(|level| level <= min));
+        let pattern_matches = self
+            .pattern
+            .as_ref()
+            .is_none_or(|pattern| pattern.is_match(&entry.message));
+        level_matches && pattern_matches
+    }
+}
+
+fn status_from_error(e: RMesgError) -> Status {
+    match e {
+        RMesgError::FilterError(s) => Status::invalid_argument(s),
+        RMesgError::LockContention(s) => Status::aborted(s),
+        RMesgError::BackendUnavailable(s) | RMesgError::DevKMsgFileOpenError(s) => {
+            Status::unavailable(s)
+        }
+        e => Status::internal(e.to_string()),
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entry::LogFacility;
+    use std::time::Duration;
+
+    fn entry(level: LogLevel, message: &str) -> Entry {
+        Entry {
+            facility: Some(LogFacility::Kern),
+            level: Some(level),
+            sequence_num: Some(42),
+            timestamp_from_system_start: Some(Duration::from_micros(1_500_000)),
+            message: message.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_entry_conversion() {
+        let anchored = Entry {
+            timestamp_realtime: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            ..entry(LogLevel::Error, "nvme nvme0: I/O 12 QID 3 timeout")
+        };
+        assert_eq!(
+            proto::Entry::from(&anchored),
+            proto::Entry {
+                timestamp_us: Some(1_500_000),
+                level: Some(proto::Level::Err as i32),
+                facility: Some(0),
+                sequence_num: Some(42),
+                subsystem: Some("nvme".to_owned()),
+                message: "nvme nvme0: I/O 12 QID 3 timeout".to_owned(),
+                host: HOSTNAME.clone(),
+                realtime_us: Some(1_700_000_000_000_000),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = RequestFilter::from_proto(Some(proto::Filter {
+            min_level: Some(proto::Level::Warn as i32),
+            pattern: Some("^usb".to_owned()),
+        }))
+        .unwrap();
+        assert!(filter.matches(&entry(
+            LogLevel::Error,
+            "usb 1-1: device descriptor read/64, error -71"
+        )));
+        assert!(!filter.matches(&entry(LogLevel::Info, "usb 1-1: new high-speed USB device")));
+        assert!(!filter.matches(&entry(LogLevel::Error, "nvme nvme0: I/O 12 QID 3 timeout")));
+
+        assert!(RequestFilter::from_proto(None)
+            .unwrap()
+            .matches(&entry(LogLevel::Debug, "anything")));
+        assert!(RequestFilter::from_proto(Some(proto::Filter {
+            min_level: Some(12),
+            pattern: None,
+        }))
+        .is_err());
+        assert!(RequestFilter::from_proto(Some(proto::Filter {
+            min_level: None,
+            pattern: Some("(".to_owned()),
+        }))
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_clear_refused() {
+        let service = KernelLogService::with_options(Backend::Default, false);
+        let status = service
+            .clear(Request::new(proto::ClearRequest {}))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    }
+}
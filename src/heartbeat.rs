@@ -0,0 +1,220 @@
+use crate::capture;
+use crate::entry::Entry;
+/// End-to-end health checks of kernel logging itself.
+///
+/// A follower that has seen nothing for a while can't tell a quiet kernel from a broken
+/// read path (a wedged backend, a stuck printk, a full pipe). A `Heartbeat` tells them
+/// apart by periodically writing its own marker into /dev/kmsg and checking that it
+/// comes back through the entries being followed within a deadline. Put it in the
+/// pipeline as a stage (which also keeps the markers out of the output) and call `tick`
+/// every so often; it writes a marker when one is due and reports markers that didn't
+/// make it back in time. Clones share the same state, so the stage and the ticker can
+/// live on different threads or tasks.
+///
+/// Writing markers needs write access to /dev/kmsg (usually root).
+///
+use crate::error::RMesgError;
+use crate::stage::Stage;
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+const MARKER_PREFIX: &str = "rmesg: heartbeat ";
+
+/// How the heartbeats have fared so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HeartbeatStatus {
+    /// Markers written
+    pub sent: u64,
+    /// Markers that came back through the read path within the deadline
+    pub returned: u64,
+    /// Markers that didn't come back within the deadline (including any that came
+    /// back after it)
+    pub late: u64,
+    /// Time from writing to reading back the newest marker that returned
+    pub last_round_trip: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct Beats {
+    interval: Duration,
+    deadline: Duration,
+    last_sent: Option<Instant>,
+    // markers written and not yet seen, oldest first
+    pending: Vec<(String, Instant)>,
+    status: HeartbeatStatus,
+}
+
+/// Periodic self-written markers, and whether they come back.
+#[derive(Clone, Debug)]
+pub struct Heartbeat {
+    beats: Arc<Mutex<Beats>>,
+}
+
+impl Heartbeat {
+    /// Writes a marker every `interval`, each of which must be read back within
+    /// `deadline` of being written.
+    pub fn with_options(interval: Duration, deadline: Duration) -> Heartbeat {
+        Heartbeat {
+            beats: Arc::new(Mutex::new(Beats {
+                interval,
+                deadline,
+                last_sent: None,
+                pending: Vec::new(),
+                status: HeartbeatStatus::default(),
+            })),
+        }
+    }
+
+    /// Writes a marker if one is due, then checks on the ones outstanding: each marker
+    /// that has gone unseen past its deadline is counted as late, and reported (once)
+    /// as a `RMesgError::Timeout`.
+    pub fn tick(&self) -> Result<(), RMesgError> {
+        let due = {
+            let beats = self.lock();
+            beats
+                .last_sent
+                .is_none_or(|last| last.elapsed() >= beats.interval)
+        };
+        if due {
+            let marker = format!("{}{}", MARKER_PREFIX, capture::marker_id());
+            capture::write_marker(&marker)?;
+            self.sent(marker, Instant::now());
+        }
+        self.check(Instant::now())
+    }
+
+    pub fn status(&self) -> HeartbeatStatus {
+        self.lock().status
+    }
+
+    /// Whether `entry` is a heartbeat marker (from any `Heartbeat`, in any process).
+    pub fn is_marker(entry: &Entry) -> bool {
+        entry.message.starts_with(MARKER_PREFIX)
+    }
+
+    /// Notes that an entry came through the read path, returning whether it was one of
+    /// this heartbeat's markers.
+    pub fn observe(&self, entry: &Entry) -> bool {
+        let mut beats = self.lock();
+        match beats.pending.iter().position(|(m, _)| *m == entry.message) {
+            Some(i) => {
+                let (_, sent_at) = beats.pending.remove(i);
+                beats.status.returned += 1;
+                beats.status.last_round_trip = Some(sent_at.elapsed());
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn sent(&self, marker: String, at: Instant) {
+        let mut beats = self.lock();
+        beats.last_sent = Some(at);
+        beats.pending.push((marker, at));
+        beats.status.sent += 1;
+    }
+
+    fn check(&self, now: Instant) -> Result<(), RMesgError> {
+        let mut beats = self.lock();
+        let deadline = beats.deadline;
+        let overdue = beats
+            .pending
+            .iter()
+            .take_while(|(_, sent_at)| now.duration_since(*sent_at) > deadline)
+            .count();
+        if overdue == 0 {
+            return Ok(());
+        }
+
+        beats.pending.drain(..overdue);
+        beats.status.late += overdue as u64;
+        Err(RMesgError::Timeout(format!(
+            "{} heartbeat marker(s) written to /dev/kmsg didn't come back through the read path within {:?}",
+            overdue, deadline
+        )))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Beats> {
+        // the state stays consistent if a panic happens while holding the lock
+        self.beats.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for Heartbeat {
+    /// A marker a minute, due back within ten seconds.
+    fn default() -> Self {
+        Heartbeat::with_options(Duration::from_secs(60), Duration::from_secs(10))
+    }
+}
+
+impl Stage for Heartbeat {
+    /// Notes this heartbeat's markers as they come back, and drops all heartbeat
+    /// markers from the output.
+    fn process(&mut self, entry: Entry) -> Option<Entry> {
+        self.observe(&entry);
+        match Heartbeat::is_marker(&entry) {
+            true => None,
+            false => Some(entry),
+        }
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entry::entry;
+
+    #[test]
+    fn test_round_trip_and_deadline() {
+        let mut heartbeat =
+            Heartbeat::with_options(Duration::from_secs(60), Duration::from_secs(10));
+        let start = Instant::now();
+        heartbeat.sent(format!("{}one", MARKER_PREFIX), start);
+        heartbeat.sent(format!("{}two", MARKER_PREFIX), start);
+
+        // markers are noted and kept out of the output, along with other heartbeats'
+        assert_eq!(heartbeat.process(entry("rmesg: heartbeat one")), None);
+        assert_eq!(heartbeat.process(entry("rmesg: heartbeat elsewhere")), None);
+        assert_eq!(
+            heartbeat.process(entry("usb 1-1: new device")),
+            Some(entry("usb 1-1: new device"))
+        );
+        assert!(heartbeat.check(start + Duration::from_secs(5)).is_ok());
+
+        // the one that never came back is reported once
+        match heartbeat.check(start + Duration::from_secs(11)) {
+            Err(RMesgError::Timeout(_)) => {}
+            other => panic!("Expected Timeout, got: {:?}", other),
+        }
+        assert!(heartbeat.check(start + Duration::from_secs(12)).is_ok());
+
+        let status = heartbeat.status();
+        assert_eq!((status.sent, status.returned, status.late), (2, 1, 1));
+        assert!(status.last_round_trip.is_some());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_tick() {
+        let heartbeat = Heartbeat::with_options(Duration::from_secs(60), Duration::from_secs(10));
+        // writing to /dev/kmsg needs privileges the test may not have
+        if heartbeat.tick().is_err() {
+            return;
+        }
+        assert_eq!(heartbeat.status().sent, 1);
+        // not due again yet
+        heartbeat.tick().unwrap();
+        assert_eq!(heartbeat.status().sent, 1);
+
+        let returned = crate::kmsgfile::kmsg(None)
+            .unwrap()
+            .iter()
+            .any(|entry| heartbeat.observe(entry));
+        assert!(returned);
+        assert_eq!(heartbeat.status().returned, 1);
+    }
+}
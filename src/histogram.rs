@@ -0,0 +1,312 @@
+use crate::entry::{Entry, LogLevel};
+/// Rolling histogram of entries per level over time, with burst detection.
+///
+/// A `SeverityHistogram` is a stage that counts the entries passing through it into
+/// fixed-width buckets (a minute by default) of kernel time, keeping the last few.
+/// Monitoring agents can read the counts, or ask a `BurstDetector` whether the newest
+/// bucket is well above the ones before it, for a quick "kernel is unhappy" signal.
+/// Like `RetentionRing`, clones share the same histogram.
+///
+use crate::stage::Stage;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// The number of levels, Emergency through Debug.
+const LEVELS: usize = 8;
+
+/// Entry counts for one bucket of kernel time.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistogramBucket {
+    /// When the bucket starts, as time since system start
+    pub start: Duration,
+    /// Entries at each level, indexed by the level's value
+    pub counts: [u64; LEVELS],
+    /// Entries without a level
+    pub unleveled: u64,
+}
+
+impl HistogramBucket {
+    fn new(start: Duration) -> HistogramBucket {
+        HistogramBucket {
+            start,
+            counts: [0; LEVELS],
+            unleveled: 0,
+        }
+    }
+
+    /// Entries at exactly `level`.
+    pub fn count(&self, level: LogLevel) -> u64 {
+        self.counts[level as usize]
+    }
+
+    /// Entries at `level` or more severe.
+    pub fn at_least(&self, level: LogLevel) -> u64 {
+        self.counts[..=level as usize].iter().sum()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum::<u64>() + self.unleveled
+    }
+}
+
+#[derive(Debug)]
+struct Histogram {
+    buckets: VecDeque<HistogramBucket>,
+    bucket_width: Duration,
+    window: usize,
+}
+
+impl Histogram {
+    fn bucket_start(&self, timestamp: Duration) -> Duration {
+        let width = self.bucket_width.as_micros();
+        Duration::from_micros((timestamp.as_micros() / width * width) as u64)
+    }
+
+    fn bucket_for(&mut self, timestamp: Option<Duration>) -> Option<&mut HistogramBucket> {
+        let start = match timestamp {
+            Some(ts) => self.bucket_start(ts),
+            // entries without a timestamp are counted as of the newest one
+            None => match self.buckets.back() {
+                Some(newest) => newest.start,
+                None => Duration::from_secs(0),
+            },
+        };
+
+        // quiet periods still count towards the baseline, so fill in empty buckets
+        let mut next = match self.buckets.back() {
+            Some(newest) => newest.start + self.bucket_width,
+            None => start,
+        };
+        if start >= next {
+            if start - next > self.bucket_width * self.window as u32 {
+                self.buckets.clear();
+                next = start;
+            }
+            while next <= start {
+                self.buckets.push_back(HistogramBucket::new(next));
+                next += self.bucket_width;
+            }
+            while self.buckets.len() > self.window {
+                self.buckets.pop_front();
+            }
+        }
+
+        // entries older than the window are dropped
+        self.buckets.iter_mut().rev().find(|b| b.start == start)
+    }
+}
+
+/// A rolling histogram of entries per level.
+#[derive(Clone, Debug)]
+pub struct SeverityHistogram {
+    histogram: Arc<Mutex<Histogram>>,
+}
+
+impl SeverityHistogram {
+    /// Counts entries into buckets `bucket_width` wide, keeping the newest `window` buckets.
+    pub fn with_options(bucket_width: Duration, window: usize) -> SeverityHistogram {
+        SeverityHistogram {
+            histogram: Arc::new(Mutex::new(Histogram {
+                buckets: VecDeque::with_capacity(window),
+                bucket_width: bucket_width.max(Duration::from_micros(1)),
+                window: window.max(1),
+            })),
+        }
+    }
+
+    pub fn record(&self, entry: &Entry) {
+        let mut histogram = self.lock();
+        if let Some(bucket) = histogram.bucket_for(entry.timestamp_from_system_start) {
+            match entry.level {
+                Some(level) => bucket.counts[level as usize] += 1,
+                None => bucket.unleveled += 1,
+            }
+        }
+    }
+
+    /// Every bucket in the window, oldest first.
+    pub fn buckets(&self) -> Vec<HistogramBucket> {
+        self.lock().buckets.iter().cloned().collect()
+    }
+
+    /// The newest bucket, if anything has been recorded.
+    pub fn newest(&self) -> Option<HistogramBucket> {
+        self.lock().buckets.back().cloned()
+    }
+
+    /// Whether the newest bucket is a burst, as `detector` defines one.
+    pub fn burst(&self, detector: &BurstDetector) -> Option<Burst> {
+        detector.detect(&self.buckets())
+    }
+
+    pub fn clear(&self) {
+        self.lock().buckets.clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Histogram> {
+        // counts stay consistent if a panic happens while holding the lock
+        self.histogram.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for SeverityHistogram {
+    /// Per-minute buckets over the last hour.
+    fn default() -> Self {
+        SeverityHistogram::with_options(Duration::from_secs(60), 60)
+    }
+}
+
+impl Stage for SeverityHistogram {
+    fn process(&mut self, entry: Entry) -> Option<Entry> {
+        self.record(&entry);
+        Some(entry)
+    }
+}
+
+/// The newest bucket's count compared to the baseline before it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Burst {
+    pub bucket_start: Duration,
+    pub count: u64,
+    /// The mean count of the buckets before the newest one
+    pub baseline: f64,
+}
+
+/// Decides whether the newest bucket of a histogram is a burst: its count (of entries
+/// at `min_level` or more severe, or of every entry) exceeds `factor` times the mean
+/// of the buckets before it, and is at least `min_count` (so that a couple of messages
+/// after a silent hour don't count).
+#[derive(Clone, Debug)]
+pub struct BurstDetector {
+    pub factor: f64,
+    pub min_count: u64,
+    pub min_level: Option<LogLevel>,
+}
+
+impl BurstDetector {
+    pub fn detect(&self, buckets: &[HistogramBucket]) -> Option<Burst> {
+        let (newest, before) = buckets.split_last()?;
+        let count = |b: &HistogramBucket| match self.min_level {
+            Some(level) => b.at_least(level),
+            None => b.total(),
+        };
+
+        let baseline = match before.len() {
+            0 => 0.0,
+            n => before.iter().map(count).sum::<u64>() as f64 / n as f64,
+        };
+        let newest_count = count(newest);
+
+        match newest_count >= self.min_count && newest_count as f64 > baseline * self.factor {
+            true => Some(Burst {
+                bucket_start: newest.start,
+                count: newest_count,
+                baseline,
+            }),
+            false => None,
+        }
+    }
+}
+
+impl Default for BurstDetector {
+    /// Five times the baseline of warnings or worse, and at least ten of them.
+    fn default() -> Self {
+        BurstDetector {
+            factor: 5.0,
+            min_count: 10,
+            min_level: Some(LogLevel::Warning),
+        }
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(secs: u64, level: Option<LogLevel>) -> Entry {
+        Entry {
+            level,
+            timestamp_from_system_start: Some(Duration::from_secs(secs)),
+            message: "message".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_buckets() {
+        let mut histogram = SeverityHistogram::with_options(Duration::from_secs(60), 3);
+        let handle = histogram.clone();
+
+        histogram.process(entry(5, Some(LogLevel::Info)));
+        histogram.process(entry(59, Some(LogLevel::Error)));
+        histogram.process(entry(61, None));
+        // a quiet minute, then
+        histogram.process(entry(190, Some(LogLevel::Warning)));
+
+        let buckets = handle.buckets();
+        assert_eq!(
+            buckets
+                .iter()
+                .map(|b| b.start.as_secs())
+                .collect::<Vec<u64>>(),
+            vec![60, 120, 180]
+        );
+        assert_eq!(buckets[0].unleveled, 1);
+        assert_eq!(buckets[1].total(), 0);
+        assert_eq!(buckets[2].count(LogLevel::Warning), 1);
+        assert_eq!(buckets[2].at_least(LogLevel::Error), 0);
+
+        // late entries land in their bucket while it's still in the window
+        handle.record(&entry(70, Some(LogLevel::Critical)));
+        handle.record(&entry(10, Some(LogLevel::Critical)));
+        assert_eq!(handle.buckets()[0].at_least(LogLevel::Error), 1);
+        assert_eq!(handle.buckets().iter().map(|b| b.total()).sum::<u64>(), 3);
+
+        // a jump past the whole window starts over
+        handle.record(&entry(3600, Some(LogLevel::Info)));
+        assert_eq!(handle.buckets().len(), 1);
+        assert_eq!(handle.newest().unwrap().start.as_secs(), 3600);
+    }
+
+    #[test]
+    fn test_burst() {
+        let histogram = SeverityHistogram::default();
+        let detector = BurstDetector {
+            factor: 3.0,
+            min_count: 5,
+            min_level: Some(LogLevel::Error),
+        };
+
+        for minute in 0..5 {
+            histogram.record(&entry(minute * 60, Some(LogLevel::Error)));
+            histogram.record(&entry(minute * 60 + 1, Some(LogLevel::Info)));
+        }
+        assert_eq!(histogram.burst(&detector), None);
+
+        for second in 0..6 {
+            histogram.record(&entry(300 + second, Some(LogLevel::Error)));
+        }
+        assert_eq!(
+            histogram.burst(&detector),
+            Some(Burst {
+                bucket_start: Duration::from_secs(300),
+                count: 6,
+                baseline: 1.0,
+            })
+        );
+        // lots of info messages don't make an error burst
+        assert_eq!(
+            detector.detect(&[HistogramBucket::new(Duration::from_secs(0)), {
+                let mut b = HistogramBucket::new(Duration::from_secs(60));
+                b.counts[LogLevel::Info as usize] = 100;
+                b
+            }]),
+            None
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use crate::error::RMesgError;
+/// Poll intervals, validated when they're made rather than when they're used.
+///
+/// A raw `Duration` lets through intervals that only go wrong at runtime: zero spins a
+/// core re-reading the buffer, and a huge one silently misses everything that goes
+/// through the ring in between (or overflows the arithmetic on it). A `PollInterval` is
+/// between a millisecond and a day, and anything else is a `ConfigError` up front.
+///
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::time::Duration;
+
+/// The shortest interval accepted
+pub const MIN_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// The longest interval accepted
+pub const MAX_POLL_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Every klogctl poll reads and parses the whole buffer, so polling it more often than
+/// this costs more than it catches.
+pub const KLOGCTL_MIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PollInterval(Duration);
+
+impl PollInterval {
+    pub fn new(interval: Duration) -> Result<PollInterval, RMesgError> {
+        if interval.is_zero() {
+            return Err(RMesgError::ConfigError(
+                "A poll interval of zero would poll continuously".to_owned(),
+            ));
+        }
+        if !(MIN_POLL_INTERVAL..=MAX_POLL_INTERVAL).contains(&interval) {
+            return Err(RMesgError::ConfigError(format!(
+                "Poll interval {:?} is outside {:?} to {:?}",
+                interval, MIN_POLL_INTERVAL, MAX_POLL_INTERVAL
+            )));
+        }
+        Ok(PollInterval(interval))
+    }
+
+    pub fn from_millis(millis: u64) -> Result<PollInterval, RMesgError> {
+        PollInterval::new(Duration::from_millis(millis))
+    }
+
+    pub fn from_secs(secs: u64) -> Result<PollInterval, RMesgError> {
+        PollInterval::new(Duration::from_secs(secs))
+    }
+
+    // For the crate's own constants, which are known to be in range
+    #[cfg_attr(not(feature = "klogctl"), allow(dead_code))]
+    pub(crate) const fn new_unchecked(interval: Duration) -> PollInterval {
+        PollInterval(interval)
+    }
+
+    pub fn get(&self) -> Duration {
+        self.0
+    }
+
+    /// A warning if the interval is too short to poll klogctl at (see
+    /// `KLOGCTL_MIN_POLL_INTERVAL`). It works, but at a cost worth knowing about.
+    pub fn klogctl_warning(&self) -> Option<String> {
+        match self.0 < KLOGCTL_MIN_POLL_INTERVAL {
+            true => Some(format!(
+                "Polling klogctl every {:?} reads the whole buffer each time; {:?} or more is recommended",
+                self.0, KLOGCTL_MIN_POLL_INTERVAL
+            )),
+            false => None,
+        }
+    }
+}
+
+impl TryFrom<Duration> for PollInterval {
+    type Error = RMesgError;
+
+    fn try_from(interval: Duration) -> Result<Self, Self::Error> {
+        PollInterval::new(interval)
+    }
+}
+
+impl From<PollInterval> for Duration {
+    fn from(interval: PollInterval) -> Duration {
+        interval.0
+    }
+}
+
+impl Display for PollInterval {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validation() {
+        assert_eq!(
+            PollInterval::from_millis(250).unwrap().get(),
+            Duration::from_millis(250)
+        );
+        assert!(PollInterval::try_from(MAX_POLL_INTERVAL).is_ok());
+        for interval in &[
+            Duration::ZERO,
+            Duration::from_micros(10),
+            MAX_POLL_INTERVAL + Duration::from_secs(1),
+            Duration::MAX,
+        ] {
+            assert!(
+                matches!(
+                    PollInterval::new(*interval),
+                    Err(RMesgError::ConfigError(_))
+                ),
+                "{:?}",
+                interval
+            );
+        }
+    }
+
+    #[test]
+    fn test_klogctl_warning() {
+        assert!(PollInterval::from_millis(10)
+            .unwrap()
+            .klogctl_warning()
+            .is_some());
+        assert!(PollInterval::new(KLOGCTL_MIN_POLL_INTERVAL)
+            .unwrap()
+            .klogctl_warning()
+            .is_none());
+    }
+}
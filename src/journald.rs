@@ -0,0 +1,210 @@
+use crate::common;
+use crate::entry::{Entry, EntryParsingError, LogFacility, LogLevel};
+/// Journald Implementation (reads kernel messages from the systemd journal through journalctl)
+///
+/// Useful where the kernel buffer itself isn't readable (containers, hardened hosts
+/// with dmesg_restrict) but the journal is. Only kernel messages from the current
+/// boot are read, as `journalctl -k` does.
+///
+use crate::error::RMesgError;
+
+use num::FromPrimitive;
+use serde_json::Value;
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime};
+
+#[cfg(feature = "sync")]
+use std::io::{BufRead, BufReader};
+#[cfg(feature = "sync")]
+use std::process::{Child, ChildStdout};
+
+pub(crate) const JOURNALCTL: &str = "journalctl";
+const JOURNALCTL_ARGS: &[&str] = &["--dmesg", "--output=json", "--no-pager", "--quiet"];
+
+/// Follows the journal's kernel messages, starting with those already logged this boot.
+#[cfg(feature = "sync")]
+pub struct JournalEntriesIter {
+    child: Child,
+    lines: std::io::Lines<BufReader<ChildStdout>>,
+}
+
+#[cfg(feature = "sync")]
+impl JournalEntriesIter {
+    pub fn with_options(raw: bool) -> Result<JournalEntriesIter, RMesgError> {
+        if raw {
+            return Err(RMesgError::BackendUnavailable(
+                "journald entries have no raw form to iterate over".to_owned(),
+            ));
+        }
+
+        let mut child = Command::new(JOURNALCTL)
+            .args(JOURNALCTL_ARGS)
+            .args(["--follow", "--lines=all"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let stdout = match child.stdout.take() {
+            Some(stdout) => stdout,
+            None => {
+                return Err(RMesgError::InternalError(
+                    "journalctl was started without a stdout pipe".to_owned(),
+                ))
+            }
+        };
+
+        Ok(JournalEntriesIter {
+            child,
+            lines: BufReader::new(stdout).lines(),
+        })
+    }
+}
+
+#[cfg(feature = "sync")]
+impl Iterator for JournalEntriesIter {
+    type Item = Result<Entry, RMesgError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.lines.next()? {
+            Ok(line) => Some(entry_from_line(&line).map_err(|e| e.into())),
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+#[cfg(feature = "sync")]
+impl Drop for JournalEntriesIter {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// The journal's kernel messages for this boot, as the JSON lines journalctl outputs.
+pub fn journal_raw() -> Result<String, RMesgError> {
+    let output = Command::new(JOURNALCTL)
+        .args(JOURNALCTL_ARGS)
+        .stdin(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(RMesgError::BackendUnavailable(format!(
+            "{} failed ({}): {}",
+            JOURNALCTL,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+pub fn journal() -> Result<Vec<Entry>, RMesgError> {
+    let raw = journal_raw()?;
+    let entry_results: Result<Vec<Entry>, EntryParsingError> =
+        raw.lines().map(entry_from_line).collect();
+    Ok(entry_results?)
+}
+
+pub fn journal_count() -> Result<usize, RMesgError> {
+    Ok(journal_raw()?.lines().count())
+}
+
+// Parses one line of `journalctl --output=json`, like so (abridged):
+// {"PRIORITY":"6","SYSLOG_FACILITY":"0","_SOURCE_MONOTONIC_TIMESTAMP":"1500000","__REALTIME_TIMESTAMP":"1609459201500000","MESSAGE":"usb 1-1: new high-speed USB device"}
+// MESSAGE is an array of bytes instead of a string when it isn't valid UTF-8.
+pub fn entry_from_line(line: &str) -> Result<Entry, EntryParsingError> {
+    let record: Value = match serde_json::from_str(line) {
+        Ok(record) => record,
+        Err(e) => {
+            return Err(EntryParsingError::Generic(format!(
+                "Unable to parse journal record due to error: {}\nLine: {}",
+                e, line
+            )))
+        }
+    };
+    let field = |name: &str| record.get(name).and_then(|v| v.as_str());
+
+    let level = match field("PRIORITY") {
+        Some(priority) => LogLevel::from_u32(common::parse_fragment(priority, line)?),
+        None => None,
+    };
+    let facility = match field("SYSLOG_FACILITY") {
+        Some(facility) => LogFacility::from_u32(common::parse_fragment(facility, line)?),
+        None => None,
+    };
+
+    // the kernel's own timestamp, rather than when journald received the message
+    let timestamp_from_system_start = match field("_SOURCE_MONOTONIC_TIMESTAMP") {
+        Some(usecs) => common::parse_timestamp_microsecs(usecs, line)?,
+        None => None,
+    };
+
+    // journald's wall clock time for it, which saves anchoring
+    let timestamp_realtime = match field("__REALTIME_TIMESTAMP") {
+        Some(usecs) => Some(
+            SystemTime::UNIX_EPOCH
+                + Duration::from_micros(common::parse_fragment::<u64>(usecs, line)?),
+        ),
+        None => None,
+    };
+
+    let message = match record.get("MESSAGE") {
+        Some(Value::String(message)) => message.to_owned(),
+        Some(Value::Array(bytes)) => {
+            let bytes: Vec<u8> = bytes
+                .iter()
+                .filter_map(|b| b.as_u64().map(|b| b as u8))
+                .collect();
+            String::from_utf8_lossy(&bytes).into_owned()
+        }
+        _ => String::new(),
+    };
+
+    Ok(Entry {
+        facility,
+        level,
+        sequence_num: None,
+        timestamp_from_system_start,
+        message,
+        timestamp_realtime,
+        provenance: None,
+        malformed: false,
+        // the journal has the facility and level separately
+        priority: match (facility, level) {
+            (Some(facility), Some(level)) => Some((facility as u32) << 3 | level as u32),
+            _ => None,
+        },
+        tags: Default::default(),
+    })
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_entry_from_line() {
+        let entry = entry_from_line(r#"{"__CURSOR":"s=abc","PRIORITY":"3","SYSLOG_FACILITY":"0","_TRANSPORT":"kernel","_SOURCE_MONOTONIC_TIMESTAMP":"1500000","__REALTIME_TIMESTAMP":"1609459201500000","MESSAGE":"nvme nvme0: I/O 12 QID 3 timeout, aborting"}"#).unwrap();
+        assert_eq!(
+            entry,
+            Entry {
+                facility: Some(LogFacility::Kern),
+                level: Some(LogLevel::Error),
+                timestamp_from_system_start: Some(Duration::from_millis(1500)),
+                message: "nvme nvme0: I/O 12 QID 3 timeout, aborting".to_owned(),
+                timestamp_realtime: Some(
+                    SystemTime::UNIX_EPOCH + Duration::from_micros(1609459201500000)
+                ),
+                priority: Some(3),
+                ..Default::default()
+            }
+        );
+
+        let entry = entry_from_line(r#"{"PRIORITY":"6","MESSAGE":[104,105,255]}"#).unwrap();
+        assert_eq!(entry.message, "hi\u{fffd}");
+        assert_eq!(entry.timestamp_from_system_start, None);
+
+        assert!(entry_from_line("not json").is_err());
+        assert!(entry_from_line(r#"{"PRIORITY":"loud"}"#).is_err());
+    }
+}
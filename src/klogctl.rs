@@ -1,5 +1,5 @@
 use crate::common;
-use crate::entry::{Entry, EntryParsingError};
+use crate::entry::{Entry, EntryParsingError, LogLevel};
 /// This crate provides a klogctl interface from Rust.
 /// klogctl is a Linux syscall that allows reading the Linux Kernel Log buffer.
 /// https://elinux.org/Debugging_by_printing
@@ -10,10 +10,13 @@ use crate::entry::{Entry, EntryParsingError};
 /// This allows Rust programs to consume dmesg-like output programmatically.
 ///
 use crate::error::RMesgError;
+use crate::interval::PollInterval;
+use crate::parse;
 
 use errno::errno;
-use lazy_static::lazy_static;
-use regex::Regex;
+use num::FromPrimitive;
+use std::cmp::Ordering;
+use std::collections::VecDeque;
 use std::convert::TryFrom;
 use std::fs;
 use std::os::raw::c_char;
@@ -76,18 +79,73 @@ pub type SignedInt = libc::c_int;
 /// The path under /proc where the parameter to set (or unset) logging a timestamp resides
 pub const SYS_MODULE_PRINTK_PARAMETERS_TIME: &str = "/sys/module/printk/parameters/time";
 
+/// The path under /proc whose first value is the console log level
+pub const PROC_SYS_KERNEL_PRINTK: &str = "/proc/sys/kernel/printk";
+
+/// What was in the kernel log buffer at the moment it was cleared.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClearedVolume {
+    /// Bytes of log text discarded
+    pub bytes: usize,
+    /// Entries discarded
+    pub entries: usize,
+    /// Bytes that had not yet been consumed by a `SyslogActionRead` reader (e.g. syslogd)
+    pub unread_bytes: usize,
+}
+
 /// suggest polling every ten seconds
-pub const SUGGESTED_POLL_INTERVAL: std::time::Duration = Duration::from_secs(10);
+pub const SUGGESTED_POLL_INTERVAL: PollInterval =
+    PollInterval::new_unchecked(Duration::from_secs(10));
+
+/// Bounds for adaptive polling: the interval drops to `min_interval` whenever a poll
+/// finds new entries, and doubles (up to `max_interval`) after each poll that doesn't.
+/// This approximates /dev/kmsg's follow latency while messages are arriving, without
+/// reading the whole buffer every few milliseconds on an idle system.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdaptivePolling {
+    pub min_interval: PollInterval,
+    pub max_interval: PollInterval,
+}
+
+impl AdaptivePolling {
+    /// Bounds from `min_interval` to `max_interval`, which can't be the wrong way round.
+    pub fn new(
+        min_interval: PollInterval,
+        max_interval: PollInterval,
+    ) -> Result<AdaptivePolling, RMesgError> {
+        if min_interval > max_interval {
+            return Err(RMesgError::ConfigError(format!(
+                "Adaptive polling's minimum interval ({}) is longer than its maximum ({})",
+                min_interval, max_interval
+            )));
+        }
+        Ok(AdaptivePolling {
+            min_interval,
+            max_interval,
+        })
+    }
+
+    /// The interval to wait after a poll that did (or didn't) find new entries.
+    pub fn next_interval(&self, current: Duration, found_entries: bool) -> Duration {
+        let (min, max) = (self.min_interval.get(), self.max_interval.get());
+        match found_entries {
+            true => min,
+            false => current
+                .checked_mul(2)
+                .unwrap_or(max)
+                .clamp(min, max.max(min)),
+        }
+    }
+}
 
-lazy_static! {
-    static ref RE_ENTRY_WITH_TIMESTAMP: Regex = Regex::new(
-        r"(?x)^
-        [[:space:]]*<(?P<faclevstr>[[:digit:]]*)>
-        [[:space:]]*([\[][[:space:]]*(?P<timestampstr>[[:digit:]]*\.[[:digit:]]*)[\]])?
-        (?P<message>.*)
-        $"
-    )
-    .unwrap();
+impl Default for AdaptivePolling {
+    /// A quarter of a second while busy, up to `SUGGESTED_POLL_INTERVAL` while idle.
+    fn default() -> Self {
+        AdaptivePolling {
+            min_interval: PollInterval::new_unchecked(Duration::from_millis(250)),
+            max_interval: SUGGESTED_POLL_INTERVAL,
+        }
+    }
 }
 
 /// While reading the kernel log buffer is very useful in and of itself (expecially when running the CLI),
@@ -110,11 +168,16 @@ lazy_static! {
 ///
 pub struct KLogEntries {
     clear: bool,
-    entries: Vec<Entry>,
-    last_timestamp: Option<Duration>,
+    entries: VecDeque<Entry>,
+    buffer: KLogBuffer,
+    cursor: PollCursor,
     poll_interval: Duration,
     sleep_interval: Duration, // Just slightly longer than poll interval so the check passes
     last_poll: SystemTime,
+    adaptive: Option<AdaptivePolling>,
+    incremental: bool,
+    polled: bool,
+    unread_polled: bool,
 
     #[cfg(feature = "async")]
     sleep_future: Option<Pin<Box<tokiotime::Sleep>>>,
@@ -123,7 +186,7 @@ pub struct KLogEntries {
 impl KLogEntries {
     /// Create a new KLogEntries with two specific options
     /// `clear: bool` specifies Whether or not to clear the buffer after every read.
-    /// `poll_interval: Duration` specifies the interval after which to poll the buffer for new lines
+    /// `poll_interval: PollInterval` specifies the interval after which to poll the buffer for new lines
     ///
     /// Choice of these parameters affects how the iterator behaves significantly.
     ///
@@ -137,9 +200,19 @@ impl KLogEntries {
     /// will be lost.
     ///
     /// This crate exports a constant `SUGGESTED_POLL_INTERVAL` which contains the recommended
-    /// default when in doubt.
+    /// default when in doubt. Where klogctl is the only way to follow the log, an interval
+    /// shorter than `interval::KLOGCTL_MIN_POLL_INTERVAL` prints a warning.
     ///
-    pub fn with_options(clear: bool, poll_interval: Duration) -> Result<KLogEntries, RMesgError> {
+    pub fn with_options(
+        clear: bool,
+        poll_interval: PollInterval,
+    ) -> Result<KLogEntries, RMesgError> {
+        if let Some(warning) = poll_interval.klogctl_warning() {
+            if klogctl_only() {
+                eprintln!("WARNING: {}", warning);
+            }
+        }
+        let poll_interval = poll_interval.get();
         let sleep_interval = match poll_interval.checked_add(Duration::from_millis(200)) {
             Some(si) => si,
             None => return Err(RMesgError::UnableToAddDurationToSystemTime),
@@ -152,62 +225,231 @@ impl KLogEntries {
         };
 
         Ok(KLogEntries {
-            entries: Vec::new(),
+            entries: VecDeque::new(),
+            buffer: KLogBuffer::new(),
             poll_interval,
             sleep_interval,
             last_poll,
+            adaptive: None,
+            incremental: false,
+            polled: false,
+            unread_polled: false,
             clear,
-            last_timestamp: None,
+            cursor: PollCursor::default(),
 
             #[cfg(feature = "async")]
             sleep_future: None,
         })
     }
 
+    /// Like `with_options`, but polling adaptively (see `AdaptivePolling`), starting
+    /// at the minimum interval. This is the lowest-latency way to follow the log
+    /// where /dev/kmsg isn't available.
+    pub fn with_adaptive_polling(
+        clear: bool,
+        polling: AdaptivePolling,
+    ) -> Result<KLogEntries, RMesgError> {
+        let polling = AdaptivePolling::new(polling.min_interval, polling.max_interval)?;
+        let mut entries = KLogEntries::with_options(clear, polling.min_interval)?;
+        // the sleep is only shortened by polls finding nothing, so it needs no margin
+        entries.sleep_interval = polling.min_interval.get();
+        entries.adaptive = Some(polling);
+        Ok(entries)
+    }
+
+    /// Makes every poll but the first read only what the kernel hasn't handed out yet
+    /// (see `KLogBuffer::read_unread`), rather than the whole buffer: on a busy system,
+    /// a few KiB per poll instead of the whole ring, and as much less parsing. The first
+    /// poll still reads the whole buffer, for the history.
+    ///
+    /// Doing so takes those bytes from other `SyslogActionRead` and /proc/kmsg readers,
+    /// so this is for systems where nothing else reads the log that way (systemd-journald
+    /// reads /dev/kmsg). It needs CAP_SYSLOG whatever `dmesg_restrict` says, and can't be
+    /// combined with `clear`.
+    pub fn incremental(mut self) -> Result<KLogEntries, RMesgError> {
+        if self.clear {
+            return Err(RMesgError::ConfigError(
+                "Incremental reads leave the buffer as it is, so they can't clear it".to_owned(),
+            ));
+        }
+        self.incremental = true;
+        Ok(self)
+    }
+
+    /// Entries read by the last poll and not yet consumed.
+    pub fn buffered(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Roughly how much memory the buffered entries take up (see `Entry::approx_size`).
+    pub fn buffered_bytes(&self) -> usize {
+        self.entries.iter().map(Entry::approx_size).sum()
+    }
+
+    /// The current interval between polls.
+    pub fn poll_interval(&self) -> Duration {
+        self.poll_interval
+    }
+
+    /// How long to wait before asking `next_ready` again after it found nothing.
+    #[cfg(feature = "futures")]
+    pub(crate) fn sleep_interval(&self) -> Duration {
+        self.sleep_interval
+    }
+
+    /// The next entry, polling the buffer first if there's none buffered and a poll
+    /// is due. `Ok(None)` means there's nothing new yet: wait `sleep_interval` and ask
+    /// again. This never blocks, so it's the building block for followers on any
+    /// executor (or none).
+    #[cfg(any(feature = "sync", feature = "futures"))]
+    pub(crate) fn next_ready(&mut self) -> Result<Option<Entry>, RMesgError> {
+        if self.entries.is_empty() {
+            let elapsed = self
+                .last_poll
+                .elapsed()
+                .map_err(RMesgError::UnableToObtainElapsedTime)?;
+
+            // Poll once if time since last poll is greater than interval
+            // This prevents lots of calls to next from hitting the kernel.
+            if elapsed >= self.poll_interval {
+                self.poll()?;
+            }
+        }
+
+        Ok(self.entries.pop_front())
+    }
+
     /// This method conducts the actual polling of the log buffer.
     ///
-    /// It tracks the timestamp of the last line buffered, and only adds lines
-    /// that have timestamps greater than that.
+    /// It tracks where it's up to by timestamp (see `PollCursor`), and only adds lines
+    /// after that.
     ///
-    /// Any lines without a timestamp are ignored. It is upto consumers to ensure timestamps
-    /// are set (possibly through the provided function `kernel_log_timestamps_enable`) before
-    /// polling/iterating.
+    /// When the whole buffer is read, any lines without a timestamp are ignored. It is upto
+    /// consumers to ensure timestamps are set (possibly through the provided function
+    /// `kernel_log_timestamps_enable`) before polling/iterating.
     ///
     fn poll(&mut self) -> Result<usize, RMesgError> {
         self.last_poll = SystemTime::now();
 
-        let mut entries = klog(self.clear)?;
-        let mut entriesadded: usize = 0;
-        match self.last_timestamp {
-            None => {
-                entriesadded += entries.len();
-                self.entries.append(&mut entries);
+        #[cfg(feature = "bench")]
+        crate::counters::bump(crate::counters::Counter::KLogPolls);
+
+        // whatever's unread the first time may go back further than what the first poll
+        // read, so it's still diffed against what's been seen; after that (as after a read
+        // that cleared the buffer) all there is to read is new
+        let only_new = (self.clear && self.polled) || self.unread_polled;
+        let entries = match self.incremental && self.polled {
+            true => {
+                self.unread_polled = true;
+                self.buffer.unread_entries()?
             }
-            Some(last_timestamp) => {
-                while !entries.is_empty() {
-                    let entry = entries.remove(0);
-                    let skip = match entry.timestamp_from_system_start {
-                        // skip if entry timestamp is older than or equal to last timestamp
-                        Some(timestamp) => timestamp <= last_timestamp,
-                        // skip all without timestamp
-                        None => true,
-                    };
-
-                    if !skip {
-                        self.entries.push(entry);
-                        entriesadded += 1;
+            false => self.buffer.entries(self.clear)?,
+        };
+        self.polled = true;
+        let entries = match only_new {
+            true => self.cursor.unread_entries(entries),
+            false => self.cursor.newer_entries(entries),
+        };
+        let entriesadded = entries.len();
+        self.entries.extend(entries);
+
+        if let Some(adaptive) = self.adaptive {
+            self.poll_interval = adaptive.next_interval(self.poll_interval, entriesadded > 0);
+            self.sleep_interval = self.poll_interval;
+        }
+
+        Ok(entriesadded)
+    }
+}
+
+/// Where a follower polling the buffer through klogctl is up to. klogctl's records
+/// have no sequence numbers, so that's the timestamp of the last entry seen, and how
+/// many entries with that same timestamp were seen: drivers often log several lines
+/// within the same microsecond, and a poll falling between them mustn't lose the rest.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PollCursor {
+    last_timestamp: Option<Duration>,
+    seen_at_last_timestamp: usize,
+}
+
+impl PollCursor {
+    /// The timestamp of the last entry seen, if any.
+    pub fn last_timestamp(&self) -> Option<Duration> {
+        self.last_timestamp
+    }
+
+    /// The entries of a poll (the whole buffer, in order) that are new, moving past
+    /// them. The first time, that's all of them; after that, entries without a timestamp
+    /// are dropped, since there's no telling whether they're new.
+    pub fn newer_entries(&mut self, entries: Vec<Entry>) -> Vec<Entry> {
+        #[cfg(feature = "bench")]
+        crate::counters::add(
+            crate::counters::Counter::PollEntriesScanned,
+            entries.len() as u64,
+        );
+
+        let previous = *self;
+        self.advance(&entries);
+        let last_timestamp = match previous.last_timestamp {
+            None => return entries,
+            Some(last_timestamp) => last_timestamp,
+        };
+
+        let mut to_skip = previous.seen_at_last_timestamp;
+        entries
+            .into_iter()
+            .filter(|entry| {
+                match entry
+                    .timestamp_from_system_start
+                    .map(|ts| ts.cmp(&last_timestamp))
+                {
+                    Some(Ordering::Greater) => true,
+                    // those at the last timestamp not yet seen
+                    Some(Ordering::Equal) if to_skip == 0 => true,
+                    Some(Ordering::Equal) => {
+                        to_skip -= 1;
+                        false
                     }
+                    _ => false,
                 }
-            }
-        };
+            })
+            .collect()
+    }
 
-        if let Some(entry) = self.entries.last() {
-            if entry.timestamp_from_system_start.is_some() {
-                self.last_timestamp = entry.timestamp_from_system_start;
+    /// The entries of a poll that only read what's new (`KLogBuffer::unread_entries`
+    /// after the first, or a read clearing the buffer after the one before it): all of
+    /// them, timestamps or not, moving past them. Those with the same timestamp as the
+    /// last poll's last entry are counted on from it, as more seen at that timestamp.
+    pub fn unread_entries(&mut self, entries: Vec<Entry>) -> Vec<Entry> {
+        let last = entries
+            .iter()
+            .rev()
+            .find_map(|e| e.timestamp_from_system_start);
+        match last.is_some() && last == self.last_timestamp {
+            true => {
+                self.seen_at_last_timestamp += entries
+                    .iter()
+                    .filter(|e| e.timestamp_from_system_start == last)
+                    .count()
             }
+            false => self.advance(&entries),
         }
+        entries
+    }
 
-        Ok(entriesadded)
+    /// Moves past all of a poll's entries (as when they've all been read).
+    pub fn advance(&mut self, entries: &[Entry]) {
+        if let Some(last_timestamp) = entries
+            .iter()
+            .rev()
+            .find_map(|e| e.timestamp_from_system_start)
+        {
+            self.last_timestamp = Some(last_timestamp);
+            self.seen_at_last_timestamp = entries
+                .iter()
+                .filter(|e| e.timestamp_from_system_start == Some(last_timestamp))
+                .count();
+        }
     }
 }
 
@@ -220,26 +462,13 @@ impl Iterator for KLogEntries {
     /// NOT a thread-safe method either. It is suggested this method be always
     /// blocked on to ensure no messages are missed.
     fn next(&mut self) -> Option<Self::Item> {
-        while self.entries.is_empty() {
-            let elapsed = match self.last_poll.elapsed() {
-                Ok(duration) => duration,
-                Err(e) => return Some(Err(RMesgError::UnableToObtainElapsedTime(e))),
-            };
-
-            // Poll once if entering next and time since last poll
-            // is greater than interval
-            // This prevents lots of calls to next from hitting the kernel.
-            if elapsed >= self.poll_interval {
-                // poll once anyway
-                if let Err(e) = self.poll() {
-                    return Some(Err(e));
-                }
-            } else {
-                thread::sleep(self.sleep_interval);
+        loop {
+            match self.next_ready() {
+                Ok(Some(entry)) => return Some(Ok(entry)),
+                Ok(None) => thread::sleep(self.sleep_interval),
+                Err(e) => return Some(Err(e)),
             }
         }
-
-        Some(Ok(self.entries.remove(0)))
     }
 }
 
@@ -284,7 +513,94 @@ impl Stream for KLogEntries {
             }
         }
 
-        Poll::Ready(Some(Ok(self.entries.remove(0))))
+        Poll::Ready(self.entries.pop_front().map(Ok))
+    }
+}
+
+/// A byte buffer for reading the kernel log through klogctl, kept across reads.
+///
+/// Each read takes the whole ring (often megabytes), so a follower allocating a buffer
+/// for every poll puts as much pressure on the allocator each poll interval. This one is
+/// grown when the kernel's buffer is bigger than it, and is otherwise reused as it is.
+#[derive(Debug, Default)]
+pub struct KLogBuffer {
+    buffer: Vec<u8>,
+    // the end of the last incremental read, when it stopped partway through a line
+    partial: Vec<u8>,
+}
+
+impl KLogBuffer {
+    pub fn new() -> KLogBuffer {
+        KLogBuffer::default()
+    }
+
+    /// The size of the buffer, in bytes (that of the kernel's as of the last read).
+    pub fn capacity(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Reads the kernel log buffer (clearing it after if `clear` is set), as text
+    /// borrowed from this buffer until the next read.
+    pub fn read(&mut self, clear: bool) -> Result<&str, RMesgError> {
+        let kernel_buffer_size = kernel_buffer_size()?;
+        if self.buffer.len() < kernel_buffer_size {
+            self.buffer.resize(kernel_buffer_size, 0);
+        }
+
+        let klogtype = match clear {
+            true => KLogType::SyslogActionReadClear,
+            false => KLogType::SyslogActionReadAll,
+        };
+        let bytes_read = safely_wrapped_klogctl(klogtype, &mut self.buffer)?;
+        std::str::from_utf8(&self.buffer[..bytes_read])
+            .map_err(|e| RMesgError::Utf8StringConversionError(format!("{:?}", e)))
+    }
+
+    /// Reads and parses the kernel log buffer (see `read`).
+    pub fn entries(&mut self, clear: bool) -> Result<Vec<Entry>, RMesgError> {
+        Ok(entries_from_lines(self.read(clear)?)?)
+    }
+
+    /// Reads only what hasn't been read yet (`SyslogActionSizeUnread` bytes, through
+    /// `SyslogActionRead`), as whole lines: a line the kernel cut short is kept back,
+    /// and completed by the next read. Returns "" at once when there's nothing unread,
+    /// rather than blocking like `SyslogActionRead` would.
+    ///
+    /// The kernel keeps one unread position for everyone, so this consumes what it reads
+    /// for every other `SyslogActionRead` and /proc/kmsg reader (such as rsyslog's
+    /// imklog) as well. It doesn't affect /dev/kmsg readers, or `read`.
+    pub fn read_unread(&mut self) -> Result<&str, RMesgError> {
+        let mut dummy_buffer: Vec<u8> = vec![0; 0];
+        let unread = safely_wrapped_klogctl(KLogType::SyslogActionSizeUnread, &mut dummy_buffer)?;
+
+        if unread == 0 {
+            return Ok("");
+        }
+
+        let carried = self.partial.len();
+        if self.buffer.len() < carried + unread {
+            self.buffer.resize(carried + unread, 0);
+        }
+        self.buffer[..carried].copy_from_slice(&self.partial);
+        let end = carried
+            + safely_wrapped_klogctl(
+                KLogType::SyslogActionRead,
+                &mut self.buffer[carried..carried + unread],
+            )?;
+
+        let complete = match self.buffer[..end].iter().rposition(|b| *b == b'\n') {
+            Some(newline) => newline + 1,
+            None => 0,
+        };
+        self.partial.clear();
+        self.partial.extend_from_slice(&self.buffer[complete..end]);
+        std::str::from_utf8(&self.buffer[..complete])
+            .map_err(|e| RMesgError::Utf8StringConversionError(format!("{:?}", e)))
+    }
+
+    /// Reads and parses what hasn't been read yet (see `read_unread`).
+    pub fn unread_entries(&mut self) -> Result<Vec<Entry>, RMesgError> {
+        Ok(entries_from_lines(self.read_unread()?)?)
     }
 }
 
@@ -297,9 +613,7 @@ impl Stream for KLogEntries {
 /// whether or not "async" feature is enabled
 ///
 pub fn klog_raw(clear: bool) -> Result<String, RMesgError> {
-    let mut dummy_buffer: Vec<u8> = vec![0; 0];
-    let kernel_buffer_size =
-        safely_wrapped_klogctl(KLogType::SyslogActionSizeBuffer, &mut dummy_buffer)?;
+    let kernel_buffer_size = kernel_buffer_size()?;
 
     let klogtype = match clear {
         true => KLogType::SyslogActionReadClear,
@@ -313,7 +627,6 @@ pub fn klog_raw(clear: bool) -> Result<String, RMesgError> {
     real_buffer.resize(bytes_read, 0);
     let utf8_str = String::from_utf8(real_buffer)?;
 
-    // if incremental,
     Ok(utf8_str)
 }
 
@@ -326,8 +639,49 @@ pub fn klog_raw(clear: bool) -> Result<String, RMesgError> {
 /// whether or not "async" feature is enabled
 ///
 pub fn klog(clear: bool) -> Result<Vec<Entry>, RMesgError> {
-    let all_lines = klog_raw(clear)?;
-    Ok(entries_from_lines(&all_lines)?)
+    KLogBuffer::new().entries(clear)
+}
+
+/// Clears the kernel log buffer, returning how much was discarded so that
+/// audit logs can record it.
+///
+/// The contents are read and cleared in one `SyslogActionReadClear` call, so
+/// nothing logged in between can be discarded without being counted.
+pub fn klog_clear() -> Result<ClearedVolume, RMesgError> {
+    let mut dummy_buffer: Vec<u8> = vec![0; 0];
+    let unread_bytes = safely_wrapped_klogctl(KLogType::SyslogActionSizeUnread, &mut dummy_buffer)?;
+    let kernel_buffer_size = kernel_buffer_size()?;
+
+    let mut real_buffer: Vec<u8> = vec![0; kernel_buffer_size];
+    let bytes = safely_wrapped_klogctl(KLogType::SyslogActionReadClear, &mut real_buffer)?;
+
+    Ok(ClearedVolume {
+        bytes,
+        entries: common::count_records(&real_buffer[..bytes], |b| b == b'<'),
+        unread_bytes,
+    })
+}
+
+/// Counts the entries currently in the kernel log buffer without parsing them.
+/// Every record klogctl returns begins with a `<faclev>` prefix, so only lines
+/// starting with one are counted.
+///
+/// This is much cheaper than `klog(false)?.len()` for callers that only want
+/// to know "how many kernel messages are there" (e.g. dashboards).
+pub fn klog_count() -> Result<usize, RMesgError> {
+    let mut real_buffer: Vec<u8> = vec![0; kernel_buffer_size()?];
+    let bytes_read = safely_wrapped_klogctl(KLogType::SyslogActionReadAll, &mut real_buffer)?;
+
+    Ok(common::count_records(&real_buffer[..bytes_read], |b| {
+        b == b'<'
+    }))
+}
+
+/// The capacity of the kernel's log ring buffer, in bytes (set at boot with
+/// `log_buf_len=`). Monitoring can compare it with the volume being logged (say, as
+/// `klog_raw(false)?.len()`) to tell how much history the buffer holds before it wraps.
+pub fn kernel_buffer_size() -> Result<usize, RMesgError> {
+    safely_wrapped_klogctl(KLogType::SyslogActionSizeBuffer, &mut [])
 }
 
 /// This function checks whether or not timestamps are enabled in the Linux Kernel log entries.
@@ -338,6 +692,12 @@ pub fn klog_timestamps_enabled() -> Result<bool, RMesgError> {
         == "Y")
 }
 
+// Whether klogctl is the only way to follow the log here, so that fast polling can't be
+// avoided by following /dev/kmsg instead
+fn klogctl_only() -> bool {
+    !cfg!(feature = "kmsg") || !std::path::Path::new("/dev/kmsg").exists()
+}
+
 /// This function can enable or disable whether or not timestamps are enabled in the Linux Kernel log entries.
 pub fn klog_timestamps_enable(desired: bool) -> Result<(), RMesgError> {
     Ok(fs::write(
@@ -349,50 +709,106 @@ pub fn klog_timestamps_enable(desired: bool) -> Result<(), RMesgError> {
     )?)
 }
 
+/// The least severe level printed to the console (entries at it, or more severe, are).
+/// klogctl can set the console log level but not read it, so this reads it from
+/// /proc/sys/kernel/printk.
+pub fn console_level() -> Result<LogLevel, RMesgError> {
+    console_level_from_printk(&fs::read_to_string(PROC_SYS_KERNEL_PRINTK)?)
+}
+
+/// Sets the least severe level printed to the console, like `dmesg -n`:
+/// `LogLevel::Emergency` quiets all but panics, `LogLevel::Debug` prints everything.
+/// Goes through klogctl, falling back to writing /proc/sys/kernel/printk when that fails
+/// (both need privileges). The kernel won't go below its minimum console level (usually
+/// `LogLevel::Emergency`), and doesn't say when it has raised a level up to it.
+pub fn set_console_level(level: LogLevel) -> Result<(), RMesgError> {
+    // the kernel's console log level is one past the least severe level it prints
+    let console_loglevel = level as libc::c_int + 1;
+    klogctl_console(KLogType::SyslogActionConsoleLevel, console_loglevel).or_else(|err| {
+        fs::write(PROC_SYS_KERNEL_PRINTK, format!("{}\n", console_loglevel)).map_err(|e| {
+            RMesgError::InternalError(format!(
+                "{}, and so did writing {}: {}",
+                err, PROC_SYS_KERNEL_PRINTK, e
+            ))
+        })
+    })
+}
+
+/// Stops the kernel printing to the console (but the most severe messages, below its
+/// minimum console level), remembering the console level to restore with `console_on`.
+/// For tooling that owns the console for a while, like an installer's prompts.
+pub fn console_off() -> Result<(), RMesgError> {
+    klogctl_console(KLogType::SyslogActionConsoleOff, 0)
+}
+
+/// Restores the console level `console_off` remembered. Without a `console_off` before
+/// it (or with a `set_console_level` since), this leaves the console level as it is.
+pub fn console_on() -> Result<(), RMesgError> {
+    klogctl_console(KLogType::SyslogActionConsoleOn, 0)
+}
+
+// The console actions take no buffer, only (for the console level) a length, so they
+// don't go through safely_wrapped_klogctl
+fn klogctl_console(klogtype: KLogType, len: libc::c_int) -> Result<(), RMesgError> {
+    if cfg!(not(target_os = "linux")) {
+        return Err(RMesgError::NotImplementedForThisPlatform);
+    }
+    let response = unsafe { klogctl(klogtype.clone() as libc::c_int, std::ptr::null_mut(), len) };
+    if response >= 0 {
+        return Ok(());
+    }
+
+    let err = errno();
+    match err.0 {
+        libc::ENOSYS => Err(RMesgError::NotImplementedForThisPlatform),
+        libc::EPERM => Err(RMesgError::InternalError(format!(
+            "Request ({}) to klogctl failed: controlling the console needs CAP_SYSLOG (or CAP_SYS_ADMIN). errno={}",
+            klogtype, err
+        ))),
+        _ => Err(RMesgError::InternalError(format!(
+            "Request ({}) to klogctl failed. errno={}",
+            klogtype, err
+        ))),
+    }
+}
+
+// The first of printk's four values is the console log level: the kernel prints what's
+// more severe than it, so 1 is emergencies only, and 8 or more (booting with `debug` sets
+// 10) everything
+fn console_level_from_printk(printk: &str) -> Result<LogLevel, RMesgError> {
+    let console_loglevel = printk
+        .split_whitespace()
+        .next()
+        .and_then(|level| level.parse::<u32>().ok())
+        .ok_or_else(|| {
+            RMesgError::InternalError(format!(
+                "Unable to read the console log level from {}: {:?}",
+                PROC_SYS_KERNEL_PRINTK, printk
+            ))
+        })?;
+    match console_loglevel {
+        0 => Err(RMesgError::InternalError(
+            "The console log level is 0, below every level (nothing is printed to the console)"
+                .to_owned(),
+        )),
+        n => Ok(LogLevel::from_u32(n.min(8) - 1).unwrap_or(LogLevel::Debug)),
+    }
+}
+
 // Message spec: https://github.com/torvalds/linux/blob/master/Documentation/ABI/testing/dev-kmsg
 // Parses a kernel log line that looks like this (we ignore lines wtihout the timestamp):
 // <5>a.out[4054]: segfault at 7ffd5503d358 ip 00007ffd5503d358 sp 00007ffd5503d258 error 15
 // OR
 // <5>[   233434.343533] a.out[4054]: segfault at 7ffd5503d358 ip 00007ffd5503d358 sp 00007ffd5503d258 error 15
 pub fn entries_from_lines(all_lines: &str) -> Result<Vec<Entry>, EntryParsingError> {
-    let entry_results: Result<Vec<Entry>, EntryParsingError> = all_lines
-        .lines()
-        .map(|line| entry_from_line(line))
-        .collect();
+    let entry_results: Result<Vec<Entry>, EntryParsingError> =
+        all_lines.lines().map(entry_from_line).collect();
 
-    Ok(entry_results?)
+    entry_results
 }
 
 pub fn entry_from_line(line: &str) -> Result<Entry, EntryParsingError> {
-    if let Some(klogparts) = RE_ENTRY_WITH_TIMESTAMP.captures(line) {
-        let (facility, level) = match klogparts.name("faclevstr") {
-            Some(faclevstr) => common::parse_favlecstr(faclevstr.as_str(), line)?,
-            None => (None, None),
-        };
-
-        let timestamp_from_system_start = match klogparts.name("timestampstr") {
-            Some(timestampstr) => common::parse_timestamp_secs(timestampstr.as_str(), line)?,
-            None => None,
-        };
-
-        let message = klogparts["message"].to_owned();
-
-        Ok(Entry {
-            facility,
-            level,
-            sequence_num: None,
-            timestamp_from_system_start,
-            message,
-        })
-    } else {
-        Ok(Entry {
-            facility: None,
-            level: None,
-            sequence_num: None,
-            timestamp_from_system_start: None,
-            message: line.to_owned(),
-        })
-    }
+    parse::console_entry_from_line(line)
 }
 
 // ************************** Private
@@ -453,8 +869,7 @@ mod test {
 
     #[test]
     fn get_kernel_buffer_size() {
-        let mut dummy_buffer: Vec<u8> = vec![0; 0];
-        let response = safely_wrapped_klogctl(KLogType::SyslogActionSizeBuffer, &mut dummy_buffer);
+        let response = kernel_buffer_size();
         assert!(response.is_ok(), "Response from klogctl not Ok");
         assert!(
             response.unwrap() > 0,
@@ -462,6 +877,102 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_klog_count() {
+        let count = klog_count();
+        assert!(count.is_ok(), "Response from klog_count not Ok");
+        assert!(count.unwrap() > 0, "Should have non-zero entries");
+    }
+
+    #[test]
+    fn test_console_level_from_printk() {
+        assert_eq!(
+            console_level_from_printk("4\t4\t1\t7\n").unwrap(),
+            LogLevel::Error
+        );
+        assert_eq!(
+            console_level_from_printk("1 4 1 7").unwrap(),
+            LogLevel::Emergency
+        );
+        assert_eq!(
+            console_level_from_printk("10\t4\t1\t7\n").unwrap(),
+            LogLevel::Debug
+        );
+        assert!(console_level_from_printk("0\t4\t1\t7\n").is_err());
+        assert!(console_level_from_printk("").is_err());
+    }
+
+    #[test]
+    fn test_console_level() {
+        let original = console_level().unwrap();
+        if set_console_level(LogLevel::Error).is_err() {
+            // needs CAP_SYSLOG, or root for the fallback
+            return;
+        }
+        assert_eq!(console_level().unwrap(), LogLevel::Error);
+        set_console_level(original).unwrap();
+        assert_eq!(console_level().unwrap(), original);
+
+        // in the same test, so as not to race changing the level in another
+        console_off().unwrap();
+        // down to the minimum console level, whatever the kernel has it at
+        assert!(console_level().unwrap() <= original);
+        console_on().unwrap();
+        assert_eq!(console_level().unwrap(), original);
+    }
+
+    #[test]
+    fn test_adaptive_intervals() {
+        let polling = AdaptivePolling::new(
+            PollInterval::from_millis(100).unwrap(),
+            PollInterval::from_millis(500).unwrap(),
+        )
+        .unwrap();
+        let (min, max) = (polling.min_interval.get(), polling.max_interval.get());
+        let idle = |current| polling.next_interval(current, false);
+        assert_eq!(idle(min), Duration::from_millis(200));
+        assert_eq!(idle(Duration::from_millis(400)), max);
+        assert_eq!(idle(max), max);
+        assert_eq!(polling.next_interval(max, true), min);
+        assert_eq!(idle(Duration::MAX), max);
+
+        // bounds the wrong way round are refused, however they're put together
+        assert!(matches!(
+            AdaptivePolling::new(polling.max_interval, polling.min_interval),
+            Err(RMesgError::ConfigError(_))
+        ));
+        let reversed = AdaptivePolling {
+            min_interval: polling.max_interval,
+            max_interval: polling.min_interval,
+        };
+        assert!(matches!(
+            KLogEntries::with_adaptive_polling(false, reversed),
+            Err(RMesgError::ConfigError(_))
+        ));
+    }
+
+    #[test]
+    fn test_adaptive_polling() {
+        let polling = AdaptivePolling::new(
+            PollInterval::from_millis(10).unwrap(),
+            PollInterval::from_secs(1).unwrap(),
+        )
+        .unwrap();
+        let mut entries = KLogEntries::with_adaptive_polling(false, polling).unwrap();
+        assert_eq!(entries.poll_interval(), polling.min_interval.get());
+
+        // the first poll finds the whole buffer
+        assert!(entries.poll().unwrap() > 0);
+        assert_eq!(entries.poll_interval(), polling.min_interval.get());
+
+        // and the next usually finds nothing new
+        let expected = match entries.poll().unwrap() {
+            0 => Duration::from_millis(20),
+            _ => polling.min_interval.get(),
+        };
+        assert_eq!(entries.poll_interval(), expected);
+    }
+
     #[test]
     fn test_klog() {
         let entries = klog(false);
@@ -469,6 +980,52 @@ mod test {
         assert!(!entries.unwrap().is_empty(), "Should have non-empty logs");
     }
 
+    #[test]
+    fn test_buffer_reused() {
+        let mut buffer = KLogBuffer::new();
+        let first = buffer.read(false).unwrap().as_ptr();
+        let capacity = buffer.capacity();
+        assert!(capacity > 0);
+        assert!(!buffer.entries(false).unwrap().is_empty());
+        assert_eq!(buffer.read(false).unwrap().as_ptr(), first);
+        assert_eq!(buffer.capacity(), capacity);
+    }
+
+    #[test]
+    fn test_incremental() {
+        assert!(matches!(
+            KLogEntries::with_options(true, SUGGESTED_POLL_INTERVAL)
+                .unwrap()
+                .incremental(),
+            Err(RMesgError::ConfigError(_))
+        ));
+
+        // reading what's unread needs privileges the test may not have
+        let mut buffer = KLogBuffer::new();
+        if buffer.read_unread().is_err() {
+            return;
+        }
+        // whole lines only, ending where the unread ones do
+        let unread = buffer.read_unread().unwrap();
+        assert!(unread.is_empty() || unread.ends_with('\n'));
+
+        let mut entries = KLogEntries::with_options(false, SUGGESTED_POLL_INTERVAL)
+            .unwrap()
+            .incremental()
+            .unwrap();
+        assert!(entries.poll().unwrap() > 0);
+        let history = entries.buffered();
+        // and then only what's new, without repeating any of the history
+        entries.poll().unwrap();
+        let seen: Vec<Entry> = entries.entries.drain(..).collect();
+        assert!(
+            seen[history..]
+                .iter()
+                .all(|e| e.timestamp_from_system_start
+                    > seen[history - 1].timestamp_from_system_start)
+        );
+    }
+
     #[cfg(feature = "sync")]
     #[test]
     fn test_iterator() {
@@ -519,23 +1076,103 @@ mod test {
     fn test_parse_serialize() {
         let line1 = "<6>a.out[4054]: segfault at 7ffd5503d358 ip 00007ffd5503d358 sp 00007ffd5503d258 error 15";
         let entries1 = entries_from_lines(line1).unwrap();
-        let e1r = entries1.get(0).unwrap();
+        let e1r = entries1.first().unwrap();
         let line1again = e1r.to_klog_str().unwrap();
         assert_eq!(line1, line1again);
 
         let line2 = "<7>[   233434.343533] a.out[4054]: segfault at 7ffd5503d358 ip 00007ffd5503d358 sp 00007ffd5503d258 error 15";
         let entries2 = entries_from_lines(line2).unwrap();
-        let e2r = entries2.get(0).unwrap();
+        let e2r = entries2.first().unwrap();
         let line2again = e2r.to_klog_str().unwrap();
         assert_eq!(line2, line2again);
 
         let line3 = "233434.343533] a.out[4054]: segfault at 7ffd5503d358 ip 00007ffd5503d358 sp 00007ffd5503d258 error 15";
         let entries3 = entries_from_lines(line3).unwrap();
-        let e3r = entries3.get(0).unwrap();
+        let e3r = entries3.first().unwrap();
         let line3again = e3r.to_klog_str().unwrap();
         assert_eq!(line3, line3again);
     }
 
+    #[test]
+    fn test_poll_cursor() {
+        let entry = |ms: Option<u64>, message: &str| Entry {
+            timestamp_from_system_start: ms.map(Duration::from_millis),
+            message: message.to_owned(),
+            ..Default::default()
+        };
+        let messages = |entries: &[Entry]| -> Vec<String> {
+            entries.iter().map(|e| e.message.clone()).collect()
+        };
+
+        let mut buffer = vec![
+            entry(Some(1), "eth0: link up"),
+            entry(Some(2), "ratelimited"),
+            entry(Some(2), "ratelimited"),
+        ];
+        let mut cursor = PollCursor::default();
+        assert_eq!(cursor.newer_entries(buffer.clone()).len(), 3);
+        assert_eq!(cursor.last_timestamp(), Some(Duration::from_millis(2)));
+
+        // logged in the same millisecond as the last two, after the poll
+        buffer.push(entry(Some(2), "ratelimited"));
+        buffer.push(entry(None, "no timestamp"));
+        buffer.push(entry(Some(3), "eth0: link down"));
+        assert_eq!(
+            messages(&cursor.newer_entries(buffer.clone())),
+            vec!["ratelimited", "eth0: link down"]
+        );
+        assert!(cursor.newer_entries(buffer.clone()).is_empty());
+
+        // the buffer wrapping doesn't bring anything back
+        buffer.drain(..2);
+        buffer.push(entry(Some(3), "eth0: link down"));
+        assert_eq!(
+            messages(&cursor.newer_entries(buffer)),
+            vec!["eth0: link down"]
+        );
+    }
+
+    #[test]
+    fn test_poll_cursor_unread() {
+        let entry = |ms: Option<u64>, message: &str| Entry {
+            timestamp_from_system_start: ms.map(Duration::from_millis),
+            message: message.to_owned(),
+            ..Default::default()
+        };
+        let messages = |entries: &[Entry]| -> Vec<String> {
+            entries.iter().map(|e| e.message.clone()).collect()
+        };
+
+        let mut cursor = PollCursor::default();
+        let history = vec![entry(Some(5), "ratelimited"), entry(Some(5), "ratelimited")];
+        assert_eq!(cursor.newer_entries(history.clone()).len(), 2);
+
+        // only what's new is read, so one more in the same millisecond is new too
+        assert_eq!(
+            messages(&cursor.unread_entries(vec![entry(Some(5), "ratelimited")])),
+            vec!["ratelimited"]
+        );
+        assert_eq!(
+            messages(&cursor.unread_entries(vec![
+                entry(Some(5), "ratelimited"),
+                entry(None, "no timestamp"),
+            ])),
+            vec!["ratelimited", "no timestamp"]
+        );
+        assert!(cursor.unread_entries(Vec::new()).is_empty());
+
+        // all four at that millisecond have been seen, should the whole buffer be read
+        let mut buffer = history;
+        buffer.push(entry(Some(5), "ratelimited"));
+        buffer.push(entry(Some(5), "ratelimited"));
+        buffer.push(entry(Some(5), "ratelimited"));
+        buffer.push(entry(Some(6), "eth0: link down"));
+        assert_eq!(
+            messages(&cursor.newer_entries(buffer)),
+            vec!["ratelimited", "eth0: link down"]
+        );
+    }
+
     #[test]
     fn test_parse_multiline() {
         let line1 = "<6>a.out[4054]: segfault at 7ffd5503d358 ip 00007ffd5503d358 sp 00007ffd5503d258 error 15";
@@ -10,11 +10,12 @@ use crate::entry::{Entry, EntryParsingError};
 /// This allows Rust programs to consume dmesg-like output programmatically.
 ///
 use crate::error::RMesgError;
+use crate::parse::{self, ParseMode};
 
-use lazy_static::lazy_static;
 use nonblock::NonBlockingReader;
-use regex::Regex;
 use std::fs as stdfs;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
 
 #[cfg(feature = "sync")]
 use std::io as stdio;
@@ -36,22 +37,90 @@ use tokio::io as tokioio;
 #[cfg(feature = "async")]
 use tokio::io::AsyncBufReadExt;
 
-const DEV_KMSG_PATH: &str = "/dev/kmsg";
-lazy_static! {
-    static ref RE_ENTRY_WITH_TIMESTAMP: Regex = Regex::new(
-        r"(?x)^
-            [[:space:]]*(?P<faclevstr>[[:digit:]]*)[[:space:]]*,
-            # Sequence is a 64-bit integer: https://www.kernel.org/doc/Documentation/ABI/testing/dev-kmsg
-            [[:space:]]*(?P<sequencenum>[[:digit:]]*)[[:space:]]*,
-            [[:space:]]*(?P<timestampstr>[[:digit:]]*)[[:space:]]*,
-            # Ignore everything until the semi-colon and then the semicolon
-            [[^;]]*;
-            (?P<message>.*)
-            $"
-    )
-    .unwrap();
+pub(crate) const DEV_KMSG_PATH: &str = "/dev/kmsg";
+
+/// Where in the buffer to start reading /dev/kmsg.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum StartPosition {
+    /// The oldest entry still in the buffer
+    #[default]
+    Oldest,
+    /// Only entries logged from now on
+    Newest,
+    /// The first entry logged since the buffer was last cleared (by anything, e.g.
+    /// `dmesg -c`), which /dev/kmsg exposes as SEEK_DATA. Reading from Oldest
+    /// ignores clears, since clearing doesn't remove entries from /dev/kmsg.
+    SinceLastClear,
+    /// The first entry with this sequence number or a later one
+    Sequence(usize),
+    /// The first entry logged at or after this time since system start
+    Time(Duration),
 }
 
+fn seek_to(file: &stdfs::File, path: &str, start: StartPosition) -> Result<(), RMesgError> {
+    let whence = match start {
+        StartPosition::Newest => libc::SEEK_END,
+        StartPosition::SinceLastClear => libc::SEEK_DATA,
+        // sequence numbers and times are found by skipping from the oldest entry
+        _ => libc::SEEK_SET,
+    };
+    match unsafe { libc::lseek(file.as_raw_fd(), 0, whence) } {
+        offset if offset < 0 => Err(RMesgError::DevKMsgFileOpenError(format!(
+            "Unable to seek to {:?} in file {}: {}",
+            start,
+            path,
+            std::io::Error::last_os_error()
+        ))),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn open_at(path: &str, start: StartPosition) -> Result<stdfs::File, RMesgError> {
+    let file = match stdfs::File::open(path) {
+        Ok(fc) => fc,
+        Err(e) => {
+            return Err(RMesgError::DevKMsgFileOpenError(format!(
+                "Unable to open file {}: {}",
+                path, e
+            )))
+        }
+    };
+    seek_to(&file, path, start)?;
+    Ok(file)
+}
+
+// Skips the records before a sequence number or time. The buffer is in order, so
+// once one record is at or after the start, every later one is too.
+#[derive(Debug)]
+struct StartFilter {
+    start: StartPosition,
+    started: bool,
+}
+
+impl StartFilter {
+    fn new(start: StartPosition) -> StartFilter {
+        StartFilter {
+            start,
+            started: !matches!(start, StartPosition::Sequence(_) | StartPosition::Time(_)),
+        }
+    }
+
+    fn skip(&mut self, line: &str) -> bool {
+        if self.started {
+            return false;
+        }
+        // continuation lines (and anything unparseable) before the start belong to
+        // records being skipped
+        self.started = entry_from_line(line).is_ok_and(|entry| match self.start {
+            StartPosition::Sequence(seq) => entry.sequence_num.is_some_and(|s| s >= seq),
+            StartPosition::Time(time) => {
+                entry.timestamp_from_system_start.is_some_and(|t| t >= time)
+            }
+            _ => true,
+        });
+        !self.started
+    }
+}
 /// While reading the kernel log buffer is very useful in and of itself (expecially when running the CLI),
 /// a lot more value is unlocked when it can be tailed line-by-line.
 ///
@@ -63,6 +132,8 @@ lazy_static! {
 #[cfg(feature = "sync")]
 pub struct KMsgEntriesIter {
     raw: bool,
+    start: StartFilter,
+    mode: ParseMode,
     lines_iter: stdio::Lines<stdio::BufReader<stdfs::File>>,
 }
 
@@ -72,21 +143,32 @@ impl KMsgEntriesIter {
     /// `file_override`: When `Some`, overrides the path from where to read the kernel logs
     /// `raw: bool` When set, does not parse the message and instead sets the entire log entry in the "message" field
     pub fn with_options(file_override: Option<String>, raw: bool) -> Result<Self, RMesgError> {
-        let path = file_override.as_deref().unwrap_or(DEV_KMSG_PATH);
+        Self::with_start(file_override, raw, StartPosition::Oldest)
+    }
 
-        let file = match stdfs::File::open(path) {
-            Ok(fc) => fc,
-            Err(e) => {
-                return Err(RMesgError::DevKMsgFileOpenError(format!(
-                    "Unable to open file {}: {}",
-                    path, e
-                )))
-            }
-        };
+    /// Like `with_options`, starting from `start` rather than the oldest entry.
+    pub fn with_start(
+        file_override: Option<String>,
+        raw: bool,
+        start: StartPosition,
+    ) -> Result<Self, RMesgError> {
+        let path = file_override.as_deref().unwrap_or(DEV_KMSG_PATH);
+        let file = open_at(path, start)?;
 
         let lines_iter = stdio::BufReader::new(file).lines();
 
-        Ok(Self { raw, lines_iter })
+        Ok(Self {
+            raw,
+            start: StartFilter::new(start),
+            mode: ParseMode::Strict,
+            lines_iter,
+        })
+    }
+
+    /// Handles malformed records as `mode` says (by default, `ParseMode::Strict`).
+    pub fn with_parse_mode(mut self, mode: ParseMode) -> Self {
+        self.mode = mode;
+        self
     }
 }
 
@@ -99,25 +181,35 @@ impl Iterator for KMsgEntriesIter {
     /// NOT a thread-safe method either. It is suggested this method be always
     /// blocked on to ensure no messages are missed.
     fn next(&mut self) -> Option<Self::Item> {
-        match self.lines_iter.next() {
-            None => None,
-            Some(Err(e)) => Some(Err(RMesgError::IOError(format!(
-                "Error reading next line from kernel log device file: {}",
-                e
-            )))),
-            Some(Ok(line)) => {
-                if self.raw {
-                    Some(Ok(Entry {
-                        facility: None,
-                        level: None,
-                        timestamp_from_system_start: None,
-                        sequence_num: None,
-                        message: line,
-                    }))
-                } else {
-                    Some(entry_from_line(&line).map_err(|e| e.into()))
+        let line = loop {
+            match self.lines_iter.next() {
+                None => return None,
+                Some(Err(e)) => {
+                    return Some(Err(RMesgError::IOError(format!(
+                        "Error reading next line from kernel log device file: {}",
+                        e
+                    ))))
                 }
+                Some(Ok(line)) if self.start.skip(&line) => continue,
+                Some(Ok(line)) => break line,
             }
+        };
+
+        if self.raw {
+            Some(Ok(Entry {
+                facility: None,
+                level: None,
+                timestamp_from_system_start: None,
+                sequence_num: None,
+                message: line,
+                timestamp_realtime: None,
+                provenance: None,
+                malformed: false,
+                priority: None,
+                tags: Default::default(),
+            }))
+        } else {
+            Some(parse::kmsg_entry(&line, self.mode).map_err(|e| e.into()))
         }
     }
 }
@@ -133,6 +225,8 @@ impl Iterator for KMsgEntriesIter {
 #[cfg(feature = "async")]
 pub struct KMsgEntriesStream {
     raw: bool,
+    start: StartFilter,
+    mode: ParseMode,
 
     lines_stream: Pin<Box<tokioio::Lines<tokioio::BufReader<tokiofs::File>>>>,
 }
@@ -145,6 +239,15 @@ impl KMsgEntriesStream {
     pub async fn with_options(
         file_override: Option<String>,
         raw: bool,
+    ) -> Result<Self, RMesgError> {
+        Self::with_start(file_override, raw, StartPosition::Oldest).await
+    }
+
+    /// Like `with_options`, starting from `start` rather than the oldest entry.
+    pub async fn with_start(
+        file_override: Option<String>,
+        raw: bool,
+        start: StartPosition,
     ) -> Result<Self, RMesgError> {
         let path = file_override.as_deref().unwrap_or(DEV_KMSG_PATH);
 
@@ -170,10 +273,21 @@ impl KMsgEntriesStream {
         }
 
         // create a new lines_stream with a new file
-        let lines_stream =
-            Box::pin(tokioio::BufReader::new(tokiofs::File::open(path).await?).lines());
+        let file = tokiofs::File::from_std(open_at(path, start)?);
+        let lines_stream = Box::pin(tokioio::BufReader::new(file).lines());
+
+        Ok(Self {
+            raw,
+            start: StartFilter::new(start),
+            mode: ParseMode::Strict,
+            lines_stream,
+        })
+    }
 
-        Ok(Self { raw, lines_stream })
+    /// Handles malformed records as `mode` says (by default, `ParseMode::Strict`).
+    pub fn with_parse_mode(mut self, mode: ParseMode) -> Self {
+        self.mode = mode;
+        self
     }
 }
 
@@ -183,58 +297,89 @@ impl Stream for KMsgEntriesStream {
     type Item = Result<Entry, RMesgError>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        match self.lines_stream.as_mut().poll_next_line(cx) {
-            Poll::Pending => Poll::Pending,
-            Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e.into()))),
-            Poll::Ready(Ok(None)) => Poll::Ready(None),
-            Poll::Ready(Ok(Some(line))) => {
-                let value = if self.raw {
-                    Some(Ok(Entry {
-                        facility: None,
-                        level: None,
-                        timestamp_from_system_start: None,
-                        sequence_num: None,
-                        message: line,
-                    }))
-                } else {
-                    Some(entry_from_line(&line).map_err(|e| e.into()))
-                };
-
-                Poll::Ready(value)
+        let line = loop {
+            match self.lines_stream.as_mut().poll_next_line(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                Poll::Ready(Ok(None)) => return Poll::Ready(None),
+                Poll::Ready(Ok(Some(line))) if self.start.skip(&line) => continue,
+                Poll::Ready(Ok(Some(line))) => break line,
             }
-        }
+        };
+
+        let value = if self.raw {
+            Some(Ok(Entry {
+                facility: None,
+                level: None,
+                timestamp_from_system_start: None,
+                sequence_num: None,
+                message: line,
+                timestamp_realtime: None,
+                provenance: None,
+                malformed: false,
+                priority: None,
+                tags: Default::default(),
+            }))
+        } else {
+            Some(parse::kmsg_entry(&line, self.mode).map_err(|e| e.into()))
+        };
+
+        Poll::Ready(value)
     }
 }
 
 pub fn kmsg_raw(file_override: Option<String>) -> Result<String, RMesgError> {
-    let path = file_override.as_deref().unwrap_or(DEV_KMSG_PATH);
+    kmsg_raw_from(file_override, StartPosition::Oldest)
+}
 
-    let file = match stdfs::File::open(path) {
-        Ok(fc) => fc,
-        Err(e) => {
-            return Err(RMesgError::DevKMsgFileOpenError(format!(
-                "Unable to open file {}: {}",
-                path, e
-            )))
-        }
-    };
+/// The records currently in the kernel log buffer from `start` on, unparsed.
+pub fn kmsg_raw_from(
+    file_override: Option<String>,
+    start: StartPosition,
+) -> Result<String, RMesgError> {
+    let path = file_override.as_deref().unwrap_or(DEV_KMSG_PATH);
+    let file = open_at(path, start)?;
 
     let mut noblock_file = NonBlockingReader::from_fd(file)?;
 
     let mut file_contents = String::new();
-    match noblock_file.read_available_to_string(&mut file_contents) {
-        Ok(_) => {}
-        Err(e) => {
-            return Err(RMesgError::DevKMsgFileOpenError(format!(
-                "Unable to open file {}: {}",
-                path, e
-            )))
+    loop {
+        match noblock_file.read_available_to_string(&mut file_contents) {
+            Ok(_) => break,
+            // the records from the start onwards were overwritten (e.g. since the last
+            // clear), and the kernel has moved on to the oldest one left
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => continue,
+            Err(e) => {
+                return Err(RMesgError::DevKMsgFileOpenError(format!(
+                    "Unable to open file {}: {}",
+                    path, e
+                )))
+            }
         }
     }
 
+    let mut start = StartFilter::new(start);
+    if !start.started {
+        file_contents = file_contents
+            .lines()
+            .filter(|line| !start.skip(line))
+            .map(|line| format!("{}\n", line))
+            .collect();
+    }
+
     Ok(file_contents)
 }
 
+/// Counts the records currently in the kernel log buffer without parsing them.
+/// Lines beginning with a space are the key/value dictionary belonging to the
+/// record before them, so they are not counted.
+pub fn kmsg_count(file_override: Option<String>) -> Result<usize, RMesgError> {
+    let file_contents = kmsg_raw(file_override)?;
+    Ok(common::count_records(file_contents.as_bytes(), |b| {
+        b != b' '
+    }))
+}
+
 /// This is the key safe function that makes the klogctl syslog call with parameters.
 /// While the internally used function supports all klogctl parameters, this function
 /// only provides one bool parameter which indicates whether the buffer is to be cleared
@@ -244,58 +389,25 @@ pub fn kmsg_raw(file_override: Option<String>) -> Result<String, RMesgError> {
 /// whether or not "async" feature is enabled
 ///
 pub fn kmsg(file_override: Option<String>) -> Result<Vec<Entry>, RMesgError> {
-    let file_contents = kmsg_raw(file_override)?;
-    let entry_results: Result<Vec<Entry>, EntryParsingError> = file_contents
-        .lines()
-        .map(|line| entry_from_line(line))
-        .collect();
+    kmsg_from(file_override, StartPosition::Oldest)
+}
+
+/// Like `kmsg`, reading the entries from `start` on.
+pub fn kmsg_from(
+    file_override: Option<String>,
+    start: StartPosition,
+) -> Result<Vec<Entry>, RMesgError> {
+    let file_contents = kmsg_raw_from(file_override, start)?;
+    let entry_results: Result<Vec<Entry>, EntryParsingError> =
+        file_contents.lines().map(entry_from_line).collect();
 
     Ok(entry_results?)
 }
 
 // Message spec: https://github.com/torvalds/linux/blob/master/Documentation/ABI/testing/dev-kmsg
-// Parses a kernel log line that looks like this (we ignore lines wtihout the timestamp):
-// 5,0,0,-;Linux version 4.14.131-linuxkit (root@6d384074ad24) (gcc version 8.3.0 (Alpine 8.3.0)) #1 SMP Fri Jul 19 12:31:17 UTC 2019
-// 6,1,0,-;Command, line: BOOT_IMAGE=/boot/kernel console=ttyS0 console=ttyS1 page_poison=1 vsyscall=emulate panic=1 root=/dev/sr0 text
-//  LINE2=foobar
-//  LINE 3 = foobar ; with semicolon
-// 6,2,0,-;x86/fpu: Supporting XSAVE feature 0x001: 'x87 floating point registers'
-// 6,3,0,-,more,deets;x86/fpu: Supporting XSAVE; feature 0x002: 'SSE registers'
+// See `parse::kmsg_entry_from_line` for the format.
 pub fn entry_from_line(line: &str) -> Result<Entry, EntryParsingError> {
-    if let Some(kmsgparts) = RE_ENTRY_WITH_TIMESTAMP.captures(line) {
-        let (facility, level) = match kmsgparts.name("faclevstr") {
-            Some(faclevstr) => common::parse_favlecstr(faclevstr.as_str(), line)?,
-            None => (None, None),
-        };
-
-        let sequence_num = match kmsgparts.name("sequencenum") {
-            Some(sequencestr) => Some(common::parse_fragment::<usize>(sequencestr.as_str(), line)?),
-            None => None,
-        };
-
-        let timestamp_from_system_start = match kmsgparts.name("timestampstr") {
-            Some(timestampstr) => common::parse_timestamp_microsecs(timestampstr.as_str(), line)?,
-            None => None,
-        };
-
-        let message = kmsgparts["message"].to_owned();
-
-        Ok(Entry {
-            facility,
-            level,
-            sequence_num,
-            timestamp_from_system_start,
-            message,
-        })
-    } else {
-        Ok(Entry {
-            facility: None,
-            level: None,
-            sequence_num: None,
-            timestamp_from_system_start: None,
-            message: line.to_owned(),
-        })
-    }
+    parse::kmsg_entry_from_line(line)
 }
 
 /**********************************************************************************/
@@ -313,6 +425,19 @@ mod test {
         assert!(!entries.unwrap().is_empty(), "Should have non-empty logs");
     }
 
+    #[test]
+    fn test_kmsg_count() {
+        let count = kmsg_count(None);
+        assert!(count.is_ok(), "Response from kmsg_count not Ok");
+        assert!(count.unwrap() > 0, "Should have non-zero entries");
+    }
+
+    #[test]
+    fn test_count_skips_dictionary() {
+        let raw = "6,1,0,-;Command line: foo\n SUBSYSTEM=net\n DEVICE=n2\n6,2,0,-;x86/fpu: bar\n";
+        assert_eq!(common::count_records(raw.as_bytes(), |b| b != b' '), 2);
+    }
+
     #[cfg(feature = "sync")]
     #[test]
     fn test_iterator() {
@@ -359,6 +484,79 @@ mod test {
         }
     }
 
+    fn kmsg_file(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("rmesg-kmsg-{}-{}", name, std::process::id()));
+        stdfs::write(
+            &path,
+            "6,1,1000000,-;first\n SUBSYSTEM=pci\n6,2,2000000,-;second\n SUBSYSTEM=usb\n6,3,3000000,-;third\n",
+        )
+        .unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn messages(entries: Vec<Entry>) -> Vec<String> {
+        entries
+            .into_iter()
+            .filter(|e| e.sequence_num.is_some())
+            .map(|e| e.message)
+            .collect()
+    }
+
+    #[test]
+    fn test_start_positions() {
+        let path = kmsg_file("start");
+        let from = |start| messages(kmsg_from(Some(path.clone()), start).unwrap());
+
+        assert_eq!(
+            from(StartPosition::Oldest),
+            vec!["first", "second", "third"]
+        );
+        assert!(from(StartPosition::Newest).is_empty());
+        assert_eq!(from(StartPosition::Sequence(2)), vec!["second", "third"]);
+        assert_eq!(
+            from(StartPosition::Time(Duration::from_millis(2500))),
+            vec!["third"]
+        );
+        assert!(from(StartPosition::Sequence(4)).is_empty());
+        // dictionary lines of the skipped records are skipped too
+        assert_eq!(
+            kmsg_raw_from(Some(path.clone()), StartPosition::Sequence(3)).unwrap(),
+            "6,3,3000000,-;third\n"
+        );
+
+        #[cfg(feature = "sync")]
+        {
+            let iter =
+                KMsgEntriesIter::with_start(Some(path.clone()), false, StartPosition::Sequence(2))
+                    .unwrap();
+            let entries: Vec<Entry> = iter.map(|e| e.unwrap()).collect();
+            assert_eq!(messages(entries), vec!["second", "third"]);
+        }
+
+        stdfs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_since_last_clear() {
+        // the buffer may or may not have been cleared, but it can always be read from there
+        let since_clear = kmsg_from(None, StartPosition::SinceLastClear).unwrap();
+        let all = kmsg(None).unwrap();
+        assert!(since_clear.len() <= all.len());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_stream_start() {
+        let path = kmsg_file("stream");
+        let stream =
+            KMsgEntriesStream::with_start(Some(path.clone()), false, StartPosition::Sequence(3))
+                .await
+                .unwrap();
+        let entries: Vec<Entry> = stream.map(|e| e.unwrap()).collect().await;
+        assert_eq!(messages(entries), vec!["third"]);
+        stdfs::remove_file(&path).unwrap();
+    }
+
     #[test]
     fn test_parse_serialize() {
         let line1 = " LINE2=foobar";
@@ -0,0 +1,250 @@
+/// Last-gasp dumps: the last few kernel records, written out from a panic hook.
+///
+/// When an application crashes right after a kernel event (a device going away, an OOM
+/// kill of a sibling), the kernel's side of the story is often gone by the time anyone
+/// looks. A `LastGasp`, set up at startup, copies the last N records from /dev/kmsg to a
+/// file descriptor synchronously, on a path that doesn't allocate: everything it needs
+/// is allocated when it's created, and it only reads and writes through libc. It's best
+/// effort: a record that can't be read or written is skipped.
+///
+/// Records are written as /dev/kmsg has them ("6,1234,5678,-;message"), without their
+/// dictionaries and cut at `slot_len` bytes, so `parse::parse_buffer` reads a dump back.
+///
+use crate::error::RMesgError;
+
+use std::ffi::CString;
+use std::os::unix::io::RawFd;
+use std::panic;
+use std::sync::{Mutex, TryLockError};
+
+const DEV_KMSG_PATH: &str = "/dev/kmsg";
+
+/// Suggested number of bytes kept of each record: enough for most messages whole.
+pub const SUGGESTED_SLOT_LEN: usize = 512;
+
+// The kernel caps a single /dev/kmsg record (including its dictionary) at a little under
+// 8KiB; a smaller buffer makes reads fail with EINVAL
+const RECORD_CAPACITY: usize = 8192;
+
+pub struct LastGasp {
+    path: CString,
+    record: Box<[u8]>,
+    // `count` slots of `slot_len` bytes, used as a ring, and how much of each is filled
+    slots: Box<[u8]>,
+    lens: Box<[usize]>,
+    slot_len: usize,
+}
+
+impl LastGasp {
+    /// Create a new LastGasp
+    /// `file_override`: When `Some`, overrides the path from where to read the kernel logs
+    /// `count`: How many of the most recent records to dump
+    /// `slot_len`: How many bytes of each record to keep (longer ones are cut short)
+    pub fn with_options(
+        file_override: Option<String>,
+        count: usize,
+        slot_len: usize,
+    ) -> Result<LastGasp, RMesgError> {
+        let path = file_override.as_deref().unwrap_or(DEV_KMSG_PATH);
+        let path = match CString::new(path) {
+            Ok(path) => path,
+            Err(_) => {
+                return Err(RMesgError::ConfigError(format!(
+                    "Invalid path {:?} (contains a NUL byte)",
+                    path
+                )))
+            }
+        };
+        if count == 0 || slot_len < 2 {
+            return Err(RMesgError::ConfigError(format!(
+                "A last-gasp dump needs at least one record of at least 2 bytes (got {} of {})",
+                count, slot_len
+            )));
+        }
+
+        Ok(LastGasp {
+            path,
+            record: vec![0; RECORD_CAPACITY].into_boxed_slice(),
+            slots: vec![0; count * slot_len].into_boxed_slice(),
+            lens: vec![0; count].into_boxed_slice(),
+            slot_len,
+        })
+    }
+
+    /// Writes the last records in the buffer to `fd`, oldest first, returning how many
+    /// were written. Doesn't allocate.
+    pub fn dump(&mut self, fd: RawFd) -> Result<usize, RMesgError> {
+        let count = self.lens.len();
+        let read = self.read_last()?;
+
+        let mut written = 0;
+        let first = read.saturating_sub(count);
+        for n in first..read {
+            let slot = n % count;
+            let start = slot * self.slot_len;
+            if write_all(fd, &self.slots[start..start + self.lens[slot]]).is_ok() {
+                written += 1;
+            }
+        }
+        Ok(written)
+    }
+
+    // Reads the whole buffer through the ring of slots, returning how many records were
+    // read (the last `count` of them are in the slots)
+    fn read_last(&mut self) -> Result<usize, RMesgError> {
+        let fd = unsafe { libc::open(self.path.as_ptr(), libc::O_RDONLY | libc::O_NONBLOCK) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        let count = self.lens.len();
+        let mut read = 0;
+        loop {
+            let n = unsafe {
+                libc::read(
+                    fd,
+                    self.record.as_mut_ptr() as *mut libc::c_void,
+                    self.record.len(),
+                )
+            };
+            if n == 0 {
+                break;
+            }
+            if n < 0 {
+                match std::io::Error::last_os_error().raw_os_error() {
+                    // records we were about to read were overwritten; carry on from the oldest available
+                    Some(libc::EPIPE) | Some(libc::EINTR) => continue,
+                    // the end of the buffer (EAGAIN), or nothing more to be done
+                    _ => break,
+                }
+            }
+
+            // the record without its dictionary lines, cut to fit the slot, and ending
+            // in a newline either way
+            let record = &self.record[..n as usize];
+            let line_len = record
+                .iter()
+                .position(|b| *b == b'\n')
+                .unwrap_or(record.len());
+            let len = line_len.min(self.slot_len - 1);
+            let slot = read % count;
+            let start = slot * self.slot_len;
+            self.slots[start..start + len].copy_from_slice(&record[..len]);
+            self.slots[start + len] = b'\n';
+            self.lens[slot] = len + 1;
+            read += 1;
+        }
+
+        unsafe { libc::close(fd) };
+        Ok(read)
+    }
+}
+
+fn write_all(fd: RawFd, mut bytes: &[u8]) -> Result<(), RMesgError> {
+    while !bytes.is_empty() {
+        let n = unsafe { libc::write(fd, bytes.as_ptr() as *const libc::c_void, bytes.len()) };
+        if n < 0 {
+            let e = std::io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                _ => return Err(e.into()),
+            }
+        }
+        bytes = &bytes[n as usize..];
+    }
+    Ok(())
+}
+
+/// Installs a panic hook dumping the last records to `fd` (e.g. stderr's, 2, or that of
+/// a file opened at startup), then calling the hook that was installed before.
+///
+/// A panic while dumping (or in another thread at the same time) skips the dump rather
+/// than waiting on it.
+pub fn install_panic_hook(last_gasp: LastGasp, fd: RawFd) {
+    let last_gasp = Mutex::new(last_gasp);
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let guard = match last_gasp.try_lock() {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::Poisoned(poisoned)) => Some(poisoned.into_inner()),
+            Err(TryLockError::WouldBlock) => None,
+        };
+        if let Some(mut last_gasp) = guard {
+            let _ = last_gasp.dump(fd);
+        }
+        previous(info);
+    }));
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+    use crate::parse;
+    use std::io::{Read, Seek, SeekFrom};
+    use std::os::unix::io::AsRawFd;
+
+    fn dump_to_string(last_gasp: &mut LastGasp, name: &str) -> (usize, String) {
+        let mut file = tempfile(name);
+        let written = last_gasp.dump(file.as_raw_fd()).unwrap();
+        let mut dump = String::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_string(&mut dump).unwrap();
+        (written, dump)
+    }
+
+    fn tempfile(name: &str) -> std::fs::File {
+        let path =
+            std::env::temp_dir().join(format!("rmesg-lastgasp-{}-{}", name, std::process::id()));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_dump() {
+        let mut last_gasp = LastGasp::with_options(None, 5, SUGGESTED_SLOT_LEN).unwrap();
+        let (written, dump) = dump_to_string(&mut last_gasp, "dump");
+        assert!(written > 0 && written <= 5, "Should have non-empty logs");
+        assert_eq!(dump.lines().count(), written);
+
+        // readable back, in order, and the most recent records
+        let entries = parse::parse_buffer(&dump);
+        assert_eq!(entries.len(), written);
+        assert!(entries
+            .windows(2)
+            .all(|pair| pair[0].sequence_num < pair[1].sequence_num));
+        let newest = crate::log_entries(crate::Backend::DevKMsg, false).unwrap();
+        assert!(newest.last().unwrap().sequence_num >= entries.last().unwrap().sequence_num);
+    }
+
+    #[test]
+    fn test_short_slots() {
+        let mut last_gasp = LastGasp::with_options(None, 3, 8).unwrap();
+        let (_, dump) = dump_to_string(&mut last_gasp, "short");
+        assert!(dump.lines().all(|line| line.len() <= 7));
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        assert!(matches!(
+            LastGasp::with_options(None, 0, SUGGESTED_SLOT_LEN),
+            Err(RMesgError::ConfigError(_))
+        ));
+        assert!(matches!(
+            LastGasp::with_options(Some("/dev/\0kmsg".to_owned()), 5, SUGGESTED_SLOT_LEN),
+            Err(RMesgError::ConfigError(_))
+        ));
+        let mut missing =
+            LastGasp::with_options(Some("/nonexistent/kmsg".to_owned()), 5, 64).unwrap();
+        assert!(missing.dump(2).is_err());
+    }
+}
@@ -0,0 +1,289 @@
+use crate::entry::Entry;
+/// Follow latency: how long after the kernel logged each entry it reached the consumer.
+///
+/// A `LatencyMeter` is a stage, best placed last, that measures each entry's latency
+/// as the time since system start when the entry passes through it, less the entry's
+/// kernel timestamp. The percentiles of the most recent measurements show whether a
+/// follow pipeline (backend, polling, stages, sinks backing up) keeps up with a latency
+/// SLO. Like `SeverityHistogram`, clones share the same measurements, so an operator can
+/// read them while the pipeline owns the stage.
+///
+/// Kernel timestamps and the clock they're compared with both stand still while the
+/// system is suspended, so a suspend doesn't count as latency. Entries logged before
+/// the meter started measuring (the history a follower reads first) aren't measured.
+///
+#[cfg(unix)]
+use crate::error::RMesgError;
+#[cfg(unix)]
+use crate::stage::Stage;
+
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LatencyOptions {
+    /// How many of the most recent measurements the percentiles are taken over
+    pub window: usize,
+    /// Entries logged before this (as time since system start) aren't measured; `None`
+    /// measures every entry
+    pub measure_from: Option<Duration>,
+}
+
+impl Default for LatencyOptions {
+    fn default() -> Self {
+        Self {
+            window: 10_000,
+            measure_from: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Latencies {
+    samples: VecDeque<Duration>,
+    options: LatencyOptions,
+    measured: u64,
+    untimed: u64,
+}
+
+/// Measures the latency of the entries passing through it.
+#[derive(Clone, Debug)]
+pub struct LatencyMeter {
+    latencies: Arc<Mutex<Latencies>>,
+}
+
+impl LatencyMeter {
+    pub fn with_options(options: LatencyOptions) -> LatencyMeter {
+        let options = LatencyOptions {
+            window: options.window.max(1),
+            ..options
+        };
+        LatencyMeter {
+            latencies: Arc::new(Mutex::new(Latencies {
+                samples: VecDeque::with_capacity(options.window),
+                options,
+                measured: 0,
+                untimed: 0,
+            })),
+        }
+    }
+
+    /// A meter measuring the entries logged from now on, over the default window.
+    #[cfg(unix)]
+    pub fn new() -> Result<LatencyMeter, RMesgError> {
+        Ok(LatencyMeter::with_options(LatencyOptions {
+            measure_from: Some(crate::clock::since_system_start()?),
+            ..Default::default()
+        }))
+    }
+
+    /// Measures `entry` as reaching the consumer now.
+    #[cfg(unix)]
+    pub fn record(&self, entry: &Entry) -> Result<(), RMesgError> {
+        self.record_at(entry, crate::clock::since_system_start()?);
+        Ok(())
+    }
+
+    /// Measures `entry` as reaching the consumer at `now` (as time since system start).
+    /// An entry timestamped later than `now` (the kernel's clock and the one read here
+    /// can disagree by a few microseconds) is measured as no latency at all.
+    pub fn record_at(&self, entry: &Entry, now: Duration) {
+        let mut latencies = self.lock();
+        let timestamp = match entry.timestamp_from_system_start {
+            Some(timestamp) => timestamp,
+            None => {
+                latencies.untimed += 1;
+                return;
+            }
+        };
+        if latencies
+            .options
+            .measure_from
+            .is_some_and(|from| timestamp < from)
+        {
+            return;
+        }
+
+        if latencies.samples.len() == latencies.options.window {
+            latencies.samples.pop_front();
+        }
+        latencies.samples.push_back(now.saturating_sub(timestamp));
+        latencies.measured += 1;
+    }
+
+    /// The latency `percentile` (0 to 100) of the entries measured in the window, by
+    /// nearest rank, or `None` if none have been.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let mut samples: Vec<Duration> = self.lock().samples.iter().copied().collect();
+        samples.sort_unstable();
+        nearest_rank(&samples, percentile)
+    }
+
+    /// The percentiles of the window, or `None` if nothing has been measured.
+    pub fn summary(&self) -> Option<LatencySummary> {
+        let latencies = self.lock();
+        let mut samples: Vec<Duration> = latencies.samples.iter().copied().collect();
+        samples.sort_unstable();
+        Some(LatencySummary {
+            measured: latencies.measured,
+            untimed: latencies.untimed,
+            samples: samples.len(),
+            min: *samples.first()?,
+            p50: nearest_rank(&samples, 50.0)?,
+            p90: nearest_rank(&samples, 90.0)?,
+            p99: nearest_rank(&samples, 99.0)?,
+            p999: nearest_rank(&samples, 99.9)?,
+            max: *samples.last()?,
+        })
+    }
+
+    pub fn clear(&self) {
+        let mut latencies = self.lock();
+        latencies.samples.clear();
+        latencies.measured = 0;
+        latencies.untimed = 0;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Latencies> {
+        // measurements stay consistent if a panic happens while holding the lock
+        self.latencies.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// The smallest sample at least `percentile` percent of `sorted` are at or below
+fn nearest_rank(sorted: &[Duration], percentile: f64) -> Option<Duration> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (percentile.clamp(0.0, 100.0) / 100.0 * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+#[cfg(unix)]
+impl Stage for LatencyMeter {
+    fn process(&mut self, entry: Entry) -> Option<Entry> {
+        // a clock that can't be read leaves this entry unmeasured, and passes it on
+        let _ = self.record(&entry);
+        Some(entry)
+    }
+}
+
+/// Latency percentiles over a meter's window.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LatencySummary {
+    /// Entries measured since the meter started (or was cleared)
+    pub measured: u64,
+    /// Entries without a kernel timestamp, which can't be measured
+    pub untimed: u64,
+    /// Measurements in the window, which the percentiles are over
+    pub samples: usize,
+    pub min: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub p999: Duration,
+    pub max: Duration,
+}
+
+impl LatencySummary {
+    /// Whether at least `percentile` of the window was within `target`, e.g.
+    /// `meets(99.0, Duration::from_millis(100))` for "p99 under 100ms". Percentiles the
+    /// summary doesn't have are rounded up to the next one it does.
+    pub fn meets(&self, percentile: f64, target: Duration) -> bool {
+        let at = match percentile {
+            p if p <= 50.0 => self.p50,
+            p if p <= 90.0 => self.p90,
+            p if p <= 99.0 => self.p99,
+            p if p <= 99.9 => self.p999,
+            _ => self.max,
+        };
+        at <= target
+    }
+}
+
+impl Display for LatencySummary {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(
+            f,
+            "p50 {:?}, p90 {:?}, p99 {:?}, p99.9 {:?}, max {:?} (over the last {} of {} measured)",
+            self.p50, self.p90, self.p99, self.p999, self.max, self.samples, self.measured
+        )
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(timestamp: Option<Duration>) -> Entry {
+        Entry {
+            timestamp_from_system_start: timestamp,
+            message: "usb 1-1: new high-speed USB device".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let meter = LatencyMeter::with_options(Default::default());
+        assert_eq!(meter.summary(), None);
+
+        // latencies of 1ms to 100ms
+        let now = Duration::from_secs(100);
+        for ms in 1..=100 {
+            meter.record_at(&entry(Some(now - Duration::from_millis(ms))), now);
+        }
+        meter.record_at(&entry(None), now);
+        // logged a hair after the clock was read
+        meter.record_at(&entry(Some(now + Duration::from_micros(3))), now);
+
+        let summary = meter.summary().unwrap();
+        assert_eq!(summary.measured, 101);
+        assert_eq!(summary.untimed, 1);
+        assert_eq!(summary.min, Duration::ZERO);
+        assert_eq!(summary.p50, Duration::from_millis(50));
+        assert_eq!(summary.p99, Duration::from_millis(99));
+        assert_eq!(summary.max, Duration::from_millis(100));
+        assert_eq!(meter.percentile(0.0), Some(Duration::ZERO));
+        assert!(summary.meets(90.0, Duration::from_millis(95)));
+        assert!(!summary.meets(99.0, Duration::from_millis(95)));
+    }
+
+    #[test]
+    fn test_window_and_start() {
+        let meter = LatencyMeter::with_options(LatencyOptions {
+            window: 10,
+            measure_from: Some(Duration::from_secs(50)),
+        });
+        let now = Duration::from_secs(100);
+        // the history before the meter started isn't measured
+        meter.record_at(&entry(Some(Duration::from_secs(1))), now);
+        assert_eq!(meter.summary(), None);
+
+        for ms in 1..=20 {
+            meter.record_at(&entry(Some(now - Duration::from_millis(ms))), now);
+        }
+        let summary = meter.summary().unwrap();
+        assert_eq!((summary.measured, summary.samples), (20, 10));
+        assert_eq!(summary.min, Duration::from_millis(11));
+
+        meter.clear();
+        assert_eq!(meter.summary(), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stage() {
+        let mut meter = LatencyMeter::new().unwrap();
+        let shared = meter.clone();
+        let now = crate::clock::since_system_start().unwrap();
+        assert!(meter.process(entry(Some(now))).is_some());
+        assert_eq!(shared.summary().unwrap().measured, 1);
+        assert!(shared.summary().unwrap().max < Duration::from_secs(1));
+    }
+}
@@ -1,154 +1,607 @@
 mod common;
 
+/// Merging of several hosts' kernel logs, followed from their gRPC agents
+#[cfg(all(feature = "grpc", any(feature = "klogctl", feature = "kmsg")))]
+pub mod aggregate;
+/// Runtime-agnostic streams over the polling backends (no tokio needed)
+#[cfg(feature = "futures")]
+pub mod agnostic;
+/// Analyses of a log snapshot (boot timelines, etc.)
+pub mod analysis;
+/// Android detection, and picking whichever of /dev/kmsg and klogctl is accessible there
+#[cfg(any(feature = "klogctl", feature = "kmsg"))]
+pub mod android;
+/// Annotations: key-value tags attached to entries by user-defined rules
+pub mod annotate;
+/// Assertion helpers for CI jobs that should fail on kernel complaints
+pub mod assertions;
+/// Backfill from /var/log/dmesg and its rotated archives, ahead of the live buffer
+#[cfg(feature = "backfill")]
+pub mod backfill;
+/// Bookmarks into the log, for replaying everything logged since
+pub mod bookmark;
+/// Support bundles (snapshot, pstore, printk settings and system context in one archive)
+pub mod bundle;
+/// Capture of kernel messages logged while running a closure (for test harnesses)
+#[cfg(feature = "kmsg")]
+pub mod capture;
+/// Advisory locking so that tools clearing the kernel log don't race each other
+#[cfg(feature = "klogctl")]
+pub mod clearlock;
+/// Conversion of kernel timestamps to wall-clock time
+pub mod clock;
+/// Compact snapshots (every message in one arena, for snapshots of millions of entries)
+pub mod compact;
+/// Counters of the work done on hot paths, for tests and benchmarks to check
+#[cfg(feature = "bench")]
+pub mod counters;
+/// Reports of what's unavailable on this host, and why
+pub mod degradation;
+/// Diffing of snapshots
+pub mod diff;
 pub mod entry;
 pub mod error;
+/// Structured events parsed out of kernel log entries
+pub mod events;
+/// Filters (stages that select which entries to keep)
+pub mod filter;
+/// Formatter options (timestamp precision, message truncation)
+pub mod format;
+/// FreeBSD Implementation (reads the kernel message buffer through the kern.msgbuf sysctl)
+#[cfg(feature = "freebsd")]
+pub mod freebsd;
+/// Entry points for fuzzing the parsers with arbitrary bytes
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
+/// Grouping of multi-line reports (oopses, warnings) and continuation lines into events
+pub mod group;
+/// gRPC service (Snapshot, Follow and Clear RPCs) for remote management planes
+#[cfg(all(feature = "grpc", any(feature = "klogctl", feature = "kmsg")))]
+pub mod grpc;
+/// Heartbeat markers written to /dev/kmsg, to check kernel logging end to end
+#[cfg(feature = "kmsg")]
+pub mod heartbeat;
+/// Rolling histogram of entries per level, with burst detection
+pub mod histogram;
+/// Poll intervals, validated up front
+pub mod interval;
+/// Journald Implementation (reads kernel messages from the systemd journal)
+#[cfg(feature = "journald")]
+pub mod journald;
 /// KLog Implementation (makes klogctl aka syslog system call through libc)
+#[cfg(feature = "klogctl")]
 pub mod klogctl;
 /// KMsg Implementation (reads from the /dev/kmsg file)
+#[cfg(feature = "kmsg")]
 pub mod kmsgfile;
+/// Last-gasp dumps of the most recent records from a panic hook, without allocating
+#[cfg(feature = "kmsg")]
+pub mod lastgasp;
+/// Follow latency measurement, with percentile summaries
+pub mod latency;
+/// OpenBSD and NetBSD implementation (reads the kernel message buffer through the kern.msgbuf sysctl)
+#[cfg(feature = "netbsdlike")]
+pub mod netbsdlike;
+/// Parsers for the formats kernel log records come in (available without any backend)
+pub mod parse;
+/// Pipelines of a source, stages and sinks on their own threads, with graceful shutdown
+pub mod pipeline;
+/// Where entries came from (backend, live or previous boot, host or container)
+pub mod provenance;
+/// PStore Implementation (reads logs saved by previous boots from /sys/fs/pstore)
+#[cfg(feature = "pstore")]
+pub mod pstore;
+/// Message rates per subsystem over a sliding window, to find what is flooding the log
+pub mod rates;
+/// Restarting followers that fail, with backoff, instead of ending the follow
+pub mod restart;
+/// In-process retention of recently seen entries, for querying later
+pub mod retention;
+/// Sanitization of terminal control sequences and other hostile characters in messages
+pub mod sanitize;
+/// Redaction of sensitive data (addresses, serial numbers, etc.) in messages
+pub mod scrub;
+/// In-memory inverted index for searching snapshots
+pub mod search;
+/// HTTP server mode (serves the entry stream over Server-Sent Events or WebSocket)
+#[cfg(all(feature = "server", any(feature = "klogctl", feature = "kmsg")))]
+pub mod server;
+/// Re-mapping of the severity of specific messages
+pub mod severity;
+/// Simulated kernel log, for load testing
+#[cfg(feature = "simulate")]
+pub mod simulate;
+/// Destinations to deliver entries to (webhooks, databases, etc.)
+pub mod sinks;
+/// Logical clearing that leaves the kernel buffer untouched
+pub mod softclear;
+/// Pluggable log sources (a `KLogSource` trait, with klogctl and /dev/kmsg implementations)
+pub mod source;
+/// Processing stages applied to entries between reading and consuming them
+pub mod stage;
+/// Versioned on-disk formats for bookmarks, baselines and suppression lists
+#[cfg(feature = "state")]
+pub mod state;
+/// Static-buffer reader (reads /dev/kmsg without growing the heap)
+#[cfg(feature = "kmsg")]
+pub mod staticbuf;
+/// Suppression lists of known-noisy messages
+pub mod suppress;
+/// Interactive terminal viewer (`rmesg tui`)
+#[cfg(all(feature = "tui", any(feature = "klogctl", feature = "kmsg")))]
+pub mod tui;
+/// Windows implementation (kernel events in the System event log)
+#[cfg(feature = "windows")]
+pub mod windows;
+/// Windows Subsystem for Linux detection, and what of the kernel log each version has
+pub mod wsl;
 
-#[cfg(feature = "sync")]
+pub use diff::diff;
+
+#[cfg(any(
+    feature = "klogctl",
+    feature = "kmsg",
+    feature = "pstore",
+    feature = "journald",
+    feature = "freebsd",
+    feature = "netbsdlike",
+    feature = "simulate",
+    feature = "windows"
+))]
+use provenance::SourceBackend;
+
+#[cfg(all(
+    feature = "sync",
+    any(
+        feature = "klogctl",
+        feature = "kmsg",
+        feature = "journald",
+        feature = "freebsd",
+        feature = "netbsdlike",
+        feature = "simulate",
+        feature = "windows"
+    )
+))]
 use std::iter::Iterator;
 
-#[cfg(feature = "async")]
+#[cfg(all(feature = "async", any(feature = "klogctl", feature = "kmsg")))]
 use core::pin::Pin;
-#[cfg(feature = "async")]
+#[cfg(all(feature = "async", any(feature = "klogctl", feature = "kmsg")))]
 use futures::stream::Stream;
-#[cfg(feature = "async")]
+#[cfg(all(feature = "async", any(feature = "klogctl", feature = "kmsg")))]
 use futures::task::{Context, Poll};
-#[cfg(feature = "async")]
+#[cfg(all(feature = "async", any(feature = "klogctl", feature = "kmsg")))]
 use pin_project::pin_project;
 
+/// Where to read kernel logs from. Each backend other than `Default` is only
+/// available with its cargo feature (the "klogctl" and "kmsg" features are on by default).
 #[derive(Clone, Copy, Debug)]
 pub enum Backend {
+    /// /dev/kmsg, falling back to klogctl (then journald) when it can't be opened
     Default,
+    #[cfg(feature = "klogctl")]
     KLogCtl,
+    #[cfg(feature = "kmsg")]
     DevKMsg,
+    /// Logs saved by pstore from previous boots (snapshots only)
+    #[cfg(feature = "pstore")]
+    PStore,
+    /// Kernel messages in the systemd journal (through journalctl)
+    #[cfg(feature = "journald")]
+    Journald,
+    /// The kernel message buffer on FreeBSD (through the kern.msgbuf sysctl)
+    #[cfg(feature = "freebsd")]
+    MsgBuf,
+    /// The kernel message buffer on OpenBSD and NetBSD (through the kern.msgbuf sysctl)
+    #[cfg(feature = "netbsdlike")]
+    BsdMsgBuf,
+    /// Kernel events in the System event log on Windows
+    #[cfg(feature = "windows")]
+    EventLog,
+    /// A simulated kernel, logging `simulate::SimProfile::default()`'s mix of entries
+    #[cfg(feature = "simulate")]
+    Simulated,
 }
 
-#[cfg(feature = "sync")]
+#[cfg(all(
+    feature = "sync",
+    any(
+        feature = "klogctl",
+        feature = "kmsg",
+        feature = "journald",
+        feature = "freebsd",
+        feature = "netbsdlike",
+        feature = "simulate",
+        feature = "windows"
+    )
+))]
 pub enum EntriesIterator {
+    #[cfg(feature = "klogctl")]
     KLogCtl(klogctl::KLogEntries),
+    #[cfg(feature = "kmsg")]
     DevKMsg(kmsgfile::KMsgEntriesIter),
+    #[cfg(feature = "journald")]
+    Journald(journald::JournalEntriesIter),
+    #[cfg(feature = "freebsd")]
+    MsgBuf(source::SourceEntries<freebsd::MsgBufSource>),
+    #[cfg(feature = "netbsdlike")]
+    BsdMsgBuf(source::SourceEntries<netbsdlike::BsdMsgBufSource>),
+    #[cfg(feature = "windows")]
+    EventLog(source::SourceEntries<windows::EventLogSource>),
+    #[cfg(feature = "simulate")]
+    Simulated(source::SourceEntries<simulate::SimulatedSource>),
 }
-#[cfg(feature = "sync")]
+#[cfg(all(
+    feature = "sync",
+    any(
+        feature = "klogctl",
+        feature = "kmsg",
+        feature = "journald",
+        feature = "freebsd",
+        feature = "netbsdlike",
+        feature = "simulate",
+        feature = "windows"
+    )
+))]
 impl Iterator for EntriesIterator {
     type Item = Result<entry::Entry, error::RMesgError>;
     fn next(&mut self) -> Option<Self::Item> {
-        match self {
-            Self::KLogCtl(k) => k.next(),
-            Self::DevKMsg(d) => d.next(),
-        }
+        let (next, backend) = match self {
+            #[cfg(feature = "klogctl")]
+            Self::KLogCtl(k) => (k.next(), SourceBackend::KLogCtl),
+            #[cfg(feature = "kmsg")]
+            Self::DevKMsg(d) => (d.next(), SourceBackend::DevKMsg),
+            #[cfg(feature = "journald")]
+            Self::Journald(j) => (j.next(), SourceBackend::Journald),
+            #[cfg(feature = "freebsd")]
+            Self::MsgBuf(m) => (m.next(), SourceBackend::MsgBuf),
+            #[cfg(feature = "netbsdlike")]
+            Self::BsdMsgBuf(m) => (m.next(), SourceBackend::MsgBuf),
+            #[cfg(feature = "windows")]
+            Self::EventLog(e) => (e.next(), SourceBackend::EventLog),
+            #[cfg(feature = "simulate")]
+            Self::Simulated(s) => (s.next(), SourceBackend::Simulated),
+        };
+        next.map(|entry| entry.map(|e| provenance::tag(e, backend)))
     }
 }
 
 #[pin_project(project = EntriesStreamPinnedProjection)]
-#[cfg(feature = "async")]
+#[cfg(all(feature = "async", any(feature = "klogctl", feature = "kmsg")))]
 pub enum EntriesStream {
+    #[cfg(feature = "klogctl")]
     KLogCtl(#[pin] klogctl::KLogEntries),
+    #[cfg(feature = "kmsg")]
     DevKMsg(#[pin] kmsgfile::KMsgEntriesStream),
+    #[cfg(feature = "simulate")]
+    Simulated(simulate::SimulatedStream),
 }
-#[cfg(feature = "async")]
+#[cfg(all(feature = "async", any(feature = "klogctl", feature = "kmsg")))]
 impl Stream for EntriesStream {
     type Item = Result<entry::Entry, error::RMesgError>;
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        match self.project() {
-            EntriesStreamPinnedProjection::KLogCtl(k) => k.poll_next(cx),
-            EntriesStreamPinnedProjection::DevKMsg(d) => d.poll_next(cx),
-        }
+        let (next, backend) = match self.project() {
+            #[cfg(feature = "klogctl")]
+            EntriesStreamPinnedProjection::KLogCtl(k) => (k.poll_next(cx), SourceBackend::KLogCtl),
+            #[cfg(feature = "kmsg")]
+            EntriesStreamPinnedProjection::DevKMsg(d) => (d.poll_next(cx), SourceBackend::DevKMsg),
+            #[cfg(feature = "simulate")]
+            EntriesStreamPinnedProjection::Simulated(s) => {
+                (s.as_mut().poll_next(cx), SourceBackend::Simulated)
+            }
+        };
+        next.map(|entry| entry.map(|entry| entry.map(|e| provenance::tag(e, backend))))
     }
 }
 
-pub fn log_entries(b: Backend, clear: bool) -> Result<Vec<entry::Entry>, error::RMesgError> {
-    match b {
-        Backend::Default => match kmsgfile::kmsg(None) {
-            Ok(e) => Ok(e),
-            Err(error::RMesgError::DevKMsgFileOpenError(s)) => {
+/// The backends `Backend::Default` tries, in order. On Android, only the ones the
+/// process may use, and under WSL only the ones it has; if there are none, the error
+/// says what's missing.
+fn default_backends() -> Result<Vec<Backend>, error::RMesgError> {
+    #[cfg(any(feature = "klogctl", feature = "kmsg"))]
+    if android::is_android() {
+        return android::Access::probe().default_backends();
+    }
+    if let Some(wsl) = wsl::detect() {
+        return wsl.default_backends();
+    }
+    Ok(vec![
+        #[cfg(all(feature = "freebsd", target_os = "freebsd"))]
+        Backend::MsgBuf,
+        #[cfg(all(
+            feature = "netbsdlike",
+            any(target_os = "openbsd", target_os = "netbsd")
+        ))]
+        Backend::BsdMsgBuf,
+        #[cfg(all(feature = "windows", windows))]
+        Backend::EventLog,
+        #[cfg(feature = "kmsg")]
+        Backend::DevKMsg,
+        #[cfg(feature = "klogctl")]
+        Backend::KLogCtl,
+        #[cfg(feature = "journald")]
+        Backend::Journald,
+    ])
+}
+
+/// Runs `f` against each default backend in turn, moving on to the next
+/// only when the device file can't be opened.
+fn with_default_backend<T>(
+    f: impl Fn(Backend) -> Result<T, error::RMesgError>,
+) -> Result<T, error::RMesgError> {
+    let backends = default_backends()?;
+    for (i, backend) in backends.iter().enumerate() {
+        match f(*backend) {
+            Err(error::RMesgError::DevKMsgFileOpenError(s)) if i + 1 < backends.len() => {
                 eprintln!(
-                    "Falling back from device file to klogctl syscall due to error: {}",
+                    "Falling back from device file to {:?} due to error: {}",
+                    backends[i + 1],
                     s
                 );
-                klogctl::klog(clear)
             }
-            Err(e) => Err(e),
-        },
-        Backend::KLogCtl => klogctl::klog(clear),
-        Backend::DevKMsg => kmsgfile::kmsg(None),
+            result => return result,
+        }
     }
+    Err(error::RMesgError::BackendUnavailable(
+        "No backend enabled (enable the klogctl, kmsg or journald feature)".to_owned(),
+    ))
 }
 
+#[cfg(any(
+    feature = "klogctl",
+    feature = "kmsg",
+    feature = "pstore",
+    feature = "journald",
+    feature = "freebsd",
+    feature = "netbsdlike",
+    feature = "simulate",
+    feature = "windows"
+))]
+fn tagged(entries: Vec<entry::Entry>, backend: SourceBackend) -> Vec<entry::Entry> {
+    entries
+        .into_iter()
+        .map(|e| provenance::tag(e, backend))
+        .collect()
+}
+
+// `clear` goes unused when only backends that can't clear are enabled
+#[allow(clippy::only_used_in_recursion)]
+pub fn log_entries(b: Backend, clear: bool) -> Result<Vec<entry::Entry>, error::RMesgError> {
+    match b {
+        Backend::Default => with_default_backend(|b| log_entries(b, clear)),
+        #[cfg(feature = "klogctl")]
+        Backend::KLogCtl => Ok(tagged(klogctl::klog(clear)?, SourceBackend::KLogCtl)),
+        #[cfg(feature = "kmsg")]
+        Backend::DevKMsg => Ok(tagged(kmsgfile::kmsg(None)?, SourceBackend::DevKMsg)),
+        #[cfg(feature = "pstore")]
+        Backend::PStore => Ok(tagged(pstore::pstore(None, clear)?, SourceBackend::PStore)),
+        #[cfg(feature = "journald")]
+        Backend::Journald => Ok(tagged(
+            journald_unless_clearing(clear, journald::journal)?,
+            SourceBackend::Journald,
+        )),
+        #[cfg(feature = "freebsd")]
+        Backend::MsgBuf => Ok(tagged(freebsd::msgbuf(clear)?, SourceBackend::MsgBuf)),
+        #[cfg(feature = "netbsdlike")]
+        Backend::BsdMsgBuf => Ok(tagged(netbsdlike::msgbuf(clear)?, SourceBackend::MsgBuf)),
+        #[cfg(feature = "windows")]
+        Backend::EventLog => Ok(tagged(windows::event_log(clear)?, SourceBackend::EventLog)),
+        #[cfg(feature = "simulate")]
+        Backend::Simulated => simulate::simulated(),
+    }
+}
+
+// `clear` goes unused when only backends that can't clear are enabled
+#[allow(clippy::only_used_in_recursion)]
 pub fn logs_raw(b: Backend, clear: bool) -> Result<String, error::RMesgError> {
     match b {
-        Backend::Default => match kmsgfile::kmsg_raw(None) {
-            Ok(e) => Ok(e),
-            Err(error::RMesgError::DevKMsgFileOpenError(s)) => {
-                eprintln!(
-                    "Falling back from device file to klogctl syscall due to error: {}",
-                    s
-                );
-                klogctl::klog_raw(clear)
-            }
-            Err(e) => Err(e),
-        },
+        Backend::Default => with_default_backend(|b| logs_raw(b, clear)),
+        #[cfg(feature = "klogctl")]
         Backend::KLogCtl => klogctl::klog_raw(clear),
+        #[cfg(feature = "kmsg")]
         Backend::DevKMsg => kmsgfile::kmsg_raw(None),
+        #[cfg(feature = "pstore")]
+        Backend::PStore => pstore::pstore_raw(None, clear),
+        #[cfg(feature = "journald")]
+        Backend::Journald => journald_unless_clearing(clear, journald::journal_raw),
+        #[cfg(feature = "freebsd")]
+        Backend::MsgBuf => freebsd::msgbuf_raw(clear),
+        #[cfg(feature = "netbsdlike")]
+        Backend::BsdMsgBuf => netbsdlike::msgbuf_raw(clear),
+        #[cfg(feature = "windows")]
+        Backend::EventLog => windows::event_log_raw(clear),
+        #[cfg(feature = "simulate")]
+        Backend::Simulated => simulate::simulated_raw(),
     }
 }
 
-#[cfg(feature = "sync")]
+/// Clears the kernel log buffer and reports what was discarded.
+/// Clearing is only possible through klogctl (/dev/kmsg has no equivalent),
+/// so there is no backend choice here.
+#[cfg(feature = "klogctl")]
+pub fn clear() -> Result<klogctl::ClearedVolume, error::RMesgError> {
+    klogctl::klog_clear()
+}
+
+/// Counts the entries in the kernel log buffer without parsing them into `Entry` values.
+pub fn count_entries(b: Backend) -> Result<usize, error::RMesgError> {
+    match b {
+        Backend::Default => with_default_backend(count_entries),
+        #[cfg(feature = "klogctl")]
+        Backend::KLogCtl => klogctl::klog_count(),
+        #[cfg(feature = "kmsg")]
+        Backend::DevKMsg => kmsgfile::kmsg_count(None),
+        #[cfg(feature = "pstore")]
+        Backend::PStore => pstore::pstore_count(None),
+        #[cfg(feature = "journald")]
+        Backend::Journald => journald::journal_count(),
+        #[cfg(feature = "freebsd")]
+        Backend::MsgBuf => freebsd::msgbuf_count(),
+        #[cfg(feature = "netbsdlike")]
+        Backend::BsdMsgBuf => netbsdlike::msgbuf_count(),
+        #[cfg(feature = "windows")]
+        Backend::EventLog => windows::event_log_count(),
+        #[cfg(feature = "simulate")]
+        Backend::Simulated => Ok(simulate::simulated()?.len()),
+    }
+}
+
+#[cfg(feature = "journald")]
+fn journald_unless_clearing<T>(
+    clear: bool,
+    f: impl Fn() -> Result<T, error::RMesgError>,
+) -> Result<T, error::RMesgError> {
+    match clear {
+        true => Err(error::RMesgError::BackendUnavailable(
+            "The journal can't be cleared through rmesg".to_owned(),
+        )),
+        false => f(),
+    }
+}
+
+#[cfg(all(
+    feature = "sync",
+    any(
+        feature = "klogctl",
+        feature = "kmsg",
+        feature = "journald",
+        feature = "freebsd",
+        feature = "netbsdlike",
+        feature = "simulate",
+        feature = "windows"
+    )
+))]
+// `clear` goes unused when only backends that can't clear are enabled
+#[allow(clippy::only_used_in_recursion)]
 pub fn logs_iter(b: Backend, clear: bool, raw: bool) -> Result<EntriesIterator, error::RMesgError> {
     match b {
-        Backend::Default => match kmsgfile::KMsgEntriesIter::with_options(None, raw) {
-            Ok(e) => Ok(EntriesIterator::DevKMsg(e)),
-            Err(error::RMesgError::DevKMsgFileOpenError(s)) => {
-                eprintln!(
-                    "Falling back from device file to klogctl syscall due to error: {}",
-                    s
-                );
-                Ok(EntriesIterator::KLogCtl(
-                    klog_entries_only_if_timestamp_enabled(clear)?,
-                ))
-            }
-            Err(e) => Err(e),
-        },
+        Backend::Default => with_default_backend(|b| logs_iter(b, clear, raw)),
+        #[cfg(feature = "klogctl")]
         Backend::KLogCtl => Ok(EntriesIterator::KLogCtl(
             klog_entries_only_if_timestamp_enabled(clear)?,
         )),
+        #[cfg(feature = "kmsg")]
         Backend::DevKMsg => Ok(EntriesIterator::DevKMsg(
             kmsgfile::KMsgEntriesIter::with_options(None, raw)?,
         )),
+        #[cfg(feature = "pstore")]
+        Backend::PStore => Err(error::RMesgError::BackendUnavailable(
+            "pstore holds logs from previous boots, which can't be followed".to_owned(),
+        )),
+        #[cfg(feature = "journald")]
+        Backend::Journald => journald_unless_clearing(clear, || {
+            Ok(EntriesIterator::Journald(
+                journald::JournalEntriesIter::with_options(raw)?,
+            ))
+        }),
+        #[cfg(feature = "freebsd")]
+        Backend::MsgBuf => {
+            let mut source = freebsd::MsgBufSource::default();
+            if clear {
+                source::KLogSource::clear(&mut source)?;
+            }
+            // the buffer is small and reading it cheap, so it can be polled often
+            Ok(EntriesIterator::MsgBuf(
+                source::SourceEntries::with_options(source, interval::PollInterval::from_secs(1)?),
+            ))
+        }
+        #[cfg(feature = "netbsdlike")]
+        Backend::BsdMsgBuf => {
+            let mut source = netbsdlike::BsdMsgBufSource::default();
+            if clear {
+                source::KLogSource::clear(&mut source)?;
+            }
+            Ok(EntriesIterator::BsdMsgBuf(
+                source::SourceEntries::with_options(source, interval::PollInterval::from_secs(1)?),
+            ))
+        }
+        #[cfg(feature = "windows")]
+        Backend::EventLog => {
+            let mut source = windows::EventLogSource::default();
+            if clear {
+                source::KLogSource::clear(&mut source)?;
+            }
+            Ok(EntriesIterator::EventLog(
+                source::SourceEntries::with_options(source, interval::PollInterval::from_secs(1)?),
+            ))
+        }
+        #[cfg(feature = "simulate")]
+        Backend::Simulated => {
+            let mut source = simulate::SimulatedSource::with_options(Default::default())?;
+            if clear {
+                source::KLogSource::clear(&mut source)?;
+            }
+            // poll often, so that high rates come in small batches
+            Ok(EntriesIterator::Simulated(
+                source::SourceEntries::with_options(
+                    source,
+                    interval::PollInterval::from_millis(100)?,
+                ),
+            ))
+        }
     }
 }
 
-#[cfg(feature = "async")]
+#[cfg(all(feature = "async", any(feature = "klogctl", feature = "kmsg")))]
 pub async fn logs_stream(
     b: Backend,
     clear: bool,
     raw: bool,
 ) -> Result<EntriesStream, error::RMesgError> {
     match b {
-        Backend::Default => match kmsgfile::KMsgEntriesStream::with_options(None, raw).await {
-            Ok(e) => Ok(EntriesStream::DevKMsg(e)),
-            Err(error::RMesgError::DevKMsgFileOpenError(s)) => {
-                eprintln!(
-                    "Falling back from device file to klogctl syscall due to error: {}",
-                    s
-                );
-                Ok(EntriesStream::KLogCtl(
-                    klog_entries_only_if_timestamp_enabled(clear)?,
-                ))
+        Backend::Default => {
+            let backends = default_backends()?;
+            for (i, backend) in backends.iter().enumerate() {
+                match backend_stream(*backend, clear, raw).await {
+                    Err(error::RMesgError::DevKMsgFileOpenError(s)) if i + 1 < backends.len() => {
+                        eprintln!(
+                            "Falling back from device file to {:?} due to error: {}",
+                            backends[i + 1],
+                            s
+                        );
+                    }
+                    result => return result,
+                }
             }
-            Err(e) => Err(e),
-        },
+            Err(error::RMesgError::BackendUnavailable(
+                "No streaming backend enabled (enable the klogctl or kmsg feature)".to_owned(),
+            ))
+        }
+        b => backend_stream(b, clear, raw).await,
+    }
+}
+
+#[cfg(all(feature = "async", any(feature = "klogctl", feature = "kmsg")))]
+async fn backend_stream(
+    b: Backend,
+    #[allow(unused_variables)] clear: bool,
+    #[allow(unused_variables)] raw: bool,
+) -> Result<EntriesStream, error::RMesgError> {
+    match b {
+        #[cfg(feature = "klogctl")]
         Backend::KLogCtl => Ok(EntriesStream::KLogCtl(
             klog_entries_only_if_timestamp_enabled(clear)?,
         )),
+        #[cfg(feature = "kmsg")]
         Backend::DevKMsg => Ok(EntriesStream::DevKMsg(
             kmsgfile::KMsgEntriesStream::with_options(None, raw).await?,
         )),
+        #[cfg(feature = "simulate")]
+        Backend::Simulated => Ok(EntriesStream::Simulated(simulate::simulated_stream(
+            Default::default(),
+            clear,
+        )?)),
+        b => Err(error::RMesgError::BackendUnavailable(format!(
+            "Backend {:?} can't be streamed",
+            b
+        ))),
     }
 }
 
+#[cfg(all(
+    feature = "klogctl",
+    any(feature = "sync", feature = "async", feature = "futures")
+))]
 fn klog_entries_only_if_timestamp_enabled(
     clear: bool,
 ) -> Result<klogctl::KLogEntries, error::RMesgError> {
@@ -163,7 +616,8 @@ fn klog_entries_only_if_timestamp_enabled(
         return Err(error::RMesgError::KLogTimestampsDisabled);
     }
 
-    klogctl::KLogEntries::with_options(clear, klogctl::SUGGESTED_POLL_INTERVAL)
+    // without /dev/kmsg to follow, poll quickly while messages are arriving
+    klogctl::KLogEntries::with_adaptive_polling(clear, klogctl::AdaptivePolling::default())
 }
 
 /**********************************************************************************/
@@ -172,7 +626,7 @@ fn klog_entries_only_if_timestamp_enabled(
 #[cfg(all(test, target_os = "linux"))]
 mod test {
     use super::*;
-    #[cfg(feature = "async")]
+    #[cfg(all(feature = "async", any(feature = "klogctl", feature = "kmsg")))]
     use tokio_stream::StreamExt;
 
     #[test]
@@ -182,7 +636,17 @@ mod test {
         assert!(!entries.unwrap().is_empty(), "Should have non-empty logs");
     }
 
-    #[cfg(feature = "sync")]
+    #[test]
+    fn test_count_entries() {
+        let count = count_entries(Backend::Default);
+        assert!(count.is_ok(), "Response from count_entries not Ok");
+        assert!(count.unwrap() > 0, "Should have non-zero entries");
+    }
+
+    #[cfg(all(
+        feature = "sync",
+        any(feature = "klogctl", feature = "kmsg", feature = "journald")
+    ))]
     #[test]
     fn test_iterator() {
         // uncomment below if you want to be extra-sure
@@ -204,7 +668,7 @@ mod test {
         }
     }
 
-    #[cfg(feature = "async")]
+    #[cfg(all(feature = "async", any(feature = "klogctl", feature = "kmsg")))]
     #[tokio::test]
     async fn test_stream() {
         // uncomment below if you want to be extra-sure
@@ -1,49 +1,254 @@
 /// rmesg - a rust-based dmesg implementation.
 /// This CLI builds on top of the eponymous crate and provides a command-line utility.
 ///
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, SubCommand};
 use futures_util::stream::TryStreamExt;
+use rmesg::entry::{Entry, LogLevel};
+use rmesg::format::{FormatOptions, TimestampPrecision};
+use rmesg::heartbeat::Heartbeat;
+use rmesg::sanitize::{SanitizeAction, Sanitizer};
+use rmesg::stage::Stage;
+use std::convert::TryFrom;
 use std::error::Error;
+use std::time::Duration;
+
+#[derive(Debug)]
+enum BaselineCommand {
+    Save(String),
+    Compare(String),
+}
 
 #[derive(Debug)]
 struct Options {
     follow: bool,
+    restart_on_error: bool,
+    heartbeat: Option<Duration>,
     clear: bool,
     raw: bool,
+    levels: Option<Vec<LogLevel>>,
+    console_level: Option<LogLevel>,
+    console_on: Option<bool>,
+    format: FormatOptions,
+    ctime: bool,
+    sanitizer: Option<Sanitizer>,
     backend: rmesg::Backend,
+    #[cfg(feature = "server")]
+    serve: Option<String>,
+    #[cfg(feature = "grpc")]
+    serve_grpc: Option<String>,
+    #[cfg(feature = "grpc")]
+    aggregate: Option<Vec<String>>,
+    #[cfg(feature = "tui")]
+    tui: bool,
+    baseline: Option<BaselineCommand>,
+    export_bundle: Option<String>,
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let opts = parse_args();
+    let mut opts = parse_args();
+    if opts.ctime {
+        // anchored once, so that every entry printed is converted alike
+        opts.format.wall_clock = Some(rmesg::clock::WallClock::now()?);
+    }
+
+    // don't race other rmesg-based tools clearing the buffer
+    let _lock = match opts.clear {
+        true => Some(rmesg::clearlock::ClearLock::acquire()?),
+        false => None,
+    };
+
+    if let Some(level) = opts.console_level {
+        rmesg::klogctl::set_console_level(level)?;
+        return Ok(());
+    }
+    match opts.console_on {
+        Some(true) => return Ok(rmesg::klogctl::console_on()?),
+        Some(false) => return Ok(rmesg::klogctl::console_off()?),
+        None => {}
+    }
+
+    if let Some(path) = &opts.export_bundle {
+        let bundle = rmesg::bundle::Bundle::collect();
+        bundle.write_to(path)?;
+        eprintln!("Wrote {} to {}", bundle.file_names().join(", "), path);
+        return Ok(());
+    }
+
+    if let Some(command) = &opts.baseline {
+        let entries = rmesg::log_entries(opts.backend, false)?;
+        match command {
+            BaselineCommand::Save(path) => {
+                let baseline = rmesg::analysis::baseline::Baseline::from_entries(&entries);
+                std::fs::write(path, baseline.to_text())?;
+                eprintln!("Saved {} message templates to {}", baseline.len(), path);
+            }
+            BaselineCommand::Compare(path) => {
+                let baseline = rmesg::analysis::baseline::Baseline::from_text(
+                    &std::fs::read_to_string(path)?,
+                )?;
+                let comparison = baseline.compare(&entries);
+                for new in &comparison.new {
+                    println!("+ {} ({}x, first: {})", new.template, new.count, new.first);
+                }
+                for missing in &comparison.missing {
+                    println!("- {} ({}x in baseline)", missing.template, missing.count);
+                }
+                // like diff(1), so scripts can check for regressions
+                if !comparison.is_empty() {
+                    std::process::exit(1);
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    #[cfg(feature = "tui")]
+    if opts.tui {
+        rmesg::tui::run(opts.backend)?;
+        return Ok(());
+    }
+
+    #[cfg(feature = "server")]
+    if let Some(addr) = &opts.serve {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        eprintln!(
+            "Serving kernel log stream on http://{}/events and ws://{}/ws",
+            addr, addr
+        );
+        rmesg::server::serve(listener, opts.backend).await?;
+        return Ok(());
+    }
+
+    #[cfg(feature = "grpc")]
+    if let Some(addr) = &opts.serve_grpc {
+        eprintln!("Serving the KernelLog gRPC service on {}", addr);
+        tonic::transport::Server::builder()
+            .add_service(
+                rmesg::grpc::KernelLogService::with_options(opts.backend, false).into_server(),
+            )
+            .serve(addr.parse()?)
+            .await?;
+        return Ok(());
+    }
+
+    #[cfg(feature = "grpc")]
+    if let Some(agents) = &opts.aggregate {
+        let mut entries =
+            rmesg::aggregate::follow_agents(agents, None, rmesg::aggregate::SUGGESTED_DELAY)
+                .await?;
+        while let Some(entry) = entries.try_next().await? {
+            if !opts.shows(&entry) {
+                continue;
+            }
+            let entry = opts.sanitize(entry);
+            match entry.provenance.as_ref().map(|p| &p.origin) {
+                Some(rmesg::provenance::Origin::Remote(host)) => {
+                    println!("{}: {}", host, entry.display_with(&opts.format))
+                }
+                _ => println!("{}", entry.display_with(&opts.format)),
+            }
+        }
+        return Ok(());
+    }
 
     if !opts.follow {
         nofollow(opts);
+        return Ok(());
+    }
+
+    let mut heartbeat = opts.heartbeat.map(start_heartbeat);
+    if opts.restart_on_error {
+        let mut events = Box::pin(
+            rmesg::restart::logs_stream(opts.backend, opts.clear, opts.raw, Default::default())
+                .await?,
+        );
+
+        while let Some(event) = events.try_next().await? {
+            match event {
+                rmesg::restart::FollowEvent::Entry(entry) => {
+                    print_followed(&opts, &mut heartbeat, entry)
+                }
+                rmesg::restart::FollowEvent::Recovered(recovery) => eprintln!(
+                    "Reopened the backend after {} attempts ({:?} down) following error: {}",
+                    recovery.attempts, recovery.downtime, recovery.error
+                ),
+            }
+        }
     } else {
         let mut entries = rmesg::logs_stream(opts.backend, opts.clear, opts.raw).await?;
 
         while let Some(entry) = entries.try_next().await? {
-            println!("{}", entry);
+            print_followed(&opts, &mut heartbeat, entry);
         }
     }
 
     Ok(())
 }
 
+/// Writes a heartbeat marker every `interval` (due back within ten seconds, or the interval
+/// if that's shorter), complaining on stderr about any that don't come back.
+fn start_heartbeat(interval: Duration) -> Heartbeat {
+    let heartbeat = Heartbeat::with_options(interval, interval.min(Duration::from_secs(10)));
+    let ticker = heartbeat.clone();
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = ticker.tick() {
+                eprintln!("Kernel logging may be unhealthy: {}", e);
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    });
+    heartbeat
+}
+
+fn print_followed(opts: &Options, heartbeat: &mut Option<Heartbeat>, entry: Entry) {
+    let entry = match heartbeat {
+        Some(heartbeat) => match heartbeat.process(entry) {
+            Some(entry) => entry,
+            None => return,
+        },
+        None => entry,
+    };
+    if opts.shows(&entry) {
+        println!("{}", opts.sanitize(entry).display_with(&opts.format));
+    }
+}
+
+impl Options {
+    /// Whether `entry` passes the level filter (entries without a level only show unfiltered).
+    fn shows(&self, entry: &Entry) -> bool {
+        match &self.levels {
+            None => true,
+            Some(levels) => entry.level.is_some_and(|level| levels.contains(&level)),
+        }
+    }
+
+    /// `entry`, with its message sanitized if asked to.
+    fn sanitize(&self, mut entry: Entry) -> Entry {
+        if let Some(sanitizer) = &self.sanitizer {
+            if let std::borrow::Cow::Owned(message) = sanitizer.sanitize(&entry.message) {
+                entry.message = message;
+            }
+        }
+        entry
+    }
+}
+
 fn nofollow(opts: Options) {
     if opts.raw {
         let raw = rmesg::logs_raw(opts.backend, opts.clear).unwrap();
         print!("{}", raw)
     } else {
         let entries = rmesg::log_entries(opts.backend, opts.clear).unwrap();
-        for entry in entries {
-            println!("{}", entry)
+        for entry in entries.into_iter().filter(|entry| opts.shows(entry)) {
+            println!("{}", opts.sanitize(entry).display_with(&opts.format))
         }
     }
 }
 
 fn parse_args() -> Options {
-    let matches = App::new("rmest: A 'dmesg' port onto Rust")
+    let app = App::new("rmest: A 'dmesg' port onto Rust")
         .version("0.2.0")
         .author("Archis Gore <archis@polyverse.com>")
         .about(
@@ -52,11 +257,28 @@ fn parse_args() -> Options {
         .arg(
             Arg::with_name("follow")
                 .short("f")
+                .long("follow")
                 .help("When specified, follows logs (like tail -f)"),
         )
+        .arg(
+            Arg::with_name("restart-on-error")
+                .long("restart-on-error")
+                .requires("follow")
+                .help("When following, reopens the backend (with backoff) if reads start failing, rather than exiting"),
+        )
+        .arg(
+            Arg::with_name("heartbeat")
+                .long("heartbeat")
+                .takes_value(true)
+                .value_name("SECS")
+                .requires("follow")
+                .validator(|secs| secs.parse::<u64>().map(|_| ()).map_err(|e| e.to_string()))
+                .help("When following, writes a marker into /dev/kmsg every SECS seconds and warns if it doesn't come back through the read path (needs write access to /dev/kmsg)"),
+        )
         .arg(
             Arg::with_name("clear")
                 .short("c")
+                .long("read-clear")
                 .help("Clear ring buffer after printing"),
         )
         .arg(
@@ -64,29 +286,275 @@ fn parse_args() -> Options {
                 .short("r")
                 .help("Print raw data as it came from the source backend."),
         )
+        .arg(
+            Arg::with_name("level")
+                .short("l")
+                .long("level")
+                .takes_value(true)
+                .value_name("list")
+                .conflicts_with("raw")
+                .validator(|list| parse_levels(&list).map(|_| ()))
+                .help("Only print entries at these levels, comma-separated (e.g. err,warn). Levels: emerg, alert, crit, err, warn, notice, info, debug"),
+        )
+        .arg(
+            Arg::with_name("console-level")
+                .short("n")
+                .long("console-level")
+                .takes_value(true)
+                .value_name("level")
+                .validator(|level| parse_console_level(&level).map(|_| ()))
+                .help("Set the least severe level printed to the console (like dmesg -n), then exit. As a level name, or the kernel's number for it, 1 (emerg only) to 8 (debug)"),
+        )
+        .arg(
+            Arg::with_name("console-off")
+                .short("D")
+                .long("console-off")
+                .conflicts_with_all(&["console-level", "console-on"])
+                .help("Stop printing messages to the console (but panics), then exit"),
+        )
+        .arg(
+            Arg::with_name("console-on")
+                .short("E")
+                .long("console-on")
+                .conflicts_with("console-level")
+                .help("Print messages to the console again, at the level before --console-off, then exit"),
+        )
+        .arg(
+            Arg::with_name("time-precision")
+                .long("time-precision")
+                .takes_value(true)
+                .possible_values(&["s", "ms", "us"])
+                .conflicts_with("raw")
+                .help("Print timestamps to the second, millisecond or microsecond (the default)"),
+        )
+        .arg(
+            Arg::with_name("ctime")
+                .short("T")
+                .long("ctime")
+                .conflicts_with("raw")
+                .help("Print wall-clock times (as RFC 3339, in UTC) instead of the time since system start"),
+        )
+        .arg(
+            Arg::with_name("truncate")
+                .long("truncate")
+                .takes_value(true)
+                .value_name("BYTES")
+                .conflicts_with("raw")
+                .validator(|bytes| bytes.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                .help("Cut messages longer than BYTES short, ending them in \"...\""),
+        )
+        .arg(
+            Arg::with_name("sanitize")
+                .long("sanitize")
+                .takes_value(true)
+                .possible_values(&["escape", "strip"])
+                .conflicts_with("raw")
+                .help("Escape (as \\xNN) or strip terminal control sequences and other hostile characters in messages"),
+        )
         .arg(
             Arg::with_name("backend")
                 .short("b")
                 .takes_value(true)
-                .possible_values(&["klogctl", "devkmsg"])
+                .possible_values(&[
+                    "klogctl",
+                    "devkmsg",
+                    #[cfg(feature = "pstore")]
+                    "pstore",
+                    #[cfg(feature = "journald")]
+                    "journald",
+                    #[cfg(feature = "freebsd")]
+                    "msgbuf",
+                    #[cfg(feature = "netbsdlike")]
+                    "bsdmsgbuf",
+                    #[cfg(feature = "windows")]
+                    "eventlog",
+                    #[cfg(feature = "simulate")]
+                    "simulated",
+                ])
                 .help("Select backend from where to read the logs. klog is the syslog/klogctl system call through libc. kmsg is the /dev/kmsg file."),
+        );
+    #[cfg(feature = "server")]
+    let app = app.arg(
+        Arg::with_name("serve")
+            .long("serve")
+            .takes_value(true)
+            .value_name("ADDR")
+            .help("Serve the entry stream over HTTP at ADDR (e.g. 127.0.0.1:8080) as Server-Sent Events on /events and WebSocket on /ws, filtered by the level and pattern query parameters"),
+    );
+    #[cfg(feature = "grpc")]
+    let app = app
+        .arg(
+            Arg::with_name("serve-grpc")
+                .long("serve-grpc")
+                .takes_value(true)
+                .value_name("ADDR")
+                .help("Serve the KernelLog gRPC service (Snapshot and Follow; Clear is refused) at ADDR (e.g. 0.0.0.0:50051), as an agent for `rmesg aggregate`"),
         )
-        .get_matches();
+        .subcommand(
+            SubCommand::with_name("aggregate")
+                .about("Follows several agents serving gRPC (see --serve-grpc), printing their entries merged in wall-clock order, each prefixed with its host")
+                .arg(
+                    Arg::with_name("AGENT")
+                        .required(true)
+                        .multiple(true)
+                        .help("An agent's endpoint, e.g. http://db1:50051"),
+                ),
+        );
+    #[cfg(feature = "tui")]
+    let app = app.subcommand(
+        SubCommand::with_name("tui")
+            .about("Interactive viewer: follows the log, with level toggles (0-7), search (/) and boot markers (b/B)"),
+    );
+    let app = app.subcommand(
+        SubCommand::with_name("baseline")
+            .about("Compares this boot's message templates against those of a known-good boot")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("save")
+                    .about("Saves this boot's message templates as the baseline")
+                    .arg(Arg::with_name("FILE").required(true)),
+            )
+            .subcommand(
+                SubCommand::with_name("compare")
+                    .about("Prints templates that are new (+) or missing (-) compared to the baseline, exiting with 1 if there are any")
+                    .arg(Arg::with_name("FILE").required(true)),
+            ),
+    );
+    let app = app.subcommand(
+        SubCommand::with_name("export")
+            .about("Exports data for support tickets")
+            .arg(
+                Arg::with_name("bundle")
+                    .long("bundle")
+                    .takes_value(true)
+                    .value_name("FILE")
+                    .required(true)
+                    .help("Writes the snapshot, pstore logs, printk settings and system context to FILE as a .tar.gz"),
+            ),
+    );
+    let matches = app.get_matches();
 
     let follow = !matches!(matches.occurrences_of("follow"), 0);
+    let restart_on_error = !matches!(matches.occurrences_of("restart-on-error"), 0);
+    let heartbeat = matches
+        .value_of("heartbeat")
+        .and_then(|secs| secs.parse().ok())
+        .map(Duration::from_secs);
     let clear = !matches!(matches.occurrences_of("clear"), 0);
     let raw = !matches!(matches.occurrences_of("raw"), 0);
+    // already validated by the parser
+    let levels = matches.value_of("level").and_then(|l| parse_levels(l).ok());
+    let console_level = matches
+        .value_of("console-level")
+        .and_then(|l| parse_console_level(l).ok());
+    let format = FormatOptions {
+        precision: match matches.value_of("time-precision") {
+            Some("s") => TimestampPrecision::Seconds,
+            Some("ms") => TimestampPrecision::Millis,
+            _ => TimestampPrecision::Micros,
+        },
+        max_message_len: matches.value_of("truncate").and_then(|b| b.parse().ok()),
+        ..Default::default()
+    };
+    let sanitizer = matches.value_of("sanitize").map(|action| {
+        Sanitizer::with_options(match action {
+            "strip" => SanitizeAction::Strip,
+            _ => SanitizeAction::Escape,
+        })
+    });
     let backend = match matches.value_of("backend") {
         None => rmesg::Backend::Default,
         Some("klogctl") => rmesg::Backend::KLogCtl,
         Some("devkmsg") => rmesg::Backend::DevKMsg,
+        #[cfg(feature = "pstore")]
+        Some("pstore") => rmesg::Backend::PStore,
+        #[cfg(feature = "journald")]
+        Some("journald") => rmesg::Backend::Journald,
+        #[cfg(feature = "freebsd")]
+        Some("msgbuf") => rmesg::Backend::MsgBuf,
+        #[cfg(feature = "netbsdlike")]
+        Some("bsdmsgbuf") => rmesg::Backend::BsdMsgBuf,
+        #[cfg(feature = "windows")]
+        Some("eventlog") => rmesg::Backend::EventLog,
+        #[cfg(feature = "simulate")]
+        Some("simulated") => rmesg::Backend::Simulated,
         Some(v) => panic!("Something went wrong. Possible values for backend were not restricted by the CLI parser and this value slipped through somehow: {}", v),
     };
 
     Options {
         follow,
+        restart_on_error,
+        heartbeat,
         clear,
         raw,
+        levels,
+        console_level,
+        console_on: match (
+            matches.occurrences_of("console-on"),
+            matches.occurrences_of("console-off"),
+        ) {
+            (0, 0) => None,
+            (0, _) => Some(false),
+            _ => Some(true),
+        },
+        format,
+        ctime: !matches!(matches.occurrences_of("ctime"), 0),
+        sanitizer,
         backend,
+        #[cfg(feature = "server")]
+        serve: matches.value_of("serve").map(|s| s.to_owned()),
+        #[cfg(feature = "grpc")]
+        serve_grpc: matches.value_of("serve-grpc").map(|s| s.to_owned()),
+        #[cfg(feature = "grpc")]
+        aggregate: matches
+            .subcommand_matches("aggregate")
+            .and_then(|m| m.values_of("AGENT"))
+            .map(|agents| agents.map(|a| a.to_owned()).collect()),
+        #[cfg(feature = "tui")]
+        tui: matches.subcommand_matches("tui").is_some(),
+        baseline: matches
+            .subcommand_matches("baseline")
+            .and_then(|m| match m.subcommand() {
+                ("save", Some(m)) => m
+                    .value_of("FILE")
+                    .map(|f| BaselineCommand::Save(f.to_owned())),
+                ("compare", Some(m)) => m
+                    .value_of("FILE")
+                    .map(|f| BaselineCommand::Compare(f.to_owned())),
+                _ => None,
+            }),
+        export_bundle: matches
+            .subcommand_matches("export")
+            .and_then(|m| m.value_of("bundle"))
+            .map(|f| f.to_owned()),
+    }
+}
+
+fn parse_levels(list: &str) -> Result<Vec<LogLevel>, String> {
+    list.split(',')
+        .map(|level| {
+            level.trim().parse().map_err(|_| {
+                format!(
+                    "Unknown log level {} (expected one of emerg, alert, crit, err, warn, notice, info, debug)",
+                    level
+                )
+            })
+        })
+        .collect()
+}
+
+// As dmesg -n takes it: a level name, or the console log level the kernel numbers it by,
+// which is one past the least severe level printed
+fn parse_console_level(level: &str) -> Result<LogLevel, String> {
+    let level = level.trim();
+    match level.parse::<u8>() {
+        Ok(n @ 1..=8) => LogLevel::try_from(n - 1).map_err(|e| e.to_string()),
+        Ok(n) => Err(format!("Console level {} is outside 1 to 8", n)),
+        Err(_) => level.parse().map_err(|_| {
+            format!(
+                "Unknown log level {} (expected 1 to 8, or one of emerg, alert, crit, err, warn, notice, info, debug)",
+                level
+            )
+        }),
     }
 }
@@ -0,0 +1,262 @@
+use crate::entry::{Entry, EntryParsingError, LogFacility};
+/// OpenBSD and NetBSD implementation: reads the kernel message buffer through the
+/// `kern.msgbuf` sysctl, as their dmesg(8) do.
+///
+/// Unlike FreeBSD's, their sysctl hands back the kernel's `struct msgbuf` as is: a few
+/// `long` fields (a magic number and the ring's pointers) followed by the ring itself,
+/// which is unrolled here starting from the write pointer. OpenBSD's header has one
+/// more field than NetBSD's (the count of bytes lost). Only snapshots are supported:
+/// neither system can clear the buffer through a sysctl.
+///
+/// On other platforms this module still builds, but reading fails with
+/// `RMesgError::NotImplementedForThisPlatform`.
+///
+use crate::error::RMesgError;
+use crate::parse;
+use crate::provenance::{self, SourceBackend};
+use crate::source::{self, KLogSource};
+
+use std::os::raw::c_long;
+
+/// `MSG_MAGIC`, which a valid buffer starts with.
+pub const MSG_MAGIC: c_long = 0x063061;
+
+/// The number of `long`s before the ring in OpenBSD's `struct msgbuf`.
+pub const OPENBSD_HEADER_FIELDS: usize = 5;
+
+/// The number of `long`s before the ring in NetBSD's `struct kern_msgbuf`.
+pub const NETBSD_HEADER_FIELDS: usize = 4;
+
+#[cfg(any(target_os = "openbsd", target_os = "netbsd"))]
+fn sysctl_msgbuf() -> Result<Vec<u8>, RMesgError> {
+    use std::ptr;
+
+    let mut mib = [libc::CTL_KERN, libc::KERN_MSGBUF];
+    let mut len: libc::size_t = 0;
+    if unsafe {
+        libc::sysctl(
+            mib.as_mut_ptr(),
+            mib.len() as libc::c_uint,
+            ptr::null_mut(),
+            &mut len,
+            ptr::null_mut(),
+            0,
+        )
+    } != 0
+    {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let mut buf: Vec<u8> = vec![0; len];
+    if unsafe {
+        libc::sysctl(
+            mib.as_mut_ptr(),
+            mib.len() as libc::c_uint,
+            buf.as_mut_ptr() as *mut libc::c_void,
+            &mut len,
+            ptr::null_mut(),
+            0,
+        )
+    } != 0
+    {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    buf.truncate(len);
+    Ok(buf)
+}
+
+#[cfg(not(any(target_os = "openbsd", target_os = "netbsd")))]
+fn sysctl_msgbuf() -> Result<Vec<u8>, RMesgError> {
+    Err(RMesgError::NotImplementedForThisPlatform)
+}
+
+#[cfg(target_os = "openbsd")]
+const HEADER_FIELDS: usize = OPENBSD_HEADER_FIELDS;
+
+#[cfg(not(target_os = "openbsd"))]
+const HEADER_FIELDS: usize = NETBSD_HEADER_FIELDS;
+
+/// Unrolls the ring in a `struct msgbuf` whose header is `header_fields` longs (see
+/// `OPENBSD_HEADER_FIELDS` and `NETBSD_HEADER_FIELDS`), into the messages oldest first.
+pub fn unwrap_msgbuf(raw: &[u8], header_fields: usize) -> Result<String, RMesgError> {
+    let long_size = std::mem::size_of::<c_long>();
+    let header_size = header_fields * long_size;
+    if raw.len() < header_size {
+        return Err(RMesgError::InternalError(format!(
+            "The message buffer is too short ({} bytes) to hold its header",
+            raw.len()
+        )));
+    }
+
+    let field = |i: usize| {
+        let mut bytes = [0u8; std::mem::size_of::<c_long>()];
+        bytes.copy_from_slice(&raw[i * long_size..(i + 1) * long_size]);
+        c_long::from_ne_bytes(bytes)
+    };
+    if field(0) != MSG_MAGIC {
+        return Err(RMesgError::InternalError(format!(
+            "The message buffer has the wrong magic number ({:#x})",
+            field(0)
+        )));
+    }
+
+    let ring = &raw[header_size..];
+    // msg_bufs is the ring's real size, which may be less than what the sysctl returned
+    let size = (field(3).max(0) as usize).min(ring.len());
+    let ring = &ring[..size];
+    let write = (field(1).max(0) as usize).min(size);
+
+    // the oldest bytes are from the write pointer on; unused space is NUL
+    let unrolled: Vec<u8> = ring[write..]
+        .iter()
+        .chain(ring[..write].iter())
+        .copied()
+        .filter(|b| *b != 0)
+        .collect();
+    Ok(String::from_utf8_lossy(&unrolled).into_owned())
+}
+
+/// The messages in the kernel message buffer. Clearing isn't supported.
+pub fn msgbuf_raw(clear: bool) -> Result<String, RMesgError> {
+    if clear {
+        return Err(RMesgError::BackendUnavailable(
+            "The message buffer can't be cleared on OpenBSD or NetBSD".to_owned(),
+        ));
+    }
+    unwrap_msgbuf(&sysctl_msgbuf()?, HEADER_FIELDS)
+}
+
+/// The kernel's entries in the message buffer (see `msgbuf_raw`).
+pub fn msgbuf(clear: bool) -> Result<Vec<Entry>, RMesgError> {
+    Ok(entries_from_msgbuf(&msgbuf_raw(clear)?)?)
+}
+
+/// Counts the kernel's entries in the message buffer.
+pub fn msgbuf_count() -> Result<usize, RMesgError> {
+    Ok(msgbuf(false)?.len())
+}
+
+/// The kernel's entries in the unrolled message buffer. Most lines have no priority and
+/// are taken to be the kernel's; ones in the console format with a facility other than
+/// kern came from userland and are skipped.
+pub fn entries_from_msgbuf(raw: &str) -> Result<Vec<Entry>, EntryParsingError> {
+    let mut entries = Vec::new();
+    for line in raw.lines().filter(|line| !line.is_empty()) {
+        let entry = parse::console_entry_from_line(line)?;
+        if entry.facility.is_none_or(|f| f == LogFacility::Kern) {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// The message buffer as a `KLogSource`, for following it with `source::SourceEntries`.
+/// Like FreeBSD's, the buffer has neither sequence numbers nor timestamps, so new
+/// entries are told apart by how a snapshot overlaps the one before (see
+/// `source::snapshot_overlap`).
+#[derive(Debug, Default)]
+pub struct BsdMsgBufSource {
+    previous: Vec<Entry>,
+}
+
+impl BsdMsgBufSource {
+    fn read(&mut self, only_new: bool) -> Result<Vec<Entry>, RMesgError> {
+        let entries = msgbuf(false)?;
+        let start = match only_new {
+            true => source::snapshot_overlap(&self.previous, &entries),
+            false => 0,
+        };
+        self.previous = entries.clone();
+        Ok(entries
+            .into_iter()
+            .skip(start)
+            .map(|e| provenance::tag(e, SourceBackend::MsgBuf))
+            .collect())
+    }
+}
+
+impl KLogSource for BsdMsgBufSource {
+    fn read_all(&mut self) -> Result<Vec<Entry>, RMesgError> {
+        self.read(false)
+    }
+
+    fn poll_new(&mut self) -> Result<Vec<Entry>, RMesgError> {
+        self.read(true)
+    }
+
+    fn clear(&mut self) -> Result<(), RMesgError> {
+        msgbuf_raw(true).map(|_| ())
+    }
+
+    fn capacity(&self) -> Result<usize, RMesgError> {
+        let raw = sysctl_msgbuf()?;
+        Ok(raw
+            .len()
+            .saturating_sub(HEADER_FIELDS * std::mem::size_of::<c_long>()))
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // a struct msgbuf with `header_fields` longs, holding `ring` with the write pointer
+    // at `write`
+    fn msgbuf_bytes(header_fields: usize, write: usize, ring: &[u8]) -> Vec<u8> {
+        let mut header = vec![0 as c_long; header_fields];
+        header[0] = MSG_MAGIC;
+        header[1] = write as c_long;
+        header[3] = ring.len() as c_long;
+        let mut raw: Vec<u8> = header.iter().flat_map(|f| f.to_ne_bytes()).collect();
+        raw.extend_from_slice(ring);
+        raw
+    }
+
+    #[test]
+    fn test_unwrap_msgbuf() {
+        // not yet wrapped: the rest of the ring is NUL
+        let raw = msgbuf_bytes(OPENBSD_HEADER_FIELDS, 11, b"em0: up\nx\n\0\0\0\0");
+        assert_eq!(
+            unwrap_msgbuf(&raw, OPENBSD_HEADER_FIELDS).unwrap(),
+            "em0: up\nx\n"
+        );
+
+        // wrapped: the oldest bytes are after the write pointer
+        let raw = msgbuf_bytes(NETBSD_HEADER_FIELDS, 4, b"new\nlder\nnewer\no");
+        assert_eq!(
+            unwrap_msgbuf(&raw, NETBSD_HEADER_FIELDS).unwrap(),
+            "lder\nnewer\nonew\n"
+        );
+
+        let mut corrupt = raw.clone();
+        corrupt[0] ^= 1;
+        assert!(unwrap_msgbuf(&corrupt, NETBSD_HEADER_FIELDS).is_err());
+        assert!(unwrap_msgbuf(&raw[..8], NETBSD_HEADER_FIELDS).is_err());
+    }
+
+    #[test]
+    fn test_entries_from_msgbuf() {
+        let entries =
+            entries_from_msgbuf("OpenBSD 7.4 (GENERIC.MP) #1397\n<13>user: not the kernel's\n<6>em0: link state changed to UP\n")
+                .unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].facility, None);
+        assert_eq!(entries[1].message, "em0: link state changed to UP");
+    }
+
+    #[cfg(not(any(target_os = "openbsd", target_os = "netbsd")))]
+    #[test]
+    fn test_unsupported_platform() {
+        assert!(matches!(
+            msgbuf(false),
+            Err(RMesgError::NotImplementedForThisPlatform)
+        ));
+        assert!(matches!(
+            msgbuf(true),
+            Err(RMesgError::BackendUnavailable(_))
+        ));
+    }
+}
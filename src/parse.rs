@@ -0,0 +1,505 @@
+/// Parsers for the formats kernel log records come in.
+///
+/// For text captured elsewhere (dmesg dumps from crash reports, say), `parse_line` and
+/// `parse_buffer` work out which of the formats each line is in.
+///
+/// These don't touch the OS at all, so they are available whatever backends are
+/// enabled, and compile for targets like wasm32 (e.g. for browser-based log viewers)
+/// with `default-features = false`.
+///
+use crate::common;
+use crate::entry::{Entry, EntryParsingError, LogFacility, LogLevel};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    static ref RE_CONSOLE_ENTRY: Regex = Regex::new(
+        r"(?x)^
+        [[:space:]]*<(?P<faclevstr>[[:digit:]]*)>
+        [[:space:]]*([\[][[:space:]]*(?P<timestampstr>[[:digit:]]*\.[[:digit:]]*)[\]])?
+        (?P<message>.*)
+        $"
+    )
+    .unwrap();
+
+    static ref RE_KMSG_ENTRY: Regex = Regex::new(
+        r"(?x)^
+            [[:space:]]*(?P<faclevstr>[[:digit:]]*)[[:space:]]*,
+            # Sequence is a 64-bit integer: https://www.kernel.org/doc/Documentation/ABI/testing/dev-kmsg
+            [[:space:]]*(?P<sequencenum>[[:digit:]]*)[[:space:]]*,
+            [[:space:]]*(?P<timestampstr>[[:digit:]]*)[[:space:]]*,
+            # Ignore everything until the semi-colon and then the semicolon
+            [[^;]]*;
+            (?P<message>.*)
+            $"
+    )
+    .unwrap();
+
+    // What dmesg(1) prints without -r: the timestamp, but no <faclev>
+    static ref RE_DMESG_ENTRY: Regex = Regex::new(
+        r"(?x)^
+        [[:space:]]*[\[][[:space:]]*(?P<timestampstr>[[:digit:]]+\.[[:digit:]]+)[\]][[:space:]]?
+        (?P<message>.*)
+        $"
+    )
+    .unwrap();
+
+    // Stricter than RE_KMSG_ENTRY, to tell records apart from messages with commas in
+    static ref RE_KMSG_RECORD: Regex =
+        Regex::new(r"^[[:digit:]]+,[[:digit:]]+,[[:digit:]]+,[^;]*;").unwrap();
+    static ref RE_CONSOLE_RECORD: Regex = Regex::new(r"^[[:space:]]*<[[:digit:]]+>").unwrap();
+}
+
+/// What to do with a malformed record: one that has the shape of a record, but a
+/// field that doesn't parse (a facility/level out of range, a number that overflows).
+/// Lines that aren't records at all (such as /dev/kmsg continuation lines) become
+/// message-only entries either way.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Malformed records are errors (for forensic tools, which shouldn't guess)
+    #[default]
+    Strict,
+    /// Malformed records become a best-effort entry, with each field that didn't parse
+    /// left unset and `malformed` set (for shippers, which shouldn't stop)
+    Permissive,
+}
+
+// A field of a record: in permissive mode, one that doesn't parse is left unset and
+// the entry marked malformed
+fn field<T>(
+    parsed: Result<T, EntryParsingError>,
+    mode: ParseMode,
+    malformed: &mut bool,
+) -> Result<Option<T>, EntryParsingError> {
+    match (parsed, mode) {
+        (Ok(value), _) => Ok(Some(value)),
+        (Err(e), ParseMode::Strict) => Err(e),
+        (Err(_), ParseMode::Permissive) => {
+            *malformed = true;
+            Ok(None)
+        }
+    }
+}
+
+// The raw <PRI>, and the facility and level it decodes to
+#[allow(clippy::type_complexity)]
+fn faclev(
+    faclevstr: &str,
+    line: &str,
+    mode: ParseMode,
+    malformed: &mut bool,
+) -> Result<(Option<u32>, Option<LogFacility>, Option<LogLevel>), EntryParsingError> {
+    let priority = field(
+        common::parse_fragment::<u32>(faclevstr, line),
+        mode,
+        malformed,
+    )?;
+    let (facility, level) = match priority {
+        Some(priority) => {
+            field(common::decode_faclev(priority, line), mode, malformed)?.unwrap_or((None, None))
+        }
+        None => (None, None),
+    };
+    Ok((priority, facility, level))
+}
+
+/// Parses a line in the console format that klogctl (and pstore) produce:
+/// <5>[   233434.343533] a.out[4054]: segfault at 7ffd5503d358 ip 00007ffd5503d358 sp 00007ffd5503d258 error 15
+/// The timestamp is optional; lines without a <faclev> prefix become message-only entries.
+pub fn console_entry_from_line(line: &str) -> Result<Entry, EntryParsingError> {
+    console_entry(line, ParseMode::Strict)
+}
+
+/// Like `console_entry_from_line`, handling malformed records as `mode` says.
+pub fn console_entry(line: &str, mode: ParseMode) -> Result<Entry, EntryParsingError> {
+    #[cfg(feature = "bench")]
+    crate::counters::bump(crate::counters::Counter::LinesParsed);
+
+    if let Some(klogparts) = RE_CONSOLE_ENTRY.captures(line) {
+        let mut malformed = false;
+        let (priority, facility, level) = match klogparts.name("faclevstr") {
+            Some(faclevstr) => faclev(faclevstr.as_str(), line, mode, &mut malformed)?,
+            None => (None, None, None),
+        };
+
+        let timestamp_from_system_start = match klogparts.name("timestampstr") {
+            Some(timestampstr) => field(
+                common::parse_timestamp_secs(timestampstr.as_str(), line),
+                mode,
+                &mut malformed,
+            )?
+            .flatten(),
+            None => None,
+        };
+
+        let message = klogparts["message"].to_owned();
+
+        Ok(Entry {
+            facility,
+            level,
+            sequence_num: None,
+            timestamp_from_system_start,
+            message,
+            timestamp_realtime: None,
+            provenance: None,
+            malformed,
+            priority,
+            tags: Default::default(),
+        })
+    } else {
+        Ok(Entry {
+            facility: None,
+            level: None,
+            sequence_num: None,
+            timestamp_from_system_start: None,
+            message: line.to_owned(),
+            timestamp_realtime: None,
+            provenance: None,
+            malformed: false,
+            priority: None,
+            tags: Default::default(),
+        })
+    }
+}
+
+// Message spec: https://github.com/torvalds/linux/blob/master/Documentation/ABI/testing/dev-kmsg
+// Parses a kernel log line that looks like this (we ignore lines wtihout the timestamp):
+// 5,0,0,-;Linux version 4.14.131-linuxkit (root@6d384074ad24) (gcc version 8.3.0 (Alpine 8.3.0)) #1 SMP Fri Jul 19 12:31:17 UTC 2019
+// 6,1,0,-;Command, line: BOOT_IMAGE=/boot/kernel console=ttyS0 console=ttyS1 page_poison=1 vsyscall=emulate panic=1 root=/dev/sr0 text
+//  LINE2=foobar
+//  LINE 3 = foobar ; with semicolon
+// 6,2,0,-;x86/fpu: Supporting XSAVE feature 0x001: 'x87 floating point registers'
+// 6,3,0,-,more,deets;x86/fpu: Supporting XSAVE; feature 0x002: 'SSE registers'
+// The kernel's \xNN escapes (of control characters, backslashes and non-ASCII bytes)
+// in the message are undone.
+pub fn kmsg_entry_from_line(line: &str) -> Result<Entry, EntryParsingError> {
+    kmsg_entry(line, ParseMode::Strict)
+}
+
+/// Like `kmsg_entry_from_line`, handling malformed records as `mode` says.
+pub fn kmsg_entry(line: &str, mode: ParseMode) -> Result<Entry, EntryParsingError> {
+    #[cfg(feature = "bench")]
+    crate::counters::bump(crate::counters::Counter::LinesParsed);
+
+    if let Some(kmsgparts) = RE_KMSG_ENTRY.captures(line) {
+        let mut malformed = false;
+        let (priority, facility, level) = match kmsgparts.name("faclevstr") {
+            Some(faclevstr) => faclev(faclevstr.as_str(), line, mode, &mut malformed)?,
+            None => (None, None, None),
+        };
+
+        let sequence_num = match kmsgparts.name("sequencenum") {
+            Some(sequencestr) => field(
+                common::parse_fragment::<usize>(sequencestr.as_str(), line),
+                mode,
+                &mut malformed,
+            )?,
+            None => None,
+        };
+
+        let timestamp_from_system_start = match kmsgparts.name("timestampstr") {
+            Some(timestampstr) => field(
+                common::parse_timestamp_microsecs(timestampstr.as_str(), line),
+                mode,
+                &mut malformed,
+            )?
+            .flatten(),
+            None => None,
+        };
+
+        let message = common::unescape_kmsg_message(&kmsgparts["message"]);
+
+        Ok(Entry {
+            facility,
+            level,
+            sequence_num,
+            timestamp_from_system_start,
+            message,
+            timestamp_realtime: None,
+            provenance: None,
+            malformed,
+            priority,
+            tags: Default::default(),
+        })
+    } else {
+        Ok(Entry {
+            facility: None,
+            level: None,
+            sequence_num: None,
+            timestamp_from_system_start: None,
+            message: line.to_owned(),
+            timestamp_realtime: None,
+            provenance: None,
+            malformed: false,
+            priority: None,
+            tags: Default::default(),
+        })
+    }
+}
+
+/// Parses a line as dmesg(1) prints it (without -r), with a timestamp but no <faclev>:
+/// [   12.500000] nvme nvme0: I/O 12 QID 3 timeout, aborting
+/// Lines without a timestamp become message-only entries.
+pub fn dmesg_entry_from_line(line: &str) -> Result<Entry, EntryParsingError> {
+    dmesg_entry(line, ParseMode::Strict)
+}
+
+/// Like `dmesg_entry_from_line`, handling malformed records as `mode` says.
+pub fn dmesg_entry(line: &str, mode: ParseMode) -> Result<Entry, EntryParsingError> {
+    #[cfg(feature = "bench")]
+    crate::counters::bump(crate::counters::Counter::LinesParsed);
+
+    let mut malformed = false;
+    let (timestamp_from_system_start, message) = match RE_DMESG_ENTRY.captures(line) {
+        Some(parts) => (
+            field(
+                common::parse_timestamp_secs(&parts["timestampstr"], line),
+                mode,
+                &mut malformed,
+            )?
+            .flatten(),
+            parts["message"].to_owned(),
+        ),
+        None => (None, line.to_owned()),
+    };
+
+    Ok(Entry {
+        facility: None,
+        level: None,
+        sequence_num: None,
+        timestamp_from_system_start,
+        message,
+        timestamp_realtime: None,
+        provenance: None,
+        malformed,
+        priority: None,
+        tags: Default::default(),
+    })
+}
+
+/// The formats `parse_line` tells apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineFormat {
+    /// A /dev/kmsg record: 6,1234,5000000,-;message
+    Kmsg,
+    /// The console format klogctl and pstore produce: <6>[    5.000000] message
+    Console,
+    /// What dmesg(1) prints: [    5.000000] message
+    Dmesg,
+    /// Anything else (e.g. the continuation of a multi-line message)
+    MessageOnly,
+}
+
+impl LineFormat {
+    pub fn of(line: &str) -> LineFormat {
+        if RE_KMSG_RECORD.is_match(line) {
+            LineFormat::Kmsg
+        } else if RE_CONSOLE_RECORD.is_match(line) {
+            LineFormat::Console
+        } else if RE_DMESG_ENTRY.is_match(line) {
+            LineFormat::Dmesg
+        } else {
+            LineFormat::MessageOnly
+        }
+    }
+}
+
+/// Parses a line of kernel log text in whichever format it's in (see `LineFormat`).
+pub fn parse_line(line: &str) -> Result<Entry, EntryParsingError> {
+    parse_line_as(line, LineFormat::of(line), ParseMode::Strict)
+}
+
+fn parse_line_as(
+    line: &str,
+    format: LineFormat,
+    mode: ParseMode,
+) -> Result<Entry, EntryParsingError> {
+    match format {
+        LineFormat::Kmsg => kmsg_entry(line, mode),
+        LineFormat::Console => console_entry(line, mode),
+        LineFormat::Dmesg | LineFormat::MessageOnly => dmesg_entry(line, mode),
+    }
+}
+
+/// Parses a buffer of kernel log text, such as a dmesg dump, one entry per line in
+/// whichever format each is in. Blank lines, and the key/value dictionaries following
+/// /dev/kmsg records (lines starting with a space), are skipped. This never fails:
+/// malformed records are parsed permissively (see `ParseMode`).
+pub fn parse_buffer(raw: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut after_kmsg = false;
+    for line in raw.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let format = LineFormat::of(line);
+        if after_kmsg && format == LineFormat::MessageOnly && line.starts_with(' ') {
+            continue;
+        }
+        after_kmsg = format == LineFormat::Kmsg;
+        // permissive parsing doesn't fail
+        if let Ok(entry) = parse_line_as(line, format, ParseMode::Permissive) {
+            entries.push(entry);
+        }
+    }
+    entries
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entry::{LogFacility, LogLevel};
+    use std::time::Duration;
+
+    #[test]
+    fn test_console_entry_from_line() {
+        let entry =
+            console_entry_from_line("<3>[   12.500000] nvme nvme0: I/O 12 QID 3 timeout, aborting")
+                .unwrap();
+        assert_eq!(entry.facility, Some(LogFacility::Kern));
+        assert_eq!(entry.level, Some(LogLevel::Error));
+        assert_eq!(
+            entry.timestamp_from_system_start,
+            Some(Duration::from_millis(12500))
+        );
+        assert_eq!(entry.message, " nvme nvme0: I/O 12 QID 3 timeout, aborting");
+
+        let entry = console_entry_from_line("continued line").unwrap();
+        assert_eq!(entry.level, None);
+        assert_eq!(entry.message, "continued line");
+    }
+
+    #[test]
+    fn test_kmsg_entry_from_line() {
+        let entry =
+            kmsg_entry_from_line("6,3,1500000,-,more,deets;x86/fpu: Supporting XSAVE; feature")
+                .unwrap();
+        assert_eq!(entry.priority, Some(6));
+        assert_eq!(entry.level, Some(LogLevel::Info));
+        assert_eq!(entry.sequence_num, Some(3));
+        assert_eq!(
+            entry.timestamp_from_system_start,
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(entry.message, "x86/fpu: Supporting XSAVE; feature");
+
+        assert!(kmsg_entry_from_line("999999,1,0,-;bad faclev").is_err());
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        // an overflowing sequence number and an out of range faclev
+        let line = "999999,99999999999999999999999,1500000,-;bad record";
+        assert!(kmsg_entry(line, ParseMode::Strict).is_err());
+
+        let entry = kmsg_entry(line, ParseMode::Permissive).unwrap();
+        assert!(entry.malformed);
+        // the raw priority is kept even though it doesn't decode
+        assert_eq!(entry.priority, Some(999999));
+        assert_eq!(entry.facility, None);
+        assert_eq!(entry.sequence_num, None);
+        assert_eq!(
+            entry.timestamp_from_system_start,
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(entry.message, "bad record");
+
+        let entry =
+            console_entry("<999999>[    1.500000] bad record", ParseMode::Permissive).unwrap();
+        assert!(entry.malformed);
+        assert_eq!(entry.level, None);
+        assert_eq!(entry.message, " bad record");
+
+        // a timestamp no Duration can hold
+        let line = "<6>[99999999999999999999.0] x";
+        assert!(console_entry(line, ParseMode::Strict).is_err());
+        let entry = console_entry(line, ParseMode::Permissive).unwrap();
+        assert!(entry.malformed);
+        assert_eq!(entry.timestamp_from_system_start, None);
+        assert_eq!(entry.level, Some(LogLevel::Info));
+        assert_eq!(entry.message, " x");
+        let entry = dmesg_entry(&line[3..], ParseMode::Permissive).unwrap();
+        assert!(entry.malformed);
+        assert_eq!(entry.timestamp_from_system_start, None);
+        assert_eq!(entry.message, "x");
+
+        // well-formed records, and lines that aren't records, aren't malformed
+        for line in ["6,1,0,-;good record", " SUBSYSTEM=usb"] {
+            let entry = kmsg_entry(line, ParseMode::Permissive).unwrap();
+            assert!(!entry.malformed);
+            assert_eq!(entry, kmsg_entry_from_line(line).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let kmsg =
+            parse_line("3,42,12500000,-;nvme nvme0: I/O 12 QID 3 timeout, aborting").unwrap();
+        assert_eq!(kmsg.sequence_num, Some(42));
+        assert_eq!(kmsg.level, Some(LogLevel::Error));
+
+        let console =
+            parse_line("<3>[   12.500000] nvme nvme0: I/O 12 QID 3 timeout, aborting").unwrap();
+        assert_eq!(console.level, Some(LogLevel::Error));
+        assert_eq!(
+            console.timestamp_from_system_start,
+            Some(Duration::from_millis(12500))
+        );
+
+        let dmesg =
+            parse_line("[   12.500000] nvme nvme0: I/O 12 QID 3 timeout, aborting").unwrap();
+        assert_eq!(dmesg.level, None);
+        assert_eq!(
+            dmesg.timestamp_from_system_start,
+            Some(Duration::from_millis(12500))
+        );
+        assert_eq!(dmesg.message, "nvme nvme0: I/O 12 QID 3 timeout, aborting");
+
+        // commas don't make a kmsg record
+        let message = parse_line("Command, line: BOOT_IMAGE=/boot/kernel").unwrap();
+        assert_eq!(LineFormat::of(&message.message), LineFormat::MessageOnly);
+        assert_eq!(message.message, "Command, line: BOOT_IMAGE=/boot/kernel");
+        assert!(parse_line("999999,1,0,-;bad faclev").is_err());
+    }
+
+    #[test]
+    fn test_parse_buffer() {
+        let entries = parse_buffer(
+            "6,1,1000000,-;usb 1-1: new high-speed USB device\n SUBSYSTEM=usb\n DEVICE=c189:1\n\n\
+             999999,2,2000000,-;bad faclev\n\
+             <4>[    3.000000] ACPI Warning: SystemIO range conflicts\n\
+             [    4.000000] EXT4-fs (sda1): mounted filesystem\n\
+             \x20continued, but not after a kmsg record\n\
+             [99999999999999999999.0] x\n\
+             <6>[99999999999999999999.0] x\n",
+        );
+        let messages: Vec<&str> = entries.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "usb 1-1: new high-speed USB device",
+                "bad faclev",
+                " ACPI Warning: SystemIO range conflicts",
+                "EXT4-fs (sda1): mounted filesystem",
+                " continued, but not after a kmsg record",
+                "x",
+                " x",
+            ]
+        );
+        assert!(entries[1].malformed);
+        assert_eq!(entries[1].sequence_num, Some(2));
+        assert_eq!(entries[2].level, Some(LogLevel::Warning));
+        assert_eq!(
+            entries[3].timestamp_from_system_start,
+            Some(Duration::from_secs(4))
+        );
+        for entry in &entries[5..] {
+            assert!(entry.malformed);
+            assert_eq!(entry.timestamp_from_system_start, None);
+        }
+    }
+}
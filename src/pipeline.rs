@@ -0,0 +1,705 @@
+use crate::bookmark::{Bookmark, BookmarkTracker};
+/// Pipelines: a source, stages and sinks running on their own threads, with a shutdown
+/// that doesn't lose what's in flight.
+///
+/// Every sink has its own bounded queue and worker thread, so a slow sink (a collector
+/// that's down, a webhook being rate limited) falls behind on its own: when its queue is
+/// full, entries are dropped for it, and counted, rather than stalling the source and
+/// every other sink. `Pipeline::stats` reports how far behind each sink is.
+///
+/// A daemon that just exits on SIGTERM loses whatever was read but not yet delivered:
+/// entries queued for the sinks, and whatever the sinks buffer (batching sinks like
+/// Loki's hold entries until a batch fills). `Pipeline::shutdown` winds down in order
+/// instead:
+///
+/// 1. the source is stopped: nothing read after this is passed on,
+/// 2. each queue is drained into its sink,
+/// 3. the sinks are flushed, within the deadline,
+/// 4. the cursor (a bookmark just past the last entry every sink has delivered and
+///    flushed, short of any a sink missed) is written to `PipelineOptions::cursor_path`,
+///    for the next run to `replay_from`.
+///
+/// A source blocked waiting for the next entry can't be interrupted, so its thread is
+/// left to end with the process. Anything it reads after the stop isn't delivered, and
+/// isn't covered by the cursor either, so the next run replays it rather than losing it.
+/// The same goes for entries buffered in sinks that don't flush by the deadline, and from
+/// the first entry a sink missed (dropped for it, or failed to write) on: the next run
+/// replays them to every sink, so those are delivered at least once rather than never.
+///
+use crate::entry::Entry;
+use crate::error::RMesgError;
+use crate::sinks::Sink;
+use crate::stage::Stage;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How often an idle sink worker checks whether it's been asked to stop
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PipelineOptions {
+    /// How many entries can wait for each sink before they're dropped for it
+    pub queue_capacity: usize,
+    /// Where `shutdown` writes the cursor (as a bookmark token), or `None` to not
+    pub cursor_path: Option<PathBuf>,
+}
+
+impl Default for PipelineOptions {
+    fn default() -> Self {
+        Self {
+            queue_capacity: 1024,
+            cursor_path: None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct SourceProgress {
+    passed: u64,
+    error: Option<String>,
+    ended: bool,
+}
+
+#[derive(Debug, Default)]
+struct SinkProgress {
+    queued: usize,
+    written: u64,
+    dropped: u64,
+    errors: u64,
+    last_error: Option<String>,
+    // just past the last entry written and flushed
+    flushed: Option<Bookmark>,
+}
+
+#[derive(Debug)]
+struct Progress {
+    source: Mutex<SourceProgress>,
+    sinks: Vec<Mutex<SinkProgress>>,
+}
+
+/// A running pipeline, reading entries from a source, passing them through stages and
+/// writing them to every sink.
+pub struct Pipeline {
+    stop: Arc<AtomicBool>,
+    progress: Arc<Progress>,
+    done: Receiver<()>,
+    cursor_path: Option<PathBuf>,
+}
+
+impl Pipeline {
+    /// Starts reading `source` (any of this crate's iterators, e.g. a follower) on one
+    /// thread, and writing what `stages` pass on to each of `sinks` on a thread of its own.
+    pub fn spawn<I>(
+        source: I,
+        stages: Vec<Box<dyn Stage + Send>>,
+        sinks: Vec<Box<dyn Sink + Send>>,
+        options: PipelineOptions,
+    ) -> Pipeline
+    where
+        I: Iterator<Item = Result<Entry, RMesgError>> + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let progress = Arc::new(Progress {
+            source: Mutex::new(SourceProgress::default()),
+            sinks: sinks.iter().map(|_| Mutex::default()).collect(),
+        });
+        let (finished, done) = mpsc::channel();
+
+        let mut queues = Vec::with_capacity(sinks.len());
+        for (index, sink) in sinks.into_iter().enumerate() {
+            let (queue, queued) = mpsc::sync_channel(options.queue_capacity.max(1));
+            queues.push(queue);
+            let worker = SinkWorker {
+                index,
+                sink,
+                queued,
+                stop: stop.clone(),
+                progress: progress.clone(),
+                finished: finished.clone(),
+                delivered: BookmarkTracker::new(),
+                delivered_count: 0,
+                missed: false,
+            };
+            thread::spawn(move || worker.run());
+        }
+
+        let (reader_stop, reader_progress) = (stop.clone(), progress.clone());
+        thread::spawn(move || {
+            read(source, stages, queues, &reader_stop, &reader_progress);
+            lock(&reader_progress.source).ended = true;
+        });
+
+        Pipeline {
+            stop,
+            progress,
+            done,
+            cursor_path: options.cursor_path,
+        }
+    }
+
+    /// How far through the source and each sink the pipeline is.
+    pub fn stats(&self) -> PipelineStats {
+        PipelineStats {
+            passed: lock(&self.progress.source).passed,
+            sinks: self
+                .progress
+                .sinks
+                .iter()
+                .map(|sink| {
+                    let sink = lock(sink);
+                    SinkStats {
+                        lag: sink.queued,
+                        written: sink.written,
+                        dropped: sink.dropped,
+                        errors: sink.errors,
+                        last_error: sink.last_error.clone(),
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// Whether the source has ended (run out, or failed), and so everything it passed on
+    /// is queued: a finite source can be shut down once it has, losing nothing.
+    pub fn source_ended(&self) -> bool {
+        lock(&self.progress.source).ended
+    }
+
+    /// Stops the source, drains the queues into the sinks, flushes them and writes the
+    /// cursor, giving up on the sinks after `timeout`. When they don't finish in time,
+    /// the cursor isn't written (the last one stays), and this is a `Timeout`.
+    pub fn shutdown(self, timeout: Duration) -> Result<ShutdownReport, RMesgError> {
+        let deadline = Instant::now() + timeout;
+        self.stop.store(true, Ordering::SeqCst);
+
+        let mut unfinished = self.progress.sinks.len();
+        while unfinished > 0 {
+            match self
+                .done
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                Ok(()) => unfinished -= 1,
+                Err(RecvTimeoutError::Disconnected) => break,
+                Err(RecvTimeoutError::Timeout) if Instant::now() < deadline => continue,
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(RMesgError::Timeout(format!(
+                        "{} of {} sinks didn't flush within {:?}; the cursor wasn't moved",
+                        unfinished,
+                        self.progress.sinks.len(),
+                        timeout
+                    )))
+                }
+            }
+        }
+
+        // as far as the sink furthest behind got
+        let flushed: Option<Vec<Bookmark>> = self
+            .progress
+            .sinks
+            .iter()
+            .map(|sink| lock(sink).flushed.clone())
+            .collect();
+        let cursor = flushed.and_then(|cursors| {
+            cursors.into_iter().min_by_key(|cursor| {
+                (
+                    cursor.mark.sequence_num,
+                    cursor.mark.timestamp_from_system_start,
+                    cursor.at_timestamp,
+                )
+            })
+        });
+        if let (Some(path), Some(cursor)) = (&self.cursor_path, &cursor) {
+            persist_cursor(path, cursor)?;
+        }
+
+        Ok(ShutdownReport {
+            stats: self.stats(),
+            source_error: lock(&self.progress.source).error.clone(),
+            cursor,
+        })
+    }
+}
+
+/// How far through the source and each sink a pipeline is.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PipelineStats {
+    /// Entries the stages passed on to the sinks
+    pub passed: u64,
+    /// In the order the sinks were given to `Pipeline::spawn`
+    pub sinks: Vec<SinkStats>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SinkStats {
+    /// Entries queued for the sink, waiting to be written
+    pub lag: usize,
+    /// Entries the sink took without an error
+    pub written: u64,
+    /// Entries dropped for the sink because its queue was full
+    pub dropped: u64,
+    /// Writes and flushes that failed
+    pub errors: u64,
+    pub last_error: Option<String>,
+}
+
+/// How a pipeline's run went, once it's shut down.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShutdownReport {
+    pub stats: PipelineStats,
+    /// The error that ended the source early, if one did
+    pub source_error: Option<String>,
+    /// Just past the last entry every sink delivered and flushed before the first it
+    /// missed (as written to the cursor path), or `None` if one of them has nothing
+    /// flushed, or its flush failed
+    pub cursor: Option<Bookmark>,
+}
+
+fn read<I>(
+    source: I,
+    mut stages: Vec<Box<dyn Stage + Send>>,
+    queues: Vec<SyncSender<Queued>>,
+    stop: &AtomicBool,
+    progress: &Progress,
+) where
+    I: Iterator<Item = Result<Entry, RMesgError>>,
+{
+    // for each sink, whether an entry has been dropped for it
+    let mut missed = vec![false; queues.len()];
+    for result in source {
+        if stop.load(Ordering::SeqCst) {
+            return;
+        }
+        let entry = match result {
+            Ok(entry) => match stages.process(entry) {
+                Some(entry) => Arc::new(entry),
+                None => continue,
+            },
+            Err(e) => {
+                lock(&progress.source).error = Some(e.to_string());
+                return;
+            }
+        };
+
+        lock(&progress.source).passed += 1;
+        for ((queue, sink), missed) in queues.iter().zip(&progress.sinks).zip(&mut missed) {
+            // counted as queued first, so the worker never takes it out of a count of 0
+            lock(sink).queued += 1;
+            let queued = Queued {
+                entry: entry.clone(),
+                after_missed: *missed,
+            };
+            if let Err(e) = queue.try_send(queued) {
+                let mut sink = lock(sink);
+                sink.queued -= 1;
+                if let TrySendError::Full(_) = e {
+                    sink.dropped += 1;
+                    *missed = true;
+                }
+            }
+        }
+    }
+}
+
+// An entry for a sink, and whether one before it was dropped for the sink
+struct Queued {
+    entry: Arc<Entry>,
+    after_missed: bool,
+}
+
+struct SinkWorker {
+    index: usize,
+    sink: Box<dyn Sink + Send>,
+    queued: Receiver<Queued>,
+    stop: Arc<AtomicBool>,
+    progress: Arc<Progress>,
+    finished: Sender<()>,
+    // just past the last entry written before the first the sink missed (dropped for it,
+    // or failed to write), and how many entries that's past
+    delivered: BookmarkTracker,
+    delivered_count: u64,
+    missed: bool,
+}
+
+impl SinkWorker {
+    fn run(mut self) {
+        // until the source ends, or shutdown stops it
+        while !self.stop.load(Ordering::SeqCst) {
+            match self.queued.recv_timeout(STOP_CHECK_INTERVAL) {
+                Ok(queued) => self.write(queued),
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        // what was queued before the stop
+        while let Ok(queued) = self.queued.try_recv() {
+            self.write(queued);
+        }
+
+        let flushed = self.sink.flush();
+        let mut progress = lock(&self.progress.sinks[self.index]);
+        match flushed {
+            Ok(()) if self.delivered_count > 0 => {
+                progress.flushed = Some(self.delivered.bookmark())
+            }
+            Ok(()) => {}
+            Err(e) => {
+                progress.errors += 1;
+                progress.last_error = Some(e.to_string());
+            }
+        }
+        drop(progress);
+        let _ = self.finished.send(());
+    }
+
+    fn write(&mut self, queued: Queued) {
+        let written = self.sink.write(&queued.entry);
+        self.missed |= queued.after_missed || written.is_err();
+        let mut progress = lock(&self.progress.sinks[self.index]);
+        progress.queued -= 1;
+        match written {
+            Ok(()) => {
+                progress.written += 1;
+                if !self.missed {
+                    self.delivered.track(&queued.entry);
+                    self.delivered_count += 1;
+                }
+            }
+            Err(e) => {
+                progress.errors += 1;
+                progress.last_error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+// Written aside and renamed into place, so that a kill mid-write leaves the old cursor
+fn persist_cursor(path: &Path, cursor: &Bookmark) -> Result<(), RMesgError> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    fs::write(&partial, format!("{}\n", cursor.to_token()))?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+fn lock<T>(progress: &Mutex<T>) -> MutexGuard<'_, T> {
+    // progress stays consistent if a sink panics while its worker holds the lock
+    progress.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(n: usize) -> Entry {
+        Entry {
+            sequence_num: Some(n),
+            timestamp_from_system_start: Some(Duration::from_millis(n as u64)),
+            message: format!("message {}", n),
+            ..Default::default()
+        }
+    }
+
+    // Buffers entries until flushed, which takes `flush_delay`. While `gate` is open,
+    // writes wait for it, and the write of entry `fail_on` fails
+    struct Buffering {
+        buffered: Vec<Entry>,
+        flushed: Arc<Mutex<Vec<Entry>>>,
+        flush_delay: Duration,
+        gate: Option<Receiver<()>>,
+        fail_on: Option<usize>,
+    }
+
+    impl Buffering {
+        fn new(flush_delay: Duration) -> (Buffering, Arc<Mutex<Vec<Entry>>>) {
+            let flushed = Arc::new(Mutex::new(vec![]));
+            let sink = Buffering {
+                buffered: vec![],
+                flushed: flushed.clone(),
+                flush_delay,
+                gate: None,
+                fail_on: None,
+            };
+            (sink, flushed)
+        }
+    }
+
+    impl Sink for Buffering {
+        fn write(&mut self, entry: &Entry) -> Result<(), RMesgError> {
+            if let Some(gate) = &self.gate {
+                // until the sender's dropped
+                let _ = gate.recv();
+            }
+            if entry.sequence_num.is_some() && entry.sequence_num == self.fail_on {
+                return Err(RMesgError::SinkError("rejected".to_owned()));
+            }
+            self.buffered.push(entry.clone());
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), RMesgError> {
+            thread::sleep(self.flush_delay);
+            self.flushed.lock().unwrap().append(&mut self.buffered);
+            Ok(())
+        }
+    }
+
+    // Fails to write entries, or takes them and fails to flush them
+    struct Failing {
+        in_flush: bool,
+    }
+
+    impl Sink for Failing {
+        fn write(&mut self, _entry: &Entry) -> Result<(), RMesgError> {
+            match self.in_flush {
+                true => Ok(()),
+                false => Err(RMesgError::SinkError("connection refused".to_owned())),
+            }
+        }
+
+        fn flush(&mut self) -> Result<(), RMesgError> {
+            match self.in_flush {
+                true => Err(RMesgError::SinkError("connection reset".to_owned())),
+                false => Ok(()),
+            }
+        }
+    }
+
+    struct DropOdd;
+    impl Stage for DropOdd {
+        fn process(&mut self, entry: Entry) -> Option<Entry> {
+            entry.sequence_num.filter(|n| n % 2 == 0).map(|_| entry)
+        }
+    }
+
+    fn cursor_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rmesg-pipeline-{}-{}", name, std::process::id()))
+    }
+
+    fn seqs(entries: &Mutex<Vec<Entry>>) -> Vec<usize> {
+        entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| e.sequence_num.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_shutdown_flushes_and_persists() {
+        // a follower that's read 10 entries and is waiting for more
+        let (source, entries) = mpsc::channel();
+        for n in 1..=10 {
+            source.send(Ok(entry(n))).unwrap();
+        }
+        let (sink, flushed) = Buffering::new(Duration::ZERO);
+        let path = cursor_path("flush");
+        let pipeline = Pipeline::spawn(
+            entries.into_iter(),
+            vec![Box::new(DropOdd)],
+            vec![Box::new(sink)],
+            PipelineOptions {
+                cursor_path: Some(path.clone()),
+                ..Default::default()
+            },
+        );
+        while pipeline.stats().sinks[0].written < 5 {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let report = pipeline.shutdown(Duration::from_secs(5)).unwrap();
+        assert_eq!(report.stats.passed, 5);
+        assert_eq!(report.stats.sinks[0].written, 5);
+        assert_eq!(report.stats.sinks[0].errors, 0);
+        assert_eq!(seqs(&flushed), vec![2, 4, 6, 8, 10]);
+
+        let cursor = report.cursor.unwrap();
+        assert!(!cursor.is_after(&entry(10)));
+        assert!(cursor.is_after(&entry(11)));
+        let persisted = fs::read_to_string(&path).unwrap();
+        assert_eq!(Bookmark::from_token(persisted.trim()).unwrap(), cursor);
+        fs::remove_file(&path).unwrap();
+        drop(source);
+    }
+
+    #[test]
+    fn test_shutdown_deadline() {
+        let (sink, flushed) = Buffering::new(Duration::from_millis(500));
+        let path = cursor_path("deadline");
+        let pipeline = Pipeline::spawn(
+            (1..=3).map(|n| Ok(entry(n))),
+            vec![],
+            vec![Box::new(sink)],
+            PipelineOptions {
+                cursor_path: Some(path.clone()),
+                ..Default::default()
+            },
+        );
+
+        assert!(matches!(
+            pipeline.shutdown(Duration::from_millis(100)),
+            Err(RMesgError::Timeout(_))
+        ));
+        // nothing was flushed, so no cursor claims it was
+        assert!(flushed.lock().unwrap().is_empty());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_failed_sink() {
+        for in_flush in [false, true] {
+            let (sink, _) = Buffering::new(Duration::ZERO);
+            let path = cursor_path(&format!("failed-{}", in_flush));
+            let pipeline = Pipeline::spawn(
+                (1..=3).map(|n| Ok(entry(n))),
+                vec![],
+                vec![Box::new(sink), Box::new(Failing { in_flush })],
+                PipelineOptions {
+                    cursor_path: Some(path.clone()),
+                    ..Default::default()
+                },
+            );
+            while !pipeline.source_ended() {
+                thread::sleep(Duration::from_millis(5));
+            }
+
+            // the entries didn't all make it, so the next run gets them again
+            let report = pipeline.shutdown(Duration::from_secs(5)).unwrap();
+            assert!(report.stats.sinks[1].errors > 0);
+            assert_eq!(report.cursor, None);
+            assert!(!path.exists());
+        }
+    }
+
+    #[test]
+    fn test_slow_sink_isolated() {
+        let (fast, fast_flushed) = Buffering::new(Duration::ZERO);
+        let (mut stuck, stuck_flushed) = Buffering::new(Duration::ZERO);
+        let (release, gate) = mpsc::channel();
+        stuck.gate = Some(gate);
+        let pipeline = Pipeline::spawn(
+            (1..=50).map(|n| {
+                thread::sleep(Duration::from_millis(1));
+                Ok(entry(n))
+            }),
+            vec![],
+            vec![Box::new(fast), Box::new(stuck)],
+            PipelineOptions {
+                queue_capacity: 20,
+                ..Default::default()
+            },
+        );
+        while !pipeline.source_ended() {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        // the stuck sink fell behind without holding up the source or the other sink
+        let stats = pipeline.stats();
+        assert_eq!(stats.passed, 50);
+        assert_eq!(stats.sinks[0].dropped, 0);
+        let stuck = &stats.sinks[1];
+        assert_eq!(stuck.written, 0);
+        assert!(stuck.dropped >= 29, "{:?}", stuck);
+        assert_eq!(stuck.lag as u64 + stuck.dropped, 50);
+
+        drop(release);
+        let report = pipeline.shutdown(Duration::from_secs(5)).unwrap();
+        let (fast, stuck) = (&report.stats.sinks[0], &report.stats.sinks[1]);
+        assert_eq!((fast.written, fast.lag), (50, 0));
+        assert_eq!((stuck.written + stuck.dropped, stuck.lag), (50, 0));
+        assert_eq!(seqs(&fast_flushed), (1..=50).collect::<Vec<_>>());
+        assert_eq!(stuck_flushed.lock().unwrap().len() as u64, stuck.written);
+
+        // as far as the stuck sink got
+        let cursor = report.cursor.unwrap();
+        let last = *seqs(&stuck_flushed).last().unwrap();
+        assert!(!cursor.is_after(&entry(last)));
+        assert!(cursor.is_after(&entry(last + 1)));
+    }
+
+    #[test]
+    fn test_cursor_before_missed() {
+        let (source, entries) = mpsc::channel();
+        let (mut stuck, stuck_flushed) = Buffering::new(Duration::ZERO);
+        let (release, gate) = mpsc::channel();
+        stuck.gate = Some(gate);
+        let (mut flaky, flaky_flushed) = Buffering::new(Duration::ZERO);
+        flaky.fail_on = Some(3);
+        let pipeline = Pipeline::spawn(
+            entries.into_iter(),
+            vec![],
+            vec![Box::new(stuck), Box::new(flaky)],
+            PipelineOptions {
+                queue_capacity: 2,
+                ..Default::default()
+            },
+        );
+
+        // the stuck sink's queue fills, and entries are dropped for it
+        for n in 1..=5 {
+            source.send(Ok(entry(n))).unwrap();
+            // the other keeping up
+            while pipeline.stats().passed < n as u64 || pipeline.stats().sinks[1].lag > 0 {
+                thread::sleep(Duration::from_millis(5));
+            }
+        }
+        assert!(pipeline.stats().sinks[0].dropped > 0);
+        // then it catches up, and gets those after them
+        drop(release);
+        while pipeline.stats().sinks[0].lag > 0 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        for n in 6..=7 {
+            source.send(Ok(entry(n))).unwrap();
+        }
+        drop(source);
+        while !pipeline.source_ended() {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let report = pipeline.shutdown(Duration::from_secs(5)).unwrap();
+        let (stuck, flaky) = (&report.stats.sinks[0], &report.stats.sinks[1]);
+        assert_eq!(stuck.written + stuck.dropped, 7);
+        assert_eq!((flaky.written, flaky.errors), (6, 1));
+        assert_eq!(seqs(&flaky_flushed), vec![1, 2, 4, 5, 6, 7]);
+        let stuck_flushed = seqs(&stuck_flushed);
+        assert!(stuck_flushed.ends_with(&[6, 7]));
+
+        // the next run starts again at the first entry a sink missed
+        let first_missed = (1..).find(|n| !stuck_flushed.contains(n)).unwrap().min(3);
+        let cursor = report.cursor.unwrap();
+        assert!(!cursor.is_after(&entry(first_missed - 1)));
+        assert!(cursor.is_after(&entry(first_missed)));
+    }
+
+    #[test]
+    fn test_source_error() {
+        let source = vec![
+            Ok(entry(1)),
+            Err(RMesgError::InternalError("boom".to_owned())),
+            Ok(entry(3)),
+        ];
+        let (sink, flushed) = Buffering::new(Duration::ZERO);
+        let pipeline = Pipeline::spawn(
+            source.into_iter(),
+            vec![],
+            vec![Box::new(sink)],
+            Default::default(),
+        );
+        while !pipeline.source_ended() {
+            thread::sleep(Duration::from_millis(5));
+        }
+        let report = pipeline.shutdown(Duration::from_secs(5)).unwrap();
+        assert!(report.source_error.unwrap().contains("boom"));
+        assert_eq!(report.stats.sinks[0].written, 1);
+        assert_eq!(flushed.lock().unwrap().len(), 1);
+    }
+}
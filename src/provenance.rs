@@ -0,0 +1,266 @@
+use crate::entry::Entry;
+/// Provenance: where an entry came from, so merged and forwarded streams stay auditable.
+///
+/// Entries read through `log_entries`, `logs_stream` and `logs_iter` are tagged with
+/// the backend that read them, whether they're from the live buffer or a previous
+/// boot, and whether the kernel log was seen from the host or from inside a container
+/// (where it may be restricted, or really belong to the host). Entries parsed directly
+/// (e.g. with `kmsgfile::entry_from_line`) have no provenance until given one.
+///
+use crate::Backend;
+
+use lazy_static::lazy_static;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use strum_macros::Display;
+
+#[cfg(feature = "extra-traits")]
+use serde::{Deserialize, Serialize};
+
+lazy_static! {
+    static ref VIEW: View = detect_view();
+    static ref BOOT_ID: Option<String> = crate::bookmark::boot_id();
+    // shared by every entry read from each backend
+    static ref BACKENDS: [Arc<Provenance>; 7] = [
+        SourceBackend::KLogCtl,
+        SourceBackend::DevKMsg,
+        SourceBackend::PStore,
+        SourceBackend::Journald,
+        SourceBackend::MsgBuf,
+        SourceBackend::EventLog,
+        SourceBackend::Simulated,
+    ]
+    .map(|backend| Arc::new(Provenance::from_backend(backend)));
+}
+
+/// What read an entry.
+#[cfg_attr(feature = "extra-traits", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Display)]
+pub enum SourceBackend {
+    #[strum(serialize = "klogctl")]
+    KLogCtl,
+    #[strum(serialize = "devkmsg")]
+    DevKMsg,
+    #[strum(serialize = "pstore")]
+    PStore,
+    #[strum(serialize = "journald")]
+    Journald,
+    /// The BSDs' kern.msgbuf
+    #[strum(serialize = "msgbuf")]
+    MsgBuf,
+    /// The System event log on Windows
+    #[strum(serialize = "eventlog")]
+    EventLog,
+    /// A simulated kernel (see `simulate`)
+    #[strum(serialize = "simulated")]
+    Simulated,
+    /// A file of records (e.g. a capture being replayed)
+    #[strum(serialize = "file")]
+    File,
+    /// An rmesg agent on another host, followed over gRPC
+    #[strum(serialize = "remote")]
+    Remote,
+}
+
+/// Which kernel log an entry is from.
+#[cfg_attr(feature = "extra-traits", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Origin {
+    /// The running kernel's buffer
+    Live,
+    /// A previous boot's log, as saved by pstore
+    PreviousBoot,
+    /// A file, at this path
+    File(String),
+    /// Another host's kernel log, from the agent on the host named this
+    Remote(String),
+}
+
+/// Whether the kernel log was read from the host or from inside a container.
+#[cfg_attr(feature = "extra-traits", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Display)]
+pub enum View {
+    #[strum(serialize = "host")]
+    Host,
+    #[strum(serialize = "container")]
+    Container,
+}
+
+/// Where an entry came from.
+#[cfg_attr(feature = "extra-traits", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Provenance {
+    pub backend: SourceBackend,
+    pub origin: Origin,
+    pub view: View,
+    /// The boot the entry was read in (not necessarily logged in, for pstore)
+    pub boot_id: Option<String>,
+}
+
+impl Provenance {
+    /// The provenance of entries read through `backend` on this host just now.
+    /// `Backend::Default` is taken to be /dev/kmsg.
+    pub fn of(backend: Backend) -> Provenance {
+        Provenance::from_backend(match backend {
+            Backend::Default => SourceBackend::DevKMsg,
+            #[cfg(feature = "klogctl")]
+            Backend::KLogCtl => SourceBackend::KLogCtl,
+            #[cfg(feature = "kmsg")]
+            Backend::DevKMsg => SourceBackend::DevKMsg,
+            #[cfg(feature = "pstore")]
+            Backend::PStore => SourceBackend::PStore,
+            #[cfg(feature = "journald")]
+            Backend::Journald => SourceBackend::Journald,
+            #[cfg(feature = "freebsd")]
+            Backend::MsgBuf => SourceBackend::MsgBuf,
+            #[cfg(feature = "netbsdlike")]
+            Backend::BsdMsgBuf => SourceBackend::MsgBuf,
+            #[cfg(feature = "windows")]
+            Backend::EventLog => SourceBackend::EventLog,
+            #[cfg(feature = "simulate")]
+            Backend::Simulated => SourceBackend::Simulated,
+        })
+    }
+
+    fn from_backend(backend: SourceBackend) -> Provenance {
+        Provenance {
+            backend,
+            origin: match backend {
+                SourceBackend::PStore => Origin::PreviousBoot,
+                _ => Origin::Live,
+            },
+            view: *VIEW,
+            boot_id: BOOT_ID.clone(),
+        }
+    }
+
+    /// The provenance of entries read from the file at `path`.
+    pub fn file(path: &str) -> Provenance {
+        Provenance {
+            backend: SourceBackend::File,
+            origin: Origin::File(path.to_owned()),
+            view: *VIEW,
+            boot_id: BOOT_ID.clone(),
+        }
+    }
+}
+
+/// Tags entries with a provenance, sharing one copy between them.
+#[derive(Clone, Debug)]
+pub struct Tagger {
+    provenance: Arc<Provenance>,
+}
+
+impl Tagger {
+    pub fn new(provenance: Provenance) -> Tagger {
+        Tagger {
+            provenance: Arc::new(provenance),
+        }
+    }
+
+    /// Sets the provenance of `entry`, unless it already has one (so entries passed
+    /// on from another source keep theirs).
+    pub fn tag(&self, mut entry: Entry) -> Entry {
+        if entry.provenance.is_none() {
+            entry.provenance = Some(self.provenance.clone());
+        }
+        entry
+    }
+}
+
+/// Tags an entry read from one of the backends (other than a file).
+#[cfg(any(
+    feature = "klogctl",
+    feature = "kmsg",
+    feature = "pstore",
+    feature = "journald",
+    feature = "freebsd",
+    feature = "netbsdlike",
+    feature = "simulate",
+    feature = "windows"
+))]
+pub(crate) fn tag(entry: Entry, backend: SourceBackend) -> Entry {
+    let i = match backend {
+        SourceBackend::KLogCtl => 0,
+        SourceBackend::DevKMsg => 1,
+        SourceBackend::PStore => 2,
+        SourceBackend::Journald => 3,
+        SourceBackend::MsgBuf => 4,
+        SourceBackend::EventLog => 5,
+        SourceBackend::Simulated => 6,
+        SourceBackend::File | SourceBackend::Remote => return entry,
+    };
+    Tagger {
+        provenance: BACKENDS[i].clone(),
+    }
+    .tag(entry)
+}
+
+/// The usual signs of running in a container: the files Docker and Podman leave,
+/// the `container` variable systemd-nspawn/LXC set for init, or a container
+/// runtime's cgroup.
+fn detect_view() -> View {
+    let markers = Path::new("/.dockerenv").exists() || Path::new("/run/.containerenv").exists();
+    let init_environment = fs::read("/proc/1/environ")
+        .map(|environ| {
+            environ
+                .split(|b| *b == 0)
+                .any(|v| v.starts_with(b"container="))
+        })
+        .unwrap_or(false);
+    let cgroup = fs::read_to_string("/proc/self/cgroup")
+        .map(|cgroup| {
+            ["docker", "kubepods", "lxc", "containerd", "libpod"]
+                .iter()
+                .any(|runtime| cgroup.contains(runtime))
+        })
+        .unwrap_or(false);
+
+    match markers || init_environment || cgroup {
+        true => View::Container,
+        false => View::Host,
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry() -> Entry {
+        Entry {
+            sequence_num: Some(1),
+            message: "message".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_tagger() {
+        let tagger = Tagger::new(Provenance::file("/tmp/capture.kmsg"));
+        let tagged = tagger.tag(entry());
+        let provenance = tagged.provenance.clone().unwrap();
+        assert_eq!(provenance.backend, SourceBackend::File);
+        assert_eq!(
+            provenance.origin,
+            Origin::File("/tmp/capture.kmsg".to_owned())
+        );
+
+        // already-tagged entries keep their provenance
+        let retagged = Tagger::new(Provenance::of(Backend::Default)).tag(tagged);
+        assert_eq!(retagged.provenance, Some(provenance));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_log_entries_tagged() {
+        let entries = crate::log_entries(Backend::Default, false).unwrap();
+        let provenance = entries[0].provenance.as_ref().unwrap();
+        assert_eq!(provenance.origin, Origin::Live);
+        assert_eq!(provenance.boot_id, crate::bookmark::boot_id());
+        assert_eq!(provenance.view, detect_view());
+    }
+}
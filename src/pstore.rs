@@ -0,0 +1,222 @@
+use crate::common;
+use crate::entry::Entry;
+/// PStore Implementation (reads kernel logs saved by a previous boot from /sys/fs/pstore)
+///
+/// When the kernel panics or oopses, pstore (backed by ramoops, EFI variables, ERST...)
+/// saves the tail of the log buffer so that it can be read after the next boot. Each
+/// dump is split over one file per part; `Part1` holds the newest messages.
+///
+/// Compressed records (`*.enc.z`) are left alone, since the kernel only exposes them
+/// compressed when it couldn't decompress them itself.
+///
+use crate::error::RMesgError;
+use crate::parse;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::fs as stdfs;
+use std::path::PathBuf;
+
+pub const PSTORE_PATH: &str = "/sys/fs/pstore";
+
+lazy_static! {
+    // Panic#1 Part1    Oops#2 Part3    Emergency#1 Part1
+    static ref RE_DUMP_HEADER: Regex =
+        Regex::new(r"^(?P<reason>[[:alpha:]]+)#(?P<count>[[:digit:]]+) Part(?P<part>[[:digit:]]+)$").unwrap();
+}
+
+/// One dump saved by pstore, with its parts put back together.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PStoreDump {
+    /// Why the kernel dumped its log (Panic, Oops, Emergency...)
+    pub reason: String,
+    pub count: usize,
+    pub entries: Vec<Entry>,
+    raw: String,
+    files: Vec<PathBuf>,
+}
+
+struct Part {
+    reason: String,
+    count: usize,
+    part: usize,
+    contents: String,
+    file: PathBuf,
+}
+
+/// Every dump in the pstore filesystem, oldest first.
+///
+/// `dir_override` reads somewhere other than `PSTORE_PATH`; when `clear` is set, the
+/// records are removed once read (which is how pstore frees space for the next dump).
+pub fn pstore_dumps(
+    dir_override: Option<String>,
+    clear: bool,
+) -> Result<Vec<PStoreDump>, RMesgError> {
+    let dir = dir_override.unwrap_or_else(|| PSTORE_PATH.to_owned());
+
+    let mut parts = Vec::new();
+    for dirent in stdfs::read_dir(&dir)? {
+        let path = dirent?.path();
+        let name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name,
+            None => continue,
+        };
+        if !name.starts_with("dmesg-") || name.ends_with(".enc.z") {
+            continue;
+        }
+
+        let contents = String::from_utf8_lossy(&stdfs::read(&path)?).into_owned();
+        let header = contents.lines().next().unwrap_or_default();
+        let (reason, count, part) = match RE_DUMP_HEADER.captures(header.trim()) {
+            Some(parts) => (
+                parts["reason"].to_owned(),
+                common::parse_fragment::<usize>(&parts["count"], header)?,
+                common::parse_fragment::<usize>(&parts["part"], header)?,
+            ),
+            None => ("Unknown".to_owned(), 0, 1),
+        };
+        parts.push(Part {
+            reason,
+            count,
+            part,
+            contents,
+            file: path,
+        });
+    }
+
+    // oldest dump first, and within a dump the highest (oldest) part first
+    parts.sort_by_key(|p| (p.count, std::cmp::Reverse(p.part)));
+
+    let mut dumps: Vec<PStoreDump> = Vec::new();
+    for part in parts {
+        let dump = match dumps.last_mut() {
+            Some(dump) if dump.count == part.count && dump.reason == part.reason => dump,
+            _ => {
+                dumps.push(PStoreDump {
+                    reason: part.reason.clone(),
+                    count: part.count,
+                    entries: Vec::new(),
+                    raw: String::new(),
+                    files: Vec::new(),
+                });
+                dumps.last_mut().unwrap()
+            }
+        };
+
+        // Parts are cut at arbitrary byte offsets: skip the header and any partial
+        // line before the first complete record.
+        for line in part
+            .contents
+            .lines()
+            .skip(1)
+            .skip_while(|l| !l.trim_start().starts_with('<'))
+        {
+            dump.entries.push(parse::console_entry_from_line(line)?);
+            dump.raw.push_str(line);
+            dump.raw.push('\n');
+        }
+        dump.files.push(part.file);
+    }
+
+    if clear {
+        for dump in dumps.iter() {
+            for file in dump.files.iter() {
+                stdfs::remove_file(file)?;
+            }
+        }
+    }
+
+    Ok(dumps)
+}
+
+/// All entries saved by pstore, oldest dump first.
+pub fn pstore(dir_override: Option<String>, clear: bool) -> Result<Vec<Entry>, RMesgError> {
+    Ok(pstore_dumps(dir_override, clear)?
+        .into_iter()
+        .flat_map(|d| d.entries)
+        .collect())
+}
+
+/// All entries saved by pstore, in the console format they were saved in.
+pub fn pstore_raw(dir_override: Option<String>, clear: bool) -> Result<String, RMesgError> {
+    Ok(pstore_dumps(dir_override, clear)?
+        .into_iter()
+        .map(|d| d.raw)
+        .collect())
+}
+
+pub fn pstore_count(dir_override: Option<String>) -> Result<usize, RMesgError> {
+    Ok(pstore(dir_override, false)?.len())
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn fixture(name: &str, files: &[(&str, &str)]) -> String {
+        let dir =
+            std::env::temp_dir().join(format!("rmesg-pstore-{}-{}", name, std::process::id()));
+        stdfs::create_dir_all(&dir).unwrap();
+        for (file, contents) in files {
+            stdfs::write(dir.join(file), contents).unwrap();
+        }
+        dir.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn test_dumps() {
+        let dir = fixture(
+            "dumps",
+            &[
+                (
+                    "dmesg-ramoops-1",
+                    "Panic#2 Part1\n<6>[  101.000000] sysrq: Trigger a crash\n<0>[  101.000100] Kernel panic - not syncing: sysrq triggered crash\n",
+                ),
+                (
+                    "dmesg-ramoops-2",
+                    "Panic#2 Part2\nncated line\n<6>[  100.000000] usb 1-1: new high-speed USB device\n",
+                ),
+                (
+                    "dmesg-ramoops-0",
+                    "Oops#1 Part1\n<4>[   50.000000] BUG: unable to handle page fault\n",
+                ),
+                ("dmesg-efi-1.enc.z", "compressed"),
+                ("console-ramoops-0", "not a dump"),
+            ],
+        );
+
+        let dumps = pstore_dumps(Some(dir.clone()), false).unwrap();
+        assert_eq!(dumps.len(), 2);
+        assert_eq!(dumps[0].reason, "Oops");
+        assert_eq!(dumps[0].entries.len(), 1);
+        assert_eq!(dumps[1].reason, "Panic");
+        assert_eq!(
+            dumps[1]
+                .entries
+                .iter()
+                .map(|e| e.timestamp_from_system_start.unwrap())
+                .collect::<Vec<Duration>>(),
+            vec![
+                Duration::from_secs(100),
+                Duration::from_secs(101),
+                Duration::from_micros(101_000_100)
+            ]
+        );
+        assert_eq!(pstore_count(Some(dir.clone())).unwrap(), 4);
+
+        assert!(pstore_raw(Some(dir.clone()), false)
+            .unwrap()
+            .starts_with("<4>[   50.000000] BUG: unable to handle page fault\n<6>[  100.000000]"));
+
+        // clearing removes the dumps, but nothing else
+        pstore(Some(dir.clone()), true).unwrap();
+        assert_eq!(pstore_count(Some(dir.clone())).unwrap(), 0);
+        assert_eq!(stdfs::read_dir(&dir).unwrap().count(), 2);
+
+        stdfs::remove_dir_all(&dir).unwrap();
+    }
+}
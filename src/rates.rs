@@ -0,0 +1,250 @@
+use crate::entry::Entry;
+/// Message rates per subsystem, for finding which driver is flooding the log.
+///
+/// A `SubsystemRates` is a stage that counts the entries passing through it by their
+/// subsystem or driver prefix (see `Entry::subsystem`), over a sliding window of kernel
+/// time made up of fixed-width buckets. `top` gives the noisiest subsystems in the
+/// window. Like `SeverityHistogram`, clones share the same counts.
+///
+use crate::stage::Stage;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// How much one subsystem logged over the window.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SubsystemRate {
+    pub subsystem: String,
+    pub count: u64,
+    /// Entries per second, over the whole window
+    pub per_second: f64,
+}
+
+#[derive(Debug)]
+struct RateBucket {
+    start: Duration,
+    counts: HashMap<String, u64>,
+    unattributed: u64,
+}
+
+#[derive(Debug)]
+struct Rates {
+    buckets: VecDeque<RateBucket>,
+    bucket_width: Duration,
+    window: usize,
+}
+
+impl Rates {
+    fn bucket_for(&mut self, timestamp: Option<Duration>) -> Option<&mut RateBucket> {
+        let width = self.bucket_width.as_micros();
+        let start = match timestamp {
+            Some(ts) => Duration::from_micros((ts.as_micros() / width * width) as u64),
+            // entries without a timestamp are counted as of the newest one
+            None => match self.buckets.back() {
+                Some(newest) => newest.start,
+                None => Duration::from_secs(0),
+            },
+        };
+
+        let window = self.bucket_width * self.window as u32;
+        let newest = self.buckets.back().map(|b| b.start);
+        if newest.is_none_or(|newest| start > newest) {
+            self.buckets.push_back(RateBucket {
+                start,
+                counts: HashMap::new(),
+                unattributed: 0,
+            });
+            while self
+                .buckets
+                .front()
+                .is_some_and(|oldest| oldest.start + window <= start)
+            {
+                self.buckets.pop_front();
+            }
+        }
+
+        // entries older than the window are dropped
+        let newest = self.buckets.back()?.start;
+        if start + window <= newest {
+            return None;
+        }
+        let position = match self.buckets.iter().rposition(|b| b.start <= start) {
+            Some(i) if self.buckets[i].start == start => i,
+            found => {
+                let i = found.map_or(0, |i| i + 1);
+                self.buckets.insert(
+                    i,
+                    RateBucket {
+                        start,
+                        counts: HashMap::new(),
+                        unattributed: 0,
+                    },
+                );
+                i
+            }
+        };
+        self.buckets.get_mut(position)
+    }
+}
+
+/// Sliding-window message counts per subsystem.
+#[derive(Clone, Debug)]
+pub struct SubsystemRates {
+    rates: Arc<Mutex<Rates>>,
+}
+
+impl SubsystemRates {
+    /// Counts entries into buckets `bucket_width` wide, over a window of the newest
+    /// `window` buckets.
+    pub fn with_options(bucket_width: Duration, window: usize) -> SubsystemRates {
+        SubsystemRates {
+            rates: Arc::new(Mutex::new(Rates {
+                buckets: VecDeque::with_capacity(window),
+                bucket_width: bucket_width.max(Duration::from_micros(1)),
+                window: window.max(1),
+            })),
+        }
+    }
+
+    pub fn record(&self, entry: &Entry) {
+        let mut rates = self.lock();
+        if let Some(bucket) = rates.bucket_for(entry.timestamp_from_system_start) {
+            match entry.subsystem() {
+                Some(subsystem) => match bucket.counts.get_mut(subsystem) {
+                    Some(count) => *count += 1,
+                    None => {
+                        bucket.counts.insert(subsystem.to_owned(), 1);
+                    }
+                },
+                None => bucket.unattributed += 1,
+            }
+        }
+    }
+
+    /// The `n` subsystems that logged the most over the window, noisiest first (ties
+    /// in name order).
+    pub fn top(&self, n: usize) -> Vec<SubsystemRate> {
+        let rates = self.lock();
+        let mut counts: HashMap<&str, u64> = HashMap::new();
+        for bucket in &rates.buckets {
+            for (subsystem, count) in &bucket.counts {
+                *counts.entry(subsystem.as_str()).or_default() += count;
+            }
+        }
+
+        let seconds = (rates.bucket_width * rates.window as u32).as_secs_f64();
+        let mut top: Vec<SubsystemRate> = counts
+            .into_iter()
+            .map(|(subsystem, count)| SubsystemRate {
+                subsystem: subsystem.to_owned(),
+                count,
+                per_second: count as f64 / seconds,
+            })
+            .collect();
+        top.sort_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then_with(|| a.subsystem.cmp(&b.subsystem))
+        });
+        top.truncate(n);
+        top
+    }
+
+    /// Entries from `subsystem` over the window.
+    pub fn count(&self, subsystem: &str) -> u64 {
+        self.lock()
+            .buckets
+            .iter()
+            .filter_map(|b| b.counts.get(subsystem))
+            .sum()
+    }
+
+    /// Entries over the window without a recognizable subsystem.
+    pub fn unattributed(&self) -> u64 {
+        self.lock().buckets.iter().map(|b| b.unattributed).sum()
+    }
+
+    pub fn clear(&self) {
+        self.lock().buckets.clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Rates> {
+        // counts stay consistent if a panic happens while holding the lock
+        self.rates.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for SubsystemRates {
+    /// Ten-second buckets over the last five minutes.
+    fn default() -> Self {
+        SubsystemRates::with_options(Duration::from_secs(10), 30)
+    }
+}
+
+impl Stage for SubsystemRates {
+    fn process(&mut self, entry: Entry) -> Option<Entry> {
+        self.record(&entry);
+        Some(entry)
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(secs: u64, message: &str) -> Entry {
+        Entry {
+            timestamp_from_system_start: Some(Duration::from_secs(secs)),
+            message: message.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_top() {
+        let mut rates = SubsystemRates::with_options(Duration::from_secs(10), 6);
+        let handle = rates.clone();
+
+        for second in 0..30 {
+            rates.process(entry(
+                second,
+                "e1000e 0000:00:19.0 eth0: Detected Hardware Unit Hang",
+            ));
+        }
+        for second in 0..5 {
+            rates.process(entry(second * 10, "usb 1-1: reset high-speed USB device"));
+        }
+        rates.process(entry(20, "nvme nvme0: I/O 12 QID 3 timeout"));
+        rates.process(entry(20, "Linux version 5.10.0"));
+
+        let top = handle.top(2);
+        assert_eq!(
+            top.iter()
+                .map(|r| (r.subsystem.as_str(), r.count))
+                .collect::<Vec<_>>(),
+            vec![("e1000e", 30), ("usb", 5)]
+        );
+        assert_eq!(top[0].per_second, 0.5);
+        assert_eq!(handle.top(10).len(), 3);
+        assert_eq!(handle.count("nvme"), 1);
+        assert_eq!(handle.unattributed(), 1);
+
+        // as the window slides on, older counts drop out
+        handle.record(&entry(75, "usb 1-1: reset high-speed USB device"));
+        assert_eq!(handle.count("e1000e"), 10);
+        assert_eq!(handle.count("usb"), 4);
+        assert_eq!(handle.top(1)[0].subsystem, "e1000e");
+
+        // late entries land in their bucket while it's still in the window
+        handle.record(&entry(25, "nvme nvme0: I/O 13 QID 3 timeout"));
+        handle.record(&entry(5, "nvme nvme0: I/O 14 QID 3 timeout"));
+        assert_eq!(handle.count("nvme"), 2);
+
+        handle.clear();
+        assert!(handle.top(5).is_empty());
+    }
+}
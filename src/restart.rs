@@ -0,0 +1,462 @@
+use crate::entry::Entry;
+/// Restarting a follow when reads start failing, rather than ending it.
+///
+/// Long-running followers see transient failures: EPIPE storms on /dev/kmsg, klogctl
+/// permissions flapping while a security policy reloads. Wrapped in `Restarting` (or,
+/// for streams, `restarting_stream`), a follower that fails is reopened with
+/// exponential backoff, and a `FollowEvent::Recovered` is emitted once it's back, so
+/// the outage shows up downstream. Entries the reopened follower replays (it starts
+/// again from the oldest entry in the buffer) are skipped. Only when a `RestartPolicy`
+/// runs out of attempts does the error come through, and the follow end.
+///
+use crate::error::RMesgError;
+
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "async")]
+use core::future::Future;
+#[cfg(feature = "async")]
+use futures::stream::{self, Stream};
+
+/// How to reopen a follower that failed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RestartPolicy {
+    /// How long to wait before the first attempt to reopen
+    pub initial_backoff: Duration,
+    /// Backoff doubles after every failed attempt, up to this
+    pub max_backoff: Duration,
+    /// Attempts to make before giving up (and passing the error on), or `None` to retry
+    /// for ever
+    pub max_attempts: Option<u32>,
+}
+
+impl RestartPolicy {
+    /// How long to wait before attempt number `attempt` (starting at 1).
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(31);
+        self.initial_backoff
+            .saturating_mul(1 << doublings)
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RestartPolicy {
+    /// 100ms, doubling up to 30s, for ever.
+    fn default() -> Self {
+        RestartPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+}
+
+/// A failure that was recovered from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recovery {
+    /// The error that failed the follower
+    pub error: String,
+    /// Attempts it took to reopen it
+    pub attempts: u32,
+    /// From the failure to the follower being reopened
+    pub downtime: Duration,
+}
+
+/// What a restarting follower yields.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FollowEvent {
+    Entry(Entry),
+    /// The follower failed and was reopened; entries logged in the meantime are still
+    /// read, as long as the kernel buffer didn't wrap
+    Recovered(Recovery),
+}
+
+impl FollowEvent {
+    /// The entry, if this is one.
+    pub fn entry(self) -> Option<Entry> {
+        match self {
+            FollowEvent::Entry(entry) => Some(entry),
+            FollowEvent::Recovered(_) => None,
+        }
+    }
+}
+
+// What was last passed on, to skip what a reopened follower replays. Without sequence
+// numbers (klogctl's), that's the last timestamp and how many entries at it were passed
+// on, since several are often logged within the same microsecond (see
+// `klogctl::PollCursor`)
+#[derive(Debug, Default)]
+struct Position {
+    sequence_num: Option<usize>,
+    timestamp: Option<Duration>,
+    passed_at_timestamp: usize,
+    replaying: bool,
+    replayed_at_timestamp: usize,
+}
+
+impl Position {
+    fn reopened(&mut self) {
+        self.replaying = true;
+        self.replayed_at_timestamp = 0;
+    }
+
+    fn is_replay(&mut self, entry: &Entry) -> bool {
+        if !self.replaying {
+            return false;
+        }
+        let replay = match (self.sequence_num, entry.sequence_num) {
+            (Some(last), Some(seq)) => seq <= last,
+            _ => match (self.timestamp, entry.timestamp_from_system_start) {
+                (Some(last), Some(ts)) if ts == last => {
+                    self.replayed_at_timestamp += 1;
+                    self.replayed_at_timestamp <= self.passed_at_timestamp
+                }
+                (Some(last), Some(ts)) => ts < last,
+                _ => false,
+            },
+        };
+        // once something new comes through, the replay is over
+        self.replaying = replay;
+        replay
+    }
+
+    fn passed_on(&mut self, entry: &Entry) {
+        self.sequence_num = entry.sequence_num.or(self.sequence_num);
+        if let Some(ts) = entry.timestamp_from_system_start {
+            match self.timestamp == Some(ts) {
+                true => self.passed_at_timestamp += 1,
+                false => {
+                    self.timestamp = Some(ts);
+                    self.passed_at_timestamp = 1;
+                }
+            }
+        }
+    }
+}
+
+/// Wraps a follower (any iterator of entries), reopening it with `open` when it fails.
+pub struct Restarting<I, F> {
+    inner: Option<I>,
+    open: F,
+    policy: RestartPolicy,
+    position: Position,
+}
+
+impl<I, F> Restarting<I, F>
+where
+    I: Iterator<Item = Result<Entry, RMesgError>>,
+    F: FnMut() -> Result<I, RMesgError>,
+{
+    /// Follows `inner`; `open` opens a new follower like it.
+    pub fn new(inner: I, open: F, policy: RestartPolicy) -> Self {
+        Restarting {
+            inner: Some(inner),
+            open,
+            policy,
+            position: Position::default(),
+        }
+    }
+
+    fn restart(&mut self, error: RMesgError) -> Result<Recovery, RMesgError> {
+        let failed_at = Instant::now();
+        let failure = error.to_string();
+        let mut error = error;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            if self.policy.max_attempts.is_some_and(|max| attempts > max) {
+                return Err(error);
+            }
+            std::thread::sleep(self.policy.backoff(attempts));
+            match (self.open)() {
+                Ok(inner) => {
+                    self.inner = Some(inner);
+                    self.position.reopened();
+                    return Ok(Recovery {
+                        error: failure,
+                        attempts,
+                        downtime: failed_at.elapsed(),
+                    });
+                }
+                Err(e) => error = e,
+            }
+        }
+    }
+}
+
+impl<I, F> Iterator for Restarting<I, F>
+where
+    I: Iterator<Item = Result<Entry, RMesgError>>,
+    F: FnMut() -> Result<I, RMesgError>,
+{
+    type Item = Result<FollowEvent, RMesgError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.inner.as_mut()?.next() {
+                None => return None,
+                Some(Ok(entry)) if self.position.is_replay(&entry) => continue,
+                Some(Ok(entry)) => {
+                    self.position.passed_on(&entry);
+                    return Some(Ok(FollowEvent::Entry(entry)));
+                }
+                Some(Err(e)) => {
+                    self.inner = None;
+                    return Some(self.restart(e).map(FollowEvent::Recovered));
+                }
+            }
+        }
+    }
+}
+
+/// Follows `stream`, reopening it with `open` when it fails, as `Restarting` does for
+/// iterators.
+#[cfg(feature = "async")]
+pub fn restarting_stream<S, F, Fut>(
+    stream: S,
+    open: F,
+    policy: RestartPolicy,
+) -> impl Stream<Item = Result<FollowEvent, RMesgError>>
+where
+    S: Stream<Item = Result<Entry, RMesgError>>,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<S, RMesgError>>,
+{
+    use futures::stream::StreamExt;
+
+    struct State<S, F> {
+        inner: Option<core::pin::Pin<Box<S>>>,
+        open: F,
+        policy: RestartPolicy,
+        position: Position,
+    }
+
+    let state = State {
+        inner: Some(Box::pin(stream)),
+        open,
+        policy,
+        position: Position::default(),
+    };
+
+    stream::unfold(state, |mut state| async move {
+        let error = loop {
+            match state.inner.as_mut()?.next().await {
+                None => return None,
+                Some(Ok(entry)) if state.position.is_replay(&entry) => continue,
+                Some(Ok(entry)) => {
+                    state.position.passed_on(&entry);
+                    return Some((Ok(FollowEvent::Entry(entry)), state));
+                }
+                Some(Err(e)) => break e,
+            }
+        };
+        state.inner = None;
+
+        let failed_at = Instant::now();
+        let failure = error.to_string();
+        let mut error = error;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            if state.policy.max_attempts.is_some_and(|max| attempts > max) {
+                return Some((Err(error), state));
+            }
+            tokio::time::sleep(state.policy.backoff(attempts)).await;
+            match (state.open)().await {
+                Ok(inner) => {
+                    state.inner = Some(Box::pin(inner));
+                    state.position.reopened();
+                    let recovery = Recovery {
+                        error: failure,
+                        attempts,
+                        downtime: failed_at.elapsed(),
+                    };
+                    return Some((Ok(FollowEvent::Recovered(recovery)), state));
+                }
+                Err(e) => error = e,
+            }
+        }
+    })
+}
+
+/// Like `logs_iter`, reopening the backend as `policy` says when reads fail.
+#[cfg(all(
+    feature = "sync",
+    any(feature = "klogctl", feature = "kmsg", feature = "journald")
+))]
+pub fn logs_iter(
+    b: crate::Backend,
+    clear: bool,
+    raw: bool,
+    policy: RestartPolicy,
+) -> Result<
+    Restarting<crate::EntriesIterator, impl FnMut() -> Result<crate::EntriesIterator, RMesgError>>,
+    RMesgError,
+> {
+    let inner = crate::logs_iter(b, clear, raw)?;
+    Ok(Restarting::new(
+        inner,
+        move || crate::logs_iter(b, clear, raw),
+        policy,
+    ))
+}
+
+/// Like `logs_stream`, reopening the backend as `policy` says when reads fail.
+#[cfg(all(feature = "async", any(feature = "klogctl", feature = "kmsg")))]
+pub async fn logs_stream(
+    b: crate::Backend,
+    clear: bool,
+    raw: bool,
+    policy: RestartPolicy,
+) -> Result<impl Stream<Item = Result<FollowEvent, RMesgError>>, RMesgError> {
+    let inner = crate::logs_stream(b, clear, raw).await?;
+    Ok(restarting_stream(
+        inner,
+        move || crate::logs_stream(b, clear, raw),
+        policy,
+    ))
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    fn entry(seq: usize) -> Entry {
+        Entry {
+            sequence_num: Some(seq),
+            message: format!("message {}", seq),
+            ..Default::default()
+        }
+    }
+
+    fn policy(max_attempts: Option<u32>) -> RestartPolicy {
+        RestartPolicy {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+            max_attempts,
+        }
+    }
+
+    // entries 1 and 2, then a broken pipe
+    fn failing() -> std::vec::IntoIter<Result<Entry, RMesgError>> {
+        vec![
+            Ok(entry(1)),
+            Ok(entry(2)),
+            Err(RMesgError::IOError("Broken pipe".to_owned())),
+        ]
+        .into_iter()
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RestartPolicy::default();
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+        assert_eq!(policy.backoff(100), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_restarting() {
+        // the first reopen fails (permissions flapping), the second replays entry 2
+        let opened = Rc::new(Cell::new(0));
+        let opens = opened.clone();
+        let open = move || {
+            opens.set(opens.get() + 1);
+            match opens.get() {
+                1 => Err(RMesgError::BackendUnavailable("restricted".to_owned())),
+                _ => Ok(vec![Ok(entry(2)), Ok(entry(3))].into_iter()),
+            }
+        };
+
+        let events: Vec<FollowEvent> = Restarting::new(failing(), open, policy(None))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0], FollowEvent::Entry(entry(1)));
+        assert_eq!(events[1], FollowEvent::Entry(entry(2)));
+        match &events[2] {
+            FollowEvent::Recovered(recovery) => {
+                assert_eq!(recovery.attempts, 2);
+                assert!(recovery.error.contains("Broken pipe"));
+            }
+            event => panic!("Expected a recovery, got {:?}", event),
+        }
+        assert_eq!(events[3], FollowEvent::Entry(entry(3)));
+        assert_eq!(opened.get(), 2);
+    }
+
+    #[test]
+    fn test_restarting_without_sequence_numbers() {
+        // as klogctl reads them, two logged in the same millisecond
+        let entry = |ms: u64, message: &str| Entry {
+            timestamp_from_system_start: Some(Duration::from_millis(ms)),
+            message: message.to_owned(),
+            ..Default::default()
+        };
+        let failing = vec![
+            Ok(entry(1, "eth0: link up")),
+            Ok(entry(2, "ratelimited")),
+            Err(RMesgError::IOError("Broken pipe".to_owned())),
+        ];
+        // a third in that millisecond was logged after the failure
+        let reopened = vec![
+            Ok(entry(1, "eth0: link up")),
+            Ok(entry(2, "ratelimited")),
+            Ok(entry(2, "ratelimited")),
+            Ok(entry(3, "eth0: link down")),
+        ];
+        let mut reopened = Some(reopened);
+        let open = move || Ok(reopened.take().unwrap_or_default().into_iter());
+
+        let messages: Vec<String> = Restarting::new(failing.into_iter(), open, policy(None))
+            .filter_map(|e| e.unwrap().entry())
+            .map(|e| e.message)
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "eth0: link up",
+                "ratelimited",
+                "ratelimited",
+                "eth0: link down"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_giving_up() {
+        let open = || Err(RMesgError::BackendUnavailable("restricted".to_owned()));
+        let mut restarting = Restarting::new(failing(), open, policy(Some(3)));
+        assert!(restarting.next().unwrap().is_ok());
+        assert!(restarting.next().unwrap().is_ok());
+        assert!(matches!(
+            restarting.next(),
+            Some(Err(RMesgError::BackendUnavailable(_)))
+        ));
+        assert!(restarting.next().is_none());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_restarting_stream() {
+        use futures::stream::StreamExt;
+
+        let stream = stream::iter(failing());
+        let open = || async { Ok(stream::iter(vec![Ok(entry(1)), Ok(entry(4))])) };
+        let events: Vec<Result<FollowEvent, RMesgError>> =
+            restarting_stream(stream, open, policy(Some(1)))
+                .collect()
+                .await;
+        let entries: Vec<Option<usize>> = events
+            .into_iter()
+            .filter_map(|e| e.unwrap().entry())
+            .map(|e| e.sequence_num)
+            .collect();
+        assert_eq!(entries, vec![Some(1), Some(2), Some(4)]);
+    }
+}
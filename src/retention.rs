@@ -0,0 +1,404 @@
+use crate::bookmark::Bookmark;
+use crate::entry::{Entry, LogLevel};
+/// In-process retention of recently seen entries.
+///
+/// A `RetentionRing` is a stage that keeps a copy of the last entries that passed
+/// through it (bounded by count, and optionally by age and by memory), so that e.g. a debug endpoint
+/// can show "recent kernel messages" without re-reading the kernel buffer. Clones share
+/// the same ring, so hand one to the pipeline and keep another to query.
+///
+/// `HistoryQuery` asks the questions a status page does ("any kernel errors in the last
+/// hour?") of any store implementing `History`: the ring, or the SQLite sink (with the
+/// "sqlite" feature) for retention across restarts.
+///
+use crate::error::RMesgError;
+use crate::stage::Stage;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Suggested number of entries to retain when in doubt.
+pub const SUGGESTED_MAX_ENTRIES: usize = 1024;
+
+#[derive(Debug)]
+struct Ring {
+    entries: VecDeque<Entry>,
+    max_entries: usize,
+    max_age: Option<Duration>,
+    max_bytes: Option<usize>,
+    // the `approx_size` of everything in `entries`
+    bytes: usize,
+}
+
+impl Ring {
+    fn pop_front(&mut self) {
+        if let Some(evicted) = self.entries.pop_front() {
+            self.bytes -= evicted.approx_size();
+        }
+    }
+}
+
+/// Selects retained entries. Every field that is set narrows the query.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HistoryQuery {
+    /// Only entries logged at or after this time since system start
+    pub since: Option<Duration>,
+    /// Only entries logged at or before this time since system start
+    pub until: Option<Duration>,
+    /// Only entries at this level or more severe
+    pub min_level: Option<LogLevel>,
+    /// Only entries from this subsystem (see `Entry::subsystem`)
+    pub subsystem: Option<String>,
+    /// Only entries whose message contains this text, ignoring ASCII case
+    pub text: Option<String>,
+    /// At most this many entries (the newest ones)
+    pub limit: Option<usize>,
+}
+
+impl HistoryQuery {
+    /// Entries logged within `window` of now.
+    #[cfg(unix)]
+    pub fn last(window: Duration) -> Result<HistoryQuery, RMesgError> {
+        Ok(HistoryQuery {
+            since: Some(crate::clock::since_system_start()?.saturating_sub(window)),
+            ..Default::default()
+        })
+    }
+
+    /// Whether `entry` matches (ignoring `limit`). Entries without a timestamp never
+    /// match a time range.
+    pub fn matches(&self, entry: &Entry) -> bool {
+        let ts = entry.timestamp_from_system_start;
+        self.since
+            .is_none_or(|since| ts.is_some_and(|ts| ts >= since))
+            && self
+                .until
+                .is_none_or(|until| ts.is_some_and(|ts| ts <= until))
+            && self
+                .min_level
+                .is_none_or(|min| entry.level.is_some_and(|l| l <= min))
+            && self
+                .subsystem
+                .as_ref()
+                .is_none_or(|s| entry.subsystem() == Some(s.as_str()))
+            && self.text.as_ref().is_none_or(|text| {
+                entry
+                    .message
+                    .to_ascii_lowercase()
+                    .contains(&text.to_ascii_lowercase())
+            })
+    }
+}
+
+/// A store of past entries that can be queried.
+pub trait History {
+    /// Entries matching `query`, oldest first.
+    fn history(&self, query: &HistoryQuery) -> Result<std::vec::IntoIter<Entry>, RMesgError>;
+}
+
+/// A bounded ring of the most recent entries.
+#[derive(Clone, Debug)]
+pub struct RetentionRing {
+    ring: Arc<Mutex<Ring>>,
+}
+
+impl RetentionRing {
+    /// Keeps at most `max_entries` entries and, when `max_age` is set, only those
+    /// logged within `max_age` of the newest one (by kernel timestamp; entries
+    /// without a timestamp are only ever evicted by count).
+    pub fn with_options(max_entries: usize, max_age: Option<Duration>) -> RetentionRing {
+        RetentionRing {
+            ring: Arc::new(Mutex::new(Ring {
+                entries: VecDeque::with_capacity(max_entries.min(SUGGESTED_MAX_ENTRIES)),
+                max_entries,
+                max_age,
+                max_bytes: None,
+                bytes: 0,
+            })),
+        }
+    }
+
+    /// Also keeps the retained entries within `max_bytes` (by `Entry::approx_size`), for
+    /// hosts logging very long messages. An entry bigger than that on its own isn't kept.
+    pub fn with_max_bytes(self, max_bytes: usize) -> RetentionRing {
+        self.lock().max_bytes = Some(max_bytes);
+        self
+    }
+
+    pub fn push(&self, entry: Entry) {
+        let mut ring = self.lock();
+        if ring.max_entries == 0 {
+            return;
+        }
+        while ring.entries.len() >= ring.max_entries {
+            ring.pop_front();
+        }
+        let size = entry.approx_size();
+        if let Some(max_bytes) = ring.max_bytes {
+            if size > max_bytes {
+                return;
+            }
+            while ring.bytes + size > max_bytes {
+                ring.pop_front();
+            }
+        }
+
+        if let (Some(max_age), Some(newest)) = (ring.max_age, entry.timestamp_from_system_start) {
+            while matches!(
+                ring.entries.front().and_then(|e| e.timestamp_from_system_start),
+                Some(ts) if newest.saturating_sub(ts) > max_age
+            ) {
+                ring.pop_front();
+            }
+        }
+
+        ring.bytes += size;
+        ring.entries.push_back(entry);
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().entries.is_empty()
+    }
+
+    /// Roughly how much memory the retained entries take up (see `Entry::approx_size`).
+    pub fn bytes(&self) -> usize {
+        self.lock().bytes
+    }
+
+    /// Everything retained, oldest first.
+    pub fn snapshot(&self) -> Vec<Entry> {
+        self.lock().entries.iter().cloned().collect()
+    }
+
+    /// The newest `count` entries, oldest first.
+    pub fn recent(&self, count: usize) -> Vec<Entry> {
+        let ring = self.lock();
+        let skip = ring.entries.len().saturating_sub(count);
+        ring.entries.iter().skip(skip).cloned().collect()
+    }
+
+    /// Retained entries logged at or after `timestamp` (time since system start).
+    pub fn since(&self, timestamp: Duration) -> Vec<Entry> {
+        self.query(|e| {
+            e.timestamp_from_system_start
+                .is_some_and(|ts| ts >= timestamp)
+        })
+    }
+
+    /// Retained entries logged after `bookmark`.
+    pub fn replay_from(&self, bookmark: &Bookmark) -> Vec<Entry> {
+        let mut replaying = bookmark.replaying();
+        self.lock()
+            .entries
+            .iter()
+            .filter(|e| replaying.is_after(e))
+            .cloned()
+            .collect()
+    }
+
+    /// Retained entries matching `predicate`, oldest first.
+    pub fn query<P: Fn(&Entry) -> bool>(&self, predicate: P) -> Vec<Entry> {
+        self.lock()
+            .entries
+            .iter()
+            .filter(|e| predicate(e))
+            .cloned()
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        let mut ring = self.lock();
+        ring.entries.clear();
+        ring.bytes = 0;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Ring> {
+        // a panic elsewhere while holding the lock leaves the ring consistent,
+        // so there's no reason to stop serving it
+        self.ring.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for RetentionRing {
+    fn default() -> Self {
+        RetentionRing::with_options(SUGGESTED_MAX_ENTRIES, None)
+    }
+}
+
+impl History for RetentionRing {
+    fn history(&self, query: &HistoryQuery) -> Result<std::vec::IntoIter<Entry>, RMesgError> {
+        let mut entries = self.query(|e| query.matches(e));
+        if let Some(limit) = query.limit {
+            entries.drain(..entries.len().saturating_sub(limit));
+        }
+        Ok(entries.into_iter())
+    }
+}
+
+impl Stage for RetentionRing {
+    fn process(&mut self, entry: Entry) -> Option<Entry> {
+        self.push(entry.clone());
+        Some(entry)
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bookmark::BookmarkTracker;
+    use crate::entry::LogLevel;
+
+    fn entry(secs: u64) -> Entry {
+        Entry {
+            level: Some(LogLevel::Info),
+            sequence_num: Some(secs as usize),
+            timestamp_from_system_start: Some(Duration::from_secs(secs)),
+            message: format!("message {}", secs),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_count_bound_and_queries() {
+        let mut ring = RetentionRing::with_options(3, None);
+        let handle = ring.clone();
+        for secs in 1..=5 {
+            assert_eq!(ring.process(entry(secs)), Some(entry(secs)));
+        }
+
+        assert_eq!(handle.len(), 3);
+        assert_eq!(handle.snapshot(), vec![entry(3), entry(4), entry(5)]);
+        assert_eq!(handle.recent(2), vec![entry(4), entry(5)]);
+        assert_eq!(handle.recent(10).len(), 3);
+        assert_eq!(
+            handle.since(Duration::from_secs(4)),
+            vec![entry(4), entry(5)]
+        );
+        assert_eq!(handle.query(|e| e.message.ends_with('3')), vec![entry(3)]);
+
+        let mut tracker = BookmarkTracker::new();
+        tracker.process(entry(3));
+        assert_eq!(
+            handle.replay_from(&tracker.bookmark()),
+            vec![entry(4), entry(5)]
+        );
+
+        handle.clear();
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn test_history() {
+        let ring = RetentionRing::default();
+        for (secs, level, message) in [
+            (1, LogLevel::Info, "usb 1-1: new high-speed USB device"),
+            (
+                2,
+                LogLevel::Error,
+                "nvme nvme0: I/O 12 QID 3 Timeout, aborting",
+            ),
+            (
+                3,
+                LogLevel::Warning,
+                "usb 1-1: device descriptor read/64, error -71",
+            ),
+            (
+                4,
+                LogLevel::Error,
+                "usb 1-1: device not accepting address 2",
+            ),
+        ] {
+            ring.push(Entry {
+                level: Some(level),
+                message: message.to_owned(),
+                ..entry(secs)
+            });
+        }
+        let messages = |query: HistoryQuery| -> Vec<String> {
+            ring.history(&query).unwrap().map(|e| e.message).collect()
+        };
+
+        assert_eq!(messages(HistoryQuery::default()).len(), 4);
+        assert_eq!(
+            messages(HistoryQuery {
+                min_level: Some(LogLevel::Error),
+                subsystem: Some("usb".to_owned()),
+                ..Default::default()
+            }),
+            vec!["usb 1-1: device not accepting address 2"]
+        );
+        assert_eq!(
+            messages(HistoryQuery {
+                since: Some(Duration::from_secs(2)),
+                until: Some(Duration::from_secs(3)),
+                text: Some("timeout".to_owned()),
+                ..Default::default()
+            }),
+            vec!["nvme nvme0: I/O 12 QID 3 Timeout, aborting"]
+        );
+        assert_eq!(
+            messages(HistoryQuery {
+                limit: Some(1),
+                ..Default::default()
+            }),
+            vec!["usb 1-1: device not accepting address 2"]
+        );
+
+        #[cfg(unix)]
+        assert!(HistoryQuery::last(Duration::from_secs(3600))
+            .unwrap()
+            .since
+            .is_some());
+    }
+
+    #[test]
+    fn test_age_bound() {
+        let ring = RetentionRing::with_options(100, Some(Duration::from_secs(10)));
+        for secs in [1, 5, 12, 20] {
+            ring.push(entry(secs));
+        }
+        assert_eq!(ring.snapshot(), vec![entry(12), entry(20)]);
+
+        let empty = RetentionRing::with_options(0, None);
+        empty.push(entry(1));
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_byte_bound() {
+        let size = entry(1).approx_size();
+        let ring = RetentionRing::with_options(100, None).with_max_bytes(size * 3);
+        for secs in 1..=5 {
+            ring.push(entry(secs));
+        }
+        assert_eq!(ring.snapshot(), vec![entry(3), entry(4), entry(5)]);
+        assert_eq!(ring.bytes(), size * 3);
+
+        // one long message makes room for itself
+        let long = Entry {
+            message: "x".repeat(size),
+            ..entry(6)
+        };
+        ring.push(long.clone());
+        assert_eq!(ring.snapshot(), vec![entry(5), long.clone()]);
+        assert_eq!(ring.bytes(), size + long.approx_size());
+
+        // and one too long to ever fit isn't kept
+        ring.push(Entry {
+            message: "x".repeat(size * 3),
+            ..entry(7)
+        });
+        assert_eq!(ring.len(), 2);
+
+        ring.clear();
+        assert_eq!(ring.bytes(), 0);
+    }
+}
@@ -0,0 +1,222 @@
+use crate::entry::Entry;
+/// Sanitization of messages before they reach terminals or log files.
+///
+/// Kernel messages carry strings from outside the kernel (device names and serial
+/// numbers reported by USB devices, file names, process names), so a crafted device can
+/// get terminal escape sequences, newlines forging further log lines, or bidirectional
+/// overrides reordering what's displayed into a message. A `Sanitizer` is a stage that
+/// strips those, or escapes them so they're visible but inert: place it last, just
+/// ahead of whatever writes entries out.
+///
+/// What's hostile: C0 control characters other than tab, DEL, C1 control characters
+/// (which include the single-character CSI), the escape sequences those introduce, byte
+/// order marks and other zero-width characters, Unicode line and paragraph separators,
+/// and the bidirectional embedding, override and isolate characters.
+///
+use crate::stage::Stage;
+
+use std::borrow::Cow;
+use std::fmt::Write;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// What to do with hostile characters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SanitizeAction {
+    /// Remove them, along with the rest of any escape sequence they start
+    Strip,
+    /// Replace each with a visible escape: \xNN as the kernel writes them (e.g. \x1b),
+    /// or \u{NNNN} for those beyond Latin-1 (e.g. \u{202e})
+    #[default]
+    Escape,
+}
+
+/// Whether `c` could do harm printed as is.
+pub fn is_hostile(c: char) -> bool {
+    match c {
+        '\t' => false,
+        '\u{0}'..='\u{1f}' | '\u{7f}'..='\u{9f}' => true,
+        // zero-width space, non-joiner and joiner, and the left-to-right and
+        // right-to-left marks
+        '\u{200b}'..='\u{200f}' => true,
+        // line and paragraph separators, and bidirectional embeddings and overrides
+        '\u{2028}'..='\u{202e}' => true,
+        // word joiner, invisible operators, bidirectional isolates, and the deprecated
+        // format characters
+        '\u{2060}'..='\u{206f}' => true,
+        // byte order mark (zero-width no-break space)
+        '\u{feff}' => true,
+        _ => false,
+    }
+}
+
+/// A stage that strips or escapes hostile characters in each entry's message.
+#[derive(Clone, Debug, Default)]
+pub struct Sanitizer {
+    action: SanitizeAction,
+    sanitized: u64,
+}
+
+impl Sanitizer {
+    pub fn with_options(action: SanitizeAction) -> Sanitizer {
+        Sanitizer {
+            action,
+            sanitized: 0,
+        }
+    }
+
+    /// The number of entries whose message had anything to sanitize.
+    pub fn sanitized(&self) -> u64 {
+        self.sanitized
+    }
+
+    pub fn sanitize<'a>(&self, message: &'a str) -> Cow<'a, str> {
+        if !message.chars().any(is_hostile) {
+            return Cow::Borrowed(message);
+        }
+
+        let mut sanitized = String::with_capacity(message.len());
+        let mut chars = message.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (is_hostile(c), self.action) {
+                (false, _) => sanitized.push(c),
+                (true, SanitizeAction::Strip) => skip_sequence(c, &mut chars),
+                (true, SanitizeAction::Escape) => {
+                    // writing to a String can't fail
+                    let _ = match c as u32 {
+                        code if code <= 0xff => write!(sanitized, "\\x{:02x}", code),
+                        code => write!(sanitized, "\\u{{{:04x}}}", code),
+                    };
+                }
+            }
+        }
+        Cow::Owned(sanitized)
+    }
+}
+
+impl Stage for Sanitizer {
+    fn process(&mut self, mut entry: Entry) -> Option<Entry> {
+        if let Cow::Owned(sanitized) = self.sanitize(&entry.message) {
+            entry.message = sanitized;
+            self.sanitized += 1;
+        }
+        Some(entry)
+    }
+}
+
+// Skips the rest of an escape sequence `c` introduces: CSI (ESC [ or U+009B) up to its
+// final byte; OSC, DCS and the other string sequences (ESC ], P, X, ^ and _, or their C1
+// forms) up to BEL or their terminator; or ESC, any intermediate bytes, and the final
+// character after them. Other characters stand alone.
+fn skip_sequence(c: char, chars: &mut Peekable<Chars>) {
+    let kind = match c {
+        '\u{1b}' => match chars.peek() {
+            Some(&next) if ('\u{20}'..='\u{7e}').contains(&next) => {
+                chars.next();
+                next
+            }
+            _ => return,
+        },
+        '\u{9b}' => '[',
+        '\u{90}' | '\u{98}' | '\u{9d}' | '\u{9e}' | '\u{9f}' => ']',
+        _ => return,
+    };
+
+    match kind {
+        // parameter and intermediate bytes, then a final byte
+        '[' => {
+            for c in chars.by_ref() {
+                if !('\u{20}'..='\u{3f}').contains(&c) {
+                    break;
+                }
+            }
+        }
+        ']' | 'P' | 'X' | '^' | '_' => {
+            while let Some(c) = chars.next() {
+                match c {
+                    '\u{7}' | '\u{9c}' => break,
+                    '\u{1b}' if chars.peek() == Some(&'\\') => {
+                        chars.next();
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        // intermediate bytes (as in ESC ( B), then a final byte
+        '\u{20}'..='\u{2f}' => {
+            for c in chars.by_ref() {
+                if !('\u{20}'..='\u{2f}').contains(&c) {
+                    break;
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        let sanitizer = Sanitizer::with_options(SanitizeAction::Escape);
+        assert_eq!(
+            sanitizer.sanitize("usb 1-1: Product: \u{1b}[2J\u{1b}[31mPWNED"),
+            "usb 1-1: Product: \\x1b[2J\\x1b[31mPWNED"
+        );
+        assert_eq!(
+            sanitizer.sanitize("usb 1-1: Manufacturer: x\nsshd[1]: Accepted password for root"),
+            "usb 1-1: Manufacturer: x\\x0asshd[1]: Accepted password for root"
+        );
+        assert_eq!(
+            sanitizer.sanitize("\u{feff}sda: \u{202e}gnp.exe"),
+            "\\u{feff}sda: \\u{202e}gnp.exe"
+        );
+        assert_eq!(sanitizer.sanitize("csi: \u{9b}1m"), "csi: \\x9b1m");
+
+        // nothing hostile, nothing allocated, and tabs and non-ASCII are fine
+        let clean = "EXT4-fs (sda1):\tmounted filesystem «données» с данными";
+        assert!(matches!(sanitizer.sanitize(clean), Cow::Borrowed(m) if m == clean));
+    }
+
+    #[test]
+    fn test_strip() {
+        let sanitizer = Sanitizer::with_options(SanitizeAction::Strip);
+        assert_eq!(
+            sanitizer.sanitize("usb 1-1: Product: \u{1b}[2J\u{1b}[1;31mPWNED\u{1b}[0m"),
+            "usb 1-1: Product: PWNED"
+        );
+        // an OSC 8 hyperlink, terminated by BEL and then by ST
+        assert_eq!(
+            sanitizer
+                .sanitize("sd 0:0:0:0: \u{1b}]8;;http://evil/\u{7}disk\u{1b}]8;;\u{1b}\\ attached"),
+            "sd 0:0:0:0: disk attached"
+        );
+        assert_eq!(sanitizer.sanitize("a\u{1b}cb\u{9b}0Kc\u{0}\u{200b}"), "abc");
+        assert_eq!(sanitizer.sanitize("charset\u{1b}(B reset"), "charset reset");
+        // a trailing ESC, and one before a character that doesn't start a sequence
+        assert_eq!(sanitizer.sanitize("x\u{1b}"), "x");
+        assert_eq!(sanitizer.sanitize("x\u{1b}\u{1b}[0my"), "xy");
+    }
+
+    #[test]
+    fn test_stage() {
+        let mut sanitizer = Sanitizer::default();
+        let entry = |message: &str| Entry {
+            sequence_num: Some(1),
+            message: message.to_owned(),
+            ..Default::default()
+        };
+
+        let sanitized = sanitizer.process(entry("eth0: \u{7}link up")).unwrap();
+        assert_eq!(sanitized.message, "eth0: \\x07link up");
+        assert_eq!(sanitized.sequence_num, Some(1));
+        sanitizer.process(entry("eth0: link down")).unwrap();
+        assert_eq!(sanitizer.sanitized(), 1);
+    }
+}
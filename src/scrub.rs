@@ -0,0 +1,180 @@
+use crate::entry::Entry;
+/// Redaction of sensitive data in messages, for shipping kernel logs off-box.
+///
+/// A `Scrubber` is a stage: place it ahead of any sinks (or whatever else consumes
+/// entries) so that they only ever see scrubbed messages.
+///
+use crate::error::RMesgError;
+use crate::stage::Stage;
+
+use regex::Regex;
+use std::borrow::Cow;
+
+/// Something to mask out of messages.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScrubPattern {
+    /// MAC addresses (and the longer MAC= headers netfilter logs), masked as "<mac>"
+    MacAddress,
+    /// IPv4 addresses, masked as "<ipv4>"
+    Ipv4Address,
+    /// IPv6 addresses, masked as "<ipv6>"
+    Ipv6Address,
+    /// Device serial numbers (e.g. "SerialNumber: 4C5300011306"), masked as "<serial>"
+    SerialNumber,
+    /// User names in home directory paths and audit records (user=, acct=), masked as "<user>"
+    Username,
+    /// Anything matching `regex`, replaced with `replacement` (which may refer to
+    /// capture groups as `$1` or `$name`)
+    Custom { regex: String, replacement: String },
+}
+
+impl ScrubPattern {
+    /// Every built-in pattern.
+    pub fn all() -> Vec<ScrubPattern> {
+        vec![
+            ScrubPattern::MacAddress,
+            ScrubPattern::Ipv6Address,
+            ScrubPattern::Ipv4Address,
+            ScrubPattern::SerialNumber,
+            ScrubPattern::Username,
+        ]
+    }
+
+    fn rule(&self) -> (&str, &str) {
+        match self {
+            ScrubPattern::MacAddress => (r"\b[[:xdigit:]]{2}(?::[[:xdigit:]]{2}){5,}\b", "<mac>"),
+            ScrubPattern::Ipv4Address => (
+                r"\b(?:(?:25[0-5]|2[0-4][[:digit:]]|1?[[:digit:]]?[[:digit:]])\.){3}(?:25[0-5]|2[0-4][[:digit:]]|1?[[:digit:]]?[[:digit:]])\b",
+                "<ipv4>",
+            ),
+            ScrubPattern::Ipv6Address => (
+                r"\b(?:(?:[[:xdigit:]]{1,4}:){7}[[:xdigit:]]{1,4}\b|(?:[[:xdigit:]]{1,4}:){1,7}:(?:[[:xdigit:]]{1,4}(?::[[:xdigit:]]{1,4})*\b)?)",
+                "<ipv6>",
+            ),
+            ScrubPattern::SerialNumber => (
+                r"(?i)(serial[ _]?(?:number|no\.?)?[[:space:]]*[:=][[:space:]]*)[^[:space:],]+",
+                "${1}<serial>",
+            ),
+            ScrubPattern::Username => (
+                r#"(/home/|\b(?:acct|user|uname|auid_name)=)(?:"[^"]*"|[^/[:space:]]+)"#,
+                "${1}<user>",
+            ),
+            ScrubPattern::Custom { regex, replacement } => (regex, replacement),
+        }
+    }
+}
+
+/// A stage that masks patterns in each entry's message.
+///
+/// Patterns are applied in the order given, each to the output of the previous one.
+pub struct Scrubber {
+    rules: Vec<(Regex, String)>,
+}
+
+impl Scrubber {
+    pub fn with_options(patterns: Vec<ScrubPattern>) -> Result<Scrubber, RMesgError> {
+        let mut rules = Vec::with_capacity(patterns.len());
+        for pattern in patterns.iter() {
+            let (regex, replacement) = pattern.rule();
+            match Regex::new(regex) {
+                Ok(re) => rules.push((re, replacement.to_owned())),
+                Err(e) => {
+                    return Err(RMesgError::FilterError(format!(
+                        "Unable to compile scrub pattern {:?}: {}",
+                        pattern, e
+                    )))
+                }
+            }
+        }
+        Ok(Scrubber { rules })
+    }
+
+    pub fn scrub<'a>(&self, message: &'a str) -> Cow<'a, str> {
+        let mut scrubbed = Cow::Borrowed(message);
+        for (re, replacement) in self.rules.iter() {
+            if let Cow::Owned(s) = re.replace_all(&scrubbed, replacement.as_str()) {
+                scrubbed = Cow::Owned(s);
+            }
+        }
+        scrubbed
+    }
+}
+
+impl Stage for Scrubber {
+    fn process(&mut self, mut entry: Entry) -> Option<Entry> {
+        if let Cow::Owned(scrubbed) = self.scrub(&entry.message) {
+            entry.message = scrubbed;
+        }
+        Some(entry)
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_builtin_patterns() {
+        let scrubber = Scrubber::with_options(ScrubPattern::all()).unwrap();
+
+        assert_eq!(
+            scrubber.scrub(
+                "e1000e 0000:00:1f.6 eth0: (PCI Express:2.5GT/s:Width x1) 8c:16:45:12:34:56"
+            ),
+            "e1000e 0000:00:1f.6 eth0: (PCI Express:2.5GT/s:Width x1) <mac>"
+        );
+        assert_eq!(
+            scrubber.scrub("IN=eth0 OUT= MAC=52:54:00:12:34:56:52:54:00:65:43:21:08:00 SRC=192.168.1.20 DST=10.0.0.1 LEN=60"),
+            "IN=eth0 OUT= MAC=<mac> SRC=<ipv4> DST=<ipv4> LEN=60"
+        );
+        assert_eq!(
+            scrubber.scrub("IPv6: ADDRCONF(NETDEV_CHANGE): fe80::5054:ff:fe12:3456 and 2001:db8:0:0:1:0:0:1 up"),
+            "IPv6: ADDRCONF(NETDEV_CHANGE): <ipv6> and <ipv6> up"
+        );
+        assert_eq!(
+            scrubber.scrub("usb 1-1: SerialNumber: 4C530001130611108463"),
+            "usb 1-1: SerialNumber: <serial>"
+        );
+        assert_eq!(
+            scrubber
+                .scrub(r#"audit: type=1101 audit(1.2:3): pid=1 acct="alice" exe="/usr/bin/sudo""#),
+            r#"audit: type=1101 audit(1.2:3): pid=1 acct=<user> exe="/usr/bin/sudo""#
+        );
+        assert_eq!(
+            scrubber.scrub("EXT4-fs warning: /home/bob/.cache/x: checksum invalid"),
+            "EXT4-fs warning: /home/<user>/.cache/x: checksum invalid"
+        );
+
+        // nothing matched, nothing allocated
+        let clean = "pci 0000:00:1f.3: [8086:9d71] type 00 class 0x040300";
+        assert!(matches!(scrubber.scrub(clean), Cow::Borrowed(m) if m == clean));
+    }
+
+    #[test]
+    fn test_custom_pattern_stage() {
+        let mut scrubber = Scrubber::with_options(vec![ScrubPattern::Custom {
+            regex: r"host=(?P<host>[[:alnum:]]+)".to_owned(),
+            replacement: "host=<host>".to_owned(),
+        }])
+        .unwrap();
+
+        let entry = scrubber
+            .process(Entry {
+                sequence_num: Some(1),
+                message: "nfs: server host=fileserver01 not responding".to_owned(),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(entry.message, "nfs: server host=<host> not responding");
+        assert_eq!(entry.sequence_num, Some(1));
+
+        assert!(Scrubber::with_options(vec![ScrubPattern::Custom {
+            regex: "(unclosed".to_owned(),
+            replacement: String::new(),
+        }])
+        .is_err());
+    }
+}
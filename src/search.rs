@@ -0,0 +1,320 @@
+use crate::entry::{Entry, LogLevel};
+/// In-memory inverted index over a snapshot, for searching large buffers instantly.
+///
+/// Messages are tokenized into lowercase words (runs of letters, digits and
+/// underscores), and entries are indexed by word, subsystem (see `Entry::subsystem`)
+/// and level. Queries intersect the posting lists rather than scanning every message.
+///
+use crate::error::RMesgError;
+
+use std::collections::{BTreeMap, HashMap};
+
+/// What to search for. Every part that is set narrows the results.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SearchQuery {
+    /// Words that must all appear in the message
+    pub words: Vec<String>,
+    /// When set, the last word only needs to be a prefix of a word in the message
+    /// (for searching as the user types)
+    pub prefix: bool,
+    pub subsystem: Option<String>,
+    /// Only entries at this level or more severe
+    pub min_level: Option<LogLevel>,
+}
+
+impl SearchQuery {
+    /// Parses a query such as `timeout nvme0 level:err subsystem:nvme`, where the
+    /// `level:` and `subsystem:` terms filter and every other term is a word to find.
+    /// The last word is matched as a prefix, unless the query ends in whitespace or
+    /// a filter term.
+    pub fn parse(query: &str) -> Result<SearchQuery, RMesgError> {
+        let is_filter = |term: &str| term.starts_with("level:") || term.starts_with("subsystem:");
+        let mut search_query = SearchQuery {
+            prefix: !query.ends_with(char::is_whitespace)
+                && query
+                    .split_whitespace()
+                    .last()
+                    .is_some_and(|t| !is_filter(t)),
+            ..Default::default()
+        };
+        for term in query.split_whitespace() {
+            if let Some(level) = term.strip_prefix("level:") {
+                match level.parse() {
+                    Ok(level) => search_query.min_level = Some(level),
+                    Err(_) => {
+                        return Err(RMesgError::FilterError(format!(
+                            "Unknown log level {} (expected one of emerg, alert, crit, err, warn, notice, info, debug)",
+                            level
+                        )))
+                    }
+                }
+            } else if let Some(subsystem) = term.strip_prefix("subsystem:") {
+                search_query.subsystem = Some(subsystem.to_owned());
+            } else {
+                search_query.words.extend(tokenize(term));
+            }
+        }
+        Ok(search_query)
+    }
+
+    /// Whether a single entry matches, without an index.
+    pub fn matches(&self, entry: &Entry) -> bool {
+        let words = tokenize(&entry.message);
+        self.words.iter().enumerate().all(|(i, word)| {
+            let word = word.to_lowercase();
+            match self.prefix && i + 1 == self.words.len() {
+                true => words.iter().any(|w| w.starts_with(&word)),
+                false => words.contains(&word),
+            }
+        }) && self
+            .subsystem
+            .as_ref()
+            .is_none_or(|s| entry.subsystem() == Some(s.as_str()))
+            && self
+                .min_level
+                .is_none_or(|min| entry.level.is_some_and(|l| l <= min))
+    }
+}
+
+/// An inverted index over entries.
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    entries: Vec<Entry>,
+    words: BTreeMap<String, Vec<usize>>,
+    subsystems: HashMap<String, Vec<usize>>,
+    levels: [Vec<usize>; 8],
+}
+
+impl SearchIndex {
+    pub fn new(entries: Vec<Entry>) -> SearchIndex {
+        let mut index = SearchIndex::default();
+        for entry in entries {
+            index.push(entry);
+        }
+        index
+    }
+
+    /// Adds an entry (e.g. one newly read while following).
+    pub fn push(&mut self, entry: Entry) {
+        let position = self.entries.len();
+
+        let mut words = tokenize(&entry.message);
+        words.sort_unstable();
+        words.dedup();
+        for word in words {
+            self.words.entry(word).or_default().push(position);
+        }
+        if let Some(subsystem) = entry.subsystem() {
+            self.subsystems
+                .entry(subsystem.to_owned())
+                .or_default()
+                .push(position);
+        }
+        if let Some(level) = entry.level {
+            self.levels[level as usize].push(position);
+        }
+
+        self.entries.push(entry);
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn get(&self, position: usize) -> Option<&Entry> {
+        self.entries.get(position)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Positions (into `entries`) of the entries matching `query`, in order.
+    pub fn search(&self, query: &SearchQuery) -> Vec<usize> {
+        let mut candidates: Vec<Vec<usize>> = Vec::new();
+
+        for (i, word) in query.words.iter().enumerate() {
+            let word = word.to_lowercase();
+            if query.prefix && i + 1 == query.words.len() {
+                candidates.push(union(
+                    self.words
+                        .range(word.clone()..)
+                        .take_while(|(w, _)| w.starts_with(&word))
+                        .map(|(_, positions)| positions.as_slice()),
+                ));
+            } else {
+                candidates.push(self.words.get(&word).cloned().unwrap_or_default());
+            }
+        }
+        if let Some(subsystem) = &query.subsystem {
+            candidates.push(self.subsystems.get(subsystem).cloned().unwrap_or_default());
+        }
+        if let Some(min_level) = query.min_level {
+            candidates.push(union(
+                self.levels[..=min_level as usize]
+                    .iter()
+                    .map(|positions| positions.as_slice()),
+            ));
+        }
+
+        // intersecting from the shortest list keeps the work proportional to the result
+        candidates.sort_by_key(|positions| positions.len());
+        let mut candidates = candidates.into_iter();
+        let first = match candidates.next() {
+            Some(first) => first,
+            None => return (0..self.entries.len()).collect(),
+        };
+        candidates.fold(first, |matched, positions| intersect(&matched, &positions))
+    }
+
+    /// The entries matching `query`, in order.
+    pub fn find(&self, query: &SearchQuery) -> Vec<&Entry> {
+        self.search(query)
+            .into_iter()
+            .map(|position| &self.entries[position])
+            .collect()
+    }
+}
+
+/// Lowercase words in `text`: runs of letters, digits and underscores.
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+fn union<'a, I: Iterator<Item = &'a [usize]>>(lists: I) -> Vec<usize> {
+    let mut positions: Vec<usize> = lists.flatten().copied().collect();
+    positions.sort_unstable();
+    positions.dedup();
+    positions
+}
+
+// both lists are sorted
+fn intersect(a: &[usize], b: &[usize]) -> Vec<usize> {
+    let mut positions = Vec::with_capacity(a.len().min(b.len()));
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                positions.push(a[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    positions
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(level: LogLevel, message: &str) -> Entry {
+        Entry {
+            level: Some(level),
+            message: message.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn index() -> SearchIndex {
+        SearchIndex::new(vec![
+            entry(
+                LogLevel::Info,
+                "usb 1-1: new high-speed USB device number 2",
+            ),
+            entry(
+                LogLevel::Error,
+                "nvme nvme0: I/O 12 QID 3 timeout, aborting",
+            ),
+            entry(LogLevel::Warning, "nvme nvme0: Abort status: 0x0"),
+            entry(
+                LogLevel::Error,
+                "usb 1-1: device descriptor read/64, error -71",
+            ),
+            entry(LogLevel::Info, "EXT4-fs (sda1): mounted filesystem"),
+        ])
+    }
+
+    #[test]
+    fn test_search() {
+        let index = index();
+        assert_eq!(index.len(), 5);
+
+        let words = |words: &[&str]| SearchQuery {
+            words: words.iter().map(|w| w.to_string()).collect(),
+            ..Default::default()
+        };
+        assert_eq!(index.search(&words(&["nvme0"])), vec![1, 2]);
+        assert_eq!(index.search(&words(&["USB", "device"])), vec![0, 3]);
+        assert_eq!(
+            index.search(&words(&["nvme", "missing"])),
+            Vec::<usize>::new()
+        );
+        assert_eq!(index.search(&SearchQuery::default()).len(), 5);
+
+        assert_eq!(
+            index.search(&SearchQuery {
+                min_level: Some(LogLevel::Error),
+                subsystem: Some("usb".to_owned()),
+                ..Default::default()
+            }),
+            vec![3]
+        );
+        assert_eq!(
+            index.search(&SearchQuery {
+                words: vec!["ab".to_owned()],
+                prefix: true,
+                ..Default::default()
+            }),
+            vec![1, 2]
+        );
+
+        // matching a single entry agrees with the index
+        let query = SearchQuery::parse("level:err usb dev").unwrap();
+        let matched: Vec<usize> = (0..index.len())
+            .filter(|p| query.matches(index.get(*p).unwrap()))
+            .collect();
+        assert_eq!(matched, index.search(&query));
+        assert_eq!(matched, vec![3]);
+
+        let mut index = index;
+        index.push(entry(LogLevel::Error, "nvme nvme0: controller is down"));
+        assert_eq!(
+            index.find(&words(&["nvme0", "down"]))[0].message,
+            "nvme nvme0: controller is down"
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            SearchQuery::parse("timeout level:err subsystem:nvme nv").unwrap(),
+            SearchQuery {
+                words: vec!["timeout".to_owned(), "nv".to_owned()],
+                prefix: true,
+                subsystem: Some("nvme".to_owned()),
+                min_level: Some(LogLevel::Error),
+            }
+        );
+        assert!(!SearchQuery::parse("timeout ").unwrap().prefix);
+        assert!(!SearchQuery::parse("timeout level:err").unwrap().prefix);
+        assert!(SearchQuery::parse("level:loud").is_err());
+
+        assert_eq!(
+            tokenize("EXT4-fs (sda1): I/O error"),
+            vec!["ext4", "fs", "sda1", "i", "o", "error"]
+        );
+    }
+}
@@ -0,0 +1,428 @@
+use crate::entry::{Entry, LogLevel};
+/// HTTP server mode, so web dashboards can tail the kernel log without shelling out.
+///
+/// Every connection gets its own stream of entries from the backend, served either as
+/// Server-Sent Events (`GET /events`) or over a WebSocket (`GET /ws`, one text message
+/// per entry). Both take optional query parameters to narrow what's sent:
+///
+/// * `level` - only entries at this level or more severe (e.g. `level=warn`)
+/// * `pattern` - only entries whose message matches this regular expression
+///
+/// The server only ever reads: clients can't clear the buffer through it.
+///
+use crate::error::RMesgError;
+use crate::Backend;
+
+use base64::Engine;
+use futures::stream::{Stream, StreamExt};
+use regex::Regex;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Request heads larger than this are rejected.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Appended to the client's key to compute Sec-WebSocket-Accept (RFC 6455).
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// How entries are framed on the wire.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    ServerSentEvents,
+    WebSocket,
+}
+
+/// The entries a client asked for, from the request's query parameters.
+#[derive(Debug, Default)]
+pub struct StreamQuery {
+    pub min_level: Option<LogLevel>,
+    pub pattern: Option<Regex>,
+}
+
+impl StreamQuery {
+    /// Parses a query string such as `level=err&pattern=nvme%5Cd`.
+    pub fn from_query_string(query: &str) -> Result<StreamQuery, RMesgError> {
+        let mut stream_query = StreamQuery::default();
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = match pair.split_once('=') {
+                Some((key, value)) => (key, percent_decode(value)?),
+                None => (pair, String::new()),
+            };
+            match key {
+                "level" => match value.parse() {
+                    Ok(level) => stream_query.min_level = Some(level),
+                    Err(_) => {
+                        return Err(RMesgError::FilterError(format!(
+                            "Unknown log level {} (expected one of emerg, alert, crit, err, warn, notice, info, debug)",
+                            value
+                        )))
+                    }
+                },
+                "pattern" => match Regex::new(&value) {
+                    Ok(pattern) => stream_query.pattern = Some(pattern),
+                    Err(e) => {
+                        return Err(RMesgError::FilterError(format!(
+                            "Unable to compile pattern {}: {}",
+                            value, e
+                        )))
+                    }
+                },
+                // unknown parameters (e.g. cache busters) are ignored
+                _ => {}
+            }
+        }
+        Ok(stream_query)
+    }
+
+    pub fn matches(&self, entry: &Entry) -> bool {
+        let level_matches = self
+            .min_level
+            .is_none_or(|min| entry.level.is_some_and(|level| level <= min));
+        let pattern_matches = self
+            .pattern
+            .as_ref()
+            .is_none_or(|pattern| pattern.is_match(&entry.message));
+        level_matches && pattern_matches
+    }
+}
+
+/// Accepts connections on `listener` forever, serving each from its own stream
+/// of entries read from `backend`.
+pub async fn serve(listener: TcpListener, backend: Backend) -> Result<(), RMesgError> {
+    loop {
+        let (socket, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(socket, backend).await {
+                eprintln!("Unable to serve kernel log stream: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(mut socket: TcpStream, backend: Backend) -> Result<(), RMesgError> {
+    let head = read_request_head(&mut socket).await?;
+    let request = match Request::parse(&head) {
+        Ok(request) => request,
+        Err(e) => return respond(&mut socket, "400 Bad Request", &e.to_string()).await,
+    };
+
+    let protocol = match request.path {
+        "/events" => Protocol::ServerSentEvents,
+        "/ws" => Protocol::WebSocket,
+        _ => return respond(&mut socket, "404 Not Found", "Try /events or /ws").await,
+    };
+    let query = match StreamQuery::from_query_string(request.query) {
+        Ok(query) => query,
+        Err(e) => return respond(&mut socket, "400 Bad Request", &e.to_string()).await,
+    };
+
+    let handshake = match protocol {
+        Protocol::ServerSentEvents => "HTTP/1.1 200 OK\r\n\
+            Content-Type: text/event-stream\r\n\
+            Cache-Control: no-cache\r\n\
+            Access-Control-Allow-Origin: *\r\n\r\n"
+            .to_owned(),
+        Protocol::WebSocket => match request.websocket_key {
+            Some(key) => format!(
+                "HTTP/1.1 101 Switching Protocols\r\n\
+                Upgrade: websocket\r\n\
+                Connection: Upgrade\r\n\
+                Sec-WebSocket-Accept: {}\r\n\r\n",
+                websocket_accept(key)
+            ),
+            None => {
+                return respond(
+                    &mut socket,
+                    "400 Bad Request",
+                    "Missing Sec-WebSocket-Key header",
+                )
+                .await
+            }
+        },
+    };
+
+    let entries = crate::logs_stream(backend, false, false).await?;
+    socket.write_all(handshake.as_bytes()).await?;
+    write_entries(&mut socket, protocol, &query, entries).await
+}
+
+/// Writes entries from `entries` that match `query` to `writer`, framed for `protocol`,
+/// until the stream ends or the client goes away.
+pub async fn write_entries<W, S>(
+    writer: &mut W,
+    protocol: Protocol,
+    query: &StreamQuery,
+    entries: S,
+) -> Result<(), RMesgError>
+where
+    W: AsyncWrite + Unpin,
+    S: Stream<Item = Result<Entry, RMesgError>>,
+{
+    futures::pin_mut!(entries);
+    while let Some(entry) = entries.next().await {
+        let entry = entry?;
+        if !query.matches(&entry) {
+            continue;
+        }
+        let frame = match protocol {
+            Protocol::ServerSentEvents => sse_event(&entry).into_bytes(),
+            Protocol::WebSocket => websocket_frame(0x1, entry.to_string().as_bytes()),
+        };
+        let written = match writer.write_all(&frame).await {
+            Ok(()) => writer.flush().await,
+            Err(e) => Err(e),
+        };
+        match written {
+            Ok(()) => {}
+            Err(e) if client_went_away(&e) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    if protocol == Protocol::WebSocket {
+        writer.write_all(&websocket_frame(0x8, &[])).await?;
+    }
+    Ok(())
+}
+
+fn client_went_away(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::BrokenPipe | std::io::ErrorKind::ConnectionReset
+    )
+}
+
+struct Request<'a> {
+    path: &'a str,
+    query: &'a str,
+    websocket_key: Option<&'a str>,
+}
+
+impl<'a> Request<'a> {
+    fn parse(head: &'a str) -> Result<Request<'a>, RMesgError> {
+        let mut lines = head.split("\r\n");
+        let target = match lines.next().map(|l| l.split(' ').collect::<Vec<&str>>()) {
+            Some(parts) if parts.len() == 3 && parts[0] == "GET" => parts[1],
+            _ => {
+                return Err(RMesgError::InternalError(
+                    "Only GET requests are served".to_owned(),
+                ))
+            }
+        };
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+        let websocket_key = lines.find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            match name.trim().eq_ignore_ascii_case("Sec-WebSocket-Key") {
+                true => Some(value.trim()),
+                false => None,
+            }
+        });
+
+        Ok(Request {
+            path,
+            query,
+            websocket_key,
+        })
+    }
+}
+
+async fn read_request_head(socket: &mut TcpStream) -> Result<String, RMesgError> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.ends_with(b"\r\n\r\n") {
+        let read = socket.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..read]);
+        if head.len() > MAX_REQUEST_SIZE {
+            return Err(RMesgError::BufferFull(MAX_REQUEST_SIZE));
+        }
+    }
+    Ok(String::from_utf8(head)?)
+}
+
+async fn respond(socket: &mut TcpStream, status: &str, body: &str) -> Result<(), RMesgError> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    socket.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+// One event per entry; the sequence number (when there is one) becomes the event id
+fn sse_event(entry: &Entry) -> String {
+    let mut event = String::new();
+    if let Some(seq) = entry.sequence_num {
+        event.push_str(&format!("id: {}\n", seq));
+    }
+    for line in entry.to_string().split('\n') {
+        event.push_str("data: ");
+        event.push_str(line);
+        event.push('\n');
+    }
+    event.push('\n');
+    event
+}
+
+// An unmasked (server to client), unfragmented frame
+fn websocket_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn websocket_accept(key: &str) -> String {
+    let digest = sha1_smol::Sha1::from(format!("{}{}", key, WEBSOCKET_GUID)).digest();
+    base64::engine::general_purpose::STANDARD.encode(digest.bytes())
+}
+
+fn percent_decode(value: &str) -> Result<String, RMesgError> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let byte = value
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match byte {
+                    Some(byte) => decoded.push(byte),
+                    None => {
+                        return Err(RMesgError::FilterError(format!(
+                            "Invalid percent-encoding in query parameter {}",
+                            value
+                        )))
+                    }
+                }
+                i += 2;
+            }
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    Ok(String::from_utf8(decoded)?)
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn entry(seq: usize, level: LogLevel, message: &str) -> Entry {
+        Entry {
+            level: Some(level),
+            sequence_num: Some(seq),
+            timestamp_from_system_start: Some(Duration::from_secs(seq as u64)),
+            message: message.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_query() {
+        let query =
+            StreamQuery::from_query_string("level=warn&pattern=nvme%5Cd+I%2FO&_=123").unwrap();
+        assert_eq!(query.min_level, Some(LogLevel::Warning));
+        assert_eq!(query.pattern.as_ref().unwrap().as_str(), r"nvme\d I/O");
+
+        assert!(query.matches(&entry(1, LogLevel::Error, "nvme0 I/O 12 QID 3 timeout")));
+        assert!(!query.matches(&entry(1, LogLevel::Info, "nvme0 I/O 12 QID 3 timeout")));
+        assert!(!query.matches(&entry(1, LogLevel::Error, "usb 1-1: reset")));
+        assert!(StreamQuery::from_query_string("").unwrap().matches(&entry(
+            1,
+            LogLevel::Debug,
+            "anything"
+        )));
+
+        assert!(StreamQuery::from_query_string("level=loud").is_err());
+        assert!(StreamQuery::from_query_string("pattern=(").is_err());
+        assert!(StreamQuery::from_query_string("pattern=%zz").is_err());
+    }
+
+    #[test]
+    fn test_request() {
+        let request = Request::parse(
+            "GET /ws?level=err HTTP/1.1\r\nHost: localhost\r\nsec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(request.path, "/ws");
+        assert_eq!(request.query, "level=err");
+        assert_eq!(request.websocket_key, Some("dGhlIHNhbXBsZSBub25jZQ=="));
+
+        assert!(Request::parse("POST /events HTTP/1.1\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_websocket() {
+        // the example handshake from RFC 6455
+        assert_eq!(
+            websocket_accept("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        assert_eq!(websocket_frame(0x1, b"hi"), vec![0x81, 2, b'h', b'i']);
+        assert_eq!(&websocket_frame(0x1, &[0; 300])[..4], &[0x81, 126, 1, 44]);
+        assert_eq!(websocket_frame(0x8, &[]), vec![0x88, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_write_entries() {
+        let entries = || {
+            futures::stream::iter(vec![
+                Ok(entry(
+                    1,
+                    LogLevel::Info,
+                    "usb 1-1: new high-speed USB device",
+                )),
+                Ok(entry(
+                    2,
+                    LogLevel::Error,
+                    "nvme nvme0: I/O 12 QID 3 timeout",
+                )),
+            ])
+        };
+        let query = StreamQuery::from_query_string("level=err").unwrap();
+
+        let mut sse = Vec::new();
+        write_entries(&mut sse, Protocol::ServerSentEvents, &query, entries())
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(sse).unwrap(),
+            "id: 2\ndata: [        2.000000] nvme nvme0: I/O 12 QID 3 timeout\n\n"
+        );
+
+        let mut ws = Vec::new();
+        write_entries(
+            &mut ws,
+            Protocol::WebSocket,
+            &StreamQuery::default(),
+            entries(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(ws[0], 0x81);
+        assert!(ws.ends_with(&[0x88, 0]));
+    }
+}
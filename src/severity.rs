@@ -0,0 +1,245 @@
+use crate::entry::{Entry, LogLevel};
+/// Re-mapping of the severity of specific messages.
+///
+/// Some drivers log at a level that doesn't reflect how much an operator should care
+/// (a known-benign firmware complaint logged as an error, for instance). A
+/// `SeverityRemap` stage rewrites the level of matching entries, so that filters and
+/// alerting placed after it see the effective severity instead.
+///
+use crate::error::RMesgError;
+use crate::stage::Stage;
+
+use regex::Regex;
+
+#[cfg(feature = "config")]
+use serde::Deserialize;
+
+/// Entries whose message matches `pattern` (and whose level is `from`, when set) are
+/// re-leveled to `to`.
+#[derive(Clone, Debug)]
+pub struct SeverityRule {
+    pub pattern: Regex,
+    pub from: Option<LogLevel>,
+    pub to: LogLevel,
+}
+
+impl SeverityRule {
+    pub fn matches(&self, entry: &Entry) -> bool {
+        (self.from.is_none() || self.from == entry.level) && self.pattern.is_match(&entry.message)
+    }
+}
+
+/// A stage re-leveling entries by rule. The first matching rule applies.
+#[derive(Clone, Debug, Default)]
+pub struct SeverityRemap {
+    rules: Vec<SeverityRule>,
+}
+
+impl SeverityRemap {
+    pub fn new() -> SeverityRemap {
+        SeverityRemap::default()
+    }
+
+    /// Adds a rule re-leveling messages matching `pattern` to `to`, whatever their level.
+    pub fn rule(self, pattern: &str, to: LogLevel) -> Result<SeverityRemap, RMesgError> {
+        self.add(pattern, None, to)
+    }
+
+    /// Adds a rule re-leveling messages matching `pattern` logged at `from` to `to`.
+    pub fn rule_from(
+        self,
+        pattern: &str,
+        from: LogLevel,
+        to: LogLevel,
+    ) -> Result<SeverityRemap, RMesgError> {
+        self.add(pattern, Some(from), to)
+    }
+
+    /// Loads rules from TOML, as a list of `[[rule]]` tables. For example:
+    ///
+    /// ```toml
+    /// [[rule]]
+    /// pattern = "ACPI BIOS Error \\(bug\\)"
+    /// from = "err"    # optional
+    /// to = "info"
+    /// ```
+    ///
+    /// Levels are named as the kernel names them: emerg, alert, crit, err, warn,
+    /// notice, info, debug.
+    #[cfg(feature = "config")]
+    pub fn from_toml(toml: &str) -> Result<SeverityRemap, RMesgError> {
+        #[derive(Deserialize)]
+        struct Config {
+            #[serde(default)]
+            rule: Vec<RuleConfig>,
+        }
+        #[derive(Deserialize)]
+        struct RuleConfig {
+            pattern: String,
+            from: Option<String>,
+            to: String,
+        }
+
+        let config: Config = match toml::from_str(toml) {
+            Ok(config) => config,
+            Err(e) => {
+                return Err(RMesgError::ConfigError(format!(
+                    "Unable to parse severity rules: {}",
+                    e
+                )))
+            }
+        };
+
+        let mut remap = SeverityRemap::new();
+        for rule in config.rule {
+            let from = match rule.from {
+                Some(from) => Some(parse_level(&from)?),
+                None => None,
+            };
+            remap = remap.add(&rule.pattern, from, parse_level(&rule.to)?)?;
+        }
+        Ok(remap)
+    }
+
+    pub fn rules(&self) -> &[SeverityRule] {
+        &self.rules
+    }
+
+    fn add(
+        mut self,
+        pattern: &str,
+        from: Option<LogLevel>,
+        to: LogLevel,
+    ) -> Result<SeverityRemap, RMesgError> {
+        let pattern = match Regex::new(pattern) {
+            Ok(re) => re,
+            Err(e) => {
+                return Err(RMesgError::FilterError(format!(
+                    "Unable to compile severity rule pattern {}: {}",
+                    pattern, e
+                )))
+            }
+        };
+        self.rules.push(SeverityRule { pattern, from, to });
+        Ok(self)
+    }
+}
+
+#[cfg(feature = "config")]
+fn parse_level(level: &str) -> Result<LogLevel, RMesgError> {
+    match level.parse() {
+        Ok(level) => Ok(level),
+        Err(_) => Err(RMesgError::ConfigError(format!(
+            "Unknown log level {} (expected one of emerg, alert, crit, err, warn, notice, info, debug)",
+            level
+        ))),
+    }
+}
+
+impl Stage for SeverityRemap {
+    fn process(&mut self, mut entry: Entry) -> Option<Entry> {
+        if let Some(rule) = self.rules.iter().find(|r| r.matches(&entry)) {
+            entry.level = Some(rule.to);
+        }
+        Some(entry)
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(level: LogLevel, message: &str) -> Entry {
+        Entry {
+            level: Some(level),
+            message: message.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_builder_rules() {
+        let mut remap = SeverityRemap::new()
+            .rule_from(r"ACPI BIOS Error \(bug\)", LogLevel::Error, LogLevel::Info)
+            .unwrap()
+            .rule("thermal.*critical temperature", LogLevel::Critical)
+            .unwrap()
+            .rule("ACPI", LogLevel::Debug)
+            .unwrap();
+
+        let e = remap
+            .process(entry(
+                LogLevel::Error,
+                "ACPI BIOS Error (bug): Failure creating named object",
+            ))
+            .unwrap();
+        assert_eq!(e.level, Some(LogLevel::Info));
+
+        // `from` doesn't match, so the later catch-all rule applies
+        let e = remap
+            .process(entry(
+                LogLevel::Warning,
+                "ACPI BIOS Error (bug): Could not resolve symbol",
+            ))
+            .unwrap();
+        assert_eq!(e.level, Some(LogLevel::Debug));
+
+        let e = remap
+            .process(entry(
+                LogLevel::Warning,
+                "thermal thermal_zone0: critical temperature reached",
+            ))
+            .unwrap();
+        assert_eq!(e.level, Some(LogLevel::Critical));
+
+        let e = remap
+            .process(entry(
+                LogLevel::Error,
+                "usb 1-1: device descriptor read/64, error -71",
+            ))
+            .unwrap();
+        assert_eq!(e.level, Some(LogLevel::Error));
+
+        assert!(SeverityRemap::new().rule("(", LogLevel::Info).is_err());
+    }
+
+    #[cfg(feature = "config")]
+    #[test]
+    fn test_from_toml() {
+        let mut remap = SeverityRemap::from_toml(
+            r#"
+            [[rule]]
+            pattern = "ACPI BIOS Error \\(bug\\)"
+            from = "err"
+            to = "info"
+
+            [[rule]]
+            pattern = "^nouveau"
+            to = "warn"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(remap.rules().len(), 2);
+
+        let e = remap
+            .process(entry(
+                LogLevel::Error,
+                "ACPI BIOS Error (bug): Failure creating named object",
+            ))
+            .unwrap();
+        assert_eq!(e.level, Some(LogLevel::Info));
+
+        assert!(matches!(
+            SeverityRemap::from_toml("[[rule]]\npattern = \"x\"\nto = \"loud\""),
+            Err(RMesgError::ConfigError(_))
+        ));
+        assert!(matches!(
+            SeverityRemap::from_toml("[[rule]]\nto = \"info\""),
+            Err(RMesgError::ConfigError(_))
+        ));
+        assert_eq!(SeverityRemap::from_toml("").unwrap().rules().len(), 0);
+    }
+}
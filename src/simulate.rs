@@ -0,0 +1,526 @@
+use crate::entry::{Entry, LogFacility, LogLevel};
+/// A simulated kernel log, for load testing sinks and alert rules without a real
+/// machine's kernel behind them.
+///
+/// `Generator` synthesizes entries like a real kernel's (drivers, filesystems, the
+/// network stack, the odd NVMe timeout or OOM kill) at a configurable rate, with a
+/// configurable mix of severities and optional periodic bursts. It's deterministic for a
+/// given `SimProfile`, seed included, so a load test can be replayed. `SimulatedSource`
+/// runs one in real time as a `KLogSource`, which is what `Backend::Simulated` reads.
+///
+use crate::error::RMesgError;
+use crate::provenance::{self, SourceBackend};
+use crate::source::KLogSource;
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "async")]
+use futures::stream::{self, Stream, StreamExt};
+#[cfg(feature = "async")]
+use std::pin::Pin;
+
+/// Periodic bursts: for `length` at the start of every `every`, entries come `factor`
+/// times as fast.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Burst {
+    pub every: Duration,
+    pub length: Duration,
+    pub factor: f64,
+}
+
+/// What a simulated kernel logs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimProfile {
+    /// Entries per second outside bursts
+    pub rate: f64,
+    /// Relative weights of the levels entries are logged at
+    pub levels: Vec<(LogLevel, u32)>,
+    pub burst: Option<Burst>,
+    pub seed: u64,
+    /// How long the simulated machine has been up when a `SimulatedSource` starts, so
+    /// its buffer isn't empty
+    pub uptime: Duration,
+    /// How many entries a `SimulatedSource`'s buffer holds; older ones are dropped
+    pub capacity: usize,
+}
+
+impl Default for SimProfile {
+    /// A quiet machine: ten entries a second, mostly informational, no bursts.
+    fn default() -> SimProfile {
+        SimProfile {
+            rate: 10.0,
+            levels: vec![
+                (LogLevel::Critical, 1),
+                (LogLevel::Error, 5),
+                (LogLevel::Warning, 10),
+                (LogLevel::Notice, 10),
+                (LogLevel::Info, 64),
+                (LogLevel::Debug, 10),
+            ],
+            burst: None,
+            seed: 0,
+            uptime: Duration::from_secs(60),
+            capacity: 4096,
+        }
+    }
+}
+
+impl SimProfile {
+    pub fn with_options(
+        rate: f64,
+        levels: Vec<(LogLevel, u32)>,
+        burst: Option<Burst>,
+    ) -> SimProfile {
+        SimProfile {
+            rate,
+            levels,
+            burst,
+            ..SimProfile::default()
+        }
+    }
+
+    fn validate(&self) -> Result<(), RMesgError> {
+        if !(self.rate > 0.0 && self.rate.is_finite()) {
+            return Err(RMesgError::ConfigError(format!(
+                "The simulated rate must be a positive number of entries a second, not {}",
+                self.rate
+            )));
+        }
+        if self.levels.iter().all(|(_, weight)| *weight == 0) {
+            return Err(RMesgError::ConfigError(
+                "The simulated severity mix needs at least one level with a weight".to_owned(),
+            ));
+        }
+        if let Some(burst) = self.burst {
+            if burst.every.is_zero() || burst.length > burst.every {
+                return Err(RMesgError::ConfigError(format!(
+                    "A simulated burst of {:?} can't recur every {:?}",
+                    burst.length, burst.every
+                )));
+            }
+            if !(burst.factor > 0.0 && burst.factor.is_finite()) {
+                return Err(RMesgError::ConfigError(format!(
+                    "A simulated burst's factor must be positive, not {}",
+                    burst.factor
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+// SplitMix64: small, fast and good enough to pick messages with
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}
+
+// Messages per level; `#` is replaced with a digit and `%` with a larger number
+const CRITICAL: &[&str] = &[
+    "Out of memory: Killed process % (java) total-vm:%kB, anon-rss:%kB, file-rss:0kB",
+    "EXT4-fs (sda#): Remounting filesystem read-only",
+    "mce: [Hardware Error]: CPU #: Machine Check: 0 Bank #: bc00000000010135",
+];
+const ERROR: &[&str] = &[
+    "nvme nvme0: I/O % QID # timeout, aborting",
+    "blk_update_request: I/O error, dev sda, sector % op 0x0:(READ) flags 0x0 phys_seg 1 prio class 0",
+    "usb 1-#: device descriptor read/64, error -71",
+    "EXT4-fs error (device sda#): ext4_find_entry:1455: inode #%: comm find: reading directory lblock 0",
+];
+const WARNING: &[&str] = &[
+    "TCP: request_sock_TCP: Possible SYN flooding on port %. Sending cookies.",
+    "CPU#: Core temperature above threshold, cpu clock throttled (total events = %)",
+    "hrtimer: interrupt took % ns",
+    "nf_conntrack: nf_conntrack: table full, dropping packet",
+];
+const NOTICE: &[&str] = &[
+    "audit: type=1400 audit(%.%:%): apparmor=\"STATUS\" operation=\"profile_load\" name=\"snap.%\"",
+    "e1000e 0000:00:1f.#: eth0: NIC Link is Up 1000 Mbps Full Duplex, Flow Control: None",
+    "device veth% entered promiscuous mode",
+];
+const INFO: &[&str] = &[
+    "usb 1-#: new high-speed USB device number # using xhci_hcd",
+    "br-%: port #(veth%) entered forwarding state",
+    "EXT4-fs (sda#): mounted filesystem with ordered data mode. Quota mode: none.",
+    "IPv6: ADDRCONF(NETDEV_CHANGE): veth%: link becomes ready",
+    "wlan0: associated",
+    "systemd[1]: Started Session % of user %.",
+];
+const DEBUG: &[&str] = &[
+    "PM: suspend entry (deep)",
+    "xhci_hcd 0000:00:14.0: xHCI Host Controller port # status change",
+    "random: crng reseeded on system resumption",
+];
+
+fn messages(level: LogLevel) -> &'static [&'static str] {
+    match level {
+        LogLevel::Emergency | LogLevel::Alert | LogLevel::Critical => CRITICAL,
+        LogLevel::Error => ERROR,
+        LogLevel::Warning => WARNING,
+        LogLevel::Notice => NOTICE,
+        LogLevel::Info => INFO,
+        LogLevel::Debug => DEBUG,
+    }
+}
+
+/// An endless, deterministic series of simulated entries, timestamped in simulated
+/// kernel time (starting at boot).
+#[derive(Debug)]
+pub struct Generator {
+    profile: SimProfile,
+    rng: Rng,
+    total_weight: u64,
+    next_at: Duration,
+    next_sequence_num: usize,
+}
+
+impl Generator {
+    pub fn with_options(profile: SimProfile) -> Result<Generator, RMesgError> {
+        profile.validate()?;
+        Ok(Generator {
+            rng: Rng(profile.seed),
+            total_weight: profile.levels.iter().map(|(_, w)| *w as u64).sum(),
+            profile,
+            next_at: Duration::from_secs(0),
+            next_sequence_num: 0,
+        })
+    }
+
+    pub fn profile(&self) -> &SimProfile {
+        &self.profile
+    }
+
+    /// When the next entry is due, in simulated kernel time.
+    pub fn next_at(&self) -> Duration {
+        self.next_at
+    }
+
+    /// The entries due up to `until`, in simulated kernel time.
+    pub fn until(&mut self, until: Duration) -> Vec<Entry> {
+        let mut entries = Vec::new();
+        while self.next_at <= until {
+            entries.push(self.generate());
+        }
+        entries
+    }
+
+    fn rate_at(&self, at: Duration) -> f64 {
+        match self.profile.burst {
+            Some(burst) if at.as_nanos() % burst.every.as_nanos() < burst.length.as_nanos() => {
+                self.profile.rate * burst.factor
+            }
+            _ => self.profile.rate,
+        }
+    }
+
+    fn level(&mut self) -> LogLevel {
+        let mut pick = self.rng.below(self.total_weight);
+        for (level, weight) in self.profile.levels.iter() {
+            if pick < *weight as u64 {
+                return *level;
+            }
+            pick -= *weight as u64;
+        }
+        unreachable!("The pick is below the total weight")
+    }
+
+    fn message(&mut self, level: LogLevel) -> String {
+        let candidates = messages(level);
+        let template = candidates[self.rng.below(candidates.len() as u64) as usize];
+        let mut message = String::with_capacity(template.len() + 16);
+        for c in template.chars() {
+            match c {
+                '#' => message.push_str(&self.rng.below(10).to_string()),
+                '%' => message.push_str(&self.rng.below(100_000).to_string()),
+                c => message.push(c),
+            }
+        }
+        message
+    }
+
+    fn generate(&mut self) -> Entry {
+        let level = self.level();
+        let message = self.message(level);
+        let entry = Entry {
+            facility: Some(LogFacility::Kern),
+            level: Some(level),
+            sequence_num: Some(self.next_sequence_num),
+            timestamp_from_system_start: Some(self.next_at),
+            message,
+            timestamp_realtime: None,
+            provenance: None,
+            malformed: false,
+            priority: Some(level as u32),
+            tags: Default::default(),
+        };
+        self.next_sequence_num += 1;
+        self.next_at += Duration::from_secs_f64(1.0 / self.rate_at(self.next_at));
+        entry
+    }
+}
+
+impl Iterator for Generator {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        Some(self.generate())
+    }
+}
+
+/// A `Generator` run in real time as a `KLogSource`: entries come due as time passes,
+/// into a buffer of `capacity` entries that starts with the ones logged during
+/// `uptime`.
+#[derive(Debug)]
+pub struct SimulatedSource {
+    generator: Generator,
+    started: Instant,
+    buffer: VecDeque<Entry>,
+}
+
+impl SimulatedSource {
+    pub fn with_options(profile: SimProfile) -> Result<SimulatedSource, RMesgError> {
+        Ok(SimulatedSource {
+            generator: Generator::with_options(profile)?,
+            started: Instant::now(),
+            buffer: VecDeque::new(),
+        })
+    }
+
+    /// How long until the next entry comes due.
+    pub fn until_next(&self) -> Duration {
+        let now = self.generator.profile.uptime + self.started.elapsed();
+        self.generator.next_at().saturating_sub(now)
+    }
+
+    // the entries that came due since last time, also kept in the buffer
+    fn catch_up(&mut self) -> Vec<Entry> {
+        let now = self.generator.profile.uptime + self.started.elapsed();
+        let mut entries = self.generator.until(now);
+        let capacity = self.generator.profile.capacity;
+        if entries.len() > capacity {
+            entries.drain(..entries.len() - capacity);
+        }
+        let overflow = (self.buffer.len() + entries.len()).saturating_sub(capacity);
+        self.buffer.drain(..overflow);
+        self.buffer.extend(entries.iter().cloned());
+        entries
+            .into_iter()
+            .map(|e| provenance::tag(e, SourceBackend::Simulated))
+            .collect()
+    }
+}
+
+impl KLogSource for SimulatedSource {
+    fn read_all(&mut self) -> Result<Vec<Entry>, RMesgError> {
+        self.catch_up();
+        Ok(self
+            .buffer
+            .iter()
+            .cloned()
+            .map(|e| provenance::tag(e, SourceBackend::Simulated))
+            .collect())
+    }
+
+    fn poll_new(&mut self) -> Result<Vec<Entry>, RMesgError> {
+        Ok(self.catch_up())
+    }
+
+    fn clear(&mut self) -> Result<(), RMesgError> {
+        self.catch_up();
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+/// A snapshot of a default simulated kernel's buffer (see `SimProfile::default`).
+pub fn simulated() -> Result<Vec<Entry>, RMesgError> {
+    SimulatedSource::with_options(SimProfile::default())?.read_all()
+}
+
+/// Like `simulated`, in the /dev/kmsg format.
+pub fn simulated_raw() -> Result<String, RMesgError> {
+    let mut raw = String::new();
+    for entry in simulated()? {
+        let line = entry.to_kmsg_str().map_err(|e| {
+            RMesgError::InternalError(format!("Unable to format a simulated entry: {}", e))
+        })?;
+        raw.push_str(&line);
+        raw.push('\n');
+    }
+    Ok(raw)
+}
+
+/// A simulated kernel's entries as they come due: its buffer, then new entries in
+/// batches that grow with the rate (timers being no finer than a millisecond or so).
+#[cfg(feature = "async")]
+pub type SimulatedStream = Pin<Box<dyn Stream<Item = Result<Entry, RMesgError>> + Send>>;
+
+/// Follows a kernel simulating `profile`, with its buffer cleared first if `clear` is
+/// set.
+#[cfg(feature = "async")]
+pub fn simulated_stream(profile: SimProfile, clear: bool) -> Result<SimulatedStream, RMesgError> {
+    let mut source = SimulatedSource::with_options(profile)?;
+    let buffered = match clear {
+        true => {
+            source.clear()?;
+            Vec::new()
+        }
+        false => source.read_all()?,
+    };
+    let new = stream::unfold(source, |mut source| async move {
+        tokio::time::sleep(source.until_next()).await;
+        let entries = source.poll_new();
+        Some((entries, source))
+    })
+    .flat_map(|entries| {
+        stream::iter(match entries {
+            Ok(entries) => entries.into_iter().map(Ok).collect(),
+            Err(e) => vec![Err(e)],
+        })
+    });
+    Ok(Box::pin(
+        stream::iter(buffered.into_iter().map(Ok)).chain(new),
+    ))
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rate_and_mix() {
+        let profile = SimProfile::with_options(
+            100.0,
+            vec![
+                (LogLevel::Error, 1),
+                (LogLevel::Info, 3),
+                (LogLevel::Debug, 0),
+            ],
+            None,
+        );
+        let entries = Generator::with_options(profile.clone())
+            .unwrap()
+            .until(Duration::from_secs(10));
+        assert_eq!(entries.len(), 1001);
+        assert_eq!(entries[1].sequence_num, Some(1));
+        assert_eq!(
+            entries[1].timestamp_from_system_start,
+            Some(Duration::from_millis(10))
+        );
+
+        let errors = entries
+            .iter()
+            .filter(|e| e.level == Some(LogLevel::Error))
+            .count();
+        assert!((200..300).contains(&errors), "{} errors", errors);
+        assert!(entries.iter().all(|e| e.level != Some(LogLevel::Debug)));
+        assert!(entries
+            .iter()
+            .all(|e| !e.message.contains('#') && !e.message.contains('%')));
+
+        // the same profile logs the same entries
+        let again: Vec<_> = Generator::with_options(profile).unwrap().take(50).collect();
+        assert_eq!(again[..], entries[..50]);
+    }
+
+    #[test]
+    fn test_bursts() {
+        let burst = Burst {
+            every: Duration::from_secs(10),
+            length: Duration::from_secs(1),
+            factor: 10.0,
+        };
+        let entries = Generator::with_options(SimProfile::with_options(
+            10.0,
+            SimProfile::default().levels,
+            Some(burst),
+        ))
+        .unwrap()
+        .until(Duration::from_secs(20) - Duration::from_millis(1));
+        let in_bursts = entries
+            .iter()
+            .filter(|e| e.timestamp_from_system_start.unwrap().as_secs() % 10 == 0)
+            .count();
+        // 100 a second for 1s in 10, 10 a second otherwise
+        assert_eq!(in_bursts, 200);
+        assert_eq!(entries.len() - in_bursts, 180);
+    }
+
+    #[test]
+    fn test_invalid_profiles() {
+        let invalid = [
+            SimProfile::with_options(0.0, SimProfile::default().levels, None),
+            SimProfile::with_options(1.0, vec![(LogLevel::Info, 0)], None),
+            SimProfile::with_options(
+                1.0,
+                SimProfile::default().levels,
+                Some(Burst {
+                    every: Duration::from_secs(1),
+                    length: Duration::from_secs(2),
+                    factor: 2.0,
+                }),
+            ),
+        ];
+        for profile in invalid.iter() {
+            assert!(matches!(
+                Generator::with_options(profile.clone()),
+                Err(RMesgError::ConfigError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_source() {
+        let profile = SimProfile {
+            capacity: 100,
+            ..SimProfile::default()
+        };
+        let mut source = SimulatedSource::with_options(profile).unwrap();
+
+        // the buffer starts with the last of the minute's entries since boot
+        let all = source.read_all().unwrap();
+        assert_eq!(all.len(), 100);
+        assert!(all[0].sequence_num > Some(400));
+        assert_eq!(
+            all[0].provenance.as_ref().map(|p| p.backend),
+            Some(SourceBackend::Simulated)
+        );
+        assert!(source.poll_new().unwrap().len() < 10);
+
+        source.clear().unwrap();
+        assert!(source.read_all().unwrap().len() < 10);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_stream() {
+        let profile = SimProfile {
+            rate: 1000.0,
+            uptime: Duration::from_secs(0),
+            ..SimProfile::default()
+        };
+        let entries: Vec<_> = simulated_stream(profile, false)
+            .unwrap()
+            .take(20)
+            .collect()
+            .await;
+        assert_eq!(entries.len(), 20);
+        assert_eq!(entries[19].as_ref().unwrap().sequence_num, Some(19));
+    }
+}
@@ -0,0 +1,314 @@
+use crate::bookmark::Bookmark;
+use crate::entry::Entry;
+use crate::error::RMesgError;
+use crate::parse;
+use crate::sinks::Checkpointed;
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// A local archive of entries: a file of /dev/kmsg-format records (as
+/// `Entry::to_kmsg_str` writes them, and `read_archive` reads them back), next to a
+/// checkpoint file (the archive's path with `.checkpoint` appended) holding the length
+/// of the archive as of the last commit and the cursor just past it.
+///
+/// A commit appends the batch and syncs it, then replaces the checkpoint (and syncs its
+/// directory). A commit that fails is rolled back at once, and anything past the
+/// checkpointed length, a batch a crash cut short of its checkpoint, when the archive is
+/// next opened, so with `Checkpointing` a retry or a restarted reader stores it again
+/// exactly once.
+pub struct FileSink {
+    file: File,
+    checkpoint_path: PathBuf,
+    committed: u64,
+    checkpoint: Option<Bookmark>,
+}
+
+impl FileSink {
+    /// Opens (or creates) the archive at `path`, truncating it to its last commit. An
+    /// archive without a checkpoint file is taken as committed as it is.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<FileSink, RMesgError> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| {
+                RMesgError::SinkError(format!("Unable to open archive {}: {}", path.display(), e))
+            })?;
+        let length = file.metadata()?.len();
+
+        let checkpoint_path = checkpoint_path(path);
+        let (committed, checkpoint) = match read_checkpoint(&checkpoint_path)? {
+            Some((committed, _)) if committed > length => {
+                return Err(RMesgError::SinkError(format!(
+                    "Archive {} is {} bytes, shorter than the {} its checkpoint has committed",
+                    path.display(),
+                    length,
+                    committed
+                )))
+            }
+            Some((committed, cursor)) => (committed, Some(cursor)),
+            None => (length, None),
+        };
+        if committed < length {
+            file.set_len(committed)?;
+        }
+
+        Ok(FileSink {
+            file,
+            checkpoint_path,
+            committed,
+            checkpoint,
+        })
+    }
+
+    fn append(&mut self, entries: &[Entry]) -> Result<u64, RMesgError> {
+        let mut batch = String::new();
+        for entry in entries {
+            let record = entry.to_kmsg_str().map_err(|e| {
+                RMesgError::SinkError(format!("Unable to format entry for the archive: {}", e))
+            })?;
+            batch.push_str(&record);
+            batch.push('\n');
+        }
+        self.file.write_all(batch.as_bytes())?;
+        self.file.sync_data()?;
+        Ok(self.committed + batch.len() as u64)
+    }
+
+    // Written aside and renamed into place, so that the checkpoint is the old one or the
+    // new one, never half of either
+    fn replace_checkpoint(&self, committed: u64, cursor: &Bookmark) -> Result<(), RMesgError> {
+        let mut partial = self.checkpoint_path.clone().into_os_string();
+        partial.push(".partial");
+        let mut checkpoint = File::create(&partial)?;
+        checkpoint.write_all(format!("{} {}\n", committed, cursor.to_token()).as_bytes())?;
+        checkpoint.sync_all()?;
+        fs::rename(&partial, &self.checkpoint_path)?;
+        Ok(())
+    }
+}
+
+impl Checkpointed for FileSink {
+    fn commit(&mut self, entries: &[Entry], cursor: &Bookmark) -> Result<(), RMesgError> {
+        let committed = match self.append(entries).and_then(|committed| {
+            self.replace_checkpoint(committed, cursor)
+                .map(|_| committed)
+        }) {
+            Ok(committed) => committed,
+            Err(e) => {
+                // so that the next commit appends to the last one, not to this batch (or
+                // half of it); otherwise a retry would store it twice
+                let _ = self.file.set_len(self.committed);
+                return Err(e);
+            }
+        };
+        self.committed = committed;
+        self.checkpoint = Some(cursor.clone());
+
+        // so that the new checkpoint survives a crash
+        sync_directory(&self.checkpoint_path)
+    }
+
+    fn checkpoint(&self) -> Result<Option<Bookmark>, RMesgError> {
+        Ok(self.checkpoint.clone())
+    }
+}
+
+/// The entries committed to the archive at `path`.
+pub fn read_archive<P: AsRef<Path>>(path: P) -> Result<Vec<Entry>, RMesgError> {
+    let path = path.as_ref();
+    let mut archive = Vec::new();
+    File::open(path)?.read_to_end(&mut archive)?;
+    if let Some((committed, _)) = read_checkpoint(&checkpoint_path(path))? {
+        archive.truncate(committed as usize);
+    }
+    Ok(parse::parse_buffer(&String::from_utf8(archive)?))
+}
+
+fn checkpoint_path(path: &Path) -> PathBuf {
+    let mut checkpoint = OsString::from(path);
+    checkpoint.push(".checkpoint");
+    PathBuf::from(checkpoint)
+}
+
+#[cfg(unix)]
+fn sync_directory(path: &Path) -> Result<(), RMesgError> {
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(directory)?.sync_all()?;
+    Ok(())
+}
+
+// Renames are durable as they are elsewhere, or can't be synced for
+#[cfg(not(unix))]
+fn sync_directory(_path: &Path) -> Result<(), RMesgError> {
+    Ok(())
+}
+
+fn read_checkpoint(path: &Path) -> Result<Option<(u64, Bookmark)>, RMesgError> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let invalid = || RMesgError::SinkError(format!("Invalid archive checkpoint {:?}", contents));
+    let (committed, token) = contents.trim().split_once(' ').ok_or_else(invalid)?;
+    let committed = committed.parse().map_err(|_| invalid())?;
+    Ok(Some((committed, Bookmark::from_token(token)?)))
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entry::{LogFacility, LogLevel};
+    use crate::sinks::{Checkpointing, Sink};
+    use std::time::Duration;
+
+    fn entry(n: usize) -> Entry {
+        Entry {
+            facility: Some(LogFacility::Kern),
+            level: Some(LogLevel::Info),
+            sequence_num: Some(n),
+            timestamp_from_system_start: Some(Duration::from_micros(1000 * n as u64)),
+            message: format!("usb 1-{}: new high-speed USB device", n),
+            priority: Some(6),
+            ..Default::default()
+        }
+    }
+
+    fn archive(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("rmesg-archive-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(checkpoint_path(&path));
+        path
+    }
+
+    fn seqs(entries: Vec<Entry>) -> Vec<usize> {
+        entries.iter().map(|e| e.sequence_num.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_exactly_once_across_restarts() {
+        let path = archive("restarts");
+        let mut sink = Checkpointing::new(FileSink::open(&path).unwrap(), 2).unwrap();
+        for n in 1..=5 {
+            sink.write(&entry(n)).unwrap();
+        }
+        // 5 hadn't been committed when the process died, and the next batch was cut short
+        drop(sink);
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"6,6,6000,-;usb 1-6: new hi")
+            .unwrap();
+        assert_eq!(seqs(read_archive(&path).unwrap()), vec![1, 2, 3, 4]);
+
+        // the restarted reader replays everything still in the buffer
+        let mut sink = Checkpointing::new(FileSink::open(&path).unwrap(), 2).unwrap();
+        for n in 1..=7 {
+            sink.write(&entry(n)).unwrap();
+        }
+        sink.flush().unwrap();
+        let entries = read_archive(&path).unwrap();
+        assert_eq!(entries[6], entry(7));
+        assert_eq!(seqs(entries), (1..=7).collect::<Vec<_>>());
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(checkpoint_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn test_restart_without_sequence_numbers() {
+        let path = archive("unnumbered");
+        let unnumbered = |us: u64, message: &str| Entry {
+            sequence_num: None,
+            timestamp_from_system_start: Some(Duration::from_micros(us)),
+            message: message.to_owned(),
+            ..entry(0)
+        };
+        let buffer = vec![
+            unnumbered(1, "a"),
+            unnumbered(2, "b1"),
+            unnumbered(2, "b2"),
+            unnumbered(2, "b3"),
+            unnumbered(3, "c"),
+        ];
+
+        // a and b1 committed, b2 still being built when the process dies
+        let mut sink = Checkpointing::new(FileSink::open(&path).unwrap(), 2).unwrap();
+        for e in &buffer[..3] {
+            sink.write(e).unwrap();
+        }
+        drop(sink);
+
+        let mut sink = Checkpointing::new(FileSink::open(&path).unwrap(), 2).unwrap();
+        for e in &buffer {
+            sink.write(e).unwrap();
+        }
+        sink.flush().unwrap();
+        let messages: Vec<String> = read_archive(&path)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.message)
+            .collect();
+        assert_eq!(messages, vec!["a", "b1", "b2", "b3", "c"]);
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(checkpoint_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn test_failed_checkpoint() {
+        let path = archive("failed-checkpoint");
+        let mut sink = Checkpointing::new(FileSink::open(&path).unwrap(), 2).unwrap();
+        sink.write(&entry(1)).unwrap();
+        sink.write(&entry(2)).unwrap();
+        let committed = fs::metadata(&path).unwrap().len();
+
+        // the batch is appended, but its checkpoint can't be written
+        let mut partial = checkpoint_path(&path).into_os_string();
+        partial.push(".partial");
+        fs::create_dir(&partial).unwrap();
+        sink.write(&entry(3)).unwrap();
+        assert!(sink.write(&entry(4)).is_err());
+        assert_eq!(fs::metadata(&path).unwrap().len(), committed);
+
+        // and the retry stores it once
+        fs::remove_dir(&partial).unwrap();
+        sink.flush().unwrap();
+        assert_eq!(seqs(read_archive(&path).unwrap()), vec![1, 2, 3, 4]);
+        let sink = Checkpointing::new(FileSink::open(&path).unwrap(), 2).unwrap();
+        assert_eq!(sink.get_ref().committed, fs::metadata(&path).unwrap().len());
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(checkpoint_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn test_open_without_checkpoint() {
+        let path = archive("uncheckpointed");
+        fs::write(&path, "6,1,1000,-;usb 1-1: new high-speed USB device\n").unwrap();
+        let sink = FileSink::open(&path).unwrap();
+        assert_eq!(sink.checkpoint().unwrap(), None);
+        assert_eq!(seqs(read_archive(&path).unwrap()), vec![1]);
+
+        fs::write(checkpoint_path(&path), "4096 -/1/1000\n").unwrap();
+        assert!(matches!(
+            FileSink::open(&path),
+            Err(RMesgError::SinkError(_))
+        ));
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(checkpoint_path(&path)).unwrap();
+    }
+}
@@ -0,0 +1,426 @@
+use crate::clock::WallClock;
+use crate::entry::Entry;
+use crate::error::RMesgError;
+use crate::sinks::Sink;
+
+use std::fs;
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Where the hostname (sent in every record) is read from
+const PROC_SYS_KERNEL_HOSTNAME: &str = "/proc/sys/kernel/hostname";
+
+#[derive(Clone, Debug)]
+pub struct FluentOptions {
+    /// The tag events are forwarded under (used for routing by the aggregator)
+    pub tag: String,
+
+    /// Entries are sent in batches of this many (or on flush)
+    pub batch_size: usize,
+
+    /// How many times to retry sending a batch, reconnecting each time, before
+    /// dropping it. Backoff doubles after every attempt.
+    pub retries: u32,
+    pub retry_backoff: Duration,
+
+    pub timeout: Duration,
+}
+
+impl Default for FluentOptions {
+    fn default() -> Self {
+        Self {
+            tag: "kernel".to_owned(),
+            batch_size: 100,
+            retries: 3,
+            retry_backoff: Duration::from_millis(500),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A sink speaking the Fluent forward protocol (msgpack over TCP), as accepted by
+/// Fluentd, Fluent Bit and Vector. Batches are sent in Forward mode, one event per
+/// entry, with the entry's wall-clock time as its EventTime.
+///
+/// Each record holds `host`, `facility`, `level`, `sequence_num`,
+/// `timestamp_from_system_start` (in seconds), `subsystem`, `source` (the backend
+/// that read the entry, see `provenance`) and `message`.
+pub struct FluentSink {
+    address: String,
+    options: FluentOptions,
+    hostname: String,
+    clock: WallClock,
+    stream: Option<TcpStream>,
+    // the batch's events, already encoded
+    pending: Vec<u8>,
+    pending_count: usize,
+}
+
+impl FluentSink {
+    /// Create a new FluentSink forwarding to `address` (host:port, usually port 24224).
+    pub fn with_options(address: &str, options: FluentOptions) -> Result<Self, RMesgError> {
+        let hostname = match fs::read_to_string(PROC_SYS_KERNEL_HOSTNAME) {
+            Ok(h) => h.trim().to_owned(),
+            Err(_) => "localhost".to_owned(),
+        };
+
+        Ok(Self {
+            address: address.to_owned(),
+            options,
+            hostname,
+            clock: WallClock::now()?,
+            stream: None,
+            pending: Vec::new(),
+            pending_count: 0,
+        })
+    }
+
+    fn send_pending(&mut self) -> Result<(), RMesgError> {
+        if self.pending_count == 0 {
+            return Ok(());
+        }
+        let message = forward_message(&self.options.tag, &self.pending, self.pending_count);
+
+        let mut backoff = self.options.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            let err = match self.send(&message) {
+                Ok(()) => {
+                    self.pending.clear();
+                    self.pending_count = 0;
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
+            // whatever was partially written, the connection can't be trusted now
+            self.stream = None;
+
+            if attempt >= self.options.retries {
+                // drop the batch rather than let it grow while the aggregator is down
+                self.pending.clear();
+                self.pending_count = 0;
+                return Err(RMesgError::SinkError(format!(
+                    "Forwarding to {} failed after {} attempts: {}",
+                    self.address,
+                    attempt + 1,
+                    err
+                )));
+            }
+
+            thread::sleep(backoff);
+            backoff = backoff.saturating_mul(2);
+            attempt += 1;
+        }
+    }
+
+    fn send(&mut self, message: &[u8]) -> Result<(), std::io::Error> {
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            None => self
+                .stream
+                .insert(connect(&self.address, self.options.timeout)?),
+        };
+        stream.write_all(message)?;
+        stream.flush()
+    }
+}
+
+impl Sink for FluentSink {
+    fn write(&mut self, entry: &Entry) -> Result<(), RMesgError> {
+        self.clock.observe(entry);
+        let time = match entry.timestamp_from_system_start {
+            Some(ts) => self.clock.to_system_time(ts),
+            None => SystemTime::now(),
+        };
+
+        encode_event(&mut self.pending, time, &self.hostname, entry);
+        self.pending_count += 1;
+        if self.pending_count >= self.options.batch_size {
+            self.send_pending()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), RMesgError> {
+        self.send_pending()
+    }
+}
+
+fn connect(address: &str, timeout: Duration) -> Result<TcpStream, std::io::Error> {
+    let mut last_err = None;
+    for addr in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => {
+                stream.set_write_timeout(Some(timeout))?;
+                return Ok(stream);
+            }
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{} did not resolve to any address", address),
+        )
+    }))
+}
+
+// Forward mode: [tag, [[time, record], ...], {"size": count}]
+fn forward_message(tag: &str, events: &[u8], count: usize) -> Vec<u8> {
+    let mut message = Vec::with_capacity(events.len() + tag.len() + 16);
+    write_array_len(&mut message, 3);
+    write_str(&mut message, tag);
+    write_array_len(&mut message, count);
+    message.extend_from_slice(events);
+    write_map_len(&mut message, 1);
+    write_str(&mut message, "size");
+    write_uint(&mut message, count as u64);
+    message
+}
+
+// [EventTime, record]
+fn encode_event(buf: &mut Vec<u8>, time: SystemTime, hostname: &str, entry: &Entry) {
+    write_array_len(buf, 2);
+    write_event_time(buf, time);
+
+    write_map_len(buf, 9);
+    write_str(buf, "host");
+    write_str(buf, hostname);
+    write_str(buf, "facility");
+    write_optional(buf, entry.facility.map(|f| f.to_string()), |b, f| {
+        write_str(b, &f)
+    });
+    write_str(buf, "level");
+    write_optional(buf, entry.level.map(|l| l.to_string()), |b, l| {
+        write_str(b, &l)
+    });
+    write_str(buf, "sequence_num");
+    write_optional(buf, entry.sequence_num, |b, s| write_uint(b, s as u64));
+    write_str(buf, "timestamp_from_system_start");
+    write_optional(buf, entry.timestamp_from_system_start, |b, ts| {
+        write_f64(b, ts.as_secs_f64())
+    });
+    write_str(buf, "subsystem");
+    write_optional(buf, entry.subsystem(), write_str);
+    write_str(buf, "source");
+    write_optional(
+        buf,
+        entry.provenance.as_ref().map(|p| p.backend.to_string()),
+        |b, s| write_str(b, &s),
+    );
+    write_str(buf, "tags");
+    write_map_len(buf, entry.tags.len());
+    for (key, value) in entry.tags.iter() {
+        write_str(buf, key);
+        write_str(buf, value);
+    }
+    write_str(buf, "message");
+    write_str(buf, &entry.message);
+}
+
+// The few msgpack encodings the forward protocol needs
+
+fn write_array_len(buf: &mut Vec<u8>, len: usize) {
+    match len {
+        0..=15 => buf.push(0x90 | len as u8),
+        16..=0xffff => {
+            buf.push(0xdc);
+            buf.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        _ => {
+            buf.push(0xdd);
+            buf.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+}
+
+fn write_map_len(buf: &mut Vec<u8>, len: usize) {
+    match len {
+        0..=15 => buf.push(0x80 | len as u8),
+        16..=0xffff => {
+            buf.push(0xde);
+            buf.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        _ => {
+            buf.push(0xdf);
+            buf.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+}
+
+fn write_str(buf: &mut Vec<u8>, s: &str) {
+    match s.len() {
+        len @ 0..=31 => buf.push(0xa0 | len as u8),
+        len @ 32..=0xff => {
+            buf.push(0xd9);
+            buf.push(len as u8);
+        }
+        len @ 0x100..=0xffff => {
+            buf.push(0xda);
+            buf.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            buf.push(0xdb);
+            buf.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn write_uint(buf: &mut Vec<u8>, n: u64) {
+    match n {
+        0..=0x7f => buf.push(n as u8),
+        0x80..=0xff => {
+            buf.push(0xcc);
+            buf.push(n as u8);
+        }
+        0x100..=0xffff => {
+            buf.push(0xcd);
+            buf.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            buf.push(0xce);
+            buf.extend_from_slice(&(n as u32).to_be_bytes());
+        }
+        _ => {
+            buf.push(0xcf);
+            buf.extend_from_slice(&n.to_be_bytes());
+        }
+    }
+}
+
+fn write_f64(buf: &mut Vec<u8>, f: f64) {
+    buf.push(0xcb);
+    buf.extend_from_slice(&f.to_be_bytes());
+}
+
+fn write_optional<T, F: Fn(&mut Vec<u8>, T)>(buf: &mut Vec<u8>, value: Option<T>, write: F) {
+    match value {
+        Some(value) => write(buf, value),
+        None => buf.push(0xc0),
+    }
+}
+
+// EventTime is ext type 0: seconds and nanoseconds since the epoch, as big-endian u32s
+fn write_event_time(buf: &mut Vec<u8>, time: SystemTime) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    buf.extend_from_slice(&[0xd7, 0x00]);
+    buf.extend_from_slice(&(since_epoch.as_secs() as u32).to_be_bytes());
+    buf.extend_from_slice(&since_epoch.subsec_nanos().to_be_bytes());
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entry::{LogFacility, LogLevel};
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    fn test_entry(seq: usize) -> Entry {
+        Entry {
+            facility: Some(LogFacility::Kern),
+            level: Some(LogLevel::Error),
+            sequence_num: Some(seq),
+            timestamp_from_system_start: Some(Duration::from_secs(3)),
+            message: "nvme nvme0: I/O 12 QID 3 timeout".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_encoding() {
+        let mut buf = Vec::new();
+        write_uint(&mut buf, 5);
+        write_uint(&mut buf, 300);
+        write_str(&mut buf, "size");
+        write_event_time(&mut buf, UNIX_EPOCH + Duration::new(1, 2));
+        assert_eq!(
+            buf,
+            vec![
+                0x05, 0xcd, 0x01, 0x2c, 0xa4, b's', b'i', b'z', b'e', 0xd7, 0x00, 0, 0, 0, 1, 0, 0,
+                0, 2
+            ]
+        );
+
+        let message = forward_message("kernel", &[0xc0, 0xc0], 2);
+        assert_eq!(
+            &message[..9],
+            &[0x93, 0xa6, b'k', b'e', b'r', b'n', b'e', b'l', 0x92]
+        );
+        assert_eq!(&message[11..], &[0x81, 0xa4, b's', b'i', b'z', b'e', 0x02]);
+
+        let mut event = Vec::new();
+        encode_event(&mut event, UNIX_EPOCH, "host1", &test_entry(42));
+        // [time, {host: "host1", ...}]
+        assert_eq!(&event[..2], &[0x92, 0xd7]);
+        assert_eq!(
+            &event[11..23],
+            &[0x89, 0xa4, b'h', b'o', b's', b't', 0xa5, b'h', b'o', b's', b't', b'1']
+        );
+        assert!(event.ends_with(b"\xa4tags\x80\xa7message\xd9\x20nvme nvme0: I/O 12 QID 3 timeout"));
+    }
+
+    #[test]
+    fn test_batches() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).unwrap();
+            tx.send(received).unwrap();
+        });
+
+        let mut sink = FluentSink::with_options(
+            &address,
+            FluentOptions {
+                batch_size: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        for seq in 0..3 {
+            sink.write(&test_entry(seq)).unwrap();
+        }
+        sink.flush().unwrap();
+        drop(sink);
+
+        // a batch of 2, then the flushed 1, on the same connection
+        let received = rx.recv().unwrap();
+        assert_eq!(
+            &received[..9],
+            &[0x93, 0xa6, b'k', b'e', b'r', b'n', b'e', b'l', 0x92]
+        );
+        assert!(received.ends_with(&[0x81, 0xa4, b's', b'i', b'z', b'e', 0x01]));
+        let batches = received
+            .windows(8)
+            .filter(|w| *w == [0x93, 0xa6, b'k', b'e', b'r', b'n', b'e', b'l'])
+            .count();
+        assert_eq!(batches, 2);
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let mut sink = FluentSink::with_options(
+            "127.0.0.1:1",
+            FluentOptions {
+                batch_size: 1,
+                retries: 1,
+                retry_backoff: Duration::from_millis(1),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(matches!(
+            sink.write(&test_entry(1)),
+            Err(RMesgError::SinkError(_))
+        ));
+    }
+}
@@ -0,0 +1,426 @@
+use crate::bookmark;
+use crate::clock::WallClock;
+use crate::entry::Entry;
+use crate::error::RMesgError;
+use crate::sinks::Sink;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Where the hostname (the `host` label) is read from
+const PROC_SYS_KERNEL_HOSTNAME: &str = "/proc/sys/kernel/hostname";
+
+/// Loki's push endpoint, relative to its base URL
+const PUSH_PATH: &str = "/loki/api/v1/push";
+
+#[derive(Clone, Debug)]
+pub struct LokiOptions {
+    /// Entries are pushed in batches of this many...
+    pub batch_size: usize,
+    /// ...or once the oldest entry in the batch has waited this long (checked on
+    /// every write, and on flush).
+    pub batch_wait: Duration,
+
+    /// Sent as X-Scope-OrgID, for multi-tenant Loki
+    pub tenant_id: Option<String>,
+
+    /// How many times to retry a push that failed with a transport error or a
+    /// retryable status (429 or 5xx) before dropping the batch. Backoff doubles
+    /// after every attempt, up to `max_backoff`.
+    pub retries: u32,
+    pub retry_backoff: Duration,
+    pub max_backoff: Duration,
+
+    pub timeout: Duration,
+}
+
+impl Default for LokiOptions {
+    fn default() -> Self {
+        Self {
+            batch_size: 500,
+            batch_wait: Duration::from_secs(1),
+            tenant_id: None,
+            retries: 5,
+            retry_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A sink that batches entries and pushes them to Grafana Loki's HTTP API, as
+/// snappy-compressed protobuf (the same format Promtail uses).
+///
+/// Entries are grouped into streams by the labels `host`, `level`, `subsystem`
+/// (when the entry has one, see `Entry::subsystem`), `source` (the backend that read
+/// the entry, when known, see `provenance`) and `boot_id`, plus one label for each of
+/// the entry's tags (see `annotate`) other than ones named like those.
+pub struct LokiSink {
+    url: String,
+    options: LokiOptions,
+    hostname: String,
+    boot_id: Option<String>,
+    clock: WallClock,
+    agent: ureq::Agent,
+    pending: Vec<(String, SystemTime, String)>,
+    oldest_pending: Option<Instant>,
+}
+
+impl LokiSink {
+    /// Create a new LokiSink pushing to the Loki at `base_url` (e.g. http://loki:3100).
+    pub fn with_options(base_url: &str, options: LokiOptions) -> Result<Self, RMesgError> {
+        if !base_url.starts_with("http://") && !base_url.starts_with("https://") {
+            return Err(RMesgError::SinkError(format!(
+                "Loki URL must be http:// or https://, got: {}",
+                base_url
+            )));
+        }
+
+        let hostname = match fs::read_to_string(PROC_SYS_KERNEL_HOSTNAME) {
+            Ok(h) => h.trim().to_owned(),
+            Err(_) => "localhost".to_owned(),
+        };
+
+        let agent = ureq::AgentBuilder::new().timeout(options.timeout).build();
+
+        Ok(Self {
+            url: format!("{}{}", base_url.trim_end_matches('/'), PUSH_PATH),
+            options,
+            hostname,
+            boot_id: bookmark::boot_id(),
+            clock: WallClock::now()?,
+            agent,
+            pending: Vec::new(),
+            oldest_pending: None,
+        })
+    }
+
+    fn labels(&self, entry: &Entry) -> String {
+        let mut labels = vec![("host", self.hostname.as_str())];
+        let level = entry.level.map(|l| l.to_string());
+        labels.push(("level", level.as_deref().unwrap_or("unknown")));
+        if let Some(subsystem) = entry.subsystem() {
+            labels.push(("subsystem", subsystem));
+        }
+        let source = entry.provenance.as_ref().map(|p| p.backend.to_string());
+        if let Some(source) = &source {
+            labels.push(("source", source));
+        }
+        if let Some(boot_id) = &self.boot_id {
+            labels.push(("boot_id", boot_id));
+        }
+        for (key, value) in entry.tags.iter() {
+            if !BUILTIN_LABELS.contains(&key.as_str()) {
+                labels.push((key, value));
+            }
+        }
+        format_labels(&labels)
+    }
+
+    fn push_pending(&mut self) -> Result<(), RMesgError> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let pending = std::mem::take(&mut self.pending);
+        self.oldest_pending = None;
+
+        let body = match snap::raw::Encoder::new().compress_vec(&push_request(&pending)) {
+            Ok(body) => body,
+            Err(e) => {
+                return Err(RMesgError::SinkError(format!(
+                    "Unable to compress Loki push request: {}",
+                    e
+                )))
+            }
+        };
+        self.post(&body)
+    }
+
+    fn post(&self, body: &[u8]) -> Result<(), RMesgError> {
+        let mut backoff = self.options.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            let mut request = self
+                .agent
+                .post(&self.url)
+                .set("Content-Type", "application/x-protobuf");
+            if let Some(tenant_id) = &self.options.tenant_id {
+                request = request.set("X-Scope-OrgID", tenant_id);
+            }
+
+            let err = match request.send_bytes(body) {
+                Ok(_) => return Ok(()),
+                Err(ureq::Error::Status(code, _)) if code != 429 && code < 500 => {
+                    return Err(RMesgError::SinkError(format!(
+                        "Loki {} rejected push with status {}",
+                        self.url, code
+                    )))
+                }
+                Err(e) => e,
+            };
+
+            if attempt >= self.options.retries {
+                return Err(RMesgError::SinkError(format!(
+                    "Loki {} push failed after {} attempts: {}",
+                    self.url,
+                    attempt + 1,
+                    err
+                )));
+            }
+
+            thread::sleep(backoff);
+            backoff = backoff.saturating_mul(2).min(self.options.max_backoff);
+            attempt += 1;
+        }
+    }
+}
+
+impl Sink for LokiSink {
+    fn write(&mut self, entry: &Entry) -> Result<(), RMesgError> {
+        self.clock.observe(entry);
+        let time = match entry.timestamp_from_system_start {
+            Some(ts) => self.clock.to_system_time(ts),
+            None => SystemTime::now(),
+        };
+
+        self.pending
+            .push((self.labels(entry), time, entry.message.clone()));
+        let oldest = *self.oldest_pending.get_or_insert_with(Instant::now);
+
+        if self.pending.len() >= self.options.batch_size
+            || oldest.elapsed() >= self.options.batch_wait
+        {
+            self.push_pending()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), RMesgError> {
+        self.push_pending()
+    }
+}
+
+// Labels set by the sink, which tags can't override
+const BUILTIN_LABELS: [&str; 5] = ["host", "level", "subsystem", "source", "boot_id"];
+
+// Loki's label set syntax: {name="value", ...}
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            format!(
+                "{}=\"{}\"",
+                name,
+                value.replace('\\', "\\\\").replace('"', "\\\"")
+            )
+        })
+        .collect();
+    format!("{{{}}}", pairs.join(", "))
+}
+
+// Encodes a logproto.PushRequest:
+//   PushRequest  { repeated Stream streams = 1; }
+//   Stream       { string labels = 1; repeated EntryAdapter entries = 2; }
+//   EntryAdapter { google.protobuf.Timestamp timestamp = 1; string line = 2; }
+//   Timestamp    { int64 seconds = 1; int32 nanos = 2; }
+// with one stream per label set, keeping entries in the order they were written.
+fn push_request(pending: &[(String, SystemTime, String)]) -> Vec<u8> {
+    let mut streams: BTreeMap<&str, Vec<(SystemTime, &str)>> = BTreeMap::new();
+    for (labels, time, line) in pending.iter() {
+        streams
+            .entry(labels.as_str())
+            .or_default()
+            .push((*time, line.as_str()));
+    }
+
+    let mut request = Vec::new();
+    for (labels, entries) in streams {
+        let mut stream = Vec::new();
+        write_bytes_field(&mut stream, 1, labels.as_bytes());
+        for (time, line) in entries {
+            let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let mut timestamp = Vec::new();
+            write_varint_field(&mut timestamp, 1, since_epoch.as_secs());
+            write_varint_field(&mut timestamp, 2, since_epoch.subsec_nanos() as u64);
+
+            let mut entry = Vec::new();
+            write_bytes_field(&mut entry, 1, &timestamp);
+            write_bytes_field(&mut entry, 2, line.as_bytes());
+            write_bytes_field(&mut stream, 2, &entry);
+        }
+        write_bytes_field(&mut request, 1, &stream);
+    }
+    request
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn write_varint_field(buf: &mut Vec<u8>, field: u64, n: u64) {
+    // zero is the default, so proto3 leaves it out
+    if n != 0 {
+        write_varint(buf, field << 3);
+        write_varint(buf, n);
+    }
+}
+
+fn write_bytes_field(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    write_varint(buf, (field << 3) | 2);
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entry::{LogFacility, LogLevel};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    fn test_entry(level: LogLevel, message: &str) -> Entry {
+        Entry {
+            facility: Some(LogFacility::Kern),
+            level: Some(level),
+            sequence_num: Some(42),
+            timestamp_from_system_start: Some(Duration::from_secs(3)),
+            message: message.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    // Serves one response per status given, reporting each request (head, body) received.
+    fn serve(statuses: Vec<u16>) -> (String, mpsc::Receiver<(String, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                loop {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let header_end = match request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        Some(header_end) => header_end,
+                        None => continue,
+                    };
+                    let head = String::from_utf8_lossy(&request[..header_end]).to_string();
+                    let content_length = head
+                        .lines()
+                        .find_map(|l| l.strip_prefix("Content-Length: "))
+                        .map(|l| l.trim().parse::<usize>().unwrap())
+                        .unwrap_or(0);
+                    if request.len() >= header_end + 4 + content_length {
+                        tx.send((head, request[header_end + 4..].to_vec())).unwrap();
+                        break;
+                    }
+                }
+                write!(
+                    stream,
+                    "HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    #[test]
+    fn test_encoding() {
+        let mut buf = Vec::new();
+        write_varint(&mut buf, 300);
+        assert_eq!(buf, vec![0xac, 0x02]);
+
+        assert_eq!(
+            format_labels(&[("host", "a\"b"), ("level", "err")]),
+            r#"{host="a\"b", level="err"}"#
+        );
+
+        let time = UNIX_EPOCH + Duration::new(1, 5);
+        let request = push_request(&[("{a=\"1\"}".to_owned(), time, "hi".to_owned())]);
+        assert_eq!(
+            request,
+            vec![
+                0x0a, 21, // stream
+                0x0a, 7, b'{', b'a', b'=', b'"', b'1', b'"', b'}', // labels
+                0x12, 10, // entry
+                0x0a, 4, 0x08, 1, 0x10, 5, // timestamp
+                0x12, 2, b'h', b'i', // line
+            ]
+        );
+    }
+
+    #[test]
+    fn test_push() {
+        let (url, rx) = serve(vec![503, 204]);
+        let mut sink = LokiSink::with_options(
+            &url,
+            LokiOptions {
+                batch_size: 3,
+                batch_wait: Duration::from_secs(60),
+                tenant_id: Some("fleet".to_owned()),
+                retry_backoff: Duration::from_millis(1),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        sink.write(&test_entry(
+            LogLevel::Error,
+            "nvme nvme0: I/O 12 QID 3 timeout",
+        ))
+        .unwrap();
+        let mut usb = test_entry(LogLevel::Info, "usb 1-1: new high-speed USB device");
+        usb.set_tag("team", "platform");
+        usb.set_tag("host", "spoofed");
+        sink.write(&usb).unwrap();
+        sink.write(&test_entry(
+            LogLevel::Error,
+            "nvme nvme0: Abort status: 0x0",
+        ))
+        .unwrap();
+
+        // retried after the 503
+        rx.recv().unwrap();
+        let (head, body) = rx.recv().unwrap();
+        assert!(head.starts_with("POST /loki/api/v1/push "));
+        assert!(head.contains("X-Scope-OrgID: fleet"));
+        assert!(head.contains("Content-Type: application/x-protobuf"));
+
+        let request = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+        let text = String::from_utf8_lossy(&request);
+        // two streams, the nvme one holding both of its entries
+        assert_eq!(text.matches("host=").count(), 2);
+        assert!(text.contains(r#"level="err", subsystem="nvme""#));
+        assert!(text.contains(r#"level="info", subsystem="usb""#));
+        assert!(text.contains(r#"team="platform""#));
+        assert!(!text.contains("spoofed"));
+        let nvme = text.find("subsystem=\"nvme\"").unwrap();
+        assert!(text[nvme..].find("I/O 12").unwrap() < text[nvme..].find("Abort").unwrap());
+    }
+
+    #[test]
+    fn test_rejected() {
+        let (url, _rx) = serve(vec![400]);
+        let mut sink = LokiSink::with_options(&url, LokiOptions::default()).unwrap();
+        sink.write(&test_entry(LogLevel::Error, "oops")).unwrap();
+        assert!(matches!(sink.flush(), Err(RMesgError::SinkError(_))));
+        // the batch isn't retried forever
+        assert!(sink.flush().is_ok());
+
+        assert!(LokiSink::with_options("loki:3100", LokiOptions::default()).is_err());
+    }
+}
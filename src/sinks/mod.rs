@@ -0,0 +1,255 @@
+/// Sinks are destinations that kernel log entries are delivered to once read
+/// (alerting webhooks, databases, log aggregators, etc.)
+///
+/// Each sink implementation lives behind its own feature so consumers only pay
+/// for the dependencies of the sinks they actually use.
+use crate::bookmark::{Bookmark, BookmarkTracker, Replaying};
+use crate::entry::Entry;
+use crate::error::RMesgError;
+
+/// Archive file sink (appends entries to a local file, committed with a checkpoint)
+#[cfg(feature = "file")]
+pub mod file;
+/// Fluent forward protocol sink (sends entries to Fluentd, Fluent Bit or Vector)
+#[cfg(feature = "fluent")]
+pub mod fluent;
+/// Loki sink (pushes batches of entries to Grafana Loki)
+#[cfg(feature = "loki")]
+pub mod loki;
+/// Parquet exporter (writes entries as Apache Parquet files for analytics pipelines)
+#[cfg(feature = "parquet")]
+pub mod parquet;
+/// SQLite sink (writes entries into a local database for querying later)
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+/// Webhook sink (POSTs entries as JSON to an HTTP endpoint)
+#[cfg(feature = "webhook")]
+pub mod webhook;
+
+/// A destination for kernel log entries.
+pub trait Sink {
+    /// Deliver a single entry to the sink.
+    fn write(&mut self, entry: &Entry) -> Result<(), RMesgError>;
+
+    /// Flush anything the sink may have buffered. Sinks which deliver
+    /// every entry immediately need not override this.
+    fn flush(&mut self) -> Result<(), RMesgError> {
+        Ok(())
+    }
+}
+
+/// A sink that stores a batch of entries and the cursor just past them in one atomic
+/// step, so that after a crash either both are there or neither is. Wrapped in
+/// `Checkpointing`, it stores every entry exactly once however often the reader
+/// restarts (from `checkpoint`).
+pub trait Checkpointed {
+    /// Stores `entries` and moves the checkpoint to `cursor`, all or nothing.
+    fn commit(&mut self, entries: &[Entry], cursor: &Bookmark) -> Result<(), RMesgError>;
+
+    /// The cursor of the last commit, or `None` if there hasn't been one.
+    fn checkpoint(&self) -> Result<Option<Bookmark>, RMesgError>;
+}
+
+/// Makes a `Checkpointed` sink a `Sink`: entries are batched, and committed with their
+/// cursor every `batch_size` entries and on `flush`. Entries at or before the sink's
+/// checkpoint (a restarted reader replaying what was committed already) are skipped,
+/// so none is stored twice; a crash only loses the batch being built, which a reader
+/// resuming from the checkpoint replays. Without sequence numbers, only as many entries
+/// at the checkpoint's timestamp as were committed are skipped (see `Bookmark::replaying`).
+/// Entries with neither a sequence number nor a timestamp can't be placed against the
+/// checkpoint, and are never skipped.
+pub struct Checkpointing<C: Checkpointed> {
+    sink: C,
+    batch: Vec<Entry>,
+    batch_size: usize,
+    tracker: BookmarkTracker,
+    // from the checkpoint, while it's in this boot
+    resume: Option<Replaying>,
+}
+
+impl<C: Checkpointed> Checkpointing<C> {
+    pub fn new(sink: C, batch_size: usize) -> Result<Checkpointing<C>, RMesgError> {
+        let resume = sink.checkpoint()?.filter(|c| c.in_this_boot());
+        // carrying on from the checkpoint, so that the next one counts the entries at its
+        // timestamp committed before it too
+        let tracker = match &resume {
+            Some(checkpoint) => BookmarkTracker::resuming(checkpoint),
+            None => BookmarkTracker::new(),
+        };
+        Ok(Checkpointing {
+            sink,
+            batch: Vec::with_capacity(batch_size),
+            batch_size: batch_size.max(1),
+            tracker,
+            resume: resume.map(|checkpoint| checkpoint.replaying()),
+        })
+    }
+
+    /// Where a restarted reader should resume from (see `bookmark::replay_from`).
+    pub fn checkpoint(&self) -> Result<Option<Bookmark>, RMesgError> {
+        self.sink.checkpoint()
+    }
+
+    pub fn get_ref(&self) -> &C {
+        &self.sink
+    }
+
+    /// The sink, once the batch being built (if any) is committed.
+    pub fn into_inner(mut self) -> Result<C, RMesgError> {
+        self.commit()?;
+        Ok(self.sink)
+    }
+
+    fn commit(&mut self) -> Result<(), RMesgError> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let cursor = self.tracker.bookmark();
+        let committed = self.sink.commit(&self.batch, &cursor);
+        // a commit can fail after it's been made (syncing it, say), and then the batch
+        // mustn't be retried
+        if committed.is_ok() || self.sink.checkpoint().ok().flatten() == Some(cursor) {
+            self.batch.clear();
+        }
+        committed
+    }
+}
+
+impl<C: Checkpointed> Sink for Checkpointing<C> {
+    fn write(&mut self, entry: &Entry) -> Result<(), RMesgError> {
+        let positioned =
+            entry.sequence_num.is_some() || entry.timestamp_from_system_start.is_some();
+        if positioned && self.resume.as_mut().is_some_and(|r| !r.is_after(entry)) {
+            return Ok(());
+        }
+
+        self.tracker.track(entry);
+        self.batch.push(entry.clone());
+        match self.batch.len() >= self.batch_size {
+            true => self.commit(),
+            false => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), RMesgError> {
+        self.commit()
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Commits in memory, failing the commit after `fail_after` of them
+    #[derive(Default)]
+    struct Memory {
+        stored: Vec<Entry>,
+        checkpoint: Option<Bookmark>,
+        fail_after: Option<usize>,
+        commits: usize,
+    }
+
+    impl Checkpointed for Memory {
+        fn commit(&mut self, entries: &[Entry], cursor: &Bookmark) -> Result<(), RMesgError> {
+            if self.fail_after == Some(self.commits) {
+                return Err(RMesgError::SinkError("disk full".to_owned()));
+            }
+            self.commits += 1;
+            self.stored.extend_from_slice(entries);
+            self.checkpoint = Some(cursor.clone());
+            Ok(())
+        }
+
+        fn checkpoint(&self) -> Result<Option<Bookmark>, RMesgError> {
+            Ok(self.checkpoint.clone())
+        }
+    }
+
+    fn entry(n: usize) -> Entry {
+        Entry {
+            sequence_num: Some(n),
+            message: format!("message {}", n),
+            ..Default::default()
+        }
+    }
+
+    fn seqs(entries: &[Entry]) -> Vec<usize> {
+        entries.iter().map(|e| e.sequence_num.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_checkpointing_restart() {
+        let mut sink = Checkpointing::new(Memory::default(), 3).unwrap();
+        for n in 1..=7 {
+            sink.write(&entry(n)).unwrap();
+        }
+        // two batches of three committed, 7 still being built when the process dies
+        let memory = sink.sink;
+        assert_eq!(seqs(&memory.stored), vec![1, 2, 3, 4, 5, 6]);
+
+        // the restarted reader replays from the start of the buffer
+        let mut sink = Checkpointing::new(memory, 3).unwrap();
+        for n in 1..=9 {
+            sink.write(&entry(n)).unwrap();
+        }
+        let memory = sink.into_inner().unwrap();
+        assert_eq!(seqs(&memory.stored), (1..=9).collect::<Vec<_>>());
+        assert!(!memory.checkpoint.unwrap().is_after(&entry(9)));
+    }
+
+    #[test]
+    fn test_checkpointing_restart_at_timestamp() {
+        // as klogctl reads them: no sequence numbers, three logged in the same microsecond
+        let entry = |us: u64, message: &str| Entry {
+            timestamp_from_system_start: Some(std::time::Duration::from_micros(us)),
+            message: message.to_owned(),
+            ..Default::default()
+        };
+        let buffer = vec![
+            entry(1, "a"),
+            entry(2, "b1"),
+            entry(2, "b2"),
+            entry(2, "b3"),
+            entry(3, "c"),
+        ];
+
+        // a and b1 committed, b2 still being built when the process dies
+        let mut sink = Checkpointing::new(Memory::default(), 2).unwrap();
+        for e in &buffer[..3] {
+            sink.write(e).unwrap();
+        }
+        let mut memory = sink.sink;
+
+        // the restarted reader replays the buffer, twice over
+        for _ in 0..2 {
+            let mut sink = Checkpointing::new(memory, 2).unwrap();
+            for e in &buffer {
+                sink.write(e).unwrap();
+            }
+            memory = sink.into_inner().unwrap();
+        }
+        let messages: Vec<&str> = memory.stored.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(messages, vec!["a", "b1", "b2", "b3", "c"]);
+    }
+
+    #[test]
+    fn test_checkpointing_failed_commit() {
+        let memory = Memory {
+            fail_after: Some(1),
+            ..Default::default()
+        };
+        let mut sink = Checkpointing::new(memory, 2).unwrap();
+        sink.write(&entry(1)).unwrap();
+        sink.write(&entry(2)).unwrap();
+        sink.write(&entry(3)).unwrap();
+        assert!(sink.write(&entry(4)).is_err());
+
+        // kept for the next try
+        sink.sink.fail_after = None;
+        sink.flush().unwrap();
+        assert_eq!(seqs(&sink.sink.stored), vec![1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,341 @@
+use crate::entry::Entry;
+use crate::error::RMesgError;
+use crate::sinks::Sink;
+
+use parquet::basic::Compression;
+use parquet::data_type::{ByteArray, ByteArrayType, Int32Type, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use std::io::Write;
+use std::sync::Arc;
+
+const SCHEMA: &str = "
+    message entry {
+        OPTIONAL INT64 ts;
+        OPTIONAL INT32 level;
+        OPTIONAL INT32 facility;
+        OPTIONAL INT64 seq;
+        OPTIONAL BYTE_ARRAY subsystem (UTF8);
+        REQUIRED BYTE_ARRAY message (UTF8);
+        OPTIONAL group tags (MAP) {
+            REPEATED group key_value {
+                REQUIRED BYTE_ARRAY key (UTF8);
+                REQUIRED BYTE_ARRAY value (UTF8);
+            }
+        }
+    }
+";
+
+#[derive(Clone, Debug)]
+pub struct ParquetOptions {
+    /// Entries are buffered and written out as a row group once this many have been
+    /// written (or on flush). Larger row groups compress and scan better.
+    pub row_group_size: usize,
+}
+
+impl Default for ParquetOptions {
+    fn default() -> Self {
+        Self {
+            row_group_size: 10_000,
+        }
+    }
+}
+
+/// An exporter that writes entries as Apache Parquet, one row per entry with the columns
+/// `ts` (microseconds since system start), `level`, `facility`, `seq`, `subsystem`
+/// (see `Entry::subsystem`), `message` and `tags` (a map, see `annotate`). Levels and
+/// facilities are their numeric values.
+///
+/// Parquet files are only readable once their footer is written, so streams must end
+/// with `finish`. Each `flush` writes out a (possibly small) row group.
+pub struct ParquetSink<W: Write + Send> {
+    writer: SerializedFileWriter<W>,
+    options: ParquetOptions,
+    pending: Vec<Entry>,
+}
+
+impl<W: Write + Send> ParquetSink<W> {
+    pub fn with_options(writer: W, options: ParquetOptions) -> Result<Self, RMesgError> {
+        let schema = Arc::new(parse_message_type(SCHEMA).map_err(parquet_error)?);
+        let properties = Arc::new(
+            WriterProperties::builder()
+                .set_compression(Compression::SNAPPY)
+                .build(),
+        );
+        Ok(Self {
+            writer: SerializedFileWriter::new(writer, schema, properties).map_err(parquet_error)?,
+            pending: Vec::with_capacity(options.row_group_size),
+            options,
+        })
+    }
+
+    /// Writes what's still buffered and the file footer, returning the underlying writer.
+    pub fn finish(mut self) -> Result<W, RMesgError> {
+        self.write_row_group()?;
+        self.writer.into_inner().map_err(parquet_error)
+    }
+
+    fn write_row_group(&mut self) -> Result<(), RMesgError> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let entries = &self.pending;
+
+        let mut row_group = self.writer.next_row_group().map_err(parquet_error)?;
+        let mut index = 0;
+        while let Some(mut column) = row_group.next_column().map_err(parquet_error)? {
+            let written = match index {
+                0 => {
+                    let (values, levels) = optional(entries, |e| {
+                        e.timestamp_from_system_start
+                            .map(|ts| ts.as_micros() as i64)
+                    });
+                    column
+                        .typed::<Int64Type>()
+                        .write_batch(&values, Some(&levels), None)
+                }
+                1 => {
+                    let (values, levels) = optional(entries, |e| e.level.map(|l| l as i32));
+                    column
+                        .typed::<Int32Type>()
+                        .write_batch(&values, Some(&levels), None)
+                }
+                2 => {
+                    let (values, levels) = optional(entries, |e| e.facility.map(|f| f as i32));
+                    column
+                        .typed::<Int32Type>()
+                        .write_batch(&values, Some(&levels), None)
+                }
+                3 => {
+                    let (values, levels) = optional(entries, |e| e.sequence_num.map(|s| s as i64));
+                    column
+                        .typed::<Int64Type>()
+                        .write_batch(&values, Some(&levels), None)
+                }
+                4 => {
+                    let (values, levels) =
+                        optional(entries, |e| e.subsystem().map(ByteArray::from));
+                    column
+                        .typed::<ByteArrayType>()
+                        .write_batch(&values, Some(&levels), None)
+                }
+                5 => {
+                    let values: Vec<ByteArray> = entries
+                        .iter()
+                        .map(|e| ByteArray::from(e.message.as_str()))
+                        .collect();
+                    column
+                        .typed::<ByteArrayType>()
+                        .write_batch(&values, None, None)
+                }
+                // tags.key_value.key, then tags.key_value.value
+                _ => {
+                    let (values, def_levels, rep_levels) = map_column(entries, index == 6);
+                    column.typed::<ByteArrayType>().write_batch(
+                        &values,
+                        Some(&def_levels),
+                        Some(&rep_levels),
+                    )
+                }
+            };
+            written.map_err(parquet_error)?;
+            column.close().map_err(parquet_error)?;
+            index += 1;
+        }
+        row_group.close().map_err(parquet_error)?;
+
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> Sink for ParquetSink<W> {
+    fn write(&mut self, entry: &Entry) -> Result<(), RMesgError> {
+        self.pending.push(entry.clone());
+        if self.pending.len() >= self.options.row_group_size {
+            self.write_row_group()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), RMesgError> {
+        self.write_row_group()
+    }
+}
+
+/// Writes a snapshot of entries (e.g. from `log_entries`) as a complete Parquet file.
+pub fn export_parquet<W: Write + Send>(entries: &[Entry], writer: W) -> Result<W, RMesgError> {
+    let mut sink = ParquetSink::with_options(
+        writer,
+        ParquetOptions {
+            row_group_size: entries.len().max(1),
+        },
+    )?;
+    for entry in entries.iter() {
+        sink.write(entry)?;
+    }
+    sink.finish()
+}
+
+// Splits an optional column into its present values and definition levels
+// (1 where the value is present, 0 where it's null).
+fn optional<T, F: Fn(&Entry) -> Option<T>>(entries: &[Entry], field: F) -> (Vec<T>, Vec<i16>) {
+    let mut values = Vec::with_capacity(entries.len());
+    let mut levels = Vec::with_capacity(entries.len());
+    for entry in entries.iter() {
+        match field(entry) {
+            Some(value) => {
+                values.push(value);
+                levels.push(1);
+            }
+            None => levels.push(0),
+        }
+    }
+    (values, levels)
+}
+
+// Splits the tags into the keys (or the values) of a map column, with their definition
+// levels (1 for an empty map, 2 for an entry in it) and repetition levels (0 for an
+// entry's first tag, 1 for the rest).
+fn map_column(entries: &[Entry], keys: bool) -> (Vec<ByteArray>, Vec<i16>, Vec<i16>) {
+    let mut values = Vec::new();
+    let mut def_levels = Vec::with_capacity(entries.len());
+    let mut rep_levels = Vec::with_capacity(entries.len());
+    for entry in entries.iter() {
+        if entry.tags.is_empty() {
+            def_levels.push(1);
+            rep_levels.push(0);
+        }
+        for (i, (key, value)) in entry.tags.iter().enumerate() {
+            values.push(ByteArray::from(if keys { key } else { value }.as_str()));
+            def_levels.push(2);
+            rep_levels.push(if i == 0 { 0 } else { 1 });
+        }
+    }
+    (values, def_levels, rep_levels)
+}
+
+fn parquet_error(e: parquet::errors::ParquetError) -> RMesgError {
+    RMesgError::SinkError(format!("Parquet error: {}", e))
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entry::{LogFacility, LogLevel};
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::{Field, Row};
+    use std::time::Duration;
+
+    fn read_rows(path: &std::path::Path) -> (usize, Vec<Row>) {
+        let reader = SerializedFileReader::new(std::fs::File::open(path).unwrap()).unwrap();
+        let row_groups = reader.metadata().num_row_groups();
+        let rows = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        (row_groups, rows)
+    }
+
+    #[test]
+    fn test_export() {
+        let mut entries = vec![
+            Entry {
+                facility: Some(LogFacility::Kern),
+                level: Some(LogLevel::Error),
+                sequence_num: Some(7),
+                timestamp_from_system_start: Some(Duration::from_micros(1_500_000)),
+                message: "nvme nvme0: I/O 12 QID 3 timeout, aborting".to_owned(),
+                ..Default::default()
+            },
+            Entry {
+                message: "Linux version 5.10.0".to_owned(),
+                ..Default::default()
+            },
+        ];
+        entries[0].set_tag("team", "storage");
+        entries[0].set_tag("rule", "disk-errors");
+
+        let path =
+            std::env::temp_dir().join(format!("rmesg-export-{}.parquet", std::process::id()));
+        export_parquet(&entries, std::fs::File::create(&path).unwrap()).unwrap();
+        let (row_groups, rows) = read_rows(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(row_groups, 1);
+        let mut columns: Vec<(String, Field)> = rows[0]
+            .get_column_iter()
+            .map(|(name, field)| (name.clone(), field.clone()))
+            .collect();
+        let tags = match columns.pop() {
+            Some((name, Field::MapInternal(map))) if name == "tags" => map.entries().to_vec(),
+            other => panic!("Expected the tags map, got: {:?}", other),
+        };
+        assert_eq!(
+            tags,
+            vec![
+                (
+                    Field::Str("rule".to_owned()),
+                    Field::Str("disk-errors".to_owned())
+                ),
+                (
+                    Field::Str("team".to_owned()),
+                    Field::Str("storage".to_owned())
+                ),
+            ]
+        );
+        assert_eq!(
+            columns,
+            vec![
+                ("ts".to_owned(), Field::Long(1_500_000)),
+                ("level".to_owned(), Field::Int(3)),
+                ("facility".to_owned(), Field::Int(0)),
+                ("seq".to_owned(), Field::Long(7)),
+                ("subsystem".to_owned(), Field::Str("nvme".to_owned())),
+                (
+                    "message".to_owned(),
+                    Field::Str("nvme nvme0: I/O 12 QID 3 timeout, aborting".to_owned())
+                ),
+            ]
+        );
+        assert!(rows[1]
+            .get_column_iter()
+            .take(5)
+            .all(|(_, field)| *field == Field::Null));
+        assert!(matches!(
+            rows[1].get_column_iter().last(),
+            Some((_, Field::MapInternal(map))) if map.entries().is_empty()
+        ));
+    }
+
+    #[test]
+    fn test_streamed_row_groups() {
+        let path =
+            std::env::temp_dir().join(format!("rmesg-stream-{}.parquet", std::process::id()));
+        let mut sink = ParquetSink::with_options(
+            std::fs::File::create(&path).unwrap(),
+            ParquetOptions { row_group_size: 2 },
+        )
+        .unwrap();
+        for seq in 0..5 {
+            sink.write(&Entry {
+                level: Some(LogLevel::Info),
+                sequence_num: Some(seq),
+                message: format!("message {}", seq),
+                ..Default::default()
+            })
+            .unwrap();
+        }
+        sink.finish().unwrap();
+
+        let (row_groups, rows) = read_rows(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(row_groups, 3);
+        assert_eq!(rows.len(), 5);
+    }
+}
@@ -0,0 +1,561 @@
+use crate::bookmark::Bookmark;
+use crate::entry::{Entry, LogFacility, LogLevel};
+use crate::error::RMesgError;
+use crate::retention::{History, HistoryQuery};
+use crate::sinks::{Checkpointed, Sink};
+
+use num::FromPrimitive;
+use rusqlite::{params, Connection, OptionalExtension, ToSql};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS entries (
+        id INTEGER PRIMARY KEY,
+        written_at_ms INTEGER NOT NULL,
+        timestamp_us INTEGER,
+        sequence_num INTEGER,
+        facility INTEGER,
+        level INTEGER,
+        subsystem TEXT,
+        message TEXT NOT NULL,
+        priority INTEGER,
+        timestamp_realtime_us INTEGER,
+        malformed INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX IF NOT EXISTS entries_written_at ON entries (written_at_ms);
+    CREATE INDEX IF NOT EXISTS entries_timestamp ON entries (timestamp_us);
+    CREATE INDEX IF NOT EXISTS entries_level ON entries (level);
+    CREATE INDEX IF NOT EXISTS entries_subsystem ON entries (subsystem);
+    CREATE TABLE IF NOT EXISTS entry_tags (
+        entry_id INTEGER NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (entry_id, key)
+    );
+    CREATE INDEX IF NOT EXISTS entry_tags_key ON entry_tags (key, value);
+    CREATE TABLE IF NOT EXISTS checkpoint (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        cursor TEXT NOT NULL
+    );
+";
+
+// Columns of `entries` added since it was first made, which older databases lack
+const ADDED_COLUMNS: &[(&str, &str)] = &[
+    ("priority", "INTEGER"),
+    ("timestamp_realtime_us", "INTEGER"),
+    ("malformed", "INTEGER NOT NULL DEFAULT 0"),
+];
+
+#[derive(Clone, Debug)]
+pub struct SqliteOptions {
+    /// Rows written longer ago than this are pruned. When `None`, rows never age out.
+    pub max_age: Option<Duration>,
+
+    /// Only the newest `max_rows` rows are kept. When `None`, there is no limit.
+    pub max_rows: Option<usize>,
+
+    /// Prune after every `prune_interval` writes (pruning can also be done explicitly)
+    pub prune_interval: usize,
+}
+
+impl Default for SqliteOptions {
+    fn default() -> Self {
+        Self {
+            max_age: Some(Duration::from_secs(7 * 24 * 60 * 60)),
+            max_rows: Some(100_000),
+            prune_interval: 1000,
+        }
+    }
+}
+
+/// Selects entries from the database (the query any `History` store takes).
+pub type SqliteQuery = HistoryQuery;
+
+/// A sink that writes entries into a local SQLite database, indexed by time, level
+/// and subsystem, pruning old rows as it goes. Their tags (see `annotate`) go in the
+/// `entry_tags` table, keyed by the entry's id. Every field is kept but `provenance`:
+/// entries queried back have none, having been read from the database.
+///
+/// As a `Checkpointed` sink, each batch is inserted in the same transaction as the
+/// cursor just past it (in the `checkpoint` table).
+pub struct SqliteSink {
+    connection: Connection,
+    options: SqliteOptions,
+    since_prune: usize,
+}
+
+impl SqliteSink {
+    /// Opens (or creates) the database at `path`.
+    pub fn with_options<P: AsRef<Path>>(
+        path: P,
+        options: SqliteOptions,
+    ) -> Result<Self, RMesgError> {
+        let connection = Connection::open(path.as_ref()).map_err(|e| {
+            RMesgError::SinkError(format!(
+                "Unable to open SQLite database {}: {}",
+                path.as_ref().display(),
+                e
+            ))
+        })?;
+        SqliteSink::with_connection(connection, options)
+    }
+
+    /// A database only held in memory, e.g. for tests.
+    pub fn in_memory(options: SqliteOptions) -> Result<Self, RMesgError> {
+        let connection = Connection::open_in_memory().map_err(sql_error)?;
+        SqliteSink::with_connection(connection, options)
+    }
+
+    fn with_connection(connection: Connection, options: SqliteOptions) -> Result<Self, RMesgError> {
+        connection.execute_batch(SCHEMA).map_err(sql_error)?;
+        add_columns(&connection)?;
+        Ok(Self {
+            connection,
+            options,
+            since_prune: 0,
+        })
+    }
+
+    /// Removes rows past the retention limits, returning how many were removed.
+    pub fn prune(&mut self) -> Result<usize, RMesgError> {
+        self.prune_at(SystemTime::now())
+    }
+
+    fn prune_at(&mut self, now: SystemTime) -> Result<usize, RMesgError> {
+        self.since_prune = 0;
+        let mut pruned = 0;
+
+        if let Some(max_age) = self.options.max_age {
+            let cutoff = now.checked_sub(max_age).unwrap_or(UNIX_EPOCH);
+            pruned += self
+                .connection
+                .execute(
+                    "DELETE FROM entries WHERE written_at_ms < ?1",
+                    params![millis_since_epoch(cutoff)],
+                )
+                .map_err(sql_error)?;
+        }
+
+        if let Some(max_rows) = self.options.max_rows {
+            pruned += self
+                .connection
+                .execute(
+                    "DELETE FROM entries WHERE id NOT IN (SELECT id FROM entries ORDER BY id DESC LIMIT ?1)",
+                    params![max_rows as i64],
+                )
+                .map_err(sql_error)?;
+        }
+
+        if pruned > 0 {
+            self.connection
+                .execute(
+                    "DELETE FROM entry_tags WHERE entry_id NOT IN (SELECT id FROM entries)",
+                    [],
+                )
+                .map_err(sql_error)?;
+        }
+
+        Ok(pruned)
+    }
+
+    /// Entries matching `query`, oldest first.
+    pub fn query(&self, query: &SqliteQuery) -> Result<Vec<Entry>, RMesgError> {
+        let mut conditions: Vec<&str> = Vec::new();
+        let mut values: Vec<Box<dyn ToSql>> = Vec::new();
+        if let Some(since) = query.since {
+            conditions.push("timestamp_us >= ?");
+            values.push(Box::new(since.as_micros() as i64));
+        }
+        if let Some(until) = query.until {
+            conditions.push("timestamp_us <= ?");
+            values.push(Box::new(until.as_micros() as i64));
+        }
+        if let Some(level) = query.min_level {
+            conditions.push("level <= ?");
+            values.push(Box::new(level as i64));
+        }
+        if let Some(subsystem) = &query.subsystem {
+            conditions.push("subsystem = ?");
+            values.push(Box::new(subsystem.clone()));
+        }
+        if let Some(text) = &query.text {
+            // SQLite's lower() only folds ASCII, as `HistoryQuery::matches` does
+            conditions.push("instr(lower(message), lower(?)) > 0");
+            values.push(Box::new(text.clone()));
+        }
+
+        let mut sql =
+            "SELECT facility, level, sequence_num, timestamp_us, message, id, priority, timestamp_realtime_us, malformed FROM entries"
+                .to_owned();
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+        sql.push_str(" ORDER BY id DESC");
+        if let Some(limit) = query.limit {
+            sql.push_str(&format!(" LIMIT {}", limit));
+        }
+
+        let mut statement = self.connection.prepare(&sql).map_err(sql_error)?;
+        let mut tags = self
+            .connection
+            .prepare("SELECT key, value FROM entry_tags WHERE entry_id = ?1")
+            .map_err(sql_error)?;
+        let rows = statement
+            .query_map(
+                rusqlite::params_from_iter(values.iter().map(|v| v.as_ref())),
+                |row| {
+                    Ok(Entry {
+                        facility: row
+                            .get::<_, Option<u32>>(0)?
+                            .and_then(LogFacility::from_u32),
+                        level: row.get::<_, Option<u32>>(1)?.and_then(LogLevel::from_u32),
+                        sequence_num: row.get::<_, Option<i64>>(2)?.map(|s| s as usize),
+                        timestamp_from_system_start: row
+                            .get::<_, Option<i64>>(3)?
+                            .map(|us| Duration::from_micros(us as u64)),
+                        message: row.get(4)?,
+                        timestamp_realtime: row
+                            .get::<_, Option<i64>>(7)?
+                            .map(|us| UNIX_EPOCH + Duration::from_micros(us as u64)),
+                        provenance: None,
+                        malformed: row.get(8)?,
+                        priority: row.get(6)?,
+                        tags: tags
+                            .query_map(params![row.get::<_, i64>(5)?], |tag| {
+                                Ok((tag.get(0)?, tag.get(1)?))
+                            })?
+                            .collect::<Result<_, _>>()?,
+                    })
+                },
+            )
+            .map_err(sql_error)?;
+
+        let mut entries = rows.collect::<Result<Vec<Entry>, _>>().map_err(sql_error)?;
+        entries.reverse();
+        Ok(entries)
+    }
+
+    /// Number of rows in the database.
+    pub fn count(&self) -> Result<usize, RMesgError> {
+        self.connection
+            .query_row("SELECT COUNT(*) FROM entries", [], |row| {
+                row.get::<_, i64>(0)
+            })
+            .map(|c| c as usize)
+            .map_err(sql_error)
+    }
+
+    fn pruned_after(&mut self, writes: usize) -> Result<(), RMesgError> {
+        self.since_prune += writes;
+        if self.since_prune >= self.options.prune_interval {
+            self.prune()?;
+        }
+        Ok(())
+    }
+}
+
+impl History for SqliteSink {
+    fn history(&self, query: &HistoryQuery) -> Result<std::vec::IntoIter<Entry>, RMesgError> {
+        Ok(self.query(query)?.into_iter())
+    }
+}
+
+impl Sink for SqliteSink {
+    fn write(&mut self, entry: &Entry) -> Result<(), RMesgError> {
+        insert(&self.connection, entry, SystemTime::now())?;
+        self.pruned_after(1)
+    }
+}
+
+impl Checkpointed for SqliteSink {
+    fn commit(&mut self, entries: &[Entry], cursor: &Bookmark) -> Result<(), RMesgError> {
+        let written_at = SystemTime::now();
+        let transaction = self.connection.transaction().map_err(sql_error)?;
+        for entry in entries {
+            insert(&transaction, entry, written_at)?;
+        }
+        transaction
+            .execute(
+                "INSERT OR REPLACE INTO checkpoint (id, cursor) VALUES (0, ?1)",
+                params![cursor.to_token()],
+            )
+            .map_err(sql_error)?;
+        transaction.commit().map_err(sql_error)?;
+        self.pruned_after(entries.len())
+    }
+
+    fn checkpoint(&self) -> Result<Option<Bookmark>, RMesgError> {
+        let token: Option<String> = self
+            .connection
+            .query_row("SELECT cursor FROM checkpoint WHERE id = 0", [], |row| {
+                row.get(0)
+            })
+            .optional()
+            .map_err(sql_error)?;
+        token.map(|t| Bookmark::from_token(&t)).transpose()
+    }
+}
+
+// Inserts into `connection`, which can be a transaction
+fn insert(
+    connection: &Connection,
+    entry: &Entry,
+    written_at: SystemTime,
+) -> Result<(), RMesgError> {
+    connection
+            .execute(
+                "INSERT INTO entries (written_at_ms, timestamp_us, sequence_num, facility, level, subsystem, message, priority, timestamp_realtime_us, malformed)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    millis_since_epoch(written_at),
+                    entry
+                        .timestamp_from_system_start
+                        .map(|ts| ts.as_micros() as i64),
+                    entry.sequence_num.map(|s| s as i64),
+                    entry.facility.map(|f| f as i64),
+                    entry.level.map(|l| l as i64),
+                    entry.subsystem(),
+                    entry.message,
+                    entry.priority,
+                    entry
+                        .timestamp_realtime
+                        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                        .map(|d| d.as_micros() as i64),
+                    entry.malformed,
+                ],
+            )
+            .map_err(sql_error)?;
+
+    if !entry.tags.is_empty() {
+        let id = connection.last_insert_rowid();
+        let mut statement = connection
+            .prepare_cached("INSERT INTO entry_tags (entry_id, key, value) VALUES (?1, ?2, ?3)")
+            .map_err(sql_error)?;
+        for (key, value) in entry.tags.iter() {
+            statement
+                .execute(params![id, key, value])
+                .map_err(sql_error)?;
+        }
+    }
+    Ok(())
+}
+
+// Databases made before a column was added get it, empty (or its default) in the rows
+// they have
+fn add_columns(connection: &Connection) -> Result<(), RMesgError> {
+    let mut statement = connection
+        .prepare("SELECT name FROM pragma_table_info('entries')")
+        .map_err(sql_error)?;
+    let columns = statement
+        .query_map([], |row| row.get::<_, String>(0))
+        .map_err(sql_error)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(sql_error)?;
+    for (column, definition) in ADDED_COLUMNS {
+        if !columns.iter().any(|c| c == column) {
+            connection
+                .execute_batch(&format!(
+                    "ALTER TABLE entries ADD COLUMN {} {}",
+                    column, definition
+                ))
+                .map_err(sql_error)?;
+        }
+    }
+    Ok(())
+}
+
+fn millis_since_epoch(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+fn sql_error(e: rusqlite::Error) -> RMesgError {
+    RMesgError::SinkError(format!("SQLite error: {}", e))
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(secs: u64, level: LogLevel, message: &str) -> Entry {
+        Entry {
+            facility: Some(LogFacility::Kern),
+            level: Some(level),
+            sequence_num: Some(secs as usize),
+            timestamp_from_system_start: Some(Duration::from_secs(secs)),
+            message: message.to_owned(),
+            priority: Some(level as u32),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_write_and_query() {
+        let mut sink = SqliteSink::in_memory(SqliteOptions::default()).unwrap();
+        let mut entries = vec![
+            entry(1, LogLevel::Info, "usb 1-1: new high-speed USB device"),
+            entry(
+                2,
+                LogLevel::Error,
+                "nvme nvme0: I/O 12 QID 3 timeout, aborting",
+            ),
+            entry(
+                3,
+                LogLevel::Warning,
+                "usb 1-1: device descriptor read/64, error -71",
+            ),
+            entry(4, LogLevel::Info, "usb 1-1: reset high-speed USB device"),
+        ];
+        entries[1].set_tag("team", "storage");
+        entries[1].set_tag("rule", "disk-errors");
+        entries[1].timestamp_realtime =
+            Some(UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456));
+        entries[3].malformed = true;
+        entries[3].priority = Some(30);
+        for e in entries.iter() {
+            sink.write(e).unwrap();
+        }
+
+        assert_eq!(sink.query(&SqliteQuery::default()).unwrap(), entries);
+        assert_eq!(
+            sink.query(&SqliteQuery {
+                min_level: Some(LogLevel::Warning),
+                ..Default::default()
+            })
+            .unwrap(),
+            vec![entries[1].clone(), entries[2].clone()]
+        );
+        assert_eq!(
+            sink.query(&SqliteQuery {
+                subsystem: Some("usb".to_owned()),
+                since: Some(Duration::from_secs(2)),
+                limit: Some(1),
+                ..Default::default()
+            })
+            .unwrap(),
+            vec![entries[3].clone()]
+        );
+        assert_eq!(
+            sink.history(&HistoryQuery {
+                until: Some(Duration::from_secs(3)),
+                text: Some("ERROR".to_owned()),
+                ..Default::default()
+            })
+            .unwrap()
+            .collect::<Vec<_>>(),
+            vec![entries[2].clone()]
+        );
+    }
+
+    #[test]
+    fn test_older_schema() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE entries (
+                    id INTEGER PRIMARY KEY,
+                    written_at_ms INTEGER NOT NULL,
+                    timestamp_us INTEGER,
+                    sequence_num INTEGER,
+                    facility INTEGER,
+                    level INTEGER,
+                    subsystem TEXT,
+                    message TEXT NOT NULL
+                );
+                INSERT INTO entries (written_at_ms, timestamp_us, sequence_num, facility, level, message)
+                VALUES (0, 1000000, 1, 0, 6, 'usb 1-1: new high-speed USB device');",
+            )
+            .unwrap();
+
+        // what the older rows didn't keep is empty
+        let mut sink = SqliteSink::with_connection(connection, SqliteOptions::default()).unwrap();
+        let mut old = entry(1, LogLevel::Info, "usb 1-1: new high-speed USB device");
+        old.priority = None;
+        let new = entry(2, LogLevel::Info, "usb 1-1: reset high-speed USB device");
+        sink.write(&new).unwrap();
+        assert_eq!(sink.query(&SqliteQuery::default()).unwrap(), vec![old, new]);
+    }
+
+    #[test]
+    fn test_pruning() {
+        let mut sink = SqliteSink::in_memory(SqliteOptions {
+            max_age: Some(Duration::from_secs(60)),
+            max_rows: Some(3),
+            prune_interval: 2,
+        })
+        .unwrap();
+
+        for secs in 1..=5 {
+            sink.write(&entry(secs, LogLevel::Info, "message")).unwrap();
+        }
+        // pruned down to 3 after the 4th write, then one more
+        assert_eq!(sink.count().unwrap(), 4);
+        assert_eq!(sink.prune().unwrap(), 1);
+        assert_eq!(sink.count().unwrap(), 3);
+
+        let now = SystemTime::now();
+        let mut old = entry(6, LogLevel::Info, "old");
+        old.set_tag("team", "storage");
+        insert(&sink.connection, &old, now - Duration::from_secs(3600)).unwrap();
+        assert_eq!(sink.prune_at(now).unwrap(), 1);
+        // its tags went with it
+        let tags: i64 = sink
+            .connection
+            .query_row("SELECT COUNT(*) FROM entry_tags", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(tags, 0);
+        assert!(sink
+            .query(&SqliteQuery::default())
+            .unwrap()
+            .iter()
+            .all(|e| e.message == "message"));
+    }
+
+    #[test]
+    fn test_checkpointed_restart() {
+        use crate::sinks::Checkpointing;
+
+        let path =
+            std::env::temp_dir().join(format!("rmesg-sqlite-checkpoint-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let open = || SqliteSink::with_options(&path, SqliteOptions::default()).unwrap();
+        assert_eq!(open().checkpoint().unwrap(), None);
+
+        let mut sink = Checkpointing::new(open(), 2).unwrap();
+        for secs in 1..=3 {
+            sink.write(&entry(secs, LogLevel::Info, "usb 1-1: reset"))
+                .unwrap();
+        }
+        // 3 wasn't committed when the process died
+        drop(sink);
+        assert_eq!(open().count().unwrap(), 2);
+
+        let mut sink = Checkpointing::new(open(), 2).unwrap();
+        for secs in 1..=4 {
+            sink.write(&entry(secs, LogLevel::Info, "usb 1-1: reset"))
+                .unwrap();
+        }
+        let sink = sink.into_inner().unwrap();
+        let seqs: Vec<_> = sink
+            .query(&SqliteQuery::default())
+            .unwrap()
+            .iter()
+            .map(|e| e.sequence_num.unwrap())
+            .collect();
+        assert_eq!(seqs, vec![1, 2, 3, 4]);
+        assert!(!sink
+            .checkpoint()
+            .unwrap()
+            .unwrap()
+            .is_after(&entry(4, LogLevel::Info, "")));
+
+        drop(sink);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,390 @@
+use crate::entry::{Entry, LogLevel};
+use crate::error::RMesgError;
+use crate::sinks::Sink;
+
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Where the hostname (used to identify the reporting host in payloads) is read from
+const PROC_SYS_KERNEL_HOSTNAME: &str = "/proc/sys/kernel/hostname";
+
+/// The shape of the JSON document POSTed to the webhook.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PayloadTemplate {
+    /// The entry's fields as a flat JSON object
+    Generic,
+    /// Slack incoming webhook (`{"text": ...}`)
+    Slack,
+    /// Microsoft Teams incoming webhook (`{"text": ...}`)
+    Teams,
+    /// PagerDuty Events API v2 trigger, using the given integration routing key
+    PagerDuty { routing_key: String },
+}
+
+/// Allow at most `max` POSTs in any window of length `per`.
+/// Entries over the limit are dropped (and counted) rather than queued.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub max: usize,
+    pub per: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct WebhookOptions {
+    pub template: PayloadTemplate,
+
+    /// When `Some`, only entries at this level or more severe are sent.
+    /// Entries without a level never match when this is set.
+    pub min_level: Option<LogLevel>,
+
+    /// When `None`, every matching entry is POSTed.
+    pub rate_limit: Option<RateLimit>,
+
+    /// How many times to retry a POST that failed with a transport error or
+    /// a retryable status (429 or 5xx). Backoff doubles after every attempt.
+    pub retries: u32,
+    pub retry_backoff: Duration,
+
+    pub timeout: Duration,
+}
+
+impl Default for WebhookOptions {
+    fn default() -> Self {
+        Self {
+            template: PayloadTemplate::Generic,
+            min_level: None,
+            rate_limit: Some(RateLimit {
+                max: 30,
+                per: Duration::from_secs(60),
+            }),
+            retries: 3,
+            retry_backoff: Duration::from_millis(500),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A sink that POSTs matching entries to a webhook URL as JSON.
+///
+/// Delivery is synchronous: `write` returns once the POST has succeeded,
+/// or all retries have been exhausted.
+pub struct WebhookSink {
+    url: String,
+    options: WebhookOptions,
+    hostname: String,
+    agent: ureq::Agent,
+    sent: VecDeque<Instant>,
+    dropped: usize,
+}
+
+impl WebhookSink {
+    /// Create a new WebhookSink POSTing to `url` (http:// or https://) with the given options.
+    pub fn with_options(url: &str, options: WebhookOptions) -> Result<Self, RMesgError> {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(RMesgError::SinkError(format!(
+                "Webhook URL must be http:// or https://, got: {}",
+                url
+            )));
+        }
+
+        let hostname = match fs::read_to_string(PROC_SYS_KERNEL_HOSTNAME) {
+            Ok(h) => h.trim().to_owned(),
+            Err(_) => "localhost".to_owned(),
+        };
+
+        let agent = ureq::AgentBuilder::new().timeout(options.timeout).build();
+
+        Ok(Self {
+            url: url.to_owned(),
+            options,
+            hostname,
+            agent,
+            sent: VecDeque::new(),
+            dropped: 0,
+        })
+    }
+
+    /// Number of matching entries that were dropped due to rate limiting.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    fn matches(&self, entry: &Entry) -> bool {
+        match (self.options.min_level, entry.level) {
+            (None, _) => true,
+            (Some(min), Some(level)) => level <= min,
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Returns true (and records the send) if another POST fits in the rate limit.
+    fn admit(&mut self) -> bool {
+        let limit = match self.options.rate_limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+
+        let now = Instant::now();
+        while let Some(sent) = self.sent.front() {
+            if now.duration_since(*sent) >= limit.per {
+                self.sent.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        if self.sent.len() >= limit.max {
+            return false;
+        }
+
+        self.sent.push_back(now);
+        true
+    }
+
+    fn post(&self, body: &str) -> Result<(), RMesgError> {
+        let mut backoff = self.options.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            let err = match self
+                .agent
+                .post(&self.url)
+                .set("Content-Type", "application/json")
+                .send_string(body)
+            {
+                Ok(_) => return Ok(()),
+                Err(ureq::Error::Status(code, _)) if code != 429 && code < 500 => {
+                    return Err(RMesgError::SinkError(format!(
+                        "Webhook {} rejected payload with status {}",
+                        self.url, code
+                    )))
+                }
+                Err(e) => e,
+            };
+
+            if attempt >= self.options.retries {
+                return Err(RMesgError::SinkError(format!(
+                    "Webhook {} failed after {} attempts: {}",
+                    self.url,
+                    attempt + 1,
+                    err
+                )));
+            }
+
+            thread::sleep(backoff);
+            backoff = backoff.saturating_mul(2);
+            attempt += 1;
+        }
+    }
+}
+
+impl Sink for WebhookSink {
+    fn write(&mut self, entry: &Entry) -> Result<(), RMesgError> {
+        if !self.matches(entry) {
+            return Ok(());
+        }
+
+        if !self.admit() {
+            self.dropped += 1;
+            return Ok(());
+        }
+
+        let payload = render(&self.options.template, &self.hostname, entry);
+        self.post(&payload.to_string())
+    }
+}
+
+/// Render an entry into the JSON payload for the given template.
+pub fn render(template: &PayloadTemplate, hostname: &str, entry: &Entry) -> Value {
+    let level = match entry.level {
+        Some(level) => level.to_string(),
+        None => "unknown".to_owned(),
+    };
+
+    match template {
+        PayloadTemplate::Generic => json!({
+            "host": hostname,
+            "facility": entry.facility.map(|f| f.to_string()),
+            "level": entry.level.map(|l| l.to_string()),
+            "sequence_num": entry.sequence_num,
+            "timestamp_from_system_start": entry.timestamp_from_system_start.map(|ts| ts.as_secs_f64()),
+            "message": entry.message,
+            "tags": entry.tags,
+        }),
+        PayloadTemplate::Slack | PayloadTemplate::Teams => json!({
+            "text": format!("[{}] {} kernel: {}", level, hostname, entry),
+        }),
+        PayloadTemplate::PagerDuty { routing_key } => json!({
+            "routing_key": routing_key,
+            "event_action": "trigger",
+            "payload": {
+                "summary": entry.message,
+                "source": hostname,
+                "severity": pagerduty_severity(entry.level),
+                "component": "kernel",
+                "custom_details": {
+                    "level": level,
+                    "sequence_num": entry.sequence_num,
+                    "timestamp_from_system_start": entry.timestamp_from_system_start.map(|ts| ts.as_secs_f64()),
+                    "tags": entry.tags,
+                },
+            },
+        }),
+    }
+}
+
+// PagerDuty only accepts critical, error, warning and info
+fn pagerduty_severity(level: Option<LogLevel>) -> &'static str {
+    match level {
+        Some(LogLevel::Emergency) | Some(LogLevel::Alert) | Some(LogLevel::Critical) => "critical",
+        Some(LogLevel::Error) => "error",
+        Some(LogLevel::Warning) => "warning",
+        _ => "info",
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entry::LogFacility;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    fn test_entry(level: LogLevel) -> Entry {
+        Entry {
+            facility: Some(LogFacility::Kern),
+            level: Some(level),
+            sequence_num: Some(42),
+            timestamp_from_system_start: Some(Duration::from_secs(3)),
+            message: "nvme0: I/O timeout".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    // Serves one response per status given, reporting each request body received.
+    fn serve(statuses: Vec<u16>) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                loop {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some(header_end) = text.find("\r\n\r\n") {
+                        let content_length = text[..header_end]
+                            .lines()
+                            .find_map(|l| l.strip_prefix("Content-Length: "))
+                            .map(|l| l.trim().parse::<usize>().unwrap())
+                            .unwrap_or(0);
+                        if request.len() >= header_end + 4 + content_length {
+                            tx.send(text[header_end + 4..].to_owned()).unwrap();
+                            break;
+                        }
+                    }
+                }
+                write!(
+                    stream,
+                    "HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    #[test]
+    fn test_rejects_non_http_url() {
+        assert!(WebhookSink::with_options("ftp://example", WebhookOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_render_templates() {
+        let mut entry = test_entry(LogLevel::Error);
+        entry.set_tag("team", "storage");
+
+        let generic = render(&PayloadTemplate::Generic, "box", &entry);
+        assert_eq!(generic["level"], "err");
+        assert_eq!(generic["tags"]["team"], "storage");
+        assert_eq!(generic["sequence_num"], 42);
+        assert_eq!(generic["message"], "nvme0: I/O timeout");
+
+        let slack = render(&PayloadTemplate::Slack, "box", &entry);
+        assert_eq!(
+            slack["text"],
+            "[err] box kernel: [        3.000000] nvme0: I/O timeout [team=storage]"
+        );
+
+        let pd = render(
+            &PayloadTemplate::PagerDuty {
+                routing_key: "abc".to_owned(),
+            },
+            "box",
+            &entry,
+        );
+        assert_eq!(pd["routing_key"], "abc");
+        assert_eq!(pd["payload"]["severity"], "error");
+        assert_eq!(pd["payload"]["source"], "box");
+        assert_eq!(pd["payload"]["custom_details"]["tags"]["team"], "storage");
+    }
+
+    #[test]
+    fn test_posts_and_retries() {
+        let (url, rx) = serve(vec![503, 200]);
+        let options = WebhookOptions {
+            retry_backoff: Duration::from_millis(1),
+            ..WebhookOptions::default()
+        };
+        let mut sink = WebhookSink::with_options(&url, options).unwrap();
+
+        assert!(sink.write(&test_entry(LogLevel::Error)).is_ok());
+
+        let first: Value = serde_json::from_str(&rx.recv().unwrap()).unwrap();
+        let second: Value = serde_json::from_str(&rx.recv().unwrap()).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first["message"], "nvme0: I/O timeout");
+    }
+
+    #[test]
+    fn test_client_error_not_retried() {
+        let (url, rx) = serve(vec![400]);
+        let mut sink = WebhookSink::with_options(&url, WebhookOptions::default()).unwrap();
+
+        assert!(sink.write(&test_entry(LogLevel::Error)).is_err());
+        assert!(rx.recv().is_ok());
+    }
+
+    #[test]
+    fn test_min_level_and_rate_limit() {
+        let (url, rx) = serve(vec![200]);
+        let options = WebhookOptions {
+            min_level: Some(LogLevel::Warning),
+            rate_limit: Some(RateLimit {
+                max: 1,
+                per: Duration::from_secs(3600),
+            }),
+            ..WebhookOptions::default()
+        };
+        let mut sink = WebhookSink::with_options(&url, options).unwrap();
+
+        // below threshold: neither sent nor counted as dropped
+        assert!(sink.write(&test_entry(LogLevel::Info)).is_ok());
+        assert_eq!(sink.dropped(), 0);
+
+        assert!(sink.write(&test_entry(LogLevel::Critical)).is_ok());
+        assert!(sink.write(&test_entry(LogLevel::Critical)).is_ok());
+        assert_eq!(sink.dropped(), 1);
+        assert!(rx.recv().is_ok());
+    }
+}
@@ -0,0 +1,152 @@
+use crate::entry::Entry;
+/// Logical ("soft") clearing of the kernel log buffer.
+///
+/// Clearing the kernel buffer destroys state every other dmesg consumer on the host
+/// shares. SoftClear never issues `SyslogActionClear`; instead it remembers the newest
+/// entry present at the time of clearing, and presents subsequent reads as though
+/// the buffer had been cleared then.
+///
+use crate::error::RMesgError;
+use crate::{log_entries, Backend};
+
+use std::time::Duration;
+
+/// The position of the newest entry seen at the time of a soft clear.
+/// Both are remembered since the Default backend may fall back from
+/// /dev/kmsg (which has sequence numbers) to klogctl (which only has timestamps).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ClearMark {
+    pub sequence_num: Option<usize>,
+    pub timestamp_from_system_start: Option<Duration>,
+}
+
+impl ClearMark {
+    /// The mark just past the newest positioned (sequence number or timestamp) entry in `entries`.
+    pub fn after(entries: &[Entry]) -> ClearMark {
+        let mut mark = ClearMark::default();
+        for entry in entries.iter().rev() {
+            if mark.sequence_num.is_none() {
+                mark.sequence_num = entry.sequence_num;
+            }
+            if mark.timestamp_from_system_start.is_none() {
+                mark.timestamp_from_system_start = entry.timestamp_from_system_start;
+            }
+            if mark.sequence_num.is_some() && mark.timestamp_from_system_start.is_some() {
+                break;
+            }
+        }
+        mark
+    }
+
+    /// Whether `entry` was logged after this mark. Entries with neither a sequence number
+    /// nor a timestamp can't be placed relative to the mark, and are treated as cleared.
+    pub fn is_after(&self, entry: &Entry) -> bool {
+        match (entry.sequence_num, self.sequence_num) {
+            (Some(seq), Some(mark)) => return seq > mark,
+            (Some(_), None) => return true,
+            _ => {}
+        }
+
+        match (
+            entry.timestamp_from_system_start,
+            self.timestamp_from_system_start,
+        ) {
+            (Some(ts), Some(mark)) => ts > mark,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+}
+
+pub struct SoftClear {
+    backend: Backend,
+    mark: Option<ClearMark>,
+}
+
+impl SoftClear {
+    /// Create a SoftClear reading from the given backend. Until `clear` is
+    /// called, reads return the whole buffer.
+    pub fn new(backend: Backend) -> SoftClear {
+        SoftClear {
+            backend,
+            mark: None,
+        }
+    }
+
+    /// Logically clear the buffer: subsequent reads only return entries newer
+    /// than the newest one in the buffer right now.
+    pub fn clear(&mut self) -> Result<(), RMesgError> {
+        let entries = log_entries(self.backend, false)?;
+        self.mark = Some(ClearMark::after(&entries));
+        Ok(())
+    }
+
+    /// The mark remembered by the last `clear`, if any.
+    pub fn mark(&self) -> Option<ClearMark> {
+        self.mark
+    }
+
+    /// Read the buffer as though it had been cleared at the last `clear`.
+    pub fn log_entries(&self) -> Result<Vec<Entry>, RMesgError> {
+        let entries = log_entries(self.backend, false)?;
+        Ok(match self.mark {
+            None => entries,
+            Some(mark) => entries.into_iter().filter(|e| mark.is_after(e)).collect(),
+        })
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(sequence_num: Option<usize>, ts_secs: Option<u64>) -> Entry {
+        Entry {
+            sequence_num,
+            timestamp_from_system_start: ts_secs.map(Duration::from_secs),
+            message: "test".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_mark_after() {
+        let entries = vec![
+            entry(Some(1), Some(1)),
+            entry(Some(2), Some(2)),
+            entry(None, None),
+        ];
+        let mark = ClearMark::after(&entries);
+        assert_eq!(mark.sequence_num, Some(2));
+        assert_eq!(
+            mark.timestamp_from_system_start,
+            Some(Duration::from_secs(2))
+        );
+
+        assert!(!mark.is_after(&entries[0]));
+        assert!(!mark.is_after(&entries[1]));
+        assert!(!mark.is_after(&entries[2]));
+        assert!(mark.is_after(&entry(Some(3), Some(2))));
+        // klogctl entries only have timestamps
+        assert!(mark.is_after(&entry(None, Some(3))));
+        assert!(!mark.is_after(&entry(None, Some(2))));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_soft_clear() {
+        let mut softclear = SoftClear::new(Backend::Default);
+        let before = softclear.log_entries().unwrap();
+        assert!(!before.is_empty(), "Should have non-empty logs");
+
+        softclear.clear().unwrap();
+        let after = softclear.log_entries().unwrap();
+        assert!(after.len() < before.len());
+
+        // the real buffer is untouched
+        assert!(!log_entries(Backend::Default, false).unwrap().is_empty());
+    }
+}
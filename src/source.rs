@@ -0,0 +1,484 @@
+use crate::entry::Entry;
+/// Pluggable log sources.
+///
+/// `KLogSource` is what a follower needs from a kernel log: everything in it, what's new
+/// since last time, and (where possible) clearing it and its size. klogctl and /dev/kmsg
+/// implement it here; applications can implement it for sources of their own (a serial
+/// console, a hypervisor's view of a guest, a recording for tests) and follow them with
+/// `SourceEntries` like the built-in ones. `MockSource` is one for tests that can't
+/// touch the real kernel buffer (say, CI without root): they push synthetic entries
+/// into it and move its clock along themselves.
+///
+use crate::entry::{LogFacility, LogLevel};
+use crate::error::RMesgError;
+#[cfg(feature = "sync")]
+use crate::interval::PollInterval;
+use crate::parse;
+#[cfg(any(feature = "klogctl", feature = "kmsg"))]
+use crate::provenance::{self, SourceBackend};
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+#[cfg(feature = "sync")]
+use std::collections::VecDeque;
+#[cfg(feature = "sync")]
+use std::thread;
+
+/// Where the new entries of a snapshot start, given the previous snapshot of the same
+/// buffer, for sources with neither sequence numbers nor timestamps to go by. The buffer
+/// loses entries from its front as it wraps and gains them at its end, so that's after
+/// the longest run at the end of `previous` that `current` starts with. Matching that
+/// whole run, rather than the last entry seen alone, keeps repeated identical messages
+/// (as from ratelimited drivers) from being lost or duplicated.
+pub fn snapshot_overlap(previous: &[Entry], current: &[Entry]) -> usize {
+    (0..=previous.len())
+        .find(|start| current.starts_with(&previous[*start..]))
+        .map_or(0, |start| previous.len() - start)
+}
+
+/// A kernel log to read from.
+pub trait KLogSource {
+    /// Everything in the log now, oldest first.
+    fn read_all(&mut self) -> Result<Vec<Entry>, RMesgError>;
+
+    /// The entries logged since the last `read_all` or `poll_new`, oldest first
+    /// (everything, the first time).
+    fn poll_new(&mut self) -> Result<Vec<Entry>, RMesgError>;
+
+    /// Clears the log. Not every source can be cleared.
+    fn clear(&mut self) -> Result<(), RMesgError> {
+        Err(RMesgError::BackendUnavailable(
+            "This source can't be cleared".to_owned(),
+        ))
+    }
+
+    /// The size of the log's buffer in bytes. Not every source knows it.
+    fn capacity(&self) -> Result<usize, RMesgError> {
+        Err(RMesgError::BackendUnavailable(
+            "This source doesn't know its capacity".to_owned(),
+        ))
+    }
+}
+
+/// The buffer, through the klogctl system call. New entries are told apart by their
+/// timestamps, so they need enabling (see `klogctl::klog_timestamps_enable`).
+#[cfg(feature = "klogctl")]
+#[derive(Debug, Default)]
+pub struct KLogCtlSource {
+    buffer: crate::klogctl::KLogBuffer,
+    cursor: crate::klogctl::PollCursor,
+}
+
+#[cfg(feature = "klogctl")]
+impl KLogCtlSource {
+    fn read(&mut self, only_new: bool) -> Result<Vec<Entry>, RMesgError> {
+        let entries = self.buffer.entries(false)?;
+        let entries = match only_new {
+            true => self.cursor.newer_entries(entries),
+            false => {
+                self.cursor.advance(&entries);
+                entries
+            }
+        };
+        Ok(entries
+            .into_iter()
+            .map(|e| provenance::tag(e, SourceBackend::KLogCtl))
+            .collect())
+    }
+}
+
+#[cfg(feature = "klogctl")]
+impl KLogSource for KLogCtlSource {
+    fn read_all(&mut self) -> Result<Vec<Entry>, RMesgError> {
+        self.read(false)
+    }
+
+    fn poll_new(&mut self) -> Result<Vec<Entry>, RMesgError> {
+        self.read(true)
+    }
+
+    fn clear(&mut self) -> Result<(), RMesgError> {
+        crate::klogctl::klog_clear().map(|_| ())
+    }
+
+    fn capacity(&self) -> Result<usize, RMesgError> {
+        crate::klogctl::kernel_buffer_size()
+    }
+}
+
+/// The buffer, through /dev/kmsg (or another file in its format). New entries are told
+/// apart by their sequence numbers.
+#[cfg(feature = "kmsg")]
+#[derive(Debug, Default)]
+pub struct KMsgSource {
+    file_override: Option<String>,
+    next_sequence_num: Option<usize>,
+}
+
+#[cfg(feature = "kmsg")]
+impl KMsgSource {
+    /// `file_override`: When `Some`, overrides the path from where to read the kernel logs
+    pub fn with_options(file_override: Option<String>) -> KMsgSource {
+        KMsgSource {
+            file_override,
+            next_sequence_num: None,
+        }
+    }
+
+    fn read(&mut self, start: crate::kmsgfile::StartPosition) -> Result<Vec<Entry>, RMesgError> {
+        let entries = crate::kmsgfile::kmsg_from(self.file_override.clone(), start)?;
+        if let Some(seq) = entries.iter().rev().find_map(|e| e.sequence_num) {
+            self.next_sequence_num = Some(seq + 1);
+        }
+        Ok(entries
+            .into_iter()
+            .map(|e| provenance::tag(e, SourceBackend::DevKMsg))
+            .collect())
+    }
+}
+
+#[cfg(feature = "kmsg")]
+impl KLogSource for KMsgSource {
+    fn read_all(&mut self) -> Result<Vec<Entry>, RMesgError> {
+        self.read(crate::kmsgfile::StartPosition::Oldest)
+    }
+
+    fn poll_new(&mut self) -> Result<Vec<Entry>, RMesgError> {
+        self.read(match self.next_sequence_num {
+            Some(seq) => crate::kmsgfile::StartPosition::Sequence(seq),
+            None => crate::kmsgfile::StartPosition::Oldest,
+        })
+    }
+}
+
+/// An in-memory kernel log for tests. Clones share the same log, so a test can keep one
+/// to push entries into while the code under test reads from another. Nothing happens
+/// in real time: entries are stamped with the mock's own clock, which only moves when
+/// `advance` moves it.
+#[derive(Clone, Debug, Default)]
+pub struct MockSource {
+    log: Arc<Mutex<MockLog>>,
+}
+
+#[derive(Debug, Default)]
+struct MockLog {
+    entries: Vec<Entry>,
+    // how many of `entries` have been read by `poll_new`
+    read: usize,
+    now: Duration,
+    next_sequence_num: usize,
+    capacity: Option<usize>,
+    fail_next: Option<RMesgError>,
+}
+
+impl MockSource {
+    /// Logs `message` at `level` (facility kern), at the mock's current time.
+    pub fn push(&self, level: LogLevel, message: &str) {
+        let priority = level as u32;
+        self.push_entry(Entry {
+            facility: Some(LogFacility::Kern),
+            level: Some(level),
+            sequence_num: None,
+            timestamp_from_system_start: None,
+            message: message.to_owned(),
+            timestamp_realtime: None,
+            provenance: None,
+            malformed: false,
+            priority: Some(priority),
+            tags: Default::default(),
+        });
+    }
+
+    /// Logs a record in the /dev/kmsg format (e.g. `6,12,1500000,-;usb 1-1: new device`),
+    /// keeping its own sequence number and timestamp.
+    pub fn push_kmsg_line(&self, line: &str) -> Result<(), RMesgError> {
+        self.push_entry(parse::kmsg_entry_from_line(line)?);
+        Ok(())
+    }
+
+    /// Logs a line in the console (klogctl) format (e.g. `<6>[    1.500000] usb 1-1: new device`),
+    /// keeping its own timestamp.
+    pub fn push_klog_line(&self, line: &str) -> Result<(), RMesgError> {
+        self.push_entry(parse::console_entry_from_line(line)?);
+        Ok(())
+    }
+
+    /// Logs `entry`, filling in a sequence number and timestamp if it has none.
+    pub fn push_entry(&self, mut entry: Entry) {
+        let mut log = self.lock();
+        let sequence_num = *entry.sequence_num.get_or_insert(log.next_sequence_num);
+        log.next_sequence_num = sequence_num + 1;
+        entry.timestamp_from_system_start.get_or_insert(log.now);
+        log.entries.push(entry);
+    }
+
+    /// Moves the mock's clock on by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        self.lock().now += duration;
+    }
+
+    /// The mock's current time since system start.
+    pub fn now(&self) -> Duration {
+        self.lock().now
+    }
+
+    /// Sets what `capacity` reports (by default, it reports that it doesn't know).
+    pub fn set_capacity(&self, capacity: usize) {
+        self.lock().capacity = Some(capacity);
+    }
+
+    /// Makes the next read fail with `error`.
+    pub fn fail_next(&self, error: RMesgError) {
+        self.lock().fail_next = Some(error);
+    }
+
+    /// Everything logged and not cleared, whether read or not.
+    pub fn entries(&self) -> Vec<Entry> {
+        self.lock().entries.clone()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MockLog> {
+        // the log stays consistent if a panic happens while holding the lock
+        self.log.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl KLogSource for MockSource {
+    fn read_all(&mut self) -> Result<Vec<Entry>, RMesgError> {
+        let mut log = self.lock();
+        if let Some(e) = log.fail_next.take() {
+            return Err(e);
+        }
+        log.read = log.entries.len();
+        Ok(log.entries.clone())
+    }
+
+    fn poll_new(&mut self) -> Result<Vec<Entry>, RMesgError> {
+        let mut log = self.lock();
+        if let Some(e) = log.fail_next.take() {
+            return Err(e);
+        }
+        let new = log.entries[log.read..].to_vec();
+        log.read = log.entries.len();
+        Ok(new)
+    }
+
+    fn clear(&mut self) -> Result<(), RMesgError> {
+        let mut log = self.lock();
+        log.entries.clear();
+        log.read = 0;
+        Ok(())
+    }
+
+    fn capacity(&self) -> Result<usize, RMesgError> {
+        self.lock().capacity.ok_or_else(|| {
+            RMesgError::BackendUnavailable("No capacity set on this MockSource".to_owned())
+        })
+    }
+}
+
+/// Follows any `KLogSource`, polling it for new entries every `poll_interval`.
+#[cfg(feature = "sync")]
+pub struct SourceEntries<S: KLogSource> {
+    source: S,
+    poll_interval: Duration,
+    entries: VecDeque<Entry>,
+}
+
+#[cfg(feature = "sync")]
+impl<S: KLogSource> SourceEntries<S> {
+    pub fn with_options(source: S, poll_interval: PollInterval) -> SourceEntries<S> {
+        SourceEntries {
+            source,
+            poll_interval: poll_interval.get(),
+            entries: VecDeque::new(),
+        }
+    }
+
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
+    pub fn source_mut(&mut self) -> &mut S {
+        &mut self.source
+    }
+
+    /// The next entry if there is one already, polling the source once if none are
+    /// buffered, but never sleeping (so tests can step through a `MockSource`).
+    pub fn try_next(&mut self) -> Result<Option<Entry>, RMesgError> {
+        if self.entries.is_empty() {
+            self.entries.extend(self.source.poll_new()?);
+        }
+        Ok(self.entries.pop_front())
+    }
+}
+
+#[cfg(feature = "sync")]
+impl<S: KLogSource> Iterator for SourceEntries<S> {
+    type Item = Result<Entry, RMesgError>;
+
+    /// Blocks (sleeping between polls) until there's an entry.
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.try_next() {
+                Ok(Some(entry)) => return Some(Ok(entry)),
+                Ok(None) => thread::sleep(self.poll_interval),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // a log that gets one more entry each time it's read
+    #[derive(Default)]
+    struct GrowingSource {
+        logged: Vec<Entry>,
+        read: usize,
+    }
+
+    impl KLogSource for GrowingSource {
+        fn read_all(&mut self) -> Result<Vec<Entry>, RMesgError> {
+            let sequence_num = self.logged.len();
+            self.logged.push(Entry {
+                sequence_num: Some(sequence_num),
+                message: format!("message {}", sequence_num),
+                ..Default::default()
+            });
+            self.read = self.logged.len();
+            Ok(self.logged.clone())
+        }
+
+        fn poll_new(&mut self) -> Result<Vec<Entry>, RMesgError> {
+            let read = self.read;
+            self.read_all()?;
+            Ok(self.logged[read..].to_vec())
+        }
+    }
+
+    #[test]
+    fn test_snapshot_overlap() {
+        let entries = |messages: &[&str]| -> Vec<Entry> {
+            messages
+                .iter()
+                .map(|m| Entry {
+                    message: (*m).to_owned(),
+                    ..Default::default()
+                })
+                .collect()
+        };
+
+        let previous = entries(&["a", "b", "b"]);
+        // the same message again, after a run of it
+        assert_eq!(
+            snapshot_overlap(&previous, &entries(&["a", "b", "b", "b"])),
+            3
+        );
+        assert_eq!(snapshot_overlap(&previous, &previous), 3);
+        // wrapped, losing "a", and gaining "b" and "c"
+        assert_eq!(
+            snapshot_overlap(&previous, &entries(&["b", "b", "b", "c"])),
+            2
+        );
+        // nothing in common (say, cleared), or nothing before
+        assert_eq!(snapshot_overlap(&previous, &entries(&["c"])), 0);
+        assert_eq!(snapshot_overlap(&[], &previous), 0);
+    }
+
+    #[test]
+    fn test_injected_source() {
+        let mut source = GrowingSource::default();
+        assert_eq!(source.read_all().unwrap().len(), 1);
+        assert_eq!(source.poll_new().unwrap()[0].message, "message 1");
+        assert!(matches!(
+            source.clear(),
+            Err(RMesgError::BackendUnavailable(_))
+        ));
+        assert!(source.capacity().is_err());
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn test_source_entries() {
+        let entries = SourceEntries::with_options(
+            GrowingSource::default(),
+            PollInterval::from_millis(1).unwrap(),
+        );
+        let messages: Vec<String> = entries.take(3).map(|e| e.unwrap().message).collect();
+        assert_eq!(messages, vec!["message 0", "message 1", "message 2"]);
+    }
+
+    #[test]
+    fn test_mock_source() {
+        let mock = MockSource::default();
+        let mut source = mock.clone();
+
+        mock.push(LogLevel::Info, "usb 1-1: new device");
+        mock.advance(Duration::from_millis(1500));
+        mock.push(LogLevel::Error, "nvme nvme0: I/O 12 QID 3 timeout");
+        mock.push_kmsg_line("4,40,3000000,-;e1000e eth0: link down")
+            .unwrap();
+        assert!(mock
+            .push_kmsg_line("99999999999,1,0,-;too high a priority")
+            .is_err());
+
+        let entries = source.poll_new().unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|e| (e.sequence_num, e.timestamp_from_system_start))
+                .collect::<Vec<_>>(),
+            vec![
+                (Some(0), Some(Duration::from_secs(0))),
+                (Some(1), Some(Duration::from_millis(1500))),
+                (Some(40), Some(Duration::from_secs(3))),
+            ]
+        );
+        assert_eq!(entries[1].level, Some(LogLevel::Error));
+        assert!(source.poll_new().unwrap().is_empty());
+
+        mock.fail_next(RMesgError::Timeout("test".to_owned()));
+        assert!(source.poll_new().is_err());
+        mock.push(LogLevel::Info, "after the error");
+        assert_eq!(source.poll_new().unwrap()[0].sequence_num, Some(41));
+
+        assert!(source.capacity().is_err());
+        mock.set_capacity(1 << 20);
+        assert_eq!(source.capacity().unwrap(), 1 << 20);
+        source.clear().unwrap();
+        assert!(mock.entries().is_empty());
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn test_stepping_through_mock() {
+        let mock = MockSource::default();
+        let mut entries =
+            SourceEntries::with_options(mock.clone(), PollInterval::from_secs(3600).unwrap());
+        assert_eq!(entries.try_next().unwrap(), None);
+        mock.push(LogLevel::Info, "one");
+        mock.push(LogLevel::Info, "two");
+        assert_eq!(entries.try_next().unwrap().unwrap().message, "one");
+        assert_eq!(entries.next().unwrap().unwrap().message, "two");
+    }
+
+    #[cfg(all(feature = "kmsg", target_os = "linux"))]
+    #[test]
+    fn test_kmsg_source() {
+        let mut source = KMsgSource::default();
+        let all = source.read_all().unwrap();
+        assert!(!all.is_empty(), "Should have non-empty logs");
+        // anything new is after what was already read
+        let last = all.iter().rev().find_map(|e| e.sequence_num);
+        for entry in source.poll_new().unwrap() {
+            assert!(entry.sequence_num > last);
+        }
+    }
+}
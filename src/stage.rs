@@ -0,0 +1,190 @@
+use crate::entry::Entry;
+/// Stages are the processing steps applied to entries between reading them and handing
+/// them to consumers (or sinks): filters that drop entries, and transforms that modify them.
+///
+/// A stage can be applied to a snapshot with `apply`, or attached to any of the
+/// iterators/streams in this crate with `Staged`.
+///
+use crate::error::RMesgError;
+
+#[cfg(feature = "async")]
+use core::pin::Pin;
+#[cfg(feature = "async")]
+use futures::stream::Stream;
+#[cfg(feature = "async")]
+use futures::task::{Context, Poll};
+
+/// A processing step applied to each entry in turn.
+pub trait Stage {
+    /// Returns the (possibly modified) entry to pass on, or `None` to drop it.
+    fn process(&mut self, entry: Entry) -> Option<Entry>;
+}
+
+impl<S: Stage + ?Sized> Stage for Box<S> {
+    fn process(&mut self, entry: Entry) -> Option<Entry> {
+        (**self).process(entry)
+    }
+}
+
+/// A chain of stages, applied in order. An entry dropped by one stage
+/// isn't seen by the stages after it.
+impl<S: Stage> Stage for Vec<S> {
+    fn process(&mut self, entry: Entry) -> Option<Entry> {
+        self.iter_mut()
+            .try_fold(entry, |entry, stage| stage.process(entry))
+    }
+}
+
+/// Apply a stage to every entry of a snapshot (such as the one returned by `log_entries`)
+pub fn apply<S: Stage + ?Sized>(entries: Vec<Entry>, stage: &mut S) -> Vec<Entry> {
+    entries
+        .into_iter()
+        .filter_map(|entry| stage.process(entry))
+        .collect()
+}
+
+/// Wraps an iterator or stream of entries, passing every entry through a stage.
+/// Errors are passed through untouched.
+pub struct Staged<I, S> {
+    inner: I,
+    stage: S,
+}
+
+impl<I, S: Stage> Staged<I, S> {
+    pub fn new(inner: I, stage: S) -> Self {
+        Self { inner, stage }
+    }
+
+    /// The stage, e.g. to inspect any counters it keeps.
+    pub fn stage(&self) -> &S {
+        &self.stage
+    }
+
+    pub fn into_inner(self) -> I {
+        self.inner
+    }
+}
+
+impl<I, S> Iterator for Staged<I, S>
+where
+    I: Iterator<Item = Result<Entry, RMesgError>>,
+    S: Stage,
+{
+    type Item = Result<Entry, RMesgError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.inner.next()? {
+                Ok(entry) => {
+                    if let Some(entry) = self.stage.process(entry) {
+                        return Some(Ok(entry));
+                    }
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<I, S> Stream for Staged<I, S>
+where
+    I: Stream<Item = Result<Entry, RMesgError>> + Unpin,
+    S: Stage + Unpin,
+{
+    type Item = Result<Entry, RMesgError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            match Pin::new(&mut this.inner).poll_next(cx) {
+                Poll::Ready(Some(Ok(entry))) => {
+                    if let Some(entry) = this.stage.process(entry) {
+                        return Poll::Ready(Some(Ok(entry)));
+                    }
+                }
+                other => return other,
+            }
+        }
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct DropOdd;
+    impl Stage for DropOdd {
+        fn process(&mut self, entry: Entry) -> Option<Entry> {
+            match entry.sequence_num {
+                Some(n) if n % 2 == 1 => None,
+                _ => Some(entry),
+            }
+        }
+    }
+
+    fn entry(n: usize) -> Entry {
+        Entry {
+            sequence_num: Some(n),
+            message: format!("message {}", n),
+            ..Default::default()
+        }
+    }
+
+    struct DropAbove(usize);
+    impl Stage for DropAbove {
+        fn process(&mut self, entry: Entry) -> Option<Entry> {
+            match entry.sequence_num {
+                Some(n) if n > self.0 => None,
+                _ => Some(entry),
+            }
+        }
+    }
+
+    #[test]
+    fn test_chain() {
+        let mut chain: Vec<Box<dyn Stage>> = vec![Box::new(DropOdd), Box::new(DropAbove(2))];
+        let entries = apply((0..5).map(entry).collect(), &mut chain);
+        let seqs: Vec<_> = entries.iter().map(|e| e.sequence_num.unwrap()).collect();
+        assert_eq!(seqs, vec![0, 2]);
+    }
+
+    #[test]
+    fn test_apply() {
+        let entries = apply((0..5).map(entry).collect(), &mut DropOdd);
+        let seqs: Vec<_> = entries.iter().map(|e| e.sequence_num.unwrap()).collect();
+        assert_eq!(seqs, vec![0, 2, 4]);
+    }
+
+    #[test]
+    fn test_staged_iterator() {
+        let inner = vec![
+            Ok(entry(1)),
+            Ok(entry(2)),
+            Err(RMesgError::InternalError("boom".to_owned())),
+            Ok(entry(4)),
+        ];
+        let mut staged = Staged::new(inner.into_iter(), DropOdd);
+        assert_eq!(staged.next().unwrap().unwrap().sequence_num, Some(2));
+        assert!(staged.next().unwrap().is_err());
+        assert_eq!(staged.next().unwrap().unwrap().sequence_num, Some(4));
+        assert!(staged.next().is_none());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_staged_stream() {
+        use tokio_stream::StreamExt;
+
+        let inner = tokio_stream::iter((0..5).map(|n| Ok(entry(n))));
+        let staged = Staged::new(inner, DropOdd);
+        let seqs: Vec<_> = staged
+            .map(|e| e.unwrap().sequence_num.unwrap())
+            .collect()
+            .await;
+        assert_eq!(seqs, vec![0, 2, 4]);
+    }
+}
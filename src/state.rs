@@ -0,0 +1,374 @@
+use crate::analysis::baseline::Baseline;
+/// Stable, versioned on-disk formats for state that outlives a process: bookmarks
+/// (cursors), baselines and suppression lists.
+///
+/// Each format is a JSON document naming its format and version, e.g.
+/// `{"format":"rmesg.cursor","version":1,...}`. Importing accepts every version this
+/// crate has ever written (including the older plain-text bookmark tokens, baseline
+/// lines and TOML suppression lists, as version 0) and migrates it, so deployments can
+/// upgrade the crate without losing their state. Documents written by a newer version
+/// than this crate understands are rejected rather than misread.
+///
+use crate::bookmark::Bookmark;
+use crate::error::RMesgError;
+use crate::softclear::ClearMark;
+use crate::suppress::{Suppression, SuppressionAction, SuppressionList};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{Duration, UNIX_EPOCH};
+
+pub const CURSOR_FORMAT: &str = "rmesg.cursor";
+pub const CURSOR_VERSION: u32 = 1;
+pub const BASELINE_FORMAT: &str = "rmesg.baseline";
+pub const BASELINE_VERSION: u32 = 1;
+pub const SUPPRESSIONS_FORMAT: &str = "rmesg.suppressions";
+pub const SUPPRESSIONS_VERSION: u32 = 1;
+
+#[derive(Deserialize)]
+struct Header {
+    format: String,
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Document<T> {
+    format: String,
+    version: u32,
+    #[serde(flatten)]
+    data: T,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CursorV1 {
+    boot_id: Option<String>,
+    sequence_num: Option<usize>,
+    /// microseconds since system start
+    timestamp_us: Option<u64>,
+    /// entries at that timestamp it's past (not written by older versions)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    at_timestamp: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BaselineV1 {
+    templates: Vec<TemplateV1>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TemplateV1 {
+    template: String,
+    count: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SuppressionsV1 {
+    action: ActionV1,
+    suppressions: Vec<SuppressionV1>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ActionV1 {
+    Drop,
+    Count,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SuppressionV1 {
+    regex: String,
+    comment: Option<String>,
+    /// seconds since the Unix epoch
+    expires: Option<u64>,
+    /// entries matched so far
+    matched: u64,
+}
+
+pub fn export_cursor(bookmark: &Bookmark) -> Result<String, RMesgError> {
+    write(
+        CURSOR_FORMAT,
+        CURSOR_VERSION,
+        CursorV1 {
+            boot_id: bookmark.boot_id.clone(),
+            sequence_num: bookmark.mark.sequence_num,
+            timestamp_us: bookmark
+                .mark
+                .timestamp_from_system_start
+                .map(|t| t.as_micros() as u64),
+            at_timestamp: bookmark.at_timestamp,
+        },
+    )
+}
+
+/// Imports a cursor written by `export_cursor` or (version 0) `Bookmark::to_token`.
+pub fn import_cursor(text: &str) -> Result<Bookmark, RMesgError> {
+    if !is_document(text) {
+        return Bookmark::from_token(text.trim());
+    }
+    match read_header(text, CURSOR_FORMAT, CURSOR_VERSION)? {
+        1 => {
+            let cursor: CursorV1 = read(text)?;
+            Ok(Bookmark {
+                boot_id: cursor.boot_id,
+                mark: ClearMark {
+                    sequence_num: cursor.sequence_num,
+                    timestamp_from_system_start: cursor.timestamp_us.map(Duration::from_micros),
+                },
+                at_timestamp: cursor.at_timestamp,
+            })
+        }
+        version => Err(unsupported(CURSOR_FORMAT, version)),
+    }
+}
+
+pub fn export_baseline(baseline: &Baseline) -> Result<String, RMesgError> {
+    write(
+        BASELINE_FORMAT,
+        BASELINE_VERSION,
+        BaselineV1 {
+            templates: baseline
+                .templates()
+                .map(|t| TemplateV1 {
+                    template: t.template,
+                    count: t.count,
+                })
+                .collect(),
+        },
+    )
+}
+
+/// Imports a baseline written by `export_baseline` or (version 0) `Baseline::to_text`.
+pub fn import_baseline(text: &str) -> Result<Baseline, RMesgError> {
+    if !is_document(text) {
+        return Baseline::from_text(text);
+    }
+    match read_header(text, BASELINE_FORMAT, BASELINE_VERSION)? {
+        1 => {
+            let baseline: BaselineV1 = read(text)?;
+            let mut templates = BTreeMap::new();
+            for t in baseline.templates {
+                *templates.entry(t.template).or_insert(0) += t.count;
+            }
+            Ok(Baseline { templates })
+        }
+        version => Err(unsupported(BASELINE_FORMAT, version)),
+    }
+}
+
+/// Exports a suppression list, including how many entries each suppression has
+/// matched so far.
+pub fn export_suppressions(list: &SuppressionList) -> Result<String, RMesgError> {
+    write(
+        SUPPRESSIONS_FORMAT,
+        SUPPRESSIONS_VERSION,
+        SuppressionsV1 {
+            action: match list.action {
+                SuppressionAction::Drop => ActionV1::Drop,
+                SuppressionAction::Count => ActionV1::Count,
+            },
+            suppressions: list
+                .counts()
+                .map(|(s, matched)| SuppressionV1 {
+                    regex: s.pattern().to_owned(),
+                    comment: s.comment.clone(),
+                    expires: s
+                        .expires
+                        .and_then(|e| e.duration_since(UNIX_EPOCH).ok())
+                        .map(|e| e.as_secs()),
+                    matched,
+                })
+                .collect(),
+        },
+    )
+}
+
+/// Imports a suppression list written by `export_suppressions` or (version 0, with the
+/// `config` feature) a TOML list as read by `SuppressionList::from_toml`, which is
+/// given `action` since TOML lists don't record one.
+pub fn import_suppressions(
+    text: &str,
+    action: SuppressionAction,
+) -> Result<SuppressionList, RMesgError> {
+    if !is_document(text) {
+        return import_toml_suppressions(text, action);
+    }
+    match read_header(text, SUPPRESSIONS_FORMAT, SUPPRESSIONS_VERSION)? {
+        1 => {
+            let list: SuppressionsV1 = read(text)?;
+            let mut suppressions = Vec::with_capacity(list.suppressions.len());
+            let mut counts = Vec::with_capacity(list.suppressions.len());
+            for s in list.suppressions {
+                let mut suppression = Suppression::regex(&s.regex)?;
+                suppression.comment = s.comment;
+                suppression.expires = s.expires.map(|e| UNIX_EPOCH + Duration::from_secs(e));
+                suppressions.push(suppression);
+                counts.push(s.matched);
+            }
+            let mut imported = SuppressionList::with_options(
+                suppressions,
+                match list.action {
+                    ActionV1::Drop => SuppressionAction::Drop,
+                    ActionV1::Count => SuppressionAction::Count,
+                },
+            );
+            imported.counts = counts;
+            Ok(imported)
+        }
+        version => Err(unsupported(SUPPRESSIONS_FORMAT, version)),
+    }
+}
+
+#[cfg(feature = "config")]
+fn import_toml_suppressions(
+    text: &str,
+    action: SuppressionAction,
+) -> Result<SuppressionList, RMesgError> {
+    SuppressionList::from_toml(text, action)
+}
+
+#[cfg(not(feature = "config"))]
+fn import_toml_suppressions(
+    _text: &str,
+    _action: SuppressionAction,
+) -> Result<SuppressionList, RMesgError> {
+    Err(RMesgError::ConfigError(
+        "Importing a TOML suppression list needs the config feature".to_owned(),
+    ))
+}
+
+// Everything written before the versioned formats was plain text or TOML
+fn is_document(text: &str) -> bool {
+    text.trim_start().starts_with('{')
+}
+
+fn write<T: Serialize>(format: &str, version: u32, data: T) -> Result<String, RMesgError> {
+    serde_json::to_string(&Document {
+        format: format.to_owned(),
+        version,
+        data,
+    })
+    .map_err(|e| RMesgError::ConfigError(format!("Unable to write {}: {}", format, e)))
+}
+
+// The version of a `format` document, checking it's one this crate can read
+fn read_header(text: &str, format: &str, latest: u32) -> Result<u32, RMesgError> {
+    let header: Header = serde_json::from_str(text).map_err(|e| {
+        RMesgError::ConfigError(format!("Unable to read {} document: {}", format, e))
+    })?;
+    if header.format != format {
+        return Err(RMesgError::ConfigError(format!(
+            "Expected a {} document, but found {}",
+            format, header.format
+        )));
+    }
+    if header.version > latest {
+        return Err(RMesgError::ConfigError(format!(
+            "{} version {} was written by a newer rmesg (this one reads up to version {})",
+            format, header.version, latest
+        )));
+    }
+    Ok(header.version)
+}
+
+fn read<T: DeserializeOwned>(text: &str) -> Result<T, RMesgError> {
+    let document: Document<T> = serde_json::from_str(text)
+        .map_err(|e| RMesgError::ConfigError(format!("Unable to read document: {}", e)))?;
+    Ok(document.data)
+}
+
+fn unsupported(format: &str, version: u32) -> RMesgError {
+    RMesgError::ConfigError(format!("Unsupported {} version {}", format, version))
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entry::entry;
+    use std::time::SystemTime;
+
+    #[test]
+    fn test_cursor() {
+        let bookmark = Bookmark::from_token("boot/42/1500000").unwrap();
+        let exported = export_cursor(&bookmark).unwrap();
+        assert_eq!(
+            exported,
+            r#"{"format":"rmesg.cursor","version":1,"boot_id":"boot","sequence_num":42,"timestamp_us":1500000}"#
+        );
+        assert_eq!(import_cursor(&exported).unwrap(), bookmark);
+        // version 0: a bookmark token
+        assert_eq!(import_cursor("boot/42/1500000\n").unwrap(), bookmark);
+    }
+
+    #[test]
+    fn test_baseline() {
+        let baseline = Baseline::from_entries(&[
+            entry("usb 1-1: new high-speed USB device number 2 using xhci_hcd"),
+            entry("Linux version 5.10.0"),
+        ]);
+        let exported = export_baseline(&baseline).unwrap();
+        assert_eq!(import_baseline(&exported).unwrap(), baseline);
+        assert_eq!(import_baseline(&baseline.to_text()).unwrap(), baseline);
+    }
+
+    #[test]
+    fn test_suppressions() {
+        let mut list = SuppressionList::with_options(
+            vec![
+                Suppression::template("usb *: device descriptor read/64, error *")
+                    .unwrap()
+                    .with_comment("flaky hub")
+                    .with_expiry(UNIX_EPOCH + Duration::from_secs(4_000_000_000)),
+                Suppression::regex("^ACPI Error").unwrap(),
+            ],
+            SuppressionAction::Count,
+        );
+        use crate::stage::Stage;
+        list.process(entry("ACPI Error: AE_NOT_FOUND"));
+
+        let imported = import_suppressions(
+            &export_suppressions(&list).unwrap(),
+            SuppressionAction::Drop,
+        )
+        .unwrap();
+        assert_eq!(imported.action, SuppressionAction::Count);
+        let counts: Vec<(String, Option<String>, Option<SystemTime>, u64)> = imported
+            .counts()
+            .map(|(s, n)| (s.pattern().to_owned(), s.comment.clone(), s.expires, n))
+            .collect();
+        assert_eq!(
+            counts,
+            list.counts()
+                .map(|(s, n)| (s.pattern().to_owned(), s.comment.clone(), s.expires, n))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(counts[1].3, 1);
+    }
+
+    #[cfg(feature = "config")]
+    #[test]
+    fn test_suppressions_from_toml() {
+        let imported = import_suppressions(
+            "[[suppress]]\nregex = \"^ACPI Error\"\n",
+            SuppressionAction::Drop,
+        )
+        .unwrap();
+        assert!(imported.matching(&entry("ACPI Error: oops")).is_some());
+    }
+
+    #[test]
+    fn test_versions() {
+        let newer = r#"{"format":"rmesg.cursor","version":99,"future":true}"#;
+        assert!(import_cursor(newer)
+            .unwrap_err()
+            .to_string()
+            .contains("newer rmesg"));
+        let wrong = r#"{"format":"rmesg.baseline","version":1,"templates":[]}"#;
+        assert!(import_cursor(wrong).is_err());
+        assert!(import_baseline(wrong).unwrap().is_empty());
+        assert!(import_cursor(r#"{"format":"rmesg.cursor","version":0}"#).is_err());
+    }
+}
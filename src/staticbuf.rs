@@ -0,0 +1,320 @@
+use crate::common;
+use crate::entry::Entry;
+/// A reader for /dev/kmsg that never grows the heap after construction.
+///
+/// Intended for embedded and initramfs use where memory is tight: the record buffer and
+/// the message storage of the one Entry it hands out are allocated up-front with fixed
+/// sizes, and every subsequent read reuses them. Records that don't fit are reported as
+/// `RMesgError::BufferFull` instead of causing an allocation.
+///
+/// `read_pages` builds on it to export a snapshot in pages of owned entries, so that even
+/// a buffer of hundreds of megabytes (a big `log_buf_len`) is never held in memory whole,
+/// unlike with `kmsgfile::kmsg`. (klogctl can only hand over the whole buffer in one call,
+/// so there's no paged equivalent for it.)
+///
+use crate::error::RMesgError;
+
+use std::fs as stdfs;
+use std::io::Read;
+use std::os::unix::fs::OpenOptionsExt;
+
+const DEV_KMSG_PATH: &str = "/dev/kmsg";
+
+/// Suggested record capacity. The kernel caps a single /dev/kmsg record
+/// (including its dictionary) at a little under 8KiB.
+pub const SUGGESTED_RECORD_CAPACITY: usize = 8192;
+
+pub struct StaticBufferReader {
+    file: stdfs::File,
+    record: Box<[u8]>,
+    entry: Entry,
+}
+
+impl StaticBufferReader {
+    /// Create a new StaticBufferReader
+    /// `file_override`: When `Some`, overrides the path from where to read the kernel logs
+    /// `record_capacity`: Size of the one record buffer (and the maximum message length)
+    /// `follow`: When set, `next_entry` blocks waiting for new records. Otherwise it
+    ///     returns `Ok(None)` once the buffer has been read to the end.
+    pub fn with_options(
+        file_override: Option<String>,
+        record_capacity: usize,
+        follow: bool,
+    ) -> Result<Self, RMesgError> {
+        let path = file_override.as_deref().unwrap_or(DEV_KMSG_PATH);
+
+        let mut options = stdfs::OpenOptions::new();
+        options.read(true);
+        if !follow {
+            options.custom_flags(libc::O_NONBLOCK);
+        }
+
+        let file = match options.open(path) {
+            Ok(fc) => fc,
+            Err(e) => {
+                return Err(RMesgError::DevKMsgFileOpenError(format!(
+                    "Unable to open file {}: {}",
+                    path, e
+                )))
+            }
+        };
+
+        Ok(Self {
+            file,
+            record: vec![0; record_capacity].into_boxed_slice(),
+            entry: Entry {
+                facility: None,
+                level: None,
+                sequence_num: None,
+                timestamp_from_system_start: None,
+                message: String::with_capacity(record_capacity),
+                timestamp_realtime: None,
+                provenance: None,
+                malformed: false,
+                priority: None,
+                tags: Default::default(),
+            },
+        })
+    }
+
+    /// Read the next record into the internal buffers and return a reference to it.
+    /// The returned Entry is overwritten by the next call; clone it to keep it
+    /// (which, of course, allocates).
+    pub fn next_entry(&mut self) -> Result<Option<&Entry>, RMesgError> {
+        loop {
+            let bytes_read = match self.file.read(&mut self.record) {
+                Ok(0) => return Ok(None),
+                Ok(n) => n,
+                Err(e) => match e.raw_os_error() {
+                    // no more records (only when not following)
+                    Some(libc::EAGAIN) => return Ok(None),
+                    // the kernel refuses to split a record across reads
+                    Some(libc::EINVAL) => return Err(RMesgError::BufferFull(self.record.len())),
+                    // records we were about to read were overwritten; carry on from the oldest available
+                    Some(libc::EPIPE) => continue,
+                    _ => return Err(e.into()),
+                },
+            };
+
+            parse_record_into(&self.record[..bytes_read], &mut self.entry)?;
+            return Ok(Some(&self.entry));
+        }
+    }
+}
+
+/// A snapshot of the buffer, a page of entries at a time (see `read_pages`).
+pub struct Pages {
+    reader: StaticBufferReader,
+    page_size: usize,
+    done: bool,
+}
+
+impl Iterator for Pages {
+    type Item = Result<Vec<Entry>, RMesgError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let mut page = Vec::with_capacity(self.page_size);
+        while page.len() < self.page_size {
+            match self.reader.next_entry() {
+                Ok(Some(entry)) => page.push(entry.clone()),
+                Ok(None) => {
+                    self.done = true;
+                    break;
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        match page.is_empty() {
+            true => None,
+            false => Some(Ok(page)),
+        }
+    }
+}
+
+/// Reads the buffer as it is now in pages of up to `page_size` entries, oldest first.
+/// Only one page (and one record buffer of `SUGGESTED_RECORD_CAPACITY`) is held at a
+/// time, however big the buffer is.
+pub fn read_pages(file_override: Option<String>, page_size: usize) -> Result<Pages, RMesgError> {
+    Ok(Pages {
+        reader: StaticBufferReader::with_options(file_override, SUGGESTED_RECORD_CAPACITY, false)?,
+        page_size: page_size.max(1),
+        done: false,
+    })
+}
+
+/// Parse a single /dev/kmsg record into `entry`, reusing its message storage (and
+/// unescaping the message in it, as `parse::kmsg_entry` does).
+/// Returns `BufferFull` (leaving the message empty) if the message doesn't fit
+/// in the capacity already allocated.
+pub fn parse_record_into(record: &[u8], entry: &mut Entry) -> Result<(), RMesgError> {
+    let record = std::str::from_utf8(record).map_err(|e| {
+        RMesgError::Utf8StringConversionError(format!("Kernel log record not UTF-8: {}", e))
+    })?;
+
+    // The message is everything after the first ';' up to the first newline
+    // (subsequent lines are the record's key/value dictionary).
+    let (prefix, rest) = match record.find(';') {
+        Some(idx) => (&record[..idx], &record[idx + 1..]),
+        None => {
+            return Err(RMesgError::EntryParsingError(format!(
+                "Kernel log record has no ';' separating prefix from message: {}",
+                record
+            )))
+        }
+    };
+    let message = match rest.find('\n') {
+        Some(idx) => &rest[..idx],
+        None => rest,
+    };
+
+    let mut fields = prefix.split(',');
+    let (priority, facility, level) = match fields.next() {
+        Some(faclevstr) => {
+            let priority = common::parse_fragment::<u32>(faclevstr, record)?;
+            let (facility, level) = common::decode_faclev(priority, record)?;
+            (Some(priority), facility, level)
+        }
+        None => (None, None, None),
+    };
+    let sequence_num = match fields.next() {
+        Some(sequencestr) => Some(common::parse_fragment::<usize>(sequencestr, record)?),
+        None => None,
+    };
+    let timestamp_from_system_start = match fields.next() {
+        Some(timestampstr) => common::parse_timestamp_microsecs(timestampstr, record)?,
+        None => None,
+    };
+
+    entry.priority = priority;
+    entry.facility = facility;
+    entry.level = level;
+    entry.sequence_num = sequence_num;
+    entry.timestamp_from_system_start = timestamp_from_system_start;
+    entry.message.clear();
+    if message.len() > entry.message.capacity() {
+        return Err(RMesgError::BufferFull(entry.message.capacity()));
+    }
+    common::unescape_kmsg_message_into(message, &mut entry.message);
+
+    Ok(())
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+    use crate::entry::{LogFacility, LogLevel};
+    use crate::parse;
+    use std::time::Duration;
+
+    #[test]
+    fn test_reads_without_growing() {
+        let mut reader =
+            StaticBufferReader::with_options(None, SUGGESTED_RECORD_CAPACITY, false).unwrap();
+
+        let mut count = 0;
+        let mut message_ptr = None;
+        while let Some(entry) = reader.next_entry().unwrap() {
+            // message storage must never be reallocated
+            let ptr = entry.message.as_ptr();
+            assert_eq!(*message_ptr.get_or_insert(ptr), ptr);
+            assert_eq!(entry.message.capacity(), SUGGESTED_RECORD_CAPACITY);
+            count += 1;
+        }
+        assert!(count > 0, "Should have non-empty logs");
+    }
+
+    #[test]
+    fn test_read_pages() {
+        let mut pages = 0;
+        let mut last_sequence_num = None;
+        for page in read_pages(None, 3).unwrap() {
+            let page = page.unwrap();
+            assert!(!page.is_empty() && page.len() <= 3);
+            for entry in page {
+                assert!(entry.sequence_num > last_sequence_num);
+                last_sequence_num = entry.sequence_num;
+            }
+            pages += 1;
+        }
+        assert!(pages > 0, "Should have non-empty logs");
+    }
+
+    #[test]
+    fn test_buffer_full() {
+        let mut reader = StaticBufferReader::with_options(None, 4, false).unwrap();
+        match reader.next_entry() {
+            Err(RMesgError::BufferFull(4)) => {}
+            other => panic!("Expected BufferFull, got: {:?}", other.map(|e| e.cloned())),
+        }
+    }
+
+    #[test]
+    fn test_parse_record_into() {
+        let mut entry = Entry {
+            message: String::with_capacity(64),
+            ..Default::default()
+        };
+
+        let record =
+            b"6,779,91650777797,-;docker0: port 2 entered disabled state\n SUBSYSTEM=net\n";
+        parse_record_into(record, &mut entry).unwrap();
+        assert_eq!(entry.facility, Some(LogFacility::Kern));
+        assert_eq!(entry.level, Some(LogLevel::Info));
+        assert_eq!(entry.sequence_num, Some(779));
+        assert_eq!(
+            entry.timestamp_from_system_start,
+            Some(Duration::from_micros(91650777797))
+        );
+        assert_eq!(entry.message, "docker0: port 2 entered disabled state");
+
+        let mut small = Entry {
+            message: String::with_capacity(4),
+            ..entry.clone()
+        };
+        let capacity = small.message.capacity();
+        match parse_record_into(record, &mut small) {
+            Err(RMesgError::BufferFull(c)) => assert_eq!(c, capacity),
+            other => panic!("Expected BufferFull, got: {:?}", other),
+        }
+
+        assert!(parse_record_into(b"no separator", &mut entry).is_err());
+    }
+
+    #[test]
+    fn test_parse_record_into_escaped() {
+        let mut entry = Entry {
+            message: String::with_capacity(64),
+            ..Default::default()
+        };
+        let storage = entry.message.as_ptr();
+
+        for (record, message) in [
+            (
+                "6,4,2000,-;usb 1-1: Product: Caf\\xc3\\xa9\\x09Pro",
+                "usb 1-1: Product: Caf\u{e9}\tPro",
+            ),
+            (
+                "6,5,3000,-;bad \\x80 then \\xe2\\x82",
+                "bad \u{fffd} then \u{fffd}",
+            ),
+        ] {
+            parse_record_into(record.as_bytes(), &mut entry).unwrap();
+            assert_eq!(entry.message, message);
+            // as /dev/kmsg reads it
+            let parsed = parse::kmsg_entry_from_line(record).unwrap();
+            assert_eq!(entry.message, parsed.message);
+            assert_eq!(entry.message.as_ptr(), storage);
+        }
+    }
+}
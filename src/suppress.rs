@@ -0,0 +1,342 @@
+use crate::entry::Entry;
+/// Suppression lists: a managed database of known-noisy messages.
+///
+/// Each suppression matches messages by template or regex, says why it's there, and
+/// can expire so that stale suppressions stop hiding messages without anyone having to
+/// remember to remove them. With the `config` feature, lists can be loaded from TOML
+/// so fleet operators can distribute one centrally.
+///
+use crate::error::RMesgError;
+use crate::stage::Stage;
+
+use regex::Regex;
+use std::time::SystemTime;
+
+#[cfg(feature = "config")]
+use serde::Deserialize;
+
+/// One known-noisy message.
+#[derive(Clone, Debug)]
+pub struct Suppression {
+    pattern: Regex,
+    /// Why this is suppressed (e.g. a ticket reference)
+    pub comment: Option<String>,
+    /// When the suppression stops applying; None for never
+    pub expires: Option<SystemTime>,
+}
+
+impl Suppression {
+    /// Matches whole messages against a template, where `*` matches anything
+    /// (e.g. "usb *: device descriptor read/64, error *").
+    pub fn template(template: &str) -> Result<Suppression, RMesgError> {
+        let pattern = template
+            .split('*')
+            .map(regex::escape)
+            .collect::<Vec<String>>()
+            .join(".*");
+        Suppression::regex(&format!("^{}$", pattern))
+    }
+
+    /// Matches messages against a regex (anywhere in the message, unless anchored).
+    pub fn regex(regex: &str) -> Result<Suppression, RMesgError> {
+        match Regex::new(regex) {
+            Ok(pattern) => Ok(Suppression {
+                pattern,
+                comment: None,
+                expires: None,
+            }),
+            Err(e) => Err(RMesgError::FilterError(format!(
+                "Unable to compile suppression {}: {}",
+                regex, e
+            ))),
+        }
+    }
+
+    /// The regex this matches messages against (templates are compiled to one).
+    pub fn pattern(&self) -> &str {
+        self.pattern.as_str()
+    }
+
+    pub fn with_comment(mut self, comment: &str) -> Suppression {
+        self.comment = Some(comment.to_owned());
+        self
+    }
+
+    pub fn with_expiry(mut self, expires: SystemTime) -> Suppression {
+        self.expires = Some(expires);
+        self
+    }
+
+    /// Whether this applies to `entry` at time `now`.
+    pub fn matches_at(&self, entry: &Entry, now: SystemTime) -> bool {
+        self.expires.is_none_or(|expires| now < expires)
+            && self.pattern.is_match(entry.message.trim_start())
+    }
+}
+
+/// What to do with the entries a suppression list matches.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SuppressionAction {
+    /// Drop them
+    Drop,
+    /// Pass them on, counting them against the suppression that matched
+    Count,
+}
+
+/// A list of suppressions, usable as a stage.
+#[derive(Clone, Debug)]
+pub struct SuppressionList {
+    suppressions: Vec<Suppression>,
+    pub(crate) action: SuppressionAction,
+    pub(crate) counts: Vec<u64>,
+}
+
+impl SuppressionList {
+    pub fn with_options(
+        suppressions: Vec<Suppression>,
+        action: SuppressionAction,
+    ) -> SuppressionList {
+        let counts = vec![0; suppressions.len()];
+        SuppressionList {
+            suppressions,
+            action,
+            counts,
+        }
+    }
+
+    /// Loads a list from TOML, as a list of `[[suppress]]` tables each with either a
+    /// `template` or a `regex`. For example:
+    ///
+    /// ```toml
+    /// [[suppress]]
+    /// template = "usb *: device descriptor read/64, error -71"
+    /// comment = "flaky hub in rack 12"
+    /// expires = "2021-06-30"   # optional; stops applying at the start of this day (UTC)
+    ///
+    /// [[suppress]]
+    /// regex = "^ACPI Error: .*AE_NOT_FOUND"
+    /// ```
+    #[cfg(feature = "config")]
+    pub fn from_toml(toml: &str, action: SuppressionAction) -> Result<SuppressionList, RMesgError> {
+        #[derive(Deserialize)]
+        struct Config {
+            #[serde(default)]
+            suppress: Vec<SuppressionConfig>,
+        }
+        #[derive(Deserialize)]
+        struct SuppressionConfig {
+            template: Option<String>,
+            regex: Option<String>,
+            comment: Option<String>,
+            expires: Option<String>,
+        }
+
+        let config: Config = match toml::from_str(toml) {
+            Ok(config) => config,
+            Err(e) => {
+                return Err(RMesgError::ConfigError(format!(
+                    "Unable to parse suppression list: {}",
+                    e
+                )))
+            }
+        };
+
+        let mut suppressions = Vec::with_capacity(config.suppress.len());
+        for s in config.suppress {
+            let mut suppression = match (s.template, s.regex) {
+                (Some(template), None) => Suppression::template(&template)?,
+                (None, Some(regex)) => Suppression::regex(&regex)?,
+                _ => {
+                    return Err(RMesgError::ConfigError(
+                        "Each suppression needs exactly one of template or regex".to_owned(),
+                    ))
+                }
+            };
+            suppression.comment = s.comment;
+            if let Some(expires) = s.expires {
+                suppression.expires = Some(parse_date(&expires)?);
+            }
+            suppressions.push(suppression);
+        }
+
+        Ok(SuppressionList::with_options(suppressions, action))
+    }
+
+    /// The first suppression applying to `entry` at time `now`.
+    pub fn matching_at(&self, entry: &Entry, now: SystemTime) -> Option<&Suppression> {
+        self.suppressions.iter().find(|s| s.matches_at(entry, now))
+    }
+
+    pub fn matching(&self, entry: &Entry) -> Option<&Suppression> {
+        self.matching_at(entry, SystemTime::now())
+    }
+
+    /// Each suppression, with the number of entries it has matched so far.
+    pub fn counts(&self) -> impl Iterator<Item = (&Suppression, u64)> {
+        self.suppressions.iter().zip(self.counts.iter().copied())
+    }
+
+    /// Suppressions that have expired at time `now` (candidates for removal from the list).
+    pub fn expired_at(&self, now: SystemTime) -> impl Iterator<Item = &Suppression> {
+        self.suppressions
+            .iter()
+            .filter(move |s| s.expires.is_some_and(|expires| now >= expires))
+    }
+}
+
+impl Stage for SuppressionList {
+    fn process(&mut self, entry: Entry) -> Option<Entry> {
+        let now = SystemTime::now();
+        match self
+            .suppressions
+            .iter()
+            .position(|s| s.matches_at(&entry, now))
+        {
+            Some(i) => {
+                self.counts[i] += 1;
+                match self.action {
+                    SuppressionAction::Drop => None,
+                    SuppressionAction::Count => Some(entry),
+                }
+            }
+            None => Some(entry),
+        }
+    }
+}
+
+/// Parses YYYY-MM-DD as midnight UTC of that day.
+#[cfg(feature = "config")]
+fn parse_date(date: &str) -> Result<SystemTime, RMesgError> {
+    let invalid = || {
+        RMesgError::ConfigError(format!(
+            "Invalid expiry date {} (expected YYYY-MM-DD)",
+            date
+        ))
+    };
+
+    let parts: Vec<&str> = date.trim().split('-').collect();
+    if parts.len() != 3 {
+        return Err(invalid());
+    }
+    let year: i64 = parts[0].parse().map_err(|_| invalid())?;
+    let month: u32 = parts[1].parse().map_err(|_| invalid())?;
+    let day: u32 = parts[2].parse().map_err(|_| invalid())?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || year < 1970 {
+        return Err(invalid());
+    }
+
+    Ok(SystemTime::UNIX_EPOCH
+        + std::time::Duration::from_secs(
+            crate::common::days_from_civil(year, month, day) as u64 * 86400,
+        ))
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entry::entry;
+    use std::time::Duration;
+
+    #[test]
+    fn test_templates_and_expiry() {
+        let now = SystemTime::now();
+        let list = SuppressionList::with_options(
+            vec![
+                Suppression::template("usb *: device descriptor read/64, error *")
+                    .unwrap()
+                    .with_comment("flaky hub"),
+                Suppression::regex("AE_NOT_FOUND")
+                    .unwrap()
+                    .with_expiry(now - Duration::from_secs(1)),
+            ],
+            SuppressionAction::Drop,
+        );
+
+        let s = list
+            .matching_at(
+                &entry(" usb 1-1: device descriptor read/64, error -71"),
+                now,
+            )
+            .unwrap();
+        assert_eq!(s.comment.as_deref(), Some("flaky hub"));
+
+        // templates match the whole message, and `.` etc. aren't special
+        assert!(list
+            .matching_at(
+                &entry("usb 1-1: device descriptor read/64, error -71 (again)x"),
+                now
+            )
+            .is_some());
+        assert!(list
+            .matching_at(&entry("usb 1-1: device descriptor readX64, error -71"), now)
+            .is_none());
+
+        // expired
+        let acpi = entry("ACPI Error: AE_NOT_FOUND, While resolving a named reference");
+        assert!(list.matching_at(&acpi, now).is_none());
+        assert!(list
+            .matching_at(&acpi, now - Duration::from_secs(60))
+            .is_some());
+        assert_eq!(list.expired_at(now).count(), 1);
+    }
+
+    #[test]
+    fn test_stage() {
+        let suppressions = vec![Suppression::template("noisy *").unwrap()];
+
+        let mut dropping =
+            SuppressionList::with_options(suppressions.clone(), SuppressionAction::Drop);
+        assert!(dropping.process(entry("noisy driver")).is_none());
+        assert!(dropping.process(entry("quiet driver")).is_some());
+        assert_eq!(dropping.counts().next().unwrap().1, 1);
+
+        let mut counting = SuppressionList::with_options(suppressions, SuppressionAction::Count);
+        assert!(counting.process(entry("noisy driver")).is_some());
+        assert!(counting.process(entry("noisy driver")).is_some());
+        assert_eq!(counting.counts().next().unwrap().1, 2);
+    }
+
+    #[cfg(feature = "config")]
+    #[test]
+    fn test_from_toml() {
+        let list = SuppressionList::from_toml(
+            r#"
+            [[suppress]]
+            template = "usb *: device descriptor read/64, error -71"
+            comment = "flaky hub in rack 12"
+            expires = "2021-06-30"
+
+            [[suppress]]
+            regex = "^ACPI Error: .*AE_NOT_FOUND"
+            "#,
+            SuppressionAction::Drop,
+        )
+        .unwrap();
+
+        let expiry = SystemTime::UNIX_EPOCH + Duration::from_secs(1625011200);
+        let (hub, _) = list.counts().next().unwrap();
+        assert_eq!(hub.expires, Some(expiry));
+        assert_eq!(hub.comment.as_deref(), Some("flaky hub in rack 12"));
+
+        let message = entry("usb 3-2: device descriptor read/64, error -71");
+        assert!(list
+            .matching_at(&message, expiry - Duration::from_secs(1))
+            .is_some());
+        assert!(list.matching_at(&message, expiry).is_none());
+
+        assert!(matches!(
+            SuppressionList::from_toml("[[suppress]]\ncomment = \"x\"", SuppressionAction::Drop),
+            Err(RMesgError::ConfigError(_))
+        ));
+        assert!(matches!(
+            SuppressionList::from_toml(
+                "[[suppress]]\nregex = \"x\"\nexpires = \"June\"",
+                SuppressionAction::Drop
+            ),
+            Err(RMesgError::ConfigError(_))
+        ));
+    }
+}
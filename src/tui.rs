@@ -0,0 +1,513 @@
+use crate::entry::{Entry, LogLevel};
+/// Interactive terminal viewer (`rmesg tui`), a modern replacement for `dmesg | less`.
+///
+/// Entries are followed live as they're logged. Keys:
+///
+/// * Up/Down, PgUp/PgDn, Home/End (or g/G) - move around
+/// * f - toggle following new entries (moving up stops following)
+/// * 0-7 - toggle showing entries at that level (0 is emerg, 7 is debug)
+/// * / - search as you type (see `SearchQuery::parse`), then n/N for the next/previous match
+/// * b/B - jump to the next/previous boot marker ("Linux version ...")
+/// * q - quit
+///
+use crate::error::RMesgError;
+use crate::search::{SearchIndex, SearchQuery};
+use crate::Backend;
+
+use futures::stream::StreamExt;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::Paragraph;
+use ratatui::{DefaultTerminal, Frame};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// Messages that start a boot, for jumping between boots.
+const BOOT_MARKER: &str = "Linux version ";
+
+/// How long to wait for a key before checking for new entries.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug, PartialEq)]
+enum Mode {
+    Normal,
+    /// Typing a search query
+    Search(String),
+}
+
+/// The viewer's state, separate from the terminal so it can be driven by tests.
+pub struct Viewer {
+    index: SearchIndex,
+    mode: Mode,
+    hidden: [bool; 8],
+    /// Positions (into the index) of the entries shown, in order
+    visible: Vec<usize>,
+    /// Index into `visible` of the selected entry
+    selected: usize,
+    follow: bool,
+    query: Option<SearchQuery>,
+    matches: Vec<usize>,
+    status: Option<String>,
+}
+
+impl Viewer {
+    pub fn new(entries: Vec<Entry>) -> Viewer {
+        let mut viewer = Viewer {
+            index: SearchIndex::new(entries),
+            mode: Mode::Normal,
+            hidden: [false; 8],
+            visible: Vec::new(),
+            selected: 0,
+            follow: true,
+            query: None,
+            matches: Vec::new(),
+            status: None,
+        };
+        viewer.refresh();
+        viewer.select_last();
+        viewer
+    }
+
+    pub fn push(&mut self, entry: Entry) {
+        let position = self.index.len();
+        let shown = self.is_shown(&entry);
+        let matched = self.query.as_ref().is_some_and(|q| q.matches(&entry));
+        self.index.push(entry);
+
+        if shown {
+            self.visible.push(position);
+        }
+        if matched {
+            self.matches.push(position);
+        }
+        if self.follow {
+            self.select_last();
+        }
+    }
+
+    /// Handles a key press, returning false when the viewer should exit.
+    pub fn handle_key(&mut self, key: KeyEvent) -> bool {
+        if let Mode::Search(input) = &mut self.mode {
+            match key.code {
+                KeyCode::Esc => {
+                    self.mode = Mode::Normal;
+                    self.set_query(None);
+                }
+                KeyCode::Enter => self.mode = Mode::Normal,
+                KeyCode::Backspace => {
+                    input.pop();
+                    let input = input.clone();
+                    self.search(&input);
+                }
+                KeyCode::Char(c) => {
+                    input.push(c);
+                    let input = input.clone();
+                    self.search(&input);
+                }
+                _ => {}
+            }
+            return true;
+        }
+
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Up | KeyCode::Char('k') => self.move_by(-1),
+            KeyCode::Down | KeyCode::Char('j') => self.move_by(1),
+            KeyCode::PageUp => self.move_by(-20),
+            KeyCode::PageDown => self.move_by(20),
+            KeyCode::Home | KeyCode::Char('g') => {
+                self.follow = false;
+                self.selected = 0;
+            }
+            KeyCode::End | KeyCode::Char('G') => self.select_last(),
+            KeyCode::Char('f') => {
+                self.follow = !self.follow;
+                if self.follow {
+                    self.select_last();
+                }
+            }
+            KeyCode::Char('/') => self.mode = Mode::Search(String::new()),
+            KeyCode::Char('n') => self.jump(true, |v, p| v.matches.binary_search(&p).is_ok()),
+            KeyCode::Char('N') => self.jump(false, |v, p| v.matches.binary_search(&p).is_ok()),
+            KeyCode::Char('b') => self.jump(true, is_boot_marker),
+            KeyCode::Char('B') => self.jump(false, is_boot_marker),
+            KeyCode::Char(c @ '0'..='7') => {
+                let level = c as usize - '0' as usize;
+                self.hidden[level] = !self.hidden[level];
+                self.refresh();
+            }
+            _ => {}
+        }
+        true
+    }
+
+    /// The entry currently selected, if any are shown.
+    pub fn selected(&self) -> Option<&Entry> {
+        self.visible
+            .get(self.selected)
+            .and_then(|p| self.index.get(*p))
+    }
+
+    pub fn set_status(&mut self, status: String) {
+        self.status = Some(status);
+    }
+
+    fn is_shown(&self, entry: &Entry) -> bool {
+        entry.level.is_none_or(|level| !self.hidden[level as usize])
+    }
+
+    // Recomputes what's shown, keeping the same entry selected where possible.
+    fn refresh(&mut self) {
+        let selected = self.visible.get(self.selected).copied();
+        self.visible = (0..self.index.len())
+            .filter(|p| self.index.get(*p).is_some_and(|e| self.is_shown(e)))
+            .collect();
+        self.selected = match selected {
+            Some(p) => match self.visible.binary_search(&p) {
+                Ok(i) | Err(i) => i.min(self.visible.len().saturating_sub(1)),
+            },
+            None => 0,
+        };
+        if self.follow {
+            self.select_last();
+        }
+    }
+
+    fn select_last(&mut self) {
+        self.follow = true;
+        self.selected = self.visible.len().saturating_sub(1);
+    }
+
+    fn move_by(&mut self, delta: isize) {
+        if delta < 0 {
+            self.follow = false;
+        }
+        let last = self.visible.len().saturating_sub(1);
+        self.selected = (self.selected as isize + delta).clamp(0, last as isize) as usize;
+    }
+
+    fn search(&mut self, input: &str) {
+        if input.trim().is_empty() {
+            self.set_query(None);
+            return;
+        }
+        match SearchQuery::parse(input) {
+            Ok(query) => {
+                self.status = None;
+                self.set_query(Some(query));
+                // jump to the first match at or after the selection, as less does
+                if !self.selected().is_some_and(|_| {
+                    self.matches
+                        .binary_search(&self.visible[self.selected])
+                        .is_ok()
+                }) {
+                    self.jump(true, |v, p| v.matches.binary_search(&p).is_ok());
+                }
+            }
+            Err(e) => self.status = Some(e.to_string()),
+        }
+    }
+
+    fn set_query(&mut self, query: Option<SearchQuery>) {
+        self.matches = match &query {
+            Some(query) => self.index.search(query),
+            None => Vec::new(),
+        };
+        self.query = query;
+    }
+
+    // Selects the next (or previous) shown entry that satisfies `found`.
+    fn jump<F: Fn(&Viewer, usize) -> bool>(&mut self, forward: bool, found: F) {
+        let candidates: Vec<usize> = match forward {
+            true => (self.selected + 1..self.visible.len()).collect(),
+            false => (0..self.selected).rev().collect(),
+        };
+        match candidates
+            .into_iter()
+            .find(|i| found(self, self.visible[*i]))
+        {
+            Some(i) => {
+                self.follow = false;
+                self.selected = i;
+            }
+            None => self.status = Some("No more matches".to_owned()),
+        }
+    }
+
+    fn render(&self, frame: &mut Frame) {
+        let [list_area, status_area] =
+            Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(frame.area());
+
+        let height = list_area.height as usize;
+        let top = (self.selected + 1).saturating_sub(height);
+        let lines: Vec<Line> = self
+            .visible
+            .iter()
+            .enumerate()
+            .skip(top)
+            .take(height)
+            .filter_map(|(i, p)| {
+                let entry = self.index.get(*p)?;
+                let mut style = level_style(entry.level);
+                if self.matches.binary_search(p).is_ok() {
+                    style = style.add_modifier(Modifier::BOLD | Modifier::UNDERLINED);
+                }
+                if i == self.selected {
+                    style = style.add_modifier(Modifier::REVERSED);
+                }
+                Some(Line::styled(entry.to_string(), style))
+            })
+            .collect();
+        frame.render_widget(Paragraph::new(lines), list_area);
+
+        frame.render_widget(Paragraph::new(self.status_line()), status_area);
+    }
+
+    fn status_line(&self) -> Line<'_> {
+        if let Mode::Search(input) = &self.mode {
+            return Line::from(vec![
+                Span::raw("/"),
+                Span::raw(input.clone()),
+                Span::styled(
+                    format!("  ({} matches)", self.matches.len()),
+                    Style::default().fg(Color::DarkGray),
+                ),
+            ]);
+        }
+
+        let levels: String = (0..8)
+            .map(|l| match self.hidden[l] {
+                true => '-',
+                false => char::from(b'0' + l as u8),
+            })
+            .collect();
+        let mut status = format!(
+            "{}/{}  levels [{}]  {}",
+            self.visible.len().min(self.selected + 1),
+            self.visible.len(),
+            levels,
+            match self.follow {
+                true => "following",
+                false => "paused (f to follow)",
+            }
+        );
+        if self.query.is_some() {
+            status.push_str(&format!("  {} matches (n/N)", self.matches.len()));
+        }
+        if let Some(message) = &self.status {
+            status.push_str("  ");
+            status.push_str(message);
+        }
+        Line::styled(status, Style::default().add_modifier(Modifier::REVERSED))
+    }
+}
+
+fn is_boot_marker(viewer: &Viewer, position: usize) -> bool {
+    viewer
+        .index
+        .get(position)
+        .is_some_and(|e| e.message.starts_with(BOOT_MARKER))
+}
+
+fn level_style(level: Option<LogLevel>) -> Style {
+    let style = Style::default();
+    match level {
+        Some(LogLevel::Emergency) | Some(LogLevel::Alert) | Some(LogLevel::Critical) => {
+            style.fg(Color::Red).add_modifier(Modifier::BOLD)
+        }
+        Some(LogLevel::Error) => style.fg(Color::Red),
+        Some(LogLevel::Warning) => style.fg(Color::Yellow),
+        Some(LogLevel::Notice) => style.fg(Color::Cyan),
+        Some(LogLevel::Debug) => style.fg(Color::DarkGray),
+        _ => style,
+    }
+}
+
+/// Runs the viewer on the terminal until the user quits, following `backend`.
+pub fn run(backend: Backend) -> Result<(), RMesgError> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || follow(backend, sender));
+
+    let mut terminal = ratatui::try_init()?;
+    let result = event_loop(&mut terminal, Viewer::new(Vec::new()), receiver);
+    ratatui::restore();
+    result
+}
+
+fn event_loop(
+    terminal: &mut DefaultTerminal,
+    mut viewer: Viewer,
+    entries: mpsc::Receiver<Result<Entry, RMesgError>>,
+) -> Result<(), RMesgError> {
+    loop {
+        while let Ok(entry) = entries.try_recv() {
+            match entry {
+                Ok(entry) => viewer.push(entry),
+                Err(e) => viewer.set_status(e.to_string()),
+            }
+        }
+
+        terminal.draw(|frame| viewer.render(frame))?;
+
+        if event::poll(POLL_INTERVAL)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && !viewer.handle_key(key) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+// Reads the backend's stream (which starts with the entries already in the buffer)
+// on its own runtime, so the terminal can be driven synchronously.
+fn follow(backend: Backend, sender: mpsc::Sender<Result<Entry, RMesgError>>) {
+    let runtime = match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            let _ = sender.send(Err(e.into()));
+            return;
+        }
+    };
+
+    runtime.block_on(async {
+        let mut entries = match crate::logs_stream(backend, false, false).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                let _ = sender.send(Err(e));
+                return;
+            }
+        };
+        while let Some(entry) = entries.next().await {
+            // the viewer has exited
+            if sender.send(entry).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(level: LogLevel, message: &str) -> Entry {
+        Entry {
+            level: Some(level),
+            message: message.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::from(code)
+    }
+
+    fn type_keys(viewer: &mut Viewer, keys: &str) {
+        for c in keys.chars() {
+            viewer.handle_key(key(KeyCode::Char(c)));
+        }
+    }
+
+    fn viewer() -> Viewer {
+        Viewer::new(vec![
+            entry(LogLevel::Notice, "Linux version 5.10.0"),
+            entry(LogLevel::Info, "usb 1-1: new high-speed USB device"),
+            entry(LogLevel::Error, "nvme nvme0: I/O 12 QID 3 timeout"),
+            entry(LogLevel::Debug, "PM: debug message"),
+            entry(LogLevel::Notice, "Linux version 5.15.0"),
+            entry(LogLevel::Error, "nvme nvme0: Abort status: 0x0"),
+        ])
+    }
+
+    #[test]
+    fn test_follow_and_movement() {
+        let mut viewer = viewer();
+        assert_eq!(
+            viewer.selected().unwrap().message,
+            "nvme nvme0: Abort status: 0x0"
+        );
+
+        viewer.handle_key(key(KeyCode::Up));
+        assert!(!viewer.follow);
+        viewer.push(entry(LogLevel::Info, "new"));
+        assert_eq!(viewer.selected().unwrap().message, "Linux version 5.15.0");
+
+        viewer.handle_key(key(KeyCode::Char('f')));
+        assert_eq!(viewer.selected().unwrap().message, "new");
+        viewer.push(entry(LogLevel::Info, "newer"));
+        assert_eq!(viewer.selected().unwrap().message, "newer");
+
+        viewer.handle_key(key(KeyCode::Home));
+        viewer.handle_key(key(KeyCode::Up));
+        assert_eq!(viewer.selected().unwrap().message, "Linux version 5.10.0");
+        assert!(!viewer.handle_key(key(KeyCode::Char('q'))));
+    }
+
+    #[test]
+    fn test_level_toggles() {
+        let mut viewer = viewer();
+        viewer.handle_key(key(KeyCode::Home));
+        type_keys(&mut viewer, "67");
+        assert_eq!(viewer.visible.len(), 4);
+        viewer.handle_key(key(KeyCode::Down));
+        assert_eq!(
+            viewer.selected().unwrap().message,
+            "nvme nvme0: I/O 12 QID 3 timeout"
+        );
+
+        // hidden entries stay hidden as they arrive
+        viewer.push(entry(LogLevel::Debug, "more debug"));
+        assert_eq!(viewer.visible.len(), 4);
+        type_keys(&mut viewer, "7");
+        assert_eq!(viewer.visible.len(), 6);
+    }
+
+    #[test]
+    fn test_search_and_boot_markers() {
+        let mut viewer = viewer();
+        viewer.handle_key(key(KeyCode::Home));
+
+        type_keys(&mut viewer, "/nvm");
+        assert_eq!(viewer.matches, vec![2, 5]);
+        assert_eq!(
+            viewer.selected().unwrap().message,
+            "nvme nvme0: I/O 12 QID 3 timeout"
+        );
+        viewer.handle_key(key(KeyCode::Enter));
+
+        type_keys(&mut viewer, "n");
+        assert_eq!(
+            viewer.selected().unwrap().message,
+            "nvme nvme0: Abort status: 0x0"
+        );
+        type_keys(&mut viewer, "N");
+        assert_eq!(
+            viewer.selected().unwrap().message,
+            "nvme nvme0: I/O 12 QID 3 timeout"
+        );
+
+        // new entries that match are found too
+        viewer.push(entry(LogLevel::Error, "nvme nvme0: controller is down"));
+        assert_eq!(viewer.matches, vec![2, 5, 6]);
+
+        type_keys(&mut viewer, "B");
+        assert_eq!(viewer.selected().unwrap().message, "Linux version 5.10.0");
+        type_keys(&mut viewer, "b");
+        assert_eq!(viewer.selected().unwrap().message, "Linux version 5.15.0");
+
+        type_keys(&mut viewer, "/level:");
+        assert!(viewer.status.is_some());
+        viewer.handle_key(key(KeyCode::Esc));
+        assert!(viewer.matches.is_empty());
+    }
+}
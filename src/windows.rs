@@ -0,0 +1,590 @@
+use crate::common;
+/// Windows implementation: reads kernel events from the System event log through the
+/// Windows Event Log API (wevtapi), as `wevtutil qe System` does.
+///
+/// Windows has no kernel ring buffer; the closest equivalent is what the kernel and its
+/// core drivers log to the System channel. Only events from the providers in
+/// `KERNEL_PROVIDERS` are read. Each event becomes an `Entry` with its message formatted
+/// by its provider (or its event data, when the provider's message table isn't around),
+/// prefixed with the provider and event ID like a syslog tag
+/// (`Microsoft-Windows-Kernel-Power[42]: The system is entering sleep.`), its record ID
+/// as the sequence number, and the time since this boot for events logged during it.
+///
+/// Clearing isn't supported, since it would clear the whole System log rather than just
+/// the kernel's events. On other platforms this module still builds, but reading fails
+/// with `RMesgError::NotImplementedForThisPlatform`.
+///
+use crate::entry::{Entry, EntryParsingError, LogFacility, LogLevel};
+use crate::error::RMesgError;
+use crate::provenance::{self, SourceBackend};
+use crate::source::KLogSource;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The channel kernel events are logged to.
+pub const SYSTEM_CHANNEL: &str = "System";
+
+/// The providers whose events are taken to be the kernel's.
+pub const KERNEL_PROVIDERS: [&str; 10] = [
+    "Microsoft-Windows-Kernel-General",
+    "Microsoft-Windows-Kernel-Boot",
+    "Microsoft-Windows-Kernel-Power",
+    "Microsoft-Windows-Kernel-Processor-Power",
+    "Microsoft-Windows-Kernel-PnP",
+    "Microsoft-Windows-Kernel-EventTracing",
+    "Microsoft-Windows-WHEA-Logger",
+    "Microsoft-Windows-Ntfs",
+    "disk",
+    "volmgr",
+];
+
+lazy_static! {
+    // the fields of interest in an event as rendered to XML (EvtRenderEventXml), e.g.
+    // <Provider Name='Microsoft-Windows-Kernel-Power' Guid='{...}'/><EventID>42</EventID>
+    // <Level>4</Level><TimeCreated SystemTime='2024-01-02T03:04:05.1234567Z'/>
+    // <EventRecordID>1234</EventRecordID>
+    static ref RE_PROVIDER: Regex = Regex::new(r#"<Provider\s+Name=['"](?P<name>[^'"]*)['"]"#).unwrap();
+    static ref RE_EVENT_ID: Regex = Regex::new(r"<EventID(?:\s[^>]*)?>(?P<id>\d+)</EventID>").unwrap();
+    static ref RE_LEVEL: Regex = Regex::new(r"<Level>(?P<level>\d+)</Level>").unwrap();
+    static ref RE_TIME_CREATED: Regex = Regex::new(
+        r#"<TimeCreated\s+SystemTime=['"](?P<year>\d{4})-(?P<month>\d{2})-(?P<day>\d{2})T(?P<hour>\d{2}):(?P<minute>\d{2}):(?P<second>\d{2})(?:\.(?P<fraction>\d+))?Z['"]"#
+    )
+    .unwrap();
+    static ref RE_RECORD_ID: Regex = Regex::new(r"<EventRecordID>(?P<id>\d+)</EventRecordID>").unwrap();
+    static ref RE_DATA: Regex = Regex::new(
+        r#"<Data(?:\s+Name=['"](?P<name>[^'"]*)['"])?\s*(?:/>|>(?P<value>[^<]*)</Data>)"#
+    )
+    .unwrap();
+}
+
+/// The XPath query selecting kernel events from the System channel, only those after the
+/// record `after` when set.
+pub fn kernel_query(after: Option<u64>) -> String {
+    let providers = KERNEL_PROVIDERS
+        .iter()
+        .map(|p| format!("@Name='{}'", p))
+        .collect::<Vec<_>>()
+        .join(" or ");
+    match after {
+        Some(record) => format!(
+            "*[System[Provider[{}] and EventRecordID > {}]]",
+            providers, record
+        ),
+        None => format!("*[System[Provider[{}]]]", providers),
+    }
+}
+
+/// An event as read from the log: its XML, and its message as formatted by its provider.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RawEvent {
+    pub xml: String,
+    pub message: Option<String>,
+}
+
+/// The kernel events in the System log as XML, one per line. Clearing isn't supported.
+pub fn event_log_raw(clear: bool) -> Result<String, RMesgError> {
+    if clear {
+        return Err(clear_unsupported());
+    }
+    Ok(read_events(None)?
+        .into_iter()
+        .map(|event| format!("{}\n", event.xml))
+        .collect())
+}
+
+/// The kernel events in the System log. Clearing isn't supported.
+pub fn event_log(clear: bool) -> Result<Vec<Entry>, RMesgError> {
+    if clear {
+        return Err(clear_unsupported());
+    }
+    entries_from_events(read_events(None)?)
+}
+
+pub fn event_log_count() -> Result<usize, RMesgError> {
+    Ok(read_events(None)?.len())
+}
+
+fn entries_from_events(events: Vec<RawEvent>) -> Result<Vec<Entry>, RMesgError> {
+    let boot_time = boot_time();
+    let entries: Result<Vec<Entry>, EntryParsingError> = events
+        .iter()
+        .map(|event| entry_from_event_xml(&event.xml, event.message.as_deref(), boot_time))
+        .collect();
+    Ok(entries?)
+}
+
+fn clear_unsupported() -> RMesgError {
+    RMesgError::BackendUnavailable(
+        "Clearing would clear the whole System event log, so rmesg doesn't".to_owned(),
+    )
+}
+
+/// Maps an event, as rendered to XML, to an `Entry`. `message` is the event's formatted
+/// message; without one, the event data is used (as `name=value` pairs). When
+/// `boot_time` is known, events logged since get a time since system start.
+pub fn entry_from_event_xml(
+    xml: &str,
+    message: Option<&str>,
+    boot_time: Option<SystemTime>,
+) -> Result<Entry, EntryParsingError> {
+    let provider = match RE_PROVIDER.captures(xml) {
+        Some(caps) => unescape(&caps["name"]),
+        None => {
+            return Err(EntryParsingError::Generic(format!(
+                "Event has no provider: {}",
+                xml
+            )))
+        }
+    };
+    let event_id: Option<u32> = match RE_EVENT_ID.captures(xml) {
+        Some(caps) => Some(common::parse_fragment(&caps["id"], xml)?),
+        None => None,
+    };
+    let level = match RE_LEVEL.captures(xml) {
+        Some(caps) => level_from_windows(common::parse_fragment(&caps["level"], xml)?),
+        None => None,
+    };
+    let sequence_num = match RE_RECORD_ID.captures(xml) {
+        Some(caps) => Some(common::parse_fragment(&caps["id"], xml)?),
+        None => None,
+    };
+    let timestamp_realtime = match RE_TIME_CREATED.captures(xml) {
+        Some(caps) => Some(system_time(&caps, xml)?),
+        None => None,
+    };
+    let timestamp_from_system_start = match (timestamp_realtime, boot_time) {
+        (Some(logged), Some(boot)) => logged.duration_since(boot).ok(),
+        _ => None,
+    };
+
+    let text = match message {
+        Some(message) => message.trim_end().to_owned(),
+        None => RE_DATA
+            .captures_iter(xml)
+            .map(|caps| {
+                let value = caps.name("value").map_or("", |v| v.as_str());
+                match caps.name("name") {
+                    Some(name) => format!("{}={}", name.as_str(), unescape(value)),
+                    None => unescape(value),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" "),
+    };
+    let message = match event_id {
+        Some(id) => format!("{}[{}]: {}", provider, id, text),
+        None => format!("{}: {}", provider, text),
+    };
+
+    Ok(Entry {
+        facility: Some(LogFacility::Kern),
+        level,
+        sequence_num,
+        timestamp_from_system_start,
+        message,
+        timestamp_realtime,
+        provenance: None,
+        malformed: false,
+        priority: None,
+        tags: Default::default(),
+    })
+}
+
+/// Event log levels (winmeta.xml) as log levels. 0 is "log always", with no severity.
+fn level_from_windows(level: u32) -> Option<LogLevel> {
+    match level {
+        1 => Some(LogLevel::Critical),
+        2 => Some(LogLevel::Error),
+        3 => Some(LogLevel::Warning),
+        0 | 4 => Some(LogLevel::Info),
+        // values above 5 are provider-defined, and more verbose still
+        _ => Some(LogLevel::Debug),
+    }
+}
+
+fn system_time(caps: &regex::Captures, xml: &str) -> Result<SystemTime, EntryParsingError> {
+    let field =
+        |name: &str| -> Result<u64, EntryParsingError> { common::parse_fragment(&caps[name], xml) };
+    let days = common::days_from_civil(
+        field("year")? as i64,
+        field("month")? as u32,
+        field("day")? as u32,
+    );
+    if days < 0 {
+        return Err(EntryParsingError::Generic(format!(
+            "Event logged before 1970: {}",
+            xml
+        )));
+    }
+    let secs =
+        days as u64 * 86_400 + field("hour")? * 3600 + field("minute")? * 60 + field("second")?;
+    // a fraction of a second, in as many digits as the log has (7, for 100ns ticks)
+    let nanos = match caps.name("fraction") {
+        Some(fraction) => {
+            let digits: String = fraction
+                .as_str()
+                .chars()
+                .chain("000000000".chars())
+                .take(9)
+                .collect();
+            common::parse_fragment::<u32>(&digits, xml)?
+        }
+        None => 0,
+    };
+    Ok(UNIX_EPOCH + Duration::new(secs, nanos))
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(windows)]
+mod ffi {
+    use std::ffi::c_void;
+
+    pub type EvtHandle = isize;
+
+    pub const EVT_QUERY_CHANNEL_PATH: u32 = 0x1;
+    pub const EVT_QUERY_FORWARD_DIRECTION: u32 = 0x100;
+    pub const EVT_RENDER_EVENT_XML: u32 = 1;
+    pub const EVT_FORMAT_MESSAGE_EVENT: u32 = 1;
+    pub const ERROR_NO_MORE_ITEMS: i32 = 259;
+    pub const INFINITE: u32 = 0xffff_ffff;
+
+    #[link(name = "wevtapi")]
+    extern "system" {
+        pub fn EvtQuery(
+            session: EvtHandle,
+            path: *const u16,
+            query: *const u16,
+            flags: u32,
+        ) -> EvtHandle;
+        pub fn EvtNext(
+            result_set: EvtHandle,
+            events_size: u32,
+            events: *mut EvtHandle,
+            timeout: u32,
+            flags: u32,
+            returned: *mut u32,
+        ) -> i32;
+        pub fn EvtRender(
+            context: EvtHandle,
+            fragment: EvtHandle,
+            flags: u32,
+            buffer_size: u32,
+            buffer: *mut c_void,
+            buffer_used: *mut u32,
+            property_count: *mut u32,
+        ) -> i32;
+        pub fn EvtOpenPublisherMetadata(
+            session: EvtHandle,
+            publisher_id: *const u16,
+            log_file_path: *const u16,
+            locale: u32,
+            flags: u32,
+        ) -> EvtHandle;
+        pub fn EvtFormatMessage(
+            publisher_metadata: EvtHandle,
+            event: EvtHandle,
+            message_id: u32,
+            value_count: u32,
+            values: *const c_void,
+            flags: u32,
+            buffer_size: u32,
+            buffer: *mut u16,
+            buffer_used: *mut u32,
+        ) -> i32;
+        pub fn EvtClose(object: EvtHandle) -> i32;
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        pub fn GetTickCount64() -> u64;
+    }
+}
+
+#[cfg(windows)]
+fn read_events(after: Option<u64>) -> Result<Vec<RawEvent>, RMesgError> {
+    use std::collections::HashMap;
+    use std::ptr;
+
+    let wide = |s: &str| -> Vec<u16> { s.encode_utf16().chain(std::iter::once(0)).collect() };
+    let channel = wide(SYSTEM_CHANNEL);
+    let query = wide(&kernel_query(after));
+
+    let results = unsafe {
+        ffi::EvtQuery(
+            0,
+            channel.as_ptr(),
+            query.as_ptr(),
+            ffi::EVT_QUERY_CHANNEL_PATH | ffi::EVT_QUERY_FORWARD_DIRECTION,
+        )
+    };
+    if results == 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    // one metadata handle per provider, for formatting messages (0 if it can't be opened)
+    let mut publishers: HashMap<String, ffi::EvtHandle> = HashMap::new();
+    let mut events = Vec::new();
+    let read = loop {
+        let mut handles = [0 as ffi::EvtHandle; 64];
+        let mut returned = 0u32;
+        if unsafe {
+            ffi::EvtNext(
+                results,
+                handles.len() as u32,
+                handles.as_mut_ptr(),
+                ffi::INFINITE,
+                0,
+                &mut returned,
+            )
+        } == 0
+        {
+            let e = std::io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(ffi::ERROR_NO_MORE_ITEMS) => break Ok(()),
+                _ => break Err(e),
+            }
+        }
+
+        let mut unrendered = None;
+        for handle in &handles[..returned as usize] {
+            let xml = unsafe { render_xml(*handle) };
+            let message = match &xml {
+                Ok(xml) => RE_PROVIDER.captures(xml).and_then(|caps| {
+                    let publisher =
+                        *publishers
+                            .entry(caps["name"].to_owned())
+                            .or_insert_with(|| unsafe {
+                                ffi::EvtOpenPublisherMetadata(
+                                    0,
+                                    wide(&caps["name"]).as_ptr(),
+                                    ptr::null(),
+                                    0,
+                                    0,
+                                )
+                            });
+                    unsafe { format_message(publisher, *handle) }
+                }),
+                Err(_) => None,
+            };
+            unsafe { ffi::EvtClose(*handle) };
+            match xml {
+                Ok(xml) => events.push(RawEvent { xml, message }),
+                // the rest of the handles still need closing
+                Err(e) => {
+                    unrendered.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(e) = unrendered {
+            break Err(e);
+        }
+    };
+
+    for publisher in publishers.values().filter(|p| **p != 0) {
+        unsafe { ffi::EvtClose(*publisher) };
+    }
+    unsafe { ffi::EvtClose(results) };
+    read?;
+    Ok(events)
+}
+
+#[cfg(windows)]
+unsafe fn render_xml(event: ffi::EvtHandle) -> Result<String, std::io::Error> {
+    let mut used = 0u32;
+    let mut properties = 0u32;
+    // the first call fails, saying how big a buffer is needed (in bytes)
+    ffi::EvtRender(
+        0,
+        event,
+        ffi::EVT_RENDER_EVENT_XML,
+        0,
+        std::ptr::null_mut(),
+        &mut used,
+        &mut properties,
+    );
+    let mut buffer = vec![0u16; used as usize / 2 + 1];
+    if ffi::EvtRender(
+        0,
+        event,
+        ffi::EVT_RENDER_EVENT_XML,
+        (buffer.len() * 2) as u32,
+        buffer.as_mut_ptr() as *mut std::ffi::c_void,
+        &mut used,
+        &mut properties,
+    ) == 0
+    {
+        return Err(std::io::Error::last_os_error());
+    }
+    buffer.truncate(used as usize / 2);
+    Ok(String::from_utf16_lossy(&buffer)
+        .trim_end_matches('\0')
+        .to_owned())
+}
+
+#[cfg(windows)]
+unsafe fn format_message(publisher: ffi::EvtHandle, event: ffi::EvtHandle) -> Option<String> {
+    if publisher == 0 {
+        return None;
+    }
+    let mut used = 0u32;
+    // the first call fails, saying how big a buffer is needed (in characters)
+    ffi::EvtFormatMessage(
+        publisher,
+        event,
+        0,
+        0,
+        std::ptr::null(),
+        ffi::EVT_FORMAT_MESSAGE_EVENT,
+        0,
+        std::ptr::null_mut(),
+        &mut used,
+    );
+    let mut buffer = vec![0u16; used as usize + 1];
+    if ffi::EvtFormatMessage(
+        publisher,
+        event,
+        0,
+        0,
+        std::ptr::null(),
+        ffi::EVT_FORMAT_MESSAGE_EVENT,
+        buffer.len() as u32,
+        buffer.as_mut_ptr(),
+        &mut used,
+    ) == 0
+    {
+        return None;
+    }
+    buffer.truncate(used as usize);
+    Some(
+        String::from_utf16_lossy(&buffer)
+            .trim_end_matches('\0')
+            .to_owned(),
+    )
+}
+
+#[cfg(windows)]
+fn boot_time() -> Option<SystemTime> {
+    let since_boot = Duration::from_millis(unsafe { ffi::GetTickCount64() });
+    SystemTime::now().checked_sub(since_boot)
+}
+
+#[cfg(not(windows))]
+fn read_events(_after: Option<u64>) -> Result<Vec<RawEvent>, RMesgError> {
+    Err(RMesgError::NotImplementedForThisPlatform)
+}
+
+#[cfg(not(windows))]
+fn boot_time() -> Option<SystemTime> {
+    None
+}
+
+/// The kernel's events in the System log as a `KLogSource`, for following with
+/// `source::SourceEntries`. New events are the ones after the last record seen.
+#[derive(Debug, Default)]
+pub struct EventLogSource {
+    last_record: Option<u64>,
+}
+
+impl EventLogSource {
+    fn read(&mut self, after: Option<u64>) -> Result<Vec<Entry>, RMesgError> {
+        let entries = entries_from_events(read_events(after)?)?;
+        if let Some(last) = entries.iter().filter_map(|e| e.sequence_num).max() {
+            self.last_record = Some(last as u64);
+        }
+        Ok(entries
+            .into_iter()
+            .map(|e| provenance::tag(e, SourceBackend::EventLog))
+            .collect())
+    }
+}
+
+impl KLogSource for EventLogSource {
+    fn read_all(&mut self) -> Result<Vec<Entry>, RMesgError> {
+        self.read(None)
+    }
+
+    fn poll_new(&mut self) -> Result<Vec<Entry>, RMesgError> {
+        self.read(self.last_record)
+    }
+
+    fn clear(&mut self) -> Result<(), RMesgError> {
+        Err(clear_unsupported())
+    }
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const POWER_EVENT: &str = "<Event xmlns='http://schemas.microsoft.com/win/2004/08/events/event'><System>\
+        <Provider Name='Microsoft-Windows-Kernel-Power' Guid='{331c3b3a-2005-44c2-ac5e-77220c37d6b4}'/>\
+        <EventID>42</EventID><Version>3</Version><Level>4</Level><Task>64</Task>\
+        <TimeCreated SystemTime='2024-01-02T03:04:05.1234567Z'/><EventRecordID>1234</EventRecordID>\
+        <Channel>System</Channel><Computer>host</Computer></System>\
+        <EventData><Data Name='TargetState'>4</Data><Data Name='Reason'>7 &amp; up</Data></EventData></Event>";
+
+    #[test]
+    fn test_entry_from_event_xml() {
+        let boot = UNIX_EPOCH + Duration::from_secs(1_704_164_645);
+        let entry = entry_from_event_xml(
+            POWER_EVENT,
+            Some("The system is entering sleep.\r\n"),
+            Some(boot),
+        )
+        .unwrap();
+        assert_eq!(entry.level, Some(LogLevel::Info));
+        assert_eq!(entry.sequence_num, Some(1234));
+        assert_eq!(
+            entry.message,
+            "Microsoft-Windows-Kernel-Power[42]: The system is entering sleep."
+        );
+        assert_eq!(entry.subsystem(), Some("Microsoft-Windows-Kernel-Power"));
+        assert_eq!(
+            entry.timestamp_realtime,
+            Some(UNIX_EPOCH + Duration::new(1_704_164_645, 123_456_700))
+        );
+        assert_eq!(
+            entry.timestamp_from_system_start,
+            Some(Duration::from_nanos(123_456_700))
+        );
+
+        // without a formatted message, the event data stands in
+        let entry = entry_from_event_xml(POWER_EVENT, None, None).unwrap();
+        assert_eq!(
+            entry.message,
+            "Microsoft-Windows-Kernel-Power[42]: TargetState=4 Reason=7 & up"
+        );
+        assert_eq!(entry.timestamp_from_system_start, None);
+
+        assert!(entry_from_event_xml("<Event></Event>", None, None).is_err());
+    }
+
+    #[test]
+    fn test_kernel_query() {
+        assert!(kernel_query(None)
+            .starts_with("*[System[Provider[@Name='Microsoft-Windows-Kernel-General' or "));
+        assert!(kernel_query(Some(99)).ends_with("] and EventRecordID > 99]]"));
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_unsupported_platform() {
+        assert!(matches!(
+            event_log(false),
+            Err(RMesgError::NotImplementedForThisPlatform)
+        ));
+        assert!(event_log(true).is_err());
+    }
+}
@@ -0,0 +1,151 @@
+use crate::degradation::Capability;
+/// Windows Subsystem for Linux: what of the kernel log there is, and what there isn't.
+///
+/// WSL1 translates Linux system calls inside Windows: there's no Linux kernel, so no
+/// /dev/kmsg, and klogctl fails with ENOSYS. WSL2 runs a real kernel in a lightweight VM,
+/// whose log reads like any other, but only starts systemd (and so journald) when
+/// `/etc/wsl.conf` asks for it. Rather than report whatever errno each backend then fails
+/// with, `Backend::Default` and `degradation::probe` say what WSL doesn't have.
+///
+use crate::error::RMesgError;
+use crate::Backend;
+
+use lazy_static::lazy_static;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::path::Path;
+
+const OSRELEASE: &str = "/proc/sys/kernel/osrelease";
+
+const NO_KERNEL: &str = "WSL1 has no Linux kernel, so there's no kernel log to read \
+                         (convert the distribution with `wsl --set-version <distro> 2` for one)";
+const NO_SYSTEMD: &str = "systemd isn't running, so the journal has no kernel messages \
+                          (WSL2 only starts it with `systemd=true` under [boot] in /etc/wsl.conf)";
+
+lazy_static! {
+    static ref WSL: Option<Wsl> = std::fs::read_to_string(OSRELEASE)
+        .ok()
+        .and_then(|release| Wsl::from_osrelease(&release));
+}
+
+/// Which WSL this is running under, if any.
+pub fn detect() -> Option<Wsl> {
+    *WSL
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Wsl {
+    Wsl1,
+    Wsl2,
+}
+
+impl Wsl {
+    /// Reads a kernel release (as in /proc/sys/kernel/osrelease): "4.4.0-19041-Microsoft"
+    /// on WSL1, "5.15.153.1-microsoft-standard-WSL2" on WSL2.
+    pub fn from_osrelease(release: &str) -> Option<Wsl> {
+        let release = release.trim().to_ascii_lowercase();
+        if !release.contains("microsoft") {
+            return None;
+        }
+        match release.contains("wsl2") || release.contains("microsoft-standard") {
+            true => Some(Wsl::Wsl2),
+            false => Some(Wsl::Wsl1),
+        }
+    }
+
+    /// Why `capability` can't work here, when that's down to WSL.
+    pub fn limitation(self, capability: Capability) -> Option<&'static str> {
+        match (self, capability) {
+            (Wsl::Wsl1, _) => Some(NO_KERNEL),
+            (Wsl::Wsl2, Capability::Journald) if !systemd_running() => Some(NO_SYSTEMD),
+            (Wsl::Wsl2, _) => None,
+        }
+    }
+
+    /// The backends worth trying here, or why there are none.
+    pub fn default_backends(self) -> Result<Vec<Backend>, RMesgError> {
+        match self {
+            Wsl::Wsl1 => Err(RMesgError::BackendUnavailable(NO_KERNEL.to_owned())),
+            Wsl::Wsl2 => {
+                #[allow(unused_mut)]
+                let mut backends = vec![
+                    #[cfg(feature = "kmsg")]
+                    Backend::DevKMsg,
+                    #[cfg(feature = "klogctl")]
+                    Backend::KLogCtl,
+                ];
+                #[cfg(feature = "journald")]
+                if systemd_running() {
+                    backends.push(Backend::Journald);
+                }
+                Ok(backends)
+            }
+        }
+    }
+}
+
+impl Display for Wsl {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            Wsl::Wsl1 => write!(f, "WSL1"),
+            Wsl::Wsl2 => write!(f, "WSL2"),
+        }
+    }
+}
+
+// As sd_booted(3) checks
+fn systemd_running() -> bool {
+    Path::new("/run/systemd/system").is_dir()
+}
+
+/**********************************************************************************/
+// Tests! Tests! Tests!
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_from_osrelease() {
+        assert_eq!(
+            Wsl::from_osrelease("4.4.0-19041-Microsoft\n"),
+            Some(Wsl::Wsl1)
+        );
+        assert_eq!(
+            Wsl::from_osrelease("5.15.153.1-microsoft-standard-WSL2\n"),
+            Some(Wsl::Wsl2)
+        );
+        assert_eq!(
+            Wsl::from_osrelease("4.19.128-microsoft-standard"),
+            Some(Wsl::Wsl2)
+        );
+        assert_eq!(Wsl::from_osrelease("6.8.0-45-generic"), None);
+    }
+
+    #[test]
+    fn test_wsl1() {
+        for capability in &[
+            Capability::DevKMsg,
+            Capability::KLogCtl,
+            Capability::Clear,
+            Capability::PStore,
+            Capability::Journald,
+        ] {
+            assert_eq!(Wsl::Wsl1.limitation(*capability), Some(NO_KERNEL));
+        }
+        assert!(matches!(
+            Wsl::Wsl1.default_backends(),
+            Err(RMesgError::BackendUnavailable(_))
+        ));
+    }
+
+    #[cfg(all(feature = "kmsg", feature = "klogctl"))]
+    #[test]
+    fn test_wsl2() {
+        assert_eq!(Wsl::Wsl2.limitation(Capability::DevKMsg), None);
+        let backends = Wsl::Wsl2.default_backends().unwrap();
+        assert!(matches!(
+            backends[..2],
+            [Backend::DevKMsg, Backend::KLogCtl]
+        ));
+    }
+}